@@ -0,0 +1,126 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Append-only audit log of signing operations.
+//!
+//! When enabled, every RSA signature that avbroot creates is recorded as a
+//! line of JSON containing what was signed, the digest that was signed, the
+//! fingerprint of the signing key, and a timestamp. This allows reviewing
+//! exactly what a compromised signing environment could have signed.
+//!
+//! Failing to write an entry fails the signing operation, so a signature is
+//! never produced without a corresponding log entry.
+
+use std::{
+    fs::File,
+    io::{self, Write},
+    path::Path,
+    sync::{Mutex, OnceLock},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use rsa::RsaPublicKey;
+use serde::Serialize;
+
+use crate::{crypto, sandbox};
+
+static LOG: OnceLock<Mutex<File>> = OnceLock::new();
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    timestamp: u64,
+    purpose: &'a str,
+    digest_algorithm: &'a str,
+    digest: String,
+    key_sha256: String,
+}
+
+/// Open the audit log for appending. This can only be called once.
+pub fn init(path: &Path) -> io::Result<()> {
+    let file = sandbox::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)?;
+
+    LOG.set(Mutex::new(file))
+        .map_err(|_| io::Error::other("Audit log already initialized"))
+}
+
+/// Record a signing operation. `purpose` describes what is being signed and
+/// `digest` is the digest that the signature covers. This does nothing if the
+/// audit log is not enabled.
+pub fn record(
+    purpose: &str,
+    digest_algorithm: &str,
+    digest: &[u8],
+    key: &RsaPublicKey,
+) -> io::Result<()> {
+    let Some(log) = LOG.get() else {
+        return Ok(());
+    };
+
+    let entry = Entry {
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs()),
+        purpose,
+        digest_algorithm,
+        digest: hex::encode(digest),
+        key_sha256: crypto::public_key_sha256(key)
+            .map(hex::encode)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+    };
+
+    let mut line = serde_json::to_vec(&entry)?;
+    line.push(b'\n');
+
+    // The lock ensures that entries from different threads never interleave.
+    let mut file = log.lock().unwrap();
+    file.write_all(&line)?;
+    file.sync_data()
+}
+
+#[cfg(test)]
+mod tests {
+    use rsa::RsaPrivateKey;
+
+    use super::*;
+
+    #[test]
+    fn append_entries() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("audit.log");
+        std::fs::write(&path, b"existing\n").unwrap();
+
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024)
+            .unwrap()
+            .to_public_key();
+
+        init(&path).unwrap();
+        assert!(init(&path).is_err());
+
+        record("payload", "sha256", &[1; 32], &key).unwrap();
+        record("ota_zip", "sha256", &[2; 32], &key).unwrap();
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        let mut lines = data.lines();
+        assert_eq!(lines.next(), Some("existing"));
+
+        // Other tests running in parallel may also sign things.
+        let key_sha256 = hex::encode(crypto::public_key_sha256(&key).unwrap());
+        let entries = lines
+            .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+            .filter(|e| e["key_sha256"] == key_sha256)
+            .collect::<Vec<_>>();
+        assert_eq!(entries.len(), 2);
+
+        for (entry, (purpose, byte)) in entries.iter().zip([("payload", 1), ("ota_zip", 2)]) {
+            assert_eq!(entry["purpose"], purpose);
+            assert_eq!(entry["digest_algorithm"], "sha256");
+            assert_eq!(entry["digest"], hex::encode([byte; 32]));
+            assert!(entry["timestamp"].is_u64());
+        }
+    }
+}
@@ -0,0 +1,146 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Content-addressed local cache of payload operation data.
+//!
+//! Full OTAs record the sha256 digest of every install operation's data. Most
+//! partitions do not change between successive OTAs, so the data for their
+//! operations can be copied from a cache on fast local storage instead of being
+//! read from the input OTA again. Entries are only added after their digest has
+//! been verified and are verified again when they are read.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+};
+
+use thiserror::Error;
+
+use crate::{digest::Context, sandbox, stream};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Cached data does not match its digest: {0:?}")]
+    CorruptEntry(PathBuf),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+pub struct BlobCache {
+    dir: PathBuf,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+impl BlobCache {
+    /// Open the cache in `dir`, creating the directory if needed.
+    pub fn new(dir: &Path) -> Result<Self> {
+        sandbox::create_dir_all(dir)?;
+
+        Ok(Self {
+            dir: dir.to_owned(),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        })
+    }
+
+    /// Get the number of copies that were served from the cache and the number
+    /// that had to be read from the original source.
+    pub fn stats(&self) -> (u64, u64) {
+        (
+            self.hits.load(Ordering::Relaxed),
+            self.misses.load(Ordering::Relaxed),
+        )
+    }
+
+    fn entry_path(&self, digest: &[u8]) -> PathBuf {
+        let name = hex::encode(digest);
+        self.dir.join(&name[..2]).join(name)
+    }
+
+    /// Copy `size` bytes of data with the sha256 `digest` to `writer`. If the
+    /// data is cached, it is read from the cache. Otherwise, it is read from
+    /// `reader` at `offset` and added to the cache if the digest matches.
+    pub fn copy(
+        &self,
+        digest: &[u8],
+        mut reader: impl Read + Seek,
+        offset: u64,
+        size: u64,
+        writer: impl Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        let path = self.entry_path(digest);
+
+        if let Ok(file) = sandbox::open(&path) {
+            if file.metadata()?.len() == size {
+                self.hits.fetch_add(1, Ordering::Relaxed);
+
+                let mut context = Context::new(&crate::digest::SHA256);
+                stream::copy_n_inspect(
+                    file,
+                    writer,
+                    size,
+                    |data| context.update(data),
+                    cancel_signal,
+                )?;
+
+                if context.finish().as_ref() != digest {
+                    return Err(Error::CorruptEntry(path));
+                }
+
+                return Ok(());
+            }
+        }
+
+        self.misses.fetch_add(1, Ordering::Relaxed);
+
+        sandbox::create_dir_all(path.parent().unwrap())?;
+
+        // Other processes may be populating the same cache.
+        let mut temp_name = path.file_name().unwrap().to_owned();
+        temp_name.push(format!(".{:016x}.tmp", rand::random::<u64>()));
+        let temp_path = path.with_file_name(temp_name);
+
+        let result = (|| {
+            let mut temp_file = sandbox::create(&temp_path)?;
+            let mut context = Context::new(&crate::digest::SHA256);
+            let mut cache_result = Ok(());
+
+            reader.seek(SeekFrom::Start(offset))?;
+
+            stream::copy_n_inspect(
+                &mut reader,
+                writer,
+                size,
+                |data| {
+                    context.update(data);
+                    if cache_result.is_ok() {
+                        cache_result = temp_file.write_all(data);
+                    }
+                },
+                cancel_signal,
+            )?;
+            cache_result?;
+
+            // Never cache data that doesn't match what the manifest claims.
+            Ok(context.finish().as_ref() == digest)
+        })();
+
+        match result {
+            Ok(true) => sandbox::rename(&temp_path, &path)?,
+            Ok(false) => sandbox::remove_file(&temp_path)?,
+            Err(e) => {
+                let _ = sandbox::remove_file(&temp_path);
+                return Err(Error::Io(e));
+            }
+        }
+
+        Ok(())
+    }
+}
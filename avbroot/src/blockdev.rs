@@ -0,0 +1,67 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Helpers for writing directly to block devices.
+
+use std::{
+    fs::File,
+    io::{self, Seek, SeekFrom},
+};
+
+/// Check if `file` refers to a block device. This is always false on
+/// platforms without block device files.
+pub fn is_block_device(file: &File) -> io::Result<bool> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileTypeExt;
+
+        Ok(file.metadata()?.file_type().is_block_device())
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = file;
+        Ok(false)
+    }
+}
+
+/// Get the size of a block device. Unlike regular files, the size is not
+/// reported in the file metadata, so this seeks to the end instead.
+pub fn size(mut file: &File) -> io::Result<u64> {
+    let size = file.seek(SeekFrom::End(0))?;
+    file.rewind()?;
+
+    Ok(size)
+}
+
+/// Discard the first `length` bytes of a block device with `BLKDISCARD`.
+pub fn discard(file: &File, length: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::os::fd::AsRawFd;
+
+        // _IO(0x12, 119) from <linux/fs.h>.
+        const BLKDISCARD: u32 = 0x1277;
+
+        let range = [0u64, length];
+        // SAFETY: The fd is valid for the lifetime of `file` and BLKDISCARD
+        // only reads the two-element range.
+        let ret = unsafe { libc::ioctl(file.as_raw_fd(), BLKDISCARD as _, range.as_ptr()) };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        Ok(())
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, length);
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "BLKDISCARD is only supported on Linux",
+        ))
+    }
+}
@@ -3,12 +3,75 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::sync::atomic::AtomicBool;
+use std::{
+    fs::File,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
 
-use anyhow::Result;
-use clap::{Parser, Subcommand};
+use anyhow::{bail, Context, Result};
+use clap::{Parser, Subcommand, ValueEnum};
 
-use crate::cli::{avb, boot, completion, cpio, fec, hashtree, key, ota};
+use crate::{
+    audit,
+    cli::{
+        avb, boot, completion, cpio, device, fec, hashtree, key, ota, payload, profile,
+        progress::{self, ProgressMode},
+        selftest, warning,
+    },
+    digest::{self, RingBackend, RustCryptoBackend},
+    format::payload::ApplyOptions,
+    harden::{self, Enforcement},
+    sandbox,
+};
+
+const HEADING_SANDBOX: &str = "Sandbox options";
+
+/// Memory limit for each XZ decoder in low-memory mode. This is enough for
+/// dictionaries up to 8 MiB, which is what liblzma's default preset uses.
+/// avbroot itself never uses a dictionary larger than a 2 MiB chunk. Payloads
+/// that declare larger dictionaries fail to extract instead of allocating
+/// them.
+const LOW_MEMORY_XZ_MEMLIMIT: u64 = 16 * 1024 * 1024;
+
+/// Number of threads in rayon's global thread pool in low-memory mode. Every
+/// thread extracting or verifying a partition holds its own decoder and
+/// buffers, so this bounds memory usage regardless of the number of CPUs.
+const LOW_MEMORY_THREADS: usize = 2;
+
+static LOW_MEMORY: AtomicBool = AtomicBool::new(false);
+
+/// Whether `--low-memory` was specified.
+pub fn low_memory() -> bool {
+    LOW_MEMORY.load(Ordering::Relaxed)
+}
+
+/// Options for applying payload operations, based on `--low-memory`.
+pub fn payload_apply_options() -> ApplyOptions {
+    ApplyOptions {
+        xz_memlimit: low_memory().then_some(LOW_MEMORY_XZ_MEMLIMIT),
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SandboxMode {
+    /// Allow access to any path.
+    #[default]
+    None,
+    /// Only allow access to paths inside directories granted with --allow-dir.
+    Strict,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum CryptoBackend {
+    /// Use RustCrypto if the CPU has SHA-NI and ring otherwise.
+    #[default]
+    Auto,
+    /// Use ring's assembly implementations.
+    Ring,
+    /// Use RustCrypto's implementations.
+    Rustcrypto,
+}
 
 #[allow(clippy::large_enum_variant)]
 #[derive(Debug, Subcommand)]
@@ -17,10 +80,13 @@ pub enum Command {
     Boot(boot::BootCli),
     Completion(completion::CompletionCli),
     Cpio(cpio::CpioCli),
+    Device(device::DeviceCli),
     Fec(fec::FecCli),
     HashTree(hashtree::HashTreeCli),
     Key(key::KeyCli),
     Ota(ota::OtaCli),
+    Payload(payload::PayloadCli),
+    Selftest(selftest::SelftestCli),
     /// (Deprecated: Use `avbroot ota patch` instead.)
     Patch(ota::PatchCli),
     /// (Deprecated: Use `avbroot ota extract` instead.)
@@ -34,20 +100,214 @@ pub enum Command {
 pub struct Cli {
     #[command(subcommand)]
     pub command: Command,
+
+    /// Filesystem sandboxing mode.
+    ///
+    /// In strict mode, every input and output path must be inside one of the
+    /// directories granted with --allow-dir. Files are opened relative to the
+    /// granted directories, so symlinks and `..` components cannot escape
+    /// them. This is useful when processing untrusted files in CI.
+    #[arg(
+        long,
+        global = true,
+        value_enum,
+        default_value_t,
+        help_heading = HEADING_SANDBOX
+    )]
+    pub sandbox: SandboxMode,
+
+    /// Directory to grant access to in strict sandbox mode or when hardening.
+    ///
+    /// This option can be specified multiple times.
+    #[arg(long, global = true, value_name = "DIR", value_parser, help_heading = HEADING_SANDBOX)]
+    pub allow_dir: Vec<PathBuf>,
+
+    /// Apply kernel-enforced restrictions to the process (Linux only).
+    ///
+    /// A Landlock ruleset is applied so that only the directories granted with
+    /// --allow-dir and the temporary directory are accessible, and a seccomp
+    /// filter blocks syscalls that avbroot never needs, like networking and
+    /// running other programs.
+    ///
+    /// Because of this, options that run programs (--signer-cmd,
+    /// --custom-boot-patcher, --payload-decrypt-cmd, --payload-encrypt-cmd, and
+    /// --notify-cmd) and options that access the network (--notify-url, cloud
+    /// KMS signing keys, and OTA URLs) cannot be used. For the same reason,
+    /// `ota download`, `ota flash-fastboot`, and `device verify-slot` do not
+    /// work.
+    #[arg(long, global = true, help_heading = HEADING_SANDBOX)]
+    pub harden: bool,
+
+    /// Machine-readable progress reporting.
+    ///
+    /// In JSON mode, newline-delimited JSON events describing the current
+    /// stage, partition, percentage, and estimated time remaining are written
+    /// to stderr, alongside the human-readable logs. Every event is a line
+    /// starting with `{`. stdout is left untouched for the command's output.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub progress: ProgressMode,
+
+    /// Write JSON progress events to this file descriptor (Unix only).
+    ///
+    /// This implies `--progress json`, but keeps the events separate from the
+    /// logs on stderr. The file descriptor must already be open for writing,
+    /// eg. a pipe set up by the parent process.
+    #[arg(
+        long,
+        global = true,
+        value_name = "FD",
+        conflicts_with_all = ["progress", "progress_json"]
+    )]
+    pub progress_fd: Option<i32>,
+
+    /// Write JSON progress events to this file.
+    ///
+    /// This implies `--progress json`, but keeps the events separate from the
+    /// logs on stderr. The file can be a named pipe.
+    #[arg(
+        long,
+        global = true,
+        value_name = "FILE",
+        value_parser,
+        conflicts_with = "progress"
+    )]
+    pub progress_json: Option<PathBuf>,
+
+    /// Append a record of every signing operation to this file.
+    ///
+    /// Each line is a JSON object containing what was signed, the signed
+    /// digest, the sha256 fingerprint of the signing key's public key, and a
+    /// timestamp. If a record cannot be written, the signing operation fails.
+    #[arg(long, global = true, value_name = "FILE", value_parser)]
+    pub audit_log: Option<PathBuf>,
+
+    /// Implementation used for computing digests.
+    ///
+    /// By default, the fastest implementation for the CPU is detected
+    /// automatically. Hashing dominates the runtime of verifying and patching
+    /// large OTAs, so this is mostly useful for benchmarking.
+    #[arg(long, global = true, value_enum, default_value_t)]
+    pub crypto_backend: CryptoBackend,
+
+    /// Reduce memory usage at the cost of speed.
+    ///
+    /// Payload images are compressed one chunk at a time instead of in
+    /// parallel, only two threads are used for extracting and verifying
+    /// images, the memory used by each XZ decoder is capped to 16 MiB, smaller
+    /// caches are used, and hash trees are generated with bounded memory usage.
+    /// This is useful in small VMs, CI containers, and on Android devices.
+    #[arg(long, global = true)]
+    pub low_memory: bool,
+}
+
+/// Open a file descriptor that was passed in by the parent process. The fd is
+/// duplicated instead of taking ownership because closing an fd that was never
+/// open would abort.
+#[cfg(unix)]
+fn open_progress_fd(fd: i32) -> Result<File> {
+    use std::os::fd::BorrowedFd;
+
+    if fd < 0 {
+        bail!("Invalid file descriptor: {fd}");
+    }
+
+    // SAFETY: The fd is only borrowed long enough to duplicate it. If it is not
+    // open, duplicating it fails with EBADF.
+    let borrowed = unsafe { BorrowedFd::borrow_raw(fd) };
+    let owned = borrowed
+        .try_clone_to_owned()
+        .with_context(|| format!("File descriptor is not open: {fd}"))?;
+
+    Ok(File::from(owned))
+}
+
+#[cfg(not(unix))]
+fn open_progress_fd(_fd: i32) -> Result<File> {
+    bail!("--progress-fd is only supported on Unix-like systems");
 }
 
 pub fn main(cancel_signal: &AtomicBool) -> Result<()> {
-    let cli = Cli::parse();
+    let cli: Cli = profile::parse_args(std::env::args_os(), &[&["ota", "patch"], &["patch"]])?;
+
+    if cli.progress_fd.is_some() || cli.progress_json.is_some() {
+        progress::init(ProgressMode::Json);
+    } else {
+        progress::init(cli.progress);
+    }
+
+    match cli.crypto_backend {
+        CryptoBackend::Auto => {}
+        CryptoBackend::Ring => {
+            digest::set_backend(&RingBackend);
+        }
+        CryptoBackend::Rustcrypto => {
+            digest::set_backend(&RustCryptoBackend);
+        }
+    }
+
+    if cli.low_memory {
+        LOW_MEMORY.store(true, Ordering::Relaxed);
+
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(LOW_MEMORY_THREADS)
+            .build_global()
+            .context("Failed to initialize thread pool")?;
+    }
+
+    match cli.sandbox {
+        SandboxMode::None => {
+            if !cli.allow_dir.is_empty() && !cli.harden {
+                bail!("--allow-dir requires --sandbox strict or --harden");
+            }
+        }
+        SandboxMode::Strict => {
+            if cli.allow_dir.is_empty() {
+                bail!("--sandbox strict requires at least one --allow-dir");
+            }
+
+            sandbox::restrict(&cli.allow_dir).context("Failed to initialize sandbox")?;
+        }
+    }
+
+    // This must be opened after the sandbox is initialized so that the path is
+    // checked, but before hardening so that the file remains writable.
+    if let Some(path) = &cli.audit_log {
+        audit::init(path).with_context(|| format!("Failed to open audit log: {path:?}"))?;
+    }
+
+    // Same as above.
+    if let Some(fd) = cli.progress_fd {
+        let file = open_progress_fd(fd)?;
+        progress::set_output(Box::new(file)).context("Failed to set progress output")?;
+    } else if let Some(path) = &cli.progress_json {
+        let file = sandbox::create(path)
+            .with_context(|| format!("Failed to open progress output: {path:?}"))?;
+        progress::set_output(Box::new(file)).context("Failed to set progress output")?;
+    }
+
+    if cli.harden {
+        if cli.allow_dir.is_empty() {
+            bail!("--harden requires at least one --allow-dir");
+        }
+
+        let enforcement = harden::apply(&cli.allow_dir).context("Failed to harden process")?;
+        if enforcement == Enforcement::Partial {
+            warning!("Kernel only partially supports Landlock; some restrictions are not enforced");
+        }
+    }
 
     match cli.command {
         Command::Avb(c) => avb::avb_main(&c, cancel_signal),
-        Command::Boot(c) => boot::boot_main(&c),
+        Command::Boot(c) => boot::boot_main(&c, cancel_signal),
         Command::Completion(c) => completion::completion_main(&c),
         Command::Cpio(c) => cpio::cpio_main(&c, cancel_signal),
+        Command::Device(c) => device::device_main(&c, cancel_signal),
         Command::Fec(c) => fec::fec_main(&c, cancel_signal),
         Command::HashTree(c) => hashtree::hash_tree_main(&c, cancel_signal),
         Command::Key(c) => key::key_main(&c),
         Command::Ota(c) => ota::ota_main(&c, cancel_signal),
+        Command::Payload(c) => payload::payload_main(&c, cancel_signal),
+        Command::Selftest(c) => selftest::selftest_main(&c, cancel_signal),
         // Deprecated aliases.
         Command::Patch(c) => ota::patch_subcommand(&c, cancel_signal),
         Command::Extract(c) => ota::extract_subcommand(&c, cancel_signal),
@@ -4,9 +4,10 @@
  */
 
 use std::{
-    collections::{HashMap, HashSet},
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
     ffi::{OsStr, OsString},
-    fs::{self, File},
+    fs::File,
     io::{self, BufReader, BufWriter, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
     str,
@@ -14,22 +15,25 @@ use std::{
 };
 
 use anyhow::{anyhow, bail, Context, Result};
-use cap_std::{
-    ambient_authority,
-    fs::{Dir, OpenOptions},
-};
-use clap::{Args, Parser, Subcommand};
-use rayon::prelude::{IntoParallelRefIterator, ParallelIterator};
+use cap_std::fs::{Dir, OpenOptions};
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use rayon::prelude::{IndexedParallelIterator, IntoParallelRefIterator, ParallelIterator};
 use rsa::RsaPublicKey;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    cli::{status, warning},
-    crypto::{self, PassphraseSource},
+    cli::{
+        args,
+        digests::{DigestsManifest, FileStamp},
+        ota, status, warning,
+    },
+    crypto::PassphraseSource,
     format::avb::{
         self, AlgorithmType, AppendedDescriptorMut, AppendedDescriptorRef, Descriptor, Footer,
         HashTreeDescriptor, Header, KernelCmdlineDescriptor,
     },
+    format::payload,
+    sandbox,
     stream::{self, PSeekFile, Reopen},
     util,
 };
@@ -42,7 +46,7 @@ struct AvbInfo {
 }
 
 fn read_avb_image(path: &Path) -> Result<(AvbInfo, BufReader<File>)> {
-    let file = File::open(path)
+    let file = sandbox::open(path)
         .with_context(|| format!("Failed to open AVB image for reading: {path:?}"))?;
     let mut reader = BufReader::new(file);
     let (header, footer, image_size) = avb::load_image(&mut reader)
@@ -73,9 +77,19 @@ fn write_avb_image(file: PSeekFile, info: &mut AvbInfo) -> Result<()> {
     Ok(())
 }
 
+/// Read AVB information from JSON file.
+fn read_info_json(path: &Path) -> Result<AvbInfo> {
+    let data =
+        sandbox::read(path).with_context(|| format!("Failed to read AVB info JSON: {path:?}"))?;
+    let info = serde_json::from_slice(&data)
+        .with_context(|| format!("Failed to parse AVB info JSON: {path:?}"))?;
+
+    Ok(info)
+}
+
 /// Read AVB information from TOML file.
 fn read_info(path: &Path) -> Result<AvbInfo> {
-    let data = fs::read_to_string(path)
+    let data = sandbox::read_to_string(path)
         .with_context(|| format!("Failed to read AVB info TOML: {path:?}"))?;
     let info = toml_edit::de::from_str(&data)
         .with_context(|| format!("Failed to parse AVB info TOML: {path:?}"))?;
@@ -87,7 +101,8 @@ fn read_info(path: &Path) -> Result<AvbInfo> {
 fn write_info(path: &Path, info: &AvbInfo) -> Result<()> {
     let data = toml_edit::ser::to_string_pretty(info)
         .with_context(|| format!("Failed to serialize AVB info TOML: {path:?}"))?;
-    fs::write(path, data).with_context(|| format!("Failed to write AVB info TOML: {path:?}"))?;
+    sandbox::write(path, data)
+        .with_context(|| format!("Failed to write AVB info TOML: {path:?}"))?;
 
     Ok(())
 }
@@ -114,7 +129,7 @@ fn write_raw(
     size: u64,
     cancel_signal: &AtomicBool,
 ) -> Result<PSeekFile> {
-    let file = fs::OpenOptions::new()
+    let file = sandbox::OpenOptions::new()
         .read(true)
         .write(true)
         .create(true)
@@ -379,10 +394,11 @@ fn sign_or_clear(info: &mut AvbInfo, orig_header: &Header, key_group: &KeyGroup)
                 key_group.pass_file.as_deref(),
                 key_group.pass_env_var.as_deref(),
             );
-            let private_key = crypto::read_pem_key_file(key_path, &source)
-                .with_context(|| format!("Failed to load key: {key_path:?}"))?;
+            let private_key =
+                ota::load_signing_key(key_path, &source, false, key_group.signer_cmd.as_deref())?;
 
-            info.header.set_algo_for_key(&private_key)?;
+            info.header
+                .set_algo_for_key(&private_key, key_group.algorithm.map(|a| a.to_algorithm()))?;
             info.header
                 .sign(&private_key)
                 .context("Failed to sign new AVB header")?;
@@ -419,12 +435,14 @@ fn ensure_name_is_safe(name: &str) -> Result<()> {
 }
 
 /// Recursively verify an image's vbmeta header and all of the chained images.
-/// `seen` is used to prevent cycles. `descriptors` will contain all of the hash
-/// and hash tree descriptors that need to be verified.
+/// Chained images are only followed up to `max_depth` levels deep, if
+/// specified. `seen` is used to prevent cycles. `descriptors` will contain all
+/// of the hash and hash tree descriptors that need to be verified.
 pub fn verify_headers(
     directory: &Dir,
     name: &str,
     expected_key: Option<&RsaPublicKey>,
+    max_depth: Option<usize>,
     seen: &mut HashSet<String>,
     descriptors: &mut HashMap<String, Descriptor>,
 ) -> Result<()> {
@@ -478,11 +496,23 @@ pub fn verify_headers(
                 }
             }
             avb::Descriptor::ChainPartition(d) => {
+                if max_depth == Some(0) {
+                    status!("Not following chain to {target_name}: maximum depth reached");
+                    continue;
+                }
+
                 let target_key = avb::decode_public_key(&d.public_key).with_context(|| {
                     format!("Failed to decode chained public key for: {target_name}")
                 })?;
 
-                verify_headers(directory, target_name, Some(&target_key), seen, descriptors)?;
+                verify_headers(
+                    directory,
+                    target_name,
+                    Some(&target_key),
+                    max_depth.map(|d| d - 1),
+                    seen,
+                    descriptors,
+                )?;
             }
             _ => {}
         }
@@ -491,6 +521,9 @@ pub fn verify_headers(
     Ok(())
 }
 
+/// Buffer size for reading images that are verified sequentially.
+const VERIFY_BUF_SIZE: usize = 1024 * 1024;
+
 /// Verify the descriptor for a file. For hash tree descriptors, if FEC data is
 /// available and `repair` is true, then attempt to repair data in the event of
 /// corruption. `file` must be opened as read-write for the repair operation to
@@ -531,8 +564,11 @@ fn verify_and_repair(
             status!("Verifying hash descriptor{suffix}");
 
             file.rewind()?;
-            d.verify(file, cancel_signal)
-                .with_context(|| format!("Failed to verify hash descriptor{suffix}"))?;
+            d.verify(
+                BufReader::with_capacity(VERIFY_BUF_SIZE, file),
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to verify hash descriptor{suffix}"))?;
         }
     }
 
@@ -540,43 +576,88 @@ fn verify_and_repair(
 }
 
 /// Verify hash and hash tree descriptor digests and FEC data against their
-/// corresponding input files.
+/// corresponding input files. Each partition is verified in parallel. If a
+/// digests manifest is provided, partitions that were already verified against
+/// the same descriptor are skipped and newly verified partitions are recorded.
 pub fn verify_descriptors(
     directory: &Dir,
     descriptors: &HashMap<String, Descriptor>,
     repair: bool,
+    manifest: Option<&mut DigestsManifest>,
     cancel_signal: &AtomicBool,
 ) -> Result<()> {
-    descriptors
+    // The total time is dominated by the largest partitions, so start them
+    // first to avoid them being the only ones left running at the end.
+    let mut descriptors = descriptors.iter().collect::<Vec<_>>();
+    descriptors.sort_by_key(|(name, descriptor)| {
+        let image_size = match descriptor {
+            Descriptor::HashTree(d) => d.image_size,
+            Descriptor::Hash(d) => d.image_size,
+            _ => 0,
+        };
+
+        (Reverse(image_size), *name)
+    });
+
+    let cached = manifest.as_deref();
+
+    let verified = descriptors
         .par_iter()
-        .map(|(name, descriptor)| {
-            let path = format!("{name}.img");
-            let file = match directory
-                .open_with(&path, OpenOptions::new().read(true).write(repair))
-                .map(|f| PSeekFile::new(f.into_std()))
-            {
-                Ok(f) => f,
-                // Some devices, like bluejay, have vbmeta descriptors that
-                // refer to partitions that exist on the device, but not in the
-                // OTA.
-                Err(e) if e.kind() == io::ErrorKind::NotFound => {
-                    warning!("Partition image does not exist: {path:?}");
-                    return Ok(());
+        // Schedule each partition as a separate task.
+        .with_max_len(1)
+        .map(
+            |(name, descriptor)| -> Result<Option<(&str, FileStamp, &Descriptor)>> {
+                if let Some(m) = cached {
+                    if let Some(stamp) = FileStamp::for_image(directory, name)? {
+                        if m.get(name, &stamp).and_then(|p| p.avb.as_ref()) == Some(*descriptor) {
+                            status!("Already verified: {name}");
+                            return Ok(None);
+                        }
+                    }
                 }
-                Err(e) => {
-                    Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))?
+
+                let path = format!("{name}.img");
+                let file = match directory
+                    .open_with(&path, OpenOptions::new().read(true).write(repair))
+                    .map(|f| PSeekFile::new(f.into_std()))
+                {
+                    Ok(f) => f,
+                    // Some devices, like bluejay, have vbmeta descriptors that
+                    // refer to partitions that exist on the device, but not in the
+                    // OTA.
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        warning!("Partition image does not exist: {path:?}");
+                        return Ok(None);
+                    }
+                    Err(e) => {
+                        Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))?
+                    }
+                };
+
+                verify_and_repair(
+                    Some(name),
+                    file,
+                    (*descriptor).try_into()?,
+                    repair,
+                    cancel_signal,
+                )?;
+
+                // Stat the file afterwards in case it was repaired.
+                match cached.and(FileStamp::for_image(directory, name)?) {
+                    Some(stamp) => Ok(Some((name.as_str(), stamp, *descriptor))),
+                    None => Ok(None),
                 }
-            };
+            },
+        )
+        .collect::<Result<Vec<_>>>()?;
 
-            verify_and_repair(
-                Some(name),
-                file,
-                descriptor.try_into()?,
-                repair,
-                cancel_signal,
-            )
-        })
-        .collect()
+    if let Some(m) = manifest {
+        for (name, stamp, descriptor) in verified.into_iter().flatten() {
+            m.entry(name, stamp).avb = Some(descriptor.clone());
+        }
+    }
+
+    Ok(())
 }
 
 fn unpack_subcommand(cli: &UnpackCli, cancel_signal: &AtomicBool) -> Result<()> {
@@ -603,7 +684,7 @@ fn pack_subcommand(cli: &PackCli, cancel_signal: &AtomicBool) -> Result<()> {
     let orig_header = info.header.clone();
 
     let file = if info.footer.is_some() {
-        let mut reader = File::open(&cli.input_raw)
+        let mut reader = sandbox::open(&cli.input_raw)
             .map(BufReader::new)
             .with_context(|| {
                 format!("Failed to open raw image for reading: {:?}", cli.input_raw)
@@ -615,7 +696,7 @@ fn pack_subcommand(cli: &PackCli, cancel_signal: &AtomicBool) -> Result<()> {
 
         file
     } else {
-        File::create(&cli.output)
+        sandbox::create(&cli.output)
             .map(PSeekFile::new)
             .with_context(|| format!("Failed to open output for writing: {:?}", cli.output))?
     };
@@ -649,7 +730,7 @@ fn repack_subcommand(cli: &RepackCli, cancel_signal: &AtomicBool) -> Result<()>
 
         file
     } else {
-        File::create(&cli.output)
+        sandbox::create(&cli.output)
             .map(PSeekFile::new)
             .with_context(|| format!("Failed to open for writing: {:?}", cli.output))?
     };
@@ -665,16 +746,130 @@ fn repack_subcommand(cli: &RepackCli, cancel_signal: &AtomicBool) -> Result<()>
     Ok(())
 }
 
+fn edit_subcommand(cli: &EditCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let (mut info, mut reader) = read_avb_image(&cli.input)?;
+    let orig_header = info.header.clone();
+    let new_info = read_info_json(&cli.from_json)?;
+
+    if new_info.footer != info.footer || new_info.image_size != info.image_size {
+        bail!("The footer and image size cannot be changed. Use `unpack` and `pack` instead");
+    }
+
+    let file = if let Some(f) = &info.footer {
+        let original_image_size = f.original_image_size;
+        let mut file = write_raw_and_verify(&cli.output, &mut reader, &info, false, cancel_signal)?;
+
+        info.header = new_info.header;
+
+        // The digests must always match the data, so recompute them in case
+        // anything that affects them, like the salt, was changed.
+        match info.header.appended_descriptor_mut()? {
+            AppendedDescriptorMut::HashTree(d) => {
+                d.image_size = original_image_size;
+                d.update(&file, &file, None, cancel_signal)
+                    .context("Failed to update hash tree descriptor")?;
+            }
+            AppendedDescriptorMut::Hash(d) => {
+                d.image_size = original_image_size;
+                file.rewind()?;
+                d.update(&mut file, cancel_signal)
+                    .context("Failed to update hash descriptor")?;
+            }
+        }
+
+        update_dm_verity_cmdline(&mut info)?;
+
+        file
+    } else {
+        info.header = new_info.header;
+
+        sandbox::create(&cli.output)
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to open for writing: {:?}", cli.output))?
+    };
+
+    sign_or_clear(&mut info, &orig_header, &cli.key)?;
+
+    write_avb_image(file, &mut info)?;
+
+    display_info(&cli.display, &info);
+
+    Ok(())
+}
+
 fn info_subcommand(cli: &InfoCli) -> Result<()> {
     let (info, _) = read_avb_image(&cli.input)?;
-    display_info(&cli.display, &info);
+
+    match cli.format {
+        InfoFormat::Text => display_info(&cli.display, &info),
+        InfoFormat::Json => {
+            let data =
+                serde_json::to_string_pretty(&info).context("Failed to serialize AVB info")?;
+            println!("{data}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Verify a single appended image's header signature and its own hash or hash
+/// tree descriptor. Chain descriptors and descriptors for other partitions are
+/// ignored.
+fn verify_standalone(
+    cli: &VerifyCli,
+    expected_key: Option<&RsaPublicKey>,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let (info, _) = read_avb_image(&cli.input)?;
+    if info.footer.is_none() {
+        bail!("Image does not have an AVB footer: {:?}", cli.input);
+    }
+
+    let public_key = info
+        .header
+        .verify()
+        .with_context(|| format!("Failed to verify header signature: {:?}", cli.input))?;
+
+    match (public_key, expected_key) {
+        (Some(k), Some(e)) if k != *e => {
+            bail!("Image has a signed vbmeta header, but is signed by an untrusted key")
+        }
+        (Some(_), Some(_)) => status!("Image has a signed vbmeta header"),
+        (Some(_), None) => {
+            warning!("Image has a signed vbmeta header, but no trusted key was specified")
+        }
+        (None, Some(_)) => bail!("Image has an unsigned vbmeta header"),
+        (None, None) => status!("Image has an unsigned vbmeta header"),
+    }
+
+    if cli.headers_only {
+        status!("Successfully verified vbmeta signature");
+        return Ok(());
+    }
+
+    let file = sandbox::OpenOptions::new()
+        .read(true)
+        .write(cli.repair)
+        .open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+
+    verify_and_repair(
+        None,
+        file,
+        info.header.appended_descriptor()?,
+        cli.repair,
+        cancel_signal,
+    )?;
+
+    status!("Successfully verified vbmeta signature and hashes");
 
     Ok(())
 }
 
 fn verify_subcommand(cli: &VerifyCli, cancel_signal: &AtomicBool) -> Result<()> {
     let public_key = if let Some(p) = &cli.public_key {
-        let data = fs::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
+        let data = sandbox::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
         let key = avb::decode_public_key(&data)
             .with_context(|| format!("Failed to decode public key: {p:?}"))?;
 
@@ -683,9 +878,12 @@ fn verify_subcommand(cli: &VerifyCli, cancel_signal: &AtomicBool) -> Result<()>
         None
     };
 
-    let authority = ambient_authority();
+    if cli.standalone {
+        return verify_standalone(cli, public_key.as_ref(), cancel_signal);
+    }
+
     let parent_path = util::parent_path(&cli.input);
-    let directory = Dir::open_ambient_dir(parent_path, authority)
+    let directory = sandbox::open_dir(parent_path)
         .with_context(|| format!("Failed to open directory: {parent_path:?}"))?;
     let name = cli
         .input
@@ -701,23 +899,266 @@ fn verify_subcommand(cli: &VerifyCli, cancel_signal: &AtomicBool) -> Result<()>
         &directory,
         name,
         public_key.as_ref(),
+        cli.max_depth,
         &mut seen,
         &mut descriptors,
     )?;
-    verify_descriptors(&directory, &descriptors, cli.repair, cancel_signal)?;
+
+    if cli.headers_only {
+        status!("Successfully verified all vbmeta signatures");
+        return Ok(());
+    }
+
+    if !cli.partition.is_empty() {
+        for partition in &cli.partition {
+            if !descriptors.contains_key(partition) {
+                bail!("No hash or hash tree descriptor found for partition: {partition}");
+            }
+        }
+
+        descriptors.retain(|n, _| cli.partition.contains(n));
+    }
+
+    let mut manifest = if cli.digests {
+        Some(DigestsManifest::load(&directory)?.unwrap_or_default())
+    } else {
+        None
+    };
+
+    verify_descriptors(
+        &directory,
+        &descriptors,
+        cli.repair,
+        manifest.as_mut(),
+        cancel_signal,
+    )?;
+
+    if let Some(m) = &manifest {
+        m.save(&directory)?;
+    }
 
     status!("Successfully verified all vbmeta signatures and hashes");
 
     Ok(())
 }
 
+/// An edge in the vbmeta dependency graph.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+struct GraphEdge {
+    parent: String,
+    child: String,
+    descriptor: &'static str,
+}
+
+/// The dependency graph between vbmeta images and the partitions referenced by
+/// their descriptors.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize)]
+struct Graph {
+    roots: BTreeSet<String>,
+    signed: BTreeMap<String, bool>,
+    edges: BTreeSet<GraphEdge>,
+}
+
+impl Graph {
+    /// Build the graph, starting from the vbmeta partitions in `names` and
+    /// following chain descriptors to load chained images. If `load_header`
+    /// returns [`None`], the chained image is not available and the chain is
+    /// not followed.
+    fn build(
+        names: impl IntoIterator<Item = String>,
+        mut load_header: impl FnMut(&str) -> Result<Option<Header>>,
+    ) -> Result<Self> {
+        let mut headers = HashMap::new();
+        let mut queue = names.into_iter().collect::<VecDeque<_>>();
+        let mut seen = queue.iter().cloned().collect::<HashSet<_>>();
+
+        while let Some(name) = queue.pop_front() {
+            let Some(header) = load_header(&name)? else {
+                warning!("Not following chain to {name}: image not found");
+                continue;
+            };
+
+            for descriptor in &header.descriptors {
+                if let Descriptor::ChainPartition(d) = descriptor {
+                    if seen.insert(d.partition_name.clone()) {
+                        queue.push_back(d.partition_name.clone());
+                    }
+                }
+            }
+
+            headers.insert(name, header);
+        }
+
+        // This is the same dependency graph that `ota patch` uses, except that
+        // every partition is included, not just the modified ones.
+        let dep_graph = ota::get_vbmeta_deps(&headers, |_| true);
+        let mut graph = Self {
+            roots: ota::get_vbmeta_roots(&dep_graph)
+                .into_iter()
+                .map(|n| n.to_owned())
+                .collect(),
+            ..Default::default()
+        };
+
+        for (name, header) in &headers {
+            graph
+                .signed
+                .insert(name.clone(), !header.public_key.is_empty());
+
+            for descriptor in &header.descriptors {
+                let Some(child) = descriptor.partition_name() else {
+                    continue;
+                };
+
+                graph.edges.insert(GraphEdge {
+                    parent: name.clone(),
+                    child: child.to_owned(),
+                    descriptor: descriptor.type_name(),
+                });
+            }
+        }
+
+        Ok(graph)
+    }
+
+    fn to_dot(&self) -> String {
+        use std::fmt::Write;
+
+        let mut result = String::from("digraph vbmeta {\n");
+
+        for (name, signed) in &self.signed {
+            let shape = if self.roots.contains(name) {
+                "doubleoctagon"
+            } else {
+                "box"
+            };
+            let style = if *signed { "solid" } else { "dashed" };
+
+            writeln!(result, "    {name:?} [shape={shape}, style={style}];").unwrap();
+        }
+
+        for edge in &self.edges {
+            writeln!(
+                result,
+                "    {:?} -> {:?} [label={:?}];",
+                edge.parent, edge.child, edge.descriptor,
+            )
+            .unwrap();
+        }
+
+        result.push_str("}\n");
+        result
+    }
+}
+
+/// Load the vbmeta dependency graph from a directory of `<partition>.img`
+/// files.
+fn load_graph_from_dir(path: &Path) -> Result<Graph> {
+    let directory =
+        sandbox::open_dir(path).with_context(|| format!("Failed to open directory: {path:?}"))?;
+    let names = sandbox::read_dir(path)
+        .with_context(|| format!("Failed to list directory: {path:?}"))?
+        .into_iter()
+        .filter(|p| p.extension() == Some(OsStr::new("img")))
+        .filter_map(|p| p.file_stem()?.to_str().map(|s| s.to_owned()))
+        .filter(|n| n.starts_with("vbmeta"))
+        .collect::<BTreeSet<_>>();
+
+    if names.is_empty() {
+        bail!("No vbmeta images found in directory: {path:?}");
+    }
+
+    Graph::build(names, |name| {
+        ensure_name_is_safe(name)?;
+
+        let path = format!("{name}.img");
+        let raw_reader = match directory.open(&path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))
+            }
+        };
+        let (header, _, _) = avb::load_image(BufReader::new(raw_reader))
+            .with_context(|| format!("Failed to load vbmeta structures: {path:?}"))?;
+
+        Ok(Some(header))
+    })
+}
+
+/// Load the vbmeta dependency graph from the payload of a full OTA zip.
+fn load_graph_from_ota(path: &Path, cancel_signal: &AtomicBool) -> Result<Graph> {
+    let (header, payload_reader) = ota::open_full_ota_payload(path)?;
+    let names = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| p.partition_name.clone())
+        .filter(|n| n.starts_with("vbmeta"))
+        .collect::<BTreeSet<_>>();
+
+    if names.is_empty() {
+        bail!("No vbmeta partitions found in OTA: {path:?}");
+    }
+
+    Graph::build(names, |name| {
+        if !header
+            .manifest
+            .partitions
+            .iter()
+            .any(|p| p.partition_name == name)
+        {
+            return Ok(None);
+        }
+
+        let mut file = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+        payload::extract_image(
+            &payload_reader,
+            &file,
+            &header,
+            name,
+            &args::payload_apply_options(),
+            cancel_signal,
+        )
+        .with_context(|| format!("Failed to extract {name} from: {path:?}"))?;
+
+        let (header, _, _) = avb::load_image(&mut file)
+            .with_context(|| format!("Failed to load vbmeta structures: {name}"))?;
+
+        Ok(Some(header))
+    })
+}
+
+fn graph_subcommand(cli: &GraphCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let graph = if sandbox::is_dir(&cli.input) {
+        load_graph_from_dir(&cli.input)?
+    } else {
+        load_graph_from_ota(&cli.input, cancel_signal)?
+    };
+
+    match cli.format {
+        GraphFormat::Dot => print!("{}", graph.to_dot()),
+        GraphFormat::Json => {
+            let data = serde_json::to_string_pretty(&graph).context("Failed to serialize graph")?;
+            println!("{data}");
+        }
+    }
+
+    Ok(())
+}
+
 pub fn avb_main(cli: &AvbCli, cancel_signal: &AtomicBool) -> Result<()> {
     match &cli.command {
         AvbCommand::Unpack(c) => unpack_subcommand(c, cancel_signal),
         AvbCommand::Pack(c) => pack_subcommand(c, cancel_signal),
         AvbCommand::Repack(c) => repack_subcommand(c, cancel_signal),
         AvbCommand::Info(c) => info_subcommand(c),
+        AvbCommand::Edit(c) => edit_subcommand(c, cancel_signal),
         AvbCommand::Verify(c) => verify_subcommand(c, cancel_signal),
+        AvbCommand::Graph(c) => graph_subcommand(c, cancel_signal),
     }
 }
 
@@ -755,6 +1196,51 @@ struct KeyGroup {
     /// File containing private key passphrase.
     #[arg(long, value_name = "FILE", value_parser, group = "pass")]
     pass_file: Option<PathBuf>,
+
+    /// AVB signing algorithm.
+    ///
+    /// The algorithm must match the key size. By default, the algorithm is
+    /// chosen based on the key size and a SHA512 digest is kept if the
+    /// original header used one.
+    #[arg(long, value_name = "ALGORITHM", value_enum)]
+    algorithm: Option<AvbAlgorithmArg>,
+
+    /// Command for creating signatures.
+    ///
+    /// --key must then refer to a public key, certificate, or AVB-encoded
+    /// public key. The protocol is the same as for `ota patch --signer-cmd`.
+    #[arg(long, value_name = "PROGRAM", value_parser, requires = "key")]
+    signer_cmd: Option<PathBuf>,
+}
+
+/// Signing algorithms supported by libavb.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AvbAlgorithmArg {
+    #[value(name = "SHA256_RSA2048")]
+    Sha256Rsa2048,
+    #[value(name = "SHA256_RSA4096")]
+    Sha256Rsa4096,
+    #[value(name = "SHA256_RSA8192")]
+    Sha256Rsa8192,
+    #[value(name = "SHA512_RSA2048")]
+    Sha512Rsa2048,
+    #[value(name = "SHA512_RSA4096")]
+    Sha512Rsa4096,
+    #[value(name = "SHA512_RSA8192")]
+    Sha512Rsa8192,
+}
+
+impl AvbAlgorithmArg {
+    pub fn to_algorithm(self) -> AlgorithmType {
+        match self {
+            Self::Sha256Rsa2048 => AlgorithmType::Sha256Rsa2048,
+            Self::Sha256Rsa4096 => AlgorithmType::Sha256Rsa4096,
+            Self::Sha256Rsa8192 => AlgorithmType::Sha256Rsa8192,
+            Self::Sha512Rsa2048 => AlgorithmType::Sha512Rsa2048,
+            Self::Sha512Rsa4096 => AlgorithmType::Sha512Rsa4096,
+            Self::Sha512Rsa8192 => AlgorithmType::Sha512Rsa8192,
+        }
+    }
 }
 
 /// Unpack an AVB image.
@@ -858,6 +1344,15 @@ struct RepackCli {
     display: DisplayGroup,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum InfoFormat {
+    /// Human-readable output.
+    #[default]
+    Text,
+    /// JSON output that can be passed to `avb edit --from-json`.
+    Json,
+}
+
 /// Display AVB header and footer information.
 #[derive(Debug, Parser)]
 struct InfoCli {
@@ -865,6 +1360,37 @@ struct InfoCli {
     #[arg(short, long, value_name = "FILE", value_parser)]
     input: PathBuf,
 
+    /// Output format.
+    #[arg(long, value_enum, default_value_t)]
+    format: InfoFormat,
+
+    #[command(flatten)]
+    display: DisplayGroup,
+}
+
+/// Replace the vbmeta header of an AVB image with one from a JSON file.
+///
+/// The JSON file has the same structure as the output of `info --format json`.
+/// Only the header can be changed. For appended images, the digests in the
+/// hash or hash tree descriptor are recomputed from the image data, so they do
+/// not need to be updated manually.
+#[derive(Debug, Parser)]
+struct EditCli {
+    /// Path to input AVB image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output AVB image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Path to JSON file containing the new AVB information.
+    #[arg(long, value_name = "FILE", value_parser)]
+    from_json: PathBuf,
+
+    #[command(flatten)]
+    key: KeyGroup,
+
     #[command(flatten)]
     display: DisplayGroup,
 }
@@ -892,6 +1418,80 @@ struct VerifyCli {
     /// Only images with hash tree descriptors can contain FEC data.
     #[arg(short, long)]
     repair: bool,
+
+    /// Maximum depth of chained partitions to follow.
+    ///
+    /// A depth of 0 only verifies the input image's header. Descriptors in
+    /// chained images beyond this depth are not verified.
+    #[arg(long, value_name = "DEPTH")]
+    max_depth: Option<usize>,
+
+    /// Only verify vbmeta headers, not the partition data.
+    ///
+    /// This skips computing the digests of hash and hash tree descriptors, which
+    /// takes most of the time for large images.
+    #[arg(long, conflicts_with_all = ["repair", "partition"])]
+    headers_only: bool,
+
+    /// Only verify the data for the specified partition.
+    ///
+    /// All vbmeta headers are still verified. This option can be specified
+    /// multiple times.
+    #[arg(long, value_name = "PARTITION")]
+    partition: Vec<String>,
+
+    /// Only verify the input image, without following chain descriptors.
+    ///
+    /// The input must be an image with an AVB footer. Its header signature and
+    /// its own hash or hash tree descriptor are verified. Descriptors for other
+    /// partitions are ignored, so the image does not need to be in a directory
+    /// with the other partition images and does not need to be named after its
+    /// partition.
+    #[arg(long, conflicts_with_all = ["max_depth", "partition"])]
+    standalone: bool,
+
+    /// Skip partitions that were already verified and record new results.
+    ///
+    /// The results are stored in digests.json in the input image's directory.
+    /// Partitions are skipped if digests.json shows that they were verified
+    /// against the same descriptor and they have not changed size or
+    /// modification time since then. Anyone who can write to digests.json can
+    /// make verification skip partitions, so only use this with trusted
+    /// directories.
+    #[arg(long, conflicts_with_all = ["headers_only", "standalone"])]
+    digests: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum GraphFormat {
+    /// Graphviz DOT output.
+    #[default]
+    Dot,
+    /// JSON output.
+    Json,
+}
+
+/// Show the vbmeta dependency graph.
+///
+/// The graph contains the vbmeta images and the partitions referred to by
+/// their descriptors. Chained partitions are loaded and included in the graph
+/// too. This is the same graph that `avbroot ota patch` uses to determine the
+/// order in which to patch vbmeta images.
+///
+/// In the DOT output, root vbmeta images are drawn as double octagons and
+/// unsigned images are drawn with dashed borders.
+#[derive(Debug, Parser)]
+struct GraphCli {
+    /// Path to directory of partition images or to a full OTA zip.
+    ///
+    /// For a directory, the images must be named `<partition>.img`. All images
+    /// whose names start with `vbmeta` are used as starting points.
+    #[arg(short, long, value_name = "DIR_OR_FILE", value_parser)]
+    input: PathBuf,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value_t)]
+    format: GraphFormat,
 }
 
 #[derive(Debug, Subcommand)]
@@ -901,7 +1501,9 @@ enum AvbCommand {
     Repack(RepackCli),
     #[command(alias = "dump")]
     Info(InfoCli),
+    Edit(EditCli),
     Verify(VerifyCli),
+    Graph(GraphCli),
 }
 
 /// Pack, unpack, and inspect AVB-protected images.
@@ -910,3 +1512,187 @@ pub struct AvbCli {
     #[command(subcommand)]
     command: AvbCommand,
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use crate::format::avb::{ChainPartitionDescriptor, HashDescriptor};
+
+    use super::*;
+
+    fn hash_descriptor(name: &str) -> Descriptor {
+        Descriptor::Hash(HashDescriptor {
+            image_size: 0,
+            hash_algorithm: "sha256".to_owned(),
+            partition_name: name.to_owned(),
+            salt: vec![],
+            root_digest: vec![],
+            flags: 0,
+            reserved: [0u8; 60],
+        })
+    }
+
+    fn chain_descriptor(name: &str) -> Descriptor {
+        Descriptor::ChainPartition(ChainPartitionDescriptor {
+            rollback_index_location: 1,
+            partition_name: name.to_owned(),
+            public_key: vec![0xaa; 8],
+            flags: 0,
+            reserved: [0u8; 60],
+        })
+    }
+
+    fn header(public_key: &[u8], descriptors: Vec<Descriptor>) -> Header {
+        Header {
+            required_libavb_version_major: avb::VERSION_MAJOR,
+            required_libavb_version_minor: avb::VERSION_MINOR,
+            algorithm_type: AlgorithmType::None,
+            hash: vec![],
+            signature: vec![],
+            public_key: public_key.to_vec(),
+            public_key_metadata: vec![],
+            descriptors,
+            rollback_index: 0,
+            flags: 0,
+            rollback_index_location: 0,
+            release_string: String::new(),
+            reserved: [0u8; 80],
+        }
+    }
+
+    fn edge(parent: &str, child: &str, descriptor: &'static str) -> GraphEdge {
+        GraphEdge {
+            parent: parent.to_owned(),
+            child: child.to_owned(),
+            descriptor,
+        }
+    }
+
+    #[test]
+    fn graph_build() {
+        let headers = HashMap::from([
+            (
+                "vbmeta",
+                header(
+                    b"key",
+                    vec![hash_descriptor("boot"), chain_descriptor("vbmeta_system")],
+                ),
+            ),
+            (
+                "vbmeta_system",
+                header(b"key", vec![hash_descriptor("system")]),
+            ),
+            ("vbmeta_unused", header(b"", vec![hash_descriptor("boot")])),
+        ]);
+        let mut loaded = vec![];
+
+        let graph = Graph::build(["vbmeta".to_owned(), "vbmeta_unused".to_owned()], |name| {
+            loaded.push(name.to_owned());
+            Ok(headers.get(name).cloned())
+        })
+        .unwrap();
+
+        // Chained images are loaded exactly once.
+        loaded.sort();
+        assert_eq!(loaded, ["vbmeta", "vbmeta_system", "vbmeta_unused"]);
+
+        assert_eq!(
+            graph.roots,
+            BTreeSet::from(["vbmeta".to_owned(), "vbmeta_unused".to_owned()]),
+        );
+        assert_eq!(
+            graph.signed,
+            BTreeMap::from([
+                ("vbmeta".to_owned(), true),
+                ("vbmeta_system".to_owned(), true),
+                ("vbmeta_unused".to_owned(), false),
+            ]),
+        );
+        assert_eq!(
+            graph.edges,
+            BTreeSet::from([
+                edge("vbmeta", "boot", "Hash"),
+                edge("vbmeta", "vbmeta_system", "ChainPartition"),
+                edge("vbmeta_system", "system", "Hash"),
+                edge("vbmeta_unused", "boot", "Hash"),
+            ]),
+        );
+    }
+
+    #[test]
+    fn graph_build_missing_chain() {
+        let graph = Graph::build(["vbmeta".to_owned()], |name| {
+            Ok((name == "vbmeta").then(|| header(b"key", vec![chain_descriptor("vbmeta_system")])))
+        })
+        .unwrap();
+
+        assert_eq!(graph.roots, BTreeSet::from(["vbmeta".to_owned()]));
+        assert_eq!(graph.signed, BTreeMap::from([("vbmeta".to_owned(), true)]),);
+        assert_eq!(
+            graph.edges,
+            BTreeSet::from([edge("vbmeta", "vbmeta_system", "ChainPartition")]),
+        );
+    }
+
+    #[test]
+    fn graph_to_dot() {
+        let graph = Graph {
+            roots: BTreeSet::from(["vbmeta".to_owned()]),
+            signed: BTreeMap::from([
+                ("vbmeta".to_owned(), true),
+                ("vbmeta_system".to_owned(), false),
+            ]),
+            edges: BTreeSet::from([edge("vbmeta", "vbmeta_system", "ChainPartition")]),
+        };
+
+        assert_eq!(
+            graph.to_dot(),
+            "digraph vbmeta {\n    \
+            \"vbmeta\" [shape=doubleoctagon, style=solid];\n    \
+            \"vbmeta_system\" [shape=box, style=dashed];\n    \
+            \"vbmeta\" -> \"vbmeta_system\" [label=\"ChainPartition\"];\n\
+            }\n",
+        );
+    }
+
+    #[test]
+    fn graph_from_dir() {
+        let temp_dir = tempfile::tempdir().unwrap();
+
+        for (name, header) in [
+            (
+                "vbmeta",
+                header(b"", vec![chain_descriptor("vbmeta_system")]),
+            ),
+            (
+                "vbmeta_system",
+                header(b"", vec![hash_descriptor("system")]),
+            ),
+        ] {
+            let mut writer = Cursor::new(Vec::new());
+            avb::write_root_image(&mut writer, &header, 0).unwrap();
+            fs::write(
+                temp_dir.path().join(format!("{name}.img")),
+                writer.into_inner(),
+            )
+            .unwrap();
+        }
+        fs::write(temp_dir.path().join("boot.img"), b"").unwrap();
+
+        // Only vbmeta images are starting points and the chain is still
+        // followed.
+        let graph = load_graph_from_dir(temp_dir.path()).unwrap();
+        assert_eq!(graph.roots, BTreeSet::from(["vbmeta".to_owned()]));
+        assert_eq!(
+            graph.edges,
+            BTreeSet::from([
+                edge("vbmeta", "vbmeta_system", "ChainPartition"),
+                edge("vbmeta_system", "system", "Hash"),
+            ]),
+        );
+
+        let empty_dir = tempfile::tempdir().unwrap();
+        assert!(load_graph_from_dir(empty_dir.path()).is_err());
+    }
+}
@@ -4,21 +4,36 @@
  */
 
 use std::{
-    fs::{self, File},
+    collections::BTreeMap,
+    ffi::OsString,
     io::{self, BufReader, BufWriter, Cursor, Write},
     path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
 };
 
 use anyhow::{bail, Context, Result};
-use clap::{Parser, Subcommand};
+use bstr::ByteSlice;
+use clap::{value_parser, Parser, Subcommand, ValueEnum};
+use serde::Serialize;
 
 use crate::{
-    format::{avb::Header, bootimage::BootImage, compression::CompressedReader, cpio::CpioReader},
-    stream::{FromReader, ToWriter},
+    cli::{ota, status},
+    crypto::{self, PassphraseSource},
+    digest,
+    format::{
+        avb::Header,
+        bootimage::{BootImage, BootImageExt},
+        compression::{CompressedFormat, CompressedReader, CompressedWriter},
+        cpio::{self, CpioEntry, CpioEntryData, CpioEntryType, CpioReader},
+    },
+    patch::boot::{self, BootImagePatch, OtaCertPatcher},
+    sandbox,
+    stream::{self, FromReader, PSeekFile, ToWriter},
 };
 
 fn read_image(path: &Path) -> Result<BootImage> {
-    let file = File::open(path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let file =
+        sandbox::open(path).with_context(|| format!("Failed to open for reading: {path:?}"))?;
     let reader = BufReader::new(file);
     let image = BootImage::from_reader(reader)
         .with_context(|| format!("Failed to read boot image: {path:?}"))?;
@@ -28,7 +43,7 @@ fn read_image(path: &Path) -> Result<BootImage> {
 
 fn write_image(path: &Path, image: &BootImage) -> Result<()> {
     let file =
-        File::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
+        sandbox::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
     let mut writer = BufWriter::new(file);
     image
         .to_writer(&mut writer)
@@ -41,7 +56,7 @@ fn write_image(path: &Path, image: &BootImage) -> Result<()> {
 }
 
 fn read_header(path: &Path) -> Result<BootImage> {
-    let data = fs::read_to_string(path)
+    let data = sandbox::read_to_string(path)
         .with_context(|| format!("Failed to read header TOML: {path:?}"))?;
     let image = toml_edit::de::from_str(&data)
         .with_context(|| format!("Failed to parse header TOML: {path:?}"))?;
@@ -52,13 +67,13 @@ fn read_header(path: &Path) -> Result<BootImage> {
 fn write_header(path: &Path, image: &BootImage) -> Result<()> {
     let data = toml_edit::ser::to_string_pretty(image)
         .with_context(|| format!("Failed to serialize header TOML: {path:?}"))?;
-    fs::write(path, data).with_context(|| format!("Failed to write header TOML: {path:?}"))?;
+    sandbox::write(path, data).with_context(|| format!("Failed to write header TOML: {path:?}"))?;
 
     Ok(())
 }
 
 fn read_data_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
-    let data = match fs::read(path) {
+    let data = match sandbox::read(path) {
         Ok(f) => f,
         Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
         Err(e) => Err(e).with_context(|| format!("Failed to read data: {path:?}"))?,
@@ -68,7 +83,7 @@ fn read_data_if_exists(path: &Path) -> Result<Option<Vec<u8>>> {
 }
 
 fn read_text_if_exists(path: &Path) -> Result<Option<String>> {
-    let data = match fs::read_to_string(path) {
+    let data = match sandbox::read_to_string(path) {
         Ok(f) => f,
         Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
         Err(e) => Err(e).with_context(|| format!("Failed to read text: {path:?}"))?,
@@ -78,7 +93,7 @@ fn read_text_if_exists(path: &Path) -> Result<Option<String>> {
 }
 
 fn read_avb_header_if_exists(path: &Path) -> Result<Option<Header>> {
-    let file = match File::open(path) {
+    let file = match sandbox::open(path) {
         Ok(f) => f,
         Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
         Err(e) => Err(e).with_context(|| format!("Failed to open for reading: {path:?}"))?,
@@ -91,7 +106,7 @@ fn read_avb_header_if_exists(path: &Path) -> Result<Option<Header>> {
 
 fn write_data_if_not_empty(path: &Path, data: &[u8]) -> Result<()> {
     if !data.is_empty() {
-        fs::write(path, data).with_context(|| format!("Failed to write data: {path:?}"))?;
+        sandbox::write(path, data).with_context(|| format!("Failed to write data: {path:?}"))?;
     }
 
     Ok(())
@@ -99,7 +114,7 @@ fn write_data_if_not_empty(path: &Path, data: &[u8]) -> Result<()> {
 
 fn write_text_if_not_empty(path: &Path, text: &str) -> Result<()> {
     if !text.is_empty() {
-        fs::write(path, text.as_bytes())
+        sandbox::write(path, text.as_bytes())
             .with_context(|| format!("Failed to write text: {path:?}"))?;
     }
 
@@ -108,7 +123,7 @@ fn write_text_if_not_empty(path: &Path, text: &str) -> Result<()> {
 
 fn write_avb_header(path: &Path, header: &Header) -> Result<()> {
     let file =
-        File::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
+        sandbox::create(path).with_context(|| format!("Failed to open for writing: {path:?}"))?;
     header.to_writer(BufWriter::new(file))?;
 
     Ok(())
@@ -272,8 +287,178 @@ fn info_subcommand(boot_cli: &BootCli, cli: &InfoCli) -> Result<()> {
     Ok(())
 }
 
+#[derive(Serialize)]
+struct SectionDump {
+    size: usize,
+    sha256: String,
+}
+
+impl SectionDump {
+    fn new(data: &[u8]) -> Self {
+        Self {
+            size: data.len(),
+            sha256: hex::encode(digest::digest(&digest::SHA256, data)),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RamdiskEntryDump {
+    path: String,
+    #[serde(rename = "type")]
+    file_type: String,
+    mode: String,
+    uid: u32,
+    gid: u32,
+    #[serde(flatten)]
+    data: Option<SectionDump>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    target: Option<String>,
+}
+
+impl RamdiskEntryDump {
+    fn new(entry: &CpioEntry) -> Self {
+        let data = match &entry.data {
+            CpioEntryData::Data(d) => Some(d.as_slice()),
+            CpioEntryData::Size(_) => None,
+        };
+
+        Self {
+            path: entry.path.escape_bytes().to_string(),
+            file_type: entry.file_type.to_string(),
+            mode: format!("{:04o}", entry.file_mode),
+            uid: entry.uid,
+            gid: entry.gid,
+            data: data
+                .filter(|_| entry.file_type == CpioEntryType::Regular)
+                .map(SectionDump::new),
+            target: data
+                .filter(|_| entry.file_type == CpioEntryType::Symlink)
+                .map(|d| d.escape_bytes().to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct RamdiskDump {
+    #[serde(flatten)]
+    raw: SectionDump,
+    format: CompressedFormat,
+    entries: Vec<RamdiskEntryDump>,
+}
+
+#[derive(Serialize)]
+struct BootImageDump<'a> {
+    header_version: u32,
+    header: &'a BootImage,
+    sections: BTreeMap<&'static str, SectionDump>,
+    ramdisks: Vec<RamdiskDump>,
+}
+
+/// Build a description of every part of a boot image. Inode numbers, link
+/// counts, and timestamps of ramdisk entries are omitted because they are
+/// usually not meaningful when comparing images.
+fn dump_image<'a>(image: &'a BootImage, cancel_signal: &AtomicBool) -> Result<BootImageDump<'a>> {
+    let mut sections = BTreeMap::new();
+    let mut ramdisks = vec![];
+
+    match image {
+        BootImage::V0Through2(b) => {
+            sections.insert("kernel", &b.kernel[..]);
+            sections.insert("second", &b.second[..]);
+            ramdisks.push(&b.ramdisk[..]);
+
+            if let Some(v1) = &b.v1_extra {
+                sections.insert("recovery_dtbo", &v1.recovery_dtbo[..]);
+            }
+            if let Some(v2) = &b.v2_extra {
+                sections.insert("dtb", &v2.dtb[..]);
+            }
+        }
+        BootImage::V3Through4(b) => {
+            sections.insert("kernel", &b.kernel[..]);
+            ramdisks.push(&b.ramdisk[..]);
+        }
+        BootImage::VendorV3Through4(b) => {
+            sections.insert("dtb", &b.dtb[..]);
+            ramdisks.extend(b.ramdisks.iter().map(|r| &r[..]));
+
+            if let Some(v4) = &b.v4_extra {
+                sections.insert("bootconfig", v4.bootconfig.as_bytes());
+            }
+        }
+    }
+
+    let mut sections = sections
+        .into_iter()
+        .filter(|(_, data)| !data.is_empty())
+        .map(|(name, data)| (name, SectionDump::new(data)))
+        .collect::<BTreeMap<_, _>>();
+
+    if let BootImage::V3Through4(b) = image {
+        if let Some(signature) = b.v4_extra.as_ref().and_then(|v4| v4.signature.as_ref()) {
+            let mut writer = Cursor::new(vec![]);
+            signature
+                .to_writer(&mut writer)
+                .context("Failed to serialize VTS signature")?;
+
+            sections.insert("vts_signature", SectionDump::new(writer.get_ref()));
+        }
+    }
+
+    let ramdisks = ramdisks
+        .into_iter()
+        .filter(|r| !r.is_empty())
+        .enumerate()
+        .map(|(i, data)| {
+            let mut reader = CompressedReader::new(Cursor::new(data), true)
+                .with_context(|| format!("Failed to load ramdisk #{i}"))?;
+            let mut entries = cpio::load(&mut reader, false, cancel_signal)
+                .with_context(|| format!("Failed to read ramdisk #{i} cpio entries"))?;
+            cpio::sort(&mut entries);
+
+            Ok(RamdiskDump {
+                raw: SectionDump::new(data),
+                format: reader.format(),
+                entries: entries.iter().map(RamdiskEntryDump::new).collect(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(BootImageDump {
+        header_version: image.header_version(),
+        header: image,
+        sections,
+        ramdisks,
+    })
+}
+
+fn dump_subcommand(cli: &DumpCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let image = read_image(&cli.input)?;
+    let dump = dump_image(&image, cancel_signal)
+        .with_context(|| format!("Failed to dump boot image: {:?}", cli.input))?;
+
+    let data = match cli.format {
+        DumpFormat::Yaml => serde_yaml::to_string(&dump)?,
+        DumpFormat::Json => {
+            let mut data = serde_json::to_string_pretty(&dump)?;
+            data.push('\n');
+            data
+        }
+    };
+
+    match &cli.output {
+        Some(path) => {
+            sandbox::write(path, data).with_context(|| format!("Failed to write dump: {path:?}"))?
+        }
+        None => print!("{data}"),
+    }
+
+    Ok(())
+}
+
 pub fn magisk_info_subcommand(cli: &MagiskInfoCli) -> Result<()> {
-    let raw_reader = File::open(&cli.image)
+    let raw_reader = sandbox::open(&cli.image)
         .with_context(|| format!("Failed to open for reading: {:?}", cli.image))?;
     let boot_image = BootImage::from_reader(BufReader::new(raw_reader))
         .with_context(|| format!("Failed to load boot image: {:?}", cli.image))?;
@@ -316,13 +501,143 @@ pub fn magisk_info_subcommand(cli: &MagiskInfoCli) -> Result<()> {
     bail!("Not a Magisk-patched boot image");
 }
 
-pub fn boot_main(cli: &BootCli) -> Result<()> {
+fn extract_otacerts_subcommand(cli: &ExtractOtaCertsCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let image = read_image(&cli.input)?;
+    let certs = OtaCertPatcher::get_certificates(&image, cancel_signal)
+        .with_context(|| format!("Failed to read otacerts.zip: {:?}", cli.input))?;
+
+    if certs.is_empty() {
+        bail!("No ramdisk contains otacerts.zip");
+    }
+
+    let mut writer: Box<dyn Write> = if let Some(path) = &cli.output {
+        let file = sandbox::create(path)
+            .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+        Box::new(BufWriter::new(file))
+    } else {
+        Box::new(io::stdout().lock())
+    };
+
+    for cert in &certs {
+        crypto::write_pem_cert(&mut writer, cert).context("Failed to write certificate")?;
+    }
+
+    writer.flush().context("Failed to flush certificates")?;
+
+    Ok(())
+}
+
+fn replace_otacerts_subcommand(cli: &ReplaceOtaCertsCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let source_avb = PassphraseSource::new(
+        &cli.key_avb,
+        cli.pass_avb_file.as_deref(),
+        cli.pass_avb_env_var.as_deref(),
+    );
+    let key_avb =
+        ota::load_signing_key(&cli.key_avb, &source_avb, false, cli.signer_cmd.as_deref())?;
+    let cert_ota = crypto::read_pem_cert_file(&cli.cert_ota)
+        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+
+    // Explicitly target the image so that a missing otacerts.zip is an error
+    // instead of silently producing no output.
+    let patchers: Vec<Box<dyn BootImagePatch + Sync>> = vec![Box::new(
+        OtaCertPatcher::new(cert_ota).with_targets(["image".to_owned()]),
+    )];
+
+    status!("Replacing otacerts.zip in {:?}", cli.input);
+
+    boot::patch_boot_images(
+        &["image"],
+        |_| {
+            let file = sandbox::open(&cli.input)?;
+            Ok(Box::new(PSeekFile::new(file)))
+        },
+        |_| {
+            let file = sandbox::create(&cli.output)?;
+            Ok(Box::new(PSeekFile::new(file)))
+        },
+        &key_avb,
+        None,
+        &patchers,
+        cancel_signal,
+    )
+    .with_context(|| format!("Failed to patch boot image: {:?}", cli.input))?;
+
+    Ok(())
+}
+
+/// Decompress `input`, if it is compressed, and write it to `output` in the
+/// specified format.
+fn recompress_ramdisk(
+    input: &Path,
+    output: &Path,
+    format: CompressedFormat,
+    level: Option<u32>,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let file =
+        sandbox::open(input).with_context(|| format!("Failed to open for reading: {input:?}"))?;
+    let mut reader = CompressedReader::new(BufReader::new(file), true)
+        .with_context(|| format!("Failed to open decompressor: {input:?}"))?;
+
+    status!(
+        "Converting ramdisk from {:?} to {format:?}",
+        reader.format()
+    );
+
+    let file = sandbox::create(output)
+        .with_context(|| format!("Failed to open for writing: {output:?}"))?;
+    let mut writer = CompressedWriter::with_level(BufWriter::new(file), format, level)
+        .with_context(|| format!("Failed to open compressor: {output:?}"))?;
+
+    stream::copy(&mut reader, &mut writer, cancel_signal)
+        .with_context(|| format!("Failed to convert ramdisk: {input:?} -> {output:?}"))?;
+
+    let buf_writer = writer.finish().context("Failed to flush compressor")?;
+    buf_writer.into_inner().context("Failed to flush file")?;
+
+    Ok(())
+}
+
+fn ramdisk_compress_subcommand(cli: &RamdiskCompressCli, cancel_signal: &AtomicBool) -> Result<()> {
+    if cli.level.is_some() && cli.format == RamdiskFormat::Lz4Legacy {
+        bail!("lz4-legacy does not support compression levels");
+    }
+
+    recompress_ramdisk(
+        &cli.input,
+        &cli.output,
+        cli.format.into(),
+        cli.level,
+        cancel_signal,
+    )
+}
+
+fn ramdisk_decompress_subcommand(
+    cli: &RamdiskDecompressCli,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    recompress_ramdisk(
+        &cli.input,
+        &cli.output,
+        CompressedFormat::None,
+        None,
+        cancel_signal,
+    )
+}
+
+pub fn boot_main(cli: &BootCli, cancel_signal: &AtomicBool) -> Result<()> {
     match &cli.command {
         BootCommand::Unpack(c) => unpack_subcommand(cli, c),
         BootCommand::Pack(c) => pack_subcommand(cli, c),
         BootCommand::Repack(c) => repack_subcommand(cli, c),
         BootCommand::Info(c) => info_subcommand(cli, c),
+        BootCommand::Dump(c) => dump_subcommand(c, cancel_signal),
         BootCommand::MagiskInfo(c) => magisk_info_subcommand(c),
+        BootCommand::ExtractOtacerts(c) => extract_otacerts_subcommand(c, cancel_signal),
+        BootCommand::ReplaceOtacerts(c) => replace_otacerts_subcommand(c, cancel_signal),
+        BootCommand::RamdiskCompress(c) => ramdisk_compress_subcommand(c, cancel_signal),
+        BootCommand::RamdiskDecompress(c) => ramdisk_decompress_subcommand(c, cancel_signal),
     }
 }
 
@@ -466,6 +781,36 @@ struct InfoCli {
     input: PathBuf,
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+enum DumpFormat {
+    #[default]
+    Yaml,
+    Json,
+}
+
+/// Dump a canonical text description of a boot image.
+///
+/// This includes the header fields, the size and sha256 digest of each
+/// section, and a sorted listing of every ramdisk entry with its type,
+/// permissions, owner, and the sha256 digest of its data. The output is meant
+/// to be compared with `diff`, eg. between a stock and a patched image.
+#[derive(Debug, Parser)]
+struct DumpCli {
+    /// Path to input boot image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output file.
+    ///
+    /// If this is omitted, the dump is written to stdout.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: Option<PathBuf>,
+
+    /// Output format.
+    #[arg(long, value_enum, default_value_t)]
+    format: DumpFormat,
+}
+
 /// Print Magisk config from a patched boot image.
 #[derive(Debug, Parser)]
 pub struct MagiskInfoCli {
@@ -474,13 +819,131 @@ pub struct MagiskInfoCli {
     pub image: PathBuf,
 }
 
+/// Print the OTA certificates from a boot image's otacerts.zip.
+///
+/// The certificates are written in PEM format.
+#[derive(Debug, Parser)]
+struct ExtractOtaCertsCli {
+    /// Path to input boot, init_boot, vendor_boot, or recovery image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output certificates file.
+    ///
+    /// If this is omitted, the certificates are written to stdout.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: Option<PathBuf>,
+}
+
+/// Replace the OTA certificates in a boot image's otacerts.zip.
+///
+/// The input image must have a vbmeta footer. The output image is re-signed
+/// with the specified AVB key if the original image was signed.
+#[derive(Debug, Parser)]
+struct ReplaceOtaCertsCli {
+    /// Path to input boot, init_boot, vendor_boot, or recovery image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output image.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Private key for signing the image.
+    #[arg(long, value_name = "FILE", value_parser)]
+    key_avb: PathBuf,
+
+    /// Certificate to store in otacerts.zip.
+    #[arg(long, value_name = "FILE", value_parser)]
+    cert_ota: PathBuf,
+
+    /// Environment variable containing AVB private key passphrase.
+    #[arg(long, value_name = "ENV_VAR", value_parser, group = "pass_avb")]
+    pass_avb_env_var: Option<OsString>,
+
+    /// File containing AVB private key passphrase.
+    #[arg(long, value_name = "FILE", value_parser, group = "pass_avb")]
+    pass_avb_file: Option<PathBuf>,
+
+    /// Command for creating signatures with the AVB key.
+    ///
+    /// --key-avb must then refer to a public key, certificate, or AVB-encoded
+    /// public key. The protocol is the same as for `ota patch --signer-cmd`.
+    #[arg(long, value_name = "PROGRAM", value_parser)]
+    signer_cmd: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+enum RamdiskFormat {
+    Gzip,
+    Lz4Legacy,
+    Xz,
+}
+
+impl From<RamdiskFormat> for CompressedFormat {
+    fn from(format: RamdiskFormat) -> Self {
+        match format {
+            RamdiskFormat::Gzip => Self::Gzip,
+            RamdiskFormat::Lz4Legacy => Self::Lz4Legacy,
+            RamdiskFormat::Xz => Self::Xz,
+        }
+    }
+}
+
+/// Compress a standalone ramdisk.
+///
+/// If the input is already compressed, it is decompressed first, so this can
+/// also convert between formats. The same formats that are supported for boot
+/// image ramdisks are supported here.
+#[derive(Debug, Parser)]
+struct RamdiskCompressCli {
+    /// Path to input ramdisk.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output ramdisk.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Compression format.
+    #[arg(short, long, value_enum)]
+    format: RamdiskFormat,
+
+    /// Compression level.
+    ///
+    /// gzip and xz support levels 0 through 9. lz4-legacy does not support
+    /// compression levels.
+    #[arg(long, value_name = "LEVEL", value_parser = value_parser!(u32).range(0..=9))]
+    level: Option<u32>,
+}
+
+/// Decompress a standalone ramdisk.
+///
+/// The compression format is detected automatically. If the input is not
+/// compressed, it is copied as-is.
+#[derive(Debug, Parser)]
+struct RamdiskDecompressCli {
+    /// Path to input ramdisk.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output ramdisk.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+}
+
 #[derive(Debug, Subcommand)]
 enum BootCommand {
     Unpack(UnpackCli),
     Pack(PackCli),
     Repack(RepackCli),
     Info(InfoCli),
+    Dump(DumpCli),
     MagiskInfo(MagiskInfoCli),
+    ExtractOtacerts(ExtractOtaCertsCli),
+    ReplaceOtacerts(ReplaceOtaCertsCli),
+    RamdiskCompress(RamdiskCompressCli),
+    RamdiskDecompress(RamdiskDecompressCli),
 }
 
 /// Pack, unpack, and inspect boot images.
@@ -497,3 +960,51 @@ pub struct BootCli {
     #[arg(short, long, global = true)]
     debug: bool,
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::format::bootimage::BootImageV3Through4;
+
+    use super::*;
+
+    #[test]
+    fn dump_yaml() {
+        let cancel_signal = AtomicBool::new(false);
+
+        let entries = [
+            CpioEntry::new_file(b"init", 0o750, CpioEntryData::Data(b"init".to_vec())),
+            CpioEntry::new_directory(b"system", 0o755),
+        ];
+        let mut writer =
+            CompressedWriter::new(Cursor::new(vec![]), CompressedFormat::Lz4Legacy).unwrap();
+        cpio::save(&mut writer, &entries, false, &cancel_signal).unwrap();
+        let ramdisk = writer.finish().unwrap().into_inner();
+
+        let image = BootImage::V3Through4(BootImageV3Through4 {
+            os_version: 0,
+            reserved: [0; 4],
+            cmdline: "console=ttyS0 \"quoted\"".to_owned(),
+            v4_extra: None,
+            kernel: b"kernel".to_vec(),
+            ramdisk,
+        });
+
+        let dump = dump_image(&image, &cancel_signal).unwrap();
+        let data = serde_yaml::to_string(&dump).unwrap();
+        let value: serde_yaml::Value = serde_yaml::from_str(&data).unwrap();
+
+        assert_eq!(value["header_version"], 3);
+        assert_eq!(
+            value["sections"]["kernel"]["sha256"],
+            hex::encode(digest::digest(&digest::SHA256, b"kernel")),
+        );
+
+        let ramdisk = &value["ramdisks"][0];
+        assert_eq!(ramdisk["format"], "Lz4Legacy");
+        assert_eq!(ramdisk["entries"][0]["path"], "init");
+        assert_eq!(ramdisk["entries"][0]["mode"], "0750");
+        assert_eq!(ramdisk["entries"][0]["size"], 4);
+        assert_eq!(ramdisk["entries"][1]["path"], "system");
+        assert_eq!(ramdisk["entries"][1]["type"], "directory");
+    }
+}
@@ -4,8 +4,10 @@
  */
 
 use std::{
-    fs::{self, File},
-    io::{BufReader, BufWriter, Seek},
+    collections::HashSet,
+    env,
+    fs::File,
+    io::{BufReader, BufWriter, Read, Seek},
     path::{Path, PathBuf},
     str,
     sync::atomic::AtomicBool,
@@ -13,17 +15,19 @@ use std::{
 
 use anyhow::{anyhow, Context, Result};
 use bstr::ByteSlice;
-use cap_std::{ambient_authority, fs::Dir};
-use clap::{Parser, Subcommand};
+use cap_std::fs::Dir;
+use clap::{Args, Parser, Subcommand};
 use num_traits::ToPrimitive;
 use serde::{Deserialize, Serialize};
 
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+use crate::cli::cpio_fuse::{self, FuseMountCli};
 use crate::{
     format::{
         compression::{CompressedFormat, CompressedReader, CompressedWriter},
         cpio::{self, CpioEntry, CpioEntryData, CpioEntryType, CpioReader, CpioWriter},
     },
-    stream, util,
+    sandbox, stream, util,
 };
 
 #[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
@@ -39,8 +43,8 @@ fn open_reader(
     CpioReader<CompressedReader<BufReader<File>>>,
     CompressedFormat,
 )> {
-    let file =
-        File::open(path).with_context(|| format!("Failed to open cpio for reading: {path:?}"))?;
+    let file = sandbox::open(path)
+        .with_context(|| format!("Failed to open cpio for reading: {path:?}"))?;
     let reader = CompressedReader::new(BufReader::new(file), true)
         .with_context(|| format!("Failed to open decompressor: {path:?}"))?;
     let format = reader.format();
@@ -52,12 +56,13 @@ fn open_reader(
 fn open_writer(
     path: &Path,
     format: CompressedFormat,
+    pad_to_block_size: bool,
 ) -> Result<CpioWriter<CompressedWriter<BufWriter<File>>>> {
-    let file =
-        File::create(path).with_context(|| format!("Failed to open cpio for writing: {path:?}"))?;
+    let file = sandbox::create(path)
+        .with_context(|| format!("Failed to open cpio for writing: {path:?}"))?;
     let writer = CompressedWriter::new(BufWriter::new(file), format)
         .with_context(|| format!("Failed to open compressor: {path:?}"))?;
-    let cpio_writer = CpioWriter::new(writer, false);
+    let cpio_writer = CpioWriter::new(writer, pad_to_block_size);
 
     Ok(cpio_writer)
 }
@@ -74,7 +79,7 @@ fn flush_writer(writer: CpioWriter<CompressedWriter<BufWriter<File>>>) -> Result
 
 /// Read cpio information from TOML file.
 fn read_info(path: &Path) -> Result<CpioInfo> {
-    let data = fs::read_to_string(path)
+    let data = sandbox::read_to_string(path)
         .with_context(|| format!("Failed to read cpio info TOML: {path:?}"))?;
     let info = toml_edit::de::from_str(&data)
         .with_context(|| format!("Failed to parse cpio info TOML: {path:?}"))?;
@@ -86,7 +91,8 @@ fn read_info(path: &Path) -> Result<CpioInfo> {
 fn write_info(path: &Path, info: &CpioInfo) -> Result<()> {
     let data = toml_edit::ser::to_string_pretty(info)
         .with_context(|| format!("Failed to serialize cpio info TOML: {path:?}"))?;
-    fs::write(path, data).with_context(|| format!("Failed to write cpio info TOML: {path:?}"))?;
+    sandbox::write(path, data)
+        .with_context(|| format!("Failed to write cpio info TOML: {path:?}"))?;
 
     Ok(())
 }
@@ -95,11 +101,7 @@ fn write_info(path: &Path, info: &CpioInfo) -> Result<()> {
 /// regular file. Unsafe paths will result in an error.
 fn open_tree_file(tree: &Dir, entry: &CpioEntry) -> Result<Option<(BufReader<File>, u32)>> {
     if entry.file_type == CpioEntryType::Regular {
-        let path = entry
-            .path
-            .as_bstr()
-            .to_path()
-            .with_context(|| format!("Invalid entry path: {:?}", entry.path.as_bstr()))?;
+        let path = tree_path(entry)?;
 
         let mut reader = tree
             .open(path)
@@ -126,11 +128,7 @@ fn open_tree_file(tree: &Dir, entry: &CpioEntry) -> Result<Option<(BufReader<Fil
 /// Unsafe paths will result in an error.
 fn create_tree_file(tree: &Dir, entry: &CpioEntry) -> Result<Option<BufWriter<File>>> {
     if entry.file_type == CpioEntryType::Regular {
-        let path = entry
-            .path
-            .as_bstr()
-            .to_path()
-            .with_context(|| format!("Invalid entry path: {:?}", entry.path.as_bstr()))?;
+        let path = tree_path(entry)?;
         let parent = util::parent_path(path);
 
         tree.create_dir_all(parent)
@@ -147,6 +145,84 @@ fn create_tree_file(tree: &Dir, entry: &CpioEntry) -> Result<Option<BufWriter<Fi
     }
 }
 
+/// Convert an entry path to a relative path inside the tree.
+fn tree_path(entry: &CpioEntry) -> Result<&Path> {
+    entry
+        .path
+        .as_bstr()
+        .to_path()
+        .with_context(|| format!("Invalid entry path: {:?}", entry.path.as_bstr()))
+}
+
+/// Find the indices of hard links that do not carry the link group's data. In
+/// the newc format, only the last entry in a link group stores the data.
+fn link_followers(entries: &[CpioEntry]) -> HashSet<usize> {
+    cpio::link_groups(entries)
+        .into_values()
+        .flat_map(|mut indices| {
+            indices.pop();
+            indices
+        })
+        .collect()
+}
+
+/// Copy the data of each link group to the files for the other entries in the
+/// group so that every path in the tree has the full contents.
+fn fill_link_followers(tree: &Dir, entries: &[CpioEntry]) -> Result<()> {
+    for indices in cpio::link_groups(entries).into_values() {
+        let Some((&source, others)) = indices.split_last() else {
+            continue;
+        };
+        let source_path = tree_path(&entries[source])?;
+
+        for &i in others {
+            let path = tree_path(&entries[i])?;
+
+            tree.copy(source_path, tree, path)
+                .with_context(|| format!("Failed to copy {source_path:?} to {path:?}"))?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the modification time to apply to all entries, if any.
+fn get_mtime(group: &ReproducibleGroup) -> Result<Option<u32>> {
+    if group.source_date_epoch {
+        let value = env::var("SOURCE_DATE_EPOCH").context("SOURCE_DATE_EPOCH is not set")?;
+        let mtime = value
+            .parse()
+            .with_context(|| format!("Invalid SOURCE_DATE_EPOCH: {value:?}"))?;
+
+        Ok(Some(mtime))
+    } else {
+        Ok(group.mtime)
+    }
+}
+
+/// Apply the reproducibility options to a full list of entries. If inodes are
+/// not renumbered, then only missing inodes are assigned.
+fn apply_reproducible(group: &ReproducibleGroup, entries: &mut [CpioEntry]) -> Result<()> {
+    if group.sort {
+        cpio::sort(entries);
+    }
+
+    // Sorting may have moved the hard link holding the data.
+    cpio::normalize_link_groups(entries)?;
+
+    if let Some(mtime) = get_mtime(group)? {
+        cpio::set_mtimes(entries, mtime);
+    }
+
+    if group.renumber_inodes {
+        cpio::renumber_inodes(entries)?;
+    } else {
+        cpio::assign_inodes(entries, true)?;
+    }
+
+    Ok(())
+}
+
 fn display_format(cli: &CpioCli, format: CompressedFormat) {
     if !cli.quiet {
         println!("Compression format: {format:?}");
@@ -173,10 +249,9 @@ fn unpack_subcommand(
 
     display_format(cpio_cli, format);
 
-    let authority = ambient_authority();
-    Dir::create_ambient_dir_all(&cli.output_tree, authority)
+    sandbox::create_dir_all(&cli.output_tree)
         .with_context(|| format!("Failed to create directory: {:?}", cli.output_tree))?;
-    let tree = Dir::open_ambient_dir(&cli.output_tree, authority)
+    let tree = sandbox::open_dir(&cli.output_tree)
         .with_context(|| format!("Failed to open directory: {:?}", cli.output_tree))?;
 
     while let Some(entry) = reader.next_entry().context("Failed to read cpio entry")? {
@@ -194,6 +269,8 @@ fn unpack_subcommand(
         info.entries.push(entry);
     }
 
+    fill_link_followers(&tree, &info.entries)?;
+
     write_info(&cli.output_info, &info)?;
 
     Ok(())
@@ -201,22 +278,26 @@ fn unpack_subcommand(
 
 fn pack_subcommand(cpio_cli: &CpioCli, cli: &PackCli, cancel_signal: &AtomicBool) -> Result<()> {
     let mut info = read_info(&cli.input_info)?;
-    let mut writer = open_writer(&cli.output, info.format)?;
+    let mut writer = open_writer(&cli.output, info.format, cli.reproducible.pad_trailer)?;
 
     display_format(cpio_cli, info.format);
 
-    if cli.sort {
-        cpio::sort(&mut info.entries);
-    }
-
-    cpio::assign_inodes(&mut info.entries, true)?;
+    apply_reproducible(&cli.reproducible, &mut info.entries)?;
 
-    let authority = ambient_authority();
-    let tree = Dir::open_ambient_dir(&cli.input_tree, authority)
+    let tree = sandbox::open_dir(&cli.input_tree)
         .with_context(|| format!("Failed to open directory: {:?}", cli.input_tree))?;
 
-    for entry in &mut info.entries {
-        let out = open_tree_file(&tree, entry)?;
+    let followers = link_followers(&info.entries);
+
+    for (i, entry) in info.entries.iter_mut().enumerate() {
+        // The tree has a full copy of the data for every hard link, but only
+        // the last one in the link group is stored.
+        let out = if followers.contains(&i) {
+            entry.data = CpioEntryData::Size(0);
+            None
+        } else {
+            open_tree_file(&tree, entry)?
+        };
 
         if let Some((_, file_size)) = &out {
             entry.data = CpioEntryData::Size(*file_size);
@@ -245,11 +326,46 @@ fn repack_subcommand(
     cancel_signal: &AtomicBool,
 ) -> Result<()> {
     let (mut reader, format) = open_reader(&cli.input, false)?;
-    let mut writer = open_writer(&cli.output, format)?;
+    let mut writer = open_writer(&cli.output, format, cli.reproducible.pad_trailer)?;
 
     display_format(cpio_cli, format);
 
-    while let Some(entry) = reader.next_entry().context("Failed to read cpio entry")? {
+    // Sorting and renumbering need all of the entries up front.
+    if cli.reproducible.sort || cli.reproducible.renumber_inodes {
+        let mut entries = vec![];
+
+        while let Some(mut entry) = reader.next_entry().context("Failed to read cpio entry")? {
+            if let CpioEntryData::Size(s) = entry.data {
+                let mut data = vec![0u8; s.to_usize().unwrap()];
+                reader
+                    .read_exact(&mut data)
+                    .context("Failed to read cpio entry data")?;
+                entry.data = CpioEntryData::Data(data);
+            }
+
+            entries.push(entry);
+        }
+
+        apply_reproducible(&cli.reproducible, &mut entries)?;
+
+        for entry in &entries {
+            display_entry(cpio_cli, entry);
+
+            writer
+                .start_entry(entry)
+                .context("Failed to write cpio entry")?;
+        }
+
+        return flush_writer(writer);
+    }
+
+    let mtime = get_mtime(&cli.reproducible)?;
+
+    while let Some(mut entry) = reader.next_entry().context("Failed to read cpio entry")? {
+        if let Some(m) = mtime {
+            entry.mtime = m;
+        }
+
         display_entry(cpio_cli, &entry);
 
         writer
@@ -285,9 +401,38 @@ pub fn cpio_main(cli: &CpioCli, cancel_signal: &AtomicBool) -> Result<()> {
         CpioCommand::Pack(c) => pack_subcommand(cli, c, cancel_signal),
         CpioCommand::Repack(c) => repack_subcommand(cli, c, cancel_signal),
         CpioCommand::Info(c) => info_subcommand(cli, c),
+        #[cfg(all(target_os = "linux", feature = "fuse"))]
+        CpioCommand::FuseMount(c) => cpio_fuse::fuse_mount_subcommand(c, cancel_signal),
     }
 }
 
+/// Options for making the output archive deterministic.
+#[derive(Debug, Args)]
+struct ReproducibleGroup {
+    /// Sort entries by path before writing.
+    #[arg(long)]
+    sort: bool,
+
+    /// Set the modification time of every entry (in seconds since the epoch).
+    #[arg(long, value_name = "SECONDS", conflicts_with = "source_date_epoch")]
+    mtime: Option<u32>,
+
+    /// Set the modification time of every entry to $SOURCE_DATE_EPOCH.
+    #[arg(long)]
+    source_date_epoch: bool,
+
+    /// Renumber all inodes sequentially in entry order.
+    ///
+    /// By default, existing inode numbers are kept and only missing inodes are
+    /// assigned.
+    #[arg(long)]
+    renumber_inodes: bool,
+
+    /// Pad the archive after the trailer to a multiple of 512 bytes.
+    #[arg(long)]
+    pad_trailer: bool,
+}
+
 /// Unpack a cpio archive.
 ///
 /// Regular files will be extracted to the output tree directory, but not any
@@ -336,9 +481,8 @@ struct PackCli {
     #[arg(long, value_name = "DIR", value_parser, default_value = "cpio_tree")]
     input_tree: PathBuf,
 
-    /// Sort entries before packing.
-    #[arg(long)]
-    sort: bool,
+    #[command(flatten)]
+    reproducible: ReproducibleGroup,
 }
 
 /// Repack a cpio archive.
@@ -351,6 +495,9 @@ struct RepackCli {
     /// Path to output cpio file.
     #[arg(short, long, value_name = "FILE", value_parser)]
     output: PathBuf,
+
+    #[command(flatten)]
+    reproducible: ReproducibleGroup,
 }
 
 /// Display cpio entry information.
@@ -371,6 +518,8 @@ enum CpioCommand {
     Pack(PackCli),
     Repack(RepackCli),
     Info(InfoCli),
+    #[cfg(all(target_os = "linux", feature = "fuse"))]
+    FuseMount(FuseMountCli),
 }
 
 /// Pack, unpack, and inspect cpio archives.
@@ -0,0 +1,384 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsStr,
+    io::BufReader,
+    os::unix::ffi::OsStrExt,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+    thread,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use anyhow::{bail, Context, Result};
+use bstr::ByteSlice;
+use clap::Parser;
+use fuser::{
+    FileAttr, FileType, Filesystem, MountOption, ReplyAttr, ReplyData, ReplyDirectory, ReplyEntry,
+    Request,
+};
+
+use crate::{
+    cli::{status, warning},
+    format::{
+        compression::CompressedReader,
+        cpio::{self, CpioEntry, CpioEntryData, CpioEntryType},
+    },
+    harden, sandbox, util,
+};
+
+/// The archive never changes while it is mounted.
+const TTL: Duration = Duration::from_secs(3600);
+
+struct Node {
+    /// Inode number of the parent directory.
+    parent: u64,
+    /// Index of the entry describing this node. Directories that are only
+    /// implied by the paths of other entries have no entry.
+    entry: Option<usize>,
+    /// Index of the entry holding the data. This differs from `entry` for hard
+    /// links because only the last entry in a link group has data.
+    data: Option<usize>,
+    children: BTreeMap<Vec<u8>, u64>,
+}
+
+/// Read-only view of the entries of a cpio archive. Inode numbers are assigned
+/// sequentially starting from the root directory and are unrelated to the inode
+/// numbers stored in the archive.
+struct CpioFs {
+    entries: Vec<CpioEntry>,
+    /// Indexed by inode number minus 1.
+    nodes: Vec<Node>,
+}
+
+impl CpioFs {
+    fn new(entries: Vec<CpioEntry>) -> Self {
+        let mut data_index = (0..entries.len()).collect::<Vec<_>>();
+        for indices in cpio::link_groups(&entries).into_values() {
+            let last = *indices.last().unwrap();
+            for i in indices {
+                data_index[i] = last;
+            }
+        }
+
+        let mut nodes = vec![Node {
+            parent: fuser::FUSE_ROOT_ID,
+            entry: None,
+            data: None,
+            children: BTreeMap::new(),
+        }];
+
+        for (i, entry) in entries.iter().enumerate() {
+            let components = entry
+                .path
+                .split_str("/")
+                .filter(|c| !c.is_empty() && *c != b".")
+                .collect::<Vec<_>>();
+
+            if components.iter().any(|c| *c == b"..") {
+                warning!(
+                    "Skipping entry with unsafe path: {:?}",
+                    entry.path.as_bstr()
+                );
+                continue;
+            }
+
+            let mut ino = fuser::FUSE_ROOT_ID;
+
+            for name in components {
+                let parent = ino;
+                let next_ino = nodes.len() as u64 + 1;
+
+                ino = *nodes[parent as usize - 1]
+                    .children
+                    .entry(name.to_vec())
+                    .or_insert(next_ino);
+                if ino == next_ino {
+                    nodes.push(Node {
+                        parent,
+                        entry: None,
+                        data: None,
+                        children: BTreeMap::new(),
+                    });
+                }
+            }
+
+            // Later entries with the same path replace earlier ones.
+            let node = &mut nodes[ino as usize - 1];
+            node.entry = Some(i);
+            node.data = Some(data_index[i]);
+        }
+
+        Self { entries, nodes }
+    }
+
+    fn node(&self, ino: u64) -> Option<&Node> {
+        ino.checked_sub(1).and_then(|i| self.nodes.get(i as usize))
+    }
+
+    fn data(&self, node: &Node) -> &[u8] {
+        match node.data.map(|i| &self.entries[i].data) {
+            Some(CpioEntryData::Data(data)) => data,
+            _ => &[],
+        }
+    }
+
+    fn attr(&self, ino: u64) -> Option<FileAttr> {
+        let node = self.node(ino)?;
+        let size = self.data(node).len() as u64;
+
+        let mut attr = FileAttr {
+            ino,
+            size,
+            blocks: util::div_ceil(size, 512),
+            atime: UNIX_EPOCH,
+            mtime: UNIX_EPOCH,
+            ctime: UNIX_EPOCH,
+            crtime: UNIX_EPOCH,
+            kind: FileType::Directory,
+            perm: 0o755,
+            nlink: 2,
+            uid: 0,
+            gid: 0,
+            rdev: 0,
+            blksize: 512,
+            flags: 0,
+        };
+
+        if let Some(entry) = node.entry.map(|i| &self.entries[i]) {
+            let mtime = UNIX_EPOCH + Duration::from_secs(entry.mtime.into());
+
+            attr.atime = mtime;
+            attr.mtime = mtime;
+            attr.ctime = mtime;
+            attr.crtime = mtime;
+            attr.kind = file_type(entry.file_type);
+            attr.perm = entry.file_mode;
+            attr.nlink = entry.nlink.max(1);
+            attr.uid = entry.uid;
+            attr.gid = entry.gid;
+            attr.rdev = libc::makedev(entry.rdev_maj, entry.rdev_min) as u32;
+        }
+
+        Some(attr)
+    }
+}
+
+fn file_type(file_type: CpioEntryType) -> FileType {
+    match file_type {
+        CpioEntryType::Pipe => FileType::NamedPipe,
+        CpioEntryType::Char => FileType::CharDevice,
+        CpioEntryType::Directory => FileType::Directory,
+        CpioEntryType::Block => FileType::BlockDevice,
+        CpioEntryType::Symlink => FileType::Symlink,
+        CpioEntryType::Socket => FileType::Socket,
+        CpioEntryType::Regular | CpioEntryType::Reserved | CpioEntryType::Unknown(_) => {
+            FileType::RegularFile
+        }
+    }
+}
+
+impl Filesystem for CpioFs {
+    fn lookup(&mut self, _req: &Request<'_>, parent: u64, name: &OsStr, reply: ReplyEntry) {
+        let child = self
+            .node(parent)
+            .and_then(|n| n.children.get(name.as_bytes()));
+
+        match child.and_then(|&ino| self.attr(ino)) {
+            Some(attr) => reply.entry(&TTL, &attr, 0),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn getattr(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyAttr) {
+        match self.attr(ino) {
+            Some(attr) => reply.attr(&TTL, &attr),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn readlink(&mut self, _req: &Request<'_>, ino: u64, reply: ReplyData) {
+        match self.node(ino) {
+            Some(node)
+                if node
+                    .entry
+                    .is_some_and(|i| self.entries[i].file_type == CpioEntryType::Symlink) =>
+            {
+                reply.data(self.data(node));
+            }
+            Some(_) => reply.error(libc::EINVAL),
+            None => reply.error(libc::ENOENT),
+        }
+    }
+
+    fn read(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        size: u32,
+        _flags: i32,
+        _lock_owner: Option<u64>,
+        reply: ReplyData,
+    ) {
+        let Some(node) = self.node(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let data = self.data(node);
+        let start = usize::try_from(offset).unwrap_or(0).min(data.len());
+        let end = start.saturating_add(size as usize).min(data.len());
+
+        reply.data(&data[start..end]);
+    }
+
+    fn readdir(
+        &mut self,
+        _req: &Request<'_>,
+        ino: u64,
+        _fh: u64,
+        offset: i64,
+        mut reply: ReplyDirectory,
+    ) {
+        let Some(node) = self.node(ino) else {
+            reply.error(libc::ENOENT);
+            return;
+        };
+
+        let special = [(ino, &b"."[..]), (node.parent, &b".."[..])];
+        let children = node.children.iter().map(|(n, &c)| (c, n.as_slice()));
+        let skip = usize::try_from(offset).unwrap_or(0);
+
+        for (i, (child, name)) in special.into_iter().chain(children).enumerate().skip(skip) {
+            let kind = self.attr(child).map_or(FileType::Directory, |a| a.kind);
+
+            // The offset is that of the next entry.
+            if reply.add(child, i as i64 + 1, kind, OsStr::from_bytes(name)) {
+                break;
+            }
+        }
+
+        reply.ok();
+    }
+}
+
+pub fn fuse_mount_subcommand(cli: &FuseMountCli, cancel_signal: &AtomicBool) -> Result<()> {
+    if harden::is_applied() {
+        bail!("Mounting cannot be used with --harden because it runs fusermount");
+    }
+
+    sandbox::check(&cli.mountpoint)
+        .with_context(|| format!("Failed to access mount point: {:?}", cli.mountpoint))?;
+
+    let file = sandbox::open(&cli.input)
+        .with_context(|| format!("Failed to open cpio for reading: {:?}", cli.input))?;
+    let reader = CompressedReader::new(BufReader::new(file), true)
+        .with_context(|| format!("Failed to open decompressor: {:?}", cli.input))?;
+    let entries = cpio::load(reader, false, cancel_signal)
+        .with_context(|| format!("Failed to read cpio entries: {:?}", cli.input))?;
+
+    let options = [
+        MountOption::RO,
+        MountOption::FSName("avbroot".to_owned()),
+        MountOption::Subtype("cpio".to_owned()),
+    ];
+    let session = fuser::spawn_mount2(CpioFs::new(entries), &cli.mountpoint, &options)
+        .with_context(|| format!("Failed to mount: {:?}", cli.mountpoint))?;
+
+    status!(
+        "Mounted at {:?}. Unmount or press Ctrl+C to exit",
+        cli.mountpoint
+    );
+
+    // The session thread exits if the filesystem is unmounted externally.
+    while !session.guard.is_finished() && !cancel_signal.load(Ordering::SeqCst) {
+        thread::sleep(Duration::from_millis(100));
+    }
+
+    // This unmounts the filesystem if it is still mounted.
+    drop(session);
+
+    Ok(())
+}
+
+/// Mount a cpio archive as a read-only FUSE filesystem.
+///
+/// The archive is decompressed and loaded into memory. File types,
+/// permissions, owners, and modification times are taken from the archive, but
+/// permissions are not enforced, so every file is readable by the current user.
+/// The command runs until the filesystem is unmounted (eg. with `fusermount -u`)
+/// or until it is interrupted.
+#[derive(Debug, Parser)]
+pub struct FuseMountCli {
+    /// Path to input cpio file.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to the directory to mount the archive on.
+    #[arg(value_name = "DIR", value_parser)]
+    mountpoint: PathBuf,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup(fs: &CpioFs, path: &str) -> Option<u64> {
+        path.split('/').try_fold(fuser::FUSE_ROOT_ID, |ino, name| {
+            fs.node(ino)?.children.get(name.as_bytes()).copied()
+        })
+    }
+
+    #[test]
+    fn build_tree() {
+        let link = |path: &[u8], data: &[u8]| CpioEntry {
+            inode: 5,
+            nlink: 2,
+            ..CpioEntry::new_file(path, 0o644, CpioEntryData::Data(data.to_vec()))
+        };
+
+        let fs = CpioFs::new(vec![
+            CpioEntry::new_directory(b".", 0o700),
+            CpioEntry::new_file(b"system/bin/sh", 0o755, CpioEntryData::Data(b"sh".to_vec())),
+            CpioEntry::new_symlink(b"init", b"system/bin/init"),
+            link(b"first", b""),
+            link(b"second", b"data"),
+            CpioEntry::new_file(b"../escape", 0o644, CpioEntryData::Data(vec![])),
+        ]);
+
+        let root = fs.attr(fuser::FUSE_ROOT_ID).unwrap();
+        assert_eq!(root.kind, FileType::Directory);
+        assert_eq!(root.perm, 0o700);
+
+        // Parent directories that are not in the archive are implied.
+        let bin = lookup(&fs, "system/bin").unwrap();
+        let bin_attr = fs.attr(bin).unwrap();
+        assert_eq!(bin_attr.kind, FileType::Directory);
+        assert_eq!(bin_attr.perm, 0o755);
+        assert_eq!(fs.node(bin).unwrap().parent, lookup(&fs, "system").unwrap());
+
+        let sh = lookup(&fs, "system/bin/sh").unwrap();
+        assert_eq!(fs.attr(sh).unwrap().size, 2);
+        assert_eq!(fs.data(fs.node(sh).unwrap()), b"sh");
+
+        let init = lookup(&fs, "init").unwrap();
+        assert_eq!(fs.attr(init).unwrap().kind, FileType::Symlink);
+        assert_eq!(fs.data(fs.node(init).unwrap()), b"system/bin/init");
+
+        // Every path in a link group has the data of the last entry.
+        for path in ["first", "second"] {
+            let ino = lookup(&fs, path).unwrap();
+            assert_eq!(fs.data(fs.node(ino).unwrap()), b"data");
+            assert_eq!(fs.attr(ino).unwrap().nlink, 2);
+        }
+
+        assert_eq!(lookup(&fs, "escape"), None);
+        assert_eq!(fs.nodes.len(), 7);
+    }
+}
@@ -0,0 +1,360 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+
+use crate::{
+    cli::{ota, status, warning},
+    harden, stream,
+};
+
+/// Exit status used by the on-device script when the block device is missing.
+const EXIT_NOT_FOUND: i32 = 2;
+
+pub struct Adb<'a> {
+    pub program: &'a Path,
+    pub serial: Option<&'a str>,
+}
+
+impl Adb<'_> {
+    fn command(&self) -> Command {
+        let mut command = Command::new(self.program);
+        if let Some(serial) = self.serial {
+            command.arg("-s").arg(serial);
+        }
+        command
+    }
+
+    /// Run `adb get-state`.
+    pub fn get_state(&self) -> Result<String> {
+        let output = self
+            .command()
+            .arg("get-state")
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Failed to run command: {:?}", self.program))?;
+        if !output.status.success() {
+            bail!("Failed to get device state: {}", output.status);
+        }
+
+        Ok(String::from_utf8_lossy(&output.stdout).trim().to_owned())
+    }
+
+    /// Run a shell command on the device. Returns the exit code and stdout.
+    pub fn shell(&self, script: &str) -> Result<(Option<i32>, String)> {
+        let output = self
+            .command()
+            .arg("shell")
+            .arg(script)
+            .stderr(Stdio::inherit())
+            .output()
+            .with_context(|| format!("Failed to run command: {:?}", self.program))?;
+
+        Ok((
+            output.status.code(),
+            String::from_utf8_lossy(&output.stdout).into_owned(),
+        ))
+    }
+
+    /// Get the value of a system property on the device. Missing properties
+    /// are returned as an empty string.
+    pub fn getprop(&self, name: &str) -> Result<String> {
+        let (code, stdout) = self.shell(&format!("getprop {name}"))?;
+        if code != Some(0) {
+            bail!("Failed to get property from device: {name}");
+        }
+
+        Ok(stdout.trim().to_owned())
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SlotStatus {
+    Ok,
+    Mismatch,
+    NotFound,
+}
+
+/// Partition names come from the (untrusted) OTA and are interpolated into a
+/// shell script, so only allow characters that are used in practice.
+fn is_safe_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'_' || b == b'-')
+}
+
+/// Compute the sha256 digest of the first `size` bytes of the partition on the
+/// device. Returns [`None`] if the block device does not exist. On A/B devices,
+/// only the block devices with `slot_suffix` are considered. Falling back to the
+/// unsuffixed name could silently verify the wrong slot.
+fn device_partition_digest(
+    adb: &Adb,
+    name: &str,
+    slot_suffix: &str,
+    size: u64,
+) -> Result<Option<String>> {
+    let script = format!(
+        "p=; \
+        for d in /dev/block/by-name/{name}{slot_suffix} \
+            /dev/block/mapper/{name}{slot_suffix}; do \
+            if [ -e \"$d\" ]; then p=$d; break; fi; \
+        done; \
+        [ -n \"$p\" ] || exit {EXIT_NOT_FOUND}; \
+        head -c {size} \"$p\" | sha256sum"
+    );
+
+    let (code, stdout) = adb.shell(&script)?;
+    match code {
+        Some(0) => {}
+        Some(EXIT_NOT_FOUND) => return Ok(None),
+        _ => bail!("Failed to compute digest of partition on device: {name}{slot_suffix}"),
+    }
+
+    let digest = stdout
+        .split_whitespace()
+        .next()
+        .filter(|d| d.len() == 64 && d.bytes().all(|b| b.is_ascii_hexdigit()))
+        .ok_or_else(|| anyhow!("Invalid sha256sum output for {name}{slot_suffix}: {stdout:?}"))?;
+
+    Ok(Some(digest.to_ascii_lowercase()))
+}
+
+fn verify_slot_subcommand(cli: &VerifySlotCli, cancel_signal: &AtomicBool) -> Result<()> {
+    if harden::is_applied() {
+        bail!("device verify-slot cannot be used with --harden because it runs adb");
+    }
+
+    let (header, _) = ota::open_full_ota_payload(&cli.input)?;
+    let adb = Adb {
+        program: &cli.adb,
+        serial: cli.serial.as_deref(),
+    };
+
+    let state = adb.get_state()?;
+    if state != "recovery" {
+        warning!("Device is in {state} mode, not recovery mode. Reading partitions may fail.");
+    }
+
+    let slot_suffix = match &cli.slot {
+        Some(slot) => format!("_{slot}"),
+        None => adb.getprop("ro.boot.slot_suffix")?,
+    };
+
+    status!(
+        "Verifying partitions in slot: {}",
+        slot_suffix.strip_prefix('_').unwrap_or("(none)"),
+    );
+
+    for name in &cli.partition {
+        if !header
+            .manifest
+            .partitions
+            .iter()
+            .any(|p| &p.partition_name == name)
+        {
+            bail!("Partition not found in payload: {name}");
+        }
+    }
+
+    let mut results = vec![];
+
+    for p in &header.manifest.partitions {
+        let name = &p.partition_name;
+        if !cli.partition.is_empty() && !cli.partition.contains(name) {
+            continue;
+        }
+
+        stream::check_cancel(cancel_signal)?;
+
+        if !is_safe_name(name) {
+            bail!("Unsafe partition name: {name:?}");
+        }
+
+        let info = p
+            .new_partition_info
+            .as_ref()
+            .ok_or_else(|| anyhow!("Partition info not found: {name}"))?;
+        let size = info
+            .size
+            .ok_or_else(|| anyhow!("Partition size not found: {name}"))?;
+        let expected = info
+            .hash
+            .as_ref()
+            .map(hex::encode)
+            .ok_or_else(|| anyhow!("Partition hash not found: {name}"))?;
+
+        let status = match device_partition_digest(&adb, name, &slot_suffix, size)? {
+            Some(digest) if digest == expected => SlotStatus::Ok,
+            Some(digest) => {
+                warning!("{name}: expected {expected}, but have {digest}");
+                SlotStatus::Mismatch
+            }
+            None => {
+                warning!("{name}: block device not found");
+                SlotStatus::NotFound
+            }
+        };
+
+        if status == SlotStatus::Ok {
+            status!("{name}: OK");
+        }
+
+        results.push((name.as_str(), status));
+    }
+
+    let failed = |s| {
+        results
+            .iter()
+            .filter(|(_, status)| *status == s)
+            .map(|(n, _)| *n)
+            .collect::<Vec<_>>()
+    };
+    let mismatched = failed(SlotStatus::Mismatch);
+    let missing = failed(SlotStatus::NotFound);
+
+    if !mismatched.is_empty() {
+        bail!("Partitions do not match the OTA: {}", mismatched.join(", "));
+    } else if !missing.is_empty() {
+        bail!(
+            "Partitions could not be verified because their block devices were not found: {}",
+            missing.join(", "),
+        );
+    }
+
+    status!("All partitions match the OTA");
+
+    Ok(())
+}
+
+pub fn device_main(cli: &DeviceCli, cancel_signal: &AtomicBool) -> Result<()> {
+    match &cli.command {
+        DeviceCommand::VerifySlot(c) => verify_slot_subcommand(c, cancel_signal),
+    }
+}
+
+/// Verify that an installed slot matches an OTA.
+///
+/// The device must be booted into recovery mode and accessible via adb. Each
+/// partition in the OTA is read on the device and its sha256 digest is compared
+/// against the digest in the OTA's payload. Only the digests are transferred
+/// over adb, not the partition data. This is useful for confirming that an
+/// OTA was installed successfully before rebooting.
+#[derive(Debug, Parser)]
+struct VerifySlotCli {
+    /// Path to (patched) OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Slot to verify.
+    ///
+    /// By default, the slot that the device is currently booted from is
+    /// verified. After sideloading an OTA, this is usually the other slot.
+    #[arg(long, value_name = "SLOT", value_parser = ["a", "b"])]
+    slot: Option<String>,
+
+    /// Verify only the specified partition.
+    ///
+    /// This option can be specified multiple times. By default, every
+    /// partition in the OTA is verified.
+    #[arg(long, value_name = "PARTITION")]
+    partition: Vec<String>,
+
+    /// Path to adb executable.
+    #[arg(long, value_name = "FILE", value_parser, default_value = "adb")]
+    adb: PathBuf,
+
+    /// Serial number of device to use.
+    #[arg(short, long, value_name = "SERIAL")]
+    serial: Option<String>,
+}
+
+#[derive(Debug, Subcommand)]
+enum DeviceCommand {
+    VerifySlot(VerifySlotCli),
+}
+
+/// Commands for working with a connected device.
+#[derive(Debug, Parser)]
+pub struct DeviceCli {
+    #[command(subcommand)]
+    command: DeviceCommand,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safe_name() {
+        assert!(is_safe_name("vendor_boot"));
+        assert!(is_safe_name("system-ext"));
+        assert!(!is_safe_name(""));
+        assert!(!is_safe_name("boot;reboot"));
+        assert!(!is_safe_name("../boot"));
+        assert!(!is_safe_name("boot a"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn partition_digest_requires_slot_suffix() {
+        use std::{fs, os::unix::fs::PermissionsExt};
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let root = temp_dir.path().join("block");
+        fs::create_dir_all(root.join("by-name")).unwrap();
+        fs::create_dir_all(root.join("mapper")).unwrap();
+        fs::write(root.join("by-name").join("boot_a"), b"boot_a data").unwrap();
+        fs::write(root.join("by-name").join("boot"), b"boot data").unwrap();
+        fs::write(root.join("mapper").join("system_a"), b"system_a data").unwrap();
+
+        // Runs the script locally with /dev/block/ pointing to the temp dir.
+        let adb_path = temp_dir.path().join("adb");
+        fs::write(
+            &adb_path,
+            format!(
+                "#!/bin/sh\n\
+                [ \"$1\" = shell ] || exit 1\n\
+                exec sh -c \"$(printf '%s' \"$2\" | sed 's|/dev/block/|{}/|g')\"\n",
+                root.to_str().unwrap(),
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&adb_path, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let adb = Adb {
+            program: &adb_path,
+            serial: None,
+        };
+        let sha256 =
+            |data: &[u8]| hex::encode(crate::digest::digest(&crate::digest::SHA256, data).as_ref());
+
+        assert_eq!(
+            device_partition_digest(&adb, "boot", "_a", 4).unwrap(),
+            Some(sha256(b"boot")),
+        );
+        assert_eq!(
+            device_partition_digest(&adb, "system", "_a", 13).unwrap(),
+            Some(sha256(b"system_a data")),
+        );
+        // The unsuffixed block device is only used on non-A/B devices.
+        assert_eq!(
+            device_partition_digest(&adb, "boot", "", 9).unwrap(),
+            Some(sha256(b"boot data")),
+        );
+        // The unsuffixed block device belongs to neither slot.
+        assert_eq!(
+            device_partition_digest(&adb, "boot", "_b", 4).unwrap(),
+            None
+        );
+    }
+}
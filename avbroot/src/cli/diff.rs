@@ -0,0 +1,447 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use x509_cert::Certificate;
+use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+use crate::{
+    cli::{args, ota as ota_cli, status},
+    crypto::{self, PassphraseSource, RsaSigningKey},
+    format::{
+        ota::{self, SigningWriter, ZipEntry},
+        payload::{self, CompressOptions, PayloadHeader, PayloadWriter},
+    },
+    protobuf::build::tools::releasetools::OtaMetadata,
+    sandbox,
+    stream::{self, CountingWriter, PSeekFile, ReadSeekReopen, SectionReader},
+};
+
+/// Minor payload version of the generated delta payloads. This is the first
+/// version that supports verity data, which every device with A/B updates and
+/// dynamic partitions understands.
+const DELTA_MINOR_VERSION: u32 = 6;
+
+/// The parts of a full OTA that are needed for generating a delta OTA.
+struct FullOta {
+    metadata: OtaMetadata,
+    cert: Certificate,
+    header: PayloadHeader,
+    payload: SectionReader<BufReader<PSeekFile>>,
+}
+
+fn open_full_ota(path: &Path) -> Result<FullOta> {
+    let mut zip_reader = sandbox::open(path)
+        .map(BufReader::new)
+        .map_err(anyhow::Error::from)
+        .and_then(|r| ZipArchive::new(r).map_err(anyhow::Error::from))
+        .with_context(|| format!("Failed to read zip: {path:?}"))?;
+
+    let metadata = ota_cli::read_ota_metadata(&mut zip_reader)
+        .with_context(|| format!("Failed to read OTA metadata: {path:?}"))?
+        .ok_or_else(|| anyhow!("OTA metadata not found: {path:?}"))?;
+
+    let cert = zip_reader
+        .by_name(ota::PATH_OTACERT)
+        .map_err(anyhow::Error::from)
+        .and_then(|entry| crypto::read_pem_cert(entry).map_err(anyhow::Error::from))
+        .with_context(|| format!("Failed to read OTA certificate: {path:?}"))?;
+
+    let (header, payload) = ota_cli::open_full_ota_payload(path)?;
+
+    Ok(FullOta {
+        metadata,
+        cert,
+        header,
+        payload,
+    })
+}
+
+/// Extract a partition image into a temporary file.
+fn extract_to_temp(ota: &FullOta, name: &str, cancel_signal: &AtomicBool) -> Result<PSeekFile> {
+    let file = tempfile::tempfile()
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+    payload::extract_image(
+        &ota.payload,
+        &file,
+        &ota.header,
+        name,
+        &args::payload_apply_options(),
+        cancel_signal,
+    )
+    .with_context(|| format!("Failed to extract image: {name}"))?;
+
+    Ok(file)
+}
+
+/// Build the delta payload header and the per-partition blob data.
+fn diff_payloads(
+    old: &FullOta,
+    new: &FullOta,
+    options: &CompressOptions,
+    cancel_signal: &AtomicBool,
+) -> Result<(PayloadHeader, BTreeMap<String, PSeekFile>)> {
+    let block_size = new.header.manifest.block_size();
+    if old.header.manifest.block_size() != block_size {
+        bail!(
+            "Block sizes differ between the old ({}) and new ({block_size}) payloads",
+            old.header.manifest.block_size(),
+        );
+    }
+
+    let mut header = new.header.clone();
+    header.manifest.minor_version = Some(DELTA_MINOR_VERSION);
+
+    let mut blobs = BTreeMap::new();
+    let mut bytes_full = 0;
+    let mut bytes_delta = 0;
+
+    for partition in &mut header.manifest.partitions {
+        let name = partition.partition_name.clone();
+        let has_source = old
+            .header
+            .manifest
+            .partitions
+            .iter()
+            .any(|p| p.partition_name == name);
+
+        let target = extract_to_temp(new, &name, cancel_signal)?;
+        let writer = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+        bytes_full += partition
+            .operations
+            .iter()
+            .filter_map(|op| op.data_length)
+            .sum::<u64>();
+
+        if has_source {
+            status!("Computing delta for: {name}");
+
+            let source = extract_to_temp(old, &name, cancel_signal)?;
+
+            let (source_info, target_info, operations) = payload::diff_image(
+                &source,
+                &target,
+                &writer,
+                &name,
+                block_size,
+                options,
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to compute delta for: {name}"))?;
+
+            partition.old_partition_info = Some(source_info);
+            partition.new_partition_info = Some(target_info);
+            partition.operations = operations;
+        } else {
+            status!("Partition not in old OTA, storing full image: {name}");
+
+            let (target_info, operations) = payload::compress_image(
+                &target,
+                &writer,
+                &name,
+                block_size,
+                options,
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to compress image: {name}"))?;
+
+            partition.old_partition_info = None;
+            partition.new_partition_info = Some(target_info);
+            partition.operations = operations;
+        }
+
+        // The full OTA's merge sequence refers to the full OTA's operations, so
+        // it is not valid for the new ones. No merge sequence is generated. On
+        // Virtual A/B devices, update_engine writes the blocks of SOURCE_COPY
+        // operations that are not covered by the merge sequence as raw data in
+        // the snapshot (COW_REPLACE) instead of as copies. This is always safe,
+        // but makes the snapshot larger. See ConvertToCowOperations() in:
+        // https://android.googlesource.com/platform/system/update_engine/+/refs/heads/main/common/cow_operation_convert.cc
+        partition.merge_operations.clear();
+
+        bytes_delta += partition
+            .operations
+            .iter()
+            .filter_map(|op| op.data_length)
+            .sum::<u64>();
+
+        blobs.insert(name, writer);
+    }
+
+    status!(
+        "Payload data: {} (full: {})",
+        ota_cli::format_mib(bytes_delta),
+        ota_cli::format_mib(bytes_full),
+    );
+
+    Ok((header, blobs))
+}
+
+fn write_payload(
+    writer: impl Write,
+    header: &PayloadHeader,
+    blobs: &mut BTreeMap<String, PSeekFile>,
+    key_ota: &RsaSigningKey,
+    cancel_signal: &AtomicBool,
+) -> Result<(String, u64)> {
+    let mut payload_writer = PayloadWriter::new(writer, header.clone(), key_ota.clone())
+        .context("Failed to write payload header")?;
+
+    while payload_writer
+        .begin_next_operation()
+        .context("Failed to begin next payload blob entry")?
+    {
+        let name = payload_writer.partition().unwrap().partition_name.clone();
+        let operation = payload_writer.operation().unwrap();
+
+        let Some(data_length) = operation.data_length else {
+            // Otherwise, this is a ZERO/DISCARD/SOURCE_COPY operation.
+            continue;
+        };
+
+        let pi = payload_writer.partition_index().unwrap();
+        let oi = payload_writer.operation_index().unwrap();
+        let data_offset = header.manifest.partitions[pi].operations[oi]
+            .data_offset
+            .ok_or_else(|| anyhow!("Missing data_offset in partition #{pi} operation #{oi}"))?;
+
+        let file = blobs.get_mut(&name).unwrap();
+        file.seek(SeekFrom::Start(data_offset))
+            .with_context(|| format!("Failed to seek image: {name}"))?;
+
+        stream::copy_n(file, &mut payload_writer, data_length, cancel_signal)
+            .with_context(|| format!("Failed to copy from image: {name}"))?;
+    }
+
+    let (_, properties, metadata_size) = payload_writer
+        .finish()
+        .context("Failed to finalize payload")?;
+
+    Ok((properties, metadata_size))
+}
+
+/// Make the new OTA's metadata require the old OTA's build on the device.
+fn delta_metadata(old: &OtaMetadata, new: &OtaMetadata) -> Result<OtaMetadata> {
+    let old_post = old
+        .postcondition
+        .as_ref()
+        .ok_or_else(|| anyhow!("Old OTA metadata has no postcondition"))?;
+    let new_post = new
+        .postcondition
+        .as_ref()
+        .ok_or_else(|| anyhow!("New OTA metadata has no postcondition"))?;
+
+    if old_post.device != new_post.device {
+        bail!(
+            "OTAs are for different devices: {:?} != {:?}",
+            old_post.device,
+            new_post.device,
+        );
+    } else if old_post.build == new_post.build {
+        bail!("OTAs are for the same build: {:?}", new_post.build);
+    }
+
+    let mut metadata = new.clone();
+    let precondition = metadata.precondition.get_or_insert_with(Default::default);
+    precondition.device.clone_from(&old_post.device);
+    precondition.build.clone_from(&old_post.build);
+    precondition
+        .build_incremental
+        .clone_from(&old_post.build_incremental);
+
+    Ok(metadata)
+}
+
+pub fn diff_subcommand(cli: &DiffCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let source_ota = PassphraseSource::new(
+        &cli.key_ota,
+        cli.pass_ota_file.as_deref(),
+        cli.pass_ota_env_var.as_deref(),
+    );
+    let key_ota = crypto::read_signing_key(&cli.key_ota, &source_ota)
+        .with_context(|| format!("Failed to load key: {:?}", cli.key_ota))?;
+    let cert_ota = crypto::read_pem_cert_file(&cli.cert_ota)
+        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+
+    if !crypto::cert_matches_key(&cert_ota, &key_ota)? {
+        bail!(
+            "Private key {:?} does not match certificate {:?}",
+            cli.key_ota,
+            cli.cert_ota,
+        );
+    }
+
+    let old = open_full_ota(&cli.old)?;
+    let new = open_full_ota(&cli.new)?;
+
+    // The device only accepts OTAs signed by a certificate that the currently
+    // installed build trusts.
+    if old.cert != cert_ota {
+        bail!(
+            "Old OTA was not patched with certificate {:?}. The device would reject the delta OTA",
+            cli.cert_ota,
+        );
+    }
+
+    let metadata = delta_metadata(&old.metadata, &new.metadata)?;
+
+    let options = CompressOptions {
+        xz_level: cli.xz_level,
+        xz_original: payload::detect_xz_params(new.payload.reopen_boxed()?, &new.header)
+            .context("Failed to detect XZ parameters of new payload")?,
+        low_memory: args::low_memory(),
+        ..Default::default()
+    };
+
+    let (header, mut blobs) = diff_payloads(&old, &new, &options, cancel_signal)?;
+
+    let raw_writer = sandbox::create(&cli.output)
+        .with_context(|| format!("Failed to open for writing: {:?}", cli.output))?;
+    let buffered_writer = BufWriter::new(raw_writer);
+    let signing_writer = SigningWriter::new(buffered_writer);
+    let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    let mut entries = vec![];
+    let mut properties = None;
+    let mut payload_metadata_size = None;
+
+    for path in [ota::PATH_OTACERT, ota::PATH_PAYLOAD, ota::PATH_PROPERTIES] {
+        zip_writer
+            .start_file_with_extra_data(path, options)
+            .with_context(|| format!("Failed to begin new zip entry: {path}"))?;
+        let offset = zip_writer
+            .end_extra_data()
+            .with_context(|| format!("Failed to end new zip entry: {path}"))?;
+        let mut writer = CountingWriter::new(&mut zip_writer);
+
+        match path {
+            ota::PATH_OTACERT => {
+                crypto::write_pem_cert(&mut writer, &cert_ota)
+                    .with_context(|| format!("Failed to write entry: {path}"))?;
+            }
+            ota::PATH_PAYLOAD => {
+                status!("Writing delta payload");
+
+                let (p, m) =
+                    write_payload(&mut writer, &header, &mut blobs, &key_ota, cancel_signal)
+                        .context("Failed to write payload")?;
+
+                properties = Some(p);
+                payload_metadata_size = Some(m);
+            }
+            ota::PATH_PROPERTIES => {
+                writer
+                    .write_all(properties.as_ref().unwrap().as_bytes())
+                    .with_context(|| format!("Failed to write payload properties: {path}"))?;
+            }
+            _ => unreachable!(),
+        }
+
+        // Cannot fail.
+        let size = writer.stream_position()?;
+
+        entries.push(ZipEntry {
+            name: path.to_owned(),
+            offset,
+            size,
+        });
+    }
+
+    status!("Generating new OTA metadata");
+
+    ota::add_metadata(
+        &entries,
+        &mut zip_writer,
+        // Offset where next entry would begin, after the data descriptor.
+        entries.last().map(|e| e.offset + e.size).unwrap() + 16,
+        &metadata,
+        payload_metadata_size.unwrap(),
+    )
+    .context("Failed to write new OTA metadata")?;
+
+    let signing_writer = zip_writer
+        .finish()
+        .context("Failed to finalize output zip")?;
+    let mut buffered_writer = signing_writer
+        .finish(&key_ota, &cert_ota)
+        .context("Failed to sign output zip")?;
+    buffered_writer
+        .flush()
+        .context("Failed to flush output zip")?;
+
+    status!("Wrote delta OTA: {:?}", cli.output);
+
+    Ok(())
+}
+
+/// Generate a delta OTA from two patched full OTAs.
+///
+/// Blocks of the new partition images that already exist in the old images are
+/// copied on the device instead of being stored in the payload. All other data
+/// is stored compressed. The delta OTA can only be installed on a device that
+/// is running the exact build from the old OTA.
+///
+/// Only SOURCE_COPY, ZERO, and REPLACE* operations are generated. Binary diff
+/// operations, like PUFFDIFF, BSDIFF, and ZUCCHINI, are not, so the delta OTA
+/// is larger than what an OEM would produce for the same builds. No Virtual
+/// A/B merge sequence is generated either, so on those devices, the snapshot
+/// stores copied blocks as raw data and needs more space during the update.
+///
+/// Both OTAs must have been patched with the same OTA signing key, which is
+/// also used to sign the delta OTA.
+#[derive(Debug, Parser)]
+pub struct DiffCli {
+    /// Path to old patched OTA zip.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub old: PathBuf,
+
+    /// Path to new patched OTA zip.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub new: PathBuf,
+
+    /// Path to output delta OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: PathBuf,
+
+    /// Private key for signing the OTA.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub key_ota: PathBuf,
+
+    /// Certificate for OTA signing key.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub cert_ota: PathBuf,
+
+    /// Environment variable containing OTA private key passphrase.
+    #[arg(long, value_name = "ENV_VAR", value_parser, group = "pass_ota")]
+    pub pass_ota_env_var: Option<OsString>,
+
+    /// File containing OTA private key passphrase.
+    #[arg(long, value_name = "FILE", value_parser, group = "pass_ota")]
+    pub pass_ota_file: Option<PathBuf>,
+
+    /// XZ compression level for data that is stored in the payload.
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u32).range(0..=9)
+    )]
+    pub xz_level: u32,
+}
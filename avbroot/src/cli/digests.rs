@@ -0,0 +1,208 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Cache of partition image digests stored in an extraction directory as
+//! `digests.json`. Each entry records the size and modification time of the
+//! image when it was last hashed or verified. An entry is only used if the
+//! image still has the same size and modification time.
+
+use std::{
+    collections::BTreeMap,
+    io,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use cap_std::fs::Dir;
+use serde::{Deserialize, Serialize};
+
+use crate::format::avb::Descriptor;
+
+pub const FILENAME: &str = "digests.json";
+
+/// Size and modification time of an image file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified: u64,
+}
+
+impl FileStamp {
+    /// Get the stamp for `{name}.img` in `directory`. Returns [`None`] if the
+    /// file does not exist.
+    pub fn for_image(directory: &Dir, name: &str) -> Result<Option<Self>> {
+        let path = format!("{name}.img");
+        let metadata = match directory.open(&path) {
+            Ok(f) => f.into_std().metadata(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e),
+        }
+        .with_context(|| format!("Failed to stat file: {path:?}"))?;
+
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .and_then(|d| u64::try_from(d.as_nanos()).ok())
+            .unwrap_or_else(|| {
+                // Without a usable modification time, make sure the entry never
+                // matches again.
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_nanos() as u64)
+            });
+
+        Ok(Some(Self {
+            size: metadata.len(),
+            modified,
+        }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PartitionDigests {
+    #[serde(flatten)]
+    pub stamp: FileStamp,
+    /// Lowercase hex-encoded sha256 digest of the image.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// The hash or hash tree descriptor that the image was verified against.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub avb: Option<Descriptor>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DigestsManifest {
+    pub partitions: BTreeMap<String, PartitionDigests>,
+}
+
+impl DigestsManifest {
+    /// Load the manifest from `directory`. Returns [`None`] if it does not
+    /// exist.
+    pub fn load(directory: &Dir) -> Result<Option<Self>> {
+        let data = match directory.read(FILENAME) {
+            Ok(d) => d,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read file: {FILENAME:?}"))?,
+        };
+
+        let manifest = serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse digests manifest: {FILENAME:?}"))?;
+
+        Ok(Some(manifest))
+    }
+
+    pub fn save(&self, directory: &Dir) -> Result<()> {
+        let mut data = serde_json::to_vec_pretty(self)?;
+        data.push(b'\n');
+
+        directory
+            .write(FILENAME, data)
+            .with_context(|| format!("Failed to write file: {FILENAME:?}"))
+    }
+
+    /// Get the entry for `name` if the image has not changed since the entry
+    /// was recorded.
+    pub fn get(&self, name: &str, stamp: &FileStamp) -> Option<&PartitionDigests> {
+        self.partitions.get(name).filter(|p| p.stamp == *stamp)
+    }
+
+    /// Get the entry for `name` for updating. Stale entries are cleared.
+    pub fn entry(&mut self, name: &str, stamp: FileStamp) -> &mut PartitionDigests {
+        let entry = self
+            .partitions
+            .entry(name.to_owned())
+            .or_insert_with(|| PartitionDigests {
+                stamp,
+                sha256: None,
+                avb: None,
+            });
+
+        if entry.stamp != stamp {
+            *entry = PartitionDigests {
+                stamp,
+                sha256: None,
+                avb: None,
+            };
+        }
+
+        entry
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use cap_std::ambient_authority;
+    use cap_tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn file_stamp() {
+        let temp_dir = TempDir::new(ambient_authority()).unwrap();
+
+        assert_eq!(FileStamp::for_image(&temp_dir, "system").unwrap(), None);
+
+        temp_dir.write("system.img", b"foobar").unwrap();
+        let stamp = FileStamp::for_image(&temp_dir, "system").unwrap().unwrap();
+        assert_eq!(stamp.size, 6);
+        assert_eq!(
+            FileStamp::for_image(&temp_dir, "system").unwrap(),
+            Some(stamp),
+        );
+    }
+
+    #[test]
+    fn stale_entries() {
+        let stamp = FileStamp {
+            size: 1,
+            modified: 2,
+        };
+        let changed = FileStamp {
+            size: 1,
+            modified: 3,
+        };
+        let mut manifest = DigestsManifest::default();
+
+        manifest.entry("system", stamp).sha256 = Some("digest".to_owned());
+        assert_eq!(
+            manifest
+                .get("system", &stamp)
+                .and_then(|p| p.sha256.as_deref()),
+            Some("digest"),
+        );
+        assert_eq!(manifest.get("system", &changed), None);
+        assert_eq!(manifest.get("vendor", &stamp), None);
+
+        // Updating an entry for a changed image discards the old results.
+        assert_eq!(manifest.entry("system", changed).sha256, None);
+        assert_eq!(manifest.get("system", &stamp), None);
+    }
+
+    #[test]
+    fn round_trip() {
+        let temp_dir = TempDir::new(ambient_authority()).unwrap();
+
+        assert_eq!(DigestsManifest::load(&temp_dir).unwrap(), None);
+
+        let mut manifest = DigestsManifest::default();
+        manifest
+            .entry(
+                "system",
+                FileStamp {
+                    size: 1,
+                    modified: 2,
+                },
+            )
+            .sha256 = Some("digest".to_owned());
+        manifest.save(&temp_dir).unwrap();
+
+        assert_eq!(DigestsManifest::load(&temp_dir).unwrap(), Some(manifest));
+
+        temp_dir.write(FILENAME, b"not json").unwrap();
+        assert!(DigestsManifest::load(&temp_dir).is_err());
+    }
+}
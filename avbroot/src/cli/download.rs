@@ -0,0 +1,470 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    ffi::{OsStr, OsString},
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::{atomic::AtomicBool, LazyLock},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    cli::{
+        ota::{self, PatchCli},
+        profile, status, warning,
+    },
+    digest::Context as DigestContext,
+    sandbox,
+    stream::{self, HashingWriter},
+};
+
+/// Built-in OTA sources.
+static BUILTIN_SOURCES: &str = include_str!("download.toml");
+
+/// Full OTA file name: `<device>-ota-<build id>[-<suffix>].zip`.
+static OTA_FILE_NAME_REGEX: LazyLock<Regex> = LazyLock::new(|| {
+    Regex::new(r"^([A-Za-z0-9_]+)-ota-([A-Za-z0-9._]+)(?:-[0-9A-Fa-f]+)?\.zip$").unwrap()
+});
+
+/// Download link in Google's OTA page.
+static GOOGLE_LINK_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r#"href="(https?://[^"]+\.zip)""#).unwrap());
+
+/// Checksum table cell in Google's OTA page.
+static GOOGLE_CHECKSUM_REGEX: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"<td>[ \t\r\n]*([0-9A-Fa-f]{64})[ \t\r\n]*</td>").unwrap());
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum IndexFormat {
+    /// HTML table from Google's OTA page.
+    Google,
+    /// Lines of `<sha256>  <url>`.
+    Sha256sum,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Source {
+    url: String,
+    format: IndexFormat,
+    /// Cookie that acknowledges the source's terms of service. This is only
+    /// sent if the user explicitly accepts the terms.
+    #[serde(default)]
+    tos_cookie: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Sources {
+    #[serde(rename = "source", default)]
+    sources: BTreeMap<String, Source>,
+}
+
+impl Sources {
+    fn from_toml(data: &str) -> Result<Self> {
+        Ok(toml_edit::de::from_str(data)?)
+    }
+}
+
+/// A full OTA listed in a source's index.
+#[derive(Clone, Debug)]
+struct IndexEntry {
+    url: String,
+    file_name: String,
+    device: String,
+    build: String,
+    sha256: String,
+}
+
+impl IndexEntry {
+    /// Parse the device codename and build ID from the OTA's file name. Returns
+    /// [`None`] if the URL does not refer to a full OTA.
+    fn new(url: &str, sha256: &str) -> Option<Self> {
+        let file_name = url.rsplit('/').next()?;
+        let captures = OTA_FILE_NAME_REGEX.captures(file_name)?;
+
+        Some(Self {
+            url: url.to_owned(),
+            file_name: file_name.to_owned(),
+            device: captures[1].to_ascii_lowercase(),
+            build: captures[2].to_ascii_lowercase(),
+            sha256: sha256.to_ascii_lowercase(),
+        })
+    }
+}
+
+/// Parse Google's OTA page. Each download link is in its own table cell and
+/// the checksum is in the cell that follows it.
+fn parse_google_index(data: &str) -> Vec<IndexEntry> {
+    let links = GOOGLE_LINK_REGEX.captures_iter(data).collect::<Vec<_>>();
+    let mut entries = vec![];
+
+    for (i, captures) in links.iter().enumerate() {
+        let end = links
+            .get(i + 1)
+            .map_or(data.len(), |c| c.get(0).unwrap().start());
+        let row = &data[captures.get(0).unwrap().end()..end];
+
+        if let Some(checksum) = GOOGLE_CHECKSUM_REGEX.captures(row) {
+            entries.extend(IndexEntry::new(&captures[1], &checksum[1]));
+        }
+    }
+
+    entries
+}
+
+/// Parse a `sha256sum`-style index.
+fn parse_sha256sum_index(data: &str) -> Vec<IndexEntry> {
+    data.lines()
+        .filter_map(|line| {
+            let (sha256, url) = line.trim().split_once(char::is_whitespace)?;
+            let url = url.trim_start().trim_start_matches('*');
+
+            if sha256.len() != 64 || !sha256.bytes().all(|b| b.is_ascii_hexdigit()) {
+                return None;
+            }
+
+            IndexEntry::new(url, sha256)
+        })
+        .collect()
+}
+
+fn http_get(url: &str, cookie: Option<&str>) -> Result<Box<dyn Read + Send + Sync>> {
+    let mut request = ureq::get(url);
+    if let Some(cookie) = cookie {
+        request = request.set("Cookie", cookie);
+    }
+
+    let response = request
+        .call()
+        .with_context(|| format!("Failed to fetch: {url}"))?;
+
+    Ok(response.into_reader())
+}
+
+fn load_sources(path: Option<&Path>) -> Result<Sources> {
+    let mut sources =
+        Sources::from_toml(BUILTIN_SOURCES).expect("Invalid built-in OTA source list");
+
+    if let Some(path) = path {
+        let data = sandbox::read_to_string(path)
+            .with_context(|| format!("Failed to read file: {path:?}"))?;
+        let custom = Sources::from_toml(&data)
+            .with_context(|| format!("Failed to load OTA source list: {path:?}"))?;
+
+        sources.sources.extend(custom.sources);
+    }
+
+    Ok(sources)
+}
+
+/// Find the OTA for the device and build in the source's index. The special
+/// build ID `latest` selects the last OTA listed for the device.
+fn find_ota(
+    source: &Source,
+    cookie: Option<&str>,
+    device: &str,
+    build: &str,
+) -> Result<IndexEntry> {
+    status!("Fetching OTA index: {}", source.url);
+
+    let mut data = String::new();
+    http_get(&source.url, cookie)?
+        .read_to_string(&mut data)
+        .with_context(|| format!("Failed to read OTA index: {}", source.url))?;
+
+    let entries = match source.format {
+        IndexFormat::Google => parse_google_index(&data),
+        IndexFormat::Sha256sum => parse_sha256sum_index(&data),
+    };
+    let mut entries = entries
+        .into_iter()
+        .filter(|e| e.device.eq_ignore_ascii_case(device))
+        .collect::<Vec<_>>();
+
+    if entries.is_empty() {
+        bail!("No OTAs found for device: {device}");
+    }
+
+    let entry = if build == "latest" {
+        entries.pop()
+    } else {
+        entries
+            .iter()
+            .position(|e| e.build.eq_ignore_ascii_case(build))
+            .map(|i| entries.swap_remove(i))
+    };
+
+    entry.ok_or_else(|| {
+        anyhow!(
+            "Build {build} not found for device {device} (available: {})",
+            entries
+                .iter()
+                .map(|e| e.build.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    })
+}
+
+fn sha256_file(path: &Path, cancel_signal: &AtomicBool) -> Result<String> {
+    let reader = sandbox::open(path)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let mut writer =
+        HashingWriter::new(std::io::sink(), DigestContext::new(&crate::digest::SHA256));
+
+    stream::copy(reader, &mut writer, cancel_signal)
+        .with_context(|| format!("Failed to read file: {path:?}"))?;
+
+    Ok(hex::encode(writer.finish().1.finish()))
+}
+
+/// Download the OTA to a temporary file next to `output` and only move it into
+/// place once the checksum has been verified.
+fn download_ota(entry: &IndexEntry, output: &Path, cancel_signal: &AtomicBool) -> Result<()> {
+    let mut temp_path = output.as_os_str().to_owned();
+    temp_path.push(".part");
+    let temp_path = PathBuf::from(temp_path);
+
+    status!("Downloading {}", entry.url);
+
+    let reader = http_get(&entry.url, None)?;
+    let writer = sandbox::create(&temp_path)
+        .map(BufWriter::new)
+        .with_context(|| format!("Failed to open for writing: {temp_path:?}"))?;
+    let mut writer = HashingWriter::new(writer, DigestContext::new(&crate::digest::SHA256));
+
+    let size = stream::copy(reader, &mut writer, cancel_signal)
+        .with_context(|| format!("Failed to download: {}", entry.url))?;
+
+    let (mut writer, context) = writer.finish();
+    writer
+        .flush()
+        .with_context(|| format!("Failed to flush writes: {temp_path:?}"))?;
+    drop(writer);
+
+    let digest = hex::encode(context.finish());
+    if digest != entry.sha256 {
+        if let Err(e) = sandbox::remove_file(&temp_path) {
+            warning!("Failed to delete {temp_path:?}: {e}");
+        }
+
+        bail!(
+            "Expected sha256 checksum {}, but have {digest}: {}",
+            entry.sha256,
+            entry.url,
+        );
+    }
+
+    sandbox::rename(&temp_path, output)
+        .with_context(|| format!("Failed to move {temp_path:?} to {output:?}"))?;
+
+    status!("Downloaded {size} bytes to {output:?}");
+
+    Ok(())
+}
+
+pub fn download_subcommand(cli: &DownloadCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let sources = load_sources(cli.sources.as_deref())?;
+    let source = sources
+        .sources
+        .get(&cli.source)
+        .ok_or_else(|| anyhow!("OTA source not found: {}", cli.source))?;
+
+    let cookie = match &source.tos_cookie {
+        Some(_) if !cli.accept_tos => bail!(
+            "OTA source {} requires accepting the terms of service at {} (--accept-tos)",
+            cli.source,
+            source.url,
+        ),
+        c => c.as_deref(),
+    };
+
+    let entry = find_ota(source, cookie, &cli.device, &cli.build)?;
+
+    status!(
+        "Found OTA for {} build {}: {}",
+        entry.device,
+        entry.build,
+        entry.file_name,
+    );
+
+    let output = cli
+        .output
+        .clone()
+        .unwrap_or_else(|| PathBuf::from(&entry.file_name));
+
+    if sandbox::open(&output).is_ok() && sha256_file(&output, cancel_signal)? == entry.sha256 {
+        status!("Already downloaded: {output:?}");
+    } else {
+        download_ota(&entry, &output, cancel_signal)?;
+    }
+
+    if !cli.patch_args.is_empty() {
+        let args = [
+            OsStr::new("patch"),
+            OsStr::new("--input"),
+            output.as_os_str(),
+        ]
+        .into_iter()
+        .chain(cli.patch_args.iter().map(|a| a.as_os_str()))
+        .map(OsString::from);
+        let patch_cli: PatchCli = profile::parse_args(args, &[&[]])?;
+
+        ota::patch_subcommand(&patch_cli, cancel_signal)?;
+    }
+
+    Ok(())
+}
+
+/// Download an official full OTA.
+///
+/// The OTA is looked up in the specified source's index, which lists the
+/// available OTAs and their sha256 checksums. The download is verified against
+/// the published checksum. If the output file already exists with the correct
+/// checksum, the download is skipped.
+///
+/// Some sources, including Google's OTA page, require accepting their terms of
+/// service. This must be done explicitly with --accept-tos.
+///
+/// To patch the OTA immediately after downloading it, pass the `avbroot ota
+/// patch` arguments after `--`. The --input option is filled in automatically.
+#[derive(Debug, Parser)]
+pub struct DownloadCli {
+    /// Device codename.
+    #[arg(short, long, value_name = "CODENAME")]
+    pub device: String,
+
+    /// Build ID to download.
+    ///
+    /// The special value `latest` selects the last OTA listed for the device.
+    #[arg(short, long, value_name = "BUILD_ID", default_value = "latest")]
+    pub build: String,
+
+    /// Path to output OTA zip.
+    ///
+    /// By default, the file name from the download URL is used.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: Option<PathBuf>,
+
+    /// Name of the OTA source.
+    #[arg(long, value_name = "NAME", default_value = "google")]
+    pub source: String,
+
+    /// Path to custom OTA source list.
+    ///
+    /// Sources in this TOML file are added to the built-in sources. They
+    /// replace built-in sources with the same name.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub sources: Option<PathBuf>,
+
+    /// Accept the OTA source's terms of service.
+    ///
+    /// For the built-in `google` source, these are the terms shown on
+    /// https://developers.google.com/android/ota.
+    #[arg(long)]
+    pub accept_tos: bool,
+
+    /// Arguments to pass to `avbroot ota patch`.
+    #[arg(last = true, value_name = "PATCH_ARGS")]
+    pub patch_args: Vec<OsString>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_google() {
+        let data = r#"
+            <tr id="husky">
+              <td>14.0.0 (UQ1A.240105.004, Jan 2024)</td>
+              <td><a href="https://dl.google.com/dl/android/aosp/husky-ota-uq1a.240105.004-b1c2d3e4.zip">Link</a></td>
+              <td>
+                0123456789abcdef0123456789ABCDEF0123456789abcdef0123456789abcdef
+              </td>
+            </tr>
+            <tr>
+              <td>Incremental</td>
+              <td><a href="https://dl.google.com/dl/android/aosp/husky-ota-incremental.txt.zip">Link</a></td>
+              <td>not a checksum</td>
+            </tr>
+            <tr id="shiba">
+              <td><a href="https://dl.google.com/dl/android/aosp/shiba-ota-ap1a.240305.019.a1.zip">Link</a></td>
+              <td>fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210</td>
+            </tr>
+        "#;
+
+        let entries = parse_google_index(data);
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].device, "husky");
+        assert_eq!(entries[0].build, "uq1a.240105.004");
+        assert_eq!(
+            entries[0].file_name,
+            "husky-ota-uq1a.240105.004-b1c2d3e4.zip"
+        );
+        assert_eq!(
+            entries[0].sha256,
+            "0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef",
+        );
+
+        assert_eq!(entries[1].device, "shiba");
+        assert_eq!(entries[1].build, "ap1a.240305.019.a1");
+        assert_eq!(
+            entries[1].url,
+            "https://dl.google.com/dl/android/aosp/shiba-ota-ap1a.240305.019.a1.zip",
+        );
+    }
+
+    #[test]
+    fn parse_sha256sum() {
+        let data = "\
+            0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef  https://example.com/a/cheetah-ota-td1a.220804.031.zip
+            fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210 *panther-ota-tq3a.230901.001-12345678.zip
+
+            abcd  https://example.com/panther-ota-tq3a.230805.001.zip
+            0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef  https://example.com/panther-img-tq3a.230805.001.zip
+        ";
+
+        let entries = parse_sha256sum_index(data);
+        assert_eq!(entries.len(), 2);
+
+        assert_eq!(entries[0].device, "cheetah");
+        assert_eq!(entries[0].build, "td1a.220804.031");
+        assert_eq!(
+            entries[0].url,
+            "https://example.com/a/cheetah-ota-td1a.220804.031.zip"
+        );
+
+        assert_eq!(entries[1].device, "panther");
+        assert_eq!(entries[1].build, "tq3a.230901.001");
+        assert_eq!(
+            entries[1].file_name,
+            "panther-ota-tq3a.230901.001-12345678.zip"
+        );
+        assert_eq!(
+            entries[1].sha256,
+            "fedcba9876543210fedcba9876543210fedcba9876543210fedcba9876543210",
+        );
+    }
+
+    #[test]
+    fn builtin_google_source_requires_tos() {
+        let sources = load_sources(None).unwrap();
+        let source = &sources.sources["google"];
+
+        assert_eq!(source.format, IndexFormat::Google);
+        assert!(source.tos_cookie.is_some());
+    }
+}
@@ -0,0 +1,1021 @@
+/*
+ * SPDX-FileCopyrightText: 2023 Andrew Gunnerson
+ * SPDX-FileCopyrightText: 2023 Pascal Roeleven
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{BufWriter, Cursor, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::Parser;
+use serde::{Deserialize, Serialize};
+use toml_edit::Document;
+use topological_sort::TopologicalSort;
+use x509_cert::Certificate;
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+use crate::{
+    cli::status,
+    crypto::{self, PassphraseSource, RsaSigningKey},
+    format::{
+        avb::{
+            self, AlgorithmType, ChainPartitionDescriptor, Descriptor, Footer, HashDescriptor,
+            HashTreeDescriptor, Header, PropertyDescriptor,
+        },
+        bootimage::{
+            self, BootImage, BootImageV0Through2, BootImageV3Through4, RamdiskMeta, V1Extra,
+            V2Extra, V4Extra, VendorBootImageV3Through4, VendorV4Extra,
+        },
+        compression::{CompressedFormat, CompressedWriter},
+        cpio::{self, CpioEntry, CpioEntryData},
+        ota::{self, SigningWriter, ZipEntry},
+        padding,
+        payload::{self, CompressOptions, PayloadHeader, PayloadWriter},
+    },
+    patch::otacert::{self, OtaCertBuildFlags},
+    protobuf::{
+        build::tools::releasetools::{ota_metadata::OtaType, DeviceState, OtaMetadata},
+        chromeos_update_engine::{
+            DeltaArchiveManifest, DynamicPartitionGroup, DynamicPartitionMetadata, PartitionUpdate,
+        },
+    },
+    sandbox,
+    stream::{self, CountingWriter, PSeekFile, Reopen, ToWriter},
+};
+
+/// Built-in profiles. These are the profiles used by the end-to-end tests.
+static BUILTIN_PROFILES: &str = include_str!("../../../e2e/e2e.toml");
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct OtaInfo {
+    pub device: String,
+    pub fingerprint: String,
+    pub build_number: String,
+    pub incremental_version: String,
+    pub android_version: String,
+    pub sdk_version: String,
+    pub security_patch_level: String,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Avb {
+    pub signed: bool,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RamdiskContent {
+    Init,
+    Otacerts,
+    InitAndOtacerts,
+    Dlkm,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BootVersion {
+    V2,
+    V3,
+    V4,
+    VendorV3,
+    VendorV4,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BootData {
+    pub version: BootVersion,
+    #[serde(default)]
+    pub kernel: bool,
+    #[serde(default)]
+    pub ramdisks: Vec<RamdiskContent>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DmVerityContent {
+    SystemOtacerts,
+    ErofsOtacerts,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DmVerityData {
+    pub content: DmVerityContent,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct VbmetaData {
+    pub deps: Vec<String>,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Data {
+    Boot(BootData),
+    DmVerity(DmVerityData),
+    Vbmeta(VbmetaData),
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Partition {
+    pub avb: Avb,
+    pub data: Data,
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub partitions: BTreeMap<String, Partition>,
+    /// Whether to write the OTA zip with zip64 extensions.
+    #[serde(default)]
+    pub zip64: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FakeConfig {
+    pub ota_info: OtaInfo,
+    #[serde(default)]
+    pub profile: BTreeMap<String, Profile>,
+}
+
+impl FakeConfig {
+    /// Load the built-in profiles.
+    pub fn builtin() -> Self {
+        let mut document = BUILTIN_PROFILES
+            .parse::<Document>()
+            .expect("Invalid built-in fake OTA profiles");
+
+        // The expected checksums are only used by the end-to-end tests.
+        if let Some(profiles) = document
+            .get_mut("profile")
+            .and_then(|p| p.as_table_like_mut())
+        {
+            for (_, profile) in profiles.iter_mut() {
+                if let Some(p) = profile.as_table_like_mut() {
+                    p.remove("hashes");
+                }
+            }
+        }
+
+        toml_edit::de::from_document(document).expect("Invalid built-in fake OTA profiles")
+    }
+}
+
+fn append_avb(
+    file: &mut PSeekFile,
+    name: &str,
+    avb: &Avb,
+    hash_tree: bool,
+    ota_info: &OtaInfo,
+    key_avb: &RsaSigningKey,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let image_size = file.seek(SeekFrom::End(0))?;
+    let salt = crate::digest::digest(&crate::digest::SHA256, b"avbroot");
+    let descriptors = vec![
+        if hash_tree {
+            let mut descriptor = HashTreeDescriptor {
+                dm_verity_version: 1,
+                image_size,
+                tree_offset: 0,
+                tree_size: 0,
+                data_block_size: 4096,
+                hash_block_size: 4096,
+                fec_num_roots: 2,
+                fec_offset: 0,
+                fec_size: 0,
+                hash_algorithm: "sha256".to_owned(),
+                partition_name: name.to_owned(),
+                salt: salt.as_ref().to_vec(),
+                root_digest: Vec::new(),
+                flags: 0,
+                reserved: [0u8; 60],
+            };
+
+            descriptor.update(file, file, None, cancel_signal)?;
+
+            Descriptor::HashTree(descriptor)
+        } else {
+            let mut descriptor = HashDescriptor {
+                image_size,
+                hash_algorithm: "sha256".to_owned(),
+                partition_name: name.to_owned(),
+                salt: salt.as_ref().to_vec(),
+                root_digest: Vec::new(),
+                flags: 0,
+                reserved: [0u8; 60],
+            };
+
+            file.rewind()?;
+            descriptor.update(&mut *file, cancel_signal)?;
+
+            Descriptor::Hash(descriptor)
+        },
+        Descriptor::Property(PropertyDescriptor {
+            key: format!("com.android.build.{name}.os_version"),
+            value: ota_info.android_version.clone().into(),
+        }),
+        Descriptor::Property(PropertyDescriptor {
+            key: format!("com.android.build.{name}.fingerprint"),
+            value: ota_info.fingerprint.clone().into(),
+        }),
+        Descriptor::Property(PropertyDescriptor {
+            key: format!("com.android.build.{name}.security_patch"),
+            value: ota_info.security_patch_level.clone().into(),
+        }),
+    ];
+
+    let mut header = Header {
+        required_libavb_version_major: avb::VERSION_MAJOR,
+        required_libavb_version_minor: avb::VERSION_MINOR,
+        algorithm_type: AlgorithmType::None,
+        hash: Vec::new(),
+        signature: Vec::new(),
+        public_key: Vec::new(),
+        public_key_metadata: Vec::new(),
+        descriptors,
+        rollback_index: 0,
+        flags: 0,
+        rollback_index_location: 0,
+        release_string: "avbroot".to_owned(),
+        reserved: [0u8; 80],
+    };
+
+    if avb.signed {
+        header.set_algo_for_key(key_avb, None)?;
+        header.sign(key_avb)?;
+    }
+
+    let mut footer = Footer {
+        version_major: avb::FOOTER_VERSION_MAJOR,
+        version_minor: avb::FOOTER_VERSION_MINOR,
+        original_image_size: image_size,
+        vbmeta_offset: 0,
+        vbmeta_size: 0,
+        reserved: Default::default(),
+    };
+
+    let eof_size = file.seek(SeekFrom::End(0))?;
+    let full_image_size = eof_size
+        .checked_add(8192)
+        .and_then(|s| padding::round(s, 4096))
+        .ok_or_else(|| anyhow!("Image size {image_size} is too large"))?
+        // Give enough free space for changes from patching.
+        .max(1024 * 1024);
+
+    avb::write_appended_image(file, &header, &mut footer, full_image_size)?;
+
+    Ok(())
+}
+
+fn ramdisk_add_init(entries: &mut Vec<CpioEntry>) {
+    entries.push(CpioEntry::new_file(
+        b"init",
+        0o755,
+        CpioEntryData::Data(vec![]),
+    ));
+}
+
+fn ramdisk_add_otacerts(entries: &mut Vec<CpioEntry>, cert_ota: &Certificate) -> Result<()> {
+    for path in [
+        b"system".as_slice(),
+        b"system/etc".as_slice(),
+        b"system/etc/security".as_slice(),
+    ] {
+        entries.push(CpioEntry::new_directory(path, 0o755));
+    }
+
+    entries.push(CpioEntry::new_file(
+        b"system/etc/security/otacerts.zip",
+        0o644,
+        CpioEntryData::Data(otacert::create_zip(cert_ota, OtaCertBuildFlags::empty())?),
+    ));
+
+    Ok(())
+}
+
+fn ramdisk_add_dlkm(entries: &mut Vec<CpioEntry>) {
+    for path in [b"lib".as_slice(), b"lib/modules".as_slice()] {
+        entries.push(CpioEntry::new_directory(path, 0o755));
+    }
+
+    for path in [
+        b"lib/modules/foo.ko".as_slice(),
+        b"lib/modules/bar.ko".as_slice(),
+    ] {
+        entries.push(CpioEntry::new_file(
+            path,
+            0o644,
+            CpioEntryData::Data(vec![]),
+        ));
+    }
+}
+
+fn create_ramdisk(
+    content: RamdiskContent,
+    cert_ota: &Certificate,
+    cancel_signal: &AtomicBool,
+) -> Result<Vec<u8>> {
+    let mut entries = vec![];
+
+    match content {
+        RamdiskContent::Init => {
+            ramdisk_add_init(&mut entries);
+        }
+        RamdiskContent::Otacerts => {
+            ramdisk_add_otacerts(&mut entries, cert_ota)?;
+        }
+        RamdiskContent::InitAndOtacerts => {
+            ramdisk_add_init(&mut entries);
+            ramdisk_add_otacerts(&mut entries, cert_ota)?;
+        }
+        RamdiskContent::Dlkm => {
+            ramdisk_add_dlkm(&mut entries);
+        }
+    }
+
+    cpio::sort(&mut entries);
+
+    let raw_writer = Cursor::new(Vec::new());
+    let mut writer = CompressedWriter::new(raw_writer, CompressedFormat::Lz4Legacy)?;
+
+    cpio::save(&mut writer, &entries, false, cancel_signal)?;
+
+    let raw_writer = writer.finish()?;
+
+    Ok(raw_writer.into_inner())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_boot_image(
+    file: &mut PSeekFile,
+    name: &str,
+    avb: &Avb,
+    boot_data: &BootData,
+    ota_info: &OtaInfo,
+    key_avb: &RsaSigningKey,
+    cert_ota: &Certificate,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let kernel = if boot_data.kernel {
+        b"kernel".to_vec()
+    } else {
+        Vec::new()
+    };
+    let ramdisks = boot_data
+        .ramdisks
+        .iter()
+        .map(|c| create_ramdisk(*c, cert_ota, cancel_signal))
+        .collect::<Result<Vec<_>>>()?;
+
+    let boot_image = match boot_data.version {
+        BootVersion::V2 => {
+            if ramdisks.len() > 1 {
+                bail!("v2 boot images can have at most one ramdisk");
+            }
+
+            BootImage::V0Through2(BootImageV0Through2 {
+                kernel_addr: 0,
+                ramdisk_addr: 0,
+                second_addr: 0,
+                tags_addr: 0,
+                page_size: 4096,
+                os_version: 0,
+                name: String::new(),
+                cmdline: String::new(),
+                id: Default::default(),
+                extra_cmdline: String::new(),
+                kernel,
+                ramdisk: ramdisks.into_iter().next().unwrap_or_default(),
+                second: Vec::new(),
+                v1_extra: Some(V1Extra {
+                    recovery_dtbo_offset: 0,
+                    recovery_dtbo: Vec::new(),
+                }),
+                v2_extra: Some(V2Extra {
+                    dtb_addr: 0,
+                    dtb: Vec::new(),
+                }),
+            })
+        }
+        BootVersion::V3 | BootVersion::V4 => {
+            if ramdisks.len() > 1 {
+                bail!("v3/v4 boot images can have at most one ramdisk");
+            }
+
+            let v4_extra = if boot_data.version == BootVersion::V4 {
+                Some(V4Extra { signature: None })
+            } else {
+                None
+            };
+
+            BootImage::V3Through4(BootImageV3Through4 {
+                os_version: 0,
+                reserved: Default::default(),
+                cmdline: String::new(),
+                v4_extra,
+                kernel,
+                ramdisk: ramdisks.into_iter().next().unwrap_or_default(),
+            })
+        }
+        BootVersion::VendorV3 | BootVersion::VendorV4 => {
+            if boot_data.version == BootVersion::VendorV3 && ramdisks.len() > 1 {
+                bail!("Vendor v3 boot images can have at most one ramdisk");
+            }
+
+            let v4_extra = if boot_data.version == BootVersion::VendorV4 {
+                Some(VendorV4Extra {
+                    ramdisk_metas: boot_data
+                        .ramdisks
+                        .iter()
+                        .map(|c| match c {
+                            RamdiskContent::Dlkm => RamdiskMeta {
+                                ramdisk_type: bootimage::VENDOR_RAMDISK_TYPE_DLKM,
+                                ramdisk_name: "dlkm".to_owned(),
+                                board_id: Default::default(),
+                            },
+                            _ => RamdiskMeta {
+                                ramdisk_type: bootimage::VENDOR_RAMDISK_TYPE_PLATFORM,
+                                ramdisk_name: String::new(),
+                                board_id: Default::default(),
+                            },
+                        })
+                        .collect(),
+                    bootconfig: String::new(),
+                })
+            } else {
+                None
+            };
+
+            BootImage::VendorV3Through4(VendorBootImageV3Through4 {
+                page_size: 2048,
+                kernel_addr: 0,
+                ramdisk_addr: 0,
+                cmdline: String::new(),
+                tags_addr: 0,
+                name: String::new(),
+                dtb: Vec::new(),
+                dtb_addr: 0,
+                ramdisks,
+                v4_extra,
+            })
+        }
+    };
+
+    boot_image.to_writer(&mut *file)?;
+
+    append_avb(file, name, avb, false, ota_info, key_avb, cancel_signal)
+        .with_context(|| format!("Failed to append AVB metadata for {name}"))?;
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn create_dm_verity_image(
+    file: &mut PSeekFile,
+    name: &str,
+    avb: &Avb,
+    dm_verity_data: &DmVerityData,
+    ota_info: &OtaInfo,
+    key_avb: &RsaSigningKey,
+    cert_ota: &Certificate,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    match dm_verity_data.content {
+        DmVerityContent::SystemOtacerts => {
+            file.write_all(b"arbitrary_prefix")?;
+
+            let data = otacert::create_zip(cert_ota, OtaCertBuildFlags::empty())?;
+            file.write_all(&data)?;
+
+            file.write_all(b"arbitrary_suffix")?;
+        }
+        DmVerityContent::ErofsOtacerts => {
+            // Minimal EROFS superblock (magic and 4096-byte block size) with
+            // otacerts.zip stored uncompressed in its own data block, which is
+            // how EROFS stores small files when compression is disabled.
+            let mut superblock = [0u8; 4096];
+            superblock[1024..1028].copy_from_slice(&0xE0F5_E1E2u32.to_le_bytes());
+            superblock[1036] = 12;
+            file.write_all(&superblock)?;
+
+            let data = otacert::create_zip(cert_ota, OtaCertBuildFlags::empty())?;
+            file.write_all(&data)?;
+        }
+    }
+
+    padding::write_zeros(&mut *file, 4096)?;
+
+    append_avb(file, name, avb, true, ota_info, key_avb, cancel_signal)
+        .with_context(|| format!("Failed to append AVB metadata for {name}"))?;
+
+    Ok(())
+}
+
+fn create_vbmeta_image(
+    file: &mut PSeekFile,
+    name: &str,
+    avb: &Avb,
+    vbmeta_data: &VbmetaData,
+    inputs: &BTreeMap<String, PSeekFile>,
+    key: &RsaSigningKey,
+) -> Result<()> {
+    let mut descriptors = Vec::new();
+
+    for dep in &vbmeta_data.deps {
+        let reader = inputs[dep].reopen()?;
+        let (child_header, _, _) =
+            avb::load_image(reader).with_context(|| format!("Failed to parse AVB image: {dep}"))?;
+
+        if child_header.public_key.is_empty() {
+            descriptors.extend(child_header.descriptors);
+        } else {
+            descriptors.push(Descriptor::ChainPartition(ChainPartitionDescriptor {
+                rollback_index_location: 0,
+                partition_name: dep.to_owned(),
+                public_key: child_header.public_key,
+                flags: 0,
+                reserved: [0u8; 60],
+            }));
+        }
+    }
+
+    let mut header = Header {
+        required_libavb_version_major: avb::VERSION_MAJOR,
+        required_libavb_version_minor: avb::VERSION_MINOR,
+        algorithm_type: AlgorithmType::None,
+        hash: Vec::new(),
+        signature: Vec::new(),
+        public_key: Vec::new(),
+        public_key_metadata: Vec::new(),
+        descriptors,
+        rollback_index: 0,
+        flags: 0,
+        rollback_index_location: 0,
+        release_string: String::new(),
+        reserved: [0u8; 80],
+    };
+
+    if avb.signed {
+        header.set_algo_for_key(key, None)?;
+        header.sign(key)?;
+    }
+
+    avb::write_root_image(file, &header, 4096)
+        .with_context(|| format!("Failed to create vbmeta image: {name}"))?;
+
+    Ok(())
+}
+
+fn create_partition_images(
+    partitions: &BTreeMap<String, Partition>,
+    ota_info: &OtaInfo,
+    key_avb: &RsaSigningKey,
+    cert_ota: &Certificate,
+    cancel_signal: &AtomicBool,
+) -> Result<BTreeMap<String, PSeekFile>> {
+    let mut topo = TopologicalSort::<&String>::new();
+
+    for (name, partition) in partitions {
+        if let Data::Vbmeta(data) = &partition.data {
+            for dep in &data.deps {
+                topo.add_dependency(dep, name);
+            }
+        }
+    }
+
+    let mut files = BTreeMap::new();
+
+    while !topo.is_empty() {
+        let Some(name) = topo.pop() else {
+            bail!("vbmeta dependency graph has cycle: {topo:?}");
+        };
+        let partition = &partitions[name];
+
+        let mut file = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for {name}"))?;
+
+        match &partition.data {
+            Data::Boot(data) => {
+                create_boot_image(
+                    &mut file,
+                    name,
+                    &partition.avb,
+                    data,
+                    ota_info,
+                    key_avb,
+                    cert_ota,
+                    cancel_signal,
+                )
+                .with_context(|| format!("Failed to create boot image: {name}"))?;
+            }
+            Data::DmVerity(data) => {
+                create_dm_verity_image(
+                    &mut file,
+                    name,
+                    &partition.avb,
+                    data,
+                    ota_info,
+                    key_avb,
+                    cert_ota,
+                    cancel_signal,
+                )
+                .with_context(|| format!("Failed to create dm-verity image: {name}"))?;
+            }
+            Data::Vbmeta(data) => {
+                create_vbmeta_image(&mut file, name, &partition.avb, data, &files, key_avb)
+                    .with_context(|| format!("Failed to create vbmeta image: {name}"))?;
+            }
+        }
+
+        files.insert(name.clone(), file);
+    }
+
+    Ok(files)
+}
+
+fn create_payload(
+    writer: impl Write,
+    partitions: &BTreeMap<String, Partition>,
+    inputs: &BTreeMap<String, PSeekFile>,
+    ota_info: &OtaInfo,
+    key_ota: &RsaSigningKey,
+    cancel_signal: &AtomicBool,
+) -> Result<(String, u64)> {
+    let dynamic_partitions_names = partitions
+        .iter()
+        .filter(|(_, p)| matches!(&p.data, Data::DmVerity(_)))
+        .map(|(n, _)| n.clone())
+        .collect::<Vec<_>>();
+
+    let mut payload_partitions = vec![];
+    let mut compressed = BTreeMap::<&String, PSeekFile>::new();
+
+    for (name, file) in inputs {
+        let writer = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+        let (partition_info, operations) = payload::compress_image(
+            file,
+            &writer,
+            name,
+            4096,
+            &CompressOptions::default(),
+            cancel_signal,
+        )?;
+
+        compressed.insert(name, writer);
+
+        payload_partitions.push(PartitionUpdate {
+            partition_name: name.clone(),
+            run_postinstall: None,
+            postinstall_path: None,
+            filesystem_type: None,
+            new_partition_signature: vec![],
+            old_partition_info: None,
+            new_partition_info: Some(partition_info),
+            operations,
+            postinstall_optional: None,
+            hash_tree_data_extent: None,
+            hash_tree_extent: None,
+            hash_tree_algorithm: None,
+            hash_tree_salt: None,
+            fec_data_extent: None,
+            fec_extent: None,
+            fec_roots: None,
+            version: None,
+            merge_operations: vec![],
+            estimate_cow_size: None,
+        });
+    }
+
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: Some(4096),
+            signatures_offset: None,
+            signatures_size: None,
+            minor_version: Some(0),
+            partitions: payload_partitions,
+            max_timestamp: None,
+            dynamic_partition_metadata: Some(DynamicPartitionMetadata {
+                groups: vec![DynamicPartitionGroup {
+                    name: "avbroot_dynamic_partitions".to_string(),
+                    size: Some(1024 * 1024 * 1024),
+                    partition_names: dynamic_partitions_names,
+                }],
+                snapshot_enabled: Some(true),
+                vabc_enabled: Some(true),
+                vabc_compression_param: Some("gz".to_owned()),
+                cow_version: Some(2),
+                vabc_feature_set: None,
+            }),
+            partial_update: None,
+            apex_info: vec![],
+            security_patch_level: Some(ota_info.security_patch_level.clone()),
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+    };
+
+    let mut payload_writer = PayloadWriter::new(writer, header.clone(), key_ota.clone())
+        .context("Failed to write payload header")?;
+
+    while payload_writer
+        .begin_next_operation()
+        .context("Failed to begin next payload blob entry")?
+    {
+        let name = payload_writer.partition().unwrap().partition_name.clone();
+        let operation = payload_writer.operation().unwrap();
+
+        let Some(data_length) = operation.data_length else {
+            // Otherwise, this is a ZERO/DISCARD operation.
+            continue;
+        };
+
+        let pi = payload_writer.partition_index().unwrap();
+        let oi = payload_writer.operation_index().unwrap();
+        let orig_partition = &header.manifest.partitions[pi];
+        let orig_operation = &orig_partition.operations[oi];
+        let data_offset = orig_operation
+            .data_offset
+            .ok_or_else(|| anyhow!("Missing data_offset in partition #{pi} operation #{oi}"))?;
+
+        let file = compressed.get_mut(&name).unwrap();
+        file.seek(SeekFrom::Start(data_offset))
+            .with_context(|| format!("Failed to seek image: {name}"))?;
+
+        stream::copy_n(file, &mut payload_writer, data_length, cancel_signal)
+            .with_context(|| format!("Failed to copy from image: {name}"))?;
+    }
+
+    let (_, properties, metadata_size) = payload_writer
+        .finish()
+        .context("Failed to finalize payload")?;
+
+    Ok((properties, metadata_size))
+}
+
+/// Create a fake OTA from the profile. All images are signed with `key_avb`
+/// where applicable and the OTA is signed with `key_ota`.
+pub fn create_ota(
+    output: &Path,
+    ota_info: &OtaInfo,
+    profile: &Profile,
+    key_avb: &RsaSigningKey,
+    key_ota: &RsaSigningKey,
+    cert_ota: &Certificate,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let inputs = create_partition_images(
+        &profile.partitions,
+        ota_info,
+        key_avb,
+        cert_ota,
+        cancel_signal,
+    )?;
+
+    let raw_writer = sandbox::create(output)
+        .with_context(|| format!("Failed to open for writing: {output:?}"))?;
+    let buffered_writer = BufWriter::new(raw_writer);
+    let signing_writer = SigningWriter::new(buffered_writer);
+    let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(profile.zip64);
+
+    let mut entries = vec![];
+    let mut properties = None;
+    let mut payload_metadata_size = None;
+
+    for path in [ota::PATH_OTACERT, ota::PATH_PAYLOAD, ota::PATH_PROPERTIES] {
+        // All remaining entries are written immediately.
+        zip_writer
+            .start_file_with_extra_data(path, options)
+            .with_context(|| format!("Failed to begin new zip entry: {path}"))?;
+        let offset = zip_writer
+            .end_extra_data()
+            .with_context(|| format!("Failed to end new zip entry: {path}"))?;
+        let mut writer = CountingWriter::new(&mut zip_writer);
+
+        match path {
+            ota::PATH_OTACERT => {
+                crypto::write_pem_cert(&mut writer, cert_ota)
+                    .with_context(|| format!("Failed to write entry: {path}"))?;
+            }
+            ota::PATH_PAYLOAD => {
+                let (p, m) = create_payload(
+                    &mut writer,
+                    &profile.partitions,
+                    &inputs,
+                    ota_info,
+                    key_ota,
+                    cancel_signal,
+                )
+                .context("Failed to create payload")?;
+
+                properties = Some(p);
+                payload_metadata_size = Some(m);
+            }
+            ota::PATH_PROPERTIES => {
+                writer
+                    .write_all(properties.as_ref().unwrap().as_bytes())
+                    .with_context(|| format!("Failed to write payload properties: {path}"))?;
+            }
+            _ => unreachable!(),
+        }
+
+        // Cannot fail.
+        let size = writer.stream_position()?;
+
+        entries.push(ZipEntry {
+            name: path.to_owned(),
+            offset,
+            size,
+        });
+    }
+
+    let metadata = OtaMetadata {
+        r#type: OtaType::Ab.into(),
+        wipe: false,
+        downgrade: false,
+        property_files: BTreeMap::new(),
+        precondition: Some(DeviceState {
+            device: vec![ota_info.device.clone()],
+            build: vec![],
+            build_incremental: String::new(),
+            timestamp: 0,
+            sdk_level: String::new(),
+            security_patch_level: String::new(),
+            partition_state: vec![],
+        }),
+        postcondition: Some(DeviceState {
+            device: vec![ota_info.device.clone()],
+            build: vec![ota_info.fingerprint.clone()],
+            build_incremental: ota_info.incremental_version.clone(),
+            timestamp: 0,
+            sdk_level: ota_info.sdk_version.clone(),
+            security_patch_level: ota_info.security_patch_level.clone(),
+            partition_state: vec![],
+        }),
+        retrofit_dynamic_partitions: false,
+        required_cache: 0,
+        spl_downgrade: false,
+    };
+
+    ota::add_metadata(
+        &entries,
+        &mut zip_writer,
+        // Offset where next entry would begin, after the data descriptor.
+        entries.last().map(|e| e.offset + e.size).unwrap() + if profile.zip64 { 24 } else { 16 },
+        &metadata,
+        payload_metadata_size.unwrap(),
+    )
+    .context("Failed to write new OTA metadata")?;
+
+    let signing_writer = zip_writer
+        .finish()
+        .context("Failed to finalize output zip")?;
+    let mut buffered_writer = signing_writer
+        .finish(key_ota, cert_ota)
+        .context("Failed to sign output zip")?;
+    buffered_writer
+        .flush()
+        .context("Failed to flush output zip")?;
+
+    Ok(())
+}
+
+pub fn fake_subcommand(cli: &FakeCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let config = FakeConfig::builtin();
+
+    let Some(profile) = config.profile.get(&cli.profile) else {
+        bail!(
+            "Unknown profile: {} (available: {})",
+            cli.profile,
+            config
+                .profile
+                .keys()
+                .map(|k| k.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        );
+    };
+
+    let source_avb = PassphraseSource::new(
+        &cli.key_avb,
+        cli.pass_avb_file.as_deref(),
+        cli.pass_avb_env_var.as_deref(),
+    );
+    let source_ota = PassphraseSource::new(
+        &cli.key_ota,
+        cli.pass_ota_file.as_deref(),
+        cli.pass_ota_env_var.as_deref(),
+    );
+
+    let key_avb = crypto::read_signing_key(&cli.key_avb, &source_avb)
+        .with_context(|| format!("Failed to load key: {:?}", cli.key_avb))?;
+    let key_ota = crypto::read_signing_key(&cli.key_ota, &source_ota)
+        .with_context(|| format!("Failed to load key: {:?}", cli.key_ota))?;
+    let cert_ota = crypto::read_pem_cert_file(&cli.cert_ota)
+        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+
+    status!("Generating fake OTA from profile: {}", cli.profile);
+
+    create_ota(
+        &cli.output,
+        &config.ota_info,
+        profile,
+        &key_avb,
+        &key_ota,
+        &cert_ota,
+        cancel_signal,
+    )
+    .with_context(|| format!("Failed to create OTA: {:?}", cli.output))?;
+
+    status!("Wrote fake OTA: {:?}", cli.output);
+
+    Ok(())
+}
+
+/// Generate a tiny, but structurally complete, fake OTA.
+///
+/// The OTA contains real AVB chains, a real payload, and valid OTA metadata,
+/// but the partition images contain no meaningful data. This is useful for
+/// experimenting with keys and for reproducing issues without sharing large
+/// files. The OTA lists a fake device name in its preconditions so that it
+/// cannot be installed on a real device.
+#[derive(Debug, Parser)]
+pub struct FakeCli {
+    /// Path to output OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: PathBuf,
+
+    /// Built-in device layout profile.
+    ///
+    /// The available profiles are: pixel_v4_gki, pixel_v4_non_gki, pixel_v3,
+    /// and pixel_v2.
+    #[arg(short, long, value_name = "NAME")]
+    pub profile: String,
+
+    /// Private key for signing vbmeta images.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub key_avb: PathBuf,
+
+    /// Private key for signing the OTA.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub key_ota: PathBuf,
+
+    /// Certificate for OTA signing key.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub cert_ota: PathBuf,
+
+    /// Environment variable containing AVB private key passphrase.
+    #[arg(long, value_name = "ENV_VAR", value_parser, group = "pass_avb")]
+    pub pass_avb_env_var: Option<OsString>,
+
+    /// File containing AVB private key passphrase.
+    #[arg(long, value_name = "FILE", value_parser, group = "pass_avb")]
+    pub pass_avb_file: Option<PathBuf>,
+
+    /// Environment variable containing OTA private key passphrase.
+    #[arg(long, value_name = "ENV_VAR", value_parser, group = "pass_ota")]
+    pub pass_ota_env_var: Option<OsString>,
+
+    /// File containing OTA private key passphrase.
+    #[arg(long, value_name = "FILE", value_parser, group = "pass_ota")]
+    pub pass_ota_file: Option<PathBuf>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn load_builtin_profiles() {
+        let config = FakeConfig::builtin();
+
+        assert_eq!(
+            config
+                .profile
+                .keys()
+                .map(|k| k.as_str())
+                .collect::<Vec<_>>(),
+            ["pixel_v2", "pixel_v3", "pixel_v4_gki", "pixel_v4_non_gki"],
+        );
+    }
+}
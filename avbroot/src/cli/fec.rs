@@ -4,7 +4,6 @@
  */
 
 use std::{
-    fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Write},
     path::{Path, PathBuf},
     sync::atomic::AtomicBool,
@@ -15,11 +14,12 @@ use clap::{Parser, Subcommand};
 
 use crate::{
     format::fec::FecImage,
+    sandbox,
     stream::{FromReader, PSeekFile, ToWriter},
 };
 
 fn open_input(path: &Path, rw: bool) -> Result<PSeekFile> {
-    OpenOptions::new()
+    sandbox::OpenOptions::new()
         .read(true)
         .write(rw)
         .open(path)
@@ -28,7 +28,7 @@ fn open_input(path: &Path, rw: bool) -> Result<PSeekFile> {
 }
 
 fn read_fec(path: &Path) -> Result<FecImage> {
-    let reader = File::open(path)
+    let reader = sandbox::open(path)
         .map(BufReader::new)
         .with_context(|| format!("Failed to open for reading: {path:?}"))?;
     let fec = FecImage::from_reader(reader)
@@ -38,7 +38,7 @@ fn read_fec(path: &Path) -> Result<FecImage> {
 }
 
 fn write_fec(path: &Path, fec: &FecImage) -> Result<()> {
-    let mut writer = File::create(path)
+    let mut writer = sandbox::create(path)
         .map(BufWriter::new)
         .with_context(|| format!("Failed to open for writing: {path:?}"))?;
     fec.to_writer(&mut writer)
@@ -0,0 +1,359 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeSet,
+    io::{BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, ValueEnum};
+
+use crate::{
+    cli::{args, ota, status, warning},
+    fastboot::{self, Fastboot, TcpTransport, Transport},
+    format::{payload, sparse},
+    stream::{self, PSeekFile, Reopen},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum SlotArg {
+    /// The currently active slot.
+    Current,
+    /// The inactive slot. The slot is marked as active after flashing.
+    Other,
+    A,
+    B,
+}
+
+/// A partition to flash and where it should be flashed.
+struct FlashTarget {
+    name: String,
+    device_name: String,
+    size: u64,
+    logical: bool,
+}
+
+fn is_yes(value: Option<String>) -> bool {
+    value.is_some_and(|v| v == "yes")
+}
+
+/// Determine which slot to flash. Returns [`None`] for devices without A/B
+/// slots.
+fn select_slot<T: Transport>(
+    fastboot: &mut Fastboot<T>,
+    slot: SlotArg,
+) -> Result<(Option<String>, Option<String>)> {
+    let slot_count = fastboot
+        .getvar_opt("slot-count")?
+        .and_then(|v| fastboot::parse_number(&v))
+        .unwrap_or(0);
+    if slot_count < 2 {
+        if slot != SlotArg::Current {
+            bail!("Device does not have A/B slots");
+        }
+
+        return Ok((None, None));
+    }
+
+    let current = fastboot
+        .getvar("current-slot")
+        .context("Failed to get current slot")?;
+    let current = current.strip_prefix('_').unwrap_or(&current).to_owned();
+
+    let target = match slot {
+        SlotArg::Current => current.clone(),
+        SlotArg::Other => match current.as_str() {
+            "a" => "b".to_owned(),
+            "b" => "a".to_owned(),
+            s => bail!("Cannot determine other slot for current slot: {s}"),
+        },
+        SlotArg::A => "a".to_owned(),
+        SlotArg::B => "b".to_owned(),
+    };
+
+    Ok((Some(current), Some(target)))
+}
+
+fn get_targets<T: Transport>(
+    fastboot: &mut Fastboot<T>,
+    header: &payload::PayloadHeader,
+    partitions: &BTreeSet<String>,
+    slot: Option<&str>,
+) -> Result<Vec<FlashTarget>> {
+    let dynamic = header
+        .manifest
+        .dynamic_partition_metadata
+        .iter()
+        .flat_map(|dpm| &dpm.groups)
+        .flat_map(|g| &g.partition_names)
+        .collect::<BTreeSet<_>>();
+    let mut targets = vec![];
+
+    for p in &header.manifest.partitions {
+        let name = &p.partition_name;
+        if !partitions.is_empty() && !partitions.contains(name) {
+            continue;
+        }
+
+        let size = p
+            .new_partition_info
+            .as_ref()
+            .and_then(|info| info.size)
+            .ok_or_else(|| anyhow!("Partition size not found: {name}"))?;
+
+        let device_name = match slot {
+            Some(s) if is_yes(fastboot.getvar_opt(&format!("has-slot:{name}"))?) => {
+                format!("{name}_{s}")
+            }
+            _ => name.clone(),
+        };
+
+        let logical = dynamic.contains(name)
+            || is_yes(fastboot.getvar_opt(&format!("is-logical:{device_name}"))?);
+
+        targets.push(FlashTarget {
+            name: name.clone(),
+            device_name,
+            size,
+            logical,
+        });
+    }
+
+    for name in partitions {
+        if !targets.iter().any(|t| &t.name == name) {
+            bail!("Partition not found in payload: {name}");
+        }
+    }
+
+    Ok(targets)
+}
+
+fn flash_image<T: Transport>(
+    fastboot: &mut Fastboot<T>,
+    target: &FlashTarget,
+    image: &PSeekFile,
+    max_download_size: u64,
+    block_size: u32,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    if target.logical {
+        fastboot
+            .resize_logical_partition(&target.device_name, target.size)
+            .with_context(|| format!("Failed to resize partition: {}", target.device_name))?;
+    }
+
+    if target.size <= max_download_size {
+        let reader = BufReader::new(image.reopen()?);
+
+        fastboot
+            .download(reader, target.size, cancel_signal)
+            .with_context(|| format!("Failed to send image: {}", target.name))?;
+        fastboot
+            .flash(&target.device_name)
+            .with_context(|| format!("Failed to flash partition: {}", target.device_name))?;
+
+        return Ok(());
+    }
+
+    // The image is too large for the device's download buffer, so it is sent
+    // as multiple sparse images that each contain a portion of the data.
+    let ranges = sparse::split(target.size, max_download_size, block_size)?;
+
+    for (i, range) in ranges.iter().enumerate() {
+        status!(
+            "Sending {} as sparse image {}/{}",
+            target.name,
+            i + 1,
+            ranges.len(),
+        );
+
+        let mut reader = BufReader::new(image.reopen()?);
+        reader.seek(SeekFrom::Start(range.start))?;
+
+        let mut sparse_file = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .context("Failed to create temp file for sparse image")?;
+        let mut writer = BufWriter::new(sparse_file.reopen()?);
+        sparse::write_range(
+            reader,
+            &mut writer,
+            target.size,
+            range.clone(),
+            block_size,
+            cancel_signal,
+        )
+        .with_context(|| format!("Failed to create sparse image: {}", target.name))?;
+        writer.flush()?;
+        drop(writer);
+
+        let sparse_size = sparse_file.seek(SeekFrom::End(0))?;
+        sparse_file.rewind()?;
+
+        fastboot
+            .download(BufReader::new(sparse_file), sparse_size, cancel_signal)
+            .with_context(|| format!("Failed to send image: {}", target.name))?;
+        fastboot
+            .flash(&target.device_name)
+            .with_context(|| format!("Failed to flash partition: {}", target.device_name))?;
+    }
+
+    Ok(())
+}
+
+pub fn flash_fastboot_subcommand(cli: &FlashFastbootCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let (header, payload_reader) = ota::open_full_ota_payload(&cli.input)?;
+    let partitions = cli.partition.iter().cloned().collect::<BTreeSet<_>>();
+
+    status!("Connecting to fastboot device: {}", cli.tcp);
+
+    let transport = TcpTransport::connect(&cli.tcp)
+        .with_context(|| format!("Failed to connect to fastboot device: {}", cli.tcp))?;
+    let mut fastboot = Fastboot::new(transport);
+    fastboot.set_info_callback(|m| status!("(device) {m}"));
+
+    let is_userspace = is_yes(fastboot.getvar_opt("is-userspace")?);
+    // Downloads are limited to 32-bit sizes by the protocol, regardless of
+    // the size of the device's buffer.
+    let max_download_size = fastboot
+        .getvar("max-download-size")
+        .ok()
+        .and_then(|v| fastboot::parse_number(&v))
+        .ok_or_else(|| anyhow!("Failed to get max download size"))?
+        .min(u32::MAX.into());
+
+    let (current_slot, target_slot) = select_slot(&mut fastboot, cli.slot)?;
+    let targets = get_targets(&mut fastboot, &header, &partitions, target_slot.as_deref())?;
+
+    let logical = targets
+        .iter()
+        .filter(|t| t.logical)
+        .map(|t| t.name.as_str())
+        .collect::<Vec<_>>();
+    if !logical.is_empty() && !is_userspace {
+        bail!(
+            "Logical partitions can only be flashed from fastbootd: {}. \
+            Reboot into fastbootd with `fastboot reboot fastboot` and run this \
+            command again",
+            logical.join(", "),
+        );
+    }
+
+    status!(
+        "Flashing {} partitions in {}{}",
+        targets.len(),
+        if is_userspace {
+            "fastbootd"
+        } else {
+            "bootloader"
+        },
+        match &target_slot {
+            Some(s) => format!(" to slot {s}"),
+            None => String::new(),
+        },
+    );
+
+    for target in &targets {
+        stream::check_cancel(cancel_signal)?;
+
+        status!("Extracting {}", target.name);
+
+        let image = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {}", target.name))?;
+        payload::extract_image(
+            &payload_reader,
+            &image,
+            &header,
+            &target.name,
+            &args::payload_apply_options(),
+            cancel_signal,
+        )
+        .with_context(|| format!("Failed to extract from payload: {}", target.name))?;
+
+        status!("Flashing {} to {}", target.name, target.device_name);
+
+        flash_image(
+            &mut fastboot,
+            target,
+            &image,
+            max_download_size,
+            header.manifest.block_size(),
+            cancel_signal,
+        )?;
+    }
+
+    if let Some(slot) = &target_slot {
+        if current_slot.as_ref() != Some(slot) {
+            status!("Setting active slot: {slot}");
+
+            fastboot
+                .set_active(slot)
+                .with_context(|| format!("Failed to set active slot: {slot}"))?;
+        }
+    }
+
+    if cli.reboot {
+        status!("Rebooting device");
+
+        fastboot.reboot().context("Failed to reboot device")?;
+    } else {
+        warning!("The device was not rebooted. Run `fastboot reboot` when ready.");
+    }
+
+    status!("Flashed all partitions successfully");
+
+    Ok(())
+}
+
+/// Flash the partitions in an OTA directly to a device in fastboot mode.
+///
+/// Each partition image is extracted from the OTA's payload and then sent to
+/// the device, without the OTA needing to be sideloaded. Images that are
+/// larger than the device's download buffer are sent as multiple sparse
+/// images.
+///
+/// Partitions in the super partition (logical partitions) can only be flashed
+/// from fastbootd, while the other partitions can be flashed from either the
+/// bootloader or fastbootd. Logical partitions are resized to match the new
+/// images before they are flashed.
+///
+/// Currently, only fastboot over TCP is supported. This works with fastbootd
+/// and emulators that expose fastboot over the network, but not with
+/// bootloaders that only support USB, like the Pixel bootloader. For those,
+/// flash the partitions with the `fastboot` tool from the Android SDK
+/// platform tools instead.
+#[derive(Debug, Parser)]
+pub struct FlashFastbootCli {
+    /// Path to OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Address of device running fastboot over TCP.
+    ///
+    /// If the port is omitted, the default fastboot port (5554) is used.
+    #[arg(long, value_name = "HOST[:PORT]")]
+    pub tcp: String,
+
+    /// Slot to flash.
+    ///
+    /// If a slot other than the current slot is flashed, it is marked as
+    /// active afterwards. Devices without A/B slots only support `current`.
+    #[arg(long, value_name = "SLOT", default_value = "current")]
+    pub slot: SlotArg,
+
+    /// Flash only the specified partition.
+    ///
+    /// This option can be specified multiple times. By default, every
+    /// partition in the OTA is flashed.
+    #[arg(long, value_name = "PARTITION")]
+    pub partition: Vec<String>,
+
+    /// Reboot the device after flashing.
+    #[arg(long)]
+    pub reboot: bool,
+}
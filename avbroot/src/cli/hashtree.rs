@@ -4,7 +4,6 @@
  */
 
 use std::{
-    fs::{File, OpenOptions},
     io::{BufReader, BufWriter, Write},
     path::{Path, PathBuf},
     sync::atomic::AtomicBool,
@@ -14,12 +13,14 @@ use anyhow::{Context, Result};
 use clap::{Parser, Subcommand};
 
 use crate::{
+    cli::args,
     format::hashtree::HashTreeImage,
+    sandbox,
     stream::{FromReader, PSeekFile, ToWriter},
 };
 
 fn open_input(path: &Path, rw: bool) -> Result<PSeekFile> {
-    OpenOptions::new()
+    sandbox::OpenOptions::new()
         .read(true)
         .write(rw)
         .open(path)
@@ -28,7 +29,7 @@ fn open_input(path: &Path, rw: bool) -> Result<PSeekFile> {
 }
 
 fn read_hash_tree(path: &Path) -> Result<HashTreeImage> {
-    let reader = File::open(path)
+    let reader = sandbox::open(path)
         .map(BufReader::new)
         .with_context(|| format!("Failed to open for reading: {path:?}"))?;
     let hash_tree = HashTreeImage::from_reader(reader)
@@ -38,7 +39,7 @@ fn read_hash_tree(path: &Path) -> Result<HashTreeImage> {
 }
 
 fn write_hash_tree(path: &Path, hash_tree: &HashTreeImage) -> Result<()> {
-    let mut writer = File::create(path)
+    let mut writer = sandbox::create(path)
         .map(BufWriter::new)
         .with_context(|| format!("Failed to open for writing: {path:?}"))?;
     hash_tree
@@ -55,6 +56,28 @@ fn generate_subcommand(cli: &GenerateCli, cancel_signal: &AtomicBool) -> Result<
     let salt = hex::decode(&cli.salt).context("Invalid salt")?;
     let input = open_input(&cli.input, false)?;
 
+    if cli.streaming || args::low_memory() {
+        let mut writer = sandbox::create(&cli.hash_tree)
+            .map(BufWriter::new)
+            .with_context(|| format!("Failed to open for writing: {:?}", cli.hash_tree))?;
+
+        HashTreeImage::generate_streaming(
+            &input,
+            cli.block_size,
+            &cli.algorithm,
+            &salt,
+            &mut writer,
+            cancel_signal,
+        )
+        .context("Failed to generate hash tree data")?;
+
+        writer
+            .flush()
+            .with_context(|| format!("Failed to flush hash tree data: {:?}", cli.hash_tree))?;
+
+        return Ok(());
+    }
+
     let hash_tree =
         HashTreeImage::generate(&input, cli.block_size, &cli.algorithm, &salt, cancel_signal)
             .context("Failed to generate hash tree data")?;
@@ -124,6 +147,15 @@ struct GenerateCli {
     /// Salt (in hex).
     #[arg(short, long, value_name = "HEX", default_value = "")]
     salt: String,
+
+    /// Generate the hash tree with bounded memory usage.
+    ///
+    /// Each level of the tree is computed in a separate pass and spilled to a
+    /// temporary file, so the full tree is never held in memory. This allows
+    /// processing images that are larger than the available RAM. This is the
+    /// default with --low-memory.
+    #[arg(long)]
+    streaming: bool,
 }
 
 /// Update hash tree data after a file is modified.
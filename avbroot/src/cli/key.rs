@@ -5,17 +5,21 @@
 
 use std::{
     ffi::OsString,
-    fs,
+    io::BufReader,
     path::{Path, PathBuf},
     time::Duration,
 };
 
 use anyhow::{Context, Result};
-use clap::{Args, Parser, Subcommand};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use clap::{Args, Parser, Subcommand, ValueEnum};
 
 use crate::{
-    crypto::{self, PassphraseSource},
+    cli::{status, warning},
+    crypto::{self, BlobDigest, BlobPadding, PassphraseSource},
+    detached::{self, DetachedSignatures, SigningRequests},
     format::avb,
+    sandbox,
 };
 
 fn get_passphrase_source(group: &PassphraseGroup, key_path: &Path) -> PassphraseSource {
@@ -68,11 +72,11 @@ pub fn key_main(cli: &KeyCli) -> Result<()> {
             let encoded = avb::encode_public_key(&public_key)
                 .context("Failed to encode public key in AVB format")?;
 
-            fs::write(&c.output, encoded)
+            sandbox::write(&c.output, encoded)
                 .with_context(|| format!("Failed to write public key: {:?}", c.output))?;
         }
         KeyCommand::DecodeAvb(c) => {
-            let encoded = fs::read(&c.key)
+            let encoded = sandbox::read(&c.key)
                 .with_context(|| format!("Failed to load AVB public key: {:?}", c.key))?;
 
             let public_key = avb::decode_public_key(&encoded)
@@ -81,6 +85,75 @@ pub fn key_main(cli: &KeyCli) -> Result<()> {
             crypto::write_pem_public_key_file(&c.output, &public_key)
                 .with_context(|| format!("Failed to write public key: {:?}", c.output))?;
         }
+        KeyCommand::SignBlob(c) => {
+            let source = get_passphrase_source(&c.passphrase, &c.key);
+            let private_key = crypto::read_pem_key_file(&c.key, &source)
+                .with_context(|| format!("Failed to load key: {:?}", c.key))?;
+
+            let reader = sandbox::open(&c.input)
+                .map(BufReader::new)
+                .with_context(|| format!("Failed to open for reading: {:?}", c.input))?;
+
+            let digest = match c.digest {
+                DigestArg::Sha1 => BlobDigest::Sha1,
+                DigestArg::Sha256 => BlobDigest::Sha256,
+                DigestArg::Sha512 => BlobDigest::Sha512,
+            };
+            let padding = match c.padding {
+                PaddingArg::Pkcs1v15 => BlobPadding::Pkcs1v15,
+                PaddingArg::Pss => BlobPadding::Pss,
+            };
+
+            let signature = crypto::sign_blob(reader, &private_key, digest, padding)
+                .with_context(|| format!("Failed to sign: {:?}", c.input))?;
+
+            let data = match c.encoding {
+                EncodingArg::Raw => signature,
+                EncodingArg::Base64 => {
+                    let mut encoded = STANDARD.encode(signature);
+                    encoded.push('\n');
+                    encoded.into_bytes()
+                }
+            };
+
+            sandbox::write(&c.output, data)
+                .with_context(|| format!("Failed to write signature: {:?}", c.output))?;
+        }
+        KeyCommand::SignRequests(c) => {
+            let source = get_passphrase_source(&c.passphrase, &c.key);
+            let private_key = crypto::read_pem_key_file(&c.key, &source)
+                .with_context(|| format!("Failed to load key: {:?}", c.key))?;
+
+            let data = sandbox::read(&c.input)
+                .with_context(|| format!("Failed to read file: {:?}", c.input))?;
+            let file: SigningRequests = serde_json::from_slice(&data)
+                .with_context(|| format!("Failed to parse signing requests: {:?}", c.input))?;
+
+            let signatures = detached::sign_requests(&file.requests, &private_key)
+                .context("Failed to sign requests")?;
+
+            for signature in &signatures {
+                let purpose = file
+                    .requests
+                    .iter()
+                    .find(|r| r.digest == signature.digest)
+                    .map_or("unknown", |r| r.purpose.as_str());
+
+                status!(
+                    "Signed {purpose} digest: {}",
+                    hex::encode(&signature.digest)
+                );
+            }
+
+            let skipped = file.requests.len() - signatures.len();
+            if skipped > 0 {
+                warning!("Skipped {skipped} requests for other keys");
+            }
+
+            let data = serde_json::to_vec_pretty(&DetachedSignatures { signatures })?;
+            sandbox::write(&c.output, data)
+                .with_context(|| format!("Failed to write signatures: {:?}", c.output))?;
+        }
     }
 
     Ok(())
@@ -174,12 +247,92 @@ struct DecodeAvbCli {
     key: PathBuf,
 }
 
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum DigestArg {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum PaddingArg {
+    Pkcs1v15,
+    Pss,
+}
+
+#[derive(Clone, Copy, Debug, ValueEnum)]
+enum EncodingArg {
+    Raw,
+    Base64,
+}
+
+/// Sign an arbitrary file with a raw RSA signature.
+///
+/// The signature covers the digest of the entire file and is not wrapped in
+/// any container format. This is useful for companion tools that need to sign
+/// data with the same keys used for patching.
+#[derive(Debug, Parser)]
+struct SignBlobCli {
+    /// Path to input private key.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    key: PathBuf,
+
+    #[command(flatten)]
+    passphrase: PassphraseGroup,
+
+    /// Path to file to sign.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output signature.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Digest algorithm.
+    #[arg(long, value_enum, default_value_t = DigestArg::Sha256)]
+    digest: DigestArg,
+
+    /// Signature padding scheme.
+    #[arg(long, value_enum, default_value_t = PaddingArg::Pkcs1v15)]
+    padding: PaddingArg,
+
+    /// Output encoding of the signature.
+    #[arg(long, value_enum, default_value_t = EncodingArg::Raw)]
+    encoding: EncodingArg,
+}
+
+/// Sign the requests created by `avbroot ota patch --emit-signing-requests`.
+///
+/// This is meant to be run on the machine that holds the private key. Only the
+/// requests for the specified key are signed, so when separate AVB and OTA keys
+/// are used, this needs to be run once for each key. The output can be passed
+/// to `avbroot ota patch --apply-signatures`.
+#[derive(Debug, Parser)]
+struct SignRequestsCli {
+    /// Path to input private key.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    key: PathBuf,
+
+    #[command(flatten)]
+    passphrase: PassphraseGroup,
+
+    /// Path to signing requests.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output signatures.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+}
+
 #[derive(Debug, Subcommand)]
 enum KeyCommand {
     GenerateKey(GenerateKeyCli),
     GenerateCert(GenerateCertCli),
     ExtractAvb(ExtractAvbCli),
     DecodeAvb(DecodeAvbCli),
+    SignBlob(SignBlobCli),
+    SignRequests(SignRequestsCli),
 }
 
 /// Generate and convert keys.
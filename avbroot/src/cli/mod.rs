@@ -8,20 +8,39 @@ pub mod avb;
 pub mod boot;
 pub mod completion;
 pub mod cpio;
+#[cfg(all(target_os = "linux", feature = "fuse"))]
+pub mod cpio_fuse;
+pub mod device;
+pub mod diff;
+pub mod digests;
+pub mod download;
+pub mod fake;
 pub mod fec;
+pub mod flash;
 pub mod hashtree;
 pub mod key;
+pub mod notify;
 pub mod ota;
+pub mod payload;
+pub mod policy;
+pub mod profile;
+pub mod progress;
+pub mod selftest;
+pub mod strip;
 
 macro_rules! status {
     ($($arg:tt)*) => {
-        eprintln!("\x1b[1m[*] {}\x1b[0m", format!($($arg)*))
+        $crate::cli::progress::eprint_line(
+            format_args!("\x1b[1m[*] {}\x1b[0m", format!($($arg)*))
+        )
     }
 }
 
 macro_rules! warning {
     ($($arg:tt)*) => {
-        eprintln!("\x1b[1;31m[WARNING] {}\x1b[0m", format!($($arg)*))
+        $crate::cli::progress::eprint_line(
+            format_args!("\x1b[1;31m[WARNING] {}\x1b[0m", format!($($arg)*))
+        )
     }
 }
 
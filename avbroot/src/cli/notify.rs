@@ -0,0 +1,202 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+    time::Instant,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use serde::Serialize;
+
+use crate::cli::warning;
+
+const HEADING_NOTIFY: &str = "Notification options";
+
+/// Options for reporting the outcome of a long-running command.
+#[derive(Clone, Debug, Default, Args)]
+pub struct NotifyGroup {
+    /// Program to run when the command finishes.
+    ///
+    /// The program is run on both success and failure and receives a JSON
+    /// summary of the outcome on stdin. This is useful for sending
+    /// notifications from unattended runs.
+    #[arg(long, value_name = "PROGRAM", value_parser, help_heading = HEADING_NOTIFY)]
+    pub notify_cmd: Option<PathBuf>,
+
+    /// Webhook URL to send a JSON summary to when the command finishes.
+    ///
+    /// The summary is sent as the body of a POST request, on both success and
+    /// failure.
+    #[arg(long, value_name = "URL", help_heading = HEADING_NOTIFY)]
+    pub notify_url: Option<String>,
+}
+
+/// The JSON summary sent to notification hooks.
+#[derive(Serialize)]
+struct Summary<'a> {
+    command: &'a str,
+    success: bool,
+    input: String,
+    output: Option<String>,
+    duration_secs: f64,
+    error: Option<String>,
+}
+
+fn run_notify_cmd(cmd: &Path, json: &str) -> Result<()> {
+    let mut child = Command::new(cmd)
+        .stdin(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run command: {cmd:?}"))?;
+
+    // The program may exit without reading stdin, so write errors are ignored.
+    let _ = child.stdin.take().unwrap().write_all(json.as_bytes());
+
+    let status = child
+        .wait()
+        .with_context(|| format!("Failed to wait for command: {cmd:?}"))?;
+    if !status.success() {
+        bail!("Command {cmd:?} failed: {status}");
+    }
+
+    Ok(())
+}
+
+fn send_webhook(url: &str, json: &str) -> Result<()> {
+    ureq::post(url)
+        .set("Content-Type", "application/json")
+        .send_string(json)
+        .context("Failed to send webhook")?;
+
+    Ok(())
+}
+
+/// Run `f` and then report its outcome to the notification hooks. Failing to
+/// notify is only a warning and never changes the result of `f`.
+pub fn run(
+    group: &NotifyGroup,
+    command: &str,
+    input: &Path,
+    output: Option<&Path>,
+    f: impl FnOnce() -> Result<()>,
+) -> Result<()> {
+    let start = Instant::now();
+    let result = f();
+
+    if group.notify_cmd.is_none() && group.notify_url.is_none() {
+        return result;
+    }
+
+    let summary = Summary {
+        command,
+        success: result.is_ok(),
+        input: input.to_string_lossy().into_owned(),
+        output: output.map(|p| p.to_string_lossy().into_owned()),
+        duration_secs: start.elapsed().as_secs_f64(),
+        error: result.as_ref().err().map(|e| format!("{e:#}")),
+    };
+    let json = serde_json::to_string(&summary).expect("Failed to serialize summary");
+
+    if let Some(cmd) = &group.notify_cmd {
+        if let Err(e) = run_notify_cmd(cmd, &json) {
+            warning!("Failed to send notification: {e:#}");
+        }
+    }
+
+    if let Some(url) = &group.notify_url {
+        if let Err(e) = send_webhook(url, &json) {
+            warning!("Failed to send notification: {e:#}");
+        }
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use anyhow::anyhow;
+    use serde_json::Value;
+
+    use super::*;
+
+    #[test]
+    fn no_hooks() {
+        let group = NotifyGroup::default();
+
+        run(&group, "test", Path::new("in"), None, || Ok(())).unwrap();
+        let err = run(&group, "test", Path::new("in"), None, || {
+            Err(anyhow!("oops"))
+        })
+        .unwrap_err();
+        assert_eq!(err.to_string(), "oops");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn notify_cmd() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let summary_path = temp_dir.path().join("summary.json");
+        let script = temp_dir.path().join("notify.sh");
+        fs::write(
+            &script,
+            format!("#!/bin/sh\ncat > '{}'\n", summary_path.to_str().unwrap()),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let group = NotifyGroup {
+            notify_cmd: Some(script),
+            notify_url: None,
+        };
+
+        run(
+            &group,
+            "ota patch",
+            Path::new("in.zip"),
+            Some(Path::new("out.zip")),
+            || Ok(()),
+        )
+        .unwrap();
+
+        let summary: Value = serde_json::from_slice(&fs::read(&summary_path).unwrap()).unwrap();
+        assert_eq!(summary["command"], "ota patch");
+        assert_eq!(summary["success"], true);
+        assert_eq!(summary["input"], "in.zip");
+        assert_eq!(summary["output"], "out.zip");
+        assert!(summary["duration_secs"].is_f64());
+        assert!(summary["error"].is_null());
+
+        // The full error chain is reported and the error is passed through.
+        let err = run(&group, "ota verify", Path::new("in.zip"), None, || {
+            Err(anyhow!("inner")).context("outer")
+        })
+        .unwrap_err();
+        assert_eq!(format!("{err:#}"), "outer: inner");
+
+        let summary: Value = serde_json::from_slice(&fs::read(&summary_path).unwrap()).unwrap();
+        assert_eq!(summary["command"], "ota verify");
+        assert_eq!(summary["success"], false);
+        assert!(summary["output"].is_null());
+        assert_eq!(summary["error"], "outer: inner");
+    }
+
+    #[test]
+    fn notify_failure_is_not_fatal() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let group = NotifyGroup {
+            notify_cmd: Some(temp_dir.path().join("nonexistent")),
+            notify_url: None,
+        };
+
+        run(&group, "ota patch", Path::new("in.zip"), None, || Ok(())).unwrap();
+    }
+}
@@ -4,49 +4,79 @@
  */
 
 use std::{
-    borrow::Cow,
-    collections::{BTreeSet, HashMap, HashSet},
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    env,
     ffi::{OsStr, OsString},
-    fmt::Display,
-    fs::{self, File},
+    fmt::{self, Display},
     io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    iter, mem,
     ops::Range,
+    panic,
     path::{Path, PathBuf},
-    sync::{atomic::AtomicBool, Mutex},
-    time::Instant,
+    process::Command,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Mutex,
+    },
+    thread,
+    time::{Instant, SystemTime, UNIX_EPOCH},
 };
 
 use anyhow::{anyhow, bail, Context, Result};
-use cap_std::{ambient_authority, fs::Dir};
-use cap_tempfile::TempDir;
-use clap::{value_parser, ArgAction, Args, Parser, Subcommand};
+use cap_std::fs::Dir;
+use clap::{value_parser, ArgAction, Args, Parser, Subcommand, ValueEnum};
 use rayon::{iter::IntoParallelRefIterator, prelude::ParallelIterator};
-use rsa::RsaPrivateKey;
+use serde::{Deserialize, Serialize};
 use tempfile::NamedTempFile;
 use topological_sort::TopologicalSort;
 use x509_cert::Certificate;
 use zip::{write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 use crate::{
-    cli::{self, status, warning},
-    crypto::{self, PassphraseSource},
+    blobcache::BlobCache,
+    blockdev,
+    cli::{
+        self, args,
+        avb::AvbAlgorithmArg,
+        device::Adb,
+        diff,
+        digests::{DigestsManifest, FileStamp},
+        download, fake, flash,
+        notify::{self, NotifyGroup},
+        policy::{Action, Check, Failure, Policy},
+        profile,
+        progress::{self, Tracker},
+        status, strip, warning,
+    },
+    crypto::{self, PassphraseSource, RsaSigningKey},
+    detached::{self, DetachedSignatures, SigningRequests},
     format::{
         avb::Header,
-        avb::{self, Descriptor},
-        ota::{self, SigningWriter, ZipEntry},
+        avb::{self, AlgorithmType, Descriptor},
+        filesystem::{self, FileSystemReader, FileType},
+        ota::{self, Provenance, SigningWriter, ZipEntry},
         padding,
-        payload::{self, PayloadHeader, PayloadWriter},
+        payload::{self, CompressOptions, PayloadHeader, PayloadWriter, XzCheck},
     },
+    harden,
     patch::{
-        boot::{self, BootImagePatch, MagiskRootPatcher, OtaCertPatcher, PrepatchedImagePatcher},
+        boot::{
+            self, BootImagePatch, CustomBootPatcher, KernelSuRootPatcher, MagiskRootPatcher,
+            OtaCertPatcher, PrepatchedImagePatcher, RootRemovalPatcher,
+        },
+        device_db::{DeviceDb, DevicePreset, Filesystem},
+        magisk_compat::MagiskCompatDb,
         system,
     },
     protobuf::{
-        build::tools::releasetools::OtaMetadata, chromeos_update_engine::DeltaArchiveManifest,
+        build::tools::releasetools::{DeviceState, OtaMetadata},
+        chromeos_update_engine::{install_operation::Type, DeltaArchiveManifest, PartitionUpdate},
     },
+    sandbox,
     stream::{
-        self, CountingWriter, FromReader, HashingWriter, HolePunchingWriter, PSeekFile,
-        ReadSeekReopen, Reopen, SectionReader, ToWriter, WriteSeekReopen,
+        self, CountingWriter, FromReader, HashingWriter, HolePunchingWriter, HttpFile, PSeekFile,
+        ReadSeekReopen, Reopen, SectionReader, SpliceWriter, SplicedReader, ToWriter,
+        WriteSeekReopen,
     },
     util,
 };
@@ -73,47 +103,221 @@ fn sorted<T: Ord>(iter: impl Iterator<Item = T>) -> Vec<T> {
     items
 }
 
-pub struct RequiredImages(HashSet<String>);
+/// How a partition is treated when patching.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionClass {
+    Boot,
+    System,
+    Vbmeta,
+    Other,
+}
+
+impl PartitionClass {
+    fn parse(value: &str) -> Option<Self> {
+        match value {
+            "boot" => Some(Self::Boot),
+            "system" => Some(Self::System),
+            "vbmeta" => Some(Self::Vbmeta),
+            "other" => Some(Self::Other),
+            _ => None,
+        }
+    }
+}
+
+/// Classifies partitions by name. The built-in rules can be overridden for
+/// devices with nonstandard partition names.
+#[derive(Clone, Debug, Default)]
+pub struct PartitionClassifier(BTreeMap<String, PartitionClass>);
+
+impl PartitionClassifier {
+    /// Parse `--classify <PARTITION>=<CLASS>` values.
+    pub fn from_args(values: &[String]) -> Result<Self> {
+        let mut overrides = BTreeMap::new();
+
+        for item in values {
+            let Some((name, class)) = item.split_once('=') else {
+                bail!("Invalid partition classification (expected PARTITION=CLASS): {item:?}");
+            };
+            if name.is_empty() {
+                bail!("Invalid partition classification (expected PARTITION=CLASS): {item:?}");
+            }
+
+            let Some(class) = PartitionClass::parse(class) else {
+                bail!(
+                    "Invalid partition class (expected boot, system, vbmeta, or other): {class:?}"
+                );
+            };
+
+            if overrides.insert(name.to_owned(), class).is_some() {
+                bail!("Partition classified more than once: {name}");
+            }
+        }
+
+        Ok(Self(overrides))
+    }
+
+    pub fn classify(&self, name: &str) -> PartitionClass {
+        if let Some(class) = self.0.get(name) {
+            *class
+        } else if name == "boot"
+            || name == "init_boot"
+            || name == "recovery"
+            || name == "vendor_boot"
+        {
+            PartitionClass::Boot
+        } else if name == "system" {
+            PartitionClass::System
+        } else if name.starts_with("vbmeta") {
+            PartitionClass::Vbmeta
+        } else {
+            PartitionClass::Other
+        }
+    }
+
+    pub fn is_boot(&self, name: &str) -> bool {
+        self.classify(name) == PartitionClass::Boot
+    }
+
+    pub fn is_system(&self, name: &str) -> bool {
+        self.classify(name) == PartitionClass::System
+    }
+
+    pub fn is_vbmeta(&self, name: &str) -> bool {
+        self.classify(name) == PartitionClass::Vbmeta
+    }
+}
+
+pub struct RequiredImages {
+    images: HashSet<String>,
+    classifier: PartitionClassifier,
+}
 
 impl RequiredImages {
-    pub fn new(manifest: &DeltaArchiveManifest) -> Self {
-        let partitions = manifest
+    pub fn new(manifest: &DeltaArchiveManifest, classifier: &PartitionClassifier) -> Self {
+        let images = manifest
             .partitions
             .iter()
             .map(|p| p.partition_name.clone())
-            .filter(|n| Self::is_boot(n) || Self::is_system(n) || Self::is_vbmeta(n))
+            .filter(|n| classifier.classify(n) != PartitionClass::Other)
             .collect();
 
-        Self(partitions)
-    }
-
-    pub fn is_boot(name: &str) -> bool {
-        name == "boot" || name == "init_boot" || name == "recovery" || name == "vendor_boot"
-    }
-
-    pub fn is_system(name: &str) -> bool {
-        name == "system"
+        Self {
+            images,
+            classifier: classifier.clone(),
+        }
     }
 
-    pub fn is_vbmeta(name: &str) -> bool {
-        name.starts_with("vbmeta")
+    pub fn is_boot(&self, name: &str) -> bool {
+        self.classifier.is_boot(name)
     }
 
     pub fn iter(&self) -> impl Iterator<Item = &str> {
-        self.0.iter().map(|n| n.as_str())
+        self.images.iter().map(|n| n.as_str())
     }
 
     pub fn iter_boot(&self) -> impl Iterator<Item = &str> {
-        self.iter().filter(|n| Self::is_boot(n))
+        self.iter().filter(|n| self.classifier.is_boot(n))
     }
 
     pub fn iter_system(&self) -> impl Iterator<Item = &str> {
-        self.iter().filter(|n| Self::is_system(n))
+        self.iter().filter(|n| self.classifier.is_system(n))
     }
 
     pub fn iter_vbmeta(&self) -> impl Iterator<Item = &str> {
-        self.iter().filter(|n| Self::is_vbmeta(n))
+        self.iter().filter(|n| self.classifier.is_vbmeta(n))
+    }
+}
+
+/// Input OTA zip for `ota patch` and `ota verify`, which is either a local file
+/// or a file on an HTTP(S) server.
+enum OtaReader {
+    File(PSeekFile),
+    Http(HttpFile),
+    /// Virtual OTA zip wrapped around a payload directory.
+    Dir(SplicedReader<PSeekFile>),
+}
+
+impl OtaReader {
+    fn open(path: &Path) -> io::Result<Self> {
+        match path.to_str().filter(|p| is_url(p)) {
+            Some(url) => HttpFile::open(url).map(Self::Http),
+            None => sandbox::open(path).map(PSeekFile::new).map(Self::File),
+        }
+    }
+}
+
+impl Read for OtaReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::File(f) => f.read(buf),
+            Self::Http(f) => f.read(buf),
+            Self::Dir(f) => f.read(buf),
+        }
+    }
+}
+
+impl Seek for OtaReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            Self::File(f) => f.seek(pos),
+            Self::Http(f) => f.seek(pos),
+            Self::Dir(f) => f.seek(pos),
+        }
+    }
+}
+
+impl Reopen for OtaReader {
+    fn reopen(&self) -> io::Result<Self> {
+        match self {
+            Self::File(f) => f.reopen().map(Self::File),
+            Self::Http(f) => f.reopen().map(Self::Http),
+            Self::Dir(f) => f.reopen().map(Self::Dir),
+        }
+    }
+}
+
+fn is_url(path: &str) -> bool {
+    path.starts_with("http://") || path.starts_with("https://")
+}
+
+/// Decode `%XX` escapes in a URL component. Invalid escapes are kept as is.
+fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut result = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let escape = bytes
+            .get(i + 1..i + 3)
+            .filter(|h| bytes[i] == b'%' && h.iter().all(u8::is_ascii_hexdigit));
+
+        if let Some(hex) = escape {
+            // Cannot fail since both digits were checked.
+            let hex = std::str::from_utf8(hex).unwrap();
+            result.push(u8::from_str_radix(hex, 16).unwrap());
+            i += 3;
+        } else {
+            result.push(bytes[i]);
+            i += 1;
+        }
     }
+
+    String::from_utf8_lossy(&result).into_owned()
+}
+
+/// Get the file name from a URL. This is the last non-empty, percent-decoded
+/// segment of the path after the authority. The query and fragment are ignored.
+fn url_file_name(url: &str) -> Option<String> {
+    let rest = url.split_once("://").map_or(url, |(_, r)| r);
+    let rest = rest.split(['?', '#']).next().unwrap_or_default();
+    let (_, path) = rest.split_once('/')?;
+    let segment = path.rsplit('/').find(|s| !s.is_empty())?;
+    let name = percent_decode(segment);
+
+    // An encoded slash or a dot segment must not escape the current directory.
+    Path::new(&name)
+        .file_name()
+        .map(|n| n.to_string_lossy().into_owned())
 }
 
 #[derive(Clone, Copy, PartialEq, Eq)]
@@ -128,14 +332,118 @@ struct InputFile {
     state: InputFileState,
 }
 
-/// Open all input files listed in `required_images`. If an image has a path
-/// in `external_images`, that file is opened. Otherwise, the image is extracted
-/// from the payload into a temporary file (that is unnamed if supported by the
-/// operating system).
+/// Source of a replacement partition image specified via `--replace`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum ExternalImage {
+    /// A raw partition image file.
+    File(PathBuf),
+    /// A partition in the payload of another OTA zip.
+    Ota { path: PathBuf, partition: String },
+    /// A generic system image whose hash tree footer is regenerated to match
+    /// the stock image's parameters.
+    Gsi(PathBuf),
+}
+
+impl ExternalImage {
+    /// Parse a `--replace` value. Values of the form `<zip>:<partition>`
+    /// refer to a partition in another OTA. Paths to existing files take
+    /// precedence so that raw images with colons in their names still work.
+    fn parse(value: &OsStr) -> Self {
+        let path = Path::new(value);
+
+        if !path.exists() {
+            if let Some((zip, partition)) = value.to_str().and_then(|v| v.rsplit_once(':')) {
+                let is_zip = Path::new(zip)
+                    .extension()
+                    .is_some_and(|e| e.eq_ignore_ascii_case("zip"));
+
+                if is_zip && !partition.is_empty() {
+                    return Self::Ota {
+                        path: PathBuf::from(zip),
+                        partition: partition.to_owned(),
+                    };
+                }
+            }
+        }
+
+        Self::File(path.to_owned())
+    }
+}
+
+impl Display for ExternalImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::File(path) => write!(f, "{path:?}"),
+            Self::Ota { path, partition } => write!(f, "{partition} from {path:?}"),
+            Self::Gsi(path) => write!(f, "GSI {path:?}"),
+        }
+    }
+}
+
+/// Open the payload of a full OTA zip for reading.
+pub fn open_full_ota_payload(
+    path: &Path,
+) -> Result<(PayloadHeader, SectionReader<BufReader<PSeekFile>>)> {
+    let raw_reader = sandbox::open(path)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    let payload_entry = {
+        let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader.reopen()?))
+            .with_context(|| format!("Failed to read zip: {path:?}"))?;
+        find_payload_entry(&raw_reader, &mut zip_reader)?
+    };
+
+    let mut payload_reader = SectionReader::new(
+        BufReader::new(raw_reader),
+        payload_entry.offset,
+        payload_entry.size,
+    )?;
+
+    check_payload_magic(&mut payload_reader)?;
+    payload_reader.rewind()?;
+
+    let header = PayloadHeader::from_reader(&mut payload_reader)
+        .with_context(|| format!("Failed to load OTA payload header: {path:?}"))?;
+    check_full_ota(&header).with_context(|| format!("Unsupported payload: {path:?}"))?;
+
+    Ok((header, payload_reader))
+}
+
+/// Extract a partition image from the payload of another OTA zip into a
+/// temporary file.
+fn extract_from_other_ota(
+    path: &Path,
+    partition: &str,
+    cancel_signal: &AtomicBool,
+) -> Result<PSeekFile> {
+    let (header, payload_reader) = open_full_ota_payload(path)?;
+
+    let file = tempfile::tempfile()
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to create temp file for: {partition}"))?;
+
+    payload::extract_image(
+        &payload_reader,
+        &file,
+        &header,
+        partition,
+        &args::payload_apply_options(),
+        cancel_signal,
+    )
+    .with_context(|| format!("Failed to extract {partition} from: {path:?}"))?;
+
+    Ok(file)
+}
+
+/// Open all input files listed in `required_images`. If an image has a source
+/// in `external_images`, that file is opened or the image is extracted from the
+/// other OTA. Otherwise, the image is extracted from the payload into a
+/// temporary file (that is unnamed if supported by the operating system).
 fn open_input_files(
     payload: &(dyn ReadSeekReopen + Sync),
     required_images: &RequiredImages,
-    external_images: &HashMap<String, PathBuf>,
+    external_images: &HashMap<String, ExternalImage>,
+    source_images: &HashMap<String, PathBuf>,
     header: &PayloadHeader,
     cancel_signal: &AtomicBool,
 ) -> Result<HashMap<String, InputFile>> {
@@ -147,14 +455,31 @@ fn open_input_files(
         .iter()
         .chain(external_images.keys().map(|k| k.as_str()))
         .collect::<HashSet<_>>();
+    let tracker = partition_tracker(
+        "extract_images",
+        header,
+        all_images
+            .iter()
+            .copied()
+            .filter(|n| !external_images.contains_key(*n)),
+    );
 
     for name in all_images {
-        if let Some(path) = external_images.get(name) {
-            status!("Opening external image: {name}: {path:?}");
+        if let Some(image) = external_images.get(name) {
+            let file = match image {
+                ExternalImage::File(path) | ExternalImage::Gsi(path) => {
+                    status!("Opening external image: {name}: {path:?}");
+
+                    sandbox::open(path)
+                        .map(PSeekFile::new)
+                        .with_context(|| format!("Failed to open external image: {path:?}"))?
+                }
+                ExternalImage::Ota { path, partition } => {
+                    status!("Extracting external image: {name}: {image}");
 
-            let file = File::open(path)
-                .map(PSeekFile::new)
-                .with_context(|| format!("Failed to open external image: {path:?}"))?;
+                    extract_from_other_ota(path, partition, cancel_signal)?
+                }
+            };
             input_files.insert(
                 name.to_owned(),
                 InputFile {
@@ -169,8 +494,29 @@ fn open_input_files(
                 .map(PSeekFile::new)
                 .with_context(|| format!("Failed to create temp file for: {name}"))?;
 
-            payload::extract_image(payload, &file, header, name, cancel_signal)
-                .with_context(|| format!("Failed to extract from original payload: {name}"))?;
+            let source = match source_images.get(name) {
+                Some(path) => {
+                    status!("Using source image: {name}: {path:?}");
+
+                    Some(
+                        sandbox::open(path)
+                            .map(PSeekFile::new)
+                            .with_context(|| format!("Failed to open source image: {path:?}"))?,
+                    )
+                }
+                None => None,
+            };
+
+            payload::extract_image_with_source(
+                payload,
+                source.as_ref().map(|f| f as &(dyn ReadSeekReopen + Sync)),
+                &tracker.writer(name, file.reopen()?),
+                header,
+                name,
+                &args::payload_apply_options(),
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to extract from original payload: {name}"))?;
             input_files.insert(
                 name.to_owned(),
                 InputFile {
@@ -181,1597 +527,8503 @@ fn open_input_files(
         }
     }
 
+    tracker.finish();
+
     Ok(input_files)
 }
 
-/// Patch the boot images listed in `required_images`. Not every image is
-/// necessarily patched. An [`OtaCertPatcher`] is always applied to the boot
-/// image that contains the trusted OTA certificate list. If `root_patcher` is
-/// specified, then it is used to patch the boot image for root access. If the
-/// original image is signed, then it will be re-signed with `key_avb`.
-fn patch_boot_images<'a, 'b: 'a>(
-    required_images: &'b RequiredImages,
-    input_files: &mut HashMap<String, InputFile>,
-    root_patcher: Option<Box<dyn BootImagePatch + Sync>>,
-    key_avb: &RsaPrivateKey,
-    cert_ota: &Certificate,
-    cancel_signal: &AtomicBool,
-) -> Result<()> {
-    let input_files = Mutex::new(input_files);
-    let mut boot_patchers = Vec::<Box<dyn BootImagePatch + Sync>>::new();
-    boot_patchers.push(Box::new(OtaCertPatcher::new(cert_ota.clone())));
+/// Create a progress tracker for a stage that processes whole partitions from
+/// the payload.
+fn partition_tracker<'a>(
+    stage: &'static str,
+    header: &PayloadHeader,
+    names: impl IntoIterator<Item = &'a str>,
+) -> Tracker {
+    let sizes = names
+        .into_iter()
+        .map(|name| {
+            let size = header
+                .manifest
+                .partitions
+                .iter()
+                .find(|p| p.partition_name == name)
+                .and_then(|p| p.new_partition_info.as_ref()?.size)
+                .unwrap_or(0);
 
-    if let Some(p) = root_patcher {
-        boot_patchers.push(p);
-    }
+            (name, size)
+        })
+        .collect::<Vec<_>>();
 
-    let boot_partitions = required_images.iter_boot().collect::<Vec<_>>();
+    Tracker::with_partitions(stage, sizes)
+}
 
-    status!(
-        "Patching boot images: {}",
-        joined(sorted(boot_partitions.iter())),
-    );
+/// Find the stock descriptor for `name` in the vbmeta images. The name of the
+/// vbmeta image containing the descriptor is returned too.
+fn find_stock_descriptor<'a>(
+    input_files: &mut HashMap<String, InputFile>,
+    vbmeta_images: &HashSet<&'a str>,
+    name: &str,
+) -> Result<Option<(&'a str, Descriptor)>> {
+    for &vbmeta_name in vbmeta_images {
+        let input_file = input_files.get_mut(vbmeta_name).unwrap();
+        let (header, _, _) = avb::load_image(&mut input_file.file)
+            .with_context(|| format!("Failed to load vbmeta image: {vbmeta_name}"))?;
 
-    boot::patch_boot_images(
-        &boot_partitions,
-        |name| {
-            let locked = input_files.lock().unwrap();
-            ReadSeekReopen::reopen_boxed(&locked[name].file)
-        },
-        |name| {
-            let mut locked = input_files.lock().unwrap();
-            let input_file = locked.get_mut(name).unwrap();
-            input_file.file = tempfile::tempfile().map(PSeekFile::new)?;
-            input_file.state = InputFileState::Modified;
-            WriteSeekReopen::reopen_boxed(&input_file.file)
-        },
-        key_avb,
-        &boot_patchers,
-        cancel_signal,
-    )
-    .with_context(|| {
-        format!(
-            "Failed to patch boot images: {}",
-            joined(sorted(boot_partitions.iter())),
-        )
-    })?;
+        let descriptor = header
+            .descriptors
+            .into_iter()
+            .find(|d| d.partition_name() == Some(name));
 
-    Ok(())
+        if let Some(d) = descriptor {
+            return Ok(Some((vbmeta_name, d)));
+        }
+    }
+
+    Ok(None)
 }
 
-/// Patch the single system image listed in `required_images` to replace the
-/// `otacerts.zip` contents.
-fn patch_system_image<'a, 'b: 'a>(
-    required_images: &'b RequiredImages,
+/// Find the stock hash tree descriptor for `name` in the vbmeta images.
+fn find_stock_hash_tree(
     input_files: &mut HashMap<String, InputFile>,
-    cert_ota: &Certificate,
-    key_avb: &RsaPrivateKey,
-    cancel_signal: &AtomicBool,
-) -> Result<(&'b str, Vec<Range<u64>>)> {
-    let Some(target) = required_images.iter_system().next() else {
-        bail!("No system partition found");
-    };
+    vbmeta_images: &HashSet<&str>,
+    name: &str,
+) -> Result<avb::HashTreeDescriptor> {
+    match find_stock_descriptor(input_files, vbmeta_images, name)? {
+        Some((_, Descriptor::HashTree(d))) => Ok(d),
+        Some((vbmeta_name, Descriptor::ChainPartition(_))) => {
+            bail!("{name} is chain loaded by {vbmeta_name}, which is not supported for GSIs");
+        }
+        _ => bail!("No hash tree descriptor for {name} found in vbmeta images"),
+    }
+}
 
-    status!("Patching system image: {target}");
+/// Replace the AVB footer of each GSI in `external_images` with an unsigned
+/// one containing a freshly generated hash tree. Any existing footer is
+/// discarded because it is signed by the GSI's own key. Other replacement
+/// images for partitions protected by a hash tree descriptor get the same
+/// treatment if they are raw filesystem images with no footer.
+///
+/// The hash tree parameters, like the salt and FEC roots, are copied from the
+/// stock image's descriptor so that the descriptor can be inserted into the
+/// parent vbmeta image as-is.
+fn prepare_hash_tree_images(
+    external_images: &HashMap<String, ExternalImage>,
+    input_files: &mut HashMap<String, InputFile>,
+    vbmeta_images: &HashSet<&str>,
+    block_size: u64,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    for (name, image) in external_images {
+        let (stock_descriptor, data_size) = if let ExternalImage::Gsi(_) = image {
+            let stock_descriptor = find_stock_hash_tree(input_files, vbmeta_images, name)?;
+            let input_file = input_files.get_mut(name).unwrap();
+
+            let data_size = match avb::load_image(&mut input_file.file) {
+                Ok((_, Some(footer), _)) => footer.original_image_size,
+                _ => input_file.file.seek(SeekFrom::End(0))?,
+            };
 
-    let input_file = input_files.get_mut(target).unwrap();
+            (stock_descriptor, data_size)
+        } else {
+            let Some((_, Descriptor::HashTree(stock_descriptor))) =
+                find_stock_descriptor(input_files, vbmeta_images, name)?
+            else {
+                continue;
+            };
+            let input_file = input_files.get_mut(name).unwrap();
 
-    // We can't modify external files in place.
-    if input_file.state == InputFileState::External {
-        let mut reader = input_file.file.reopen()?;
-        let mut writer = tempfile::tempfile()
-            .map(PSeekFile::new)
-            .with_context(|| format!("Failed to create temp file for: {target}"))?;
+            if let Ok((_, Some(_), _)) = avb::load_image(&mut input_file.file) {
+                continue;
+            }
 
-        stream::copy(&mut reader, &mut writer, cancel_signal)?;
+            (stock_descriptor, input_file.file.seek(SeekFrom::End(0))?)
+        };
 
-        input_file.file = writer;
-        input_file.state = InputFileState::Extracted;
-    }
+        let input_file = input_files.get_mut(name).unwrap();
 
-    let (mut ranges, other_ranges) = system::patch_system_image(
-        &input_file.file,
-        &input_file.file,
-        cert_ota,
-        key_avb,
-        cancel_signal,
-    )
-    .with_context(|| format!("Failed to patch system image: {target}"))?;
+        status!("Generating hash tree for replacement image: {name}: {image}");
 
-    input_file.state = InputFileState::Modified;
+        let mut file = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+        input_file.file.rewind()?;
+        stream::copy_n(&mut input_file.file, &mut file, data_size, cancel_signal)
+            .with_context(|| format!("Failed to copy image data: {image}"))?;
+        padding::write_zeros(&mut file, stock_descriptor.data_block_size.into())?;
+
+        let mut descriptor = stock_descriptor;
+        descriptor.image_size = file.stream_position()?;
+        descriptor
+            .update(&file, &file, None, cancel_signal)
+            .with_context(|| format!("Failed to generate hash tree for: {image}"))?;
+
+        let eof_size = descriptor.image_size + descriptor.tree_size + descriptor.fec_size;
+        // The image must be a multiple of the payload's block size, which is
+        // never smaller than the 4096-byte block size of the AVB footer.
+        let full_image_size = eof_size
+            .checked_add(8192)
+            .and_then(|s| padding::round(s, block_size.max(4096)))
+            .ok_or_else(|| anyhow!("Image size {eof_size} is too large: {image}"))?;
+
+        let header = Header {
+            required_libavb_version_major: avb::VERSION_MAJOR,
+            required_libavb_version_minor: avb::VERSION_MINOR,
+            algorithm_type: avb::AlgorithmType::None,
+            hash: vec![],
+            signature: vec![],
+            public_key: vec![],
+            public_key_metadata: vec![],
+            descriptors: vec![Descriptor::HashTree(descriptor)],
+            rollback_index: 0,
+            flags: 0,
+            rollback_index_location: 0,
+            release_string: "avbroot".to_owned(),
+            reserved: [0u8; 80],
+        };
+        let mut footer = avb::Footer {
+            version_major: avb::FOOTER_VERSION_MAJOR,
+            version_minor: avb::FOOTER_VERSION_MINOR,
+            original_image_size: 0,
+            vbmeta_offset: 0,
+            vbmeta_size: 0,
+            reserved: Default::default(),
+        };
 
-    status!("Patched otacerts.zip offsets in {target}: {ranges:?}");
+        avb::write_appended_image(&mut file, &header, &mut footer, full_image_size)
+            .with_context(|| format!("Failed to write AVB footer for: {image}"))?;
 
-    ranges.extend(other_ranges);
+        input_file.file = file;
+        input_file.state = InputFileState::Modified;
+    }
 
-    Ok((target, ranges))
+    Ok(())
 }
 
-/// Load the specified vbmeta image headers. If an image has a vbmeta footer,
-/// then an error is returned because the vbmeta patching logic only ever writes
-/// root vbmeta images.
-fn load_vbmeta_images(
-    images: &mut HashMap<String, InputFile>,
+/// Find the stock hash descriptor for `name` in the vbmeta images. Returns
+/// [`None`] if the partition is protected some other way, like via a chain
+/// descriptor.
+fn find_stock_hash(
+    input_files: &mut HashMap<String, InputFile>,
     vbmeta_images: &HashSet<&str>,
-) -> Result<HashMap<String, Header>> {
-    let mut result = HashMap::new();
-
-    for &name in vbmeta_images {
-        let input_file = images.get_mut(name).unwrap();
-        let (header, footer, _) = avb::load_image(&mut input_file.file)
-            .with_context(|| format!("Failed to load vbmeta image: {name}"))?;
-
-        if let Some(f) = footer {
-            bail!("{name} is a vbmeta partition, but has a footer: {f:?}");
-        }
-
-        result.insert(name.to_owned(), header);
+    name: &str,
+) -> Result<Option<avb::HashDescriptor>> {
+    match find_stock_descriptor(input_files, vbmeta_images, name)? {
+        Some((_, Descriptor::Hash(d))) => Ok(Some(d)),
+        _ => Ok(None),
     }
-
-    Ok(result)
 }
 
-/// Check that all critical partitions within the payload are protected by a
-/// vbmeta image in `vbmeta_headers`.
-fn ensure_partitions_protected(
-    required_images: &RequiredImages,
-    vbmeta_headers: &HashMap<String, Header>,
+/// Replace the AVB footer of each replacement image in `external_images` that
+/// is protected by a hash descriptor with an unsigned one containing a freshly
+/// computed digest. This is only done if the image has no footer or if the
+/// footer is signed, since a signed image can't be inserted into the parent
+/// vbmeta image's hash descriptor. The salt and hash algorithm are reused from
+/// the stock image's descriptor and the footer is placed at the end of the
+/// stock partition size.
+fn prepare_hash_images(
+    external_images: &HashMap<String, ExternalImage>,
+    input_files: &mut HashMap<String, InputFile>,
+    vbmeta_images: &HashSet<&str>,
+    manifest: &DeltaArchiveManifest,
+    cancel_signal: &AtomicBool,
 ) -> Result<()> {
-    let critical_partitions = required_images
-        .iter_boot()
-        .chain(required_images.iter_vbmeta())
-        .collect::<BTreeSet<_>>();
+    for (name, image) in external_images {
+        if matches!(image, ExternalImage::Gsi(_)) {
+            continue;
+        }
 
-    // vbmeta partitions first.
-    let mut avb_partitions = vbmeta_headers
-        .keys()
-        .map(|n| n.as_str())
-        .collect::<BTreeSet<_>>();
+        let Some(stock_descriptor) = find_stock_hash(input_files, vbmeta_images, name)? else {
+            continue;
+        };
+        let input_file = input_files.get_mut(name).unwrap();
+
+        let data_size = match avb::load_image(&mut input_file.file) {
+            Ok((header, Some(_), _)) if header.public_key.is_empty() => continue,
+            Ok((_, Some(footer), _)) => footer.original_image_size,
+            // The image has no footer, so all of it is data.
+            Ok((_, None, _)) | Err(avb::Error::InvalidHeaderMagic(_)) => {
+                input_file.file.seek(SeekFrom::End(0))?
+            }
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to load AVB footer from image: {name}"));
+            }
+        };
 
-    // Then, everything referred to by the descriptors.
-    for header in vbmeta_headers.values() {
-        let partition_names = header.descriptors.iter().filter_map(|d| d.partition_name());
+        let partition_size = manifest
+            .partitions
+            .iter()
+            .find(|p| &p.partition_name == name)
+            .and_then(|p| p.new_partition_info.as_ref()?.size)
+            .ok_or_else(|| anyhow!("Missing partition size for: {name}"))?;
 
-        avb_partitions.extend(partition_names);
-    }
+        status!("Regenerating hash descriptor for replacement image: {name}: {image}");
 
-    let missing = critical_partitions
-        .difference(&avb_partitions)
-        .collect::<Vec<_>>();
+        let mut file = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+        input_file.file.rewind()?;
+        stream::copy_n(&mut input_file.file, &mut file, data_size, cancel_signal)
+            .with_context(|| format!("Failed to copy replacement image data: {name}"))?;
+
+        let mut descriptor = stock_descriptor;
+        descriptor.image_size = data_size;
+
+        file.rewind()?;
+        descriptor
+            .update(&mut file, cancel_signal)
+            .with_context(|| format!("Failed to compute digest for replacement image: {name}"))?;
+
+        let header = Header {
+            required_libavb_version_major: avb::VERSION_MAJOR,
+            required_libavb_version_minor: avb::VERSION_MINOR,
+            algorithm_type: avb::AlgorithmType::None,
+            hash: vec![],
+            signature: vec![],
+            public_key: vec![],
+            public_key_metadata: vec![],
+            descriptors: vec![Descriptor::Hash(descriptor)],
+            rollback_index: 0,
+            flags: 0,
+            rollback_index_location: 0,
+            release_string: "avbroot".to_owned(),
+            reserved: [0u8; 80],
+        };
+        let mut footer = avb::Footer {
+            version_major: avb::FOOTER_VERSION_MAJOR,
+            version_minor: avb::FOOTER_VERSION_MINOR,
+            original_image_size: 0,
+            vbmeta_offset: 0,
+            vbmeta_size: 0,
+            reserved: Default::default(),
+        };
 
-    if !missing.is_empty() {
-        bail!(
-            "Found critical partitions that are not protected by AVB: {}",
-            joined(missing),
-        );
+        avb::write_appended_image(&mut file, &header, &mut footer, partition_size)
+            .with_context(|| format!("Failed to write AVB footer for replacement image: {name}"))?;
+
+        input_file.file = file;
+        input_file.state = InputFileState::Modified;
     }
 
     Ok(())
 }
 
-/// From the set of input images (modified partitions + all vbmeta partitions),
-/// determine the order to patch the vbmeta images so that it can be done in a
-/// single pass.
-fn get_vbmeta_patch_order(
-    images: &mut HashMap<String, InputFile>,
-    vbmeta_headers: &HashMap<String, Header>,
-) -> Result<Vec<(String, HashSet<String>)>> {
-    let mut dep_graph = HashMap::<&str, HashSet<String>>::new();
-    let mut missing = images.keys().cloned().collect::<BTreeSet<_>>();
+/// A size constraint that a replacement image does not satisfy.
+struct SizeViolation {
+    partition: String,
+    constraint: String,
+    size: u64,
+    limit: u64,
+}
+
+/// Check every replacement image in `external_images` against the size
+/// constraints of the partition it is replacing. All violations are reported
+/// at once in a table.
+///
+/// * Images for non-dynamic partitions must not be larger than the stock
+///   image. If they have an AVB footer, they must be exactly the same size so
+///   that the footer is at the end of the partition.
+/// * The partitions in a dynamic partition group must fit within the group.
+///   Groups containing a GSI are exempt because they are grown automatically.
+/// * The data covered by an image's AVB descriptor must not overlap the vbmeta
+///   header.
+fn check_replacement_sizes(
+    external_images: &HashMap<String, ExternalImage>,
+    input_files: &mut HashMap<String, InputFile>,
+    manifest: &DeltaArchiveManifest,
+) -> Result<()> {
+    let stock_size = |name: &str| {
+        manifest
+            .partitions
+            .iter()
+            .find(|p| p.partition_name == name)
+            .and_then(|p| p.new_partition_info.as_ref()?.size)
+            .unwrap_or_default()
+    };
+    let groups = manifest
+        .dynamic_partition_metadata
+        .as_ref()
+        .map(|dpm| dpm.groups.as_slice())
+        .unwrap_or_default();
+    let is_dynamic = |name: &str| {
+        groups
+            .iter()
+            .any(|g| g.partition_names.iter().any(|n| n == name))
+    };
 
-    for (vbmeta_name, header) in vbmeta_headers {
-        dep_graph.insert(vbmeta_name, HashSet::new());
-        missing.remove(vbmeta_name);
+    let mut image_sizes = HashMap::new();
+    let mut violations = vec![];
 
-        for descriptor in &header.descriptors {
-            let Some(partition_name) = descriptor.partition_name() else {
-                continue;
-            };
+    for name in sorted(external_images.keys()) {
+        let input_file = input_files.get_mut(name).unwrap();
+        let size = input_file.file.seek(SeekFrom::End(0))?;
+        let footer = match avb::load_image(&mut input_file.file) {
+            Ok((header, Some(footer), _)) => Some((header, footer)),
+            _ => None,
+        };
 
-            // Only consider (chained) vbmeta partitions and other partitions
-            // that were modified during patching.
-            if images.contains_key(partition_name)
-                && (vbmeta_headers.contains_key(partition_name)
-                    || images[partition_name].state != InputFileState::Extracted)
-            {
-                dep_graph
-                    .get_mut(vbmeta_name.as_str())
-                    .unwrap()
-                    .insert(partition_name.to_owned());
-                missing.remove(partition_name);
+        image_sizes.insert(name.as_str(), size);
+
+        if !is_dynamic(name) {
+            let limit = stock_size(name);
+
+            if size > limit {
+                violations.push(SizeViolation {
+                    partition: name.clone(),
+                    constraint: "partition size".to_owned(),
+                    size,
+                    limit,
+                });
+            } else if footer.is_some() && size != limit {
+                violations.push(SizeViolation {
+                    partition: name.clone(),
+                    constraint: "AVB footer at partition end".to_owned(),
+                    size,
+                    limit,
+                });
             }
         }
-    }
-
-    if !missing.is_empty() {
-        warning!("Partitions aren't protected by AVB: {:?}", joined(missing));
-    }
 
-    // Ensure that there's only a single root of trust. Otherwise, there could
-    // be eg. a `vbmeta_unused` containing all the relevant descriptors, but is
-    // never loaded by the bootloader.
-    let mut roots = BTreeSet::new();
+        if let Some((header, footer)) = footer {
+            let covered = match header.appended_descriptor() {
+                Ok(avb::AppendedDescriptorRef::Hash(d)) => Some(d.image_size),
+                Ok(avb::AppendedDescriptorRef::HashTree(d)) => Some(
+                    d.tree_size
+                        .checked_add(d.fec_size)
+                        .and_then(|s| s.checked_add(d.image_size))
+                        .unwrap_or(u64::MAX),
+                ),
+                Err(_) => None,
+            };
 
-    for name in vbmeta_headers.keys() {
-        if !dep_graph.values().any(|d| d.contains(name)) {
-            roots.insert(name.as_str());
+            if let Some(covered) = covered.filter(|s| *s > footer.vbmeta_offset) {
+                violations.push(SizeViolation {
+                    partition: name.clone(),
+                    constraint: "AVB descriptor image size".to_owned(),
+                    size: covered,
+                    limit: footer.vbmeta_offset,
+                });
+            }
         }
     }
 
-    // For zero roots, let TopologicalSort report the cycle.
-    if roots.len() > 1 {
-        bail!("Found multiple root vbmeta images: {}", joined(roots));
-    }
+    for group in groups {
+        let Some(limit) = group.size else {
+            continue;
+        };
 
-    // Compute the patching order. This only includes vbmeta images. All vbmeta
-    // images are included (even those that have no dependencies) so that
-    // update_vbmeta_headers() can check and update the flags field if needed.
-    let mut topo = TopologicalSort::<String>::new();
-    let mut order = vec![];
+        let names = &group.partition_names;
+        if !names.iter().any(|n| external_images.contains_key(n))
+            || names
+                .iter()
+                .any(|n| matches!(external_images.get(n), Some(ExternalImage::Gsi(_))))
+        {
+            continue;
+        }
 
-    for (name, deps) in &dep_graph {
-        for dep in deps {
-            topo.add_dependency(dep, name.to_owned());
+        let size = names
+            .iter()
+            .map(|n| {
+                image_sizes
+                    .get(n.as_str())
+                    .copied()
+                    .unwrap_or_else(|| stock_size(n))
+            })
+            .sum::<u64>();
+
+        if size > limit {
+            violations.push(SizeViolation {
+                partition: joined(names),
+                constraint: format!("group {} size", group.name),
+                size,
+                limit,
+            });
         }
     }
 
-    while !topo.is_empty() {
-        match topo.pop() {
-            Some(item) => {
-                // Only include vbmeta images.
-                if dep_graph.contains_key(item.as_str()) {
-                    order.push((item.clone(), dep_graph.remove(item.as_str()).unwrap()));
-                }
-            }
-            None => bail!("vbmeta dependency graph has cycle: {topo:?}"),
-        }
+    if violations.is_empty() {
+        return Ok(());
     }
 
-    Ok(order)
+    let mut table = format!(
+        "{:<24} {:<28} {:>14} {:>14}",
+        "PARTITION", "CONSTRAINT", "SIZE", "LIMIT",
+    );
+    for v in &violations {
+        table.push_str(&format!(
+            "\n{:<24} {:<28} {:>14} {:>14}",
+            v.partition, v.constraint, v.size, v.limit,
+        ));
+    }
+
+    bail!("Replacement images do not fit their partitions:\n{table}");
 }
 
-/// Copy the hash or hashtree descriptor from the child image header into the
-/// parent image header if the child is unsigned or update the parent's chain
-/// descriptor if the child is signed. The existing descriptor in the parent
-/// must have the same type as the child.
-fn update_security_descriptors(
-    parent_header: &mut Header,
-    child_header: &Header,
-    parent_name: &str,
-    child_name: &str,
+/// Grow dynamic partition groups containing a GSI that are too small to hold
+/// their partitions' new sizes. The device's super partition must still have
+/// enough room for the larger groups. Every other group must already fit, which
+/// [`check_replacement_sizes`] verifies before any images are patched.
+fn grow_partition_groups(
+    manifest: &mut DeltaArchiveManifest,
+    external_images: &HashMap<String, ExternalImage>,
 ) -> Result<()> {
-    // This can't fail since the descriptor must have existed for the dependency
-    // to exist.
-    let parent_descriptor = parent_header
-        .descriptors
-        .iter_mut()
-        .find(|d| d.partition_name() == Some(child_name))
-        .unwrap();
-    let parent_type = parent_descriptor.type_name();
+    let Some(dpm) = &mut manifest.dynamic_partition_metadata else {
+        return Ok(());
+    };
 
-    if child_header.public_key.is_empty() {
-        // vbmeta is unsigned. Copy the child's existing descriptor.
-        let Some(child_descriptor) = child_header
-            .descriptors
-            .iter()
-            .find(|d| d.partition_name() == Some(child_name))
-        else {
-            bail!("{child_name} has no descriptor for itself");
+    for group in &mut dpm.groups {
+        let Some(group_size) = group.size else {
+            continue;
         };
-        let child_type = child_descriptor.type_name();
 
-        match (parent_descriptor, child_descriptor) {
-            (Descriptor::Hash(pd), Descriptor::Hash(cd)) => {
-                *pd = cd.clone();
-            }
-            (Descriptor::HashTree(pd), Descriptor::HashTree(cd)) => {
-                *pd = cd.clone();
-            }
-            _ => {
-                bail!("{child_name} descriptor ({child_type}) does not match entry in {parent_name} ({parent_type})");
-            }
+        let total_size = manifest
+            .partitions
+            .iter()
+            .filter(|p| group.partition_names.contains(&p.partition_name))
+            .filter_map(|p| p.new_partition_info.as_ref()?.size)
+            .sum::<u64>();
+
+        if total_size <= group_size {
+            continue;
         }
-    } else {
-        // vbmeta is signed; Use a chain descriptor.
-        match parent_descriptor {
-            Descriptor::ChainPartition(pd) => {
-                pd.public_key = child_header.public_key.clone();
-            }
-            _ => {
-                bail!("{child_name} descriptor ({parent_type}) in {parent_name} must be a chain descriptor");
-            }
+
+        let has_gsi = group
+            .partition_names
+            .iter()
+            .any(|n| matches!(external_images.get(n), Some(ExternalImage::Gsi(_))));
+        if !has_gsi {
+            bail!(
+                "Partitions in group {} do not fit: {total_size} > {group_size}",
+                group.name,
+            );
         }
+
+        warning!(
+            "Growing partition group {}: {group_size} -> {total_size}",
+            group.name,
+        );
+        group.size = Some(total_size);
     }
 
     Ok(())
 }
 
-/// Get the text before the first equal sign in the kernel command line if it is
-/// not empty.
-fn cmdline_prefix(cmdline: &str) -> Option<&str> {
-    let Some((prefix, _)) = cmdline.split_once('=') else {
-        return None;
-    };
-    if prefix.is_empty() {
-        return None;
-    }
-
-    Some(prefix)
-}
+/// Get the list of boot image patchers to apply. An [`OtaCertPatcher`] is
+/// applied to the boot images that contain the trusted OTA certificate list. If
+/// `otacerts_targets` is specified, then only the boot images in the list are
+/// patched, which may be none at all if the device only stores the list in the
+/// system image. The `extra_patchers`, like the root patcher, are applied
+/// afterwards in order.
+fn get_boot_patchers(
+    required_images: &RequiredImages,
+    extra_patchers: Vec<Box<dyn BootImagePatch + Sync>>,
+    cert_ota: &Certificate,
+    otacerts_targets: Option<&[String]>,
+) -> Vec<Box<dyn BootImagePatch + Sync>> {
+    let mut boot_patchers = Vec::<Box<dyn BootImagePatch + Sync>>::new();
 
-/// Merge property descriptors and kernel command line descriptors from the
-/// child into the parent. The property descriptors are matched based on the
-/// entire property key. The kernel command line descriptors are matched based
-/// on the non-empty text left of the first equal sign (if it exists).
-///
-/// This is a no-op if the child is signed because it is expected to be chain
-/// loaded by the parent.
-fn update_metadata_descriptors(parent_header: &mut Header, child_header: &Header) {
-    if !child_header.public_key.is_empty() {
-        return;
+    if let Some(targets) = otacerts_targets {
+        let boot_targets = targets
+            .iter()
+            .filter(|n| required_images.is_boot(n))
+            .cloned()
+            .collect::<Vec<_>>();
+
+        if !boot_targets.is_empty() {
+            boot_patchers.push(Box::new(
+                OtaCertPatcher::new(cert_ota.clone()).with_targets(boot_targets),
+            ));
+        }
+    } else {
+        boot_patchers.push(Box::new(OtaCertPatcher::new(cert_ota.clone())));
     }
 
-    for child_descriptor in &child_header.descriptors {
-        match child_descriptor {
-            Descriptor::Property(cd) => {
-                let parent_property = parent_header.descriptors.iter_mut().find_map(|d| match d {
-                    Descriptor::Property(p) if p.key == cd.key => Some(p),
-                    _ => None,
-                });
+    boot_patchers.extend(extra_patchers);
 
-                if let Some(pd) = parent_property {
-                    pd.value = cd.value.clone();
-                } else {
-                    parent_header
-                        .descriptors
-                        .push(Descriptor::Property(cd.clone()));
-                }
-            }
-            Descriptor::KernelCmdline(cd) => {
-                let Some(prefix) = cmdline_prefix(&cd.cmdline) else {
-                    continue;
-                };
+    boot_patchers
+}
 
-                let parent_property = parent_header.descriptors.iter_mut().find_map(|d| match d {
-                    Descriptor::KernelCmdline(p) if cmdline_prefix(&p.cmdline) == Some(prefix) => {
-                        Some(p)
-                    }
-                    _ => None,
-                });
+/// Patch the boot images listed in `required_images` with `boot_patchers`. Not
+/// every image is necessarily patched. If the original image is signed, then it
+/// will be re-signed with `key_avb`.
+fn patch_boot_images<'a, 'b: 'a>(
+    required_images: &'b RequiredImages,
+    input_files: &mut HashMap<String, InputFile>,
+    boot_patchers: &[Box<dyn BootImagePatch + Sync>],
+    key_avb: &RsaSigningKey,
+    avb_algorithm: Option<AlgorithmType>,
+    cancel_signal: &AtomicBool,
+) -> Result<HashMap<&'b str, Vec<&'static str>>> {
+    let input_files = Mutex::new(input_files);
+    let boot_partitions = required_images.iter_boot().collect::<Vec<_>>();
 
-                if let Some(pd) = parent_property {
-                    pd.cmdline = cd.cmdline.clone();
-                } else {
-                    parent_header
-                        .descriptors
-                        .push(Descriptor::KernelCmdline(cd.clone()));
-                }
-            }
-            _ => {}
-        }
-    }
+    status!(
+        "Patching boot images: {}",
+        joined(sorted(boot_partitions.iter())),
+    );
+
+    boot::patch_boot_images(
+        &boot_partitions,
+        |name| {
+            let locked = input_files.lock().unwrap();
+            ReadSeekReopen::reopen_boxed(&locked[name].file)
+        },
+        |name| {
+            let mut locked = input_files.lock().unwrap();
+            let input_file = locked.get_mut(name).unwrap();
+            input_file.file = tempfile::tempfile().map(PSeekFile::new)?;
+            input_file.state = InputFileState::Modified;
+            WriteSeekReopen::reopen_boxed(&input_file.file)
+        },
+        key_avb,
+        avb_algorithm,
+        boot_patchers,
+        cancel_signal,
+    )
+    .with_context(|| {
+        format!(
+            "Failed to patch boot images: {}",
+            joined(sorted(boot_partitions.iter())),
+        )
+    })
 }
 
-/// Update vbmeta headers.
-///
-/// * If [`Header::flags`] is non-zero, then an error is returned because the
-///   value renders AVB useless. If `clear_vbmeta_flags` is set to true, then
-///   the value is set to 0 instead.
-/// * [`Header::descriptors`] is updated for each dependency listed in `order`.
-/// * [`Header::algorithm_type`] is updated with an algorithm type that matches
-///   `key`. This is not a factor when determining if a header is changed.
-///
-/// If changes were made to a vbmeta header, then the image in `images` will be
-/// replaced with a new in-memory reader containing the new image. Otherwise,
-/// the image is removed from `images` entirely to avoid needing to repack it.
-fn update_vbmeta_headers(
-    images: &mut HashMap<String, InputFile>,
-    headers: &mut HashMap<String, Header>,
-    order: &mut [(String, HashSet<String>)],
-    clear_vbmeta_flags: bool,
-    key: &RsaPrivateKey,
-    block_size: u64,
-) -> Result<()> {
-    for (name, deps) in order {
-        let parent_header = headers.get_mut(name).unwrap();
-        let orig_parent_header = parent_header.clone();
-
-        if parent_header.flags != 0 {
-            if clear_vbmeta_flags {
-                parent_header.flags = 0;
-            } else {
-                bail!(
-                    "Verified boot is disabled by {name}'s header flags: {:#x}",
-                    parent_header.flags,
-                );
-            }
-        }
-
-        for dep in deps.iter() {
-            let input_file = images.get_mut(dep).unwrap();
-            let (header, _, _) = avb::load_image(&mut input_file.file)
-                .with_context(|| format!("Failed to load vbmeta footer from image: {dep}"))?;
+/// Patch the single system image listed in `required_images` to replace the
+/// `otacerts.zip` contents.
+fn patch_system_image<'a, 'b: 'a>(
+    required_images: &'b RequiredImages,
+    input_files: &mut HashMap<String, InputFile>,
+    cert_ota: &Certificate,
+    key_avb: &RsaSigningKey,
+    avb_algorithm: Option<AlgorithmType>,
+    cancel_signal: &AtomicBool,
+) -> Result<(&'b str, Vec<Range<u64>>)> {
+    let Some(target) = required_images.iter_system().next() else {
+        bail!("No system partition found");
+    };
 
-            update_security_descriptors(parent_header, &header, name, dep)?;
-            update_metadata_descriptors(parent_header, &header);
-        }
+    status!("Patching system image: {target}");
 
-        // Only sign and rewrite the image if we need to. Some vbmeta images may
-        // have no dependencies and are only being processed to ensure that the
-        // flags are set to a sane value.
-        if parent_header != &orig_parent_header {
-            parent_header.set_algo_for_key(key)?;
-            parent_header
-                .sign(key)
-                .with_context(|| format!("Failed to sign vbmeta header for image: {name}"))?;
+    let input_file = input_files.get_mut(target).unwrap();
 
-            let mut writer = tempfile::tempfile()
-                .map(PSeekFile::new)
-                .with_context(|| format!("Failed to create temp file for: {name}"))?;
-            parent_header
-                .to_writer(&mut writer)
-                .with_context(|| format!("Failed to write vbmeta image: {name}"))?;
+    // We can't modify external files in place.
+    if input_file.state == InputFileState::External {
+        let mut reader = input_file.file.reopen()?;
+        let mut writer = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {target}"))?;
 
-            padding::write_zeros(&mut writer, block_size)
-                .with_context(|| format!("Failed to write vbmeta padding: {name}"))?;
+        stream::copy(&mut reader, &mut writer, cancel_signal)?;
 
-            let input_file = images.get_mut(name).unwrap();
-            input_file.file = writer;
-            input_file.state = InputFileState::Modified;
-        }
+        input_file.file = writer;
+        input_file.state = InputFileState::Extracted;
     }
 
-    Ok(())
-}
+    let (mut ranges, other_ranges) = system::patch_system_image(
+        &input_file.file,
+        &input_file.file,
+        cert_ota,
+        key_avb,
+        avb_algorithm,
+        cancel_signal,
+    )
+    .with_context(|| format!("Failed to patch system image: {target}"))?;
 
-/// Compress an image and update the OTA manifest partition entry appropriately.
-/// If `ranges` is [`None`], then the entire file is compressed. Otherwise, only
-/// the chunks containing the specified ranges are compressed. In the latter
-/// scenario, unmodified chunks must be copied from the original payload.
-fn compress_image(
-    name: &str,
-    file: &mut PSeekFile,
-    header: &mut PayloadHeader,
-    ranges: Option<&[Range<u64>]>,
-    cancel_signal: &AtomicBool,
-) -> Result<Vec<Range<usize>>> {
-    file.rewind()?;
+    input_file.state = InputFileState::Modified;
 
-    let writer = tempfile::tempfile()
-        .map(PSeekFile::new)
-        .with_context(|| format!("Failed to create temp file for: {name}"))?;
+    status!("Patched otacerts.zip offsets in {target}: {ranges:?}");
 
-    let block_size = header.manifest.block_size();
-    let partition = header
-        .manifest
-        .partitions
-        .iter_mut()
-        .find(|p| p.partition_name == name)
-        .unwrap();
+    ranges.extend(other_ranges);
 
-    if let Some(r) = ranges {
-        status!("Compressing partial image: {name}: {r:?}");
+    Ok((target, ranges))
+}
 
-        match payload::compress_modified_image(
-            &*file,
-            &writer,
-            block_size,
-            partition.new_partition_info.as_mut().unwrap(),
-            &mut partition.operations,
-            r,
-            cancel_signal,
-        ) {
-            Ok(indices) => {
-                *file = writer;
-                return Ok(indices);
-            }
-            // If we can't take advantage of the optimization, we can still
-            // compress the whole image.
-            Err(payload::Error::ExtentsNotInOrder) => {
-                warning!("Cannot use optimization for {name}: extents not in order");
-            }
-            Err(e) => return Err(e.into()),
+/// Ensure that the system image uses the filesystem that the device preset
+/// expects.
+fn check_system_fs(file: &PSeekFile, name: &str, expected: Filesystem) -> Result<()> {
+    let actual = Filesystem::detect(BufReader::new(file.reopen()?))
+        .with_context(|| format!("Failed to read filesystem superblock: {name}"))?;
+
+    match actual {
+        Some(fs) if fs == expected => Ok(()),
+        Some(fs) => bail!("Device preset expects {expected} {name} image, but found {fs}"),
+        None => {
+            bail!("Device preset expects {expected} {name} image, but found unknown filesystem")
         }
     }
+}
 
-    status!("Compressing full image: {name}");
-
-    // Otherwise, compress the entire image.
-    let (partition_info, operations) =
-        payload::compress_image(&*file, &writer, name, block_size, cancel_signal)?;
-
-    partition.new_partition_info = Some(partition_info);
-    partition.operations = operations;
+/// Ensure that no patched vbmeta image exceeds the device's size limit.
+fn check_vbmeta_sizes(
+    input_files: &HashMap<String, InputFile>,
+    vbmeta_images: &HashSet<&str>,
+    max_size: u64,
+) -> Result<()> {
+    for (name, input_file) in input_files {
+        if !vbmeta_images.contains(name.as_str()) || input_file.state != InputFileState::Modified {
+            continue;
+        }
 
-    *file = writer;
+        let size = input_file.file.reopen()?.seek(SeekFrom::End(0))?;
+        if size > max_size {
+            bail!("Patched {name} image is {size} bytes, which exceeds the device limit of {max_size} bytes");
+        }
+    }
 
-    #[allow(clippy::single_range_in_vec_init)]
-    Ok(vec![0..partition.operations.len()])
+    Ok(())
 }
 
-#[allow(clippy::too_many_arguments)]
-fn patch_ota_payload(
-    payload: &(dyn ReadSeekReopen + Sync),
-    writer: impl Write,
-    external_images: &HashMap<String, PathBuf>,
-    root_patcher: Option<Box<dyn BootImagePatch + Sync>>,
-    clear_vbmeta_flags: bool,
-    key_avb: &RsaPrivateKey,
-    key_ota: &RsaPrivateKey,
-    cert_ota: &Certificate,
-    cancel_signal: &AtomicBool,
-) -> Result<(String, u64)> {
-    let header = PayloadHeader::from_reader(payload.reopen_boxed()?)
-        .context("Failed to load OTA payload header")?;
-    if !header.is_full_ota() {
-        bail!("Payload is a delta OTA, not a full OTA");
+/// Rename partitions in the payload manifest according to `partition_map`,
+/// which maps old names to new names. All renames are applied at once, so
+/// partitions can swap names.
+fn rename_manifest_partitions(
+    header: &mut PayloadHeader,
+    partition_map: &BTreeMap<String, String>,
+) -> Result<()> {
+    let partitions = &mut header.manifest.partitions;
+    let mut new_names = HashSet::new();
+
+    for (old_name, new_name) in partition_map {
+        if !partitions.iter().any(|p| &p.partition_name == old_name) {
+            bail!("Cannot rename non-existent partition: {old_name}");
+        } else if !new_names.insert(new_name) {
+            bail!("Multiple partitions would be renamed to: {new_name}");
+        } else if partitions.iter().any(|p| &p.partition_name == new_name)
+            && !partition_map.contains_key(new_name)
+        {
+            bail!("Cannot rename {old_name} to existing partition: {new_name}");
+        }
     }
 
-    let header = Mutex::new(header);
-    let mut header_locked = header.lock().unwrap();
-    let all_partitions = header_locked
-        .manifest
-        .partitions
-        .iter()
-        .map(|p| p.partition_name.as_str())
-        .collect::<HashSet<_>>();
-
-    // Use external partition images if provided. This may be a larger set than
-    // what's needed for our patches.
-    for (name, path) in external_images {
-        if !all_partitions.contains(name.as_str()) {
-            bail!("Cannot replace non-existent {name} partition with {path:?}");
+    for partition in partitions.iter_mut() {
+        if let Some(new_name) = partition_map.get(&partition.partition_name) {
+            status!(
+                "Renaming partition: {} -> {new_name}",
+                partition.partition_name
+            );
+            partition.partition_name.clone_from(new_name);
         }
     }
 
-    // Determine what images need to be patched. For simplicity, we pre-read all
-    // vbmeta images since they're tiny. They're discarded later if the they
-    // don't need to be modified.
-    let required_images = RequiredImages::new(&header_locked.manifest);
-    let vbmeta_images = required_images.iter_vbmeta().collect::<HashSet<_>>();
-
-    // The set of source images to be inserted into the new payload, replacing
-    // what was in the original payload. Initially, this refers to either user
-    // specified files (--replace option) or temporary files (extracted from the
-    // old payload). The values will be replaced later if the images need to be
-    // patched (eg. boot or vbmeta image).
-    let mut input_files = open_input_files(
-        payload,
-        &required_images,
-        external_images,
-        &header_locked,
-        cancel_signal,
-    )?;
+    if let Some(dpm) = &mut header.manifest.dynamic_partition_metadata {
+        for name in dpm.groups.iter_mut().flat_map(|g| &mut g.partition_names) {
+            if let Some(new_name) = partition_map.get(name.as_str()) {
+                name.clone_from(new_name);
+            }
+        }
+    }
 
-    patch_boot_images(
-        &required_images,
-        &mut input_files,
-        root_patcher,
-        key_avb,
-        cert_ota,
-        cancel_signal,
-    )?;
+    Ok(())
+}
 
-    // Main patching operation is done. Unmodified boot images no longer need to
-    // be kept around.
-    input_files
-        .retain(|n, f| !(f.state == InputFileState::Extracted && RequiredImages::is_boot(n)));
+/// Load the specified vbmeta image headers. If an image has a vbmeta footer,
+/// then an error is returned because the vbmeta patching logic only ever writes
+/// root vbmeta images. Descriptors referring to partitions in `partition_map`
+/// are renamed and the images containing them are marked as modified. If
+/// `strict` is true, then an error is returned if a header cannot be written
+/// back out byte-for-byte.
+fn load_vbmeta_images(
+    images: &mut HashMap<String, InputFile>,
+    vbmeta_images: &HashSet<&str>,
+    partition_map: &BTreeMap<String, String>,
+    strict: bool,
+) -> Result<HashMap<String, Header>> {
+    let mut result = HashMap::new();
 
-    let (system_target, system_ranges) = patch_system_image(
-        &required_images,
-        &mut input_files,
-        cert_ota,
-        key_avb,
-        cancel_signal,
-    )?;
+    for &name in vbmeta_images {
+        let input_file = images.get_mut(name).unwrap();
+        let load_image = if strict {
+            avb::load_image_strict
+        } else {
+            avb::load_image
+        };
+        let (mut header, footer, _) = load_image(&mut input_file.file)
+            .with_context(|| format!("Failed to load vbmeta image: {name}"))?;
 
-    let mut vbmeta_headers = load_vbmeta_images(&mut input_files, &vbmeta_images)?;
+        if let Some(f) = footer {
+            bail!("{name} is a vbmeta partition, but has a footer: {f:?}");
+        }
 
-    ensure_partitions_protected(&required_images, &vbmeta_headers)?;
+        for descriptor in &mut header.descriptors {
+            let is_chain = matches!(descriptor, Descriptor::ChainPartition(_));
+            let Some(partition_name) = descriptor.partition_name_mut() else {
+                continue;
+            };
+            let Some(new_name) = partition_map.get(partition_name.as_str()) else {
+                continue;
+            };
 
-    let mut vbmeta_order = get_vbmeta_patch_order(&mut input_files, &vbmeta_headers)?;
+            // The chained image's own signed descriptors would still refer to
+            // the old name.
+            if is_chain && !vbmeta_images.contains(new_name.as_str()) {
+                bail!("Cannot rename {partition_name}: it is chain loaded by {name}");
+            }
 
-    status!(
-        "Patching vbmeta images: {}",
-        joined(vbmeta_order.iter().map(|(n, _)| n)),
-    );
+            partition_name.clone_from(new_name);
+            input_file.state = InputFileState::Modified;
+        }
 
-    update_vbmeta_headers(
-        &mut input_files,
-        &mut vbmeta_headers,
-        &mut vbmeta_order,
-        clear_vbmeta_flags,
-        key_avb,
-        header_locked.manifest.block_size().into(),
-    )?;
+        result.insert(name.to_owned(), header);
+    }
 
-    // Unmodified vbmeta images no longer need to be kept around either.
-    input_files.retain(|_, f| f.state != InputFileState::Extracted);
+    Ok(result)
+}
 
-    let mut compressed_files = input_files
-        .into_iter()
-        .map(|(name, mut input_file)| {
-            let modified_operations = compress_image(
-                &name,
-                &mut input_file.file,
-                &mut header_locked,
-                // We can only perform the optimization of avoiding
-                // recompression if the image came from the original payload.
-                if name == system_target && !external_images.contains_key(&name) {
-                    Some(&system_ranges)
-                } else {
-                    None
-                },
-                cancel_signal,
-            )
-            .with_context(|| format!("Failed to compress image: {name}"))?;
+/// Check that all critical partitions within the payload are protected by a
+/// vbmeta image in `vbmeta_headers`.
+fn ensure_partitions_protected(
+    required_images: &RequiredImages,
+    vbmeta_headers: &HashMap<String, Header>,
+) -> Result<()> {
+    let critical_partitions = required_images
+        .iter_boot()
+        .chain(required_images.iter_vbmeta())
+        .collect::<BTreeSet<_>>();
 
-            Ok((name, (input_file, modified_operations)))
+    // vbmeta partitions first.
+    let mut avb_partitions = vbmeta_headers
+        .keys()
+        .map(|n| n.as_str())
+        .collect::<BTreeSet<_>>();
+
+    // Then, everything referred to by the descriptors.
+    for header in vbmeta_headers.values() {
+        let partition_names = header.descriptors.iter().filter_map(|d| d.partition_name());
+
+        avb_partitions.extend(partition_names);
+    }
+
+    let missing = critical_partitions
+        .difference(&avb_partitions)
+        .collect::<Vec<_>>();
+
+    if !missing.is_empty() {
+        bail!(
+            "Found critical partitions that are not protected by AVB: {}",
+            joined(missing),
+        );
+    }
+
+    Ok(())
+}
+
+/// The true root vbmeta image, for OTAs that contain unused vbmeta images that
+/// would otherwise also look like roots.
+#[derive(Clone, Copy, Debug)]
+struct VbmetaRoot<'a> {
+    name: &'a str,
+    /// Leave the other roots, and any vbmeta images only they refer to,
+    /// unmodified.
+    skip_others: bool,
+}
+
+/// Map each vbmeta image to the partitions that its descriptors refer to. Only
+/// partitions for which `include` returns true are added as dependencies.
+pub fn get_vbmeta_deps(
+    vbmeta_headers: &HashMap<String, Header>,
+    mut include: impl FnMut(&str) -> bool,
+) -> HashMap<&str, HashSet<String>> {
+    vbmeta_headers
+        .iter()
+        .map(|(vbmeta_name, header)| {
+            let deps = header
+                .descriptors
+                .iter()
+                .filter_map(|d| d.partition_name())
+                .filter(|&n| include(n))
+                .map(|n| n.to_owned())
+                .collect();
+
+            (vbmeta_name.as_str(), deps)
         })
-        .collect::<Result<HashMap<_, _>>>()?;
+        .collect()
+}
 
-    status!("Generating new OTA payload");
+/// Get the vbmeta images in the dependency graph that no other vbmeta image
+/// refers to.
+pub fn get_vbmeta_roots<'a>(dep_graph: &HashMap<&'a str, HashSet<String>>) -> BTreeSet<&'a str> {
+    dep_graph
+        .keys()
+        .filter(|n| !dep_graph.values().any(|d| d.contains(**n)))
+        .copied()
+        .collect()
+}
 
-    let mut payload_writer = PayloadWriter::new(writer, header_locked.clone(), key_ota.clone())
-        .context("Failed to write payload header")?;
-    let mut orig_payload_reader = payload.reopen_boxed().context("Failed to open payload")?;
+/// From the set of input images (modified partitions + all vbmeta partitions),
+/// determine the order to patch the vbmeta images so that it can be done in a
+/// single pass. If there are multiple root vbmeta images, then `vbmeta_root`
+/// must specify which one is loaded by the bootloader.
+fn get_vbmeta_patch_order(
+    image_states: &HashMap<String, InputFileState>,
+    vbmeta_headers: &HashMap<String, Header>,
+    vbmeta_root: Option<VbmetaRoot>,
+) -> Result<Vec<(String, HashSet<String>)>> {
+    // Only consider (chained) vbmeta partitions and other partitions that were
+    // modified during patching.
+    let mut dep_graph = get_vbmeta_deps(vbmeta_headers, |name| {
+        image_states
+            .get(name)
+            .is_some_and(|s| vbmeta_headers.contains_key(name) || *s != InputFileState::Extracted)
+    });
+
+    let missing = image_states
+        .keys()
+        .filter(|n| {
+            !dep_graph.contains_key(n.as_str()) && !dep_graph.values().any(|d| d.contains(*n))
+        })
+        .collect::<BTreeSet<_>>();
 
-    while payload_writer
-        .begin_next_operation()
-        .context("Failed to begin next payload blob entry")?
-    {
-        let name = payload_writer.partition().unwrap().partition_name.clone();
-        let operation = payload_writer.operation().unwrap();
+    if !missing.is_empty() {
+        warning!("Partitions aren't protected by AVB: {:?}", joined(missing));
+    }
+
+    // Ensure that there's only a single root of trust. Otherwise, there could
+    // be eg. a `vbmeta_unused` containing all the relevant descriptors, but is
+    // never loaded by the bootloader.
+    let roots = get_vbmeta_roots(&dep_graph);
+
+    if let Some(root) = vbmeta_root {
+        if !vbmeta_headers.contains_key(root.name) {
+            bail!("Specified root vbmeta image not found: {}", root.name);
+        } else if !roots.contains(root.name) {
+            bail!("Specified vbmeta image is not a root: {}", root.name);
+        }
+
+        for other in roots.iter().filter(|n| **n != root.name) {
+            warning!("Ignoring unused root vbmeta image: {other}");
+        }
+
+        if root.skip_others {
+            let mut reachable = HashSet::from([root.name.to_owned()]);
+            let mut stack = vec![root.name.to_owned()];
+
+            while let Some(name) = stack.pop() {
+                for dep in &dep_graph[name.as_str()] {
+                    if dep_graph.contains_key(dep.as_str()) && reachable.insert(dep.clone()) {
+                        stack.push(dep.clone());
+                    }
+                }
+            }
+
+            dep_graph.retain(|n, _| reachable.contains(*n));
+        }
+    } else if roots.len() > 1 {
+        // For zero roots, let TopologicalSort report the cycle.
+        bail!(
+            "Found multiple root vbmeta images: {}. Use --vbmeta-root to select the one that \
+            is loaded by the bootloader",
+            joined(roots),
+        );
+    }
+
+    // Compute the patching order. This only includes vbmeta images. All vbmeta
+    // images are included (even those that have no dependencies) so that
+    // update_vbmeta_headers() can check and update the flags field if needed.
+    let mut topo = TopologicalSort::<String>::new();
+    let mut order = vec![];
+
+    for (name, deps) in &dep_graph {
+        for dep in deps {
+            topo.add_dependency(dep, name.to_owned());
+        }
+    }
+
+    while !topo.is_empty() {
+        match topo.pop() {
+            Some(item) => {
+                // Only include vbmeta images.
+                if dep_graph.contains_key(item.as_str()) {
+                    order.push((item.clone(), dep_graph.remove(item.as_str()).unwrap()));
+                }
+            }
+            None => bail!("vbmeta dependency graph has cycle: {topo:?}"),
+        }
+    }
+
+    Ok(order)
+}
+
+/// Copy the hash or hashtree descriptor from the child image header into the
+/// parent image header if the child is unsigned or update the parent's chain
+/// descriptor if the child is signed. The existing descriptor in the parent
+/// must have the same type as the child. If the child was renamed, its own
+/// descriptor still refers to `child_orig_name`.
+fn update_security_descriptors(
+    parent_header: &mut Header,
+    child_header: &Header,
+    parent_name: &str,
+    child_name: &str,
+    child_orig_name: &str,
+) -> Result<()> {
+    // This can't fail since the descriptor must have existed for the dependency
+    // to exist.
+    let parent_descriptor = parent_header
+        .descriptors
+        .iter_mut()
+        .find(|d| d.partition_name() == Some(child_name))
+        .unwrap();
+    let parent_type = parent_descriptor.type_name();
+
+    if child_header.public_key.is_empty() {
+        // vbmeta is unsigned. Copy the child's existing descriptor.
+        let Some(child_descriptor) = child_header
+            .descriptors
+            .iter()
+            .find(|d| d.partition_name() == Some(child_orig_name))
+        else {
+            bail!("{child_name} has no descriptor for itself");
+        };
+        let child_type = child_descriptor.type_name();
+
+        match (parent_descriptor, child_descriptor) {
+            (Descriptor::Hash(pd), Descriptor::Hash(cd)) => {
+                *pd = cd.clone();
+                child_name.clone_into(&mut pd.partition_name);
+            }
+            (Descriptor::HashTree(pd), Descriptor::HashTree(cd)) => {
+                *pd = cd.clone();
+                child_name.clone_into(&mut pd.partition_name);
+            }
+            _ => {
+                bail!("{child_name} descriptor ({child_type}) does not match entry in {parent_name} ({parent_type})");
+            }
+        }
+    } else {
+        // vbmeta is signed; Use a chain descriptor.
+        match parent_descriptor {
+            Descriptor::ChainPartition(pd) => {
+                pd.public_key = child_header.public_key.clone();
+            }
+            _ => {
+                bail!("{child_name} descriptor ({parent_type}) in {parent_name} must be a chain descriptor");
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the text before the first equal sign in the kernel command line if it is
+/// not empty.
+fn cmdline_prefix(cmdline: &str) -> Option<&str> {
+    let Some((prefix, _)) = cmdline.split_once('=') else {
+        return None;
+    };
+    if prefix.is_empty() {
+        return None;
+    }
+
+    Some(prefix)
+}
+
+/// Merge property descriptors and kernel command line descriptors from the
+/// child into the parent. The property descriptors are matched based on the
+/// entire property key. The kernel command line descriptors are matched based
+/// on the non-empty text left of the first equal sign (if it exists).
+///
+/// This is a no-op if the child is signed because it is expected to be chain
+/// loaded by the parent.
+fn update_metadata_descriptors(parent_header: &mut Header, child_header: &Header) {
+    if !child_header.public_key.is_empty() {
+        return;
+    }
+
+    for child_descriptor in &child_header.descriptors {
+        match child_descriptor {
+            Descriptor::Property(cd) => {
+                let parent_property = parent_header.descriptors.iter_mut().find_map(|d| match d {
+                    Descriptor::Property(p) if p.key == cd.key => Some(p),
+                    _ => None,
+                });
+
+                if let Some(pd) = parent_property {
+                    pd.value = cd.value.clone();
+                } else {
+                    parent_header
+                        .descriptors
+                        .push(Descriptor::Property(cd.clone()));
+                }
+            }
+            Descriptor::KernelCmdline(cd) => {
+                let Some(prefix) = cmdline_prefix(&cd.cmdline) else {
+                    continue;
+                };
+
+                let parent_property = parent_header.descriptors.iter_mut().find_map(|d| match d {
+                    Descriptor::KernelCmdline(p) if cmdline_prefix(&p.cmdline) == Some(prefix) => {
+                        Some(p)
+                    }
+                    _ => None,
+                });
+
+                if let Some(pd) = parent_property {
+                    pd.cmdline = cd.cmdline.clone();
+                } else {
+                    parent_header
+                        .descriptors
+                        .push(Descriptor::KernelCmdline(cd.clone()));
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Update vbmeta headers.
+///
+/// * If [`Header::flags`] is non-zero, then an error is returned because the
+///   value renders AVB useless. If `clear_vbmeta_flags` is set to true, then
+///   the value is set to 0 instead.
+/// * [`Header::descriptors`] is updated for each dependency listed in `order`.
+/// * [`Header::algorithm_type`] is updated with an algorithm type that matches
+///   `key`. This is not a factor when determining if a header is changed.
+///
+/// If changes were made to a vbmeta header, then the image in `images` will be
+/// replaced with a new in-memory reader containing the new image. Otherwise,
+/// the image is removed from `images` entirely to avoid needing to repack it.
+#[allow(clippy::too_many_arguments)]
+fn update_vbmeta_headers(
+    images: &mut HashMap<String, InputFile>,
+    headers: &mut HashMap<String, Header>,
+    order: &mut [(String, HashSet<String>)],
+    partition_map: &BTreeMap<String, String>,
+    clear_vbmeta_flags: bool,
+    key: &RsaSigningKey,
+    algorithm: Option<AlgorithmType>,
+    block_size: u64,
+) -> Result<()> {
+    for (name, deps) in order {
+        let parent_header = headers.get_mut(name).unwrap();
+        let orig_parent_header = parent_header.clone();
+
+        if parent_header.flags != 0 {
+            if clear_vbmeta_flags {
+                parent_header.flags = 0;
+            } else {
+                bail!(
+                    "Verified boot is disabled by {name}'s header flags: {:#x}",
+                    parent_header.flags,
+                );
+            }
+        }
+
+        for dep in deps.iter() {
+            let input_file = images.get_mut(dep).unwrap();
+            let (header, _, _) = avb::load_image(&mut input_file.file)
+                .with_context(|| format!("Failed to load vbmeta footer from image: {dep}"))?;
+
+            let orig_dep = partition_map
+                .iter()
+                .find(|(_, n)| *n == dep)
+                .map_or(dep.as_str(), |(o, _)| o.as_str());
+
+            update_security_descriptors(parent_header, &header, name, dep, orig_dep)?;
+            update_metadata_descriptors(parent_header, &header);
+        }
+
+        // Only sign and rewrite the image if we need to. Some vbmeta images may
+        // have no dependencies and are only being processed to ensure that the
+        // flags are set to a sane value. Images with renamed descriptors are
+        // already marked as modified.
+        if parent_header != &orig_parent_header
+            || images[name.as_str()].state == InputFileState::Modified
+        {
+            parent_header.set_algo_for_key(key, algorithm)?;
+            parent_header
+                .sign(key)
+                .with_context(|| format!("Failed to sign vbmeta header for image: {name}"))?;
+
+            let mut writer = tempfile::tempfile()
+                .map(PSeekFile::new)
+                .with_context(|| format!("Failed to create temp file for: {name}"))?;
+            parent_header
+                .to_writer(&mut writer)
+                .with_context(|| format!("Failed to write vbmeta image: {name}"))?;
+
+            padding::write_zeros(&mut writer, block_size)
+                .with_context(|| format!("Failed to write vbmeta padding: {name}"))?;
+
+            let input_file = images.get_mut(name).unwrap();
+            input_file.file = writer;
+            input_file.state = InputFileState::Modified;
+        }
+    }
+
+    Ok(())
+}
+
+/// A partition whose image differs from the input OTA.
+#[derive(Default, Serialize)]
+struct PartitionChangeReport {
+    /// Whether the image was replaced with `--replace`.
+    replaced: bool,
+    /// Patchers that modified the image, in the order they ran.
+    patchers: Vec<&'static str>,
+    /// AVB digest in the input OTA. This is not known for replaced images.
+    old_avb_digest: Option<String>,
+    /// AVB digest in the output OTA.
+    new_avb_digest: Option<String>,
+}
+
+/// Result of `ota patch --patch-report`.
+#[derive(Default, Serialize)]
+struct PatchReport {
+    avbroot_version: String,
+    /// Patchers that modified at least one partition.
+    patchers: BTreeSet<&'static str>,
+    /// Modified partitions, keyed by partition name.
+    partitions: BTreeMap<String, PartitionChangeReport>,
+    /// New headers of the modified vbmeta images, keyed by image name.
+    vbmeta: BTreeMap<String, VbmetaReport>,
+    /// sha256 digests of the certificates that signed the output OTA.
+    cert_fingerprints: BTreeMap<String, String>,
+}
+
+/// Get the AVB digest of every partition described by the images' AVB headers.
+/// For partitions with a hash or hash tree descriptor, this is the root digest.
+/// For vbmeta images, this is the digest covered by the signature. Other images
+/// without an AVB footer, like replacement images for partitions that AVB does
+/// not protect, are skipped.
+fn get_avb_digests<'a>(
+    images: impl Iterator<Item = (&'a String, &'a mut InputFile)>,
+    vbmeta_images: &HashSet<&str>,
+    partition_map: &BTreeMap<String, String>,
+) -> Result<BTreeMap<String, String>> {
+    let mut result = BTreeMap::new();
+
+    for (name, input_file) in images {
+        let is_vbmeta = vbmeta_images.contains(name.as_str());
+        let header = match avb::load_image(&mut input_file.file) {
+            Ok((header, footer, _)) if is_vbmeta || footer.is_some() => header,
+            Ok(_) => continue,
+            Err(avb::Error::InvalidHeaderMagic(_)) if !is_vbmeta => continue,
+            Err(e) => {
+                return Err(e)
+                    .with_context(|| format!("Failed to load AVB header from image: {name}"));
+            }
+        };
+
+        if is_vbmeta {
+            result.insert(name.clone(), hex::encode(&header.hash));
+        }
+
+        for descriptor in &header.descriptors {
+            let (partition_name, digest) = match descriptor {
+                Descriptor::Hash(d) => (&d.partition_name, &d.root_digest),
+                Descriptor::HashTree(d) => (&d.partition_name, &d.root_digest),
+                _ => continue,
+            };
+            let partition_name = partition_map.get(partition_name).unwrap_or(partition_name);
+
+            result.insert(partition_name.clone(), hex::encode(digest));
+        }
+    }
+
+    Ok(result)
+}
+
+/// Record every image that differs from the input OTA in the patch report.
+/// This must be called after the vbmeta images are updated, but before the
+/// unmodified images are discarded.
+#[allow(clippy::too_many_arguments)]
+fn update_patch_report(
+    report: &mut PatchReport,
+    input_files: &mut HashMap<String, InputFile>,
+    external_images: &HashMap<String, ExternalImage>,
+    vbmeta_images: &HashSet<&str>,
+    vbmeta_headers: &HashMap<String, Header>,
+    partition_map: &BTreeMap<String, String>,
+    old_digests: &BTreeMap<String, String>,
+    boot_patchers: &HashMap<&str, Vec<&'static str>>,
+    system_target: &str,
+    cert_ota: &Certificate,
+) -> Result<()> {
+    let new_digests = get_avb_digests(input_files.iter_mut(), vbmeta_images, partition_map)?;
+
+    for (name, input_file) in input_files.iter() {
+        if input_file.state == InputFileState::Extracted {
+            continue;
+        }
+
+        let mut patchers = boot_patchers
+            .get(name.as_str())
+            .cloned()
+            .unwrap_or_default();
+        // The system image's otacerts.zip is patched the same way.
+        if name == system_target {
+            patchers.push(OtaCertPatcher::new(cert_ota.clone()).patcher_name());
+        }
+
+        if let Some(header) = vbmeta_headers.get(name) {
+            report
+                .vbmeta
+                .insert(name.clone(), VbmetaReport::new(header)?);
+        }
+
+        report.patchers.extend(&patchers);
+
+        let replaced = external_images.contains_key(name);
+
+        report.partitions.insert(
+            name.clone(),
+            PartitionChangeReport {
+                replaced,
+                patchers,
+                old_avb_digest: if replaced {
+                    None
+                } else {
+                    old_digests.get(name).cloned()
+                },
+                new_avb_digest: new_digests.get(name).cloned(),
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Print what [`patch_ota_payload`] is going to do with the input files. The
+/// boot image patcher targets and the vbmeta dependency graph are computed the
+/// same way as when patching. The system image and every boot image targeted by
+/// a patcher is assumed to be modified.
+#[allow(clippy::too_many_arguments)]
+fn print_patch_plan(
+    header: &PayloadHeader,
+    required_images: &RequiredImages,
+    input_files: &mut HashMap<String, InputFile>,
+    external_images: &HashMap<String, ExternalImage>,
+    vbmeta_images: &HashSet<&str>,
+    partition_map: &BTreeMap<String, String>,
+    vbmeta_root: Option<VbmetaRoot>,
+    strict_vbmeta: bool,
+    boot_patchers: &[Box<dyn BootImagePatch + Sync>],
+    transcode_ops: bool,
+    compress_options: &CompressOptions,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    status!("Patch plan:");
+
+    status!("- Images to read:");
+    for name in sorted(input_files.keys()) {
+        match external_images.get(name) {
+            Some(image) => status!("  - {name}: {image}"),
+            None => status!("  - {name}: original payload"),
+        }
+    }
+
+    let boot_partitions = required_images.iter_boot().collect::<Vec<_>>();
+    let boot_images = {
+        let input_files = &*input_files;
+
+        boot::load_boot_images(&boot_partitions, |name| {
+            ReadSeekReopen::reopen_boxed(&input_files[name].file)
+        })
+        .context("Failed to load boot images")?
+    };
+    let all_targets = boot::find_patch_targets(&boot_images, boot_patchers, cancel_signal)
+        .context("Failed to find boot images to patch")?;
+
+    let mut boot_plan = BTreeMap::<&str, Vec<&str>>::new();
+    for (patcher, targets) in boot_patchers.iter().zip(all_targets) {
+        for target in targets {
+            boot_plan
+                .entry(target)
+                .or_default()
+                .push(patcher.patcher_name());
+        }
+    }
+
+    status!("- Boot image patchers:");
+    for (name, patchers) in &boot_plan {
+        status!("  - {name}: {}", joined(patchers));
+    }
+
+    let system_target = required_images.iter_system().next();
+    if let Some(target) = system_target {
+        status!("- System image patchers:");
+        status!("  - {target}: otacerts.zip");
+    }
+
+    let vbmeta_headers =
+        load_vbmeta_images(input_files, vbmeta_images, partition_map, strict_vbmeta)?;
+    let mut modified = external_images
+        .keys()
+        .map(|n| n.as_str())
+        .chain(boot_plan.keys().copied())
+        .chain(system_target)
+        .collect::<BTreeSet<_>>();
+
+    let image_states = input_files
+        .iter()
+        .map(|(n, f)| {
+            let state = if modified.contains(n.as_str()) {
+                InputFileState::Modified
+            } else {
+                f.state
+            };
+
+            (n.clone(), state)
+        })
+        .collect::<HashMap<_, _>>();
+    let vbmeta_order = get_vbmeta_patch_order(&image_states, &vbmeta_headers, vbmeta_root)?;
+
+    status!("- vbmeta dependencies:");
+    for (name, deps) in &vbmeta_order {
+        if deps.is_empty() {
+            status!("  - {name}: (none)");
+        } else {
+            status!("  - {name}: {}", joined(sorted(deps.iter())));
+        }
+    }
+
+    status!(
+        "- vbmeta patch order: {}",
+        joined(vbmeta_order.iter().map(|(n, _)| n)),
+    );
+
+    // vbmeta images are only rewritten if their headers change.
+    for (name, deps) in &vbmeta_order {
+        if !deps.is_empty()
+            || image_states[name] == InputFileState::Modified
+            || vbmeta_headers[name].flags != 0
+        {
+            modified.insert(name);
+        }
+    }
+
+    if transcode_ops {
+        modified.extend(
+            header
+                .manifest
+                .partitions
+                .iter()
+                .filter(|p| p.operations.iter().any(|op| op.r#type() == Type::ReplaceBz))
+                .map(|p| p.partition_name.as_str()),
+        );
+    }
+
+    // The system image is only partially recompressed if it came from the
+    // original payload.
+    let partial = system_target.filter(|n| !external_images.contains_key(*n));
+    let copied = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| p.partition_name.as_str())
+        .filter(|n| !modified.contains(n))
+        .collect::<BTreeSet<_>>();
+
+    status!(
+        "- Fully recompressed: {}",
+        joined(modified.iter().filter(|n| Some(**n) != partial)),
+    );
+    if let Some(name) = partial {
+        status!("- Partially recompressed: {name}");
+    }
+    status!("- Copied as-is: {}", joined(copied));
+
+    let partition_size = |name: &str| {
+        header
+            .manifest
+            .partitions
+            .iter()
+            .find(|p| p.partition_name == name)
+            .and_then(|p| p.new_partition_info.as_ref()?.size)
+            .unwrap_or(0)
+    };
+
+    // Images from the original payload are extracted to temp files. Modified
+    // images are rewritten to new temp files and each one is then compressed
+    // into another temp file, which is at most as large as the image.
+    let extracted_size = input_files
+        .keys()
+        .filter(|n| !external_images.contains_key(*n))
+        .map(|n| partition_size(n))
+        .sum::<u64>();
+    let modified_size = modified.iter().map(|n| partition_size(n)).sum::<u64>();
+    let compressed_size = modified.iter().map(|n| partition_size(n)).max();
+    let disk_usage = extracted_size + modified_size + compressed_size.unwrap_or(0);
+
+    let memory_usage = compress_options.estimate_peak_memory(header.manifest.block_size())?;
+
+    status!(
+        "- Estimated peak memory usage: {}",
+        format_mib(memory_usage),
+    );
+    status!(
+        "- Estimated peak temporary disk usage: {} (in {:?})",
+        format_mib(disk_usage),
+        env::temp_dir(),
+    );
+
+    Ok(())
+}
+
+/// Extract every partition that is not already being replaced and contains
+/// [`Type::ReplaceBz`] operations so that it is recompressed with the current
+/// compression options. bzip2 data is both larger and much slower to decompress
+/// on the device than XZ data.
+fn transcode_bz_images(
+    payload: &(dyn ReadSeekReopen + Sync),
+    header: &PayloadHeader,
+    input_files: &mut HashMap<String, InputFile>,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let names = header
+        .manifest
+        .partitions
+        .iter()
+        .filter(|p| !input_files.contains_key(&p.partition_name))
+        .filter(|p| p.operations.iter().any(|op| op.r#type() == Type::ReplaceBz))
+        .filter(|p| !p.operations.iter().any(payload::requires_source))
+        .map(|p| p.partition_name.as_str())
+        .collect::<Vec<_>>();
+
+    if names.is_empty() {
+        return Ok(());
+    }
+
+    status!("Transcoding REPLACE_BZ operations: {}", joined(&names));
+
+    for name in names {
+        let file = tempfile::tempfile()
+            .map(PSeekFile::new)
+            .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+        payload::extract_image(
+            payload,
+            &file,
+            header,
+            name,
+            &args::payload_apply_options(),
+            cancel_signal,
+        )
+        .with_context(|| format!("Failed to extract from original payload: {name}"))?;
+
+        input_files.insert(
+            name.to_owned(),
+            InputFile {
+                file,
+                state: InputFileState::Modified,
+            },
+        );
+    }
+
+    Ok(())
+}
+
+/// Compress an image and update the OTA manifest partition entry appropriately.
+/// If `ranges` is [`None`], then the entire file is compressed. Otherwise, only
+/// the chunks containing the specified ranges are compressed. In the latter
+/// scenario, unmodified chunks must be copied from the original payload.
+fn compress_image(
+    name: &str,
+    file: &mut PSeekFile,
+    header: &mut PayloadHeader,
+    ranges: Option<&[Range<u64>]>,
+    options: &CompressOptions,
+    cancel_signal: &AtomicBool,
+) -> Result<Vec<Range<usize>>> {
+    file.rewind()?;
+
+    let writer = tempfile::tempfile()
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to create temp file for: {name}"))?;
+
+    let block_size = header.manifest.block_size();
+    let partition = header
+        .manifest
+        .partitions
+        .iter_mut()
+        .find(|p| p.partition_name == name)
+        .unwrap();
+
+    if let Some(r) = ranges {
+        status!("Compressing partial image: {name}: {r:?}");
+
+        match payload::compress_modified_image(
+            &*file,
+            &writer,
+            block_size,
+            partition.new_partition_info.as_mut().unwrap(),
+            &mut partition.operations,
+            r,
+            options,
+            cancel_signal,
+        ) {
+            Ok(indices) => {
+                *file = writer;
+                return Ok(indices);
+            }
+            // If we can't take advantage of the optimization, we can still
+            // compress the whole image.
+            Err(payload::Error::ExtentsNotInOrder) => {
+                warning!("Cannot use optimization for {name}: extents not in order");
+            }
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    status!("Compressing full image: {name}");
+
+    // Otherwise, compress the entire image.
+    let (partition_info, operations) =
+        payload::compress_image(&*file, &writer, name, block_size, options, cancel_signal)?;
+
+    // The new operations no longer depend on the partition's previous
+    // contents.
+    partition.old_partition_info = None;
+    partition.new_partition_info = Some(partition_info);
+    partition.operations = operations;
+
+    *file = writer;
+
+    #[allow(clippy::single_range_in_vec_init)]
+    Ok(vec![0..partition.operations.len()])
+}
+
+#[allow(clippy::too_many_arguments)]
+fn patch_ota_payload(
+    payload: &(dyn ReadSeekReopen + Sync),
+    writer: impl Write,
+    external_images: &HashMap<String, ExternalImage>,
+    source_images: &HashMap<String, PathBuf>,
+    partition_map: &BTreeMap<String, String>,
+    extra_patchers: Vec<Box<dyn BootImagePatch + Sync>>,
+    clear_vbmeta_flags: bool,
+    strict_vbmeta: bool,
+    vbmeta_root: Option<VbmetaRoot>,
+    transcode_ops: bool,
+    print_plan: bool,
+    key_avb: &RsaSigningKey,
+    avb_algorithm: Option<AlgorithmType>,
+    keys_ota: &[RsaSigningKey],
+    cert_ota: &Certificate,
+    otacerts_targets: Option<&[String]>,
+    classifier: &PartitionClassifier,
+    device_preset: Option<&DevicePreset>,
+    compress_options: &mut CompressOptions,
+    blob_cache: Option<&BlobCache>,
+    report: Option<&mut PatchReport>,
+    cancel_signal: &AtomicBool,
+) -> Result<(String, u64)> {
+    let mut header = PayloadHeader::from_reader(payload.reopen_boxed()?)
+        .context("Failed to load OTA payload header")?;
+    check_no_diff_operations(&header)?;
+
+    // Match the original payload's XZ parameters unless they are overridden.
+    // Detection is skipped when both are overridden since the result would be
+    // unused.
+    if compress_options.xz_check.is_none() || compress_options.xz_dict_size.is_none() {
+        compress_options.xz_original = payload::detect_xz_params(payload.reopen_boxed()?, &header)
+            .context("Failed to detect XZ parameters of original payload")?;
+        if let Some(params) = &compress_options.xz_original {
+            status!(
+                "Original payload XZ parameters: check {:?}, dictionary size {}",
+                params.check,
+                params.dict_size,
+            );
+        }
+    }
+    let compress_options = &*compress_options;
+
+    // Renaming happens first so that every other option refers to the new
+    // partition names.
+    rename_manifest_partitions(&mut header, partition_map)?;
+
+    let header = Mutex::new(header);
+    let mut header_locked = header.lock().unwrap();
+    let all_partitions = header_locked
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| p.partition_name.as_str())
+        .collect::<HashSet<_>>();
+
+    // Use external partition images if provided. This may be a larger set than
+    // what's needed for our patches.
+    for (name, image) in external_images {
+        if !all_partitions.contains(name.as_str()) {
+            bail!("Cannot replace non-existent {name} partition with {image}");
+        }
+    }
+
+    // Determine what images need to be patched. For simplicity, we pre-read all
+    // vbmeta images since they're tiny. They're discarded later if the they
+    // don't need to be modified.
+    let required_images = RequiredImages::new(&header_locked.manifest, classifier);
+    let vbmeta_images = required_images.iter_vbmeta().collect::<HashSet<_>>();
+
+    check_source_dependencies(
+        &header_locked,
+        &required_images,
+        external_images,
+        source_images,
+    )?;
+
+    // The set of source images to be inserted into the new payload, replacing
+    // what was in the original payload. Initially, this refers to either user
+    // specified files (--replace option) or temporary files (extracted from the
+    // old payload). The values will be replaced later if the images need to be
+    // patched (eg. boot or vbmeta image).
+    let mut input_files = open_input_files(
+        payload,
+        &required_images,
+        external_images,
+        source_images,
+        &header_locked,
+        cancel_signal,
+    )?;
+
+    prepare_hash_tree_images(
+        external_images,
+        &mut input_files,
+        &vbmeta_images,
+        header_locked.manifest.block_size().into(),
+        cancel_signal,
+    )?;
+
+    prepare_hash_images(
+        external_images,
+        &mut input_files,
+        &vbmeta_images,
+        &header_locked.manifest,
+        cancel_signal,
+    )?;
+
+    check_replacement_sizes(external_images, &mut input_files, &header_locked.manifest)?;
+
+    // Only the images from the original payload are relevant for the report.
+    let old_digests = if report.is_some() {
+        let images = input_files
+            .iter_mut()
+            .filter(|(_, f)| f.state == InputFileState::Extracted);
+
+        Some(get_avb_digests(images, &vbmeta_images, partition_map)?)
+    } else {
+        None
+    };
+
+    let boot_patchers =
+        get_boot_patchers(&required_images, extra_patchers, cert_ota, otacerts_targets);
+
+    if print_plan {
+        print_patch_plan(
+            &header_locked,
+            &required_images,
+            &mut input_files,
+            external_images,
+            &vbmeta_images,
+            partition_map,
+            vbmeta_root,
+            strict_vbmeta,
+            &boot_patchers,
+            transcode_ops,
+            compress_options,
+            cancel_signal,
+        )?;
+    }
+
+    progress::stage("patch_boot_images");
+    let applied_patchers = patch_boot_images(
+        &required_images,
+        &mut input_files,
+        &boot_patchers,
+        key_avb,
+        avb_algorithm,
+        cancel_signal,
+    )?;
+
+    // Main patching operation is done. Unmodified boot images no longer need to
+    // be kept around.
+    input_files
+        .retain(|n, f| !(f.state == InputFileState::Extracted && required_images.is_boot(n)));
+
+    progress::stage("patch_system_image");
+    let (system_target, system_ranges) = patch_system_image(
+        &required_images,
+        &mut input_files,
+        cert_ota,
+        key_avb,
+        avb_algorithm,
+        cancel_signal,
+    )?;
+
+    if let Some(expected) = device_preset.and_then(|p| p.system_fs) {
+        check_system_fs(&input_files[system_target].file, system_target, expected)?;
+    }
+
+    progress::stage("patch_vbmeta_images");
+    let mut vbmeta_headers = load_vbmeta_images(
+        &mut input_files,
+        &vbmeta_images,
+        partition_map,
+        strict_vbmeta,
+    )?;
+
+    ensure_partitions_protected(&required_images, &vbmeta_headers)?;
+
+    let image_states = input_files
+        .iter()
+        .map(|(n, f)| (n.clone(), f.state))
+        .collect();
+    let mut vbmeta_order = get_vbmeta_patch_order(&image_states, &vbmeta_headers, vbmeta_root)?;
+
+    status!(
+        "Patching vbmeta images: {}",
+        joined(vbmeta_order.iter().map(|(n, _)| n)),
+    );
+
+    update_vbmeta_headers(
+        &mut input_files,
+        &mut vbmeta_headers,
+        &mut vbmeta_order,
+        partition_map,
+        clear_vbmeta_flags,
+        key_avb,
+        avb_algorithm,
+        header_locked.manifest.block_size().into(),
+    )?;
+
+    if let Some(max_size) = device_preset.and_then(|p| p.max_vbmeta_size) {
+        check_vbmeta_sizes(&input_files, &vbmeta_images, max_size)?;
+    }
+
+    if let (Some(report), Some(old_digests)) = (report, &old_digests) {
+        update_patch_report(
+            report,
+            &mut input_files,
+            external_images,
+            &vbmeta_images,
+            &vbmeta_headers,
+            partition_map,
+            old_digests,
+            &applied_patchers,
+            system_target,
+            cert_ota,
+        )?;
+    }
+
+    // Unmodified vbmeta images no longer need to be kept around either.
+    input_files.retain(|_, f| f.state != InputFileState::Extracted);
+
+    if transcode_ops {
+        transcode_bz_images(payload, &header_locked, &mut input_files, cancel_signal)?;
+    }
+
+    let image_sizes = input_files
+        .iter()
+        .map(|(name, f)| Ok((name.clone(), f.file.reopen()?.seek(SeekFrom::End(0))?)))
+        .collect::<io::Result<HashMap<_, _>>>()?;
+    let tracker = Tracker::with_partitions(
+        "compress_images",
+        image_sizes.iter().map(|(n, s)| (n.as_str(), *s)),
+    );
+    let mut compressed_files = input_files
+        .into_iter()
+        .map(|(name, mut input_file)| {
+            let modified_operations = compress_image(
+                &name,
+                &mut input_file.file,
+                &mut header_locked,
+                // We can only perform the optimization of avoiding
+                // recompression if the image came from the original payload.
+                if name == system_target && !external_images.contains_key(&name) {
+                    Some(&system_ranges)
+                } else {
+                    None
+                },
+                compress_options,
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to compress image: {name}"))?;
+
+            tracker.add(Some(&name), image_sizes[&name]);
+
+            Ok((name, (input_file, modified_operations)))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    tracker.finish();
+
+    grow_partition_groups(&mut header_locked.manifest, external_images)?;
+
+    status!("Generating new OTA payload");
+
+    let tracker = Tracker::new(
+        "write_payload",
+        header_locked
+            .manifest
+            .partitions
+            .iter()
+            .flat_map(|p| &p.operations)
+            .filter_map(|op| op.data_length)
+            .sum(),
+    );
+    let mut written = 0;
+
+    let mut payload_writer =
+        PayloadWriter::new_with_keys(writer, header_locked.clone(), keys_ota.to_vec())
+            .context("Failed to write payload header")?;
+    let mut orig_payload_reader = payload.reopen_boxed().context("Failed to open payload")?;
+
+    while payload_writer
+        .begin_next_operation()
+        .context("Failed to begin next payload blob entry")?
+    {
+        let name = payload_writer.partition().unwrap().partition_name.clone();
+        let operation = payload_writer.operation().unwrap();
+
+        let Some(data_length) = operation.data_length else {
+            // Otherwise, this is a ZERO/DISCARD operation.
+            continue;
+        };
+
+        tracker.update(Some(&name), written);
+        written += data_length;
+
+        let pi = payload_writer.partition_index().unwrap();
+        let oi = payload_writer.operation_index().unwrap();
+        let orig_partition = &header_locked.manifest.partitions[pi];
+        let orig_operation = &orig_partition.operations[oi];
+        let data_offset = orig_operation
+            .data_offset
+            .ok_or_else(|| anyhow!("Missing data_offset in partition #{pi} operation #{oi}"))?;
+
+        // Try to copy from our replacement image. The compressed chunks are
+        // laid out sequentially and data_offset is set to the offset within
+        // that file.
+        if let Some((input_file, modified_operations)) = compressed_files.get_mut(&name) {
+            if util::ranges_contains(modified_operations, &oi) {
+                input_file
+                    .file
+                    .seek(SeekFrom::Start(data_offset))
+                    .with_context(|| format!("Failed to seek image: {name}"))?;
+
+                stream::copy_n(
+                    &mut input_file.file,
+                    &mut payload_writer,
+                    data_length,
+                    cancel_signal,
+                )
+                .with_context(|| format!("Failed to copy from replacement image: {name}"))?;
+
+                continue;
+            }
+        }
+
+        // Otherwise, copy from the original payload.
+        let data_offset = data_offset
+            .checked_add(header_locked.blob_offset)
+            .ok_or_else(|| anyhow!("data_offset overflow in partition #{pi} operation #{oi}"))?;
+
+        match (blob_cache, &orig_operation.data_sha256_hash) {
+            (Some(cache), Some(digest)) if digest.len() == crate::digest::SHA256_OUTPUT_LEN => {
+                cache
+                    .copy(
+                        digest,
+                        &mut orig_payload_reader,
+                        data_offset,
+                        data_length,
+                        &mut payload_writer,
+                        cancel_signal,
+                    )
+                    .with_context(|| format!("Failed to copy via blob cache: {name}"))?;
+            }
+            _ => {
+                orig_payload_reader
+                    .seek(SeekFrom::Start(data_offset))
+                    .with_context(|| format!("Failed to seek original payload to {data_offset}"))?;
+
+                stream::copy_n(
+                    &mut orig_payload_reader,
+                    &mut payload_writer,
+                    data_length,
+                    cancel_signal,
+                )
+                .with_context(|| format!("Failed to copy from original payload: {name}"))?;
+            }
+        }
+    }
+
+    tracker.update(None, written);
+    tracker.finish();
+
+    if let Some(cache) = blob_cache {
+        let (hits, misses) = cache.stats();
+
+        status!("Blob cache: {hits} hits, {misses} misses");
+    }
+
+    let (_, properties, metadata_size) = payload_writer
+        .finish()
+        .context("Failed to finalize payload")?;
+
+    Ok((properties, metadata_size))
+}
+
+/// Find the location of the uncompressed payload data. If the zip library
+/// cannot open the entry, then the central directory is parsed directly as a
+/// fallback. Some OEM OTAs are produced by tools that emit streaming entries or
+/// unusual local headers.
+fn find_payload_entry(
+    raw_reader: &(impl Read + Seek + Reopen),
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+) -> Result<ZipEntry> {
+    let path = ota::PATH_PAYLOAD;
+
+    match zip_reader.by_name(path) {
+        Ok(reader) => {
+            if reader.compression() != CompressionMethod::Stored {
+                bail!("{path} is not stored uncompressed");
+            }
+
+            Ok(ZipEntry {
+                name: path.to_owned(),
+                offset: reader.data_start(),
+                size: reader.size(),
+            })
+        }
+        Err(e) => {
+            warning!("Failed to open {path} via zip library, using central directory: {e}");
+
+            ota::find_stored_zip_entry(BufReader::new(raw_reader.reopen()?), path)
+                .with_context(|| format!("Failed to find zip entry: {path}"))
+        }
+    }
+}
+
+/// External commands for unwrapping and rewrapping an OEM container around
+/// payload.bin. Each command is run with the input and output file paths as its
+/// two arguments.
+#[derive(Clone, Copy, Debug, Default)]
+struct PayloadHooks<'a> {
+    decrypt_cmd: Option<&'a Path>,
+    encrypt_cmd: Option<&'a Path>,
+}
+
+fn run_payload_hook(cmd: &Path, input: &Path, output: &Path) -> Result<()> {
+    let status = Command::new(cmd)
+        .arg(input)
+        .arg(output)
+        .status()
+        .with_context(|| format!("Failed to run command: {cmd:?}"))?;
+
+    if !status.success() {
+        bail!("Command {cmd:?} failed: {status}");
+    }
+
+    Ok(())
+}
+
+/// Copy the wrapped payload.bin data to a temporary file and unwrap it with the
+/// decrypt command. Returns the file containing the plain payload and its size.
+fn unwrap_payload(
+    cmd: &Path,
+    raw_reader: &(impl Read + Seek + Reopen),
+    entry: &ZipEntry,
+    temp_dir: &Path,
+    cancel_signal: &AtomicBool,
+) -> Result<(PSeekFile, u64)> {
+    let wrapped_path = temp_dir.join("payload.wrapped.bin");
+    let plain_path = temp_dir.join("payload.bin");
+
+    status!("Unwrapping {} with {cmd:?}", ota::PATH_PAYLOAD);
+
+    {
+        let mut reader = SectionReader::new(
+            BufReader::new(raw_reader.reopen()?),
+            entry.offset,
+            entry.size,
+        )?;
+        let mut writer = sandbox::create(&wrapped_path)
+            .map(BufWriter::new)
+            .with_context(|| format!("Failed to open for writing: {wrapped_path:?}"))?;
+
+        stream::copy(&mut reader, &mut writer, cancel_signal)
+            .with_context(|| format!("Failed to copy wrapped payload: {wrapped_path:?}"))?;
+        writer
+            .into_inner()
+            .with_context(|| format!("Failed to flush wrapped payload: {wrapped_path:?}"))?;
+    }
+
+    run_payload_hook(cmd, &wrapped_path, &plain_path)?;
+
+    let file = sandbox::open(&plain_path)
+        .with_context(|| format!("Failed to open for reading: {plain_path:?}"))?;
+    let size = file
+        .metadata()
+        .with_context(|| format!("Failed to get file size: {plain_path:?}"))?
+        .len();
+
+    Ok((PSeekFile::new(file), size))
+}
+
+/// Write the patched plain payload to a temporary file with `patch`, wrap it
+/// with the encrypt command, and copy the wrapped data to `writer`. Returns the
+/// payload properties and the payload metadata size. These describe the wrapped
+/// data that is actually stored in the OTA, not the plain payload.
+fn rewrap_payload(
+    cmd: &Path,
+    patch: impl FnOnce(&mut dyn Write) -> Result<(String, u64)>,
+    writer: impl Write,
+    temp_dir: &Path,
+    cancel_signal: &AtomicBool,
+) -> Result<(String, u64)> {
+    let plain_path = temp_dir.join("payload.patched.bin");
+    let wrapped_path = temp_dir.join("payload.rewrapped.bin");
+
+    {
+        let mut plain_writer = sandbox::create(&plain_path)
+            .map(BufWriter::new)
+            .with_context(|| format!("Failed to open for writing: {plain_path:?}"))?;
+        patch(&mut plain_writer)?;
+        plain_writer
+            .into_inner()
+            .with_context(|| format!("Failed to flush patched payload: {plain_path:?}"))?;
+    }
+
+    status!("Rewrapping {} with {cmd:?}", ota::PATH_PAYLOAD);
+
+    run_payload_hook(cmd, &plain_path, &wrapped_path)?;
+
+    let mut reader = sandbox::open(&wrapped_path)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {wrapped_path:?}"))?;
+    let mut writer =
+        HashingWriter::new(writer, crate::digest::Context::new(&crate::digest::SHA256));
+
+    let size = stream::copy(&mut reader, &mut writer, cancel_signal)
+        .with_context(|| format!("Failed to copy rewrapped payload: {wrapped_path:?}"))?;
+    let digest = writer.finish().1.finish();
+
+    Ok((
+        payload::generate_opaque_properties(digest.as_ref(), size),
+        size,
+    ))
+}
+
+/// Make sure that the payload begins with the payload magic. Payloads wrapped
+/// in an OEM container would otherwise result in an opaque parse failure.
+fn check_payload_magic(mut reader: impl Read) -> Result<()> {
+    let mut magic = [0u8; 4];
+    reader
+        .read_exact(&mut magic)
+        .context("Failed to read payload magic")?;
+
+    if magic != *payload::OTA_MAGIC {
+        bail!(
+            "{} does not begin with the payload magic ({magic:02x?}). It may be wrapped in an OEM \
+            container, which can be unwrapped with --payload-decrypt-cmd",
+            ota::PATH_PAYLOAD,
+        );
+    }
+
+    Ok(())
+}
+
+/// Build the error for a delta (incremental) OTA. Reconstructing the target
+/// partitions would require implementations of the bsdiff, puffdiff, and
+/// zucchini patch formats, none of which are available. The error lists the
+/// operations that depend on source data so it is clear why the payload cannot
+/// be used.
+fn delta_ota_error(header: &PayloadHeader) -> anyhow::Error {
+    let source_ops = header
+        .manifest
+        .partitions
+        .iter()
+        .flat_map(|p| &p.operations)
+        .filter_map(|op| Type::try_from(op.r#type).ok())
+        .filter(|t| {
+            !matches!(
+                t,
+                Type::Replace | Type::ReplaceBz | Type::ReplaceXz | Type::Zero | Type::Discard,
+            )
+        })
+        .map(|t| t.as_str_name())
+        .collect::<BTreeSet<_>>();
+    let partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .filter(|p| p.old_partition_info.is_some())
+        .map(|p| p.partition_name.as_str())
+        .collect::<Vec<_>>();
+
+    anyhow!(
+        "Payload is a delta OTA, not a full OTA. Delta OTAs are not supported because they can \
+        only be applied on top of the exact source partitions. Use the full OTA for the same build \
+        instead. Partitions with source dependencies: {}; operations requiring source data: {}",
+        partitions.join(", "),
+        source_ops.into_iter().collect::<Vec<_>>().join(", "),
+    )
+}
+
+/// Reject delta (incremental) OTAs.
+fn check_full_ota(header: &PayloadHeader) -> Result<()> {
+    if header.is_full_ota() {
+        Ok(())
+    } else {
+        Err(delta_ota_error(header))
+    }
+}
+
+/// Reject delta (incremental) OTAs when patching. Unlike [`check_full_ota`],
+/// this allows partitions whose only operations that read source data are
+/// [`Type::SourceCopy`], which some OEMs include in otherwise full OTAs. Those
+/// are handled by [`check_source_dependencies`].
+fn check_no_diff_operations(header: &PayloadHeader) -> Result<()> {
+    let has_diff = header
+        .manifest
+        .partitions
+        .iter()
+        .flat_map(|p| &p.operations)
+        .any(|op| payload::requires_source(op) && op.r#type() != Type::SourceCopy);
+
+    if has_diff {
+        Err(delta_ota_error(header))
+    } else {
+        Ok(())
+    }
+}
+
+/// Check partitions with [`Type::SourceCopy`] operations, which read from the
+/// partition's previous contents. Images that avbroot needs to read can only be
+/// reconstructed if a source image was provided. Any other partition is copied
+/// to the new payload as is, which is fine because the device applies those
+/// operations itself. [`check_no_diff_operations`] must be called first.
+fn check_source_dependencies(
+    header: &PayloadHeader,
+    required_images: &RequiredImages,
+    external_images: &HashMap<String, ExternalImage>,
+    source_images: &HashMap<String, PathBuf>,
+) -> Result<()> {
+    let all_partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| p.partition_name.as_str())
+        .collect::<HashSet<_>>();
+
+    for name in sorted(source_images.keys()) {
+        if !all_partitions.contains(name.as_str()) {
+            bail!("Cannot use source image for non-existent {name} partition");
+        }
+    }
+
+    let mut blocking = vec![];
+    let mut passthrough = vec![];
+
+    for partition in &header.manifest.partitions {
+        let name = partition.partition_name.as_str();
+        let has_source_ops = partition.operations.iter().any(payload::requires_source);
+
+        if !has_source_ops || external_images.contains_key(name) {
+            continue;
+        } else if !required_images.iter().any(|n| n == name) {
+            passthrough.push(name);
+        } else if !source_images.contains_key(name) {
+            blocking.push(name);
+        }
+    }
+
+    if !passthrough.is_empty() {
+        passthrough.sort_unstable();
+        warning!(
+            "Partitions depend on the data currently on the device and are copied as is: {}",
+            joined(passthrough),
+        );
+    }
+
+    if !blocking.is_empty() {
+        blocking.sort_unstable();
+        bail!(
+            "Partitions that need to be patched depend on the data currently on the device: {}. \
+            Use --source-image to provide the currently installed image or --replace to provide \
+            the complete new image",
+            joined(blocking),
+        );
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn patch_ota_zip(
+    raw_reader: &OtaReader,
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+    mut zip_writer: &mut ZipWriter<impl Write>,
+    external_images: &HashMap<String, ExternalImage>,
+    source_images: &HashMap<String, PathBuf>,
+    partition_map: &BTreeMap<String, String>,
+    mut extra_patchers: Vec<Box<dyn BootImagePatch + Sync>>,
+    clear_vbmeta_flags: bool,
+    strict_vbmeta: bool,
+    vbmeta_root: Option<VbmetaRoot>,
+    transcode_ops: bool,
+    print_plan: bool,
+    key_avb: &RsaSigningKey,
+    avb_algorithm: Option<AlgorithmType>,
+    keys_ota: &[RsaSigningKey],
+    cert_ota: &Certificate,
+    otacerts_targets: Option<&[String]>,
+    classifier: &PartitionClassifier,
+    device_preset: Option<&DevicePreset>,
+    compress_options: &mut CompressOptions,
+    blob_cache: Option<&BlobCache>,
+    provenance: Option<&Provenance>,
+    keep_original_manifest: bool,
+    payload_hooks: PayloadHooks,
+    mut report: Option<&mut PatchReport>,
+    cancel_signal: &AtomicBool,
+) -> Result<(OtaMetadata, u64)> {
+    let mut missing = BTreeSet::from([ota::PATH_OTACERT, ota::PATH_PAYLOAD, ota::PATH_PROPERTIES]);
+
+    // Keep in sorted order for reproducibility and to guarantee that the
+    // payload is processed before its properties file.
+    let paths = zip_reader
+        .file_names()
+        .map(|p| p.to_owned())
+        .collect::<BTreeSet<_>>();
+
+    for path in &paths {
+        missing.remove(path.as_str());
+    }
+
+    if !missing.is_empty() {
+        bail!("Missing entries in OTA zip: {:?}", joined(missing));
+    } else if !paths.contains(ota::PATH_METADATA) && !paths.contains(ota::PATH_METADATA_PB) {
+        bail!(
+            "Neither legacy nor protobuf OTA metadata files exist: {:?}, {:?}",
+            ota::PATH_METADATA,
+            ota::PATH_METADATA_PB,
+        )
+    }
+
+    let mut metadata = None;
+    let mut properties = None;
+    let mut payload_metadata_size = None;
+    let mut original_header = None;
+    let mut input_original_manifest = None;
+    let mut entries = vec![];
+    let mut last_entry_used_zip64 = false;
+
+    let payload_entry = find_payload_entry(raw_reader, zip_reader)?;
+
+    for path in &paths {
+        // The payload is read directly from the underlying file instead.
+        let (mut reader, input_size): (Box<dyn Read>, u64) = if path == ota::PATH_PAYLOAD {
+            (Box::new(io::empty()), payload_entry.size)
+        } else {
+            let reader = zip_reader
+                .by_name(path)
+                .with_context(|| format!("Failed to open zip entry: {path}"))?;
+            let size = reader.size();
+
+            (Box::new(reader), size)
+        };
+
+        // Android's libarchive parser is broken and only reads data descriptor
+        // size fields as 64-bit integers if the central directory says the file
+        // size is >= 2^32 - 1. We'll turn on zip64 if the input is above this
+        // threshold. This should be sufficient since the output file is likely
+        // to be larger.
+        let use_zip64 = input_size >= 0xffffffff;
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(use_zip64);
+
+        // Processed at the end after all other entries are written.
+        match path.as_str() {
+            // Convert legacy metadata from Android 11 to the modern protobuf
+            // structure. Note that although we can read legacy-only OTAs, we
+            // always produce both the legacy and protobuf representations in
+            // the output.
+            ota::PATH_METADATA => {
+                let mut buf = String::new();
+                reader
+                    .read_to_string(&mut buf)
+                    .with_context(|| format!("Failed to read OTA metadata: {path}"))?;
+                metadata = Some(
+                    ota::parse_legacy_metadata(&buf)
+                        .with_context(|| format!("Failed to parse OTA metadata: {path}"))?,
+                );
+                continue;
+            }
+            // This takes precedence due to sorted iteration order.
+            ota::PATH_METADATA_PB => {
+                let mut buf = vec![];
+                reader
+                    .read_to_end(&mut buf)
+                    .with_context(|| format!("Failed to read OTA metadata: {path}"))?;
+                metadata = Some(
+                    ota::parse_protobuf_metadata(&buf)
+                        .with_context(|| format!("Failed to parse OTA metadata: {path}"))?,
+                );
+                continue;
+            }
+            // This describes how the input was patched, not the output. A new
+            // one is added at the end if requested.
+            ota::PATH_PROVENANCE => {
+                status!("Dropping zip entry: {path}");
+                continue;
+            }
+            // If the input was already patched, this describes the stock OTA
+            // that it was produced from, which is still the rollback target.
+            ota::PATH_ORIGINAL_MANIFEST => {
+                status!("Dropping zip entry: {path}");
+
+                if keep_original_manifest {
+                    let mut buf = vec![];
+                    reader
+                        .read_to_end(&mut buf)
+                        .with_context(|| format!("Failed to read original manifest: {path}"))?;
+                    input_original_manifest =
+                        Some(ota::parse_original_manifest(&buf).with_context(|| {
+                            format!("Failed to parse original manifest: {path}")
+                        })?);
+                }
+
+                continue;
+            }
+            _ => {}
+        }
+
+        // All remaining entries are written immediately.
+        zip_writer
+            .start_file_with_extra_data(path, options)
+            .with_context(|| format!("Failed to begin new zip entry: {path}"))?;
+        let offset = zip_writer
+            .end_extra_data()
+            .with_context(|| format!("Failed to end new zip entry: {path}"))?;
+        let mut writer = CountingWriter::new(&mut zip_writer);
+
+        match path.as_str() {
+            ota::PATH_OTACERT => {
+                // Use the user's certificate
+                status!("Replacing zip entry: {path}");
+
+                crypto::write_pem_cert(&mut writer, cert_ota)
+                    .with_context(|| format!("Failed to write entry: {path}"))?;
+            }
+            ota::PATH_PAYLOAD => {
+                status!("Patching zip entry: {path}");
+
+                let temp_dir = sandbox::tempdir().context("Failed to create temp directory")?;
+
+                let (payload_file, payload_offset, payload_size) = match payload_hooks.decrypt_cmd {
+                    Some(cmd) => {
+                        let (file, size) = unwrap_payload(
+                            cmd,
+                            raw_reader,
+                            &payload_entry,
+                            temp_dir.path(),
+                            cancel_signal,
+                        )?;
+
+                        (OtaReader::File(file), 0, size)
+                    }
+                    None => (
+                        raw_reader.reopen()?,
+                        payload_entry.offset,
+                        payload_entry.size,
+                    ),
+                };
+
+                // The zip library doesn't provide us with a seekable reader, so
+                // we make our own from the underlying file.
+                let mut payload_reader =
+                    SectionReader::new(BufReader::new(payload_file), payload_offset, payload_size)?;
+
+                check_payload_magic(&mut payload_reader)?;
+                payload_reader.rewind()?;
+
+                if keep_original_manifest {
+                    original_header = Some(
+                        PayloadHeader::from_reader(&mut payload_reader)
+                            .with_context(|| format!("Failed to read payload header: {path}"))?,
+                    );
+                    payload_reader.rewind()?;
+                }
+
+                let mut patch = |writer: &mut dyn Write| {
+                    patch_ota_payload(
+                        &payload_reader,
+                        writer,
+                        external_images,
+                        source_images,
+                        partition_map,
+                        // There's only one payload in the OTA.
+                        mem::take(&mut extra_patchers),
+                        clear_vbmeta_flags,
+                        strict_vbmeta,
+                        vbmeta_root,
+                        transcode_ops,
+                        print_plan,
+                        key_avb,
+                        avb_algorithm,
+                        keys_ota,
+                        cert_ota,
+                        otacerts_targets,
+                        classifier,
+                        device_preset,
+                        compress_options,
+                        blob_cache,
+                        report.as_deref_mut(),
+                        cancel_signal,
+                    )
+                    .with_context(|| format!("Failed to patch payload: {path}"))
+                };
+
+                let (p, m) = match payload_hooks.encrypt_cmd {
+                    Some(cmd) => rewrap_payload(
+                        cmd,
+                        &mut patch,
+                        &mut writer,
+                        temp_dir.path(),
+                        cancel_signal,
+                    )?,
+                    None => patch(&mut writer)?,
+                };
+
+                properties = Some(p);
+                payload_metadata_size = Some(m);
+            }
+            ota::PATH_PROPERTIES => {
+                status!("Patching zip entry: {path}");
+
+                // payload.bin is guaranteed to be patched first.
+                writer
+                    .write_all(properties.as_ref().unwrap().as_bytes())
+                    .with_context(|| format!("Failed to write payload properties: {path}"))?;
+            }
+            _ => {
+                status!("Copying zip entry: {path}");
+
+                stream::copy(&mut reader, &mut writer, cancel_signal)
+                    .with_context(|| format!("Failed to copy zip entry: {path}"))?;
+            }
+        }
+
+        // Cannot fail.
+        let size = writer.stream_position()?;
+
+        entries.push(ZipEntry {
+            name: path.clone(),
+            offset,
+            size,
+        });
+
+        last_entry_used_zip64 = use_zip64;
+    }
+
+    if let Some(p) = provenance {
+        let path = ota::PATH_PROVENANCE;
+        let data = ota::serialize_provenance(p)
+            .with_context(|| format!("Failed to serialize provenance: {path}"))?;
+
+        entries.push(add_stored_entry(zip_writer, path, data.as_bytes())?);
+        last_entry_used_zip64 = false;
+    }
+
+    if keep_original_manifest {
+        let path = ota::PATH_ORIGINAL_MANIFEST;
+        let manifest = input_original_manifest.unwrap_or_else(|| {
+            ota::OriginalManifest::new(
+                metadata.as_ref().unwrap(),
+                original_header.as_ref().unwrap(),
+            )
+        });
+        let data = ota::serialize_original_manifest(&manifest)
+            .with_context(|| format!("Failed to serialize original manifest: {path}"))?;
+
+        entries.push(add_stored_entry(zip_writer, path, data.as_bytes())?);
+        last_entry_used_zip64 = false;
+    }
+
+    progress::stage("write_metadata");
+    status!("Generating new OTA metadata");
+
+    let data_descriptor_size = if last_entry_used_zip64 { 24 } else { 16 };
+    let metadata = ota::add_metadata(
+        &entries,
+        zip_writer,
+        // Offset where next entry would begin.
+        entries.last().map(|e| e.offset + e.size).unwrap() + data_descriptor_size,
+        &metadata.unwrap(),
+        payload_metadata_size.unwrap(),
+    )
+    .context("Failed to write new OTA metadata")?;
+
+    Ok((metadata, payload_metadata_size.unwrap()))
+}
+
+/// Add a new uncompressed zip entry that is generated by avbroot.
+fn add_stored_entry(
+    zip_writer: &mut ZipWriter<impl Write>,
+    path: &str,
+    data: &[u8],
+) -> Result<ZipEntry> {
+    status!("Adding zip entry: {path}");
+
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    zip_writer
+        .start_file_with_extra_data(path, options)
+        .with_context(|| format!("Failed to begin new zip entry: {path}"))?;
+    let offset = zip_writer
+        .end_extra_data()
+        .with_context(|| format!("Failed to end new zip entry: {path}"))?;
+    zip_writer
+        .write_all(data)
+        .with_context(|| format!("Failed to write entry: {path}"))?;
+
+    Ok(ZipEntry {
+        name: path.to_owned(),
+        offset,
+        size: data.len() as u64,
+    })
+}
+
+/// Parse `--output [<partition>=]<path>` values. A plain path is only allowed
+/// when a single partition is being extracted.
+fn parse_extract_outputs(
+    values: &[String],
+    images: &BTreeSet<String>,
+) -> Result<BTreeMap<String, PathBuf>> {
+    let mut outputs = BTreeMap::new();
+
+    for value in values {
+        let (name, path) = match value.split_once('=') {
+            Some((name, path)) if images.contains(name) => (name, path),
+            _ if images.len() == 1 => (images.first().unwrap().as_str(), value.as_str()),
+            _ => bail!("Output must be specified as <partition>=<path>: {value:?}"),
+        };
+
+        if outputs
+            .insert(name.to_owned(), PathBuf::from(path))
+            .is_some()
+        {
+            bail!("Output specified more than once for partition: {name}");
+        }
+    }
+
+    Ok(outputs)
+}
+
+/// Open an explicitly specified output path for a partition. Block devices are
+/// written in place and must be large enough to hold the partition. Other
+/// paths are created or truncated like regular output files.
+fn open_extract_output(path: &Path, partition_size: u64, discard: bool) -> Result<PSeekFile> {
+    let file = sandbox::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+
+    if blockdev::is_block_device(&file)
+        .with_context(|| format!("Failed to get file type: {path:?}"))?
+    {
+        let device_size = blockdev::size(&file)
+            .with_context(|| format!("Failed to get block device size: {path:?}"))?;
+        if partition_size > device_size {
+            bail!("Partition size ({partition_size}) exceeds block device size ({device_size}): {path:?}");
+        }
+
+        if discard {
+            status!("Discarding {partition_size} bytes: {path:?}");
+
+            blockdev::discard(&file, partition_size)
+                .with_context(|| format!("Failed to discard block device: {path:?}"))?;
+        }
+    } else {
+        if discard {
+            warning!("Not a block device, ignoring --discard: {path:?}");
+        }
+
+        file.set_len(0)
+            .with_context(|| format!("Failed to truncate file: {path:?}"))?;
+    }
+
+    Ok(PSeekFile::new(file))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn extract_ota_zip(
+    raw_reader: &(impl Read + Seek + Reopen + Sync + 'static),
+    directory: &Dir,
+    payload_offset: u64,
+    payload_size: u64,
+    header: &PayloadHeader,
+    images: &BTreeSet<String>,
+    outputs: &BTreeMap<String, PathBuf>,
+    discard: bool,
+    sparse: bool,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    for name in images {
+        if Path::new(name).file_name() != Some(OsStr::new(name)) {
+            bail!("Unsafe partition name: {name}");
+        }
+    }
+
+    status!("Extracting from the payload: {}", joined(images));
+
+    // Pre-open all output files.
+    let output_files = images
+        .iter()
+        .map(|name| {
+            let partition_size = header
+                .manifest
+                .partitions
+                .iter()
+                .find(|p| &p.partition_name == name)
+                .and_then(|p| p.new_partition_info.as_ref()?.size)
+                .ok_or_else(|| anyhow!("Partition size not found: {name}"))?;
+
+            let file = if let Some(path) = outputs.get(name) {
+                open_extract_output(path, partition_size, discard)?
+            } else {
+                let path = format!("{name}.img");
+                let file = directory
+                    .create(&path)
+                    .map(|f| PSeekFile::new(f.into_std()))
+                    .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+
+                // All-zero blocks are skipped when writing sparse files, so the
+                // file needs to have the full size from the start.
+                if sparse {
+                    file.set_len(partition_size)
+                        .with_context(|| format!("Failed to set file size: {path:?}"))?;
+                }
+
+                file
+            };
+            Ok((name.as_str(), file))
+        })
+        .collect::<Result<HashMap<_, _>>>()?;
+
+    let payload_reader = SectionReader::new(
+        BufReader::new(raw_reader.reopen()?),
+        payload_offset,
+        payload_size,
+    )?;
+
+    let tracker = partition_tracker("extract_images", header, images.iter().map(|n| n.as_str()));
+
+    // Extract the images. Each time we're asked to open a new file, we just
+    // clone the relevant PSeekFile. We only ever have one actual kernel file
+    // descriptor for each file.
+    payload::extract_images(
+        &payload_reader,
+        |name| {
+            let file = output_files[name].reopen()?;
+
+            if sparse && !outputs.contains_key(name) {
+                let writer = BufWriter::new(HolePunchingWriter::new(file));
+                Ok(Box::new(tracker.writer(name, writer)))
+            } else {
+                Ok(Box::new(tracker.writer(name, BufWriter::new(file))))
+            }
+        },
+        header,
+        images.iter().map(|n| n.as_str()),
+        &args::payload_apply_options(),
+        cancel_signal,
+    )
+    .context("Failed to extract images from payload")?;
+
+    tracker.finish();
+
+    // Make sure the data has reached the device before a flashing workflow
+    // continues with a reboot.
+    for (name, path) in outputs {
+        output_files[name.as_str()]
+            .sync_all()
+            .with_context(|| format!("Failed to sync writes: {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+/// Get the sha256 digest of a partition image from the payload header.
+fn expected_partition_hash<'a>(header: &'a PayloadHeader, name: &str) -> Result<&'a [u8]> {
+    let partition = header
+        .manifest
+        .partitions
+        .iter()
+        .find(|p| p.partition_name == name)
+        .ok_or_else(|| anyhow!("Partition not found in header: {name}"))?;
+
+    partition
+        .new_partition_info
+        .as_ref()
+        .and_then(|info| info.hash.as_deref())
+        .ok_or_else(|| anyhow!("Hash not found for partition: {name}"))
+}
+
+/// Compute the sha256 digest of an extracted image and make sure it matches
+/// the expected digest. Returns the hex-encoded digest.
+fn verify_image_hash(
+    directory: &Dir,
+    name: &str,
+    expected_digest: &[u8],
+    tracker: &Tracker,
+    cancel_signal: &AtomicBool,
+) -> Result<String> {
+    let path = format!("{name}.img");
+    let file = directory
+        .open(&path)
+        .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+
+    let mut writer = tracker.writer(
+        name,
+        HashingWriter::new(
+            io::sink(),
+            crate::digest::Context::new(&crate::digest::SHA256),
+        ),
+    );
+
+    stream::copy(file, &mut writer, cancel_signal)?;
+
+    let digest = writer.into_inner().finish().1.finish();
+
+    if digest.as_ref() != expected_digest {
+        bail!(
+            "Expected sha256 {}, but have {} for partition {name}",
+            hex::encode(expected_digest),
+            hex::encode(digest),
+        );
+    }
+
+    Ok(hex::encode(digest))
+}
+
+/// Extract the images to `directory` and verify their sha256 digests against
+/// the payload. The images are extracted one at a time, with the operations of
+/// each image applied in parallel, and each image is hashed on a separate
+/// thread while the next one is being extracted. Unlike [`extract_ota_zip()`],
+/// operations from different images are not applied at the same time. If
+/// hashing fails, extraction stops at the next operation.
+fn extract_and_verify_images(
+    raw_reader: &(impl Read + Seek + Reopen + Sync + 'static),
+    directory: &Dir,
+    payload_offset: u64,
+    payload_size: u64,
+    header: &PayloadHeader,
+    images: &BTreeSet<String>,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    for name in images {
+        if Path::new(name).file_name() != Some(OsStr::new(name)) {
+            bail!("Unsafe partition name: {name}");
+        }
+    }
+
+    let payload_reader = SectionReader::new(
+        BufReader::new(raw_reader.reopen()?),
+        payload_offset,
+        payload_size,
+    )?;
+
+    // Each image counts twice towards the progress: once for writing it and
+    // once for reading it back.
+    let sizes = images
+        .iter()
+        .map(|name| {
+            let size = header
+                .manifest
+                .partitions
+                .iter()
+                .find(|p| &p.partition_name == name)
+                .and_then(|p| p.new_partition_info.as_ref()?.size)
+                .unwrap_or(0);
+
+            (name.as_str(), size * 2)
+        })
+        .collect::<Vec<_>>();
+    let tracker = Tracker::with_partitions("extract_verify_images", sizes);
+
+    let (sender, receiver) = mpsc::channel::<&str>();
+    let hash_failed = AtomicBool::new(false);
+
+    thread::scope(|s| {
+        let hash_tracker = tracker.clone();
+        let hash_failed = &hash_failed;
+        let hasher = s.spawn(move || -> Result<()> {
+            let result = receiver.into_iter().try_for_each(|name| {
+                let expected_digest = expected_partition_hash(header, name)?;
+                verify_image_hash(
+                    directory,
+                    name,
+                    expected_digest,
+                    &hash_tracker,
+                    cancel_signal,
+                )
+                .map(|_| ())
+            });
+
+            if result.is_err() {
+                hash_failed.store(true, Ordering::SeqCst);
+            }
+
+            result
+        });
+
+        let extracted = images.iter().try_for_each(|name| -> Result<()> {
+            let path = format!("{name}.img");
+            let file = directory
+                .create(&path)
+                .map(|f| PSeekFile::new(f.into_std()))
+                .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+
+            payload::extract_images(
+                &payload_reader,
+                |_| {
+                    // This is called for every operation.
+                    if hash_failed.load(Ordering::SeqCst) {
+                        return Err(io::Error::other("Stopped because hashing failed"));
+                    }
+
+                    Ok(Box::new(
+                        tracker.writer(name, BufWriter::new(file.reopen()?)),
+                    ))
+                },
+                header,
+                [name.as_str()],
+                &args::payload_apply_options(),
+                cancel_signal,
+            )
+            .with_context(|| format!("Failed to extract image: {name}"))?;
+
+            // This only fails if the hashing thread already failed, which is
+            // reported below.
+            sender
+                .send(name.as_str())
+                .map_err(|_| anyhow!("Stopped because hashing failed"))
+        });
+        drop(sender);
+
+        let hashed = match hasher.join() {
+            Ok(r) => r,
+            Err(e) => panic::resume_unwind(e),
+        };
+
+        // Hashing errors take precedence because extraction is stopped when
+        // hashing fails. Only fully extracted images are hashed, so a hashing
+        // error is never caused by an extraction error.
+        hashed.and(extracted)
+    })?;
+
+    tracker.finish();
+
+    Ok(())
+}
+
+/// Verify the sha256 digests of the extracted images against the payload. If a
+/// digests manifest is provided, images with a matching cached digest are not
+/// read again and newly computed digests are recorded.
+fn verify_partition_hashes(
+    directory: &Dir,
+    header: &PayloadHeader,
+    images: &BTreeSet<String>,
+    manifest: Option<&mut DigestsManifest>,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let cached = manifest.as_deref();
+    let tracker = partition_tracker(
+        "verify_partition_hashes",
+        header,
+        images.iter().map(|n| n.as_str()),
+    );
+
+    let computed = images
+        .par_iter()
+        .map(|name| -> Result<Option<(&str, FileStamp, String)>> {
+            let expected_digest = expected_partition_hash(header, name)?;
+
+            // Stat the file before reading so that modifications made while
+            // hashing invalidate the entry.
+            let stamp = match cached {
+                Some(_) => FileStamp::for_image(directory, name)?,
+                None => None,
+            };
+
+            if let (Some(m), Some(s)) = (cached, &stamp) {
+                let digest = m.get(name, s).and_then(|p| p.sha256.as_deref());
+                if digest == Some(hex::encode(expected_digest).as_str()) {
+                    return Ok(None);
+                }
+            }
+
+            let digest =
+                verify_image_hash(directory, name, expected_digest, &tracker, cancel_signal)?;
+
+            Ok(stamp.map(|s| (name.as_str(), s, digest)))
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    tracker.finish();
+
+    if let Some(m) = manifest {
+        for (name, stamp, digest) in computed.into_iter().flatten() {
+            m.entry(name, stamp).sha256 = Some(digest);
+        }
+    }
+
+    Ok(())
+}
+
+/// Read the OTA metadata, preferring the protobuf format over the legacy
+/// format. Returns [`None`] if the OTA has no metadata.
+pub fn read_ota_metadata(
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+) -> Result<Option<OtaMetadata>> {
+    let has_entry = |zip: &ZipArchive<_>, path| zip.file_names().any(|n| n == path);
+
+    // The protobuf metadata takes precedence, like in patch_ota_zip().
+    let metadata = if has_entry(zip_reader, ota::PATH_METADATA_PB) {
+        let mut reader = zip_reader.by_name(ota::PATH_METADATA_PB)?;
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf)?;
+
+        ota::parse_protobuf_metadata(&buf)?
+    } else if has_entry(zip_reader, ota::PATH_METADATA) {
+        let mut reader = zip_reader.by_name(ota::PATH_METADATA)?;
+        let mut buf = String::new();
+        reader.read_to_string(&mut buf)?;
+
+        ota::parse_legacy_metadata(&buf)?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(metadata))
+}
+
+/// Like [`read_ota_metadata`], but for a payload directory accepted by
+/// `ota patch`.
+fn read_payload_dir_metadata(dir: &Path) -> Result<Option<OtaMetadata>> {
+    let metadata = if let Some(data) = read_payload_dir_file(dir, "metadata.pb")? {
+        ota::parse_protobuf_metadata(&data)?
+    } else if let Some(data) = read_payload_dir_file(dir, "metadata")? {
+        let data = String::from_utf8(data).context("Legacy metadata is not valid UTF-8")?;
+        ota::parse_legacy_metadata(&data)?
+    } else {
+        return Ok(None);
+    };
+
+    Ok(Some(metadata))
+}
+
+/// Get the Android SDK level that the OTA updates the device to, if the OTA
+/// metadata specifies it.
+fn read_ota_sdk_level(zip_reader: &mut ZipArchive<impl Read + Seek>) -> Result<Option<u32>> {
+    Ok(read_ota_metadata(zip_reader)?
+        .and_then(|m| m.postcondition)
+        .and_then(|p| p.sdk_level.parse().ok()))
+}
+
+/// Load a signing key. With detached signing or a signer command, the file
+/// must contain a public key so that the private key never needs to be present
+/// on this machine. A PEM-encoded public key, a certificate, or an AVB-encoded
+/// public key is accepted.
+pub fn load_signing_key(
+    path: &Path,
+    source: &PassphraseSource,
+    detached: bool,
+    signer_cmd: Option<&Path>,
+) -> Result<RsaSigningKey> {
+    if !detached && signer_cmd.is_none() {
+        return crypto::read_signing_key(path, source)
+            .with_context(|| format!("Failed to load key: {path:?}"));
+    }
+
+    if let Some(program) = signer_cmd.filter(|_| harden::is_applied()) {
+        bail!("--signer-cmd cannot be used with --harden because it runs a program: {program:?}");
+    }
+
+    let data = sandbox::read(path).with_context(|| format!("Failed to read file: {path:?}"))?;
+
+    let public_key = if data.starts_with(b"-----BEGIN PUBLIC KEY-----") {
+        crypto::read_pem_public_key(data.as_slice())
+            .with_context(|| format!("Failed to load public key: {path:?}"))?
+    } else if data.starts_with(b"-----BEGIN CERTIFICATE-----") {
+        crypto::read_pem_cert(data.as_slice())
+            .and_then(|c| crypto::get_public_key(&c))
+            .with_context(|| format!("Failed to load certificate: {path:?}"))?
+    } else if data.starts_with(b"-----BEGIN") {
+        bail!("External signing requires a public key, not a private key: {path:?}");
+    } else {
+        avb::decode_public_key(&data)
+            .with_context(|| format!("Failed to load AVB public key: {path:?}"))?
+    };
+
+    match signer_cmd {
+        Some(program) => Ok(RsaSigningKey::Command {
+            program: program.to_owned(),
+            public_key,
+        }),
+        None => Ok(RsaSigningKey::Detached(public_key)),
+    }
+}
+
+fn init_detached_signing(paths: &[PathBuf]) -> Result<()> {
+    let mut signatures = vec![];
+
+    for path in paths {
+        let data = sandbox::read(path).with_context(|| format!("Failed to read file: {path:?}"))?;
+        let file: DetachedSignatures = serde_json::from_slice(&data)
+            .with_context(|| format!("Failed to parse signatures: {path:?}"))?;
+
+        signatures.extend(file.signatures);
+    }
+
+    detached::init(signatures).context("Failed to enable detached signing")
+}
+
+/// Write the outstanding signing requests if any signatures are missing.
+/// Returns true if the output is incomplete and should not be written.
+fn finish_detached_signing(cli: &PatchCli) -> Result<bool> {
+    let requests = detached::pending_requests();
+
+    let Some(path) = &cli.emit_signing_requests else {
+        if requests.is_some() {
+            bail!("Signatures are missing. Pass in --emit-signing-requests to list them");
+        }
+
+        return Ok(false);
+    };
+
+    // Always write the file so that stale requests are not signed again.
+    let file = SigningRequests {
+        requests: requests.clone().unwrap_or_default(),
+    };
+    let data = serde_json::to_vec_pretty(&file)?;
+    sandbox::write(path, data)
+        .with_context(|| format!("Failed to write signing requests: {path:?}"))?;
+
+    let Some(requests) = requests else {
+        return Ok(false);
+    };
+
+    for request in &requests {
+        status!(
+            "Requesting {} signature: {}",
+            request.purpose,
+            hex::encode(&request.digest),
+        );
+    }
+
+    status!(
+        "Wrote {} signing requests to {path:?}. Sign them with `avbroot key sign-requests` \
+        and run this command again with the signatures added to --apply-signatures",
+        requests.len(),
+    );
+
+    Ok(true)
+}
+
+/// Check that the AVB key is not also used as an OTA key. Since the OTA keys
+/// must match their certificates, this also covers the OTA certificates.
+fn check_key_separation(
+    cli: &PatchCli,
+    key_avb: &RsaSigningKey,
+    key_ota: &RsaSigningKey,
+    key_ota_secondary: Option<&RsaSigningKey>,
+) -> Result<()> {
+    let public_key_avb = key_avb.to_public_key();
+    let mut reused = vec![];
+
+    if key_ota.to_public_key() == public_key_avb {
+        reused.push("--key-ota");
+    }
+    if key_ota_secondary.is_some_and(|k| k.to_public_key() == public_key_avb) {
+        reused.push("--key-ota-secondary");
+    }
+
+    if reused.is_empty() {
+        return Ok(());
+    }
+
+    let message = format!(
+        "The same key is used for --key-avb and {}",
+        reused.join(" and "),
+    );
+
+    if cli.forbid_key_reuse {
+        bail!("{message}");
+    }
+
+    warning!("{message}. Using separate keys is strongly recommended.");
+
+    Ok(())
+}
+
+/// Load the preset for a device from the built-in database, extended by the
+/// user-specified database.
+fn load_device_preset(codename: &str, db_path: Option<&Path>) -> Result<DevicePreset> {
+    let mut db = DeviceDb::builtin();
+
+    if let Some(path) = db_path {
+        let data = sandbox::read_to_string(path)
+            .with_context(|| format!("Failed to read file: {path:?}"))?;
+        let custom = DeviceDb::from_toml(&data)
+            .with_context(|| format!("Failed to load device database: {path:?}"))?;
+
+        db.extend(custom);
+    }
+
+    db.find(codename)
+        .cloned()
+        .ok_or_else(|| anyhow!("Device not found in device database: {codename}"))
+}
+
+/// Fill in patch options from a device preset. Options that were explicitly
+/// specified on the command line take precedence.
+fn apply_device_preset(cli: &PatchCli, codename: &str, preset: &DevicePreset) -> PatchCli {
+    let mut cli = cli.clone();
+
+    match &preset.name {
+        Some(name) => status!("Applying device preset: {codename} ({name})"),
+        None => status!("Applying device preset: {codename}"),
+    }
+
+    if let Some(note) = &preset.note {
+        warning!("{codename}: {note}");
+    }
+
+    if cli.root.magisk.is_some() && cli.magisk_preinit_device.is_none() {
+        if let Some(device) = &preset.magisk_preinit_device {
+            status!("- Magisk preinit device: {device}");
+            cli.magisk_preinit_device = Some(device.clone());
+        }
+    }
+
+    if cli.otacerts_target.is_empty() && !preset.otacerts_target.is_empty() {
+        status!("- otacerts targets: {}", joined(&preset.otacerts_target));
+        cli.otacerts_target.clone_from(&preset.otacerts_target);
+    }
+
+    if preset.clear_vbmeta_flags && !cli.clear_vbmeta_flags {
+        status!("- Clearing vbmeta flags");
+        cli.clear_vbmeta_flags = true;
+    }
+
+    cli
+}
+
+/// Ensure that the OTA is meant for the device selected with `--device`.
+fn check_ota_device(zip_reader: &mut ZipArchive<impl Read + Seek>, codename: &str) -> Result<()> {
+    let devices = read_ota_metadata(zip_reader)?
+        .and_then(|m| m.precondition)
+        .map(|p| p.device)
+        .unwrap_or_default();
+
+    if devices.is_empty() {
+        warning!("OTA metadata does not list any devices; cannot validate device preset");
+    } else if !devices.iter().any(|d| d == codename) {
+        bail!(
+            "OTA is for {}, not the selected device: {codename}",
+            joined(&devices),
+        );
+    }
+
+    Ok(())
+}
+
+/// Check if a security patch level is in the `YYYY-MM-DD` format, which allows
+/// them to be compared as strings.
+fn is_valid_spl(spl: &str) -> bool {
+    spl.len() == 10
+        && spl.bytes().enumerate().all(|(i, b)| match i {
+            4 | 7 => b == b'-',
+            _ => b.is_ascii_digit(),
+        })
+}
+
+/// Make sure that the OTA does not have an older security patch level than the
+/// build installed on the device. Otherwise, the device's rollback protection
+/// would refuse to boot it and may require a data wipe to recover.
+fn check_spl_downgrade(
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+    current_spl: &str,
+) -> Result<()> {
+    if !is_valid_spl(current_spl) {
+        bail!("Invalid security patch level (expected YYYY-MM-DD): {current_spl:?}");
+    }
+
+    let ota_spl = read_ota_metadata(zip_reader)?
+        .and_then(|m| m.postcondition)
+        .map(|p| p.security_patch_level)
+        .unwrap_or_default();
+
+    if ota_spl.is_empty() {
+        bail!("OTA metadata does not list a security patch level; cannot check for downgrade");
+    } else if !is_valid_spl(&ota_spl) {
+        bail!("OTA metadata has an invalid security patch level: {ota_spl:?}");
+    } else if ota_spl.as_str() < current_spl {
+        bail!(
+            "OTA security patch level ({ota_spl}) is older than the installed build's \
+            ({current_spl}); rollback protection would prevent it from booting",
+        );
+    }
+
+    status!("OTA security patch level ({ota_spl}) is not older than {current_spl}");
+
+    Ok(())
+}
+
+/// Find signs that the input OTA was already patched by avbroot. Stacking
+/// patches on top of an already-patched OTA is a common mistake that usually
+/// results in a boot loop. Returns a list of human-readable descriptions of
+/// the signs that were found.
+fn find_patched_input_signs(
+    zip_reader: &mut ZipArchive<impl Read + Seek>,
+    cert_ota: &Certificate,
+) -> Vec<String> {
+    let mut signs = vec![];
+
+    if let Ok(mut reader) = zip_reader.by_name(ota::PATH_PROVENANCE) {
+        let mut buf = vec![];
+        let provenance = reader
+            .read_to_end(&mut buf)
+            .ok()
+            .and_then(|_| ota::parse_provenance(&buf).ok());
+
+        match provenance {
+            Some(p) => signs.push(format!(
+                "{} entry is from avbroot {}",
+                ota::PATH_PROVENANCE,
+                p.avbroot_version,
+            )),
+            None => signs.push(format!("{} entry exists", ota::PATH_PROVENANCE)),
+        }
+    }
+
+    if zip_reader.by_name(ota::PATH_ORIGINAL_MANIFEST).is_ok() {
+        signs.push(format!("{} entry exists", ota::PATH_ORIGINAL_MANIFEST));
+    }
+
+    if let Ok(reader) = zip_reader.by_name(ota::PATH_OTACERT) {
+        if crypto::read_pem_cert(reader).is_ok_and(|c| &c == cert_ota) {
+            signs.push(format!(
+                "{} already matches the OTA signing certificate",
+                ota::PATH_OTACERT,
+            ));
+        }
+    }
+
+    signs
+}
+
+/// Get the patch options to record in the provenance entry. Paths are reduced
+/// to their file names to avoid leaking details about the user's system.
+fn provenance_options(cli: &PatchCli) -> BTreeMap<String, String> {
+    let file_name = |p: &Path| {
+        p.file_name()
+            .unwrap_or(p.as_os_str())
+            .to_string_lossy()
+            .into_owned()
+    };
+    let mut options = BTreeMap::new();
+
+    if let Some(magisk) = &cli.root.magisk {
+        options.insert("magisk".to_owned(), file_name(magisk));
+
+        if let Some(device) = &cli.magisk_preinit_device {
+            options.insert("magisk_preinit_device".to_owned(), device.clone());
+        }
+
+        if let Some(target) = &cli.magisk_target {
+            options.insert("magisk_target".to_owned(), target.clone());
+        }
+
+        if let Some(seed) = cli.magisk_random_seed {
+            options.insert("magisk_random_seed".to_owned(), seed.to_string());
+        }
+
+        if let Some(db) = &cli.magisk_compat_db {
+            options.insert("magisk_compat_db".to_owned(), file_name(db));
+        }
+    } else if let Some(kernelsu) = &cli.root.kernelsu {
+        options.insert("kernelsu".to_owned(), file_name(kernelsu));
+
+        if let Some(init) = &cli.kernelsu_init {
+            options.insert("kernelsu_init".to_owned(), file_name(init));
+        }
+
+        if cli.ignore_kernelsu_kmi {
+            options.insert("ignore_kernelsu_kmi".to_owned(), true.to_string());
+        }
+    } else if let Some(prepatched) = &cli.root.prepatched {
+        options.insert("prepatched".to_owned(), file_name(prepatched));
+    } else {
+        options.insert("rootless".to_owned(), true.to_string());
+    }
+
+    if cli.revert {
+        options.insert("revert".to_owned(), true.to_string());
+    }
+
+    for item in cli.replace.chunks_exact(2) {
+        options.insert(
+            format!("replace.{}", item[0].to_string_lossy()),
+            file_name(Path::new(&item[1])),
+        );
+    }
+
+    for item in cli.source_image.chunks_exact(2) {
+        options.insert(
+            format!("source_image.{}", item[0].to_string_lossy()),
+            file_name(Path::new(&item[1])),
+        );
+    }
+
+    if let Some(gsi) = &cli.gsi {
+        options.insert("gsi".to_owned(), file_name(gsi));
+    }
+
+    for item in &cli.map_partition {
+        if let Some((old_name, new_name)) = item.split_once('=') {
+            options.insert(format!("map_partition.{old_name}"), new_name.to_owned());
+        }
+    }
+
+    if let Some(device) = &cli.device {
+        options.insert("device".to_owned(), device.clone());
+    }
+
+    if let Some(db) = &cli.device_db {
+        options.insert("device_db".to_owned(), file_name(db));
+    }
+
+    if let Some(program) = &cli.custom_boot_patcher {
+        options.insert("custom_boot_patcher".to_owned(), file_name(program));
+    }
+
+    if !cli.custom_boot_patcher_target.is_empty() {
+        options.insert(
+            "custom_boot_patcher_target".to_owned(),
+            cli.custom_boot_patcher_target.join(","),
+        );
+    }
+
+    if !cli.otacerts_target.is_empty() {
+        options.insert("otacerts_target".to_owned(), cli.otacerts_target.join(","));
+    }
+
+    if !cli.classify.is_empty() {
+        options.insert("classify".to_owned(), cli.classify.join(","));
+    }
+
+    if cli.clear_vbmeta_flags {
+        options.insert("clear_vbmeta_flags".to_owned(), true.to_string());
+    }
+
+    if let Some(root) = &cli.vbmeta_root {
+        options.insert("vbmeta_root".to_owned(), root.clone());
+
+        if cli.skip_other_vbmeta_roots {
+            options.insert("skip_other_vbmeta_roots".to_owned(), true.to_string());
+        }
+    }
+
+    if cli.strict_vbmeta {
+        options.insert("strict_vbmeta".to_owned(), true.to_string());
+    }
+
+    if let Some(algorithm) = cli.avb_algorithm {
+        let value = algorithm.to_possible_value().unwrap();
+        options.insert("avb_algorithm".to_owned(), value.get_name().to_owned());
+    }
+
+    if cli.zero_chunks {
+        options.insert("zero_chunks".to_owned(), true.to_string());
+    }
+
+    if let Some(threshold) = cli.compression_skip_entropy {
+        options.insert("compression_skip_entropy".to_owned(), threshold.to_string());
+    }
+
+    if cli.xz_level != 0 {
+        options.insert("xz_level".to_owned(), cli.xz_level.to_string());
+    }
+
+    if cli.xz_extreme {
+        options.insert("xz_extreme".to_owned(), true.to_string());
+    }
+
+    if cli.xz_check != XzCheckArg::Auto {
+        let value = cli.xz_check.to_possible_value().unwrap();
+        options.insert("xz_check".to_owned(), value.get_name().to_owned());
+    }
+
+    if let Some(size) = cli.xz_dict_size {
+        options.insert("xz_dict_size".to_owned(), size.to_string());
+    }
+
+    if cli.transcode_ops {
+        options.insert("transcode_ops".to_owned(), true.to_string());
+    }
+
+    if let Some(path) = &cli.zip_comment_file {
+        options.insert("zip_comment_file".to_owned(), file_name(path));
+    } else if cli.zip_comment != ZipCommentArg::Default {
+        let value = cli.zip_comment.to_possible_value().unwrap();
+        options.insert("zip_comment".to_owned(), value.get_name().to_owned());
+    }
+
+    if let Some(path) = &cli.signing_block_file {
+        options.insert("signing_block_file".to_owned(), file_name(path));
+    } else if cli.signing_block != SigningBlockArg::Strip {
+        let value = cli.signing_block.to_possible_value().unwrap();
+        options.insert("signing_block".to_owned(), value.get_name().to_owned());
+    }
+
+    if cli.keep_original_manifest {
+        options.insert("keep_original_manifest".to_owned(), true.to_string());
+    }
+
+    if let Some(cmd) = &cli.payload_decrypt_cmd {
+        options.insert("payload_decrypt_cmd".to_owned(), file_name(cmd));
+    }
+
+    if let Some(cmd) = &cli.payload_encrypt_cmd {
+        options.insert("payload_encrypt_cmd".to_owned(), file_name(cmd));
+    }
+
+    options
+}
+
+/// Compute the sha256 digests of the files referenced by the patch options.
+/// Unlike the options themselves, this detects changes to the file contents.
+fn provenance_input_digests(
+    cli: &PatchCli,
+    cancel_signal: &AtomicBool,
+) -> Result<BTreeMap<String, String>> {
+    let mut paths = BTreeMap::new();
+
+    paths.insert("cert_ota".to_owned(), cli.cert_ota.clone());
+
+    for (name, path) in [
+        ("cert_ota_secondary", &cli.cert_ota_secondary),
+        ("magisk", &cli.root.magisk),
+        ("kernelsu", &cli.root.kernelsu),
+        ("kernelsu_init", &cli.kernelsu_init),
+        ("prepatched", &cli.root.prepatched),
+        ("custom_boot_patcher", &cli.custom_boot_patcher),
+        ("magisk_compat_db", &cli.magisk_compat_db),
+        ("gsi", &cli.gsi),
+        ("device_db", &cli.device_db),
+        ("zip_comment_file", &cli.zip_comment_file),
+        ("signing_block_file", &cli.signing_block_file),
+    ] {
+        if let Some(path) = path {
+            paths.insert(name.to_owned(), path.clone());
+        }
+    }
+
+    for item in cli.replace.chunks_exact(2) {
+        let path = match ExternalImage::parse(&item[1]) {
+            ExternalImage::File(p) | ExternalImage::Gsi(p) | ExternalImage::Ota { path: p, .. } => {
+                p
+            }
+        };
+        paths.insert(format!("replace.{}", item[0].to_string_lossy()), path);
+    }
+
+    for item in cli.source_image.chunks_exact(2) {
+        paths.insert(
+            format!("source_image.{}", item[0].to_string_lossy()),
+            PathBuf::from(&item[1]),
+        );
+    }
+
+    paths
+        .into_iter()
+        .map(|(name, path)| {
+            let reader = sandbox::open(&path)
+                .map(BufReader::new)
+                .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+            let mut writer = HashingWriter::new(
+                io::sink(),
+                crate::digest::Context::new(&crate::digest::SHA256),
+            );
+
+            stream::copy(reader, &mut writer, cancel_signal)
+                .with_context(|| format!("Failed to compute digest: {path:?}"))?;
+
+            Ok((name, hex::encode(writer.finish().1.finish())))
+        })
+        .collect()
+}
+
+/// Check if `output` was already produced from the same input with the same
+/// options and keys, according to its provenance entry, and that it still has
+/// a valid whole-file signature from `cert_ota`. `allow_multiple_signers`
+/// should be true if the output was signed with a secondary key too.
+fn is_output_up_to_date(
+    output: &Path,
+    provenance: &Provenance,
+    cert_ota: &Certificate,
+    allow_multiple_signers: bool,
+    cancel_signal: &AtomicBool,
+) -> Result<bool> {
+    let raw_reader = match sandbox::open(output) {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(e).with_context(|| format!("Failed to open for reading: {output:?}")),
+    };
+    let mut reader = BufReader::new(raw_reader);
+
+    let existing = {
+        let mut zip_reader = ZipArchive::new(&mut reader)
+            .with_context(|| format!("Failed to read zip: {output:?}"))?;
+        let Ok(mut entry) = zip_reader.by_name(ota::PATH_PROVENANCE) else {
+            return Ok(false);
+        };
+        let mut buf = vec![];
+        entry
+            .read_to_end(&mut buf)
+            .with_context(|| format!("Failed to read zip entry: {}", ota::PATH_PROVENANCE))?;
+
+        ota::parse_provenance(&buf).ok()
+    };
+
+    if existing.as_ref() != Some(provenance) {
+        return Ok(false);
+    }
+
+    status!("Verifying existing output: {output:?}");
+
+    reader.rewind()?;
+    let certs = ota::verify_ota(&mut reader, allow_multiple_signers, cancel_signal)
+        .with_context(|| format!("Failed to verify existing output: {output:?}"))?;
+
+    Ok(certs.contains(cert_ota))
+}
+
+/// Expand `--output-template`. The result is relative to the input OTA's
+/// directory.
+fn expand_output_template(cli: &PatchCli, template: &str, input_name: &str) -> Result<PathBuf> {
+    let metadata = if sandbox::is_dir(&cli.input) {
+        read_payload_dir_metadata(&cli.input)
+    } else {
+        let raw_reader = OtaReader::open(&cli.input)
+            .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+        let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader))
+            .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+        read_ota_metadata(&mut zip_reader)
+    };
+    let postcondition = metadata
+        .with_context(|| format!("Failed to read OTA metadata: {:?}", cli.input))?
+        .and_then(|m| m.postcondition)
+        .unwrap_or_default();
+
+    let magisk_version = cli
+        .root
+        .magisk
+        .as_deref()
+        .map(|p| {
+            MagiskRootPatcher::get_version(p)
+                .with_context(|| format!("Failed to get Magisk version: {p:?}"))
+        })
+        .transpose()?;
+    let root = if cli.root.magisk.is_some() {
+        "magisk"
+    } else if cli.root.kernelsu.is_some() {
+        "kernelsu"
+    } else if cli.root.prepatched.is_some() {
+        "prepatched"
+    } else {
+        "rootless"
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+
+    let expanded = util::expand_template(template, |name| {
+        let value = match name {
+            "name" => input_name.to_owned(),
+            "stem" => input_name
+                .strip_suffix(".zip")
+                .unwrap_or(input_name)
+                .to_owned(),
+            "device" => postcondition.device.join("+"),
+            "fingerprint" => postcondition.build.first().cloned().unwrap_or_default(),
+            "incremental" => postcondition.build_incremental.clone(),
+            "spl" => postcondition.security_patch_level.clone(),
+            "sdk" => postcondition.sdk_level.clone(),
+            "date" => util::format_utc_timestamp(now)[..8].to_owned(),
+            "root" => root.to_owned(),
+            "magisk_version" => magisk_version.map(|v| v.to_string()).unwrap_or_default(),
+            _ => return None,
+        };
+
+        // Values must not be able to introduce path components. Fingerprints
+        // contain colons, which are not allowed in Windows file names.
+        Some(value.replace(['/', '\\', ':', '\0'], "_"))
+    })
+    .with_context(|| format!("Invalid output template: {template:?}"))?;
+
+    if expanded.is_empty() {
+        bail!("Output template expands to an empty path: {template:?}");
+    }
+
+    // The output must stay within the input OTA's directory.
+    let is_special = |c: &str| c == "." || c == "..";
+    if Path::new(&expanded).has_root() || expanded.split(['/', '\\']).any(is_special) {
+        bail!("Output template expands to a path outside of the input directory: {expanded:?}");
+    }
+
+    let parent = match cli.input.to_str().filter(|p| is_url(p)) {
+        Some(_) => Path::new(""),
+        None => util::parent_path(&cli.input),
+    };
+
+    Ok(parent.join(expanded))
+}
+
+/// Output path for `ota patch`, which defaults to the input path with a
+/// `.patched` suffix. For URL inputs, the file name from the URL is used
+/// relative to the current directory. For payload directory inputs, the
+/// output is a sibling of the directory with a `.patched.zip` suffix.
+fn patch_output_path(cli: &PatchCli) -> Result<PathBuf> {
+    if let Some(output) = &cli.output {
+        return Ok(output.clone());
+    }
+
+    let is_dir = sandbox::is_dir(&cli.input);
+    let input_name = match cli.input.to_str().filter(|p| is_url(p)) {
+        Some(url) => OsString::from(url_file_name(url).unwrap_or_else(|| "ota.zip".to_owned())),
+        // Strip trailing slashes so that the output isn't placed inside the
+        // directory.
+        None if is_dir => cli.input.components().as_path().as_os_str().to_owned(),
+        None => cli.input.clone().into_os_string(),
+    };
+
+    if let Some(template) = &cli.output_template {
+        let input_name = Path::new(&input_name)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy();
+
+        return expand_output_template(cli, template, &input_name);
+    }
+
+    let mut s = input_name;
+    s.push(if is_dir { ".patched.zip" } else { ".patched" });
+    Ok(PathBuf::from(s))
+}
+
+/// Read an optional file from a payload directory.
+fn read_payload_dir_file(dir: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+    let path = dir.join(name);
+
+    match sandbox::read(&path) {
+        Ok(data) => Ok(Some(data)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(e).with_context(|| format!("Failed to read file: {path:?}")),
+    }
+}
+
+/// Open a directory containing a bare `payload.bin` alongside the `metadata`
+/// and/or `metadata.pb` files that would normally be stored in
+/// `META-INF/com/android/`. This is the layout of captured OTA downloads that
+/// were not packaged as a zip. The directory is presented as a virtual OTA zip
+/// where only the zip structures and the small files are kept in memory. The
+/// payload's data is read from the original file, so it is never copied.
+/// Missing entries that are regenerated by `ota patch` anyway, like the payload
+/// properties and the OTA certificate, are added as empty placeholders.
+fn open_payload_dir(dir: &Path, cancel_signal: &AtomicBool) -> Result<SplicedReader<PSeekFile>> {
+    let payload_path = dir.join(ota::PATH_PAYLOAD);
+    let mut payload_file = sandbox::open(&payload_path)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {payload_path:?}"))?;
+    let payload_size = payload_file
+        .seek(SeekFrom::End(0))
+        .with_context(|| format!("Failed to get file size: {payload_path:?}"))?;
+
+    let metadata = read_payload_dir_file(dir, "metadata")?;
+    let metadata_pb = read_payload_dir_file(dir, "metadata.pb")?;
+    if metadata.is_none() && metadata_pb.is_none() {
+        bail!("Payload directory has no metadata or metadata.pb file: {dir:?}");
+    }
+
+    let properties = match read_payload_dir_file(dir, "payload_properties.txt")? {
+        Some(data) => data,
+        None => read_payload_dir_file(dir, "properties")?.unwrap_or_default(),
+    };
+
+    let entries = [
+        (ota::PATH_METADATA, metadata),
+        (ota::PATH_METADATA_PB, metadata_pb),
+        (ota::PATH_PROPERTIES, Some(properties)),
+        (ota::PATH_OTACERT, Some(vec![])),
+    ];
+
+    let writer = SpliceWriter::new();
+    let mut zip_writer = ZipWriter::new(writer.clone());
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+    // The payload is still read once because the zip structures need its CRC.
+    zip_writer
+        .start_file(
+            ota::PATH_PAYLOAD,
+            options.large_file(payload_size >= 0xffffffff),
+        )
+        .with_context(|| format!("Failed to begin new zip entry: {}", ota::PATH_PAYLOAD))?;
+    writer.skip(payload_size)?;
+
+    status!(
+        "Adding {payload_path:?} as zip entry: {}",
+        ota::PATH_PAYLOAD
+    );
+
+    stream::copy_n(
+        BufReader::new(payload_file.reopen()?),
+        &mut zip_writer,
+        payload_size,
+        cancel_signal,
+    )
+    .with_context(|| format!("Failed to read file: {payload_path:?}"))?;
+
+    for (path, data) in entries {
+        let Some(data) = data else {
+            continue;
+        };
+
+        zip_writer
+            .start_file(path, options)
+            .with_context(|| format!("Failed to begin new zip entry: {path}"))?;
+        zip_writer
+            .write_all(&data)
+            .with_context(|| format!("Failed to write zip entry: {path}"))?;
+    }
+
+    zip_writer
+        .finish()
+        .context("Failed to finalize virtual OTA zip")?;
+
+    let (head, tail) = writer.buffers();
+
+    Ok(SplicedReader::new(head, payload_file, payload_size, tail))
+}
+
+pub fn revert_subcommand(cli: &RevertCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let output = cli.output.clone().unwrap_or_else(|| {
+        let mut s = cli.input.clone().into_os_string();
+        s.push(".reverted");
+        PathBuf::from(s)
+    });
+
+    let args = [
+        OsStr::new("patch"),
+        OsStr::new("--input"),
+        cli.input.as_os_str(),
+        OsStr::new("--output"),
+        output.as_os_str(),
+        OsStr::new("--rootless"),
+    ]
+    .into_iter()
+    .chain(cli.patch_args.iter().map(|a| a.as_os_str()))
+    .map(OsString::from);
+    let mut patch_cli: PatchCli = profile::parse_args(args, &[&[]])?;
+    patch_cli.revert = true;
+    patch_cli.allow_multiple_input_signers = cli.allow_multiple_signers;
+
+    patch_subcommand(&patch_cli, cancel_signal)
+}
+
+/// Reject options that cannot work when the process is hardened with
+/// `--harden`. The seccomp filter blocks running other programs, so these would
+/// otherwise only fail when they are first used, after most of the work is
+/// done.
+fn check_harden_conflicts(cli: &PatchCli) -> Result<()> {
+    if !harden::is_applied() {
+        return Ok(());
+    }
+
+    for (option, program) in [
+        ("--signer-cmd", &cli.signer_cmd),
+        ("--custom-boot-patcher", &cli.custom_boot_patcher),
+        ("--payload-decrypt-cmd", &cli.payload_decrypt_cmd),
+        ("--payload-encrypt-cmd", &cli.payload_encrypt_cmd),
+    ] {
+        if let Some(program) = program {
+            bail!("{option} cannot be used with --harden because it runs a program: {program:?}");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn patch_subcommand(cli: &PatchCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let output = patch_output_path(cli)?;
+
+    notify::run(&cli.notify, "ota patch", &cli.input, Some(&output), || {
+        patch_ota(cli, &output, cancel_signal)
+    })
+}
+
+fn patch_ota(cli: &PatchCli, output: &Path, cancel_signal: &AtomicBool) -> Result<()> {
+    if cli.boot_partition.is_some() {
+        warning!("Ignoring --boot-partition: deprecated and no longer needed");
+    }
+
+    if let Some(profile) = &cli.profile {
+        status!("Using profile {profile:?} from {:?}", cli.config);
+    }
+
+    let preset_cli;
+    let (cli, device_preset) = match &cli.device {
+        Some(codename) => {
+            let preset = load_device_preset(codename, cli.device_db.as_deref())?;
+            preset_cli = apply_device_preset(cli, codename, &preset);
+            (&preset_cli, Some(preset))
+        }
+        None => (cli, None),
+    };
+
+    check_harden_conflicts(cli)?;
+
+    let source_avb = PassphraseSource::new(
+        &cli.key_avb,
+        cli.pass_avb_file.as_deref(),
+        cli.pass_avb_env_var.as_deref(),
+    );
+    let source_ota = PassphraseSource::new(
+        &cli.key_ota,
+        cli.pass_ota_file.as_deref(),
+        cli.pass_ota_env_var.as_deref(),
+    );
+
+    let detached = cli.emit_signing_requests.is_some() || !cli.apply_signatures.is_empty();
+    if detached {
+        init_detached_signing(&cli.apply_signatures)?;
+    }
+
+    let signer_cmd = cli.signer_cmd.as_deref();
+    let key_avb = load_signing_key(&cli.key_avb, &source_avb, detached, signer_cmd)?;
+    let key_ota = load_signing_key(&cli.key_ota, &source_ota, detached, signer_cmd)?;
+    let cert_ota = crypto::read_pem_cert_file(&cli.cert_ota)
+        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+
+    if !crypto::cert_matches_key(&cert_ota, &key_ota)? {
+        bail!(
+            "Key {:?} does not match certificate {:?}",
+            cli.key_ota,
+            cli.cert_ota,
+        );
+    }
+
+    let secondary_ota = match (&cli.key_ota_secondary, &cli.cert_ota_secondary) {
+        (Some(key_path), Some(cert_path)) => {
+            let source = PassphraseSource::new(
+                key_path,
+                cli.pass_ota_secondary_file.as_deref(),
+                cli.pass_ota_secondary_env_var.as_deref(),
+            );
+            let key = load_signing_key(key_path, &source, detached, signer_cmd)?;
+            let cert = crypto::read_pem_cert_file(cert_path)
+                .with_context(|| format!("Failed to load certificate: {cert_path:?}"))?;
+
+            if !crypto::cert_matches_key(&cert, &key)? {
+                bail!("Key {key_path:?} does not match certificate {cert_path:?}");
+            }
+
+            Some((key, cert))
+        }
+        _ => None,
+    };
+
+    check_key_separation(
+        cli,
+        &key_avb,
+        &key_ota,
+        secondary_ota.as_ref().map(|(k, _)| k),
+    )?;
+
+    for cert in iter::once(&cert_ota).chain(secondary_ota.as_ref().map(|(_, c)| c)) {
+        for issue in crypto::signing_key_usage_issues(cert)? {
+            warning!("OTA certificate {}: {issue}", cert.tbs_certificate.subject);
+        }
+    }
+
+    let keys_ota = iter::once(&key_ota)
+        .chain(secondary_ota.as_ref().map(|(k, _)| k))
+        .cloned()
+        .collect::<Vec<_>>();
+    let signers_ota = iter::once((&key_ota, &cert_ota))
+        .chain(secondary_ota.as_ref().map(|(k, c)| (k, c)))
+        .collect::<Vec<_>>();
+
+    let mut external_images = HashMap::new();
+
+    for item in cli.replace.chunks_exact(2) {
+        let name = item[0]
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid partition name: {:?}", item[0]))?;
+
+        external_images.insert(name.to_owned(), ExternalImage::parse(&item[1]));
+    }
+
+    if let Some(gsi) = &cli.gsi {
+        if external_images.contains_key("system") {
+            bail!("--gsi cannot be used with --replace system");
+        }
+
+        external_images.insert("system".to_owned(), ExternalImage::Gsi(gsi.clone()));
+    }
+
+    let mut source_images = HashMap::new();
+
+    for item in cli.source_image.chunks_exact(2) {
+        let name = item[0]
+            .to_str()
+            .ok_or_else(|| anyhow!("Invalid partition name: {:?}", item[0]))?;
+
+        source_images.insert(name.to_owned(), PathBuf::from(&item[1]));
+    }
+
+    let mut partition_map = BTreeMap::new();
+
+    for item in &cli.map_partition {
+        let Some((old_name, new_name)) = item.split_once('=') else {
+            bail!("Invalid partition mapping (expected OLD=NEW): {item:?}");
+        };
+        if old_name.is_empty() || new_name.is_empty() {
+            bail!("Invalid partition mapping (expected OLD=NEW): {item:?}");
+        }
+
+        if partition_map
+            .insert(old_name.to_owned(), new_name.to_owned())
+            .is_some()
+        {
+            bail!("Partition mapped more than once: {old_name}");
+        }
+    }
+
+    let raw_reader = if sandbox::is_dir(&cli.input) {
+        open_payload_dir(&cli.input, cancel_signal)
+            .map(OtaReader::Dir)
+            .with_context(|| format!("Failed to open payload directory: {:?}", cli.input))?
+    } else {
+        OtaReader::open(&cli.input)
+            .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?
+    };
+    let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader.reopen()?))
+        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+
+    if let Some(codename) = &cli.device {
+        check_ota_device(&mut zip_reader, codename)
+            .with_context(|| format!("Failed to validate OTA metadata: {:?}", cli.input))?;
+    }
+
+    let current_spl = if let Some(spl) = &cli.current_spl {
+        Some(spl.clone())
+    } else if cli.current_spl_from_device {
+        let adb = Adb {
+            program: &cli.adb,
+            serial: cli.adb_serial.as_deref(),
+        };
+        let spl = adb
+            .getprop("ro.build.version.security_patch")
+            .context("Failed to get security patch level from device")?;
+        if spl.is_empty() {
+            bail!("Device did not report a security patch level");
+        }
+
+        status!("Installed build's security patch level: {spl}");
+        Some(spl)
+    } else {
+        None
+    };
+
+    if let Some(spl) = &current_spl {
+        check_spl_downgrade(&mut zip_reader, spl)
+            .with_context(|| format!("Failed to check security patch level: {:?}", cli.input))?;
+    }
+
+    let classifier = PartitionClassifier::from_args(&cli.classify)?;
+
+    for target in &cli.otacerts_target {
+        if !classifier.is_boot(target) && !classifier.is_system(target) {
+            bail!("Not a boot or system partition: {target}");
+        }
+    }
+
+    for target in &cli.custom_boot_patcher_target {
+        if !classifier.is_boot(target) {
+            bail!("Not a boot partition: {target}");
+        }
+    }
+
+    if let Some(target) = &cli.magisk_target {
+        if !classifier.is_boot(target) {
+            bail!("Not a boot partition: {target}");
+        }
+    }
+
+    let otacerts_targets = if cli.otacerts_target.is_empty() {
+        None
+    } else {
+        Some(cli.otacerts_target.as_slice())
+    };
+
+    let patched_signs = find_patched_input_signs(&mut zip_reader, &cert_ota);
+    if cli.revert {
+        if patched_signs.is_empty() {
+            warning!("Input OTA does not appear to be patched by avbroot");
+        }
+
+        status!("Verifying input OTA signature");
+
+        let certs = ota::verify_ota(
+            BufReader::new(raw_reader.reopen()?),
+            cli.allow_multiple_input_signers,
+            cancel_signal,
+        )
+        .with_context(|| format!("Failed to verify OTA signature: {:?}", cli.input))?;
+        if !certs.contains(&cert_ota) {
+            bail!("Input OTA is not signed by the specified OTA certificate");
+        }
+    } else if !patched_signs.is_empty() {
+        for sign in &patched_signs {
+            warning!("Input OTA appears to already be patched: {sign}");
+        }
+
+        if cli.force {
+            warning!("Patching an already-patched OTA will likely result in a boot loop");
+        } else {
+            bail!("Refusing to patch an already-patched OTA without --force");
+        }
+    }
+
+    let root_patcher = if let Some(magisk) = &cli.root.magisk {
+        let compat_db = if let Some(path) = &cli.magisk_compat_db {
+            let data = sandbox::read_to_string(path)
+                .with_context(|| format!("Failed to read file: {path:?}"))?;
+
+            MagiskCompatDb::from_toml(&data).with_context(|| {
+                format!("Failed to load Magisk compatibility database: {path:?}")
+            })?
+        } else {
+            MagiskCompatDb::builtin()
+        };
+        let sdk = read_ota_sdk_level(&mut zip_reader)
+            .with_context(|| format!("Failed to read OTA metadata: {:?}", cli.input))?;
+
+        let mut patcher = MagiskRootPatcher::new(
+            magisk,
+            cli.magisk_preinit_device.as_deref(),
+            cli.magisk_random_seed,
+            cli.ignore_magisk_warnings,
+            &compat_db,
+            sdk,
+            move |s| warning!("{s}"),
+        )
+        .context("Failed to create Magisk boot image patcher")?;
+
+        if let Some(target) = &cli.magisk_target {
+            patcher = patcher.with_target(target);
+        }
+
+        let patcher: Box<dyn BootImagePatch + Sync> = Box::new(patcher);
+
+        Some(patcher)
+    } else if let Some(kernelsu) = &cli.root.kernelsu {
+        let Some(init) = &cli.kernelsu_init else {
+            bail!("--kernelsu-init is required when using --kernelsu");
+        };
+
+        let patcher: Box<dyn BootImagePatch + Sync> = Box::new(
+            KernelSuRootPatcher::new(kernelsu, init, cli.ignore_kernelsu_kmi, move |s| {
+                warning!("{s}");
+            })
+            .context("Failed to create KernelSU boot image patcher")?,
+        );
+
+        Some(patcher)
+    } else if let Some(prepatched) = &cli.root.prepatched {
+        let patcher: Box<dyn BootImagePatch + Sync> = Box::new(PrepatchedImagePatcher::new(
+            prepatched,
+            cli.ignore_prepatched_compat + 1,
+            move |s| {
+                warning!("{s}");
+            },
+        ));
+
+        Some(patcher)
+    } else if cli.revert {
+        let patcher: Box<dyn BootImagePatch + Sync> = Box::new(RootRemovalPatcher);
+
+        Some(patcher)
+    } else {
+        assert!(cli.root.rootless);
+        None
+    };
+
+    let mut extra_patchers = Vec::<Box<dyn BootImagePatch + Sync>>::new();
+    extra_patchers.extend(root_patcher);
+
+    if let Some(program) = &cli.custom_boot_patcher {
+        let mut patcher = CustomBootPatcher::new(program);
+
+        if !cli.custom_boot_patcher_target.is_empty() {
+            patcher = patcher.with_targets(cli.custom_boot_patcher_target.iter().cloned());
+        }
+
+        extra_patchers.push(Box::new(patcher));
+    }
+
+    if let Some(threshold) = cli.compression_skip_entropy {
+        if !(0.0..=8.0).contains(&threshold) {
+            bail!("Entropy threshold must be between 0 and 8: {threshold}");
+        }
+    }
+
+    let low_memory = args::low_memory();
+
+    let mut compress_options = CompressOptions {
+        zero_chunks: cli.zero_chunks,
+        skip_entropy: cli.compression_skip_entropy,
+        xz_level: cli.xz_level,
+        xz_extreme: cli.xz_extreme,
+        xz_check: cli.xz_check.to_check(),
+        xz_dict_size: cli.xz_dict_size,
+        xz_original: None,
+        low_memory,
+    };
+
+    let blob_cache = cli
+        .blob_cache
+        .as_deref()
+        .map(|dir| {
+            BlobCache::new(dir).with_context(|| format!("Failed to open blob cache: {dir:?}"))
+        })
+        .transpose()?;
+
+    let start = Instant::now();
+
+    let provenance = if cli.provenance {
+        status!("Computing input OTA digest");
+
+        let mut writer = HashingWriter::new(
+            io::sink(),
+            crate::digest::Context::new(&crate::digest::SHA256),
+        );
+
+        stream::copy(
+            BufReader::new(raw_reader.reopen()?),
+            &mut writer,
+            cancel_signal,
+        )
+        .with_context(|| format!("Failed to compute digest: {:?}", cli.input))?;
+
+        let mut patchers = vec![];
+
+        let patch_boot_otacerts = match otacerts_targets {
+            Some(t) => t.iter().any(|n| classifier.is_boot(n)),
+            None => true,
+        };
+
+        if patch_boot_otacerts {
+            patchers.push(OtaCertPatcher::new(cert_ota.clone()).patcher_name());
+        }
+
+        patchers.extend(extra_patchers.iter().map(|p| p.patcher_name()));
+
+        let mut key_fingerprints = BTreeMap::new();
+
+        for (name, key) in [("avb", Some(&key_avb)), ("ota", Some(&key_ota))]
+            .into_iter()
+            .chain([("ota_secondary", secondary_ota.as_ref().map(|(k, _)| k))])
+        {
+            if let Some(key) = key {
+                let digest = crypto::public_key_sha256(&key.to_public_key())?;
+                key_fingerprints.insert(name.to_owned(), hex::encode(digest));
+            }
+        }
+
+        Some(Provenance {
+            avbroot_version: env!("CARGO_PKG_VERSION").to_owned(),
+            input_sha256: hex::encode(writer.finish().1.finish()),
+            options: provenance_options(cli),
+            patchers: patchers.into_iter().map(|p| p.to_owned()).collect(),
+            input_digests: provenance_input_digests(cli, cancel_signal)?,
+            key_fingerprints,
+        })
+    } else {
+        None
+    };
+
+    if cli.skip_unchanged {
+        let p = provenance.as_ref().unwrap();
+
+        match is_output_up_to_date(
+            output,
+            p,
+            &cert_ota,
+            cli.key_ota_secondary.is_some(),
+            cancel_signal,
+        ) {
+            Ok(true) => {
+                status!("Output is already up to date: {output:?}");
+                return Ok(());
+            }
+            Ok(false) => {}
+            Err(e) => warning!("Patching again because the existing output is invalid: {e:?}"),
+        }
+    }
+
+    let input_extras = if cli.zip_comment == ZipCommentArg::Preserve
+        || cli.signing_block == SigningBlockArg::Preserve
+    {
+        Some(
+            ota::read_zip_extras(BufReader::new(raw_reader.reopen()?))
+                .with_context(|| format!("Failed to read zip comment: {:?}", cli.input))?,
+        )
+    } else {
+        None
+    };
+
+    let zip_comment = if let Some(path) = &cli.zip_comment_file {
+        Some(sandbox::read(path).with_context(|| format!("Failed to read file: {path:?}"))?)
+    } else {
+        match cli.zip_comment {
+            ZipCommentArg::Default => None,
+            ZipCommentArg::Preserve => input_extras.as_ref().map(|e| e.comment.clone()),
+            ZipCommentArg::Strip => Some(vec![]),
+        }
+    };
+
+    let signing_block = if let Some(path) = &cli.signing_block_file {
+        let data = sandbox::read(path).with_context(|| format!("Failed to read file: {path:?}"))?;
+        ota::parse_signing_block(&data)
+            .with_context(|| format!("Failed to parse APK signing block: {path:?}"))?;
+
+        Some(data)
+    } else {
+        match input_extras.and_then(|e| e.signing_block) {
+            Some(pairs) if cli.signing_block == SigningBlockArg::Preserve => {
+                let (dropped, kept): (Vec<_>, Vec<_>) = pairs.into_iter().partition(|(id, _)| {
+                    ota::APK_SIG_BLOCK_TRANSIENT_IDS
+                        .iter()
+                        .any(|(i, _)| i == id)
+                });
+
+                for (id, _) in &dropped {
+                    status!("Dropping APK signing block pair: 0x{id:08x}");
+                }
+
+                if kept.is_empty() {
+                    None
+                } else {
+                    Some(ota::build_signing_block(&kept))
+                }
+            }
+            _ => None,
+        }
+    };
+
+    // Templates can organize outputs into directories that do not exist yet.
+    if cli.output_template.is_some() {
+        let parent = util::parent_path(output);
+        sandbox::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {parent:?}"))?;
+    }
+
+    // Named temporary files cannot be created through a directory handle.
+    sandbox::check(output).with_context(|| format!("Cannot write output: {output:?}"))?;
+
+    // Open the output file for reading too, so we can verify offsets later.
+    let temp_writer = NamedTempFile::with_prefix_in(
+        output
+            .file_name()
+            .unwrap_or_else(|| OsStr::new("avbroot.tmp")),
+        util::parent_path(output),
+    )
+    .context("Failed to open temporary output file")?;
+    let temp_path = temp_writer.path().to_owned();
+    let hole_punching_writer = HolePunchingWriter::new(temp_writer);
+    let buffered_writer = BufWriter::new(hole_punching_writer);
+    let mut signing_writer = SigningWriter::new(buffered_writer);
+    if let Some(comment) = zip_comment {
+        signing_writer = signing_writer.with_comment(comment);
+    }
+    if let Some(block) = signing_block {
+        signing_writer = signing_writer.with_signing_block(block);
+    }
+    let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+
+    let mut report = if cli.patch_report.is_some() {
+        let mut cert_fingerprints = BTreeMap::new();
+
+        for (name, cert) in iter::once(("ota", &cert_ota))
+            .chain(secondary_ota.as_ref().map(|(_, c)| ("ota_secondary", c)))
+        {
+            let digest = crypto::cert_sha256(cert)?;
+            cert_fingerprints.insert(name.to_owned(), hex::encode(digest));
+        }
+
+        Some(PatchReport {
+            avbroot_version: env!("CARGO_PKG_VERSION").to_owned(),
+            cert_fingerprints,
+            ..Default::default()
+        })
+    } else {
+        None
+    };
+
+    let (metadata, payload_metadata_size) = patch_ota_zip(
+        &raw_reader,
+        &mut zip_reader,
+        &mut zip_writer,
+        &external_images,
+        &source_images,
+        &partition_map,
+        extra_patchers,
+        cli.clear_vbmeta_flags,
+        cli.strict_vbmeta,
+        cli.vbmeta_root.as_deref().map(|name| VbmetaRoot {
+            name,
+            skip_others: cli.skip_other_vbmeta_roots,
+        }),
+        cli.transcode_ops,
+        cli.print_plan,
+        &key_avb,
+        cli.avb_algorithm.map(|a| a.to_algorithm()),
+        &keys_ota,
+        &cert_ota,
+        otacerts_targets,
+        &classifier,
+        device_preset.as_ref(),
+        &mut compress_options,
+        blob_cache.as_ref(),
+        provenance.as_ref(),
+        cli.keep_original_manifest,
+        PayloadHooks {
+            decrypt_cmd: cli.payload_decrypt_cmd.as_deref(),
+            encrypt_cmd: cli.payload_encrypt_cmd.as_deref(),
+        },
+        report.as_mut(),
+        cancel_signal,
+    )
+    .context("Failed to patch OTA zip")?;
+
+    let signing_writer = zip_writer
+        .finish()
+        .context("Failed to finalize output zip")?;
+    let buffered_writer = signing_writer
+        .finish_with_signers(&signers_ota)
+        .context("Failed to sign output zip")?;
+
+    if detached && finish_detached_signing(cli)? {
+        return Ok(());
+    }
+    let hole_punching_writer = buffered_writer
+        .into_inner()
+        .context("Failed to flush output zip")?;
+    let mut temp_writer = hole_punching_writer.into_inner();
+    temp_writer.flush().context("Failed to flush output zip")?;
+
+    // We do a lot of low-level hackery. Reopen and verify offsets.
+    progress::stage("verify_output");
+    status!("Verifying metadata offsets");
+    temp_writer.rewind().context("Failed to seek output zip")?;
+    ota::verify_metadata(
+        BufReader::new(&mut temp_writer),
+        &metadata,
+        payload_metadata_size,
+    )
+    .context("Failed to verify OTA metadata offsets")?;
+
+    if cli.size_report {
+        temp_writer.rewind().context("Failed to seek output zip")?;
+
+        // This fails if the payloads are wrapped in an OEM container.
+        let headers = ota::parse_zip_ota_info(BufReader::new(raw_reader.reopen()?)).and_then(
+            |(_, _, old_header, _)| {
+                let (_, _, new_header, _) =
+                    ota::parse_zip_ota_info(BufReader::new(&mut temp_writer))?;
+                Ok((old_header, new_header))
+            },
+        );
+
+        match headers {
+            Ok((old_header, new_header)) => print_size_report(&old_header, &new_header),
+            Err(e) => warning!("Cannot generate size report: {e}"),
+        }
+    }
+
+    status!("Completed after {:.1}s", start.elapsed().as_secs_f64());
+
+    // NamedTempFile forces 600 permissions on temp files because it's the safe
+    // option for a shared /tmp. Since we're writing to the output file's
+    // directory, just mimic umask.
+    #[cfg(unix)]
+    {
+        use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
+
+        use rustix::{fs::Mode, process::umask};
+
+        let mask = umask(Mode::empty());
+        umask(mask);
+
+        // Mac uses a 16-bit value.
+        #[allow(clippy::useless_conversion)]
+        let mode = u32::from(0o666 & !mask.bits());
+
+        temp_writer
+            .as_file()
+            .set_permissions(Permissions::from_mode(mode))
+            .with_context(|| format!("Failed to set permissions to {mode:o}: {temp_path:?}"))?;
+    }
+
+    temp_writer.persist(output).with_context(|| {
+        format!("Failed to move temporary file to output path: {temp_path:?} -> {output:?}")
+    })?;
+
+    if let (Some(path), Some(report)) = (&cli.patch_report, &report) {
+        let mut data =
+            serde_json::to_string_pretty(report).context("Failed to serialize patch report")?;
+        data.push('\n');
+
+        sandbox::write(path, data)
+            .with_context(|| format!("Failed to write patch report: {path:?}"))?;
+    }
+
+    Ok(())
+}
+
+pub fn extract_subcommand(cli: &ExtractCli, cancel_signal: &AtomicBool) -> Result<()> {
+    if cli.boot_partition.is_some() {
+        warning!("Ignoring --boot-partition: deprecated and no longer needed");
+    }
+
+    if harden::is_applied() {
+        if let Some(program) = &cli.payload_decrypt_cmd {
+            bail!(
+                "--payload-decrypt-cmd cannot be used with --harden because it runs a program: \
+                {program:?}",
+            );
+        }
+    }
+
+    let raw_reader = sandbox::open(&cli.input)
+        .map(PSeekFile::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    // Only the payload is needed, so a zip that the zip library cannot parse
+    // can still be handled by reading the central directory directly.
+    let payload_entry = match ZipArchive::new(BufReader::new(raw_reader.reopen()?)) {
+        Ok(mut zip) => find_payload_entry(&raw_reader, &mut zip)?,
+        Err(e) => {
+            warning!("Failed to read zip via zip library, using central directory: {e}");
+
+            ota::find_stored_zip_entry(BufReader::new(raw_reader.reopen()?), ota::PATH_PAYLOAD)
+                .with_context(|| format!("Failed to read zip: {:?}", cli.input))?
+        }
+    };
+
+    if cli.payload_only {
+        sandbox::create_dir_all(&cli.directory)
+            .with_context(|| format!("Failed to create directory: {:?}", cli.directory))?;
+        let directory = sandbox::open_dir(&cli.directory)
+            .with_context(|| format!("Failed to open directory: {:?}", cli.directory))?;
+
+        return extract_ota_internals(
+            &raw_reader,
+            &directory,
+            payload_entry.offset,
+            payload_entry.size,
+            cancel_signal,
+        );
+    }
+
+    let temp_dir = sandbox::tempdir().context("Failed to create temp directory")?;
+
+    let (payload_file, payload_offset, payload_size) = match &cli.payload_decrypt_cmd {
+        Some(cmd) => {
+            let (file, size) = unwrap_payload(
+                cmd,
+                &raw_reader,
+                &payload_entry,
+                temp_dir.path(),
+                cancel_signal,
+            )?;
+
+            (file, 0, size)
+        }
+        None => (raw_reader, payload_entry.offset, payload_entry.size),
+    };
+
+    // Open the payload data directly.
+    let mut payload_reader = SectionReader::new(
+        BufReader::new(payload_file.reopen()?),
+        payload_offset,
+        payload_size,
+    )
+    .context("Failed to directly open payload section")?;
+
+    check_payload_magic(&mut payload_reader)?;
+    payload_reader
+        .rewind()
+        .context("Failed to seek payload section")?;
+
+    let header = PayloadHeader::from_reader(&mut payload_reader)
+        .context("Failed to load OTA payload header")?;
+    check_full_ota(&header)?;
+
+    if !cli.file.is_empty() {
+        return extract_files(
+            &cli.file,
+            &payload_file,
+            &cli.directory,
+            payload_offset,
+            payload_size,
+            &header,
+            cancel_signal,
+        );
+    }
+
+    let mut unique_images = BTreeSet::new();
+
+    if cli.all {
+        unique_images.extend(
+            header
+                .manifest
+                .partitions
+                .iter()
+                .map(|p| &p.partition_name)
+                .cloned(),
+        );
+    } else if !cli.partition.is_empty() {
+        for name in &cli.partition {
+            if !header
+                .manifest
+                .partitions
+                .iter()
+                .any(|p| &p.partition_name == name)
+            {
+                bail!("Partition not found in payload: {name}");
+            }
+
+            unique_images.insert(name.clone());
+        }
+    } else {
+        let classifier = PartitionClassifier::from_args(&cli.classify)?;
+        let images = RequiredImages::new(&header.manifest, &classifier);
+
+        if cli.boot_only {
+            unique_images.extend(images.iter_boot().map(|n| n.to_owned()));
+        } else {
+            unique_images.extend(images.iter().map(|n| n.to_owned()));
+        }
+    }
+
+    let outputs = parse_extract_outputs(&cli.output, &unique_images)?;
+
+    sandbox::create_dir_all(&cli.directory)
+        .with_context(|| format!("Failed to create directory: {:?}", cli.directory))?;
+    let directory = sandbox::open_dir(&cli.directory)
+        .with_context(|| format!("Failed to open directory: {:?}", cli.directory))?;
+
+    let mut manifest = if cli.digests {
+        Some(DigestsManifest::load(&directory)?.unwrap_or_default())
+    } else {
+        None
+    };
+
+    // Images written to the output directory, as opposed to --output paths.
+    let directory_images = unique_images
+        .iter()
+        .filter(|n| !outputs.contains_key(*n))
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    if let Some(m) = &manifest {
+        for name in &directory_images {
+            let expected = header
+                .manifest
+                .partitions
+                .iter()
+                .find(|p| &p.partition_name == name)
+                .and_then(|p| p.new_partition_info.as_ref()?.hash.as_ref())
+                .map(hex::encode);
+            let Some(stamp) = FileStamp::for_image(&directory, name)? else {
+                continue;
+            };
+
+            if expected.is_some() && m.get(name, &stamp).and_then(|p| p.sha256.clone()) == expected
+            {
+                status!("Already extracted: {name}");
+                unique_images.remove(name);
+            }
+        }
+    }
+
+    if !unique_images.is_empty() {
+        extract_ota_zip(
+            &payload_file,
+            &directory,
+            payload_offset,
+            payload_size,
+            &header,
+            &unique_images,
+            &outputs,
+            cli.discard,
+            cli.sparse,
+            cancel_signal,
+        )?;
+    }
+
+    if let Some(m) = &mut manifest {
+        status!("Recording partition digests");
+
+        verify_partition_hashes(
+            &directory,
+            &header,
+            &directory_images,
+            Some(m),
+            cancel_signal,
+        )?;
+
+        m.save(&directory)?;
+    }
+
+    Ok(())
+}
+
+/// Parse `--file <partition>:<path>` values.
+fn parse_extract_files(values: &[String]) -> Result<Vec<(String, String)>> {
+    let mut result = vec![];
+
+    for value in values {
+        let Some((partition, path)) = value.split_once(':') else {
+            bail!("Invalid file (expected <partition>:<path>): {value:?}");
+        };
+
+        if !path.starts_with('/') {
+            bail!("File path must be absolute: {value:?}");
+        } else if path.split('/').any(|c| c == "..") {
+            bail!("File path must not contain `..`: {value:?}");
+        }
+
+        result.push((partition.to_owned(), path.to_owned()));
+    }
+
+    Ok(result)
+}
+
+/// Recursively copy a file or directory from a filesystem image to `path` in
+/// `directory`. Symlinks and special files are skipped. `visited` holds the
+/// directories that have already been extracted so that directory cycles in a
+/// malformed image are detected.
+#[allow(clippy::too_many_arguments)]
+fn extract_fs_entry(
+    fs: &mut dyn FileSystemReader,
+    inode: u64,
+    display_path: &str,
+    directory: &Dir,
+    path: &Path,
+    visited: &mut HashSet<u64>,
+    depth: usize,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    match fs
+        .file_type(inode)
+        .with_context(|| format!("Failed to read inode: {display_path}"))?
+    {
+        FileType::Regular => {
+            status!("Extracting file: {display_path}");
+
+            if let Some(parent) = path.parent() {
+                directory
+                    .create_dir_all(parent)
+                    .with_context(|| format!("Failed to create directory: {parent:?}"))?;
+            }
+
+            let mut writer = directory
+                .create(path)
+                .map(|f| BufWriter::new(f.into_std()))
+                .with_context(|| format!("Failed to open for writing: {path:?}"))?;
+
+            fs.read_file(inode, &mut writer, cancel_signal)
+                .with_context(|| format!("Failed to extract file: {display_path}"))?;
+            writer.flush()?;
+        }
+        FileType::Directory => {
+            if depth >= filesystem::MAX_DEPTH {
+                bail!("Directory nesting is too deep: {display_path}");
+            } else if !visited.insert(inode) {
+                bail!("Directory was already visited: {display_path}");
+            }
+
+            directory
+                .create_dir_all(path)
+                .with_context(|| format!("Failed to create directory: {path:?}"))?;
+
+            let mut entries = fs
+                .read_dir(inode)
+                .with_context(|| format!("Failed to read directory: {display_path}"))?;
+            entries.sort_by(|a, b| a.name.cmp(&b.name));
+
+            for entry in entries {
+                let name = String::from_utf8_lossy(&entry.name);
+
+                extract_fs_entry(
+                    fs,
+                    entry.inode,
+                    &format!("{}/{name}", display_path.trim_end_matches('/')),
+                    directory,
+                    &path.join(&*name),
+                    visited,
+                    depth + 1,
+                    cancel_signal,
+                )?;
+            }
+        }
+        FileType::Symlink => warning!("Skipping symlink: {display_path}"),
+        FileType::Other => warning!("Skipping special file: {display_path}"),
+    }
+
+    Ok(())
+}
+
+/// Extract individual files from filesystem partitions. Only the partitions
+/// that are needed are extracted, into a temporary directory.
+fn extract_files(
+    values: &[String],
+    payload_file: &PSeekFile,
+    output_dir: &Path,
+    payload_offset: u64,
+    payload_size: u64,
+    header: &PayloadHeader,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let files = parse_extract_files(values)?;
+    let mut unique_images = BTreeSet::new();
+
+    for (partition, _) in &files {
+        if !header
+            .manifest
+            .partitions
+            .iter()
+            .any(|p| &p.partition_name == partition)
+        {
+            bail!("Partition not found in payload: {partition}");
+        }
+
+        unique_images.insert(partition.clone());
+    }
+
+    let temp_dir = sandbox::cap_tempdir().context("Failed to create temporary directory")?;
+
+    extract_ota_zip(
+        payload_file,
+        &temp_dir,
+        payload_offset,
+        payload_size,
+        header,
+        &unique_images,
+        &BTreeMap::new(),
+        false,
+        false,
+        cancel_signal,
+    )?;
+
+    sandbox::create_dir_all(output_dir)
+        .with_context(|| format!("Failed to create directory: {output_dir:?}"))?;
+    let directory = sandbox::open_dir(output_dir)
+        .with_context(|| format!("Failed to open directory: {output_dir:?}"))?;
+
+    for (partition, pattern) in &files {
+        let image = temp_dir
+            .open(format!("{partition}.img"))
+            .map(|f| BufReader::new(f.into_std()))
+            .with_context(|| format!("Failed to open image: {partition}"))?;
+        let mut fs = filesystem::open(image)
+            .with_context(|| format!("Failed to open filesystem: {partition}"))?;
+
+        let matches = filesystem::find(fs.as_mut(), pattern)
+            .with_context(|| format!("Failed to find files: {partition}:{pattern}"))?;
+
+        let mut visited = HashSet::new();
+
+        for (path, inode) in matches {
+            extract_fs_entry(
+                fs.as_mut(),
+                inode,
+                &format!("{partition}:{path}"),
+                &directory,
+                &Path::new(partition).join(path.trim_start_matches('/')),
+                &mut visited,
+                0,
+                cancel_signal,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Copy the OTA's payload and the entries describing it to `directory` as-is,
+/// without extracting any partitions.
+fn extract_ota_internals(
+    raw_reader: &PSeekFile,
+    directory: &Dir,
+    payload_offset: u64,
+    payload_size: u64,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let create = |name: &str| {
+        directory
+            .create(name)
+            .map(|f| BufWriter::new(f.into_std()))
+            .with_context(|| format!("Failed to open for writing: {name:?}"))
+    };
+
+    status!("Copying entry: {}", ota::PATH_PAYLOAD);
+
+    let mut reader = SectionReader::new(
+        BufReader::new(raw_reader.reopen()?),
+        payload_offset,
+        payload_size,
+    )?;
+    let mut writer = create(ota::PATH_PAYLOAD)?;
+
+    stream::copy(&mut reader, &mut writer, cancel_signal)
+        .with_context(|| format!("Failed to copy entry: {}", ota::PATH_PAYLOAD))?;
+    writer.flush()?;
+
+    let mut zip_reader =
+        ZipArchive::new(BufReader::new(raw_reader.reopen()?)).context("Failed to read OTA zip")?;
+
+    for (path, required) in [
+        (ota::PATH_PROPERTIES, true),
+        (ota::PATH_METADATA, true),
+        // Older OTAs do not have metadata.pb.
+        (ota::PATH_METADATA_PB, false),
+        (ota::PATH_OTACERT, true),
+    ] {
+        let mut reader = match zip_reader.by_name(path) {
+            Ok(r) => r,
+            Err(zip::result::ZipError::FileNotFound) if !required => continue,
+            Err(e) => return Err(e).with_context(|| format!("Failed to open zip entry: {path}")),
+        };
+
+        status!("Copying entry: {path}");
+
+        let name = path.rsplit('/').next().unwrap();
+        let mut writer = create(name)?;
+
+        stream::copy(&mut reader, &mut writer, cancel_signal)
+            .with_context(|| format!("Failed to copy entry: {path}"))?;
+        writer.flush()?;
+    }
+
+    Ok(())
+}
+
+pub fn format_mib(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / 1024.0 / 1024.0)
+}
+
+/// Total size of the blob data referenced by a partition's operations.
+fn partition_data_size(partition: &PartitionUpdate) -> u64 {
+    partition
+        .operations
+        .iter()
+        .filter_map(|op| op.data_length)
+        .sum()
+}
+
+/// Roughly estimate how long update_engine takes to apply a partition's
+/// operations. The throughputs are conservative figures for mid-range devices
+/// writing to UFS storage and are only meant to give a ballpark number.
+fn estimate_install_secs(partition: &PartitionUpdate, block_size: u64) -> f64 {
+    const MIB: f64 = 1024.0 * 1024.0;
+
+    partition
+        .operations
+        .iter()
+        .map(|op| {
+            let dst_size = op
+                .dst_extents
+                .iter()
+                .filter_map(|e| e.num_blocks)
+                .sum::<u64>()
+                * block_size;
+            let throughput = match op.r#type() {
+                Type::Replace => 150.0,
+                Type::ReplaceXz => 40.0,
+                Type::ReplaceBz => 15.0,
+                Type::Zero | Type::Discard => 1000.0,
+                _ => 20.0,
+            };
+
+            dst_size as f64 / MIB / throughput
+        })
+        .sum()
+}
+
+/// Print the size difference between the original and patched payloads, a
+/// rough install time estimate, and the snapshot space needed on Virtual A/B
+/// devices.
+fn print_size_report(old_header: &PayloadHeader, new_header: &PayloadHeader) {
+    let block_size = u64::from(new_header.manifest.block_size());
+    let old_partitions = old_header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| (p.partition_name.as_str(), p))
+        .collect::<HashMap<_, _>>();
+    let size = |p: &PartitionUpdate| p.new_partition_info.as_ref().and_then(|i| i.size);
+
+    status!("Size report:");
+
+    let mut old_total = 0;
+    let mut new_total = 0;
+    let mut install_secs = 0.0;
+
+    for partition in &new_header.manifest.partitions {
+        let name = &partition.partition_name;
+        let new_data = partition_data_size(partition);
+        let new_size = size(partition).unwrap_or_default();
+
+        new_total += new_data;
+        install_secs += estimate_install_secs(partition, block_size);
+
+        match old_partitions.get(name.as_str()) {
+            Some(old) => {
+                let old_data = partition_data_size(old);
+                let old_size = size(old).unwrap_or_default();
+
+                old_total += old_data;
+
+                if old_data == new_data && old_size == new_size {
+                    continue;
+                }
+
+                status!(
+                    "- {name}: image {} -> {}, payload data {} -> {}",
+                    format_mib(old_size),
+                    format_mib(new_size),
+                    format_mib(old_data),
+                    format_mib(new_data),
+                );
+            }
+            None => {
+                status!(
+                    "- {name}: image {}, payload data {} (new)",
+                    format_mib(new_size),
+                    format_mib(new_data),
+                );
+            }
+        }
+    }
+
+    status!(
+        "Total payload data: {} -> {}",
+        format_mib(old_total),
+        format_mib(new_total),
+    );
+    status!("Estimated install time: ~{:.0}s", install_secs.ceil());
+
+    if let Some(cow_size) = estimate_snapshot_size(old_header, new_header) {
+        status!(
+            "Estimated Virtual A/B snapshot space required: {}",
+            format_mib(cow_size),
+        );
+    }
+}
+
+/// Estimate the snapshot space needed to install the new payload on a Virtual
+/// A/B device. Returns [`None`] if the payload does not use snapshots.
+fn estimate_snapshot_size(old_header: &PayloadHeader, new_header: &PayloadHeader) -> Option<u64> {
+    let dpm = new_header.manifest.dynamic_partition_metadata.as_ref()?;
+    if dpm.snapshot_enabled != Some(true) {
+        return None;
+    }
+
+    let old_partitions = old_header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| (p.partition_name.as_str(), p))
+        .collect::<HashMap<_, _>>();
+
+    // Only dynamic partitions are snapshotted. The OTA's estimates are only
+    // valid for partitions whose operations were not changed by patching. For
+    // modified partitions or if the estimate is missing, fall back to the size
+    // of the data that would be written.
+    let dynamic = dpm
+        .groups
+        .iter()
+        .flat_map(|g| &g.partition_names)
+        .collect::<HashSet<_>>();
+    let cow_size = new_header
+        .manifest
+        .partitions
+        .iter()
+        .filter(|p| dynamic.contains(&p.partition_name))
+        .map(|p| {
+            let unchanged = old_partitions
+                .get(p.partition_name.as_str())
+                .is_some_and(|old| old.operations == p.operations);
+
+            match p.estimate_cow_size.filter(|s| *s != 0 && unchanged) {
+                Some(s) => s,
+                None if dpm.vabc_enabled == Some(true) => partition_data_size(p),
+                None => p
+                    .new_partition_info
+                    .as_ref()
+                    .and_then(|i| i.size)
+                    .unwrap_or_default(),
+            }
+        })
+        .sum();
+
+    Some(cow_size)
+}
+
+/// Record of a successful `ota verify` run, stored in the verification cache.
+#[derive(Deserialize, Serialize)]
+struct VerifyCacheEntry {
+    /// sha256 digest of the OTA zip.
+    #[serde(with = "hex")]
+    file_digest: Vec<u8>,
+    /// Details of the run for `ota verify --format json`.
+    report: VerifyReport,
+}
+
+/// Compute the path of the verification cache entry for an OTA. The entry is
+/// keyed by the digest of the OTA zip and the contents of the certificate,
+/// public key, and policy files used for verification (if any) so that
+/// verifying with different trust anchors or policies never reuses a result.
+/// Returns the entry path and the OTA zip's digest.
+fn verify_cache_entry_path(
+    cli: &VerifyCli,
+    cache_dir: &Path,
+    raw_reader: &(impl Read + Seek + Reopen),
+    cancel_signal: &AtomicBool,
+) -> Result<(PathBuf, Vec<u8>)> {
+    let mut writer = HashingWriter::new(
+        io::sink(),
+        crate::digest::Context::new(&crate::digest::SHA256),
+    );
+
+    stream::copy(
+        BufReader::new(raw_reader.reopen()?),
+        &mut writer,
+        cancel_signal,
+    )
+    .with_context(|| format!("Failed to compute digest: {:?}", cli.input))?;
+
+    let file_digest = writer.finish().1.finish().as_ref().to_vec();
+
+    let mut context = crate::digest::Context::new(&crate::digest::SHA256);
+    context.update(&file_digest);
+
+    for path in [&cli.cert_ota, &cli.public_key_avb] {
+        if let Some(p) = path {
+            let data = sandbox::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
+            let digest = crate::digest::digest(&crate::digest::SHA256, &data);
+
+            context.update(&[1]);
+            context.update(digest.as_ref());
+        } else {
+            context.update(&[0]);
+        }
+    }
+
+    // These are separate from the loop above so that cache entries created
+    // without a previous certificate or a policy remain valid.
+    for (name, path) in [
+        ("previous_cert", &cli.previous_cert),
+        ("policy", &cli.policy),
+    ] {
+        if let Some(p) = path {
+            let data = sandbox::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
+            let digest = crate::digest::digest(&crate::digest::SHA256, &data);
+
+            context.update(name.as_bytes());
+            context.update(digest.as_ref());
+        }
+    }
+
+    for (name, value) in [
+        ("expect_device", &cli.expect_device),
+        ("expect_fingerprint", &cli.expect_fingerprint),
+    ] {
+        if let Some(v) = value {
+            context.update(name.as_bytes());
+            context.update(&(v.len() as u64).to_le_bytes());
+            context.update(v.as_bytes());
+        }
+    }
+
+    if cli.allow_multiple_signers {
+        context.update(b"allow_multiple_signers");
+    }
+
+    for target in &cli.otacerts_target {
+        context.update(b"otacerts_target");
+        context.update(&(target.len() as u64).to_le_bytes());
+        context.update(target.as_bytes());
+    }
+
+    // The classification decides which images are checked for otacerts.zip.
+    // The order in which the overrides are specified does not matter.
+    for item in sorted(cli.classify.iter()) {
+        context.update(b"classify");
+        context.update(&(item.len() as u64).to_le_bytes());
+        context.update(item.as_bytes());
+    }
+
+    let key = hex::encode(context.finish());
+
+    Ok((cache_dir.join(format!("{key}.toml")), file_digest))
+}
+
+/// Check that the OTA metadata matches the device and build fingerprint that
+/// the user expects. Unlike the policy-controlled checks, these are always
+/// fatal because they were explicitly requested.
+fn check_ota_expectations(cli: &VerifyCli, metadata: &OtaMetadata) -> Result<()> {
+    if let Some(codename) = &cli.expect_device {
+        let devices = metadata
+            .precondition
+            .as_ref()
+            .map(|p| p.device.as_slice())
+            .filter(|d| !d.is_empty())
+            .or_else(|| metadata.postcondition.as_ref().map(|p| p.device.as_slice()))
+            .unwrap_or_default();
+
+        if devices.is_empty() {
+            bail!("OTA metadata does not list any devices; cannot confirm device: {codename}");
+        } else if !devices.iter().any(|d| d == codename) {
+            bail!(
+                "OTA is for {}, not the expected device: {codename}",
+                joined(devices),
+            );
+        }
+
+        status!("OTA is for the expected device: {codename}");
+    }
+
+    if let Some(prefix) = &cli.expect_fingerprint {
+        let fingerprints = metadata
+            .postcondition
+            .as_ref()
+            .map(|p| p.build.as_slice())
+            .unwrap_or_default();
+
+        if fingerprints.is_empty() {
+            bail!("OTA metadata does not list any build fingerprints; cannot confirm: {prefix}");
+        } else if !fingerprints.iter().any(|f| f.starts_with(prefix.as_str())) {
+            bail!(
+                "OTA is for {}, which does not start with the expected fingerprint: {prefix}",
+                joined(fingerprints),
+            );
+        }
+
+        status!("OTA build fingerprint starts with: {prefix}");
+    }
+
+    Ok(())
+}
+
+/// Get the report of a successful result for the OTA from the verification
+/// cache.
+fn verify_cache_lookup(path: &Path, file_digest: &[u8]) -> Option<VerifyReport> {
+    let data = sandbox::read_to_string(path).ok()?;
+
+    match toml_edit::de::from_str::<VerifyCacheEntry>(&data) {
+        Ok(entry) if entry.file_digest == file_digest => Some(entry.report),
+        Ok(_) => None,
+        Err(e) => {
+            warning!("Ignoring invalid verification cache entry: {path:?}: {e}");
+            None
+        }
+    }
+}
+
+/// Save a successful verification result to the verification cache.
+fn verify_cache_store(path: &Path, entry: &VerifyCacheEntry) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        sandbox::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory: {parent:?}"))?;
+    }
+
+    let data = toml_edit::ser::to_string_pretty(entry)
+        .with_context(|| format!("Failed to serialize verification cache entry: {path:?}"))?;
+    sandbox::write(path, data)
+        .with_context(|| format!("Failed to write verification cache entry: {path:?}"))?;
+
+    Ok(())
+}
+
+/// Certificate details for `ota verify --format json`.
+#[derive(Clone, Deserialize, Serialize)]
+struct CertReport {
+    sha256: String,
+    subject: String,
+    not_before: String,
+    not_after: String,
+    expired: bool,
+}
+
+impl CertReport {
+    fn new(cert: &Certificate) -> Result<Self> {
+        let validity = &cert.tbs_certificate.validity;
+
+        Ok(Self {
+            sha256: hex::encode(crypto::cert_sha256(cert)?),
+            subject: cert.tbs_certificate.subject.to_string(),
+            not_before: validity.not_before.to_string(),
+            not_after: validity.not_after.to_string(),
+            expired: validity.not_after.to_system_time() < SystemTime::now(),
+        })
+    }
+}
+
+/// Print every certificate in an otacerts.zip, marking the one that matches the
+/// OTA's signing certificate. Android does not check the validity period of
+/// these certificates, so expired ones are only pointed out.
+fn show_otacerts(
+    source: &str,
+    certs: &[Certificate],
+    ota_cert: &Certificate,
+) -> Result<Vec<CertReport>> {
+    status!(
+        "{source}'s otacerts.zip contains {} certificates",
+        certs.len()
+    );
+
+    let mut result = vec![];
+
+    for cert in certs {
+        let info = CertReport::new(cert)?;
+        let mut notes = vec![];
+
+        if cert == ota_cert {
+            notes.push("OTA certificate");
+        }
+        if info.expired {
+            notes.push("expired");
+        }
+
+        let suffix = if notes.is_empty() {
+            String::new()
+        } else {
+            format!(" [{}]", notes.join(", "))
+        };
+
+        status!(
+            "- {}: {} (valid from {} to {}){suffix}",
+            info.sha256,
+            info.subject,
+            info.not_before,
+            info.not_after,
+        );
+
+        result.push(info);
+    }
+
+    Ok(result)
+}
+
+/// A vbmeta header that was visited while following the AVB chain.
+#[derive(Clone, Deserialize, Serialize)]
+struct VbmetaReport {
+    algorithm: AlgorithmType,
+    public_key_sha256: Option<String>,
+    rollback_index: u64,
+    flags: u32,
+    /// Partitions covered by hash or hash tree descriptors.
+    partitions: Vec<String>,
+    /// Partitions covered by chain descriptors.
+    chains: Vec<String>,
+}
+
+impl VbmetaReport {
+    fn new(header: &Header) -> Result<Self> {
+        let public_key_sha256 = if header.public_key.is_empty() {
+            None
+        } else {
+            let key = avb::decode_public_key(&header.public_key)?;
+            Some(hex::encode(crypto::public_key_sha256(&key)?))
+        };
+        let mut partitions = vec![];
+        let mut chains = vec![];
+
+        for descriptor in &header.descriptors {
+            match descriptor {
+                Descriptor::Hash(d) => partitions.push(d.partition_name.clone()),
+                Descriptor::HashTree(d) => partitions.push(d.partition_name.clone()),
+                Descriptor::ChainPartition(d) => chains.push(d.partition_name.clone()),
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            algorithm: header.algorithm_type,
+            public_key_sha256,
+            rollback_index: header.rollback_index,
+            flags: header.flags,
+            partitions,
+            chains,
+        })
+    }
+}
+
+/// Result of `ota verify --format json`. Fields for checks that were not
+/// reached before a failure are left empty.
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct VerifyReport {
+    success: bool,
+    error: Option<String>,
+    /// Whether the result came from the verification cache. The other fields
+    /// are then the details of the run that was cached.
+    cached: bool,
+    /// Certificates embedded in the whole-file signature, sorted in the order
+    /// that devices see them.
+    signature_certs: Vec<CertReport>,
+    /// The certificate for the whole-file signature that devices check.
+    device_signature_cert: Option<CertReport>,
+    /// The certificate from the otacert zip entry.
+    otacert: Option<CertReport>,
+    /// Verified sha256 digests of the partition images.
+    partitions: BTreeMap<String, String>,
+    /// vbmeta headers, keyed by image name.
+    vbmeta: BTreeMap<String, VbmetaReport>,
+    /// Certificates in each boot image's otacerts.zip.
+    otacerts: BTreeMap<String, Vec<CertReport>>,
+    failed_checks: Vec<Failure>,
+}
+
+pub fn verify_subcommand(cli: &VerifyCli, cancel_signal: &AtomicBool) -> Result<()> {
+    notify::run(&cli.notify, "ota verify", &cli.input, None, || {
+        let mut policy = match &cli.policy {
+            Some(p) => Policy::load(p)?,
+            None => Policy::default(),
+        };
+        let mut report = VerifyReport::default();
+
+        let classifier = PartitionClassifier::from_args(&cli.classify)?;
+
+        for target in &cli.otacerts_target {
+            if !classifier.is_boot(target) && !classifier.is_system(target) {
+                bail!("Not a boot or system partition: {target}");
+            }
+        }
+
+        // Devices that only store otacerts.zip in the system image have none in
+        // the boot images.
+        if cli.otacerts_target.iter().any(|t| classifier.is_system(t)) {
+            policy.set_default(Check::RamdiskOtacertsMissing, Action::Warn);
+        }
+
+        let ret = verify_ota(cli, &classifier, &policy, &mut report, cancel_signal);
+
+        if cli.format == OutputFormat::Json {
+            report.success = ret.is_ok();
+            report.error = ret.as_ref().err().map(|e| format!("{e:#}"));
+            // Cached results already include the checks that failed when
+            // they were first verified.
+            report.failed_checks.extend(policy.failures());
+
+            let data = serde_json::to_string_pretty(&report)
+                .context("Failed to serialize verification report")?;
+            println!("{data}");
+        }
+
+        ret
+    })
+}
+
+fn verify_ota(
+    cli: &VerifyCli,
+    classifier: &PartitionClassifier,
+    policy: &Policy,
+    report: &mut VerifyReport,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let raw_reader = OtaReader::open(&cli.input)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+
+    let cache = if let Some(cache_dir) = &cli.cache {
+        status!("Checking verification cache");
+
+        let (path, file_digest) =
+            verify_cache_entry_path(cli, cache_dir, &raw_reader, cancel_signal)?;
+
+        if let Some(cached) = verify_cache_lookup(&path, &file_digest) {
+            status!("Found successful verification result in cache: {path:?}");
+            status!("Signatures are all valid!");
+            *report = cached;
+            report.cached = true;
+            return Ok(());
+        }
+
+        Some((path, file_digest))
+    } else {
+        None
+    };
+
+    let mut reader = BufReader::new(raw_reader);
+
+    progress::stage("verify_whole_file_signature");
+    status!("Verifying whole-file signature");
+
+    let embedded_certs = ota::verify_ota(&mut reader, cli.allow_multiple_signers, cancel_signal)?;
+    // Devices only check the first signature in DER order.
+    let device_cert = &embedded_certs[0];
+    if embedded_certs.len() > 1 {
+        status!("OTA has {} whole-file signatures", embedded_certs.len());
+        status!(
+            "Devices only check the signature from: {}",
+            device_cert.tbs_certificate.subject,
+        );
+    }
+
+    let (metadata, ota_cert, header, properties) = ota::parse_zip_ota_info(&mut reader)?;
+
+    report.signature_certs = embedded_certs
+        .iter()
+        .map(CertReport::new)
+        .collect::<Result<_>>()?;
+    report.device_signature_cert = Some(CertReport::new(device_cert)?);
+    report.otacert = Some(CertReport::new(&ota_cert)?);
+
+    check_ota_expectations(cli, &metadata)?;
+
+    if !embedded_certs.contains(&ota_cert) {
+        policy.fail(
+            Check::OtacertNotEmbedded,
+            format!(
+                "CMS embedded certificates do not include {}",
+                ota::PATH_OTACERT,
+            ),
+        )?;
+    }
+
+    if let Some(p) = &cli.cert_ota {
+        let verify_cert = crypto::read_pem_cert_file(p)
+            .with_context(|| format!("Failed to load certificate: {:?}", p))?;
+        let previous_cert = match &cli.previous_cert {
+            Some(prev_p) => Some(
+                crypto::read_pem_cert_file(prev_p)
+                    .with_context(|| format!("Failed to load certificate: {:?}", prev_p))?,
+            ),
+            None => None,
+        };
+
+        if let (Some(prev_p), Some(previous_cert)) = (&cli.previous_cert, &previous_cert) {
+            if embedded_certs.contains(&verify_cert) {
+                status!("OTA is signed with the current certificate: {p:?}");
+            } else if embedded_certs.contains(previous_cert) {
+                status!("OTA is signed with the previous certificate: {prev_p:?}");
+            } else {
+                policy.fail(
+                    Check::CertMismatch,
+                    format!(
+                        "OTA has a valid signature, but was not signed with: {p:?} or {prev_p:?}"
+                    ),
+                )?;
+            }
+        } else if !embedded_certs.contains(&verify_cert) {
+            policy.fail(
+                Check::CertMismatch,
+                format!("OTA has a valid signature, but was not signed with: {p:?}"),
+            )?;
+        }
+
+        if embedded_certs.len() > 1
+            && device_cert != &verify_cert
+            && previous_cert.as_ref() != Some(device_cert)
+        {
+            warning!("The signature that devices check is not from the specified certificate");
+        }
+    } else {
+        policy.fail(
+            Check::CertUnknown,
+            "Whole-file signature is valid, but its trust is unknown",
+        )?;
+    }
+
+    if let Err(e) = ota::verify_metadata(&mut reader, &metadata, header.blob_offset) {
+        policy.fail(
+            Check::MetadataOffsets,
+            format!("Failed to verify OTA metadata offsets: {e}"),
+        )?;
+    }
+
+    if metadata.spl_downgrade {
+        policy.fail(
+            Check::SplDowngrade,
+            "OTA permits a security patch level downgrade",
+        )?;
+    }
+
+    progress::stage("verify_payload");
+    status!("Verifying payload");
+
+    let pfs_raw = metadata
+        .property_files
+        .get(ota::PF_NAME)
+        .ok_or_else(|| anyhow!("Missing property files: {}", ota::PF_NAME))?;
+    let pfs = ota::parse_property_files(pfs_raw)
+        .with_context(|| format!("Failed to parse property files: {}", ota::PF_NAME))?;
+    let pf_payload = pfs
+        .iter()
+        .find(|pf| pf.name == ota::PATH_PAYLOAD)
+        .ok_or_else(|| anyhow!("Missing property files entry: {}", ota::PATH_PAYLOAD))?;
+
+    let section_reader = SectionReader::new(&mut reader, pf_payload.offset, pf_payload.size)
+        .context("Failed to directly open payload section")?;
+
+    payload::verify_payload(section_reader, &ota_cert, &properties, cancel_signal)?;
+
+    status!("Extracting and verifying partition images in temporary directory");
+
+    let temp_dir = sandbox::cap_tempdir().context("Failed to create temporary directory")?;
+    let raw_reader = reader.into_inner();
+    let unique_images = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| &p.partition_name)
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    extract_and_verify_images(
+        &raw_reader,
+        &temp_dir,
+        pf_payload.offset,
+        pf_payload.size,
+        &header,
+        &unique_images,
+        cancel_signal,
+    )?;
+
+    report.partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .filter_map(|p| {
+            let hash = p.new_partition_info.as_ref()?.hash.as_ref()?;
+            Some((p.partition_name.clone(), hex::encode(hash)))
+        })
+        .collect();
+
+    status!("Checking ramdisk's otacerts.zip");
+
+    {
+        let required_images = RequiredImages::new(&header.manifest, classifier);
+        let boot_images =
+            boot::load_boot_images(&required_images.iter_boot().collect::<Vec<_>>(), |name| {
+                Ok(Box::new(
+                    temp_dir
+                        .open(format!("{name}.img"))
+                        .map(|f| PSeekFile::new(f.into_std()))?,
+                ))
+            })
+            .context("Failed to load all boot images")?;
+        let targets = OtaCertPatcher::new(ota_cert.clone())
+            .find_targets(&boot_images, cancel_signal)
+            .context("Failed to find boot image containing otacerts.zip")?;
+
+        if targets.is_empty() {
+            policy.fail(
+                Check::RamdiskOtacertsMissing,
+                "No boot image contains otacerts.zip. The system image may be the only copy",
+            )?;
+        }
+
+        for target in targets {
+            let boot_image = &boot_images[target].boot_image;
+            let ramdisk_certs = OtaCertPatcher::get_certificates(boot_image, cancel_signal)
+                .with_context(|| format!("Failed to read {target}'s otacerts.zip"))?;
+
+            report.otacerts.insert(
+                target.to_string(),
+                show_otacerts(target, &ramdisk_certs, &ota_cert)?,
+            );
+
+            if !ramdisk_certs.contains(&ota_cert) {
+                policy.fail(
+                    Check::RamdiskOtacertsMismatch,
+                    format!("{target}'s otacerts.zip does not contain OTA certificate"),
+                )?;
+            }
+        }
+
+        status!("Checking system's otacerts.zip");
+
+        for name in required_images.iter_system() {
+            let path = format!("{name}.img");
+            let file = temp_dir
+                .open(&path)
+                .map(|f| PSeekFile::new(f.into_std()))
+                .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+
+            let system_certs = match system::get_certificates(&file, cancel_signal) {
+                Ok(c) => c,
+                Err(system::Error::OldZipNotFound) => {
+                    policy.fail(
+                        Check::SystemOtacertsMissing,
+                        format!("{name} does not contain an uncompressed otacerts.zip"),
+                    )?;
+                    continue;
+                }
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to read {name}'s otacerts.zip"));
+                }
+            };
+
+            report.otacerts.insert(
+                name.to_owned(),
+                show_otacerts(name, &system_certs, &ota_cert)?,
+            );
+
+            if !system_certs.contains(&ota_cert) {
+                policy.fail(
+                    Check::SystemOtacertsMismatch,
+                    format!("{name}'s otacerts.zip does not contain OTA certificate"),
+                )?;
+            }
+        }
+    }
+
+    progress::stage("verify_avb_signatures");
+    status!("Verifying AVB signatures");
+
+    let public_key = if let Some(p) = &cli.public_key_avb {
+        let data = sandbox::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
+        let key = avb::decode_public_key(&data)
+            .with_context(|| format!("Failed to decode public key: {p:?}"))?;
+
+        Some(key)
+    } else {
+        None
+    };
+
+    let mut seen = HashSet::<String>::new();
+    let mut descriptors = HashMap::<String, Descriptor>::new();
+
+    cli::avb::verify_headers(
+        &temp_dir,
+        "vbmeta",
+        public_key.as_ref(),
+        None,
+        &mut seen,
+        &mut descriptors,
+    )?;
+    cli::avb::verify_descriptors(&temp_dir, &descriptors, false, None, cancel_signal)?;
+
+    for name in &seen {
+        let path = format!("{name}.img");
+        let raw_reader = temp_dir
+            .open(&path)
+            .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+        let (header, _, _) = avb::load_image(BufReader::new(raw_reader))
+            .with_context(|| format!("Failed to load vbmeta structures: {path:?}"))?;
+
+        report
+            .vbmeta
+            .insert(name.clone(), VbmetaReport::new(&header)?);
+    }
+
+    let unprotected = unique_images
+        .iter()
+        .filter(|n| !seen.contains(*n) && !descriptors.contains_key(*n))
+        .collect::<Vec<_>>();
+    if !unprotected.is_empty() {
+        policy.fail(
+            Check::UnprotectedPartitions,
+            format!("Partitions not protected by AVB: {}", joined(&unprotected)),
+        )?;
+    }
+
+    if let Some((path, file_digest)) = cache {
+        let entry = VerifyCacheEntry {
+            file_digest,
+            report: VerifyReport {
+                failed_checks: policy.failures(),
+                ..report.clone()
+            },
+        };
+
+        if let Err(e) = verify_cache_store(&path, &entry) {
+            warning!("Failed to update verification cache: {e:?}");
+        }
+    }
+
+    status!("Signatures are all valid!");
+
+    Ok(())
+}
+
+pub fn rollback_info_subcommand(cli: &RollbackInfoCli) -> Result<()> {
+    let raw_reader = sandbox::open(&cli.input)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader))
+        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+
+    let path = ota::PATH_ORIGINAL_MANIFEST;
+    let mut reader = match zip_reader.by_name(path) {
+        Ok(r) => r,
+        Err(zip::result::ZipError::FileNotFound) => {
+            bail!("OTA has no {path} entry; it was not patched with --keep-original-manifest")
+        }
+        Err(e) => return Err(e).with_context(|| format!("Failed to open zip entry: {path}")),
+    };
+    let mut buf = vec![];
+    reader
+        .read_to_end(&mut buf)
+        .with_context(|| format!("Failed to read original manifest: {path}"))?;
+    let manifest = ota::parse_original_manifest(&buf)
+        .with_context(|| format!("Failed to parse original manifest: {path}"))?;
+
+    println!("Device: {}", manifest.device.join(", "));
+    println!("Build fingerprint: {}", manifest.build.join(", "));
+    println!("Build incremental: {}", manifest.build_incremental);
+    println!("Security patch level: {}", manifest.security_patch_level);
+    println!("SDK level: {}", manifest.sdk_level);
+    println!();
+    println!("Original partitions:");
+
+    for (name, partition) in &manifest.partitions {
+        println!("  {name:<24} {:>12} {}", partition.size, partition.sha256);
+    }
+
+    println!();
+    println!(
+        "To roll back, flash the stock full OTA for build {}.",
+        manifest
+            .build
+            .first()
+            .unwrap_or(&manifest.build_incremental),
+    );
+
+    if let Some(stock) = &cli.stock {
+        let reader = sandbox::open(stock)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to open for reading: {stock:?}"))?;
+        let (_, _, header, _) = ota::parse_zip_ota_info(reader)
+            .with_context(|| format!("Failed to parse OTA: {stock:?}"))?;
+        let stock_manifest = ota::OriginalManifest::new(&OtaMetadata::default(), &header);
+
+        if stock_manifest.partitions != manifest.partitions {
+            let mismatched = manifest
+                .partitions
+                .keys()
+                .chain(stock_manifest.partitions.keys())
+                .filter(|n| manifest.partitions.get(*n) != stock_manifest.partitions.get(*n))
+                .collect::<BTreeSet<_>>();
+
+            bail!(
+                "Stock OTA does not match the original OTA: {stock:?}: {}",
+                joined(mismatched),
+            );
+        }
+
+        status!("Stock OTA matches the original OTA: {stock:?}");
+    }
+
+    Ok(())
+}
+
+pub fn inspect_subcommand(cli: &InspectCli) -> Result<()> {
+    let reader = OtaReader::open(&cli.input)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let extras = ota::read_zip_extras(reader)
+        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+
+    println!(
+        "Archive comment: {} bytes: {:?}",
+        extras.comment.len(),
+        String::from_utf8_lossy(&extras.comment),
+    );
+    println!(
+        "Whole-file signature: {}",
+        if extras.signed { "present" } else { "absent" },
+    );
+
+    match &extras.signing_block {
+        Some(pairs) => {
+            println!("APK signing block: {} pairs", pairs.len());
+
+            for (id, value) in pairs {
+                let description = ota::APK_SIG_BLOCK_TRANSIENT_IDS
+                    .iter()
+                    .find(|(i, _)| i == id)
+                    .map_or("Custom (kept by --signing-block preserve)", |(_, d)| d);
+
+                println!("  0x{id:08x} {:>10} bytes  {description}", value.len());
+            }
+        }
+        None => println!("APK signing block: absent"),
+    }
+
+    Ok(())
+}
+
+/// Subset of an OTA's [`DeviceState`] shown by `ota info`.
+#[derive(Debug, Serialize)]
+struct DeviceStateInfo {
+    device: Vec<String>,
+    build: Vec<String>,
+    build_incremental: String,
+    timestamp: i64,
+    sdk_level: String,
+    security_patch_level: String,
+}
+
+impl From<&DeviceState> for DeviceStateInfo {
+    fn from(state: &DeviceState) -> Self {
+        Self {
+            device: state.device.clone(),
+            build: state.build.clone(),
+            build_incremental: state.build_incremental.clone(),
+            timestamp: state.timestamp,
+            sdk_level: state.sdk_level.clone(),
+            security_patch_level: state.security_patch_level.clone(),
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+struct MetadataInfo {
+    #[serde(rename = "type")]
+    ota_type: String,
+    wipe: bool,
+    downgrade: bool,
+    spl_downgrade: bool,
+    precondition: Option<DeviceStateInfo>,
+    postcondition: Option<DeviceStateInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct PartitionInfo {
+    name: String,
+    size: Option<u64>,
+    sha256: Option<String>,
+    version: Option<String>,
+    /// Size of the partition's blob data in the payload.
+    data_size: u64,
+    /// Number of operations of each type.
+    operations: BTreeMap<&'static str, usize>,
+}
+
+#[derive(Debug, Serialize)]
+struct PayloadInfo {
+    version: u64,
+    minor_version: u32,
+    block_size: u32,
+    max_timestamp: Option<i64>,
+    security_patch_level: Option<String>,
+    partial_update: bool,
+    vabc_enabled: bool,
+    vabc_compression: Option<String>,
+    partitions: Vec<PartitionInfo>,
+}
+
+#[derive(Debug, Serialize)]
+struct OtaInfo {
+    metadata: Option<MetadataInfo>,
+    payload: PayloadInfo,
+}
+
+impl OtaInfo {
+    fn new(metadata: Option<&OtaMetadata>, header: &PayloadHeader) -> Self {
+        let metadata = metadata.map(|m| MetadataInfo {
+            ota_type: m.r#type().as_str_name().to_owned(),
+            wipe: m.wipe,
+            downgrade: m.downgrade,
+            spl_downgrade: m.spl_downgrade,
+            precondition: m.precondition.as_ref().map(DeviceStateInfo::from),
+            postcondition: m.postcondition.as_ref().map(DeviceStateInfo::from),
+        });
+
+        let manifest = &header.manifest;
+        let dynamic = manifest.dynamic_partition_metadata.as_ref();
+
+        let partitions = manifest
+            .partitions
+            .iter()
+            .map(|p| {
+                let mut operations = BTreeMap::new();
+                for op in &p.operations {
+                    *operations.entry(op.r#type().as_str_name()).or_default() += 1;
+                }
+
+                PartitionInfo {
+                    name: p.partition_name.clone(),
+                    size: p.new_partition_info.as_ref().and_then(|i| i.size),
+                    sha256: p
+                        .new_partition_info
+                        .as_ref()
+                        .and_then(|i| i.hash.as_ref())
+                        .map(hex::encode),
+                    version: p.version.clone(),
+                    data_size: partition_data_size(p),
+                    operations,
+                }
+            })
+            .collect();
+
+        Self {
+            metadata,
+            payload: PayloadInfo {
+                version: header.version,
+                minor_version: manifest.minor_version(),
+                block_size: manifest.block_size(),
+                max_timestamp: manifest.max_timestamp,
+                security_patch_level: manifest.security_patch_level.clone(),
+                partial_update: manifest.partial_update(),
+                vabc_enabled: dynamic.is_some_and(|d| d.vabc_enabled()),
+                vabc_compression: dynamic.and_then(|d| d.vabc_compression_param.clone()),
+                partitions,
+            },
+        }
+    }
+
+    fn display_device_state(title: &str, state: &DeviceStateInfo) {
+        println!("  {title}:");
+        println!("    Device: {}", state.device.join(", "));
+
+        let optional = [
+            ("Build fingerprint", state.build.join(", ")),
+            ("Build incremental", state.build_incremental.clone()),
+            ("Security patch level", state.security_patch_level.clone()),
+            ("SDK level", state.sdk_level.clone()),
+            (
+                "Timestamp",
+                if state.timestamp == 0 {
+                    String::new()
+                } else {
+                    state.timestamp.to_string()
+                },
+            ),
+        ];
+
+        for (name, value) in optional {
+            if !value.is_empty() {
+                println!("    {name}: {value}");
+            }
+        }
+    }
+
+    fn display(&self) {
+        if let Some(m) = &self.metadata {
+            println!("Metadata:");
+            println!("  Type: {}", m.ota_type);
+            println!("  Wipe: {}", m.wipe);
+            println!("  Downgrade: {}", m.downgrade);
+            println!("  SPL downgrade: {}", m.spl_downgrade);
+
+            if let Some(state) = &m.precondition {
+                Self::display_device_state("Precondition", state);
+            }
+            if let Some(state) = &m.postcondition {
+                Self::display_device_state("Postcondition", state);
+            }
+        } else {
+            println!("Metadata: absent");
+        }
+
+        let p = &self.payload;
+
+        println!();
+        println!("Payload:");
+        println!("  Version: {}", p.version);
+        println!(
+            "  Minor version: {} ({})",
+            p.minor_version,
+            if p.minor_version == 0 {
+                "full"
+            } else {
+                "delta"
+            },
+        );
+        println!("  Block size: {}", p.block_size);
+        if let Some(timestamp) = p.max_timestamp {
+            println!("  Max timestamp: {timestamp}");
+        }
+        if let Some(spl) = &p.security_patch_level {
+            println!("  Security patch level: {spl}");
+        }
+        println!("  Partial update: {}", p.partial_update);
+        println!(
+            "  Virtual A/B compression: {}",
+            if p.vabc_enabled {
+                p.vabc_compression.as_deref().unwrap_or("default")
+            } else {
+                "disabled"
+            }
+        );
+
+        println!();
+        println!("Partitions:");
+        println!(
+            "  {:<24} {:>12} {:>12}  {:<64}  OPERATIONS",
+            "NAME", "SIZE", "DATA", "SHA256",
+        );
+
+        for partition in &p.partitions {
+            let ops = partition
+                .operations
+                .iter()
+                .map(|(name, count)| format!("{name}={count}"))
+                .collect::<Vec<_>>()
+                .join(" ");
+
+            println!(
+                "  {:<24} {:>12} {:>12}  {:<64}  {ops}",
+                partition.name,
+                partition.size.map_or_else(|| "-".to_owned(), format_mib),
+                format_mib(partition.data_size),
+                partition.sha256.as_deref().unwrap_or("-"),
+            );
+        }
+    }
+}
+
+pub fn info_subcommand(cli: &InfoCli) -> Result<()> {
+    let reader = OtaReader::open(&cli.input)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let mut zip_reader =
+        ZipArchive::new(reader).with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+
+    let metadata = read_ota_metadata(&mut zip_reader)
+        .with_context(|| format!("Failed to read OTA metadata: {:?}", cli.input))?;
+
+    let header = {
+        let path = ota::PATH_PAYLOAD;
+        let reader = zip_reader
+            .by_name(path)
+            .with_context(|| format!("Failed to open zip entry: {path}"))?;
+
+        PayloadHeader::from_reader(reader).context("Failed to load OTA payload header")?
+    };
+
+    let info = OtaInfo::new(metadata.as_ref(), &header);
+
+    match cli.format {
+        OutputFormat::Text => info.display(),
+        OutputFormat::Json => {
+            let data =
+                serde_json::to_string_pretty(&info).context("Failed to serialize OTA info")?;
+            println!("{data}");
+        }
+    }
+
+    Ok(())
+}
+
+pub fn ota_main(cli: &OtaCli, cancel_signal: &AtomicBool) -> Result<()> {
+    match &cli.command {
+        OtaCommand::Patch(c) => patch_subcommand(c, cancel_signal),
+        OtaCommand::Revert(c) => revert_subcommand(c, cancel_signal),
+        OtaCommand::Extract(c) => extract_subcommand(c, cancel_signal),
+        OtaCommand::Verify(c) => verify_subcommand(c, cancel_signal),
+        OtaCommand::Download(c) => download::download_subcommand(c, cancel_signal),
+        OtaCommand::Fake(c) => fake::fake_subcommand(c, cancel_signal),
+        OtaCommand::Strip(c) => strip::strip_subcommand(c, cancel_signal),
+        OtaCommand::Unstrip(c) => strip::unstrip_subcommand(c, cancel_signal),
+        OtaCommand::FlashFastboot(c) => flash::flash_fastboot_subcommand(c, cancel_signal),
+        OtaCommand::Diff(c) => diff::diff_subcommand(c, cancel_signal),
+        OtaCommand::RollbackInfo(c) => rollback_info_subcommand(c),
+        OtaCommand::Inspect(c) => inspect_subcommand(c),
+        OtaCommand::Info(c) => info_subcommand(c),
+    }
+}
+
+// We currently use the `conflicts_with_all` option instead of `requires`
+// because the latter currently doesn't work when the dependent is an argument
+// inside a group: https://github.com/clap-rs/clap/issues/4707. Even if that
+// were fixed, the former option's error message is much more user friendly.
+
+const HEADING_PATH: &str = "Path options";
+const HEADING_KEY: &str = "Key options";
+const HEADING_MAGISK: &str = "Magisk patch options";
+const HEADING_KERNELSU: &str = "KernelSU patch options";
+const HEADING_PREPATCHED: &str = "Prepatched boot image options";
+const HEADING_OTHER: &str = "Other patch options";
+
+#[derive(Clone, Debug, Args)]
+#[group(required = true, multiple = false)]
+pub struct RootGroup {
+    /// Path to Magisk APK.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_MAGISK)]
+    pub magisk: Option<PathBuf>,
+
+    /// Path to KernelSU kernel module (LKM).
+    ///
+    /// The kernel module must be built for the KMI of the OTA's kernel, eg.
+    /// android14-6.1_kernelsu.ko. This requires --kernelsu-init.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_KERNELSU)]
+    pub kernelsu: Option<PathBuf>,
+
+    /// Path to prepatched boot image.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_PREPATCHED)]
+    pub prepatched: Option<PathBuf>,
+
+    /// Skip applying root patch.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub rootless: bool,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum XzCheckArg {
+    /// Use the same check type as the original payload.
+    #[default]
+    Auto,
+    None,
+    Crc32,
+    Crc64,
+    Sha256,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ZipCommentArg {
+    /// Use avbroot's default message.
+    #[default]
+    Default,
+    /// Keep the input OTA's archive comment.
+    Preserve,
+    /// Leave only the whole-file signature.
+    Strip,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum SigningBlockArg {
+    /// Do not write an APK signing block.
+    #[default]
+    Strip,
+    /// Keep the input OTA's APK signing block, minus any APK signatures.
+    Preserve,
+}
+
+impl XzCheckArg {
+    fn to_check(self) -> Option<XzCheck> {
+        match self {
+            Self::Auto => None,
+            Self::None => Some(XzCheck::None),
+            Self::Crc32 => Some(XzCheck::Crc32),
+            Self::Crc64 => Some(XzCheck::Crc64),
+            Self::Sha256 => Some(XzCheck::Sha256),
+        }
+    }
+}
+
+/// Patch a full OTA zip.
+#[derive(Clone, Debug, Parser)]
+pub struct PatchCli {
+    /// Patch to original OTA zip.
+    ///
+    /// This can also be an http:// or https:// URL. The OTA is then read via
+    /// HTTP range requests instead of being downloaded in full first. The
+    /// server must support range requests.
+    ///
+    /// This can also be a directory containing a bare payload.bin alongside
+    /// the metadata and/or metadata.pb files from META-INF/com/android/. A
+    /// payload_properties.txt file is optional since it is regenerated.
+    #[arg(short, long, value_name = "FILE", value_parser, help_heading = HEADING_PATH)]
+    pub input: PathBuf,
+
+    /// Path to new OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser, help_heading = HEADING_PATH)]
+    pub output: Option<PathBuf>,
+
+    /// Template for the name of the new OTA zip.
+    ///
+    /// The result is relative to the directory containing the input OTA.
+    /// Supported variables are {name} and {stem} (input file name with and
+    /// without .zip), {device}, {fingerprint}, {incremental}, {spl}, and {sdk}
+    /// (from the OTA metadata), {date} (UTC, YYYYMMDD), {root} (magisk,
+    /// kernelsu, prepatched, or rootless), and {magisk_version}. Use {{ and }} for
+    /// literal braces. Slashes, backslashes, and colons in the values are
+    /// replaced with underscores. The result must not be absolute or contain .
+    /// or .. components.
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        conflicts_with = "output",
+        help_heading = HEADING_PATH
+    )]
+    pub output_template: Option<String>,
+
+    /// Private key for signing vbmeta images.
+    #[arg(
+        long,
+        alias = "privkey-avb",
+        value_name = "FILE",
+        value_parser,
+        help_heading = HEADING_KEY
+    )]
+    pub key_avb: PathBuf,
+
+    /// AVB signing algorithm for vbmeta images.
+    ///
+    /// The algorithm must match the size of --key-avb. By default, the
+    /// algorithm is chosen based on the key size and a SHA512 digest is kept
+    /// for images that originally used one.
+    #[arg(long, value_name = "ALGORITHM", value_enum, help_heading = HEADING_KEY)]
+    pub avb_algorithm: Option<AvbAlgorithmArg>,
+
+    /// Private key for signing the OTA.
+    #[arg(
+        long,
+        alias = "privkey-ota",
+        value_name = "FILE",
+        value_parser,
+        help_heading = HEADING_KEY
+    )]
+    pub key_ota: PathBuf,
+
+    /// Certificate for OTA signing key.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_KEY)]
+    pub cert_ota: PathBuf,
+
+    /// Environment variable containing AVB private key passphrase.
+    #[arg(
+        long,
+        alias = "passphrase-avb-env-var",
+        value_name = "ENV_VAR",
+        value_parser,
+        group = "pass_avb",
+        help_heading = HEADING_KEY
+    )]
+    pub pass_avb_env_var: Option<OsString>,
+
+    /// File containing AVB private key passphrase.
+    #[arg(
+        long,
+        alias = "passphrase-avb-file",
+        value_name = "FILE",
+        value_parser,
+        group = "pass_avb",
+        help_heading = HEADING_KEY
+    )]
+    pub pass_avb_file: Option<PathBuf>,
+
+    /// Environment variable containing OTA private key passphrase.
+    #[arg(
+        long,
+        alias = "passphrase-ota-env-var",
+        value_name = "ENV_VAR",
+        value_parser,
+        group = "pass_ota",
+        help_heading = HEADING_KEY
+    )]
+    pub pass_ota_env_var: Option<OsString>,
+
+    /// File containing OTA private key passphrase.
+    #[arg(
+        long,
+        alias = "passphrase-ota-file",
+        value_name = "FILE",
+        value_parser,
+        group = "pass_ota",
+        help_heading = HEADING_KEY
+    )]
+    pub pass_ota_file: Option<PathBuf>,
+
+    /// Secondary private key for signing the OTA.
+    ///
+    /// The payload and the whole-file signature will be signed with both keys.
+    /// This is useful during key migration periods where devices may trust
+    /// either key. The otacerts.zip files will only contain the primary
+    /// certificate.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        requires = "cert_ota_secondary",
+        help_heading = HEADING_KEY
+    )]
+    pub key_ota_secondary: Option<PathBuf>,
+
+    /// Certificate for secondary OTA signing key.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        requires = "key_ota_secondary",
+        help_heading = HEADING_KEY
+    )]
+    pub cert_ota_secondary: Option<PathBuf>,
+
+    /// Environment variable containing secondary OTA private key passphrase.
+    #[arg(
+        long,
+        value_name = "ENV_VAR",
+        value_parser,
+        group = "pass_ota_secondary",
+        requires = "key_ota_secondary",
+        help_heading = HEADING_KEY
+    )]
+    pub pass_ota_secondary_env_var: Option<OsString>,
+
+    /// File containing secondary OTA private key passphrase.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        group = "pass_ota_secondary",
+        requires = "key_ota_secondary",
+        help_heading = HEADING_KEY
+    )]
+    pub pass_ota_secondary_file: Option<PathBuf>,
+
+    /// Write the digests that need to be signed to a file.
+    ///
+    /// This enables detached signing, where the private keys are kept on a
+    /// separate (eg. air-gapped) machine. --key-avb, --key-ota, and
+    /// --key-ota-secondary must then refer to public keys, certificates, or
+    /// AVB-encoded public keys instead of private keys. The requests can be
+    /// signed with `avbroot key sign-requests` and passed back in via
+    /// --apply-signatures.
+    ///
+    /// Since some signed data contains other signatures, this process needs to
+    /// be repeated until no more signatures are missing. The output file is
+    /// only written once every signature is available.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_KEY)]
+    pub emit_signing_requests: Option<PathBuf>,
+
+    /// Use signatures from a file created by `avbroot key sign-requests`.
+    ///
+    /// This option can be specified multiple times and enables detached
+    /// signing. See --emit-signing-requests.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_KEY)]
+    pub apply_signatures: Vec<PathBuf>,
+
+    /// Command for creating signatures.
+    ///
+    /// This allows signing with an external service without ever exposing the
+    /// private keys to avbroot. --key-avb, --key-ota, and --key-ota-secondary
+    /// must then refer to public keys, certificates, or AVB-encoded public keys
+    /// instead of private keys.
+    ///
+    /// For each signature, the command is run with the raw digest on stdin and
+    /// must write the raw PKCS#1 v1.5 signature to stdout. The request is
+    /// described by the AVBROOT_SIGN_PURPOSE, AVBROOT_SIGN_ALGORITHM,
+    /// AVBROOT_SIGN_DIGEST, and AVBROOT_SIGN_KEY (sha256 of the public key)
+    /// environment variables. Every signature is verified before it is used.
+    /// This cannot be used with --harden, which blocks running programs.
+    #[arg(
+        long,
+        value_name = "PROGRAM",
+        value_parser,
+        conflicts_with_all = ["emit_signing_requests", "apply_signatures"],
+        help_heading = HEADING_KEY
+    )]
+    pub signer_cmd: Option<PathBuf>,
+
+    /// Fail if the same key is used for AVB and OTA signing.
+    ///
+    /// Using separate keys limits the damage if one of them is compromised.
+    /// By default, key reuse only results in a warning.
+    #[arg(long, help_heading = HEADING_KEY)]
+    pub forbid_key_reuse: bool,
+
+    /// Use partition image from a file instead of the original payload.
+    ///
+    /// The source can also be a partition in another OTA zip, specified as
+    /// `<zip>:<partition>`, in which case the image is extracted from that OTA's
+    /// payload.
+    ///
+    /// If the partition is protected by a hash descriptor in a vbmeta image,
+    /// the replacement does not need to have a signed AVB footer. The digest is
+    /// recomputed automatically. Similarly, if the partition is protected by a
+    /// hash tree descriptor, the replacement can be a raw filesystem image and
+    /// the hash tree and AVB footer are generated automatically.
+    #[arg(
+        long,
+        value_names = ["PARTITION", "FILE"],
+        value_parser = value_parser!(OsString),
+        num_args = 2,
+        help_heading = HEADING_PATH,
+    )]
+    pub replace: Vec<OsString>,
+
+    /// Use the currently installed image as the source for a partition.
+    ///
+    /// Some OTAs contain SOURCE_COPY operations, which copy data from the
+    /// partition's previous contents, even for partitions that avbroot needs
+    /// to patch. If such a partition's image is provided with this option, it
+    /// is reconstructed by copying the data from this file. Partitions that
+    /// are not patched are always copied to the new OTA as is.
+    #[arg(
+        long,
+        value_names = ["PARTITION", "FILE"],
+        value_parser = value_parser!(OsString),
+        num_args = 2,
+        help_heading = HEADING_PATH,
+    )]
+    pub source_image: Vec<OsString>,
+
+    /// Replace the system partition with a generic system image (GSI).
+    ///
+    /// Unlike `--replace system`, the GSI does not need a valid AVB footer.
+    /// Any existing footer is discarded and a new hash tree is generated with
+    /// the same parameters as the stock system image. The vbmeta image that
+    /// covers the system partition is updated and re-signed, and dynamic
+    /// partition groups are grown if the GSI is larger than the stock image.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_PATH)]
+    pub gsi: Option<PathBuf>,
+
+    #[command(flatten)]
+    pub root: RootGroup,
+
+    /// Magisk preinit block device (version >=25211 only).
+    #[arg(
+        long,
+        value_name = "PARTITION",
+        conflicts_with_all = ["kernelsu", "prepatched", "rootless"],
+        help_heading = HEADING_MAGISK
+    )]
+    pub magisk_preinit_device: Option<String>,
+
+    /// Boot image partition to apply Magisk to.
+    ///
+    /// By default, `init_boot` is patched if it exists and `boot` is patched
+    /// otherwise, unless `boot` has no ramdisk and `recovery` exists. This
+    /// option is needed for devices where that choice is wrong. Any partition
+    /// classified as a boot image (see --classify) can be specified.
+    #[arg(
+        long,
+        value_name = "PARTITION",
+        conflicts_with_all = ["kernelsu", "prepatched", "rootless"],
+        help_heading = HEADING_MAGISK
+    )]
+    pub magisk_target: Option<String>,
+
+    /// Path to custom Magisk compatibility database.
+    ///
+    /// This is a TOML file that lists which Magisk versions are supported,
+    /// which options they require, and which Android versions they support.
+    /// By default, the database built into avbroot is used.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        conflicts_with_all = ["kernelsu", "prepatched", "rootless"],
+        help_heading = HEADING_MAGISK
+    )]
+    pub magisk_compat_db: Option<PathBuf>,
+
+    /// Magisk random seed (version >=25211, <26103 only).
+    #[arg(
+        long,
+        value_name = "NUMBER",
+        conflicts_with_all = ["kernelsu", "prepatched", "rootless"],
+        help_heading = HEADING_MAGISK
+    )]
+    pub magisk_random_seed: Option<u64>,
+
+    /// Ignore Magisk compatibility/version warnings.
+    #[arg(
+        long,
+        conflicts_with_all = ["kernelsu", "prepatched", "rootless"],
+        help_heading = HEADING_MAGISK
+    )]
+    pub ignore_magisk_warnings: bool,
+
+    /// Ignore compatibility issues with prepatched boot images.
+    #[arg(
+        long,
+        action = ArgAction::Count,
+        conflicts_with_all = ["magisk", "kernelsu", "rootless"],
+        help_heading = HEADING_PREPATCHED
+    )]
+    pub ignore_prepatched_compat: u8,
+
+    /// Path to KernelSU ksuinit binary.
+    ///
+    /// This replaces the ramdisk's init and loads the KernelSU kernel module
+    /// before executing the original init.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        conflicts_with_all = ["magisk", "prepatched", "rootless"],
+        help_heading = HEADING_KERNELSU
+    )]
+    pub kernelsu_init: Option<PathBuf>,
+
+    /// Ignore KMI mismatches between the KernelSU kernel module and the kernel.
+    #[arg(
+        long,
+        conflicts_with_all = ["magisk", "prepatched", "rootless"],
+        help_heading = HEADING_KERNELSU
+    )]
+    pub ignore_kernelsu_kmi: bool,
+
+    /// Device codename to apply the patch preset for.
+    ///
+    /// The preset fills in known device quirks, like the Magisk preinit device
+    /// and otacerts.zip locations, unless they are explicitly specified. The
+    /// OTA is also validated against the preset, including checking that the
+    /// OTA metadata lists the device.
+    #[arg(long, value_name = "CODENAME", help_heading = HEADING_OTHER)]
+    pub device: Option<String>,
+
+    /// Path to custom device database.
+    ///
+    /// Entries in this TOML file are added to the built-in device database.
+    /// They replace built-in entries with the same codename.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        requires = "device",
+        help_heading = HEADING_OTHER
+    )]
+    pub device_db: Option<PathBuf>,
+
+    /// Name of the profile to load patch options from.
+    ///
+    /// Profiles are defined in the config file as `[profile.<name>]` tables
+    /// that map long option names to values, eg. `key-avb = "avb.key"`. Flags
+    /// are enabled with `true`, options that can be specified multiple times
+    /// take lists, and --replace and --source-image take tables. Options that
+    /// are specified on the command line take precedence over the profile.
+    #[arg(long, value_name = "NAME", help_heading = HEADING_OTHER)]
+    pub profile: Option<String>,
+
+    /// Path to config file containing profiles.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        default_value = "avbroot.toml",
+        requires = "profile",
+        help_heading = HEADING_OTHER
+    )]
+    pub config: PathBuf,
+
+    /// Partition containing otacerts.zip to patch.
+    ///
+    /// By default, every boot image (boot, init_boot, recovery, vendor_boot)
+    /// with a ramdisk containing otacerts.zip is patched and patching fails if
+    /// there are none. If this option is specified, only the listed partitions
+    /// are patched. For devices that only store otacerts.zip in the system
+    /// image, specify `system`. The system image's otacerts.zip is always
+    /// patched. This option can be specified multiple times.
+    #[arg(long, value_name = "PARTITION", help_heading = HEADING_OTHER)]
+    pub otacerts_target: Vec<String>,
+
+    /// Program for patching boot images.
+    ///
+    /// The boot image is unpacked to a temporary directory with the same file
+    /// names that `avbroot boot unpack` uses, the program is run with the
+    /// directory as its only argument, and the directory is packed back into a
+    /// boot image, which is then re-signed. By default, the image that the root
+    /// patch targets is patched. This runs after the root patch, if any.
+    #[arg(long, value_name = "PROGRAM", value_parser, help_heading = HEADING_OTHER)]
+    pub custom_boot_patcher: Option<PathBuf>,
+
+    /// Boot image partition to patch with --custom-boot-patcher.
+    ///
+    /// This option can be specified multiple times.
+    #[arg(
+        long,
+        value_name = "PARTITION",
+        requires = "custom_boot_patcher",
+        help_heading = HEADING_OTHER
+    )]
+    pub custom_boot_patcher_target: Vec<String>,
+
+    /// Rename a partition in the output OTA.
+    ///
+    /// The partition is renamed in the payload manifest, the dynamic partition
+    /// groups, and every vbmeta descriptor that refers to it. The vbmeta images
+    /// containing those descriptors are re-signed. All other options, like
+    /// --replace, refer to the new name. Partitions that are chain loaded by a
+    /// vbmeta image cannot be renamed. This option can be specified multiple
+    /// times.
+    #[arg(long, value_name = "OLD=NEW", help_heading = HEADING_OTHER)]
+    pub map_partition: Vec<String>,
+
+    /// Override how a partition is classified.
+    ///
+    /// By default, boot, init_boot, recovery, and vendor_boot are boot images,
+    /// system is the system image, and partitions starting with `vbmeta` are
+    /// vbmeta images. For devices with nonstandard partition names, this
+    /// option assigns a partition to one of `boot`, `system`, `vbmeta`, or
+    /// `other`. This option can be specified multiple times.
+    #[arg(long, value_name = "PARTITION=CLASS", help_heading = HEADING_OTHER)]
+    pub classify: Vec<String>,
 
-        let Some(data_length) = operation.data_length else {
-            // Otherwise, this is a ZERO/DISCARD operation.
-            continue;
-        };
+    /// Forcibly clear vbmeta flags if they disable AVB.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub clear_vbmeta_flags: bool,
 
-        let pi = payload_writer.partition_index().unwrap();
-        let oi = payload_writer.operation_index().unwrap();
-        let orig_partition = &header_locked.manifest.partitions[pi];
-        let orig_operation = &orig_partition.operations[oi];
-        let data_offset = orig_operation
-            .data_offset
-            .ok_or_else(|| anyhow!("Missing data_offset in partition #{pi} operation #{oi}"))?;
+    /// Name of the root vbmeta partition.
+    ///
+    /// Some OTAs contain unused vbmeta images that are never loaded by the
+    /// bootloader, which makes it ambiguous which vbmeta image is the root of
+    /// trust. This option selects the one that the bootloader actually loads.
+    /// The other root vbmeta images are still patched unless
+    /// --skip-other-vbmeta-roots is specified.
+    #[arg(long, value_name = "PARTITION", help_heading = HEADING_OTHER)]
+    pub vbmeta_root: Option<String>,
+
+    /// Leave the other root vbmeta images unmodified.
+    ///
+    /// Any vbmeta images that are only referenced by the other roots are left
+    /// unmodified too. Since they are not loaded by the bootloader, their
+    /// outdated descriptors have no effect.
+    #[arg(long, requires = "vbmeta_root", help_heading = HEADING_OTHER)]
+    pub skip_other_vbmeta_roots: bool,
 
-        // Try to copy from our replacement image. The compressed chunks are
-        // laid out sequentially and data_offset is set to the offset within
-        // that file.
-        if let Some((input_file, modified_operations)) = compressed_files.get_mut(&name) {
-            if util::ranges_contains(modified_operations, &oi) {
-                input_file
-                    .file
-                    .seek(SeekFrom::Start(data_offset))
-                    .with_context(|| format!("Failed to seek image: {name}"))?;
+    /// Fail if a vbmeta image cannot be written back out byte-for-byte.
+    ///
+    /// Unknown descriptors, descriptor ordering, the release string, and
+    /// reserved fields are always preserved. However, some details, like
+    /// non-zero descriptor padding, are normalized when vbmeta images are
+    /// re-signed. This option rejects such images instead, which guarantees
+    /// that the only changes are the ones made by avbroot. This matters for
+    /// devices with bootloaders that are picky about the exact layout.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub strict_vbmeta: bool,
 
-                stream::copy_n(
-                    &mut input_file.file,
-                    &mut payload_writer,
-                    data_length,
-                    cancel_signal,
-                )
-                .with_context(|| format!("Failed to copy from replacement image: {name}"))?;
+    /// Store all-zero chunks as ZERO operations when compressing partition
+    /// images.
+    ///
+    /// ZERO operations have no data, so this makes the OTA smaller and faster
+    /// to install than compressing the zeros.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub zero_chunks: bool,
 
-                continue;
-            }
-        }
+    /// Store chunks above this entropy threshold without compression.
+    ///
+    /// The threshold is in bits per byte and must be between 0 and 8. Chunks
+    /// of already-compressed data, like APKs, usually have an entropy close to
+    /// 8 and are stored as REPLACE operations instead of REPLACE_XZ operations
+    /// to avoid wasting CPU time. By default, all chunks are compressed.
+    #[arg(long, value_name = "BITS", help_heading = HEADING_OTHER)]
+    pub compression_skip_entropy: Option<f64>,
+
+    /// XZ compression level for recompressed partition images.
+    ///
+    /// The default of 0 is the fastest and is usually good enough because most
+    /// of the savings come from compressing zeros. Higher levels produce
+    /// slightly smaller OTAs at the cost of much more CPU time.
+    #[arg(
+        long,
+        value_name = "LEVEL",
+        default_value_t = 0,
+        value_parser = value_parser!(u32).range(0..=9),
+        help_heading = HEADING_OTHER
+    )]
+    pub xz_level: u32,
 
-        // Otherwise, copy from the original payload.
-        let data_offset = data_offset
-            .checked_add(header_locked.blob_offset)
-            .ok_or_else(|| anyhow!("data_offset overflow in partition #{pi} operation #{oi}"))?;
+    /// Use the slower "extreme" variant of the XZ compression level.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub xz_extreme: bool,
 
-        orig_payload_reader
-            .seek(SeekFrom::Start(data_offset))
-            .with_context(|| format!("Failed to seek original payload to {data_offset}"))?;
+    /// XZ integrity check type for recompressed partition images.
+    ///
+    /// By default, the check type of the original payload is used. AOSP's
+    /// update_engine only supports none and crc32, but some OEM builds require
+    /// crc32.
+    #[arg(
+        long,
+        value_name = "CHECK",
+        value_enum,
+        default_value_t = XzCheckArg::Auto,
+        help_heading = HEADING_OTHER
+    )]
+    pub xz_check: XzCheckArg,
 
-        stream::copy_n(
-            &mut orig_payload_reader,
-            &mut payload_writer,
-            data_length,
-            cancel_signal,
-        )
-        .with_context(|| format!("Failed to copy from original payload: {name}"))?;
-    }
+    /// XZ dictionary size in bytes for recompressed partition images.
+    ///
+    /// By default, the dictionary size of the XZ compression level is used,
+    /// but it is capped to the size of each chunk (2 MiB) and to the
+    /// dictionary size used by the original payload. Some OEM update_engine
+    /// builds reject larger dictionaries than what they were built for.
+    #[arg(
+        long,
+        value_name = "BYTES",
+        value_parser = value_parser!(u32).range(4096..=64 * 1024 * 1024),
+        help_heading = HEADING_OTHER
+    )]
+    pub xz_dict_size: Option<u32>,
 
-    let (_, properties, metadata_size) = payload_writer
-        .finish()
-        .context("Failed to finalize payload")?;
+    /// Print a report of the size changes after patching.
+    ///
+    /// This shows the image and payload data size changes for each modified
+    /// partition, a rough estimate of the install time, and the snapshot space
+    /// required on Virtual A/B devices.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub size_report: bool,
 
-    Ok((properties, metadata_size))
-}
+    /// Write a JSON report of what was changed to this file.
+    ///
+    /// This lists every partition that differs from the input OTA, the
+    /// patchers that were applied to it, and its AVB digests before and after
+    /// patching. It also includes the rollback index and flags of each patched
+    /// vbmeta image and the fingerprints of the certificates that signed the
+    /// output OTA. The report is written after the output OTA.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_OTHER)]
+    pub patch_report: Option<PathBuf>,
+
+    /// Print the patch plan before patching.
+    ///
+    /// This shows which images will be read, which patchers will be applied to
+    /// each boot image, the vbmeta dependency graph and patching order, and
+    /// which partitions will be recompressed or copied from the original
+    /// payload as-is. It also includes rough estimates of the peak memory and
+    /// temporary disk usage.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub print_plan: bool,
 
-#[allow(clippy::too_many_arguments)]
-fn patch_ota_zip(
-    raw_reader: &PSeekFile,
-    zip_reader: &mut ZipArchive<impl Read + Seek>,
-    mut zip_writer: &mut ZipWriter<impl Write>,
-    external_images: &HashMap<String, PathBuf>,
-    mut root_patch: Option<Box<dyn BootImagePatch + Sync>>,
-    clear_vbmeta_flags: bool,
-    key_avb: &RsaPrivateKey,
-    key_ota: &RsaPrivateKey,
-    cert_ota: &Certificate,
-    cancel_signal: &AtomicBool,
-) -> Result<(OtaMetadata, u64)> {
-    let mut missing = BTreeSet::from([ota::PATH_OTACERT, ota::PATH_PAYLOAD, ota::PATH_PROPERTIES]);
+    /// Recompress partitions that use REPLACE_BZ operations.
+    ///
+    /// Some older OTAs store partitions with bzip2 compression, which is larger
+    /// and slower to install than XZ. With this option, those partitions are
+    /// recompressed with the same settings as patched partitions.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub transcode_ops: bool,
 
-    // Keep in sorted order for reproducibility and to guarantee that the
-    // payload is processed before its properties file.
-    let paths = zip_reader
-        .file_names()
-        .map(|p| p.to_owned())
-        .collect::<BTreeSet<_>>();
+    /// Directory for caching unmodified payload data between runs.
+    ///
+    /// The data of every operation that is copied as-is from the input OTA is
+    /// stored in this directory, keyed by its sha256 digest. When patching a
+    /// later OTA that shares partition data, the data is read from the cache
+    /// instead of the input OTA. Entries are never removed automatically.
+    #[arg(long, value_name = "DIR", value_parser, help_heading = HEADING_OTHER)]
+    pub blob_cache: Option<PathBuf>,
+
+    /// Add an avbroot.json entry describing how the OTA was patched.
+    ///
+    /// This records the avbroot version, the patch options, the digest of the
+    /// input OTA, and the boot image patchers that were applied. Like every
+    /// other entry, it is covered by the whole-file signature.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub provenance: bool,
 
-    for path in &paths {
-        missing.remove(path.as_str());
-    }
+    /// Skip patching if the output is already up to date.
+    ///
+    /// The output is considered up to date if its avbroot.json entry matches
+    /// the current input OTA, options, referenced files, and keys, and its
+    /// whole-file signature is still valid. Otherwise, the OTA is patched as
+    /// usual.
+    #[arg(long, requires = "provenance", help_heading = HEADING_OTHER)]
+    pub skip_unchanged: bool,
+
+    /// Add an avbroot.original.json entry describing the original OTA.
+    ///
+    /// This records the stock build fingerprint, security patch level, and the
+    /// digests of the original partition images so that `ota rollback-info`
+    /// can later report which stock OTA to flash to undo the patches.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub keep_original_manifest: bool,
 
-    if !missing.is_empty() {
-        bail!("Missing entries in OTA zip: {:?}", joined(missing));
-    } else if !paths.contains(ota::PATH_METADATA) && !paths.contains(ota::PATH_METADATA_PB) {
-        bail!(
-            "Neither legacy nor protobuf OTA metadata files exist: {:?}, {:?}",
-            ota::PATH_METADATA,
-            ota::PATH_METADATA_PB,
-        )
-    }
+    /// How to handle the input OTA's archive comment.
+    ///
+    /// The archive comment always ends in the whole-file signature. This only
+    /// controls the text that precedes it. Some vendors store information in
+    /// this part of the comment.
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_enum,
+        default_value_t = ZipCommentArg::Default,
+        conflicts_with = "zip_comment_file",
+        help_heading = HEADING_OTHER
+    )]
+    pub zip_comment: ZipCommentArg,
 
-    let mut metadata = None;
-    let mut properties = None;
-    let mut payload_metadata_size = None;
-    let mut entries = vec![];
-    let mut last_entry_used_zip64 = false;
+    /// Replace the archive comment with the contents of a file.
+    ///
+    /// Like with --zip-comment, the whole-file signature is appended after it.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_OTHER)]
+    pub zip_comment_file: Option<PathBuf>,
 
-    for path in &paths {
-        let mut reader = zip_reader
-            .by_name(path)
-            .with_context(|| format!("Failed to open zip entry: {path}"))?;
+    /// How to handle the input OTA's APK signing block.
+    ///
+    /// Some vendors store custom data in an APK signing block in front of the
+    /// zip central directory. When preserving it, APK signatures, source
+    /// stamps, and padding are dropped because they are invalid once the OTA
+    /// is modified.
+    #[arg(
+        long,
+        value_name = "MODE",
+        value_enum,
+        default_value_t = SigningBlockArg::Strip,
+        conflicts_with = "signing_block_file",
+        help_heading = HEADING_OTHER
+    )]
+    pub signing_block: SigningBlockArg,
 
-        // Android's libarchive parser is broken and only reads data descriptor
-        // size fields as 64-bit integers if the central directory says the file
-        // size is >= 2^32 - 1. We'll turn on zip64 if the input is above this
-        // threshold. This should be sufficient since the output file is likely
-        // to be larger.
-        let use_zip64 = reader.size() >= 0xffffffff;
-        let options = FileOptions::default()
-            .compression_method(CompressionMethod::Stored)
-            .large_file(use_zip64);
+    /// Insert a raw APK signing block from a file.
+    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_OTHER)]
+    pub signing_block_file: Option<PathBuf>,
 
-        // Processed at the end after all other entries are written.
-        match path.as_str() {
-            // Convert legacy metadata from Android 11 to the modern protobuf
-            // structure. Note that although we can read legacy-only OTAs, we
-            // always produce both the legacy and protobuf representations in
-            // the output.
-            ota::PATH_METADATA => {
-                let mut buf = String::new();
-                reader
-                    .read_to_string(&mut buf)
-                    .with_context(|| format!("Failed to read OTA metadata: {path}"))?;
-                metadata = Some(
-                    ota::parse_legacy_metadata(&buf)
-                        .with_context(|| format!("Failed to parse OTA metadata: {path}"))?,
-                );
-                continue;
-            }
-            // This takes precedence due to sorted iteration order.
-            ota::PATH_METADATA_PB => {
-                let mut buf = vec![];
-                reader
-                    .read_to_end(&mut buf)
-                    .with_context(|| format!("Failed to read OTA metadata: {path}"))?;
-                metadata = Some(
-                    ota::parse_protobuf_metadata(&buf)
-                        .with_context(|| format!("Failed to parse OTA metadata: {path}"))?,
-                );
-                continue;
-            }
-            _ => {}
-        }
+    /// Patch the OTA even if it appears to already be patched.
+    ///
+    /// Patching an OTA that was produced by avbroot stacks patches on top of
+    /// each other, which usually results in a boot loop.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub force: bool,
 
-        // All remaining entries are written immediately.
-        zip_writer
-            .start_file_with_extra_data(path, options)
-            .with_context(|| format!("Failed to begin new zip entry: {path}"))?;
-        let offset = zip_writer
-            .end_extra_data()
-            .with_context(|| format!("Failed to end new zip entry: {path}"))?;
-        let mut writer = CountingWriter::new(&mut zip_writer);
+    /// Security patch level of the build installed on the device.
+    ///
+    /// The value is in the YYYY-MM-DD format. Patching fails if the OTA has
+    /// an older security patch level because rollback protection would
+    /// prevent the device from booting it.
+    #[arg(
+        long,
+        value_name = "DATE",
+        conflicts_with = "current_spl_from_device",
+        help_heading = HEADING_OTHER
+    )]
+    pub current_spl: Option<String>,
 
-        match path.as_str() {
-            ota::PATH_OTACERT => {
-                // Use the user's certificate
-                status!("Replacing zip entry: {path}");
+    /// Read the installed security patch level from a device via adb.
+    ///
+    /// This is the same as --current-spl, except the value is read from the
+    /// ro.build.version.security_patch property of the connected device.
+    #[arg(long, help_heading = HEADING_OTHER)]
+    pub current_spl_from_device: bool,
 
-                crypto::write_pem_cert(&mut writer, cert_ota)
-                    .with_context(|| format!("Failed to write entry: {path}"))?;
-            }
-            ota::PATH_PAYLOAD => {
-                status!("Patching zip entry: {path}");
+    /// Path to adb executable.
+    #[arg(
+        long,
+        value_name = "FILE",
+        value_parser,
+        default_value = "adb",
+        requires = "current_spl_from_device",
+        help_heading = HEADING_OTHER
+    )]
+    pub adb: PathBuf,
 
-                if reader.compression() != CompressionMethod::Stored {
-                    bail!("{path} is not stored uncompressed");
-                }
+    /// Serial number of device to read the security patch level from.
+    #[arg(
+        long,
+        value_name = "SERIAL",
+        requires = "current_spl_from_device",
+        help_heading = HEADING_OTHER
+    )]
+    pub adb_serial: Option<String>,
 
-                // The zip library doesn't provide us with a seekable reader, so
-                // we make our own from the underlying file.
-                let payload_reader = SectionReader::new(
-                    BufReader::new(raw_reader.reopen()?),
-                    reader.data_start(),
-                    reader.size(),
-                )?;
+    /// Command for unwrapping an OEM container around payload.bin.
+    ///
+    /// The command is run with two arguments: the path to the raw payload.bin
+    /// entry data and the path where the plain payload should be written.
+    #[arg(long, value_name = "PROGRAM", value_parser, help_heading = HEADING_OTHER)]
+    pub payload_decrypt_cmd: Option<PathBuf>,
 
-                let (p, m) = patch_ota_payload(
-                    &payload_reader,
-                    &mut writer,
-                    external_images,
-                    // There's only one payload in the OTA.
-                    root_patch.take(),
-                    clear_vbmeta_flags,
-                    key_avb,
-                    key_ota,
-                    cert_ota,
-                    cancel_signal,
-                )
-                .with_context(|| format!("Failed to patch payload: {path}"))?;
+    /// Command for rewrapping the patched payload.bin in an OEM container.
+    ///
+    /// The command is run with two arguments: the path to the patched plain
+    /// payload and the path where the wrapped data should be written. If this
+    /// is omitted, the plain payload is stored in the output OTA.
+    #[arg(
+        long,
+        value_name = "PROGRAM",
+        value_parser,
+        requires = "payload_decrypt_cmd",
+        help_heading = HEADING_OTHER
+    )]
+    pub payload_encrypt_cmd: Option<PathBuf>,
 
-                properties = Some(p);
-                payload_metadata_size = Some(m);
-            }
-            ota::PATH_PROPERTIES => {
-                status!("Patching zip entry: {path}");
+    #[command(flatten)]
+    pub notify: NotifyGroup,
 
-                // payload.bin is guaranteed to be patched first.
-                writer
-                    .write_all(properties.as_ref().unwrap().as_bytes())
-                    .with_context(|| format!("Failed to write payload properties: {path}"))?;
-            }
-            _ => {
-                status!("Copying zip entry: {path}");
+    /// (Deprecated: no longer needed)
+    #[arg(
+        long,
+        value_name = "PARTITION",
+        help_heading = HEADING_OTHER
+    )]
+    pub boot_partition: Option<String>,
 
-                stream::copy(&mut reader, &mut writer, cancel_signal)
-                    .with_context(|| format!("Failed to copy zip entry: {path}"))?;
-            }
-        }
+    // Set by `ota revert` to remove root from an already-patched OTA.
+    #[arg(skip)]
+    pub revert: bool,
 
-        // Cannot fail.
-        let size = writer.stream_position()?;
+    // Set by `ota revert --allow-multiple-signers`.
+    #[arg(skip)]
+    pub allow_multiple_input_signers: bool,
+}
 
-        entries.push(ZipEntry {
-            name: path.clone(),
-            offset,
-            size,
-        });
+/// Remove root from an OTA that was patched by avbroot.
+///
+/// Magisk and KernelSU are removed from the boot images by restoring the
+/// original ramdisk contents. The OTA is then re-signed like `ota patch
+/// --rootless` would, so the patch arguments must include the same keys that
+/// were used for patching. The input OTA's whole-file signature is checked
+/// against the OTA signing certificate to ensure this. OTAs patched with
+/// --prepatched cannot be reverted because the original boot image is not
+/// available.
+#[derive(Debug, Parser)]
+pub struct RevertCli {
+    /// Path to patched OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Path to new OTA zip.
+    ///
+    /// If unspecified, the output is written next to the input with a
+    /// `.reverted` suffix.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: Option<PathBuf>,
 
-        last_entry_used_zip64 = use_zip64;
-    }
+    /// Accept an input OTA with multiple whole-file signatures.
+    ///
+    /// By default, the input OTA must have exactly one whole-file signature.
+    /// OTAs patched with --key-ota-secondary have two.
+    #[arg(long)]
+    pub allow_multiple_signers: bool,
 
-    status!("Generating new OTA metadata");
+    /// Arguments to pass to `avbroot ota patch`, like the signing keys.
+    ///
+    /// The root options cannot be used. A profile can be used with --profile.
+    #[arg(last = true, value_name = "PATCH_ARGS")]
+    pub patch_args: Vec<OsString>,
+}
 
-    let data_descriptor_size = if last_entry_used_zip64 { 24 } else { 16 };
-    let metadata = ota::add_metadata(
-        &entries,
-        zip_writer,
-        // Offset where next entry would begin.
-        entries.last().map(|e| e.offset + e.size).unwrap() + data_descriptor_size,
-        &metadata.unwrap(),
-        payload_metadata_size.unwrap(),
-    )
-    .context("Failed to write new OTA metadata")?;
+/// Extract partition images from an OTA zip's payload.
+#[derive(Debug, Parser)]
+pub struct ExtractCli {
+    /// Path to OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
 
-    Ok((metadata, payload_metadata_size.unwrap()))
-}
+    /// Output directory for extracted images.
+    #[arg(short, long, value_parser, default_value = ".")]
+    pub directory: PathBuf,
 
-fn extract_ota_zip(
-    raw_reader: &PSeekFile,
-    directory: &Dir,
-    payload_offset: u64,
-    payload_size: u64,
-    header: &PayloadHeader,
-    images: &BTreeSet<String>,
-    cancel_signal: &AtomicBool,
-) -> Result<()> {
-    for name in images {
-        if Path::new(name).file_name() != Some(OsStr::new(name)) {
-            bail!("Unsafe partition name: {name}");
-        }
-    }
+    /// Extract all images from the payload.
+    #[arg(short, long, group = "extract")]
+    pub all: bool,
 
-    status!("Extracting from the payload: {}", joined(images));
+    /// Extract only the boot image.
+    #[arg(long, group = "extract")]
+    pub boot_only: bool,
 
-    // Pre-open all output files.
-    let output_files = images
-        .iter()
-        .map(|name| {
-            let path = format!("{name}.img");
-            let file = directory
-                .create(&path)
-                .map(|f| PSeekFile::new(f.into_std()))
-                .with_context(|| format!("Failed to open for writing: {path:?}"))?;
-            Ok((name.as_str(), file))
-        })
-        .collect::<Result<HashMap<_, _>>>()?;
+    /// Extract only the specified partition.
+    ///
+    /// This option can be specified multiple times.
+    #[arg(long, value_name = "PARTITION", group = "extract")]
+    pub partition: Vec<String>,
 
-    let payload_reader = SectionReader::new(
-        BufReader::new(raw_reader.reopen()?),
-        payload_offset,
-        payload_size,
-    )?;
+    /// Extract only the specified files from a filesystem partition.
+    ///
+    /// The path must be absolute and each component may contain `*` and `?`
+    /// wildcards. Directories are extracted recursively. Files are written to
+    /// `<directory>/<PARTITION>/<PATH>`. Only ext4 and EROFS partitions are
+    /// supported. This option can be specified multiple times.
+    #[arg(
+        long,
+        value_name = "PARTITION:PATH",
+        group = "extract",
+        conflicts_with_all = ["sparse", "digests"]
+    )]
+    pub file: Vec<String>,
 
-    // Extract the images. Each time we're asked to open a new file, we just
-    // clone the relevant PSeekFile. We only ever have one actual kernel file
-    // descriptor for each file.
-    payload::extract_images(
-        &payload_reader,
-        |name| Ok(Box::new(BufWriter::new(output_files[name].reopen()?))),
-        header,
-        images.iter().map(|n| n.as_str()),
-        cancel_signal,
-    )
-    .context("Failed to extract images from payload")?;
+    /// Write a partition to the specified path instead of the output directory.
+    ///
+    /// The path can be a block device, which is written in place. Extraction
+    /// fails if the block device is smaller than the partition. If only a
+    /// single --partition is extracted, the `<PARTITION>=` prefix can be
+    /// omitted. This option can be specified multiple times.
+    #[arg(long, value_name = "[PARTITION=]PATH", requires = "partition")]
+    pub output: Vec<String>,
+
+    /// Discard block device contents with BLKDISCARD before writing.
+    ///
+    /// This only applies to --output paths that are block devices and is only
+    /// supported on Linux.
+    #[arg(long, requires = "output")]
+    pub discard: bool,
 
-    Ok(())
-}
+    /// Write images in the output directory as sparse files.
+    ///
+    /// All-zero blocks are skipped instead of written, so they do not take up
+    /// any disk space on filesystems that support sparse files. This does not
+    /// apply to --output paths.
+    #[arg(long, conflicts_with = "payload_only")]
+    pub sparse: bool,
 
-fn verify_partition_hashes(
-    directory: &Dir,
-    header: &PayloadHeader,
-    images: &BTreeSet<String>,
-    cancel_signal: &AtomicBool,
-) -> Result<()> {
-    images
-        .par_iter()
-        .map(|name| -> Result<()> {
-            let partition = header
-                .manifest
-                .partitions
-                .iter()
-                .find(|p| p.partition_name == name.as_str())
-                .ok_or_else(|| anyhow!("Partition not found in header: {name}"))?;
-            let expected_digest = partition
-                .new_partition_info
-                .as_ref()
-                .and_then(|info| info.hash.as_ref())
-                .ok_or_else(|| anyhow!("Hash not found for partition: {name}"))?;
+    /// Record the size and sha256 digest of each extracted image.
+    ///
+    /// The digests are written to digests.json in the output directory and are
+    /// verified against the payload. If digests.json already exists, images
+    /// that have not changed since they were recorded are not extracted again.
+    /// `avbroot avb verify --digests` also uses digests.json to skip
+    /// partitions that were already verified.
+    #[arg(long, conflicts_with = "payload_only")]
+    pub digests: bool,
+
+    /// Copy the raw payload and metadata entries instead of extracting images.
+    ///
+    /// payload.bin, payload_properties.txt, metadata, metadata.pb, and otacert
+    /// are written to the output directory exactly as they are stored in the
+    /// OTA. No partitions are decompressed.
+    #[arg(long, group = "extract", conflicts_with = "payload_decrypt_cmd")]
+    pub payload_only: bool,
 
-            let path = format!("{name}.img");
-            let file = directory
-                .open(&path)
-                .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+    /// Command for unwrapping an OEM container around payload.bin.
+    ///
+    /// The command is run with two arguments: the path to the raw payload.bin
+    /// entry data and the path where the plain payload should be written.
+    #[arg(long, value_name = "PROGRAM", value_parser)]
+    pub payload_decrypt_cmd: Option<PathBuf>,
 
-            let mut writer = HashingWriter::new(
-                io::sink(),
-                ring::digest::Context::new(&ring::digest::SHA256),
-            );
+    /// Override how a partition is classified.
+    ///
+    /// This affects which images --boot-only extracts. See `avbroot ota patch
+    /// --help` for details.
+    #[arg(long, value_name = "PARTITION=CLASS")]
+    pub classify: Vec<String>,
 
-            stream::copy(file, &mut writer, cancel_signal)?;
+    /// (Deprecated: no longer needed)
+    #[arg(long, value_name = "PARTITION")]
+    pub boot_partition: Option<String>,
+}
 
-            let digest = writer.finish().1.finish();
+/// Show which stock build a patched OTA was produced from.
+///
+/// This requires the OTA to have been patched with `--keep-original-manifest`.
+#[derive(Debug, Parser)]
+pub struct RollbackInfoCli {
+    /// Path to patched OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
 
-            if digest.as_ref() != expected_digest {
-                bail!(
-                    "Expected sha256 {}, but have {} for partition {name}",
-                    hex::encode(expected_digest),
-                    hex::encode(digest),
-                );
-            }
+    /// Stock OTA zip to check against the original OTA.
+    ///
+    /// The check fails if the partition images in the stock OTA differ from
+    /// those that the patched OTA was produced from.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub stock: Option<PathBuf>,
+}
 
-            Ok(())
-        })
-        .collect()
+/// Show the archive comment and APK signing block of an OTA.
+///
+/// These regions are outside of the zip entries. Use --zip-comment and
+/// --signing-block when patching to control whether they are carried over.
+#[derive(Debug, Parser)]
+pub struct InspectCli {
+    /// Path or HTTP(S) URL to OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
 }
 
-pub fn patch_subcommand(cli: &PatchCli, cancel_signal: &AtomicBool) -> Result<()> {
-    if cli.boot_partition.is_some() {
-        warning!("Ignoring --boot-partition: deprecated and no longer needed");
-    }
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// Human-readable output.
+    #[default]
+    Text,
+    /// JSON output.
+    Json,
+}
 
-    let output = cli.output.as_ref().map_or_else(
-        || {
-            let mut s = cli.input.clone().into_os_string();
-            s.push(".patched");
-            Cow::Owned(PathBuf::from(s))
-        },
-        Cow::Borrowed,
-    );
+/// Show the metadata and payload information of an OTA.
+///
+/// This includes the target device and build, the security patch level, the
+/// payload version, and each partition's size, digest, and operation types.
+/// Nothing is extracted. Only the metadata and payload header are read.
+#[derive(Debug, Parser)]
+pub struct InfoCli {
+    /// Path or HTTP(S) URL to OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
 
-    let source_avb = PassphraseSource::new(
-        &cli.key_avb,
-        cli.pass_avb_file.as_deref(),
-        cli.pass_avb_env_var.as_deref(),
-    );
-    let source_ota = PassphraseSource::new(
-        &cli.key_ota,
-        cli.pass_ota_file.as_deref(),
-        cli.pass_ota_env_var.as_deref(),
-    );
+    /// Output format.
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
+}
 
-    let key_avb = crypto::read_pem_key_file(&cli.key_avb, &source_avb)
-        .with_context(|| format!("Failed to load key: {:?}", cli.key_avb))?;
-    let key_ota = crypto::read_pem_key_file(&cli.key_ota, &source_ota)
-        .with_context(|| format!("Failed to load key: {:?}", cli.key_ota))?;
-    let cert_ota = crypto::read_pem_cert_file(&cli.cert_ota)
-        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+/// Verify signatures of an OTA.
+///
+/// This includes both the whole-file signature and the payload signature.
+#[derive(Debug, Parser)]
+pub struct VerifyCli {
+    /// Path to OTA zip.
+    ///
+    /// This can also be an http:// or https:// URL. The OTA is then read via
+    /// HTTP range requests instead of being downloaded in full first. The
+    /// server must support range requests.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
 
-    if !crypto::cert_matches_key(&cert_ota, &key_ota)? {
-        bail!(
-            "Private key {:?} does not match certificate {:?}",
-            cli.key_ota,
-            cli.cert_ota,
-        );
-    }
+    /// Certificate for verifying the OTA signatures.
+    ///
+    /// If this is omitted, the check only verifies that the signatures are
+    /// valid, not that they are trusted.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub cert_ota: Option<PathBuf>,
 
-    let mut external_images = HashMap::new();
+    /// Previous certificate to also accept for the OTA signatures.
+    ///
+    /// This is useful during a key rotation window, where devices trust both
+    /// the old and new certificates in their otacerts.zip. The OTA is accepted
+    /// if it is signed by either certificate and the one that was used is
+    /// reported.
+    #[arg(long, value_name = "FILE", value_parser, requires = "cert_ota")]
+    pub previous_cert: Option<PathBuf>,
+
+    /// Accept OTAs with multiple whole-file signatures.
+    ///
+    /// By default, the OTA must have exactly one whole-file signature. OTAs
+    /// signed by `avbroot ota patch --key-ota-secondary` have two. Devices
+    /// only check the first signature in DER order, which is reported.
+    #[arg(long)]
+    pub allow_multiple_signers: bool,
 
-    for item in cli.replace.chunks_exact(2) {
-        let name = item[0]
-            .to_str()
-            .ok_or_else(|| anyhow!("Invalid partition name: {:?}", item[0]))?;
-        let path = Path::new(&item[1]);
+    /// Public key for verifying the vbmeta signatures.
+    ///
+    /// If this is omitted, the check only verifies that the signatures are
+    /// valid, not that they are trusted.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub public_key_avb: Option<PathBuf>,
 
-        external_images.insert(name.to_owned(), path.to_owned());
-    }
+    /// Directory for caching successful verification results.
+    ///
+    /// Results are keyed by the digest of the OTA zip and the certificate and
+    /// public key used for verification. If an unchanged OTA was previously
+    /// verified successfully with the same options, the remaining checks are
+    /// skipped.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub cache: Option<PathBuf>,
+
+    /// TOML file that changes how failed checks are handled.
+    ///
+    /// Each check can be set to `error`, `warn`, or `ignore` in the `[checks]`
+    /// table. The name of a check is shown in brackets when it fails. Checks
+    /// that are not listed keep their default behavior.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub policy: Option<PathBuf>,
 
-    let root_patcher = if let Some(magisk) = &cli.root.magisk {
-        let patcher: Box<dyn BootImagePatch + Sync> = Box::new(
-            MagiskRootPatcher::new(
-                magisk,
-                cli.magisk_preinit_device.as_deref(),
-                cli.magisk_random_seed,
-                cli.ignore_magisk_warnings,
-                move |s| warning!("{s}"),
-            )
-            .context("Failed to create Magisk boot image patcher")?,
-        );
+    /// Override how a partition is classified.
+    ///
+    /// This affects which images are checked for otacerts.zip. See `avbroot
+    /// ota patch --help` for details.
+    #[arg(long, value_name = "PARTITION=CLASS")]
+    pub classify: Vec<String>,
 
-        Some(patcher)
-    } else if let Some(prepatched) = &cli.root.prepatched {
-        let patcher: Box<dyn BootImagePatch + Sync> = Box::new(PrepatchedImagePatcher::new(
-            prepatched,
-            cli.ignore_prepatched_compat + 1,
-            move |s| {
-                warning!("{s}");
-            },
-        ));
+    /// Partition that is expected to contain otacerts.zip.
+    ///
+    /// By default, verification fails if no boot image contains otacerts.zip.
+    /// For devices that only store otacerts.zip in the system image, specify
+    /// `system` to only warn instead. This matches `avbroot ota patch
+    /// --otacerts-target` and can be specified multiple times.
+    #[arg(long, value_name = "PARTITION")]
+    pub otacerts_target: Vec<String>,
 
-        Some(patcher)
-    } else {
-        assert!(cli.root.rootless);
-        None
-    };
+    /// Device codename that the OTA must be for.
+    ///
+    /// Verification fails if the OTA metadata does not list this device. This
+    /// guards against using an OTA for the wrong model variant.
+    #[arg(long, value_name = "CODENAME")]
+    pub expect_device: Option<String>,
 
-    let start = Instant::now();
+    /// Prefix that the OTA's build fingerprint must start with.
+    ///
+    /// For example, `google/husky/husky:14/` ensures that the OTA is for the
+    /// expected product and Android version.
+    #[arg(long, value_name = "PREFIX")]
+    pub expect_fingerprint: Option<String>,
 
-    let raw_reader = File::open(&cli.input)
-        .map(PSeekFile::new)
-        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
-    let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader.reopen()?))
-        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
+    /// Output format.
+    ///
+    /// With `json`, a structured report of the verification results is
+    /// printed to stdout, even if verification fails.
+    #[arg(long, value_enum, default_value_t)]
+    pub format: OutputFormat,
 
-    // Open the output file for reading too, so we can verify offsets later.
-    let temp_writer = NamedTempFile::with_prefix_in(
-        output
-            .file_name()
-            .unwrap_or_else(|| OsStr::new("avbroot.tmp")),
-        util::parent_path(&output),
-    )
-    .context("Failed to open temporary output file")?;
-    let temp_path = temp_writer.path().to_owned();
-    let hole_punching_writer = HolePunchingWriter::new(temp_writer);
-    let buffered_writer = BufWriter::new(hole_punching_writer);
-    let signing_writer = SigningWriter::new(buffered_writer);
-    let mut zip_writer = ZipWriter::new_streaming(signing_writer);
+    #[command(flatten)]
+    pub notify: NotifyGroup,
+}
 
-    let (metadata, payload_metadata_size) = patch_ota_zip(
-        &raw_reader,
-        &mut zip_reader,
-        &mut zip_writer,
-        &external_images,
-        root_patcher,
-        cli.clear_vbmeta_flags,
-        &key_avb,
-        &key_ota,
-        &cert_ota,
-        cancel_signal,
-    )
-    .context("Failed to patch OTA zip")?;
+#[allow(clippy::large_enum_variant)]
+#[derive(Debug, Subcommand)]
+enum OtaCommand {
+    Patch(PatchCli),
+    Revert(RevertCli),
+    Extract(ExtractCli),
+    Verify(VerifyCli),
+    Download(download::DownloadCli),
+    Fake(fake::FakeCli),
+    Strip(strip::StripCli),
+    Unstrip(strip::UnstripCli),
+    FlashFastboot(flash::FlashFastbootCli),
+    Diff(diff::DiffCli),
+    RollbackInfo(RollbackInfoCli),
+    Inspect(InspectCli),
+    Info(InfoCli),
+}
+
+/// Patch or extract OTA images.
+#[derive(Debug, Parser)]
+pub struct OtaCli {
+    #[command(subcommand)]
+    command: OtaCommand,
+}
 
-    let signing_writer = zip_writer
-        .finish()
-        .context("Failed to finalize output zip")?;
-    let buffered_writer = signing_writer
-        .finish(&key_ota, &cert_ota)
-        .context("Failed to sign output zip")?;
-    let hole_punching_writer = buffered_writer
-        .into_inner()
-        .context("Failed to flush output zip")?;
-    let mut temp_writer = hole_punching_writer.into_inner();
-    temp_writer.flush().context("Failed to flush output zip")?;
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor, time::Duration};
 
-    // We do a lot of low-level hackery. Reopen and verify offsets.
-    status!("Verifying metadata offsets");
-    temp_writer.rewind().context("Failed to seek output zip")?;
-    ota::verify_metadata(
-        BufReader::new(&mut temp_writer),
-        &metadata,
-        payload_metadata_size,
-    )
-    .context("Failed to verify OTA metadata offsets")?;
+    use prost::Message;
 
-    status!("Completed after {:.1}s", start.elapsed().as_secs_f64());
+    use crate::{
+        protobuf::chromeos_update_engine::{
+            DynamicPartitionGroup, DynamicPartitionMetadata, Extent, InstallOperation,
+            PartitionInfo,
+        },
+        stream::SharedCursor,
+    };
 
-    // NamedTempFile forces 600 permissions on temp files because it's the safe
-    // option for a shared /tmp. Since we're writing to the output file's
-    // directory, just mimic umask.
-    #[cfg(unix)]
-    {
-        use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
+    use super::*;
 
-        use rustix::{fs::Mode, process::umask};
+    fn header_with_ops(partitions: &[(&str, &[Type])]) -> PayloadHeader {
+        let partitions = partitions
+            .iter()
+            .map(|(name, types)| PartitionUpdate {
+                partition_name: (*name).to_owned(),
+                old_partition_info: types
+                    .iter()
+                    .any(|t| *t == Type::SourceCopy || *t == Type::SourceBsdiff)
+                    .then(PartitionInfo::default),
+                operations: types
+                    .iter()
+                    .map(|t| InstallOperation {
+                        r#type: (*t).into(),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            })
+            .collect();
 
-        let mask = umask(Mode::empty());
-        umask(mask);
+        PayloadHeader {
+            version: 2,
+            manifest: DeltaArchiveManifest {
+                partitions,
+                ..Default::default()
+            },
+            metadata_signature_size: 0,
+            blob_offset: 0,
+        }
+    }
 
-        // Mac uses a 16-bit value.
-        #[allow(clippy::useless_conversion)]
-        let mode = u32::from(0o666 & !mask.bits());
+    #[test]
+    fn reject_diff_operations() {
+        let full = header_with_ops(&[("system", &[Type::ReplaceXz, Type::Zero])]);
+        assert!(check_full_ota(&full).is_ok());
+        assert!(check_no_diff_operations(&full).is_ok());
+
+        // OEM full OTAs with SOURCE_COPY are allowed when patching.
+        let source_copy = header_with_ops(&[
+            ("system", &[Type::ReplaceXz]),
+            ("vendor", &[Type::SourceCopy, Type::ReplaceXz]),
+        ]);
+        assert!(check_full_ota(&source_copy).is_err());
+        assert!(check_no_diff_operations(&source_copy).is_ok());
+
+        let delta = header_with_ops(&[
+            ("system", &[Type::SourceCopy]),
+            ("vendor", &[Type::SourceBsdiff]),
+        ]);
+        assert!(check_no_diff_operations(&delta).is_err());
+    }
 
-        temp_writer
-            .as_file()
-            .set_permissions(Permissions::from_mode(mode))
-            .with_context(|| format!("Failed to set permissions to {mode:o}: {temp_path:?}"))?;
+    #[test]
+    fn classify_partitions() {
+        let default = PartitionClassifier::default();
+        assert_eq!(default.classify("init_boot"), PartitionClass::Boot);
+        assert_eq!(default.classify("system"), PartitionClass::System);
+        assert_eq!(default.classify("vbmeta_system"), PartitionClass::Vbmeta);
+        assert_eq!(
+            default.classify("vendor_boot_custom"),
+            PartitionClass::Other
+        );
+
+        let classifier = PartitionClassifier::from_args(&[
+            "vendor_boot_custom=boot".to_owned(),
+            "system=other".to_owned(),
+            "oem_vbmeta=vbmeta".to_owned(),
+        ])
+        .unwrap();
+        assert!(classifier.is_boot("vendor_boot_custom"));
+        assert!(!classifier.is_system("system"));
+        assert!(classifier.is_vbmeta("oem_vbmeta"));
+        // Partitions without an override use the built-in rules.
+        assert!(classifier.is_boot("boot"));
+
+        for invalid in ["boot", "=boot", "boot=kernel"] {
+            assert!(PartitionClassifier::from_args(&[invalid.to_owned()]).is_err());
+        }
+        assert!(
+            PartitionClassifier::from_args(&["boot=boot".to_owned(), "boot=other".to_owned()])
+                .is_err()
+        );
     }
 
-    temp_writer.persist(output.as_ref()).with_context(|| {
-        format!("Failed to move temporary file to output path: {temp_path:?} -> {output:?}")
-    })?;
+    #[test]
+    fn extract_outputs() {
+        let one = BTreeSet::from(["boot".to_owned()]);
+        let two = BTreeSet::from(["boot".to_owned(), "vendor_boot".to_owned()]);
 
-    Ok(())
-}
+        // A plain path is only allowed for a single partition.
+        assert_eq!(
+            parse_extract_outputs(&["/dev/block/sda1".to_owned()], &one).unwrap(),
+            BTreeMap::from([("boot".to_owned(), PathBuf::from("/dev/block/sda1"))]),
+        );
+        assert!(parse_extract_outputs(&["/dev/block/sda1".to_owned()], &two).is_err());
+
+        assert_eq!(
+            parse_extract_outputs(
+                &[
+                    "boot=boot.img".to_owned(),
+                    "vendor_boot=/tmp/a=b".to_owned()
+                ],
+                &two,
+            )
+            .unwrap(),
+            BTreeMap::from([
+                ("boot".to_owned(), PathBuf::from("boot.img")),
+                ("vendor_boot".to_owned(), PathBuf::from("/tmp/a=b")),
+            ]),
+        );
 
-pub fn extract_subcommand(cli: &ExtractCli, cancel_signal: &AtomicBool) -> Result<()> {
-    if cli.boot_partition.is_some() {
-        warning!("Ignoring --boot-partition: deprecated and no longer needed");
+        // Unknown partitions and duplicates are rejected.
+        assert!(parse_extract_outputs(&["system=system.img".to_owned()], &two).is_err());
+        assert!(
+            parse_extract_outputs(&["boot=a.img".to_owned(), "boot=b.img".to_owned()], &two,)
+                .is_err()
+        );
     }
 
-    let raw_reader = File::open(&cli.input)
-        .map(PSeekFile::new)
-        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
-    let mut zip = ZipArchive::new(BufReader::new(raw_reader.reopen()?))
-        .with_context(|| format!("Failed to read zip: {:?}", cli.input))?;
-    let payload_entry = zip
-        .by_name(ota::PATH_PAYLOAD)
-        .with_context(|| format!("Failed to open zip entry: {:?}", ota::PATH_PAYLOAD))?;
-    let payload_offset = payload_entry.data_start();
-    let payload_size = payload_entry.size();
+    #[test]
+    fn grow_only_gsi_groups() {
+        let partition = |name: &str, size: u64| PartitionUpdate {
+            partition_name: name.to_owned(),
+            new_partition_info: Some(PartitionInfo {
+                size: Some(size),
+                hash: None,
+            }),
+            ..Default::default()
+        };
+        let group = |name: &str, partitions: &[&str]| DynamicPartitionGroup {
+            name: name.to_owned(),
+            size: Some(100),
+            partition_names: partitions.iter().map(|p| (*p).to_owned()).collect(),
+        };
+        let mut manifest = DeltaArchiveManifest {
+            partitions: vec![
+                partition("system", 80),
+                partition("product", 40),
+                partition("vendor", 60),
+            ],
+            dynamic_partition_metadata: Some(DynamicPartitionMetadata {
+                groups: vec![
+                    group("main", &["system", "product"]),
+                    group("other", &["vendor"]),
+                ],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
 
-    // Open the payload data directly.
-    let mut payload_reader = SectionReader::new(
-        BufReader::new(raw_reader.reopen()?),
-        payload_offset,
-        payload_size,
-    )
-    .context("Failed to directly open payload section")?;
+        let mut external_images = HashMap::new();
+        external_images.insert("system".to_owned(), ExternalImage::Gsi("gsi.img".into()));
 
-    let header = PayloadHeader::from_reader(&mut payload_reader)
-        .context("Failed to load OTA payload header")?;
-    if !header.is_full_ota() {
-        bail!("Payload is a delta OTA, not a full OTA");
+        grow_partition_groups(&mut manifest, &external_images).unwrap();
+        let groups = &manifest.dynamic_partition_metadata.as_ref().unwrap().groups;
+        assert_eq!(groups[0].size, Some(120));
+        assert_eq!(groups[1].size, Some(100));
+
+        // Groups without a GSI are never grown.
+        manifest.partitions[2] = partition("vendor", 101);
+        assert!(grow_partition_groups(&mut manifest, &external_images).is_err());
     }
 
-    let mut unique_images = BTreeSet::new();
+    /// Build a zip containing only OTA metadata. If `spl` is [`None`], the
+    /// metadata has no postcondition. If `metadata` is false, there is no
+    /// metadata at all.
+    fn zip_with_spl(metadata: bool, spl: Option<&str>) -> ZipArchive<Cursor<Vec<u8>>> {
+        let mut writer = ZipWriter::new(Cursor::new(vec![]));
+
+        if metadata {
+            let metadata = OtaMetadata {
+                postcondition: spl.map(|s| DeviceState {
+                    security_patch_level: s.to_owned(),
+                    ..Default::default()
+                }),
+                ..Default::default()
+            };
 
-    if cli.all {
-        unique_images.extend(
-            header
-                .manifest
-                .partitions
-                .iter()
-                .map(|p| &p.partition_name)
-                .cloned(),
-        );
-    } else {
-        let images = RequiredImages::new(&header.manifest);
+            writer
+                .start_file(ota::PATH_METADATA_PB, FileOptions::default())
+                .unwrap();
+            writer.write_all(&metadata.encode_to_vec()).unwrap();
+        }
 
-        if cli.boot_only {
-            unique_images.extend(images.iter_boot().map(|n| n.to_owned()));
-        } else {
-            unique_images.extend(images.iter().map(|n| n.to_owned()));
+        ZipArchive::new(writer.finish().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn valid_spl() {
+        assert!(is_valid_spl("2024-01-05"));
+        assert!(!is_valid_spl(""));
+        assert!(!is_valid_spl("2024-1-05"));
+        assert!(!is_valid_spl("2024-01-5"));
+        assert!(!is_valid_spl("2024/01/05"));
+        assert!(!is_valid_spl("2024-01-05 "));
+        assert!(!is_valid_spl("20x4-01-05"));
+    }
+
+    #[test]
+    fn spl_downgrade() {
+        let current = "2024-02-05";
+
+        // Equal and newer are fine.
+        check_spl_downgrade(&mut zip_with_spl(true, Some(current)), current).unwrap();
+        check_spl_downgrade(&mut zip_with_spl(true, Some("2024-03-01")), current).unwrap();
+
+        // Older is a downgrade.
+        let mut zip = zip_with_spl(true, Some("2024-01-05"));
+        assert!(check_spl_downgrade(&mut zip, current).is_err());
+
+        // Malformed SPLs are rejected on both sides.
+        let mut zip = zip_with_spl(true, Some("2024-3-1"));
+        assert!(check_spl_downgrade(&mut zip, current).is_err());
+        let mut zip = zip_with_spl(true, Some(current));
+        assert!(check_spl_downgrade(&mut zip, "2024-02").is_err());
+
+        // Missing metadata or SPL cannot be checked.
+        for mut zip in [
+            zip_with_spl(false, None),
+            zip_with_spl(true, None),
+            zip_with_spl(true, Some("")),
+        ] {
+            assert!(check_spl_downgrade(&mut zip, current).is_err());
         }
     }
 
-    let authority = ambient_authority();
-    Dir::create_ambient_dir_all(&cli.directory, authority)
-        .with_context(|| format!("Failed to create directory: {:?}", cli.directory))?;
-    let directory = Dir::open_ambient_dir(&cli.directory, authority)
-        .with_context(|| format!("Failed to open directory: {:?}", cli.directory))?;
+    /// Directory tree where every directory contains a single subdirectory.
+    /// Inode `n` contains inode `next(n)`.
+    struct NestedFs {
+        next: fn(u64) -> u64,
+    }
 
-    extract_ota_zip(
-        &raw_reader,
-        &directory,
-        payload_offset,
-        payload_size,
-        &header,
-        &unique_images,
-        cancel_signal,
-    )?;
+    impl FileSystemReader for NestedFs {
+        fn root(&self) -> u64 {
+            0
+        }
 
-    Ok(())
-}
+        fn file_type(&mut self, _inode: u64) -> filesystem::Result<FileType> {
+            Ok(FileType::Directory)
+        }
 
-pub fn verify_subcommand(cli: &VerifyCli, cancel_signal: &AtomicBool) -> Result<()> {
-    let raw_reader = File::open(&cli.input)
-        .map(PSeekFile::new)
-        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
-    let mut reader = BufReader::new(raw_reader);
+        fn read_dir(&mut self, inode: u64) -> filesystem::Result<Vec<filesystem::DirEntry>> {
+            Ok(vec![filesystem::DirEntry {
+                name: b"d".to_vec(),
+                inode: (self.next)(inode),
+            }])
+        }
 
-    status!("Verifying whole-file signature");
+        fn read_link(&mut self, _inode: u64) -> filesystem::Result<Vec<u8>> {
+            unreachable!()
+        }
 
-    let embedded_cert = ota::verify_ota(&mut reader, cancel_signal)?;
+        fn read_file(
+            &mut self,
+            _inode: u64,
+            _writer: &mut dyn Write,
+            _cancel_signal: &AtomicBool,
+        ) -> filesystem::Result<()> {
+            unreachable!()
+        }
+    }
 
-    let (metadata, ota_cert, header, properties) = ota::parse_zip_ota_info(&mut reader)?;
-    if embedded_cert != ota_cert {
-        bail!(
-            "CMS embedded certificate does not match {}",
-            ota::PATH_OTACERT,
+    #[test]
+    fn payload_dir_is_read_in_place() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let cancel_signal = AtomicBool::new(false);
+        let payload = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+
+        fs::write(dir.join("payload.bin"), &payload).unwrap();
+        assert!(open_payload_dir(dir, &cancel_signal).is_err());
+
+        let metadata = OtaMetadata {
+            postcondition: Some(DeviceState {
+                security_patch_level: "2024-01-01".to_owned(),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        fs::write(dir.join("metadata.pb"), metadata.encode_to_vec()).unwrap();
+
+        let raw_reader = OtaReader::Dir(open_payload_dir(dir, &cancel_signal).unwrap());
+        let mut zip_reader = ZipArchive::new(BufReader::new(raw_reader.reopen().unwrap())).unwrap();
+        let mut names = zip_reader.file_names().collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(
+            names,
+            [
+                ota::PATH_METADATA_PB,
+                ota::PATH_OTACERT,
+                ota::PATH_PAYLOAD,
+                ota::PATH_PROPERTIES,
+            ],
         );
-    } else if let Some(p) = &cli.cert_ota {
-        let verify_cert = crypto::read_pem_cert_file(p)
-            .with_context(|| format!("Failed to load certificate: {:?}", p))?;
 
-        if embedded_cert != verify_cert {
-            bail!("OTA has a valid signature, but was not signed with: {p:?}");
-        }
-    } else {
-        warning!("Whole-file signature is valid, but its trust is unknown");
+        // The CRC is valid when read through the zip library.
+        let mut data = vec![];
+        zip_reader
+            .by_name(ota::PATH_PAYLOAD)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, payload);
+
+        // And the entry offset points at the payload data.
+        let entry = find_payload_entry(&raw_reader, &mut zip_reader).unwrap();
+        let mut data = vec![];
+        SectionReader::new(raw_reader.reopen().unwrap(), entry.offset, entry.size)
+            .unwrap()
+            .read_to_end(&mut data)
+            .unwrap();
+        assert_eq!(data, payload);
+
+        assert_eq!(read_payload_dir_metadata(dir).unwrap(), Some(metadata));
     }
 
-    ota::verify_metadata(&mut reader, &metadata, header.blob_offset)
-        .context("Failed to verify OTA metadata offsets")?;
+    /// Build a payload with one REPLACE operation per partition. The header's
+    /// digest for `bad_partition` does not match the data.
+    fn replace_payload(
+        partitions: &[(&str, &[u8])],
+        bad_partition: Option<&str>,
+    ) -> (SharedCursor, PayloadHeader) {
+        let sha256 = |data: &[u8]| {
+            let mut context = crate::digest::Context::new(&crate::digest::SHA256);
+            context.update(data);
+            context.finish().as_ref().to_vec()
+        };
 
-    status!("Verifying payload");
+        let mut blob = SharedCursor::new();
+        let mut offset = 0;
+        let mut updates = vec![];
 
-    let pfs_raw = metadata
-        .property_files
-        .get(ota::PF_NAME)
-        .ok_or_else(|| anyhow!("Missing property files: {}", ota::PF_NAME))?;
-    let pfs = ota::parse_property_files(pfs_raw)
-        .with_context(|| format!("Failed to parse property files: {}", ota::PF_NAME))?;
-    let pf_payload = pfs
-        .iter()
-        .find(|pf| pf.name == ota::PATH_PAYLOAD)
-        .ok_or_else(|| anyhow!("Missing property files entry: {}", ota::PATH_PAYLOAD))?;
+        for (name, data) in partitions {
+            blob.write_all(data).unwrap();
 
-    let section_reader = SectionReader::new(&mut reader, pf_payload.offset, pf_payload.size)
-        .context("Failed to directly open payload section")?;
+            let mut hash = sha256(data);
+            if bad_partition == Some(*name) {
+                hash[0] ^= 0xff;
+            }
 
-    payload::verify_payload(section_reader, &ota_cert, &properties, cancel_signal)?;
+            updates.push(PartitionUpdate {
+                partition_name: (*name).to_owned(),
+                new_partition_info: Some(PartitionInfo {
+                    size: Some(data.len() as u64),
+                    hash: Some(hash),
+                }),
+                operations: vec![InstallOperation {
+                    r#type: Type::Replace.into(),
+                    data_offset: Some(offset),
+                    data_length: Some(data.len() as u64),
+                    dst_extents: vec![Extent {
+                        start_block: Some(0),
+                        num_blocks: Some(data.len() as u64 / 4096),
+                    }],
+                    data_sha256_hash: Some(sha256(data)),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            });
+
+            offset += data.len() as u64;
+        }
 
-    status!("Extracting partition images to temporary directory");
+        let header = PayloadHeader {
+            version: 2,
+            manifest: DeltaArchiveManifest {
+                partitions: updates,
+                ..Default::default()
+            },
+            metadata_signature_size: 0,
+            blob_offset: 0,
+        };
 
-    let authority = ambient_authority();
-    let temp_dir = TempDir::new(authority).context("Failed to create temporary directory")?;
-    let raw_reader = reader.into_inner();
-    let unique_images = header
-        .manifest
-        .partitions
-        .iter()
-        .map(|p| &p.partition_name)
-        .cloned()
-        .collect::<BTreeSet<_>>();
+        (blob, header)
+    }
 
-    extract_ota_zip(
-        &raw_reader,
-        &temp_dir,
-        pf_payload.offset,
-        pf_payload.size,
-        &header,
-        &unique_images,
-        cancel_signal,
-    )?;
+    #[test]
+    fn extract_and_verify() {
+        let cancel_signal = AtomicBool::new(false);
+        let a = vec![1u8; 4096];
+        let b = vec![2u8; 8192];
+        let images = ["a", "b"].map(str::to_owned).into();
+
+        let (blob, header) = replace_payload(&[("a", &a), ("b", &b)], None);
+        let size = a.len() as u64 + b.len() as u64;
+        let temp_dir = cap_tempfile::TempDir::new(cap_std::ambient_authority()).unwrap();
+
+        extract_and_verify_images(&blob, &temp_dir, 0, size, &header, &images, &cancel_signal)
+            .unwrap();
+
+        assert_eq!(temp_dir.read("a.img").unwrap(), a);
+        assert_eq!(temp_dir.read("b.img").unwrap(), b);
+
+        // The digest mismatch is reported instead of extraction being stopped.
+        let (blob, header) = replace_payload(&[("a", &a), ("b", &b)], Some("a"));
+        let temp_dir = cap_tempfile::TempDir::new(cap_std::ambient_authority()).unwrap();
+
+        let error =
+            extract_and_verify_images(&blob, &temp_dir, 0, size, &header, &images, &cancel_signal)
+                .unwrap_err();
+        assert!(
+            error.to_string().contains("for partition a"),
+            "Unexpected error: {error:?}",
+        );
+    }
 
-    status!("Verifying partition hashes");
+    #[test]
+    fn extract_fs_entry_limits() {
+        let temp_dir = cap_tempfile::TempDir::new(cap_std::ambient_authority()).unwrap();
+        let cancel_signal = AtomicBool::new(false);
+
+        for (next, message) in [
+            ((|i: u64| i / 2) as fn(u64) -> u64, "already visited"),
+            (|i: u64| i + 1, "too deep"),
+        ] {
+            let err = extract_fs_entry(
+                &mut NestedFs { next },
+                2,
+                "/",
+                &temp_dir,
+                Path::new("root"),
+                &mut HashSet::new(),
+                0,
+                &cancel_signal,
+            )
+            .unwrap_err();
 
-    verify_partition_hashes(&temp_dir, &header, &unique_images, cancel_signal)?;
+            assert!(err.to_string().contains(message), "{err:?}");
+        }
+    }
 
-    status!("Checking ramdisk's otacerts.zip");
+    #[cfg(unix)]
+    #[test]
+    fn rewrap_payload_describes_stored_data() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let script = temp_dir.path().join("wrap.sh");
+        fs::write(
+            &script,
+            "#!/bin/sh\n{ printf 'wrapped:'; cat \"$1\"; } > \"$2\"\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let mut output = vec![];
+        let (properties, metadata_size) = rewrap_payload(
+            &script,
+            |writer| {
+                writer.write_all(b"plain")?;
+                Ok(("FILE_SIZE=5\n".to_owned(), 5))
+            },
+            &mut output,
+            temp_dir.path(),
+            &AtomicBool::new(false),
+        )
+        .unwrap();
 
-    {
-        let required_images = RequiredImages::new(&header.manifest);
-        let boot_images =
-            boot::load_boot_images(&required_images.iter_boot().collect::<Vec<_>>(), |name| {
-                Ok(Box::new(
-                    temp_dir
-                        .open(format!("{name}.img"))
-                        .map(|f| PSeekFile::new(f.into_std()))?,
-                ))
-            })
-            .context("Failed to load all boot images")?;
-        let targets = OtaCertPatcher::new(ota_cert.clone())
-            .find_targets(&boot_images, cancel_signal)
-            .context("Failed to find boot image containing otacerts.zip")?;
+        assert_eq!(output, b"wrapped:plain");
+        assert_eq!(metadata_size, 13);
+        assert_eq!(
+            properties,
+            "FILE_HASH=O9Izgn93IE/n1R1T98e/NiL6UNHKoKya89mnbibtQew=\n\
+            FILE_SIZE=13\n\
+            METADATA_HASH=O9Izgn93IE/n1R1T98e/NiL6UNHKoKya89mnbibtQew=\n\
+            METADATA_SIZE=13\n",
+        );
+    }
 
-        if targets.is_empty() {
-            bail!("No boot image contains otacerts.zip");
-        }
+    #[test]
+    fn prepare_hash_images_keeps_algorithm() {
+        let cancel_signal = AtomicBool::new(false);
+        // Large enough to not be mistaken for a truncated footer.
+        let data = b"replacement data".repeat(256);
 
-        for target in targets {
-            let boot_image = &boot_images[target].boot_image;
-            let ramdisk_certs = OtaCertPatcher::get_certificates(boot_image, cancel_signal)
-                .context("Failed to read {target}'s otacerts.zip")?;
+        let new_file = |contents: &[u8]| {
+            let mut file = tempfile::tempfile().map(PSeekFile::new).unwrap();
+            file.write_all(contents).unwrap();
+            file
+        };
 
-            if !ramdisk_certs.contains(&ota_cert) {
-                bail!("{target}'s otacerts.zip does not contain OTA certificate");
-            }
+        let vbmeta_header = Header {
+            required_libavb_version_major: avb::VERSION_MAJOR,
+            required_libavb_version_minor: avb::VERSION_MINOR,
+            algorithm_type: AlgorithmType::None,
+            hash: vec![],
+            signature: vec![],
+            public_key: vec![],
+            public_key_metadata: vec![],
+            descriptors: vec![Descriptor::Hash(avb::HashDescriptor {
+                image_size: 0,
+                hash_algorithm: "sha512".to_owned(),
+                partition_name: "boot".to_owned(),
+                salt: b"salt".to_vec(),
+                root_digest: vec![],
+                flags: 0,
+                reserved: [0u8; 60],
+            })],
+            rollback_index: 0,
+            flags: 0,
+            rollback_index_location: 0,
+            release_string: String::new(),
+            reserved: [0u8; 80],
+        };
+        let mut vbmeta_file = new_file(b"");
+        avb::write_root_image(&mut vbmeta_file, &vbmeta_header, 0).unwrap();
+
+        let manifest = DeltaArchiveManifest {
+            partitions: vec![PartitionUpdate {
+                partition_name: "boot".to_owned(),
+                new_partition_info: Some(PartitionInfo {
+                    size: Some(65536),
+                    hash: None,
+                }),
+                ..Default::default()
+            }],
+            ..Default::default()
+        };
+        let external_images =
+            HashMap::from([("boot".to_owned(), ExternalImage::File("boot.img".into()))]);
+        let vbmeta_images = HashSet::from(["vbmeta"]);
+
+        let mut input_files = HashMap::from([
+            (
+                "vbmeta".to_owned(),
+                InputFile {
+                    file: vbmeta_file,
+                    state: InputFileState::Extracted,
+                },
+            ),
+            (
+                "boot".to_owned(),
+                InputFile {
+                    file: new_file(&data),
+                    state: InputFileState::External,
+                },
+            ),
+        ]);
+
+        prepare_hash_images(
+            &external_images,
+            &mut input_files,
+            &vbmeta_images,
+            &manifest,
+            &cancel_signal,
+        )
+        .unwrap();
+
+        let boot = input_files.get_mut("boot").unwrap();
+        assert!(boot.state == InputFileState::Modified);
+
+        let (header, footer, image_size) = avb::load_image(&mut boot.file).unwrap();
+        assert_eq!(image_size, 65536);
+        assert_eq!(footer.unwrap().original_image_size, data.len() as u64);
+
+        let Some(Descriptor::Hash(descriptor)) = header.descriptors.first() else {
+            panic!("Missing hash descriptor: {header:?}");
+        };
+        assert_eq!(descriptor.hash_algorithm, "sha512");
+        assert_eq!(descriptor.salt, b"salt");
+        assert_eq!(descriptor.image_size, data.len() as u64);
+        descriptor.verify(data.as_slice(), &cancel_signal).unwrap();
+
+        // A footer pointing to a vbmeta header that can't be read is an error
+        // instead of being treated as an image without a footer.
+        let mut file = new_file(&data);
+        avb::Footer {
+            version_major: avb::FOOTER_VERSION_MAJOR,
+            version_minor: avb::FOOTER_VERSION_MINOR,
+            original_image_size: data.len() as u64,
+            vbmeta_offset: 1 << 20,
+            vbmeta_size: 64,
+            reserved: Default::default(),
         }
+        .to_writer(&mut file)
+        .unwrap();
+        input_files.get_mut("boot").unwrap().file = file;
+
+        assert!(prepare_hash_images(
+            &external_images,
+            &mut input_files,
+            &vbmeta_images,
+            &manifest,
+            &cancel_signal,
+        )
+        .is_err());
     }
 
-    status!("Verifying AVB signatures");
+    #[test]
+    fn provenance_digests_track_file_contents() {
+        let cancel_signal = AtomicBool::new(false);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| temp_dir.path().join(name);
+
+        fs::write(path("ota.crt"), b"cert").unwrap();
+        fs::write(path("magisk.apk"), b"magisk").unwrap();
+        fs::write(path("system.img"), b"system").unwrap();
+
+        let cli = PatchCli::try_parse_from([
+            OsStr::new("patch"),
+            OsStr::new("--input"),
+            path("ota.zip").as_os_str(),
+            OsStr::new("--key-avb"),
+            path("avb.key").as_os_str(),
+            OsStr::new("--key-ota"),
+            path("ota.key").as_os_str(),
+            OsStr::new("--cert-ota"),
+            path("ota.crt").as_os_str(),
+            OsStr::new("--magisk"),
+            path("magisk.apk").as_os_str(),
+            OsStr::new("--replace"),
+            OsStr::new("system"),
+            path("system.img").as_os_str(),
+        ])
+        .unwrap();
 
-    let public_key = if let Some(p) = &cli.public_key_avb {
-        let data = fs::read(p).with_context(|| format!("Failed to read file: {p:?}"))?;
-        let key = avb::decode_public_key(&data)
-            .with_context(|| format!("Failed to decode public key: {p:?}"))?;
+        let sha256 = |data: &[u8]| hex::encode(crate::digest::digest(&crate::digest::SHA256, data));
+
+        // Keys are not included because their contents are secret. Their
+        // fingerprints are recorded separately.
+        let digests = provenance_input_digests(&cli, &cancel_signal).unwrap();
+        assert_eq!(
+            digests,
+            BTreeMap::from([
+                ("cert_ota".to_owned(), sha256(b"cert")),
+                ("magisk".to_owned(), sha256(b"magisk")),
+                ("replace.system".to_owned(), sha256(b"system")),
+            ]),
+        );
 
-        Some(key)
-    } else {
-        None
-    };
+        fs::write(path("system.img"), b"new system").unwrap();
+        let digests = provenance_input_digests(&cli, &cancel_signal).unwrap();
+        assert_eq!(digests["replace.system"], sha256(b"new system"));
 
-    let mut seen = HashSet::<String>::new();
-    let mut descriptors = HashMap::<String, Descriptor>::new();
+        fs::remove_file(path("magisk.apk")).unwrap();
+        assert!(provenance_input_digests(&cli, &cancel_signal).is_err());
+    }
 
-    cli::avb::verify_headers(
-        &temp_dir,
-        "vbmeta",
-        public_key.as_ref(),
-        &mut seen,
-        &mut descriptors,
-    )?;
-    cli::avb::verify_descriptors(&temp_dir, &descriptors, false, cancel_signal)?;
+    #[test]
+    fn output_up_to_date() {
+        let cancel_signal = AtomicBool::new(false);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let output = temp_dir.path().join("ota.zip");
+
+        let signers = (1..=2)
+            .map(|serial| {
+                let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+                let cert = crypto::generate_cert(
+                    &key,
+                    serial,
+                    Duration::from_secs(3600),
+                    &format!("CN=signer{serial}"),
+                )
+                .unwrap();
 
-    status!("Signatures are all valid!");
+                (RsaSigningKey::Internal(key), cert)
+            })
+            .collect::<Vec<_>>();
+        let (_, cert) = &signers[0];
 
-    Ok(())
-}
+        let provenance = Provenance {
+            avbroot_version: "1.0.0".to_owned(),
+            input_sha256: "00".repeat(32),
+            ..Default::default()
+        };
 
-pub fn ota_main(cli: &OtaCli, cancel_signal: &AtomicBool) -> Result<()> {
-    match &cli.command {
-        OtaCommand::Patch(c) => patch_subcommand(c, cancel_signal),
-        OtaCommand::Extract(c) => extract_subcommand(c, cancel_signal),
-        OtaCommand::Verify(c) => verify_subcommand(c, cancel_signal),
-    }
-}
+        let write_output = |provenance: Option<&Provenance>,
+                            signers: &[(RsaSigningKey, Certificate)]| {
+            let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(Cursor::new(vec![])));
+            let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+
+            zip_writer.start_file(ota::PATH_PAYLOAD, options).unwrap();
+            zip_writer.write_all(b"payload").unwrap();
+
+            if let Some(p) = provenance {
+                zip_writer
+                    .start_file(ota::PATH_PROVENANCE, options)
+                    .unwrap();
+                zip_writer
+                    .write_all(ota::serialize_provenance(p).unwrap().as_bytes())
+                    .unwrap();
+            }
+
+            let signers = signers.iter().map(|(k, c)| (k, c)).collect::<Vec<_>>();
+            let data = zip_writer
+                .finish()
+                .unwrap()
+                .finish_with_signers(&signers)
+                .unwrap()
+                .into_inner();
+
+            fs::write(&output, data).unwrap();
+        };
+        let check = |allow_multiple_signers| {
+            is_output_up_to_date(
+                &output,
+                &provenance,
+                cert,
+                allow_multiple_signers,
+                &cancel_signal,
+            )
+        };
 
-// We currently use the `conflicts_with_all` option instead of `requires`
-// because the latter currently doesn't work when the dependent is an argument
-// inside a group: https://github.com/clap-rs/clap/issues/4707. Even if that
-// were fixed, the former option's error message is much more user friendly.
+        // Nothing to compare against.
+        assert!(!check(false).unwrap());
 
-const HEADING_PATH: &str = "Path options";
-const HEADING_KEY: &str = "Key options";
-const HEADING_MAGISK: &str = "Magisk patch options";
-const HEADING_PREPATCHED: &str = "Prepatched boot image options";
-const HEADING_OTHER: &str = "Other patch options";
+        write_output(Some(&provenance), &signers[..1]);
+        assert!(check(false).unwrap());
 
-#[derive(Debug, Args)]
-#[group(required = true, multiple = false)]
-pub struct RootGroup {
-    /// Path to Magisk APK.
-    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_MAGISK)]
-    pub magisk: Option<PathBuf>,
+        // Produced with different options.
+        let other = Provenance {
+            options: BTreeMap::from([("rootless".to_owned(), "true".to_owned())]),
+            ..provenance.clone()
+        };
+        write_output(Some(&other), &signers[..1]);
+        assert!(!check(false).unwrap());
 
-    /// Path to prepatched boot image.
-    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_PREPATCHED)]
-    pub prepatched: Option<PathBuf>,
+        // Not produced by avbroot.
+        write_output(None, &signers[..1]);
+        assert!(!check(false).unwrap());
 
-    /// Skip applying root patch.
-    #[arg(long, help_heading = HEADING_OTHER)]
-    pub rootless: bool,
-}
+        // Signed with a different key.
+        write_output(Some(&provenance), &signers[1..]);
+        assert!(!check(false).unwrap());
 
-/// Patch a full OTA zip.
-#[derive(Debug, Parser)]
-pub struct PatchCli {
-    /// Patch to original OTA zip.
-    #[arg(short, long, value_name = "FILE", value_parser, help_heading = HEADING_PATH)]
-    pub input: PathBuf,
+        // Signed with a secondary key too.
+        write_output(Some(&provenance), &signers);
+        assert!(check(false).is_err());
+        assert!(check(true).unwrap());
+    }
 
-    /// Path to new OTA zip.
-    #[arg(short, long, value_name = "FILE", value_parser, help_heading = HEADING_PATH)]
-    pub output: Option<PathBuf>,
+    #[test]
+    fn ota_expectations() {
+        let cli = |args: &[&str]| {
+            VerifyCli::try_parse_from(["verify", "--input", "ota.zip"].iter().chain(args)).unwrap()
+        };
+        let state = |devices: &[&str], builds: &[&str]| {
+            Some(DeviceState {
+                device: devices.iter().map(|d| (*d).to_owned()).collect(),
+                build: builds.iter().map(|b| (*b).to_owned()).collect(),
+                ..Default::default()
+            })
+        };
+        let fingerprint = "google/husky/husky:14/AP1A.240305.019.A1/11445699:user/release-keys";
+        let metadata = OtaMetadata {
+            precondition: state(&["husky"], &[]),
+            postcondition: state(&["husky"], &[fingerprint]),
+            ..Default::default()
+        };
 
-    /// Private key for signing vbmeta images.
-    #[arg(
-        long,
-        alias = "privkey-avb",
-        value_name = "FILE",
-        value_parser,
-        help_heading = HEADING_KEY
-    )]
-    pub key_avb: PathBuf,
+        // Nothing expected.
+        check_ota_expectations(&cli(&[]), &OtaMetadata::default()).unwrap();
+
+        let matching = cli(&[
+            "--expect-device",
+            "husky",
+            "--expect-fingerprint",
+            "google/husky/husky:14/",
+        ]);
+        check_ota_expectations(&matching, &metadata).unwrap();
+
+        // Devices fall back to the postcondition if the precondition has none.
+        let postcondition_only = OtaMetadata {
+            precondition: state(&[], &[]),
+            ..metadata.clone()
+        };
+        check_ota_expectations(&matching, &postcondition_only).unwrap();
+
+        for args in [
+            ["--expect-device", "shiba"],
+            ["--expect-fingerprint", "google/husky/husky:15/"],
+            ["--expect-fingerprint", "google/shiba/"],
+        ] {
+            assert!(check_ota_expectations(&cli(&args), &metadata).is_err());
+        }
 
-    /// Private key for signing the OTA.
-    #[arg(
-        long,
-        alias = "privkey-ota",
-        value_name = "FILE",
-        value_parser,
-        help_heading = HEADING_KEY
-    )]
-    pub key_ota: PathBuf,
+        // Missing information can't be confirmed.
+        for args in [
+            ["--expect-device", "husky"],
+            ["--expect-fingerprint", "google/husky/"],
+        ] {
+            assert!(check_ota_expectations(&cli(&args), &OtaMetadata::default()).is_err());
+        }
+    }
 
-    /// Certificate for OTA signing key.
-    #[arg(long, value_name = "FILE", value_parser, help_heading = HEADING_KEY)]
-    pub cert_ota: PathBuf,
+    #[test]
+    fn cert_report_json() {
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=test").unwrap();
+        let validity = &cert.tbs_certificate.validity;
+
+        let report = CertReport::new(&cert).unwrap();
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "sha256": hex::encode(crypto::cert_sha256(&cert).unwrap()),
+                "subject": "CN=test",
+                "not_before": validity.not_before.to_string(),
+                "not_after": validity.not_after.to_string(),
+                "expired": false,
+            }),
+        );
+    }
 
-    /// Environment variable containing AVB private key passphrase.
-    #[arg(
-        long,
-        alias = "passphrase-avb-env-var",
-        value_name = "ENV_VAR",
-        value_parser,
-        group = "pass_avb",
-        help_heading = HEADING_KEY
-    )]
-    pub pass_avb_env_var: Option<OsString>,
+    #[test]
+    fn vbmeta_report_json() {
+        let hash = |name: &str| avb::HashDescriptor {
+            image_size: 0,
+            hash_algorithm: "sha256".to_owned(),
+            partition_name: name.to_owned(),
+            salt: vec![],
+            root_digest: vec![],
+            flags: 0,
+            reserved: [0u8; 60],
+        };
+        let mut header = Header {
+            required_libavb_version_major: avb::VERSION_MAJOR,
+            required_libavb_version_minor: avb::VERSION_MINOR,
+            algorithm_type: AlgorithmType::None,
+            hash: vec![],
+            signature: vec![],
+            public_key: vec![],
+            public_key_metadata: vec![],
+            descriptors: vec![
+                Descriptor::Hash(hash("boot")),
+                Descriptor::KernelCmdline(avb::KernelCmdlineDescriptor {
+                    flags: 0,
+                    cmdline: "foo=bar".to_owned(),
+                }),
+                Descriptor::ChainPartition(avb::ChainPartitionDescriptor {
+                    rollback_index_location: 1,
+                    partition_name: "vbmeta_system".to_owned(),
+                    public_key: vec![],
+                    flags: 0,
+                    reserved: [0u8; 60],
+                }),
+                Descriptor::Hash(hash("dtbo")),
+            ],
+            rollback_index: 5,
+            flags: 2,
+            rollback_index_location: 0,
+            release_string: String::new(),
+            reserved: [0u8; 80],
+        };
 
-    /// File containing AVB private key passphrase.
-    #[arg(
-        long,
-        alias = "passphrase-avb-file",
-        value_name = "FILE",
-        value_parser,
-        group = "pass_avb",
-        help_heading = HEADING_KEY
-    )]
-    pub pass_avb_file: Option<PathBuf>,
+        // Only partition descriptors are listed, in order.
+        let report = VbmetaReport::new(&header).unwrap();
+        assert_eq!(
+            serde_json::to_value(&report).unwrap(),
+            serde_json::json!({
+                "algorithm": "None",
+                "public_key_sha256": null,
+                "rollback_index": 5,
+                "flags": 2,
+                "partitions": ["boot", "dtbo"],
+                "chains": ["vbmeta_system"],
+            }),
+        );
 
-    /// Environment variable containing OTA private key passphrase.
-    #[arg(
-        long,
-        alias = "passphrase-ota-env-var",
-        value_name = "ENV_VAR",
-        value_parser,
-        group = "pass_ota",
-        help_heading = HEADING_KEY
-    )]
-    pub pass_ota_env_var: Option<OsString>,
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        header.algorithm_type = AlgorithmType::Sha256Rsa2048;
+        header.public_key = avb::encode_public_key(&key.to_public_key()).unwrap();
 
-    /// File containing OTA private key passphrase.
-    #[arg(
-        long,
-        alias = "passphrase-ota-file",
-        value_name = "FILE",
-        value_parser,
-        group = "pass_ota",
-        help_heading = HEADING_KEY
-    )]
-    pub pass_ota_file: Option<PathBuf>,
+        let report = VbmetaReport::new(&header).unwrap();
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["algorithm"], "Sha256Rsa2048");
+        assert_eq!(
+            value["public_key_sha256"],
+            hex::encode(crypto::public_key_sha256(&key.to_public_key()).unwrap()),
+        );
+    }
 
-    /// Use partition image from a file instead of the original payload.
-    #[arg(
-        long,
-        value_names = ["PARTITION", "FILE"],
-        value_parser = value_parser!(OsString),
-        num_args = 2,
-        help_heading = HEADING_PATH,
-    )]
-    pub replace: Vec<OsString>,
+    #[test]
+    fn verify_report_json() {
+        // Every field is present, even for checks that were never reached.
+        assert_eq!(
+            serde_json::to_value(VerifyReport::default()).unwrap(),
+            serde_json::json!({
+                "success": false,
+                "error": null,
+                "cached": false,
+                "signature_certs": [],
+                "device_signature_cert": null,
+                "otacert": null,
+                "partitions": {},
+                "vbmeta": {},
+                "otacerts": {},
+                "failed_checks": [],
+            }),
+        );
 
-    #[command(flatten)]
-    pub root: RootGroup,
+        let report = VerifyReport {
+            success: true,
+            partitions: BTreeMap::from([("boot".to_owned(), "00".repeat(32))]),
+            failed_checks: vec![Failure {
+                check: Check::SplDowngrade,
+                action: Action::Warn,
+                message: "downgrade".to_owned(),
+            }],
+            ..Default::default()
+        };
+        let value = serde_json::to_value(&report).unwrap();
+        assert_eq!(value["success"], true);
+        assert_eq!(value["partitions"]["boot"], "00".repeat(32));
+        assert_eq!(
+            value["failed_checks"],
+            serde_json::json!([{
+                "check": "spl-downgrade",
+                "action": "warn",
+                "message": "downgrade",
+            }]),
+        );
+    }
 
-    /// Magisk preinit block device (version >=25211 only).
-    #[arg(
-        long,
-        value_name = "PARTITION",
-        conflicts_with_all = ["prepatched", "rootless"],
-        help_heading = HEADING_MAGISK
-    )]
-    pub magisk_preinit_device: Option<String>,
+    #[test]
+    fn verify_cache_keeps_report() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("entry.toml");
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=test").unwrap();
+
+        let report = VerifyReport {
+            otacert: Some(CertReport::new(&cert).unwrap()),
+            partitions: BTreeMap::from([("boot".to_owned(), "00".repeat(32))]),
+            otacerts: BTreeMap::from([(
+                "init_boot".to_owned(),
+                vec![CertReport::new(&cert).unwrap()],
+            )]),
+            failed_checks: vec![Failure {
+                check: Check::SplDowngrade,
+                action: Action::Warn,
+                message: "downgrade".to_owned(),
+            }],
+            ..Default::default()
+        };
+        let entry = VerifyCacheEntry {
+            file_digest: vec![0xaa; 32],
+            report: report.clone(),
+        };
+        verify_cache_store(&path, &entry).unwrap();
 
-    /// Magisk random seed (version >=25211, <26103 only).
-    #[arg(
-        long,
-        value_name = "NUMBER",
-        conflicts_with_all = ["prepatched", "rootless"],
-        help_heading = HEADING_MAGISK
-    )]
-    pub magisk_random_seed: Option<u64>,
+        // Cached results report the same details as the original run.
+        let cached = verify_cache_lookup(&path, &[0xaa; 32]).unwrap();
+        assert_eq!(
+            serde_json::to_value(&cached).unwrap(),
+            serde_json::to_value(&report).unwrap(),
+        );
 
-    /// Ignore Magisk compatibility/version warnings.
-    #[arg(
-        long,
-        conflicts_with_all = ["prepatched", "rootless"],
-        help_heading = HEADING_MAGISK
-    )]
-    pub ignore_magisk_warnings: bool,
+        assert!(verify_cache_lookup(&path, &[0xbb; 32]).is_none());
+    }
 
-    /// Ignore compatibility issues with prepatched boot images.
-    #[arg(
-        long,
-        action = ArgAction::Count,
-        conflicts_with_all = ["magisk", "rootless"],
-        help_heading = HEADING_PREPATCHED
-    )]
-    pub ignore_prepatched_compat: u8,
+    #[test]
+    fn patch_report_digests() {
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=test").unwrap();
+
+        let header = |descriptors| Header {
+            required_libavb_version_major: avb::VERSION_MAJOR,
+            required_libavb_version_minor: avb::VERSION_MINOR,
+            algorithm_type: AlgorithmType::None,
+            hash: vec![],
+            signature: vec![],
+            public_key: vec![],
+            public_key_metadata: vec![],
+            descriptors,
+            rollback_index: 0,
+            flags: 0,
+            rollback_index_location: 0,
+            release_string: String::new(),
+            reserved: [0u8; 80],
+        };
+        let hash = |name: &str, image_size: u64| {
+            Descriptor::Hash(avb::HashDescriptor {
+                image_size,
+                hash_algorithm: "sha256".to_owned(),
+                partition_name: name.to_owned(),
+                salt: vec![],
+                root_digest: vec![0xbb; 32],
+                flags: 0,
+                reserved: [0u8; 60],
+            })
+        };
+        let input_file = |data: &[u8], state| {
+            let mut file = tempfile::tempfile().map(PSeekFile::new).unwrap();
+            file.write_all(data).unwrap();
+            InputFile { file, state }
+        };
 
-    /// Forcibly clear vbmeta flags if they disable AVB.
-    #[arg(long, help_heading = HEADING_OTHER)]
-    pub clear_vbmeta_flags: bool,
+        let vbmeta_header = header(vec![hash("boot", 0)]);
+        let mut vbmeta = input_file(b"", InputFileState::Modified);
+        avb::write_root_image(&mut vbmeta.file, &vbmeta_header, 0).unwrap();
+
+        let mut boot = input_file(b"boot", InputFileState::Modified);
+        let mut footer = avb::Footer {
+            version_major: avb::FOOTER_VERSION_MAJOR,
+            version_minor: avb::FOOTER_VERSION_MINOR,
+            original_image_size: 0,
+            vbmeta_offset: 0,
+            vbmeta_size: 0,
+            reserved: Default::default(),
+        };
+        avb::write_appended_image(
+            &mut boot.file,
+            &header(vec![hash("boot", 4)]),
+            &mut footer,
+            65536,
+        )
+        .unwrap();
 
-    /// (Deprecated: no longer needed)
-    #[arg(
-        long,
-        value_name = "PARTITION",
-        help_heading = HEADING_OTHER
-    )]
-    pub boot_partition: Option<String>,
-}
+        let mut input_files = HashMap::from([
+            ("vbmeta".to_owned(), vbmeta),
+            ("boot".to_owned(), boot),
+            // Replaced with an image that has no AVB footer.
+            (
+                "system".to_owned(),
+                input_file(&[0u8; 4096], InputFileState::External),
+            ),
+            (
+                "vendor_boot".to_owned(),
+                input_file(&[0u8; 4096], InputFileState::Extracted),
+            ),
+        ]);
+        let external_images = HashMap::from([(
+            "system".to_owned(),
+            ExternalImage::File("system.img".into()),
+        )]);
+        let vbmeta_images = HashSet::from(["vbmeta"]);
+        let vbmeta_headers = HashMap::from([("vbmeta".to_owned(), vbmeta_header)]);
+        let old_digests = BTreeMap::from([
+            ("boot".to_owned(), "aa".repeat(32)),
+            ("system".to_owned(), "cc".repeat(32)),
+        ]);
+        let boot_patchers = HashMap::from([("boot", vec!["MagiskRootPatcher"])]);
+
+        // Renamed partitions are reported under their new names.
+        let partition_map = BTreeMap::from([("boot".to_owned(), "init_boot".to_owned())]);
+        let digests =
+            get_avb_digests(input_files.iter_mut(), &vbmeta_images, &partition_map).unwrap();
+        assert_eq!(
+            digests,
+            BTreeMap::from([
+                ("init_boot".to_owned(), "bb".repeat(32)),
+                ("vbmeta".to_owned(), String::new()),
+            ]),
+        );
 
-/// Extract partition images from an OTA zip's payload.
-#[derive(Debug, Parser)]
-pub struct ExtractCli {
-    /// Path to OTA zip.
-    #[arg(short, long, value_name = "FILE", value_parser)]
-    pub input: PathBuf,
+        let mut report = PatchReport::default();
+        update_patch_report(
+            &mut report,
+            &mut input_files,
+            &external_images,
+            &vbmeta_images,
+            &vbmeta_headers,
+            &BTreeMap::new(),
+            &old_digests,
+            &boot_patchers,
+            "system",
+            &cert,
+        )
+        .unwrap();
 
-    /// Output directory for extracted images.
-    #[arg(short, long, value_parser, default_value = ".")]
-    pub directory: PathBuf,
+        let otacert_patcher = OtaCertPatcher::new(cert.clone()).patcher_name();
+        assert_eq!(
+            report.patchers,
+            BTreeSet::from(["MagiskRootPatcher", otacert_patcher]),
+        );
+        assert_eq!(report.vbmeta.keys().collect::<Vec<_>>(), ["vbmeta"],);
+        assert_eq!(
+            serde_json::to_value(&report.partitions).unwrap(),
+            serde_json::json!({
+                "boot": {
+                    "replaced": false,
+                    "patchers": ["MagiskRootPatcher"],
+                    "old_avb_digest": "aa".repeat(32),
+                    "new_avb_digest": "bb".repeat(32),
+                },
+                "system": {
+                    "replaced": true,
+                    "patchers": [otacert_patcher],
+                    "old_avb_digest": null,
+                    "new_avb_digest": null,
+                },
+                "vbmeta": {
+                    "replaced": false,
+                    "patchers": [],
+                    "old_avb_digest": null,
+                    "new_avb_digest": "",
+                },
+            }),
+        );
+    }
 
-    /// Extract all images from the payload.
-    #[arg(short, long, group = "extract")]
-    pub all: bool,
+    #[test]
+    fn output_template() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let input = temp_dir.path().join("payload");
+        fs::create_dir(&input).unwrap();
+
+        let expand = |template: &str, input_name: &str| {
+            let cli = PatchCli::try_parse_from([
+                OsStr::new("patch"),
+                OsStr::new("--input"),
+                input.as_os_str(),
+                OsStr::new("--key-avb"),
+                OsStr::new("avb.key"),
+                OsStr::new("--key-ota"),
+                OsStr::new("ota.key"),
+                OsStr::new("--cert-ota"),
+                OsStr::new("ota.crt"),
+                OsStr::new("--rootless"),
+            ])
+            .unwrap();
+
+            expand_output_template(&cli, template, input_name)
+        };
 
-    /// Extract only the boot image.
-    #[arg(long, group = "extract")]
-    pub boot_only: bool,
+        assert_eq!(
+            expand("{stem}/{root}-{name}", "ota:1.zip").unwrap(),
+            temp_dir.path().join("ota_1/rootless-ota_1.zip"),
+        );
+        assert_eq!(
+            expand("{name}", "a/b\\c").unwrap(),
+            temp_dir.path().join("a_b_c"),
+        );
 
-    /// (Deprecated: no longer needed)
-    #[arg(long, value_name = "PARTITION")]
-    pub boot_partition: Option<String>,
-}
+        assert!(expand("{name}", "..").is_err());
+        assert!(expand("{name}/.", "ota.zip").is_err());
+        assert!(expand("../{name}", "ota.zip").is_err());
+        assert!(expand("/{name}", "ota.zip").is_err());
+        assert!(expand("{incremental}", "ota.zip").is_err());
+    }
 
-/// Verify signatures of an OTA.
-///
-/// This includes both the whole-file signature and the payload signature.
-#[derive(Debug, Parser)]
-pub struct VerifyCli {
-    /// Path to OTA zip.
-    #[arg(short, long, value_name = "FILE", value_parser)]
-    pub input: PathBuf,
+    #[test]
+    fn url_output_path() {
+        let output = |url: &str| {
+            let cli = PatchCli::try_parse_from([
+                "patch",
+                "--input",
+                url,
+                "--key-avb",
+                "avb.key",
+                "--key-ota",
+                "ota.key",
+                "--cert-ota",
+                "ota.crt",
+                "--rootless",
+            ])
+            .unwrap();
+
+            patch_output_path(&cli).unwrap()
+        };
 
-    /// Certificate for verifying the OTA signatures.
-    ///
-    /// If this is omitted, the check only verifies that the signatures are
-    /// valid, not that they are trusted.
-    #[arg(long, value_name = "FILE", value_parser)]
-    pub cert_ota: Option<PathBuf>,
+        assert_eq!(
+            output("https://example.com/ota/husky-ota.zip?token=a/b#frag"),
+            Path::new("husky-ota.zip.patched"),
+        );
+        assert_eq!(
+            output("https://example.com/ota/husky%20ota%2Bx.zip/"),
+            Path::new("husky ota+x.zip.patched"),
+        );
+        assert_eq!(
+            output("https://example.com/a%2Fb.zip"),
+            Path::new("b.zip.patched"),
+        );
+        assert_eq!(
+            output("https://example.com%zz/"),
+            Path::new("ota.zip.patched")
+        );
+        assert_eq!(
+            output("https://example.com/%2E%2E"),
+            Path::new("ota.zip.patched")
+        );
+        assert_eq!(output("https://example.com"), Path::new("ota.zip.patched"));
+        assert_eq!(
+            output("http://example.com?x=/y.zip"),
+            Path::new("ota.zip.patched")
+        );
 
-    /// Public key for verifying the vbmeta signatures.
-    ///
-    /// If this is omitted, the check only verifies that the signatures are
-    /// valid, not that they are trusted.
-    #[arg(long, value_name = "FILE", value_parser)]
-    pub public_key_avb: Option<PathBuf>,
-}
+        assert_eq!(percent_decode("100%25%zz%4"), "100%%zz%4");
+    }
 
-#[allow(clippy::large_enum_variant)]
-#[derive(Debug, Subcommand)]
-enum OtaCommand {
-    Patch(PatchCli),
-    Extract(ExtractCli),
-    Verify(VerifyCli),
-}
+    #[test]
+    fn snapshot_size_ignores_stale_estimates() {
+        let header = |system_data: u64, vabc: bool| {
+            let mut header = header_with_ops(&[
+                ("system", &[Type::ReplaceXz]),
+                ("vendor", &[Type::ReplaceXz]),
+                ("boot", &[Type::ReplaceXz]),
+            ]);
+
+            for p in &mut header.manifest.partitions {
+                p.new_partition_info = Some(PartitionInfo {
+                    size: Some(4096),
+                    hash: None,
+                });
+                p.operations[0].data_length = Some(100);
+                p.estimate_cow_size = Some(1000);
+            }
+            header.manifest.partitions[0].operations[0].data_length = Some(system_data);
+
+            header.manifest.dynamic_partition_metadata = Some(DynamicPartitionMetadata {
+                groups: vec![DynamicPartitionGroup {
+                    name: "main".to_owned(),
+                    partition_names: vec!["system".to_owned(), "vendor".to_owned()],
+                    ..Default::default()
+                }],
+                snapshot_enabled: Some(true),
+                vabc_enabled: Some(vabc),
+                ..Default::default()
+            });
 
-/// Patch or extract OTA images.
-#[derive(Debug, Parser)]
-pub struct OtaCli {
-    #[command(subcommand)]
-    command: OtaCommand,
+            header
+        };
+
+        // The OTA's estimates are used for unmodified dynamic partitions.
+        let old = header(100, true);
+        assert_eq!(estimate_snapshot_size(&old, &old), Some(2000));
+
+        // The estimate for a modified partition is stale.
+        assert_eq!(estimate_snapshot_size(&old, &header(200, true)), Some(1200));
+        assert_eq!(
+            estimate_snapshot_size(&header(100, false), &header(200, false)),
+            Some(5096),
+        );
+
+        let mut no_snapshots = header(100, true);
+        no_snapshots
+            .manifest
+            .dynamic_partition_metadata
+            .as_mut()
+            .unwrap()
+            .snapshot_enabled = None;
+        assert_eq!(estimate_snapshot_size(&old, &no_snapshots), None);
+    }
 }
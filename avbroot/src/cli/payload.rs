@@ -0,0 +1,524 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    io::{BufReader, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Args, Parser, Subcommand};
+
+use crate::{
+    cli::{ota as ota_cli, status},
+    crypto::{self, PassphraseSource},
+    format::{
+        ota,
+        payload::{self, PayloadHeader},
+    },
+    protobuf::chromeos_update_engine::{
+        install_operation::Type, InstallOperation, PartitionUpdate,
+    },
+    sandbox,
+    stream::FromReader,
+};
+
+/// Parse `--<option> <PARTITION> <BOOL>` pairs.
+fn parse_partition_flags(values: &[String]) -> Result<Vec<(&str, bool)>> {
+    values
+        .chunks_exact(2)
+        .map(|pair| {
+            let value = pair[1].parse().map_err(|_| {
+                anyhow!("Invalid boolean for partition {:?}: {:?}", pair[0], pair[1])
+            })?;
+
+            Ok((pair[0].as_str(), value))
+        })
+        .collect()
+}
+
+fn find_partition<'a>(
+    partitions: &'a mut [PartitionUpdate],
+    name: &str,
+) -> Result<&'a mut PartitionUpdate> {
+    partitions
+        .iter_mut()
+        .find(|p| p.partition_name == name)
+        .ok_or_else(|| anyhow!("Partition not found in payload: {name}"))
+}
+
+/// Load the payload header from the input, let `modify` change it, and then
+/// write the new payload with the original blob data.
+fn rewrite_payload(
+    args: &RewriteGroup,
+    modify: impl FnOnce(&mut PayloadHeader) -> Result<()>,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let source = PassphraseSource::new(
+        &args.key_ota,
+        args.pass_ota_file.as_deref(),
+        args.pass_ota_env_var.as_deref(),
+    );
+    let key_ota =
+        ota_cli::load_signing_key(&args.key_ota, &source, false, args.signer_cmd.as_deref())?;
+
+    let mut reader = sandbox::open(&args.input)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", args.input))?;
+    let mut header = PayloadHeader::from_reader(&mut reader)
+        .with_context(|| format!("Failed to read payload header: {:?}", args.input))?;
+
+    modify(&mut header)?;
+
+    status!("Rewriting payload header and signatures");
+
+    let writer = sandbox::create(&args.output)
+        .map(BufWriter::new)
+        .with_context(|| format!("Failed to open for writing: {:?}", args.output))?;
+
+    let (properties, _) =
+        payload::rewrite_header(&mut reader, writer, &header, &key_ota, cancel_signal)
+            .with_context(|| format!("Failed to write payload: {:?}", args.output))?;
+
+    match &args.output_properties {
+        Some(path) => sandbox::write(path, &properties)
+            .with_context(|| format!("Failed to write payload properties: {path:?}"))?,
+        None => {
+            let mut stdout = std::io::stdout().lock();
+            stdout.write_all(properties.as_bytes())?;
+            stdout.flush()?;
+        }
+    }
+
+    Ok(())
+}
+
+fn set_metadata_subcommand(cli: &SetMetadataCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let run_postinstall = parse_partition_flags(&cli.run_postinstall)?;
+    let postinstall_optional = parse_partition_flags(&cli.postinstall_optional)?;
+
+    if cli.max_timestamp.is_none()
+        && cli.security_patch_level.is_none()
+        && run_postinstall.is_empty()
+        && postinstall_optional.is_empty()
+    {
+        bail!("No metadata changes were specified");
+    }
+
+    rewrite_payload(
+        &cli.rewrite,
+        |header| modify_metadata(cli, header, &run_postinstall, &postinstall_optional),
+        cancel_signal,
+    )
+}
+
+fn modify_metadata(
+    cli: &SetMetadataCli,
+    header: &mut PayloadHeader,
+    run_postinstall: &[(&str, bool)],
+    postinstall_optional: &[(&str, bool)],
+) -> Result<()> {
+    if let Some(timestamp) = cli.max_timestamp {
+        status!(
+            "Changing max timestamp: {:?} -> {timestamp}",
+            header.manifest.max_timestamp,
+        );
+        header.manifest.max_timestamp = Some(timestamp);
+    }
+
+    if let Some(spl) = &cli.security_patch_level {
+        status!(
+            "Changing security patch level: {:?} -> {spl:?}",
+            header.manifest.security_patch_level,
+        );
+        header.manifest.security_patch_level = Some(spl.clone());
+    }
+
+    for &(name, value) in run_postinstall {
+        let partition = find_partition(&mut header.manifest.partitions, name)?;
+        status!(
+            "Changing run_postinstall for {name}: {:?} -> {value}",
+            partition.run_postinstall,
+        );
+        partition.run_postinstall = Some(value);
+    }
+
+    for &(name, value) in postinstall_optional {
+        let partition = find_partition(&mut header.manifest.partitions, name)?;
+        status!(
+            "Changing postinstall_optional for {name}: {:?} -> {value}",
+            partition.postinstall_optional,
+        );
+        partition.postinstall_optional = Some(value);
+    }
+
+    Ok(())
+}
+
+fn resign_subcommand(cli: &ResignCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let source = PassphraseSource::new(
+        &cli.key_ota,
+        cli.pass_ota_file.as_deref(),
+        cli.pass_ota_env_var.as_deref(),
+    );
+    let key_ota =
+        ota_cli::load_signing_key(&cli.key_ota, &source, false, cli.signer_cmd.as_deref())?;
+    let cert_ota = crypto::read_pem_cert_file(&cli.cert_ota)
+        .with_context(|| format!("Failed to load certificate: {:?}", cli.cert_ota))?;
+
+    if !crypto::cert_matches_key(&cert_ota, &key_ota)? {
+        bail!(
+            "Key {:?} does not match certificate {:?}",
+            cli.key_ota,
+            cli.cert_ota,
+        );
+    }
+
+    let reader = sandbox::open(&cli.input)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let writer = sandbox::create(&cli.output)
+        .map(BufWriter::new)
+        .with_context(|| format!("Failed to open for writing: {:?}", cli.output))?;
+
+    status!("Replacing payload and OTA signatures");
+
+    let (writer, metadata, payload_metadata_size) =
+        ota::resign(reader, writer, &key_ota, &cert_ota, cancel_signal)
+            .with_context(|| format!("Failed to re-sign OTA: {:?}", cli.input))?;
+    writer
+        .into_inner()
+        .map_err(|e| e.into_error())
+        .with_context(|| format!("Failed to flush output: {:?}", cli.output))?;
+
+    status!("Verifying metadata offsets");
+
+    let reader = sandbox::open(&cli.output)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.output))?;
+    ota::verify_metadata(reader, &metadata, payload_metadata_size)
+        .context("Failed to verify OTA metadata offsets")?;
+
+    Ok(())
+}
+
+/// Operation counts and sizes for one operation type or partition.
+#[derive(Clone, Copy, Debug, Default)]
+struct OpStats {
+    count: u64,
+    /// Size of the blob data in the payload.
+    data_size: u64,
+    /// Size of the data written to the partition.
+    dst_size: u64,
+}
+
+impl OpStats {
+    fn add(&mut self, op: &InstallOperation, block_size: u64) {
+        self.count += 1;
+        self.data_size += op.data_length.unwrap_or_default();
+        self.dst_size += op
+            .dst_extents
+            .iter()
+            .filter_map(|e| e.num_blocks)
+            .sum::<u64>()
+            * block_size;
+    }
+
+    fn ratio(&self) -> String {
+        if self.dst_size == 0 {
+            "-".to_owned()
+        } else {
+            format!(
+                "{:.1}%",
+                self.data_size as f64 * 100.0 / self.dst_size as f64
+            )
+        }
+    }
+}
+
+struct PartitionStats<'a> {
+    name: &'a str,
+    total: OpStats,
+    by_type: BTreeMap<&'static str, OpStats>,
+}
+
+/// Load the payload header from either a raw payload.bin or an OTA zip.
+fn load_payload_header(path: &Path) -> Result<PayloadHeader> {
+    let mut magic = [0u8; 4];
+    sandbox::open(path)
+        .and_then(|mut f| f.read_exact(&mut magic))
+        .with_context(|| format!("Failed to read file: {path:?}"))?;
+
+    if magic == *payload::OTA_MAGIC {
+        let mut reader = sandbox::open(path)
+            .map(BufReader::new)
+            .with_context(|| format!("Failed to open for reading: {path:?}"))?;
+
+        PayloadHeader::from_reader(&mut reader)
+            .with_context(|| format!("Failed to read payload header: {path:?}"))
+    } else {
+        ota_cli::open_full_ota_payload(path).map(|(h, _)| h)
+    }
+}
+
+fn analyze_subcommand(cli: &AnalyzeCli) -> Result<()> {
+    let header = load_payload_header(&cli.input)?;
+    let block_size = u64::from(header.manifest.block_size());
+
+    let mut totals = BTreeMap::<&'static str, OpStats>::new();
+    let mut partitions = header
+        .manifest
+        .partitions
+        .iter()
+        .map(|p| {
+            let mut stats = PartitionStats {
+                name: &p.partition_name,
+                total: OpStats::default(),
+                by_type: BTreeMap::new(),
+            };
+
+            for op in &p.operations {
+                let name = op.r#type().as_str_name();
+
+                stats.total.add(op, block_size);
+                stats.by_type.entry(name).or_default().add(op, block_size);
+                totals.entry(name).or_default().add(op, block_size);
+            }
+
+            stats
+        })
+        .collect::<Vec<_>>();
+
+    println!("Operation types:");
+    println!(
+        "  {:<16} {:>8} {:>12} {:>12} {:>8}",
+        "TYPE", "COUNT", "DATA", "OUTPUT", "RATIO",
+    );
+    for (name, stats) in &totals {
+        println!(
+            "  {name:<16} {:>8} {:>12} {:>12} {:>8}",
+            stats.count,
+            ota_cli::format_mib(stats.data_size),
+            ota_cli::format_mib(stats.dst_size),
+            stats.ratio(),
+        );
+    }
+
+    println!();
+    println!("Partitions:");
+    println!(
+        "  {:<24} {:>12} {:>12} {:>8}  OPERATIONS",
+        "NAME", "DATA", "OUTPUT", "RATIO",
+    );
+    for stats in &partitions {
+        let ops = stats
+            .by_type
+            .iter()
+            .map(|(name, s)| format!("{name}={}", s.count))
+            .collect::<Vec<_>>()
+            .join(" ");
+
+        println!(
+            "  {:<24} {:>12} {:>12} {:>8}  {ops}",
+            stats.name,
+            ota_cli::format_mib(stats.total.data_size),
+            ota_cli::format_mib(stats.total.dst_size),
+            stats.total.ratio(),
+        );
+    }
+
+    partitions.sort_by_key(|s| std::cmp::Reverse(s.total.data_size));
+
+    println!();
+    println!("Largest partitions by payload data:");
+    for stats in partitions.iter().take(cli.top) {
+        println!(
+            "  {:<24} {:>12} {:>8}",
+            stats.name,
+            ota_cli::format_mib(stats.total.data_size),
+            stats.total.ratio(),
+        );
+    }
+
+    // Hints for the options that affect how partitions are compressed.
+    let with_type = |t: Type| {
+        partitions
+            .iter()
+            .filter(|s| s.by_type.contains_key(t.as_str_name()))
+            .map(|s| s.name)
+            .collect::<Vec<_>>()
+    };
+    let bz = with_type(Type::ReplaceBz);
+    let raw = with_type(Type::Replace);
+
+    if !bz.is_empty() {
+        println!();
+        println!(
+            "Partitions with bzip2 data, which `ota patch --transcode-ops` recompresses with XZ: {}",
+            bz.join(", "),
+        );
+    }
+    if !raw.is_empty() {
+        println!();
+        println!(
+            "Partitions with uncompressed data, which `ota patch` compresses if they are \
+            patched, unless --compression-skip-entropy is used: {}",
+            raw.join(", "),
+        );
+    }
+
+    Ok(())
+}
+
+pub fn payload_main(cli: &PayloadCli, cancel_signal: &AtomicBool) -> Result<()> {
+    match &cli.command {
+        PayloadCommand::SetMetadata(c) => set_metadata_subcommand(c, cancel_signal),
+        PayloadCommand::Resign(c) => resign_subcommand(c, cancel_signal),
+        PayloadCommand::Analyze(c) => analyze_subcommand(c),
+    }
+}
+
+#[derive(Debug, Args)]
+struct RewriteGroup {
+    /// Path to input payload.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output payload.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Path to output payload properties file.
+    #[arg(long, value_name = "FILE", value_parser)]
+    output_properties: Option<PathBuf>,
+
+    /// Private key for signing the payload.
+    #[arg(long, value_name = "FILE", value_parser)]
+    key_ota: PathBuf,
+
+    /// Environment variable containing private key passphrase.
+    #[arg(long, value_name = "ENV_VAR", value_parser, group = "pass")]
+    pass_ota_env_var: Option<OsString>,
+
+    /// File containing private key passphrase.
+    #[arg(long, value_name = "FILE", value_parser, group = "pass")]
+    pass_ota_file: Option<PathBuf>,
+
+    /// Command for creating signatures with the OTA key.
+    ///
+    /// --key-ota must then refer to a public key, certificate, or AVB-encoded
+    /// public key. The protocol is the same as for `ota patch --signer-cmd`.
+    #[arg(long, value_name = "PROGRAM", value_parser)]
+    signer_cmd: Option<PathBuf>,
+}
+
+/// Change manifest-level metadata and re-sign a payload.
+///
+/// Only the header and signature sections are rewritten. The partition data is
+/// copied from the original payload as-is without being recompressed. The new
+/// `payload_properties.txt` contents are written to stdout unless
+/// --output-properties is specified.
+#[derive(Debug, Parser)]
+struct SetMetadataCli {
+    #[command(flatten)]
+    rewrite: RewriteGroup,
+
+    /// New maximum timestamp, used for downgrade prevention.
+    #[arg(long, value_name = "SECONDS", allow_negative_numbers = true)]
+    max_timestamp: Option<i64>,
+
+    /// New security patch level string (eg. 2024-01-01).
+    #[arg(long, value_name = "STRING")]
+    security_patch_level: Option<String>,
+
+    /// Whether to run the postinstall step for a partition.
+    ///
+    /// This option can be specified multiple times.
+    #[arg(long, value_names = ["PARTITION", "BOOL"], num_args = 2)]
+    run_postinstall: Vec<String>,
+
+    /// Whether a postinstall failure for a partition should be ignored.
+    ///
+    /// This option can be specified multiple times.
+    #[arg(long, value_names = ["PARTITION", "BOOL"], num_args = 2)]
+    postinstall_optional: Vec<String>,
+}
+
+/// Replace the payload and whole-file signatures of an OTA with a new key.
+///
+/// This is useful for rotating the OTA signing key of already-patched OTAs. The
+/// payload's manifest and partition data are copied as-is. The payload
+/// properties, the OTA certificate, and the OTA metadata are regenerated to
+/// match the new signatures. Note that the otacerts.zip in the boot and system
+/// images is not modified, so devices only accept the output if the images
+/// already trust the new certificate.
+#[derive(Debug, Parser)]
+struct ResignCli {
+    /// Path to input OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Path to output OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    output: PathBuf,
+
+    /// Private key for signing the payload and OTA.
+    #[arg(long, value_name = "FILE", value_parser)]
+    key_ota: PathBuf,
+
+    /// Certificate for the OTA signing key.
+    #[arg(long, value_name = "FILE", value_parser)]
+    cert_ota: PathBuf,
+
+    /// Environment variable containing private key passphrase.
+    #[arg(long, value_name = "ENV_VAR", value_parser, group = "pass")]
+    pass_ota_env_var: Option<OsString>,
+
+    /// File containing private key passphrase.
+    #[arg(long, value_name = "FILE", value_parser, group = "pass")]
+    pass_ota_file: Option<PathBuf>,
+
+    /// Command for creating signatures with the OTA key.
+    ///
+    /// --key-ota must then refer to a public key, certificate, or AVB-encoded
+    /// public key. The protocol is the same as for `ota patch --signer-cmd`.
+    #[arg(long, value_name = "PROGRAM", value_parser)]
+    signer_cmd: Option<PathBuf>,
+}
+
+/// Show operation types, sizes, and compression ratios of a payload.
+///
+/// This reports the number of operations of each type, the size of the payload
+/// data compared to the size of the data written to the partitions, and the
+/// partitions with the most payload data. This is useful for deciding whether
+/// options like `ota patch --transcode-ops` are worthwhile. Only the payload
+/// header is read.
+#[derive(Debug, Parser)]
+struct AnalyzeCli {
+    /// Path to payload or OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    input: PathBuf,
+
+    /// Number of partitions to show in the list of largest partitions.
+    #[arg(long, value_name = "N", default_value_t = 10)]
+    top: usize,
+}
+
+#[derive(Debug, Subcommand)]
+enum PayloadCommand {
+    SetMetadata(SetMetadataCli),
+    Resign(ResignCli),
+    Analyze(AnalyzeCli),
+}
+
+/// Modify OTA payload files.
+#[derive(Debug, Parser)]
+pub struct PayloadCli {
+    #[command(subcommand)]
+    command: PayloadCommand,
+}
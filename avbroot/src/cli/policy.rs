@@ -0,0 +1,152 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{cell::RefCell, collections::BTreeMap, fmt, path::Path};
+
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{cli::warning, sandbox};
+
+/// What to do when a verification check fails.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Error,
+    Warn,
+    Ignore,
+}
+
+/// Verification checks whose outcome can be changed by a [`Policy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Check {
+    /// No certificate was specified, so the whole-file signature's trust is
+    /// unknown.
+    CertUnknown,
+    /// The OTA was not signed with the specified certificate.
+    CertMismatch,
+    /// The CMS signature's certificates do not include the otacert entry.
+    OtacertNotEmbedded,
+    /// The metadata's property files do not match the zip entry offsets.
+    MetadataOffsets,
+    /// The OTA permits a security patch level downgrade.
+    SplDowngrade,
+    /// No boot image contains otacerts.zip.
+    RamdiskOtacertsMissing,
+    /// A boot image's otacerts.zip does not contain the OTA certificate.
+    RamdiskOtacertsMismatch,
+    /// No otacerts.zip was found in a system image.
+    SystemOtacertsMissing,
+    /// A system image's otacerts.zip does not contain the OTA certificate.
+    SystemOtacertsMismatch,
+    /// Partitions that are not covered by any vbmeta descriptor.
+    UnprotectedPartitions,
+}
+
+impl Check {
+    fn name(self) -> &'static str {
+        match self {
+            Self::CertUnknown => "cert-unknown",
+            Self::CertMismatch => "cert-mismatch",
+            Self::OtacertNotEmbedded => "otacert-not-embedded",
+            Self::MetadataOffsets => "metadata-offsets",
+            Self::SplDowngrade => "spl-downgrade",
+            Self::RamdiskOtacertsMissing => "ramdisk-otacerts-missing",
+            Self::RamdiskOtacertsMismatch => "ramdisk-otacerts-mismatch",
+            Self::SystemOtacertsMissing => "system-otacerts-missing",
+            Self::SystemOtacertsMismatch => "system-otacerts-mismatch",
+            Self::UnprotectedPartitions => "unprotected-partitions",
+        }
+    }
+
+    /// The action used when the policy does not mention the check. This
+    /// matches the behavior from before policies existed.
+    fn default_action(self) -> Action {
+        match self {
+            Self::CertUnknown | Self::SplDowngrade | Self::SystemOtacertsMismatch => Action::Warn,
+            Self::SystemOtacertsMissing | Self::UnprotectedPartitions => Action::Ignore,
+            Self::CertMismatch
+            | Self::OtacertNotEmbedded
+            | Self::MetadataOffsets
+            | Self::RamdiskOtacertsMissing
+            | Self::RamdiskOtacertsMismatch => Action::Error,
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// A check that failed during verification and how it was handled.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct Failure {
+    pub check: Check,
+    pub action: Action,
+    pub message: String,
+}
+
+/// A set of overrides for how verification check failures are handled.
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    checks: BTreeMap<Check, Action>,
+    /// Every failure reported via [`Self::fail()`], including ignored ones.
+    #[serde(skip)]
+    failures: RefCell<Vec<Failure>>,
+}
+
+impl Policy {
+    /// Load a policy from a TOML file.
+    pub fn load(path: &Path) -> Result<Self> {
+        let data = sandbox::read_to_string(path)
+            .with_context(|| format!("Failed to read policy TOML: {path:?}"))?;
+        let policy = toml_edit::de::from_str(&data)
+            .with_context(|| format!("Failed to parse policy TOML: {path:?}"))?;
+
+        Ok(policy)
+    }
+
+    /// Change the action for `check` if the policy does not mention it.
+    pub fn set_default(&mut self, check: Check, action: Action) {
+        self.checks.entry(check).or_insert(action);
+    }
+
+    pub fn action(&self, check: Check) -> Action {
+        self.checks
+            .get(&check)
+            .copied()
+            .unwrap_or_else(|| check.default_action())
+    }
+
+    /// Report a failed check. This returns an error if the policy says the
+    /// check is fatal.
+    pub fn fail(&self, check: Check, message: impl fmt::Display) -> Result<()> {
+        let action = self.action(check);
+
+        self.failures.borrow_mut().push(Failure {
+            check,
+            action,
+            message: message.to_string(),
+        });
+
+        match action {
+            Action::Error => bail!("{message} [{check}]"),
+            Action::Warn => warning!("{message} [{check}]"),
+            Action::Ignore => {}
+        }
+
+        Ok(())
+    }
+
+    /// Get the list of failed checks in the order they were reported.
+    pub fn failures(&self) -> Vec<Failure> {
+        self.failures.borrow().clone()
+    }
+}
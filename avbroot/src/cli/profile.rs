@@ -0,0 +1,482 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{parser::ValueSource, Arg, ArgAction, ArgMatches, Command, CommandFactory, Parser};
+use serde::Deserialize;
+
+use crate::{cli::ota::PatchCli, sandbox};
+
+/// Options that identify the inputs of a specific run and cannot be set by a
+/// profile.
+const FORBIDDEN_OPTIONS: &[&str] = &["input", "profile", "config"];
+
+/// The value of a patch option in a profile.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(untagged)]
+enum Value {
+    /// A flag, like `clear-vbmeta-flags = true`.
+    Flag(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    /// An option that is specified multiple times, like `otacerts-target`.
+    List(Vec<String>),
+    /// An option that takes two values, like `replace = { system = "..." }`.
+    Pairs(BTreeMap<String, String>),
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Config {
+    #[serde(rename = "profile", default)]
+    profiles: BTreeMap<String, BTreeMap<String, Value>>,
+}
+
+impl Config {
+    fn from_toml(data: &str) -> Result<Self> {
+        Ok(toml_edit::de::from_str(data)?)
+    }
+
+    fn load(path: &Path) -> Result<Self> {
+        let data = sandbox::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {path:?}"))?;
+
+        Self::from_toml(&data).with_context(|| format!("Failed to parse config file: {path:?}"))
+    }
+}
+
+/// Get the name of an argument as it would be specified on the command line.
+fn arg_flag(arg: &Arg) -> Option<OsString> {
+    if let Some(long) = arg.get_long() {
+        Some(format!("--{long}").into())
+    } else {
+        arg.get_short().map(|s| format!("-{s}").into())
+    }
+}
+
+/// Get the arguments of `command` that were specified on the command line.
+fn specified_args<'a>(command: &'a Command, matches: &ArgMatches) -> Vec<&'a Arg> {
+    command
+        .get_arguments()
+        .filter(|a| matches.value_source(a.get_id().as_str()) == Some(ValueSource::CommandLine))
+        .collect()
+}
+
+/// Convert the arguments that were specified on the command line back into
+/// command-line arguments. Default values are not included.
+fn command_line_args(command: &Command, matches: &ArgMatches) -> Vec<OsString> {
+    let mut result = vec![];
+
+    for arg in specified_args(command, matches) {
+        let id = arg.get_id().as_str();
+        let flag = arg_flag(arg);
+
+        match arg.get_action() {
+            ArgAction::SetTrue | ArgAction::SetFalse => result.extend(flag),
+            ArgAction::Count => {
+                for _ in 0..matches.get_count(id) {
+                    result.extend(flag.clone());
+                }
+            }
+            a if a.takes_values() => {
+                for occurrence in matches.get_raw_occurrences(id).into_iter().flatten() {
+                    result.extend(flag.clone());
+                    result.extend(occurrence.map(OsString::from));
+                }
+            }
+            _ => {}
+        }
+    }
+
+    result
+}
+
+/// Check if a profile option should be ignored because the command line
+/// specifies the same option or one that cannot be used together with it.
+fn is_overridden(command: &Command, arg: &Arg, specified: &[&Arg]) -> bool {
+    let conflicts = command.get_arg_conflicts_with(arg);
+
+    specified.iter().any(|s| {
+        s.get_id() == arg.get_id()
+            || conflicts.iter().any(|c| c.get_id() == s.get_id())
+            || command
+                .get_arg_conflicts_with(s)
+                .iter()
+                .any(|c| c.get_id() == arg.get_id())
+            || command.get_groups().any(|g| {
+                !g.clone().is_multiple()
+                    && g.get_args().any(|a| a == arg.get_id())
+                    && g.get_args().any(|a| a == s.get_id())
+            })
+    })
+}
+
+/// Convert a profile option to command-line arguments.
+fn option_to_args(arg: &Arg, name: &str, value: &Value) -> Result<Vec<OsString>> {
+    let flag = OsString::from(format!("--{name}"));
+    let takes_values = arg.get_action().takes_values();
+    let num_values = arg.get_value_names().map_or(0, |n| n.len());
+
+    let args = match value {
+        Value::Flag(_) if takes_values => bail!("Option requires a value, not a boolean: {name}"),
+        Value::Flag(true) => vec![flag],
+        // There is no way to turn off a flag on the command line, so this would
+        // silently do nothing.
+        Value::Flag(false) => {
+            bail!("Flags can only be enabled; remove the option to leave it disabled: {name}")
+        }
+        _ if !takes_values => bail!("Option is a flag and must be true: {name}"),
+        Value::Pairs(_) if num_values != 2 => bail!("Option does not take key/value pairs: {name}"),
+        Value::Pairs(pairs) => pairs
+            .iter()
+            .flat_map(|(k, v)| [flag.clone(), k.into(), v.into()])
+            .collect(),
+        _ if num_values == 2 => bail!("Option requires a table of key/value pairs: {name}"),
+        Value::Integer(n) => vec![flag, n.to_string().into()],
+        Value::Float(n) => vec![flag, n.to_string().into()],
+        Value::String(s) => vec![flag, s.into()],
+        Value::List(items) => items
+            .iter()
+            .flat_map(|i| [flag.clone(), i.into()])
+            .collect(),
+    };
+
+    Ok(args)
+}
+
+/// Convert the options in a profile to command-line arguments, skipping those
+/// that are overridden by the arguments in `matches`.
+fn profile_to_args(
+    options: &BTreeMap<String, Value>,
+    matches: &ArgMatches,
+) -> Result<Vec<OsString>> {
+    // Groups declared with `#[arg(group = ...)]` only exist once the command is
+    // built.
+    let mut command = PatchCli::command();
+    command.build();
+    let specified = specified_args(&command, matches);
+    let mut result = vec![];
+
+    for (name, value) in options {
+        if FORBIDDEN_OPTIONS.contains(&name.as_str()) {
+            bail!("Option cannot be set in a profile: {name}");
+        }
+
+        let arg = command
+            .get_arguments()
+            .find(|a| a.get_long() == Some(name))
+            .ok_or_else(|| anyhow!("Unknown patch option: {name}"))?;
+
+        if is_overridden(&command, arg, &specified) {
+            continue;
+        }
+
+        result.extend(option_to_args(arg, name, value)?);
+    }
+
+    Ok(result)
+}
+
+/// Make the options of the subcommand at `path` optional so that the command
+/// line can be parsed before the profile fills in the remaining options.
+fn relax_requirements(command: Command, path: &[&str]) -> Command {
+    match path.split_first() {
+        Some((name, rest)) => command.mut_subcommand(name, |c| relax_requirements(c, rest)),
+        None => {
+            let groups = command
+                .get_groups()
+                .map(|g| g.get_id().clone())
+                .collect::<Vec<_>>();
+
+            groups
+                .iter()
+                .fold(command.mut_args(|a| a.required(false)), |command, id| {
+                    command.mut_group(id, |g| g.required(false))
+                })
+        }
+    }
+}
+
+/// Get the command-line arguments for the profile selected with `--profile`,
+/// where `matches` are the parsed arguments of `ota patch`. If `--profile` is
+/// not specified, [`None`] is returned.
+fn profile_args(
+    matches: &ArgMatches,
+    load: impl FnOnce(&Path) -> Result<Config>,
+) -> Result<Option<Vec<OsString>>> {
+    let Some(name) = matches.get_one::<String>("profile") else {
+        return Ok(None);
+    };
+    let path = matches
+        .get_one::<PathBuf>("config")
+        .expect("--config has a default value");
+    let config = load(path)?;
+
+    let options = config
+        .profiles
+        .get(name)
+        .ok_or_else(|| anyhow!("Profile {name:?} not found in config file: {path:?}"))?;
+    let args = profile_to_args(options, matches)
+        .with_context(|| format!("Invalid profile {name:?} in config file: {path:?}"))?;
+
+    Ok(Some(args))
+}
+
+fn parse_with_config<P: Parser>(
+    args: impl IntoIterator<Item = OsString>,
+    patch_paths: &[&[&str]],
+    load: impl FnOnce(&Path) -> Result<Config>,
+) -> Result<P> {
+    let args = args.into_iter().collect::<Vec<_>>();
+
+    let matches = patch_paths
+        .iter()
+        .fold(P::command(), |c, path| relax_requirements(c, path))
+        .try_get_matches_from(&args)
+        .unwrap_or_else(|e| e.exit());
+
+    let Some((path, patch_matches)) = patch_paths.iter().find_map(|path| {
+        path.iter()
+            .try_fold(&matches, |m, name| m.subcommand_matches(name))
+            .map(|m| (*path, m))
+    }) else {
+        return Ok(P::try_parse_from(args).unwrap_or_else(|e| e.exit()));
+    };
+    let Some(profile_args) = profile_args(patch_matches, load)? else {
+        return Ok(P::try_parse_from(args).unwrap_or_else(|e| e.exit()));
+    };
+
+    // Rebuild the command line from what clap parsed, with the profile's
+    // options inserted before the patch options, and parse it again to check
+    // that no required options are missing.
+    let mut command = P::command();
+    let mut matches = &matches;
+    let mut new_args = args.iter().take(1).cloned().collect::<Vec<_>>();
+
+    for name in path {
+        new_args.extend(command_line_args(&command, matches));
+        new_args.push(name.into());
+
+        command = command
+            .find_subcommand(name)
+            .cloned()
+            .expect("Subcommand was matched");
+        matches = matches
+            .subcommand_matches(name)
+            .expect("Subcommand was matched");
+    }
+
+    new_args.extend(profile_args);
+    new_args.extend(command_line_args(&command, matches));
+
+    Ok(P::try_parse_from(new_args).unwrap_or_else(|e| e.exit()))
+}
+
+/// Parse the command line, applying the patch options from the profile
+/// selected with `--profile` if the subcommand is one of the `ota patch`
+/// subcommands in `patch_paths`. Options that are specified on the command line
+/// take precedence over the profile's options, as do options that conflict
+/// with them, like a different root method.
+pub fn parse_args<P: Parser>(
+    args: impl IntoIterator<Item = impl Into<OsString>>,
+    patch_paths: &[&[&str]],
+) -> Result<P> {
+    parse_with_config(args.into_iter().map(Into::into), patch_paths, Config::load)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::cli::args::{Cli, Command as CliCommand};
+
+    use super::*;
+
+    const CONFIG: &str = r#"
+        [profile.pixel]
+        key-avb = "avb.key"
+        key-ota = "ota.key"
+        cert-ota = "ota.crt"
+        pass-avb-file = "avb.passphrase"
+        magisk = "magisk.apk"
+        magisk-preinit-device = "metadata"
+        clear-vbmeta-flags = true
+        xz-level = 3
+        otacerts-target = ["init_boot", "vendor_boot"]
+        replace = { vendor_dlkm = "vendor_dlkm.img" }
+    "#;
+
+    fn parse(args: &[&str]) -> Result<PatchCli> {
+        parse_with_config(args.iter().map(OsString::from), &[&[]], |_| {
+            Config::from_toml(CONFIG)
+        })
+    }
+
+    fn matches(args: &[&str]) -> ArgMatches {
+        relax_requirements(PatchCli::command(), &[])
+            .try_get_matches_from(args)
+            .unwrap()
+    }
+
+    #[test]
+    fn no_profile() {
+        let cli = parse(&[
+            "patch",
+            "-i",
+            "ota.zip",
+            "--key-avb",
+            "avb.key",
+            "--key-ota",
+            "ota.key",
+            "--cert-ota",
+            "ota.crt",
+            "--rootless",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.profile, None);
+        assert!(cli.root.rootless);
+    }
+
+    #[test]
+    fn apply_profile() {
+        let cli = parse(&["patch", "--profile", "pixel", "-i", "ota.zip"]).unwrap();
+
+        assert_eq!(cli.input, Path::new("ota.zip"));
+        assert_eq!(cli.key_avb, Path::new("avb.key"));
+        assert_eq!(cli.key_ota, Path::new("ota.key"));
+        assert_eq!(cli.cert_ota, Path::new("ota.crt"));
+        assert_eq!(
+            cli.pass_avb_file.as_deref(),
+            Some(Path::new("avb.passphrase")),
+        );
+        assert_eq!(cli.root.magisk.as_deref(), Some(Path::new("magisk.apk")));
+        assert_eq!(cli.magisk_preinit_device.as_deref(), Some("metadata"));
+        assert!(cli.clear_vbmeta_flags);
+        assert_eq!(cli.xz_level, 3);
+        assert_eq!(cli.otacerts_target, ["init_boot", "vendor_boot"]);
+        assert_eq!(cli.replace, ["vendor_dlkm", "vendor_dlkm.img"]);
+        assert_eq!(cli.profile.as_deref(), Some("pixel"));
+    }
+
+    #[test]
+    fn command_line_takes_precedence() {
+        let cli = parse(&[
+            "patch",
+            "--profile=pixel",
+            "-i",
+            "ota.zip",
+            "--key-avb=other.key",
+            "--pass-avb-env-var",
+            "PASSPHRASE",
+            "--rootless",
+            "--xz-level",
+            "0",
+            "--otacerts-target",
+            "system",
+        ])
+        .unwrap();
+
+        assert_eq!(cli.key_avb, Path::new("other.key"));
+        assert_eq!(cli.pass_avb_env_var, Some("PASSPHRASE".into()));
+        assert_eq!(cli.pass_avb_file, None);
+        assert!(cli.root.rootless);
+        assert_eq!(cli.root.magisk, None);
+        assert_eq!(cli.magisk_preinit_device, None);
+        assert_eq!(cli.xz_level, 0);
+        assert_eq!(cli.otacerts_target, ["system"]);
+        assert_eq!(cli.key_ota, Path::new("ota.key"));
+    }
+
+    #[test]
+    fn subcommand_name_as_value() {
+        // A value that happens to be named `patch` must not be mistaken for the
+        // subcommand.
+        let cli: Cli = parse_with_config(
+            [
+                "avbroot",
+                "--low-memory",
+                "patch",
+                "--magisk-preinit-device",
+                "patch",
+                "--profile",
+                "pixel",
+                "-i",
+                "ota.zip",
+            ]
+            .map(OsString::from),
+            &[&["ota", "patch"], &["patch"]],
+            |_| Config::from_toml(CONFIG),
+        )
+        .unwrap();
+
+        assert!(cli.low_memory);
+
+        let CliCommand::Patch(cli) = cli.command else {
+            panic!("Expected patch subcommand");
+        };
+
+        assert_eq!(cli.magisk_preinit_device.as_deref(), Some("patch"));
+        assert_eq!(cli.root.magisk.as_deref(), Some(Path::new("magisk.apk")));
+        assert_eq!(cli.key_avb, Path::new("avb.key"));
+    }
+
+    #[test]
+    fn config_path() {
+        for (args, expected) in [
+            (
+                &["patch", "--profile", "pixel", "-i", "ota.zip"][..],
+                "avbroot.toml",
+            ),
+            (
+                &[
+                    "patch",
+                    "--profile",
+                    "pixel",
+                    "--config",
+                    "other.toml",
+                    "-i",
+                    "ota.zip",
+                ],
+                "other.toml",
+            ),
+        ] {
+            let mut path = None;
+
+            profile_args(&matches(args), |p| {
+                path = Some(p.to_owned());
+                Config::from_toml(CONFIG)
+            })
+            .unwrap();
+
+            assert_eq!(path.as_deref(), Some(Path::new(expected)));
+        }
+    }
+
+    #[test]
+    fn invalid_profile() {
+        let args = ["patch", "--profile", "missing", "-i", "ota.zip"];
+        assert!(parse(&args).is_err());
+
+        let m = matches(&["patch", "-i", "ota.zip", "--rootless"]);
+
+        let config = Config::from_toml("[profile.bad]\ninput = \"ota.zip\"").unwrap();
+        let options = &config.profiles["bad"];
+        assert!(profile_to_args(options, &m).is_err());
+
+        let config = Config::from_toml("[profile.bad]\nclear-vbmeta-flags = \"yes\"").unwrap();
+        let options = &config.profiles["bad"];
+        assert!(profile_to_args(options, &m).is_err());
+
+        let config = Config::from_toml("[profile.bad]\nzero-chunks = false").unwrap();
+        let options = &config.profiles["bad"];
+        assert!(profile_to_args(options, &m).is_err());
+    }
+}
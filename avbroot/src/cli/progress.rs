@@ -0,0 +1,533 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Progress reporting for long running operations.
+//!
+//! Progress is either shown as a progress bar on stderr or written as
+//! newline-delimited JSON events for programs that wrap avbroot. The JSON
+//! events go to stderr by default, one complete line at a time, so they never
+//! mix with command output on stdout. They can be redirected to a separate file
+//! descriptor or file with [`set_output()`] so that they don't need to be
+//! separated from the human-readable logs.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    io::{self, IsTerminal, Seek, SeekFrom, Write},
+    sync::{Arc, Mutex, OnceLock},
+    time::{Duration, Instant},
+};
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::stream::{WriteSeek, WriteSeekReopen};
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, ValueEnum)]
+pub enum ProgressMode {
+    /// Show progress bars if stderr is a terminal.
+    #[default]
+    Auto,
+    /// Do not report progress.
+    None,
+    /// Always show progress bars on stderr.
+    Bar,
+    /// Write newline-delimited JSON progress events to stderr.
+    Json,
+}
+
+static MODE: OnceLock<ProgressMode> = OnceLock::new();
+
+/// Destination for JSON events if not stderr.
+static OUTPUT: OnceLock<Mutex<Box<dyn Write + Send>>> = OnceLock::new();
+
+/// The currently displayed progress bar line, if any. Log messages must clear
+/// it before being printed and redraw it afterwards.
+static BAR_LINE: Mutex<Option<String>> = Mutex::new(None);
+
+/// Minimum time between progress bar redraws.
+const BAR_INTERVAL: Duration = Duration::from_millis(100);
+
+const BAR_WIDTH: usize = 30;
+
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Event<'a> {
+    Stage {
+        stage: &'a str,
+    },
+    Progress {
+        stage: &'a str,
+        partition: Option<&'a str>,
+        current: u64,
+        total: u64,
+        percent: u32,
+        partition_percent: Option<u32>,
+        eta_secs: Option<f64>,
+    },
+}
+
+/// Set the progress reporting mode. This can only be called once.
+pub fn init(mode: ProgressMode) {
+    let mode = match mode {
+        ProgressMode::Auto if io::stderr().is_terminal() => ProgressMode::Bar,
+        ProgressMode::Auto => ProgressMode::None,
+        m => m,
+    };
+
+    let _ = MODE.set(mode);
+}
+
+fn mode() -> ProgressMode {
+    MODE.get().copied().unwrap_or(ProgressMode::None)
+}
+
+fn enabled() -> bool {
+    matches!(mode(), ProgressMode::Bar | ProgressMode::Json)
+}
+
+/// Write JSON events to `writer` instead of stderr. This can only be called
+/// once.
+pub fn set_output(writer: Box<dyn Write + Send>) -> io::Result<()> {
+    OUTPUT
+        .set(Mutex::new(writer))
+        .map_err(|_| io::Error::other("Progress output already initialized"))
+}
+
+fn emit(event: &Event) {
+    let mut line = match serde_json::to_vec(event) {
+        Ok(l) => l,
+        Err(_) => return,
+    };
+    line.push(b'\n');
+
+    // Progress reporting is best effort. A closed output should not cause the
+    // actual operation to fail. Each event is written in one call so that
+    // events from different threads never interleave.
+    let _ = match OUTPUT.get() {
+        Some(output) => {
+            let mut writer = output.lock().unwrap();
+            writer.write_all(&line).and_then(|_| writer.flush())
+        }
+        None => {
+            let mut stderr = io::stderr().lock();
+            stderr.write_all(&line).and_then(|_| stderr.flush())
+        }
+    };
+}
+
+/// Print a log message to stderr without mangling the progress bar. This is
+/// used by the `status!` and `warning!` macros.
+pub fn eprint_line(args: fmt::Arguments) {
+    let bar_line = BAR_LINE.lock().unwrap();
+    let mut stderr = io::stderr().lock();
+
+    if bar_line.is_some() {
+        let _ = write!(stderr, "\r\x1b[K");
+    }
+
+    let _ = writeln!(stderr, "{args}");
+
+    if let Some(line) = bar_line.as_ref() {
+        let _ = write!(stderr, "{line}");
+    }
+}
+
+fn draw_bar(line: Option<String>) {
+    let mut bar_line = BAR_LINE.lock().unwrap();
+    let mut stderr = io::stderr().lock();
+
+    let _ = write!(stderr, "\r\x1b[K");
+
+    match &line {
+        Some(l) => {
+            let _ = write!(stderr, "{l}");
+        }
+        // The final state of the bar is kept on screen.
+        None => {
+            if let Some(l) = bar_line.as_ref() {
+                let _ = writeln!(stderr, "{l}");
+            }
+        }
+    }
+
+    let _ = stderr.flush();
+
+    *bar_line = line;
+}
+
+/// Report that a new stage has started.
+pub fn stage(stage: &str) {
+    if mode() == ProgressMode::Json {
+        emit(&Event::Stage { stage });
+    }
+}
+
+fn percent_of(current: u64, total: u64) -> u32 {
+    // Stages with nothing to do are always complete.
+    (current.min(total) * 100)
+        .checked_div(total)
+        .map_or(100, |p| p as u32)
+}
+
+fn format_duration(secs: f64) -> String {
+    let secs = secs as u64;
+
+    format!("{}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+}
+
+struct PartitionState {
+    current: u64,
+    total: u64,
+}
+
+struct State {
+    current: u64,
+    partitions: HashMap<String, PartitionState>,
+    last_partition: Option<String>,
+    last_percent: Option<u32>,
+    last_draw: Option<Instant>,
+    finished: bool,
+}
+
+struct Inner {
+    stage: &'static str,
+    total: u64,
+    start: Instant,
+    state: Mutex<State>,
+}
+
+/// Tracks the progress of a stage, both overall and for each partition. The
+/// amounts are in bytes. In JSON mode, events are only emitted when the integer
+/// percentage changes. Cloned trackers refer to the same stage, so one can be
+/// handed to each thread.
+#[derive(Clone)]
+pub struct Tracker(Arc<Inner>);
+
+impl Tracker {
+    /// Report that a new stage has started and begin tracking its progress.
+    pub fn new(stage: &'static str, total: u64) -> Self {
+        self::stage(stage);
+
+        Self(Arc::new(Inner {
+            stage,
+            total,
+            start: Instant::now(),
+            state: Mutex::new(State {
+                current: 0,
+                partitions: HashMap::new(),
+                last_partition: None,
+                last_percent: None,
+                last_draw: None,
+                finished: false,
+            }),
+        }))
+    }
+
+    /// Like [`Self::new()`], but with the total computed from the size of each
+    /// partition. This allows the per-partition progress to be reported.
+    pub fn with_partitions<'a>(
+        stage: &'static str,
+        partitions: impl IntoIterator<Item = (&'a str, u64)>,
+    ) -> Self {
+        let partitions = partitions
+            .into_iter()
+            .map(|(n, total)| (n.to_owned(), PartitionState { current: 0, total }))
+            .collect::<HashMap<_, _>>();
+        let tracker = Self::new(stage, partitions.values().map(|p| p.total).sum());
+
+        tracker.0.state.lock().unwrap().partitions = partitions;
+
+        tracker
+    }
+
+    /// Set the absolute amount of overall progress.
+    pub fn update(&self, partition: Option<&str>, current: u64) {
+        if !enabled() {
+            return;
+        }
+
+        let mut state = self.0.state.lock().unwrap();
+        state.current = current;
+        self.report(&mut state, partition);
+    }
+
+    /// Add to the progress of `partition` and the overall progress.
+    pub fn add(&self, partition: Option<&str>, amount: u64) {
+        if !enabled() {
+            return;
+        }
+
+        let mut state = self.0.state.lock().unwrap();
+        state.current += amount;
+
+        if let Some(p) = partition.and_then(|n| state.partitions.get_mut(n)) {
+            p.current += amount;
+        }
+
+        self.report(&mut state, partition);
+    }
+
+    /// Mark the stage as complete. Work that was skipped, like sparse regions
+    /// that were never written, would otherwise leave the progress below 100%.
+    pub fn finish(&self) {
+        if !enabled() {
+            return;
+        }
+
+        let mut state = self.0.state.lock().unwrap();
+        if state.finished {
+            return;
+        }
+
+        state.current = self.0.total;
+        state.last_draw = None;
+        for p in state.partitions.values_mut() {
+            p.current = p.total;
+        }
+
+        self.report(&mut state, None);
+        state.finished = true;
+
+        if mode() == ProgressMode::Bar {
+            draw_bar(None);
+        }
+    }
+
+    /// Wrap a writer so that bytes written to it count towards `partition`.
+    pub fn writer<W>(&self, partition: &str, inner: W) -> ProgressWriter<W> {
+        ProgressWriter {
+            inner,
+            tracker: self.clone(),
+            partition: partition.to_owned(),
+        }
+    }
+
+    fn report(&self, state: &mut State, partition: Option<&str>) {
+        if state.finished {
+            return;
+        }
+
+        if let Some(p) = partition {
+            if state.last_partition.as_deref() != Some(p) {
+                state.last_partition = Some(p.to_owned());
+            }
+        }
+
+        let last_partition = state.last_partition.clone();
+        let partition = last_partition.as_deref();
+        let percent = percent_of(state.current, self.0.total);
+        let partition_percent = partition
+            .and_then(|n| state.partitions.get(n))
+            .map(|p| percent_of(p.current, p.total));
+
+        let eta_secs = (state.current > 0).then(|| {
+            let elapsed = self.0.start.elapsed().as_secs_f64();
+            elapsed * (self.0.total.saturating_sub(state.current)) as f64 / state.current as f64
+        });
+
+        match mode() {
+            ProgressMode::Json => {
+                if state.last_percent == Some(percent) {
+                    return;
+                }
+
+                emit(&Event::Progress {
+                    stage: self.0.stage,
+                    partition,
+                    current: state.current,
+                    total: self.0.total,
+                    percent,
+                    partition_percent,
+                    eta_secs,
+                });
+            }
+            ProgressMode::Bar => {
+                let now = Instant::now();
+                if state.last_draw.is_some_and(|t| now - t < BAR_INTERVAL) {
+                    return;
+                }
+                state.last_draw = Some(now);
+
+                let filled = BAR_WIDTH * percent as usize / 100;
+                let mut line = format!(
+                    "{}: [{}{}] {percent:>3}% ({:.1}/{:.1} MiB)",
+                    self.0.stage,
+                    "#".repeat(filled),
+                    "-".repeat(BAR_WIDTH - filled),
+                    state.current as f64 / 1024.0 / 1024.0,
+                    self.0.total as f64 / 1024.0 / 1024.0,
+                );
+
+                if let (Some(name), Some(p)) = (partition, partition_percent) {
+                    line.push_str(&format!(" {name}: {p}%"));
+                }
+                if let Some(eta) = eta_secs {
+                    line.push_str(&format!(" ETA {}", format_duration(eta)));
+                }
+
+                draw_bar(Some(line));
+            }
+            ProgressMode::Auto | ProgressMode::None => {}
+        }
+
+        state.last_percent = Some(percent);
+    }
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        // Don't leave a stale progress bar behind if the stage failed.
+        if mode() == ProgressMode::Bar && !self.state.get_mut().unwrap().finished {
+            draw_bar(None);
+        }
+    }
+}
+
+/// A writer wrapper that reports the number of bytes written to a [`Tracker`].
+pub struct ProgressWriter<W> {
+    inner: W,
+    tracker: Tracker,
+    partition: String,
+}
+
+impl<W> ProgressWriter<W> {
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for ProgressWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.tracker.add(Some(&self.partition), n as u64);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Seek> Seek for ProgressWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
+impl<W: WriteSeekReopen> WriteSeekReopen for ProgressWriter<W> {
+    fn reopen_boxed(&self) -> io::Result<Box<dyn WriteSeek>> {
+        Ok(Box::new(ProgressWriter {
+            inner: self.inner.reopen_boxed()?,
+            tracker: self.tracker.clone(),
+            partition: self.partition.clone(),
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    /// Writer that appends to a buffer shared with the test.
+    #[derive(Clone, Default)]
+    struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn percent() {
+        assert_eq!(percent_of(0, 200), 0);
+        assert_eq!(percent_of(199, 200), 99);
+        assert_eq!(percent_of(200, 200), 100);
+        assert_eq!(percent_of(300, 200), 100);
+        assert_eq!(percent_of(0, 0), 100);
+    }
+
+    #[test]
+    fn duration() {
+        assert_eq!(format_duration(0.0), "0:00:00");
+        assert_eq!(format_duration(59.9), "0:00:59");
+        assert_eq!(format_duration(3725.0), "1:02:05");
+    }
+
+    #[test]
+    fn json_events() {
+        // This is the only test that sets the global mode and output. Other
+        // tests' stages may show up in the output too, so events are filtered
+        // by stage name.
+        init(ProgressMode::Json);
+        let buf = SharedBuf::default();
+        set_output(Box::new(buf.clone())).unwrap();
+
+        let tracker = Tracker::with_partitions("json_events", [("a", 100), ("b", 300)]);
+
+        let mut writer = tracker.writer("a", vec![]);
+        writer.write_all(&[0u8; 50]).unwrap();
+        assert_eq!(writer.into_inner().len(), 50);
+
+        // Still 12%, so no event.
+        tracker.add(Some("a"), 1);
+        tracker.add(Some("b"), 150);
+        tracker.finish();
+        // Already finished, so no event.
+        tracker.finish();
+
+        let data = buf.0.lock().unwrap().clone();
+        let events = data
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+            .map(|l| serde_json::from_slice::<Value>(l).unwrap())
+            .filter(|e| e["stage"] == "json_events")
+            .map(|e| {
+                (
+                    e["event"].as_str().unwrap().to_owned(),
+                    e["partition"].as_str().map(|p| p.to_owned()),
+                    e["current"].as_u64(),
+                    e["percent"].as_u64(),
+                    e["partition_percent"].as_u64(),
+                )
+            })
+            .collect::<Vec<_>>();
+
+        assert_eq!(
+            events,
+            [
+                ("stage".to_owned(), None, None, None, None),
+                (
+                    "progress".to_owned(),
+                    Some("a".to_owned()),
+                    Some(50),
+                    Some(12),
+                    Some(50),
+                ),
+                (
+                    "progress".to_owned(),
+                    Some("b".to_owned()),
+                    Some(201),
+                    Some(50),
+                    Some(50),
+                ),
+                (
+                    "progress".to_owned(),
+                    Some("b".to_owned()),
+                    Some(400),
+                    Some(100),
+                    Some(100),
+                ),
+            ],
+        );
+    }
+}
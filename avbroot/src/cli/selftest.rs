@@ -0,0 +1,194 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    ffi::OsStr,
+    path::{Path, PathBuf},
+    sync::atomic::AtomicBool,
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Parser;
+use tempfile::TempDir;
+
+use crate::{
+    cli::{
+        ota::{self, PatchCli, VerifyCli},
+        status, warning,
+    },
+    crypto::{self, PassphraseSource},
+    format::avb,
+    sandbox,
+};
+
+/// Paths to the ephemeral keys used for patching fixtures.
+struct SelftestKeys {
+    key_avb: PathBuf,
+    key_ota: PathBuf,
+    cert_ota: PathBuf,
+    public_key_avb: PathBuf,
+}
+
+impl SelftestKeys {
+    fn generate(dir: &Path) -> Result<Self> {
+        let keys = Self {
+            key_avb: dir.join("avb.key"),
+            key_ota: dir.join("ota.key"),
+            cert_ota: dir.join("ota.crt"),
+            public_key_avb: dir.join("avb_pkmd.bin"),
+        };
+
+        // The keys are only used for this run, so they are not encrypted.
+        let pass_file = dir.join("empty.passphrase");
+        sandbox::write(&pass_file, "")
+            .with_context(|| format!("Failed to write file: {pass_file:?}"))?;
+        let source = PassphraseSource::File(pass_file);
+
+        let key_avb = crypto::generate_rsa_key_pair().context("Failed to generate RSA keypair")?;
+        let key_ota = crypto::generate_rsa_key_pair().context("Failed to generate RSA keypair")?;
+        let cert_ota = crypto::generate_cert(
+            &key_ota,
+            rand::random(),
+            Duration::from_secs(24 * 60 * 60),
+            "CN=avbroot selftest",
+        )
+        .context("Failed to generate certificate")?;
+        let public_key_avb = avb::encode_public_key(&key_avb.to_public_key())
+            .context("Failed to encode public key in AVB format")?;
+
+        crypto::write_pem_key_file(&keys.key_avb, &key_avb, &source)
+            .with_context(|| format!("Failed to write private key: {:?}", keys.key_avb))?;
+        crypto::write_pem_key_file(&keys.key_ota, &key_ota, &source)
+            .with_context(|| format!("Failed to write private key: {:?}", keys.key_ota))?;
+        crypto::write_pem_cert_file(&keys.cert_ota, &cert_ota)
+            .with_context(|| format!("Failed to write certificate: {:?}", keys.cert_ota))?;
+        sandbox::write(&keys.public_key_avb, public_key_avb)
+            .with_context(|| format!("Failed to write public key: {:?}", keys.public_key_avb))?;
+
+        Ok(keys)
+    }
+}
+
+/// Find all fixture OTAs in the directory, sorted by name.
+fn find_fixtures(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut fixtures = vec![];
+
+    for path in
+        sandbox::read_dir(dir).with_context(|| format!("Failed to read directory: {dir:?}"))?
+    {
+        if path.extension() == Some(OsStr::new("zip")) && path.is_file() {
+            fixtures.push(path);
+        }
+    }
+
+    fixtures.sort();
+
+    Ok(fixtures)
+}
+
+/// Patch and then verify a single fixture.
+fn run_fixture(
+    input: &Path,
+    output: &Path,
+    keys: &SelftestKeys,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let patch_cli = PatchCli::try_parse_from([
+        OsStr::new("patch"),
+        OsStr::new("--input"),
+        input.as_os_str(),
+        OsStr::new("--output"),
+        output.as_os_str(),
+        OsStr::new("--key-avb"),
+        keys.key_avb.as_os_str(),
+        OsStr::new("--key-ota"),
+        keys.key_ota.as_os_str(),
+        OsStr::new("--cert-ota"),
+        keys.cert_ota.as_os_str(),
+        OsStr::new("--rootless"),
+    ])?;
+
+    ota::patch_subcommand(&patch_cli, cancel_signal).context("Failed to patch OTA")?;
+
+    let verify_cli = VerifyCli::try_parse_from([
+        OsStr::new("verify"),
+        OsStr::new("--input"),
+        output.as_os_str(),
+        OsStr::new("--cert-ota"),
+        keys.cert_ota.as_os_str(),
+        OsStr::new("--public-key-avb"),
+        keys.public_key_avb.as_os_str(),
+    ])?;
+
+    ota::verify_subcommand(&verify_cli, cancel_signal).context("Failed to verify patched OTA")?;
+
+    Ok(())
+}
+
+pub fn selftest_main(cli: &SelftestCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let fixtures = find_fixtures(&cli.fixtures_dir)?;
+    if fixtures.is_empty() {
+        bail!("No fixture OTAs found in: {:?}", cli.fixtures_dir);
+    }
+
+    // When filesystem access is restricted, the fixtures directory is the only
+    // location that is guaranteed to be accessible.
+    let temp_dir = if sandbox::is_restricted() {
+        TempDir::new_in(&cli.fixtures_dir)
+    } else {
+        TempDir::new()
+    }
+    .context("Failed to create temporary directory")?;
+
+    status!("Generating ephemeral signing keys");
+
+    let keys = SelftestKeys::generate(temp_dir.path())?;
+    let mut failed = vec![];
+
+    for fixture in &fixtures {
+        let name = fixture.file_stem().unwrap().to_string_lossy();
+        let output = temp_dir.path().join(format!("{name}.patched.zip"));
+
+        status!("[{name}] Running patch pipeline");
+
+        match run_fixture(fixture, &output, &keys, cancel_signal) {
+            Ok(()) => status!("[{name}] Passed"),
+            Err(e) => {
+                warning!("[{name}] Failed: {e:?}");
+                failed.push(name.into_owned());
+            }
+        }
+
+        // Patched OTAs are not needed after verification.
+        let _ = sandbox::remove_file(&output);
+    }
+
+    if !failed.is_empty() {
+        bail!(
+            "{} of {} fixtures failed: {}",
+            failed.len(),
+            fixtures.len(),
+            failed.join(", "),
+        );
+    }
+
+    status!("All {} fixtures passed", fixtures.len());
+
+    Ok(())
+}
+
+/// Run the full patching pipeline against a set of fixture OTAs.
+///
+/// Every `*.zip` file in the fixtures directory is patched (without root)
+/// using freshly generated keys and then verified. The fixtures can be
+/// generated with `e2e fixtures`, which produces miniature OTAs for uncommon
+/// device layouts.
+#[derive(Debug, Parser)]
+pub struct SelftestCli {
+    /// Directory containing fixture OTA zips.
+    #[arg(long, value_name = "DIR", value_parser)]
+    pub fixtures_dir: PathBuf,
+}
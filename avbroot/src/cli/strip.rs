@@ -0,0 +1,124 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{BufReader, BufWriter, Write},
+    path::PathBuf,
+    sync::atomic::AtomicBool,
+};
+
+use anyhow::{Context, Result};
+use clap::Parser;
+
+use crate::{cli::status, format::ota, sandbox};
+
+pub fn strip_subcommand(cli: &StripCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let patched = sandbox::open(&cli.input)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let stock = sandbox::open(&cli.stock)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.stock))?;
+    let mut writer = sandbox::create(&cli.output)
+        .map(BufWriter::new)
+        .with_context(|| format!("Failed to open for writing: {:?}", cli.output))?;
+
+    status!("Stripping data that is identical to the stock OTA");
+
+    let manifest = ota::strip(patched, stock, &mut writer, cancel_signal)
+        .with_context(|| format!("Failed to strip OTA: {:?}", cli.input))?;
+
+    writer
+        .flush()
+        .with_context(|| format!("Failed to flush writes: {:?}", cli.output))?;
+
+    let removed: u64 = manifest.holes.iter().map(|h| h.size).sum();
+    status!(
+        "Removed {removed} of {} bytes in {} holes",
+        manifest.size,
+        manifest.holes.len(),
+    );
+
+    let data = ota::serialize_strip_manifest(&manifest)?;
+    sandbox::write(&cli.manifest, data)
+        .with_context(|| format!("Failed to write strip manifest: {:?}", cli.manifest))?;
+
+    Ok(())
+}
+
+pub fn unstrip_subcommand(cli: &UnstripCli, cancel_signal: &AtomicBool) -> Result<()> {
+    let data = sandbox::read(&cli.manifest)
+        .with_context(|| format!("Failed to read strip manifest: {:?}", cli.manifest))?;
+    let manifest = ota::parse_strip_manifest(&data)
+        .with_context(|| format!("Failed to parse strip manifest: {:?}", cli.manifest))?;
+
+    let stripped = sandbox::open(&cli.input)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.input))?;
+    let stock = sandbox::open(&cli.stock)
+        .map(BufReader::new)
+        .with_context(|| format!("Failed to open for reading: {:?}", cli.stock))?;
+    let writer = sandbox::create(&cli.output)
+        .map(BufWriter::new)
+        .with_context(|| format!("Failed to open for writing: {:?}", cli.output))?;
+
+    status!("Reconstructing patched OTA from stock OTA");
+
+    ota::unstrip(stripped, stock, &manifest, writer, cancel_signal)
+        .with_context(|| format!("Failed to reconstruct OTA: {:?}", cli.output))?;
+
+    status!("Reconstructed OTA matches the original digest");
+
+    Ok(())
+}
+
+/// Remove unmodified partition data from a patched OTA.
+///
+/// The payload data in the patched OTA that is identical to the stock OTA's
+/// payload data is removed. The output file only contains the remaining data
+/// and the manifest describes how to reconstruct the original patched OTA with
+/// `avbroot ota unstrip`. This is useful for archiving patched OTAs when the
+/// stock OTAs are kept anyway.
+#[derive(Debug, Parser)]
+pub struct StripCli {
+    /// Path to patched OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Path to stock OTA zip that the patched OTA was produced from.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub stock: PathBuf,
+
+    /// Path to output stripped file.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: PathBuf,
+
+    /// Path to output strip manifest.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub manifest: PathBuf,
+}
+
+/// Reconstruct a patched OTA that was stripped with `avbroot ota strip`.
+///
+/// The stock OTA must be the exact file that was used when stripping. The
+/// sha256 digest of the reconstructed OTA is checked against the manifest.
+#[derive(Debug, Parser)]
+pub struct UnstripCli {
+    /// Path to stripped file.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub input: PathBuf,
+
+    /// Path to stock OTA zip that the patched OTA was produced from.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub stock: PathBuf,
+
+    /// Path to strip manifest.
+    #[arg(long, value_name = "FILE", value_parser)]
+    pub manifest: PathBuf,
+
+    /// Path to output patched OTA zip.
+    #[arg(short, long, value_name = "FILE", value_parser)]
+    pub output: PathBuf,
+}
@@ -6,9 +6,11 @@
 use std::{
     env::{self, VarError},
     ffi::{OsStr, OsString},
-    fs::{self, File, OpenOptions},
+    fmt,
     io::{self, BufReader, BufWriter, Read, Write},
     path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    sync::Arc,
     time::Duration,
 };
 
@@ -20,24 +22,37 @@ use cms::{
         SignedData, SignerIdentifier, SignerInfo, SignerInfos,
     },
 };
+use const_oid::{db::rfc5280, AssociatedOid};
 use pkcs8::{
     pkcs5::{pbes2, scrypt},
-    DecodePrivateKey, EncodePrivateKey, EncodePublicKey, EncryptedPrivateKeyInfo, LineEnding,
-    PrivateKeyInfo,
+    DecodePrivateKey, DecodePublicKey, EncodePrivateKey, EncodePublicKey, EncryptedPrivateKeyInfo,
+    LineEnding, PrivateKeyInfo,
 };
 use rand::RngCore;
-use rsa::{pkcs1v15::SigningKey, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
-use sha2::Sha256;
+use rsa::{
+    pkcs1v15::SigningKey, traits::PublicKeyParts, Pkcs1v15Sign, Pss, RsaPrivateKey, RsaPublicKey,
+};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use sha2::{digest::DynDigest, Digest, Sha256, Sha512};
 use thiserror::Error;
 use x509_cert::{
     builder::{Builder, CertificateBuilder, Profile},
-    der::{pem::PemLabel, referenced::OwnedToRef, Any, Decode, DecodePem, EncodePem},
+    der::{pem::PemLabel, referenced::OwnedToRef, Any, Decode, DecodePem, Encode, EncodePem},
+    ext::pkix::{ExtendedKeyUsage, KeyUsage, KeyUsages},
     serial_number::SerialNumber,
     spki::{AlgorithmIdentifierOwned, SubjectPublicKeyInfoOwned},
     time::Validity,
     Certificate,
 };
 
+use crate::{
+    audit, detached, harden,
+    kms::{self, KmsKey},
+    pkcs11::{self, Pkcs11Key},
+    sandbox,
+};
+
 #[derive(Debug, Error)]
 pub enum Error {
     #[error("Passphrases do not match")]
@@ -62,12 +77,137 @@ pub enum Error {
     Der(#[from] x509_cert::der::Error),
     #[error("RSA error")]
     Rsa(#[from] rsa::Error),
+    #[error("Detached signing error")]
+    Detached(#[from] detached::Error),
+    #[error("PKCS#11 error")]
+    Pkcs11(#[from] pkcs11::Error),
+    #[error("Cloud KMS error")]
+    Kms(#[from] kms::Error),
+    #[error("Cloud KMS key cannot be used with --harden, which blocks network access: {0:?}")]
+    KmsHardened(String),
+    #[error("Failed to run signer command: {0:?}")]
+    SignerCommandRun(PathBuf, #[source] io::Error),
+    #[error("Signer command {0:?} failed: {1}")]
+    SignerCommandFailed(PathBuf, ExitStatus),
+    #[error("Signer command {0:?} returned an invalid signature")]
+    SignerCommandSignature(PathBuf, #[source] rsa::Error),
     #[error("I/O error")]
     Io(#[from] io::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Digest algorithm used by [`sign_blob()`] and [`RsaSigningKey::sign()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BlobDigest {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl BlobDigest {
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    /// Get the PKCS#1 v1.5 signature scheme for this digest algorithm.
+    pub fn pkcs1v15(self) -> Pkcs1v15Sign {
+        match self {
+            Self::Sha1 => Pkcs1v15Sign::new::<Sha1>(),
+            Self::Sha256 => Pkcs1v15Sign::new::<Sha256>(),
+            Self::Sha512 => Pkcs1v15Sign::new::<Sha512>(),
+        }
+    }
+}
+
+/// RSA signature scheme used by [`sign_blob()`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlobPadding {
+    Pkcs1v15,
+    Pss,
+}
+
+/// A key for creating RSA signatures.
+#[allow(clippy::large_enum_variant)]
+#[derive(Clone, Debug)]
+pub enum RsaSigningKey {
+    /// The private key is available locally.
+    Internal(RsaPrivateKey),
+    /// The private key is kept offline. Signatures are provided by the user via
+    /// the [`detached`] module.
+    Detached(RsaPublicKey),
+    /// The private key is stored on a PKCS#11 token.
+    Pkcs11(Arc<Pkcs11Key>),
+    /// The private key is stored in a cloud KMS.
+    Kms(Arc<KmsKey>),
+    /// Signatures are created by running an external command. See
+    /// [`sign_with_command()`] for the protocol.
+    Command {
+        program: PathBuf,
+        public_key: RsaPublicKey,
+    },
+}
+
+impl RsaSigningKey {
+    pub fn to_public_key(&self) -> RsaPublicKey {
+        match self {
+            Self::Internal(key) => key.to_public_key(),
+            Self::Detached(key) => key.clone(),
+            Self::Pkcs11(key) => key.public_key().clone(),
+            Self::Kms(key) => key.public_key().clone(),
+            Self::Command { public_key, .. } => public_key.clone(),
+        }
+    }
+
+    /// Size of the key's modulus in bytes. This is also the signature size.
+    pub fn size(&self) -> usize {
+        match self {
+            Self::Internal(key) => key.size(),
+            Self::Detached(key) => key.size(),
+            Self::Pkcs11(key) => key.public_key().size(),
+            Self::Kms(key) => key.public_key().size(),
+            Self::Command { public_key, .. } => public_key.size(),
+        }
+    }
+
+    /// Create a PKCS#1 v1.5 signature of `digest`. `purpose` describes what is
+    /// being signed and is recorded in the audit log.
+    ///
+    /// For [`Self::Detached`] keys, if the signature has not been provided yet,
+    /// a signing request is recorded and a zero-filled placeholder signature is
+    /// returned.
+    pub fn sign(&self, purpose: &str, algorithm: BlobDigest, digest: &[u8]) -> Result<Vec<u8>> {
+        let signature = match self {
+            Self::Internal(key) => key.sign(algorithm.pkcs1v15(), digest)?,
+            Self::Detached(key) => match detached::sign(key, purpose, algorithm, digest)? {
+                Some(s) => s,
+                None => return Ok(vec![0u8; key.size()]),
+            },
+            Self::Pkcs11(key) => key.sign(algorithm.pkcs1v15(), digest)?,
+            Self::Kms(key) => key.sign(algorithm, digest)?,
+            Self::Command {
+                program,
+                public_key,
+            } => sign_with_command(program, public_key, purpose, algorithm, digest)?,
+        };
+
+        audit::record(purpose, algorithm.name(), digest, &self.to_public_key())?;
+
+        Ok(signature)
+    }
+}
+
+impl From<RsaPrivateKey> for RsaSigningKey {
+    fn from(key: RsaPrivateKey) -> Self {
+        Self::Internal(key)
+    }
+}
+
 pub enum PassphraseSource {
     Prompt(String),
     EnvVar(OsString),
@@ -101,7 +241,7 @@ impl PassphraseSource {
                 first
             }
             Self::EnvVar(v) => env::var(v).map_err(|e| Error::InvalidEnvVar(v.clone(), e))?,
-            Self::File(p) => fs::read_to_string(p)?
+            Self::File(p) => sandbox::read_to_string(p)?
                 .trim_end_matches(&['\r', '\n'])
                 .to_owned(),
         };
@@ -142,6 +282,9 @@ pub fn generate_cert(
     let mut rng = rand::thread_rng();
     let cert = builder.build_with_rng(&mut rng)?;
 
+    let tbs_digest = Sha256::digest(cert.tbs_certificate.to_der()?);
+    audit::record("certificate", "sha256", &tbs_digest, &key.to_public_key())?;
+
     Ok(cert)
 }
 
@@ -211,7 +354,7 @@ pub fn write_pem_cert(mut writer: impl Write, cert: &Certificate) -> Result<()>
 
 /// Read PEM-encoded certificate from a file.
 pub fn read_pem_cert_file(path: &Path) -> Result<Certificate> {
-    let file = File::open(path)?;
+    let file = sandbox::open(path)?;
     let reader = BufReader::new(file);
 
     read_pem_cert(reader)
@@ -219,7 +362,7 @@ pub fn read_pem_cert_file(path: &Path) -> Result<Certificate> {
 
 /// Write PEM-encoded certificate to a file.
 pub fn write_pem_cert_file(path: &Path, cert: &Certificate) -> Result<()> {
-    let file = File::create(path)?;
+    let file = sandbox::create(path)?;
     let writer = BufWriter::new(file);
 
     write_pem_cert(writer, cert)
@@ -234,9 +377,19 @@ pub fn write_pem_public_key(mut writer: impl Write, key: &RsaPublicKey) -> Resul
     Ok(())
 }
 
+/// Read PEM-encoded PKCS8 public key from a reader.
+pub fn read_pem_public_key(mut reader: impl Read) -> Result<RsaPublicKey> {
+    let mut data = String::new();
+    reader.read_to_string(&mut data)?;
+
+    let key = RsaPublicKey::from_public_key_pem(&data)?;
+
+    Ok(key)
+}
+
 /// Write PEM-encoded PKCS8 public key to a file.
 pub fn write_pem_public_key_file(path: &Path, key: &RsaPublicKey) -> Result<()> {
-    let file = File::create(path)?;
+    let file = sandbox::create(path)?;
     let writer = BufWriter::new(file);
 
     write_pem_public_key(writer, key)
@@ -310,28 +463,105 @@ pub fn write_pem_key(
 
 /// Read PEM-encoded PKCS8 private key from a file.
 pub fn read_pem_key_file(path: &Path, source: &PassphraseSource) -> Result<RsaPrivateKey> {
-    let file = File::open(path)?;
+    let file = sandbox::open(path)?;
     let reader = BufReader::new(file);
 
     read_pem_key(reader, source)
 }
 
+/// Create a PKCS#1 v1.5 signature of `digest` by running `program`. The raw
+/// digest is written to the program's stdin and the raw signature is read from
+/// its stdout. The following environment variables describe the request:
+///
+/// * `AVBROOT_SIGN_PURPOSE`: What is being signed, as recorded in the audit log.
+/// * `AVBROOT_SIGN_ALGORITHM`: Digest algorithm (`sha1`, `sha256`, or
+///   `sha512`).
+/// * `AVBROOT_SIGN_DIGEST`: Hex-encoded digest.
+/// * `AVBROOT_SIGN_KEY`: Hex-encoded sha256 digest of the DER-encoded public
+///   key, for selecting which key to sign with.
+///
+/// The signature is verified against `public_key` before it is returned.
+pub fn sign_with_command(
+    program: &Path,
+    public_key: &RsaPublicKey,
+    purpose: &str,
+    algorithm: BlobDigest,
+    digest: &[u8],
+) -> Result<Vec<u8>> {
+    let run_error = |e| Error::SignerCommandRun(program.to_owned(), e);
+
+    let mut child = Command::new(program)
+        .env("AVBROOT_SIGN_PURPOSE", purpose)
+        .env("AVBROOT_SIGN_ALGORITHM", algorithm.name())
+        .env("AVBROOT_SIGN_DIGEST", hex::encode(digest))
+        .env(
+            "AVBROOT_SIGN_KEY",
+            hex::encode(public_key_sha256(public_key)?),
+        )
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(run_error)?;
+
+    // The digest is tiny, so this cannot block on a full pipe.
+    let stdin_result = child.stdin.take().unwrap().write_all(digest);
+    let output = child.wait_with_output().map_err(run_error)?;
+    if !output.status.success() {
+        return Err(Error::SignerCommandFailed(
+            program.to_owned(),
+            output.status,
+        ));
+    }
+    stdin_result.map_err(run_error)?;
+
+    public_key
+        .verify(algorithm.pkcs1v15(), digest, &output.stdout)
+        .map_err(|e| Error::SignerCommandSignature(program.to_owned(), e))?;
+
+    Ok(output.stdout)
+}
+
+/// Load a signing key. If `path` is a `pkcs11:` URI, the key is opened on the
+/// PKCS#11 token and `source` provides the PIN if the URI does not contain it.
+/// If `path` is a cloud KMS URI (see [`kms`]), the key is used remotely.
+/// Otherwise, `path` is a PEM-encoded private key file.
+pub fn read_signing_key(path: &Path, source: &PassphraseSource) -> Result<RsaSigningKey> {
+    if let Some(uri) = path.to_str().filter(|p| kms::is_kms_uri(p)) {
+        // Otherwise, this would fail with a confusing permission error.
+        if harden::is_applied() {
+            return Err(Error::KmsHardened(uri.to_owned()));
+        }
+
+        return Ok(RsaSigningKey::Kms(Arc::new(KmsKey::open(uri)?)));
+    }
+
+    if let Some(uri) = path.to_str().filter(|p| p.starts_with(pkcs11::URI_PREFIX)) {
+        let key = Pkcs11Key::open(uri, |description| match source {
+            PassphraseSource::Prompt(_) => {
+                PassphraseSource::Prompt(format!("Enter PIN for {description}: ")).acquire(false)
+            }
+            _ => source.acquire(false),
+        })?;
+
+        return Ok(RsaSigningKey::Pkcs11(Arc::new(key)));
+    }
+
+    read_pem_key_file(path, source).map(RsaSigningKey::Internal)
+}
+
 /// Save PEM-encoded PKCS8 private key to a file.
 pub fn write_pem_key_file(
     path: &Path,
     key: &RsaPrivateKey,
     source: &PassphraseSource,
 ) -> Result<()> {
-    let mut options = OpenOptions::new();
+    let mut options = sandbox::OpenOptions::new();
     options.write(true);
     options.create(true);
     options.truncate(true);
 
     #[cfg(unix)]
-    {
-        use std::os::unix::fs::OpenOptionsExt;
-        options.mode(0o600);
-    }
+    options.mode(0o600);
 
     let file = options.open(path)?;
     let writer = BufWriter::new(file);
@@ -347,13 +577,123 @@ pub fn get_public_key(cert: &Certificate) -> Result<RsaPublicKey> {
     Ok(public_key)
 }
 
-/// Check if a certificate matches a private key.
-pub fn cert_matches_key(cert: &Certificate, key: &RsaPrivateKey) -> Result<bool> {
+/// Get the sha256 digest of the DER-encoded public key.
+pub fn public_key_sha256(key: &RsaPublicKey) -> Result<[u8; 32]> {
+    let der = key.to_public_key_der()?;
+
+    Ok(Sha256::digest(der.as_bytes()).into())
+}
+
+/// Get the sha256 digest of the DER-encoded certificate.
+pub fn cert_sha256(cert: &Certificate) -> Result<[u8; 32]> {
+    let der = cert.to_der()?;
+
+    Ok(Sha256::digest(der).into())
+}
+
+/// Check if a certificate matches a signing key.
+pub fn cert_matches_key(cert: &Certificate, key: &RsaSigningKey) -> Result<bool> {
     let public_key = get_public_key(cert)?;
 
     Ok(key.to_public_key() == public_key)
 }
 
+/// A reason why a certificate's key usage extensions do not allow it to be used
+/// for signing.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KeyUsageIssue {
+    /// The key usage extension does not include `digitalSignature`.
+    NoDigitalSignature,
+    /// The extended key usage extension does not include `codeSigning`.
+    NoCodeSigning,
+}
+
+impl fmt::Display for KeyUsageIssue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NoDigitalSignature => f.write_str("key usage does not include digitalSignature"),
+            Self::NoCodeSigning => f.write_str("extended key usage does not include codeSigning"),
+        }
+    }
+}
+
+/// Check if a certificate's key usage extensions permit signing. Certificates
+/// without these extensions are unrestricted. Self-signed certificates with the
+/// `keyCertSign` key usage, like the ones created by [`generate_cert()`], are
+/// also accepted because the key usage only restricts the certificate's use
+/// within a chain and a self-signed certificate is its own chain.
+pub fn signing_key_usage_issues(cert: &Certificate) -> Result<Vec<KeyUsageIssue>> {
+    let tbs = &cert.tbs_certificate;
+    let mut issues = vec![];
+
+    if let Some((_, key_usage)) = tbs.get::<KeyUsage>()? {
+        let self_signed = tbs.issuer == tbs.subject;
+
+        let allowed = key_usage.digital_signature()
+            || (self_signed && key_usage.0.contains(KeyUsages::KeyCertSign));
+        if !allowed {
+            issues.push(KeyUsageIssue::NoDigitalSignature);
+        }
+    }
+
+    if let Some((_, ext_key_usage)) = tbs.get::<ExtendedKeyUsage>()? {
+        if !ext_key_usage.0.iter().any(|oid| {
+            *oid == rfc5280::ID_KP_CODE_SIGNING || *oid == rfc5280::ANY_EXTENDED_KEY_USAGE
+        }) {
+            issues.push(KeyUsageIssue::NoCodeSigning);
+        }
+    }
+
+    Ok(issues)
+}
+
+fn hash_reader<D: Digest + Write>(mut reader: impl Read) -> Result<Vec<u8>> {
+    let mut hasher = D::new();
+    io::copy(&mut reader, &mut hasher)?;
+
+    Ok(hasher.finalize().to_vec())
+}
+
+fn sign_digest<D: Digest + DynDigest + AssociatedOid + Send + Sync + 'static>(
+    key: &RsaPrivateKey,
+    digest: &[u8],
+    digest_name: &str,
+    padding: BlobPadding,
+) -> Result<Vec<u8>> {
+    audit::record("blob", digest_name, digest, &key.to_public_key())?;
+
+    let signature = match padding {
+        BlobPadding::Pkcs1v15 => key.sign(Pkcs1v15Sign::new::<D>(), digest)?,
+        BlobPadding::Pss => {
+            let mut rng = rand::thread_rng();
+            key.sign_with_rng(&mut rng, Pss::new::<D>(), digest)?
+        }
+    };
+
+    Ok(signature)
+}
+
+/// Sign the data from a reader with a raw RSA signature. Unlike
+/// [`cms_sign_external()`], the signature is not wrapped in any container.
+pub fn sign_blob(
+    reader: impl Read,
+    key: &RsaPrivateKey,
+    digest: BlobDigest,
+    padding: BlobPadding,
+) -> Result<Vec<u8>> {
+    match digest {
+        BlobDigest::Sha1 => {
+            sign_digest::<Sha1>(key, &hash_reader::<Sha1>(reader)?, "sha1", padding)
+        }
+        BlobDigest::Sha256 => {
+            sign_digest::<Sha256>(key, &hash_reader::<Sha256>(reader)?, "sha256", padding)
+        }
+        BlobDigest::Sha512 => {
+            sign_digest::<Sha512>(key, &hash_reader::<Sha512>(reader)?, "sha512", padding)
+        }
+    }
+}
+
 /// Parse a CMS [`SignedData`] structure from raw DER-encoded data.
 pub fn parse_cms(data: &[u8]) -> Result<SignedData> {
     let ci = ContentInfo::from_der(data)?;
@@ -385,37 +725,32 @@ pub fn get_cms_certs(sd: &SignedData) -> Vec<Certificate> {
 /// actually CMS compliant. It simply uses the CMS [`SignedData`] structure as
 /// a transport mechanism for a raw signature. Thus, we need to ensure that the
 /// signature covers nothing but the raw data.
+///
+/// If multiple signers are specified, a [`SignerInfo`] is added for each one,
+/// in order. AOSP only checks the first signer.
 pub fn cms_sign_external(
-    key: &RsaPrivateKey,
-    cert: &Certificate,
+    signers: &[(&RsaSigningKey, &Certificate)],
     digest: &[u8],
 ) -> Result<ContentInfo> {
-    let scheme = Pkcs1v15Sign::new::<Sha256>();
-    let signature = key.sign(scheme, digest)?;
-
     let digest_algorithm = AlgorithmIdentifierOwned {
         oid: const_oid::db::rfc5912::ID_SHA_256,
         parameters: None,
     };
 
-    let signed_data = SignedData {
-        version: CmsVersion::V1,
-        digest_algorithms: DigestAlgorithmIdentifiers::try_from(vec![digest_algorithm.clone()])?,
-        encap_content_info: EncapsulatedContentInfo {
-            econtent_type: const_oid::db::rfc5911::ID_DATA,
-            econtent: None,
-        },
-        certificates: Some(CertificateSet::try_from(vec![
-            CertificateChoices::Certificate(cert.clone()),
-        ])?),
-        crls: None,
-        signer_infos: SignerInfos::try_from(vec![SignerInfo {
+    let mut certificates = vec![];
+    let mut signer_infos = vec![];
+
+    for (key, cert) in signers {
+        let signature = key.sign("ota_zip", BlobDigest::Sha256, digest)?;
+
+        certificates.push(CertificateChoices::Certificate((*cert).clone()));
+        signer_infos.push(SignerInfo {
             version: CmsVersion::V1,
             sid: SignerIdentifier::IssuerAndSerialNumber(IssuerAndSerialNumber {
                 issuer: cert.tbs_certificate.issuer.clone(),
                 serial_number: cert.tbs_certificate.serial_number.clone(),
             }),
-            digest_alg: digest_algorithm,
+            digest_alg: digest_algorithm.clone(),
             signed_attrs: None,
             signature_algorithm: AlgorithmIdentifierOwned {
                 oid: const_oid::db::rfc5912::SHA_256_WITH_RSA_ENCRYPTION,
@@ -423,7 +758,19 @@ pub fn cms_sign_external(
             },
             signature: SignatureValue::new(signature)?,
             unsigned_attrs: None,
-        }])?,
+        });
+    }
+
+    let signed_data = SignedData {
+        version: CmsVersion::V1,
+        digest_algorithms: DigestAlgorithmIdentifiers::try_from(vec![digest_algorithm.clone()])?,
+        encap_content_info: EncapsulatedContentInfo {
+            econtent_type: const_oid::db::rfc5911::ID_DATA,
+            econtent: None,
+        },
+        certificates: Some(CertificateSet::try_from(certificates)?),
+        crls: None,
+        signer_infos: SignerInfos::try_from(signer_infos)?,
     };
 
     let signed_data = ContentInfo {
@@ -433,3 +780,113 @@ pub fn cms_sign_external(
 
     Ok(signed_data)
 }
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, os::unix::fs::PermissionsExt};
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    /// Create a signer command that records its request and prints the
+    /// contents of `signature` instead of signing anything.
+    fn fake_signer(dir: &Path, signature: &[u8], exit_code: u8) -> PathBuf {
+        let script = dir.join("signer.sh");
+        fs::write(dir.join("signature"), signature).unwrap();
+        fs::write(
+            &script,
+            format!(
+                "#!/bin/sh\n\
+                cat > '{dir}/digest'\n\
+                env | grep '^AVBROOT_SIGN_' | sort > '{dir}/env'\n\
+                cat '{dir}/signature'\n\
+                exit {exit_code}\n",
+                dir = dir.display(),
+            ),
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        script
+    }
+
+    #[test]
+    fn sign_with_command_protocol() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let public_key = private_key.to_public_key();
+        let digest = Sha256::digest(b"payload").to_vec();
+        let signature = private_key
+            .sign(BlobDigest::Sha256.pkcs1v15(), &digest)
+            .unwrap();
+
+        let script = fake_signer(dir, &signature, 0);
+        let result =
+            sign_with_command(&script, &public_key, "payload", BlobDigest::Sha256, &digest)
+                .unwrap();
+        assert_eq!(result, signature);
+
+        assert_eq!(fs::read(dir.join("digest")).unwrap(), digest);
+        assert_eq!(
+            fs::read_to_string(dir.join("env")).unwrap(),
+            format!(
+                "AVBROOT_SIGN_ALGORITHM=sha256\n\
+                AVBROOT_SIGN_DIGEST={}\n\
+                AVBROOT_SIGN_KEY={}\n\
+                AVBROOT_SIGN_PURPOSE=payload\n",
+                hex::encode(&digest),
+                hex::encode(public_key_sha256(&public_key).unwrap()),
+            ),
+        );
+    }
+
+    #[test]
+    fn sign_with_command_errors() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let other_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let public_key = private_key.to_public_key();
+        let digest = Sha256::digest(b"payload").to_vec();
+
+        // Signed by the wrong key.
+        let signature = other_key
+            .sign(BlobDigest::Sha256.pkcs1v15(), &digest)
+            .unwrap();
+        let script = fake_signer(dir, &signature, 0);
+        assert_matches!(
+            sign_with_command(&script, &public_key, "payload", BlobDigest::Sha256, &digest),
+            Err(Error::SignerCommandSignature(_, _))
+        );
+
+        // Garbage output.
+        let script = fake_signer(dir, b"not a signature", 0);
+        assert_matches!(
+            sign_with_command(&script, &public_key, "payload", BlobDigest::Sha256, &digest),
+            Err(Error::SignerCommandSignature(_, _))
+        );
+
+        // Non-zero exit status, even with a valid signature.
+        let signature = private_key
+            .sign(BlobDigest::Sha256.pkcs1v15(), &digest)
+            .unwrap();
+        let script = fake_signer(dir, &signature, 1);
+        assert_matches!(
+            sign_with_command(&script, &public_key, "payload", BlobDigest::Sha256, &digest),
+            Err(Error::SignerCommandFailed(_, _))
+        );
+
+        assert_matches!(
+            sign_with_command(
+                &dir.join("missing"),
+                &public_key,
+                "payload",
+                BlobDigest::Sha256,
+                &digest,
+            ),
+            Err(Error::SignerCommandRun(_, _))
+        );
+    }
+}
@@ -0,0 +1,316 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Detached signing for keys that are kept offline.
+//!
+//! When a [`RsaSigningKey::Detached`] key is used, the digest that needs to be
+//! signed is recorded as a [`SigningRequest`] and a placeholder signature is
+//! used instead. The requests can be signed on another machine that has the
+//! private key and the resulting [`DetachedSignature`]s are provided on a later
+//! run. Since patching is reproducible, the later run computes the same digests
+//! and uses the provided signatures.
+//!
+//! Signed data often contains earlier signatures. For example, the payload
+//! contains the signed vbmeta images and the OTA zip contains the payload. A
+//! request is only recorded if no signatures from an earlier stage are missing
+//! because otherwise, the digest would change once those signatures are
+//! available.
+
+use std::{
+    collections::HashMap,
+    sync::{Mutex, OnceLock},
+};
+
+use rsa::{RsaPrivateKey, RsaPublicKey};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use crate::crypto::{self, BlobDigest, RsaSigningKey};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Detached signing is not enabled")]
+    NotEnabled,
+    #[error("Detached signing is already enabled")]
+    AlreadyEnabled,
+    #[error(
+        "Provided signature for {purpose} digest {} is invalid",
+        hex::encode(digest)
+    )]
+    InvalidSignature {
+        purpose: String,
+        digest: Vec<u8>,
+        #[source]
+        source: rsa::Error,
+    },
+    #[error("Crypto error")]
+    Crypto(#[source] Box<crypto::Error>),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A digest that needs to be signed by an offline key.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningRequest {
+    /// What is being signed, like `avb_header` or `payload`.
+    pub purpose: String,
+    pub digest_algorithm: BlobDigest,
+    #[serde(with = "hex")]
+    pub digest: Vec<u8>,
+    /// sha256 digest of the DER-encoded public key.
+    #[serde(with = "hex")]
+    pub key_sha256: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SigningRequests {
+    pub requests: Vec<SigningRequest>,
+}
+
+/// A PKCS#1 v1.5 signature created for a [`SigningRequest`].
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DetachedSignature {
+    pub digest_algorithm: BlobDigest,
+    #[serde(with = "hex")]
+    pub digest: Vec<u8>,
+    #[serde(with = "hex")]
+    pub key_sha256: Vec<u8>,
+    #[serde(with = "hex")]
+    pub signature: Vec<u8>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DetachedSignatures {
+    pub signatures: Vec<DetachedSignature>,
+}
+
+/// Get the stage in which signatures for `purpose` are created. Data signed in
+/// a later stage may contain signatures from an earlier stage.
+fn stage(purpose: &str) -> u8 {
+    match purpose {
+        "avb_header" => 0,
+        "payload_metadata" | "payload" => 1,
+        _ => 2,
+    }
+}
+
+fn key_sha256(key: &RsaPublicKey) -> Result<Vec<u8>> {
+    crypto::public_key_sha256(key)
+        .map(|d| d.to_vec())
+        .map_err(|e| Error::Crypto(Box::new(e)))
+}
+
+/// Lookup key for provided signatures: key digest, digest algorithm, digest.
+type SignatureKey = (Vec<u8>, BlobDigest, Vec<u8>);
+
+/// Tracks the provided signatures and the signatures that are still missing.
+#[derive(Debug, Default)]
+pub struct DetachedSigner {
+    signatures: HashMap<SignatureKey, Vec<u8>>,
+    requests: Vec<SigningRequest>,
+    /// Stage of the earliest missing signature.
+    missing_stage: Option<u8>,
+}
+
+impl DetachedSigner {
+    pub fn new(signatures: impl IntoIterator<Item = DetachedSignature>) -> Self {
+        let signatures = signatures
+            .into_iter()
+            .map(|s| ((s.key_sha256, s.digest_algorithm, s.digest), s.signature))
+            .collect();
+
+        Self {
+            signatures,
+            requests: vec![],
+            missing_stage: None,
+        }
+    }
+
+    /// Get the provided signature for `digest`. If the signature has not been
+    /// provided, [`None`] is returned and a request is recorded if possible.
+    pub fn sign(
+        &mut self,
+        key: &RsaPublicKey,
+        purpose: &str,
+        algorithm: BlobDigest,
+        digest: &[u8],
+    ) -> Result<Option<Vec<u8>>> {
+        let lookup = (key_sha256(key)?, algorithm, digest.to_vec());
+
+        if let Some(signature) = self.signatures.get(&lookup) {
+            key.verify(algorithm.pkcs1v15(), digest, signature)
+                .map_err(|e| Error::InvalidSignature {
+                    purpose: purpose.to_owned(),
+                    digest: digest.to_vec(),
+                    source: e,
+                })?;
+
+            return Ok(Some(signature.clone()));
+        }
+
+        let stage = stage(purpose);
+
+        match self.missing_stage {
+            Some(s) if s < stage => return Ok(None),
+            Some(s) if s == stage => {}
+            _ => {
+                self.requests.clear();
+                self.missing_stage = Some(stage);
+            }
+        }
+
+        let (key_sha256, _, digest) = lookup;
+        let request = SigningRequest {
+            purpose: purpose.to_owned(),
+            digest_algorithm: algorithm,
+            digest,
+            key_sha256,
+        };
+
+        if !self.requests.contains(&request) {
+            self.requests.push(request);
+        }
+
+        Ok(None)
+    }
+
+    /// Whether every signature was provided.
+    pub fn is_complete(&self) -> bool {
+        self.missing_stage.is_none()
+    }
+
+    /// Requests for the earliest stage that has missing signatures.
+    pub fn requests(&self) -> &[SigningRequest] {
+        &self.requests
+    }
+}
+
+static SIGNER: OnceLock<Mutex<DetachedSigner>> = OnceLock::new();
+
+/// Enable detached signing with the signatures that have been provided so far.
+/// This can only be called once.
+pub fn init(signatures: Vec<DetachedSignature>) -> Result<()> {
+    SIGNER
+        .set(Mutex::new(DetachedSigner::new(signatures)))
+        .map_err(|_| Error::AlreadyEnabled)
+}
+
+/// Like [`DetachedSigner::sign()`], but for the global signer.
+pub fn sign(
+    key: &RsaPublicKey,
+    purpose: &str,
+    algorithm: BlobDigest,
+    digest: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    let signer = SIGNER.get().ok_or(Error::NotEnabled)?;
+
+    signer.lock().unwrap().sign(key, purpose, algorithm, digest)
+}
+
+/// Get the outstanding requests from the global signer. Returns [`None`] if
+/// detached signing is not enabled or if every signature was provided.
+pub fn pending_requests() -> Option<Vec<SigningRequest>> {
+    let signer = SIGNER.get()?.lock().unwrap();
+
+    if signer.is_complete() {
+        None
+    } else {
+        Some(signer.requests().to_vec())
+    }
+}
+
+/// Sign each request that is for `key`. Requests for other keys are skipped.
+pub fn sign_requests(
+    requests: &[SigningRequest],
+    key: &RsaPrivateKey,
+) -> Result<Vec<DetachedSignature>> {
+    let signing_key = RsaSigningKey::Internal(key.clone());
+    let key_sha256 = key_sha256(&key.to_public_key())?;
+
+    requests
+        .iter()
+        .filter(|r| r.key_sha256 == key_sha256)
+        .map(|r| {
+            let signature = signing_key
+                .sign(&r.purpose, r.digest_algorithm, &r.digest)
+                .map_err(|e| Error::Crypto(Box::new(e)))?;
+
+            Ok(DetachedSignature {
+                digest_algorithm: r.digest_algorithm,
+                digest: r.digest.clone(),
+                key_sha256: r.key_sha256.clone(),
+                signature,
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn digest(byte: u8) -> Vec<u8> {
+        vec![byte; 32]
+    }
+
+    #[test]
+    fn requests_for_earliest_stage() {
+        let key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024)
+            .unwrap()
+            .to_public_key();
+        let mut signer = DetachedSigner::default();
+
+        assert!(signer.is_complete());
+
+        for purpose in ["payload", "avb_header", "avb_header", "ota_zip"] {
+            let result = signer
+                .sign(&key, purpose, BlobDigest::Sha256, &digest(1))
+                .unwrap();
+            assert_eq!(result, None);
+        }
+
+        assert!(!signer.is_complete());
+        assert_eq!(signer.requests().len(), 1);
+        assert_eq!(signer.requests()[0].purpose, "avb_header");
+        assert_eq!(signer.requests()[0].digest, digest(1));
+    }
+
+    #[test]
+    fn round_trip_signatures() {
+        let private_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let other_key = RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let key = private_key.to_public_key();
+
+        let mut signer = DetachedSigner::default();
+        signer
+            .sign(&key, "payload", BlobDigest::Sha256, &digest(1))
+            .unwrap();
+        signer
+            .sign(&key, "payload_metadata", BlobDigest::Sha256, &digest(2))
+            .unwrap();
+
+        let requests = signer.requests().to_vec();
+        assert_eq!(requests.len(), 2);
+        assert!(sign_requests(&requests, &other_key).unwrap().is_empty());
+
+        let mut signatures = sign_requests(&requests, &private_key).unwrap();
+        assert_eq!(signatures.len(), 2);
+
+        let mut signer = DetachedSigner::new(signatures.clone());
+        let signature = signer
+            .sign(&key, "payload", BlobDigest::Sha256, &digest(1))
+            .unwrap()
+            .unwrap();
+        assert_eq!(signature, signatures[0].signature);
+        assert!(signer.is_complete());
+
+        signatures[0].signature[0] ^= 0xff;
+        let mut signer = DetachedSigner::new(signatures);
+        assert!(matches!(
+            signer.sign(&key, "payload", BlobDigest::Sha256, &digest(1)),
+            Err(Error::InvalidSignature { .. }),
+        ));
+    }
+}
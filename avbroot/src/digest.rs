@@ -0,0 +1,286 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Digest computation with a selectable implementation.
+//!
+//! Hashing tens of gigabytes dominates the runtime of `ota verify` and `ota
+//! patch`. ring's assembly implementations are the fastest option on CPUs
+//! without dedicated SHA instructions, but ring does not use the x86 SHA
+//! extensions (SHA-NI). RustCrypto's sha2 crate does, so it is preferred on
+//! CPUs that support them. The backend is detected automatically on first use
+//! unless it is overridden with [`set_backend()`].
+//!
+//! The API mirrors `ring::digest` so that callers can use either
+//! interchangeably.
+
+use std::{fmt, sync::OnceLock};
+
+use sha1::Sha1;
+use sha2::{Digest as _, Sha256, Sha512};
+
+/// Largest output size of all supported algorithms.
+pub const MAX_OUTPUT_LEN: usize = 64;
+
+pub const SHA256_OUTPUT_LEN: usize = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum AlgorithmId {
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+/// A digest algorithm.
+pub struct Algorithm {
+    id: AlgorithmId,
+    name: &'static str,
+    output_len: usize,
+    ring: &'static ring::digest::Algorithm,
+}
+
+impl Algorithm {
+    /// Size of the digest in bytes.
+    pub fn output_len(&self) -> usize {
+        self.output_len
+    }
+}
+
+impl fmt::Debug for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name)
+    }
+}
+
+impl PartialEq for Algorithm {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Algorithm {}
+
+/// SHA-1. This should only be used for verifying legacy data.
+pub static SHA1_FOR_LEGACY_USE_ONLY: Algorithm = Algorithm {
+    id: AlgorithmId::Sha1,
+    name: "SHA1",
+    output_len: 20,
+    ring: &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+};
+
+pub static SHA256: Algorithm = Algorithm {
+    id: AlgorithmId::Sha256,
+    name: "SHA256",
+    output_len: SHA256_OUTPUT_LEN,
+    ring: &ring::digest::SHA256,
+};
+
+pub static SHA512: Algorithm = Algorithm {
+    id: AlgorithmId::Sha512,
+    name: "SHA512",
+    output_len: 64,
+    ring: &ring::digest::SHA512,
+};
+
+/// A computed digest.
+#[derive(Clone, Copy)]
+pub struct Digest {
+    algorithm: &'static Algorithm,
+    value: [u8; MAX_OUTPUT_LEN],
+}
+
+impl Digest {
+    fn new(algorithm: &'static Algorithm, data: &[u8]) -> Self {
+        let mut value = [0u8; MAX_OUTPUT_LEN];
+        value[..data.len()].copy_from_slice(data);
+
+        Self { algorithm, value }
+    }
+
+    pub fn algorithm(&self) -> &'static Algorithm {
+        self.algorithm
+    }
+}
+
+impl AsRef<[u8]> for Digest {
+    fn as_ref(&self) -> &[u8] {
+        &self.value[..self.algorithm.output_len]
+    }
+}
+
+impl fmt::Debug for Digest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?}:{}", self.algorithm, hex::encode(self.as_ref()))
+    }
+}
+
+#[derive(Clone)]
+enum ContextInner {
+    Ring(ring::digest::Context),
+    Sha1(Sha1),
+    Sha256(Sha256),
+    Sha512(Sha512),
+}
+
+/// A context for incrementally computing a digest. The implementation is
+/// chosen by the current [`Backend`] when the context is created.
+#[derive(Clone)]
+pub struct Context {
+    algorithm: &'static Algorithm,
+    inner: ContextInner,
+}
+
+impl Context {
+    pub fn new(algorithm: &'static Algorithm) -> Self {
+        backend().new_context(algorithm)
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match &mut self.inner {
+            ContextInner::Ring(c) => c.update(data),
+            ContextInner::Sha1(c) => c.update(data),
+            ContextInner::Sha256(c) => c.update(data),
+            ContextInner::Sha512(c) => c.update(data),
+        }
+    }
+
+    pub fn finish(self) -> Digest {
+        match self.inner {
+            ContextInner::Ring(c) => Digest::new(self.algorithm, c.finish().as_ref()),
+            ContextInner::Sha1(c) => Digest::new(self.algorithm, &c.finalize()),
+            ContextInner::Sha256(c) => Digest::new(self.algorithm, &c.finalize()),
+            ContextInner::Sha512(c) => Digest::new(self.algorithm, &c.finalize()),
+        }
+    }
+
+    pub fn algorithm(&self) -> &'static Algorithm {
+        self.algorithm
+    }
+}
+
+/// Compute the digest of `data` in one step.
+pub fn digest(algorithm: &'static Algorithm, data: &[u8]) -> Digest {
+    let mut context = Context::new(algorithm);
+    context.update(data);
+    context.finish()
+}
+
+/// An implementation of the digest algorithms.
+pub trait Backend: Send + Sync {
+    /// Name of the backend for display purposes.
+    fn name(&self) -> &'static str;
+
+    /// Whether the backend uses dedicated SHA instructions on the current CPU.
+    fn is_accelerated(&self) -> bool;
+
+    fn new_context(&self, algorithm: &'static Algorithm) -> Context;
+}
+
+/// Digests computed by ring. This uses SSSE3/AVX2 on x86_64 and the ARMv8
+/// cryptography extensions on aarch64, but never SHA-NI.
+pub struct RingBackend;
+
+impl Backend for RingBackend {
+    fn name(&self) -> &'static str {
+        "ring"
+    }
+
+    fn is_accelerated(&self) -> bool {
+        #[cfg(target_arch = "aarch64")]
+        {
+            std::arch::is_aarch64_feature_detected!("sha2")
+        }
+        #[cfg(not(target_arch = "aarch64"))]
+        {
+            false
+        }
+    }
+
+    fn new_context(&self, algorithm: &'static Algorithm) -> Context {
+        Context {
+            algorithm,
+            inner: ContextInner::Ring(ring::digest::Context::new(algorithm.ring)),
+        }
+    }
+}
+
+/// Digests computed by RustCrypto's sha1 and sha2 crates. These detect and use
+/// SHA-NI on x86 and x86_64 at runtime.
+pub struct RustCryptoBackend;
+
+impl Backend for RustCryptoBackend {
+    fn name(&self) -> &'static str {
+        "rustcrypto"
+    }
+
+    fn is_accelerated(&self) -> bool {
+        #[cfg(any(target_arch = "x86", target_arch = "x86_64"))]
+        {
+            // Same requirements as sha2's SHA-NI implementation.
+            std::arch::is_x86_feature_detected!("sha")
+                && std::arch::is_x86_feature_detected!("sse2")
+                && std::arch::is_x86_feature_detected!("ssse3")
+                && std::arch::is_x86_feature_detected!("sse4.1")
+        }
+        #[cfg(not(any(target_arch = "x86", target_arch = "x86_64")))]
+        {
+            false
+        }
+    }
+
+    fn new_context(&self, algorithm: &'static Algorithm) -> Context {
+        let inner = match algorithm.id {
+            AlgorithmId::Sha1 => ContextInner::Sha1(Sha1::new()),
+            AlgorithmId::Sha256 => ContextInner::Sha256(Sha256::new()),
+            AlgorithmId::Sha512 => ContextInner::Sha512(Sha512::new()),
+        };
+
+        Context { algorithm, inner }
+    }
+}
+
+static BACKEND: OnceLock<&'static dyn Backend> = OnceLock::new();
+
+/// Pick the fastest backend for the current CPU. RustCrypto is only faster if
+/// it can use SHA-NI.
+pub fn detect_backend() -> &'static dyn Backend {
+    if RustCryptoBackend.is_accelerated() {
+        &RustCryptoBackend
+    } else {
+        &RingBackend
+    }
+}
+
+/// Override the automatically detected backend. This must be called before
+/// any digests are computed. Returns false if a backend was already selected.
+pub fn set_backend(backend: &'static dyn Backend) -> bool {
+    BACKEND.set(backend).is_ok()
+}
+
+/// Get the backend that is used for new contexts.
+pub fn backend() -> &'static dyn Backend {
+    *BACKEND.get_or_init(detect_backend)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn backends_match() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+
+        for algorithm in [&SHA1_FOR_LEGACY_USE_ONLY, &SHA256, &SHA512] {
+            let mut ring = RingBackend.new_context(algorithm);
+            ring.update(data);
+            let mut rustcrypto = RustCryptoBackend.new_context(algorithm);
+            rustcrypto.update(data);
+
+            let expected = ring::digest::digest(algorithm.ring, data);
+
+            assert_eq!(ring.finish().as_ref(), expected.as_ref());
+            assert_eq!(rustcrypto.finish().as_ref(), expected.as_ref());
+        }
+    }
+}
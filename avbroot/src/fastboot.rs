@@ -0,0 +1,439 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Client for the fastboot protocol, as implemented by bootloaders and by
+//! fastbootd. The protocol is independent of the transport. Currently, only
+//! the TCP transport is implemented. The USB transport, which is the only one
+//! that most bootloaders (including Pixel bootloaders) support, is not.
+
+use std::{
+    io::{self, Read, Write},
+    net::TcpStream,
+    sync::atomic::AtomicBool,
+};
+
+use thiserror::Error;
+
+use crate::stream;
+
+/// Maximum length of a command, as specified by the protocol.
+const MAX_COMMAND_SIZE: usize = 4096;
+/// Maximum length of a response. Responses consist of a 4-byte status and a
+/// message.
+const MAX_RESPONSE_SIZE: usize = 256;
+/// Amount of data to send in each transport packet during a download.
+const DOWNLOAD_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Default TCP port for fastboot.
+pub const TCP_DEFAULT_PORT: u16 = 5554;
+const TCP_HANDSHAKE: &[u8; 4] = b"FB01";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Command is too long: {0:?}")]
+    CommandTooLong(String),
+    #[error("Command {command:?} failed: {reason}")]
+    CommandFailed { command: String, reason: String },
+    #[error("Unexpected response to {command:?}: {response:?}")]
+    UnexpectedResponse { command: String, response: String },
+    #[error("Data is too large for a single download: {0}")]
+    DataTooLarge(u64),
+    #[error("Device requested {actual} bytes, but expected {expected} bytes")]
+    DownloadSizeMismatch { expected: u32, actual: u32 },
+    #[error("Invalid TCP handshake: {0:?}")]
+    InvalidTcpHandshake([u8; 4]),
+    #[error("Invalid TCP packet size: {0}")]
+    InvalidTcpPacketSize(u64),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A transport that preserves packet boundaries.
+pub trait Transport {
+    /// Send a single packet.
+    fn send(&mut self, data: &[u8]) -> io::Result<()>;
+
+    /// Receive a single packet into `buf`, returning its size.
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+}
+
+/// Fastboot over TCP. Each packet is prefixed by its size as a 64-bit big
+/// endian integer.
+pub struct TcpTransport<S: Read + Write> {
+    stream: S,
+}
+
+impl TcpTransport<TcpStream> {
+    /// Connect to `host`, which may include a port.
+    pub fn connect(host: &str) -> Result<Self> {
+        let stream = if host.contains(':') {
+            TcpStream::connect(host)?
+        } else {
+            TcpStream::connect((host, TCP_DEFAULT_PORT))?
+        };
+        stream.set_nodelay(true)?;
+
+        Self::new(stream)
+    }
+}
+
+impl<S: Read + Write> TcpTransport<S> {
+    /// Perform the protocol version handshake over an existing stream.
+    pub fn new(mut stream: S) -> Result<Self> {
+        stream.write_all(TCP_HANDSHAKE)?;
+        stream.flush()?;
+
+        let mut handshake = [0u8; 4];
+        stream.read_exact(&mut handshake)?;
+
+        // Any version is accepted as long as it is a number.
+        if &handshake[..2] != b"FB" || !handshake[2..].iter().all(u8::is_ascii_digit) {
+            return Err(Error::InvalidTcpHandshake(handshake));
+        }
+
+        Ok(Self { stream })
+    }
+}
+
+impl<S: Read + Write> Transport for TcpTransport<S> {
+    fn send(&mut self, data: &[u8]) -> io::Result<()> {
+        self.stream.write_all(&(data.len() as u64).to_be_bytes())?;
+        self.stream.write_all(data)?;
+        self.stream.flush()
+    }
+
+    fn receive(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut size = [0u8; 8];
+        self.stream.read_exact(&mut size)?;
+
+        let size = u64::from_be_bytes(size);
+        if size > buf.len() as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                Error::InvalidTcpPacketSize(size),
+            ));
+        }
+
+        self.stream.read_exact(&mut buf[..size as usize])?;
+
+        Ok(size as usize)
+    }
+}
+
+type InfoCallback = Box<dyn FnMut(&str)>;
+
+enum Reply {
+    Okay(String),
+    Data(u32),
+}
+
+/// A fastboot client.
+pub struct Fastboot<T: Transport> {
+    transport: T,
+    info_callback: Option<InfoCallback>,
+}
+
+impl<T: Transport> Fastboot<T> {
+    pub fn new(transport: T) -> Self {
+        Self {
+            transport,
+            info_callback: None,
+        }
+    }
+
+    /// Set a callback for the informational messages that the device sends
+    /// while processing a command.
+    pub fn set_info_callback(&mut self, callback: impl FnMut(&str) + 'static) {
+        self.info_callback = Some(Box::new(callback));
+    }
+
+    fn send_command(&mut self, command: &str) -> Result<Reply> {
+        if command.len() > MAX_COMMAND_SIZE {
+            return Err(Error::CommandTooLong(command.to_owned()));
+        }
+
+        self.transport.send(command.as_bytes())?;
+
+        self.read_reply(command)
+    }
+
+    fn read_reply(&mut self, command: &str) -> Result<Reply> {
+        let mut buf = [0u8; MAX_RESPONSE_SIZE];
+
+        loop {
+            let n = self.transport.receive(&mut buf)?;
+            // Split before decoding so that a multi-byte character straddling
+            // the status can't cause a panic.
+            let (status, message) = buf[..n].split_at(n.min(4));
+            let message = String::from_utf8_lossy(message);
+            let unexpected = || Error::UnexpectedResponse {
+                command: command.to_owned(),
+                response: String::from_utf8_lossy(&buf[..n]).into_owned(),
+            };
+
+            match status {
+                b"INFO" | b"TEXT" => {
+                    if let Some(callback) = &mut self.info_callback {
+                        callback(&message);
+                    }
+                }
+                b"OKAY" => return Ok(Reply::Okay(message.into_owned())),
+                b"FAIL" => {
+                    return Err(Error::CommandFailed {
+                        command: command.to_owned(),
+                        reason: message.into_owned(),
+                    });
+                }
+                b"DATA" => {
+                    let size = u32::from_str_radix(&message, 16).map_err(|_| unexpected())?;
+
+                    return Ok(Reply::Data(size));
+                }
+                _ => return Err(unexpected()),
+            }
+        }
+    }
+
+    /// Run a command that does not transfer data and return the message from
+    /// the `OKAY` response.
+    pub fn command(&mut self, command: &str) -> Result<String> {
+        match self.send_command(command)? {
+            Reply::Okay(message) => Ok(message),
+            Reply::Data(size) => Err(Error::UnexpectedResponse {
+                command: command.to_owned(),
+                response: format!("DATA{size:08x}"),
+            }),
+        }
+    }
+
+    /// Get the value of a variable.
+    pub fn getvar(&mut self, name: &str) -> Result<String> {
+        self.command(&format!("getvar:{name}"))
+    }
+
+    /// Get the value of a variable or [`None`] if the device does not know
+    /// about the variable.
+    pub fn getvar_opt(&mut self, name: &str) -> Result<Option<String>> {
+        match self.getvar(name) {
+            Ok(value) => Ok(Some(value)),
+            Err(Error::CommandFailed { .. }) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Send `size` bytes from `reader` to the device's download buffer.
+    pub fn download(
+        &mut self,
+        mut reader: impl Read,
+        size: u64,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        let size_32 = u32::try_from(size).map_err(|_| Error::DataTooLarge(size))?;
+        let command = format!("download:{size_32:08x}");
+
+        match self.send_command(&command)? {
+            Reply::Data(n) if n == size_32 => {}
+            Reply::Data(n) => {
+                return Err(Error::DownloadSizeMismatch {
+                    expected: size_32,
+                    actual: n,
+                });
+            }
+            Reply::Okay(message) => {
+                return Err(Error::UnexpectedResponse {
+                    command,
+                    response: format!("OKAY{message}"),
+                });
+            }
+        }
+
+        let mut buf = vec![0u8; DOWNLOAD_CHUNK_SIZE];
+        let mut remaining = size;
+
+        while remaining > 0 {
+            stream::check_cancel(cancel_signal)?;
+
+            let n = remaining.min(buf.len() as u64) as usize;
+            reader.read_exact(&mut buf[..n])?;
+            self.transport.send(&buf[..n])?;
+
+            remaining -= n as u64;
+        }
+
+        match self.read_reply(&command)? {
+            Reply::Okay(_) => Ok(()),
+            Reply::Data(size) => Err(Error::UnexpectedResponse {
+                command,
+                response: format!("DATA{size:08x}"),
+            }),
+        }
+    }
+
+    /// Flash the contents of the download buffer to a partition.
+    pub fn flash(&mut self, partition: &str) -> Result<()> {
+        self.command(&format!("flash:{partition}")).map(|_| ())
+    }
+
+    /// Resize a logical partition in the super partition's metadata.
+    pub fn resize_logical_partition(&mut self, partition: &str, size: u64) -> Result<()> {
+        self.command(&format!("resize-logical-partition:{partition}:{size}"))
+            .map(|_| ())
+    }
+
+    /// Mark a slot as active.
+    pub fn set_active(&mut self, slot: &str) -> Result<()> {
+        self.command(&format!("set_active:{slot}")).map(|_| ())
+    }
+
+    /// Reboot the device into the OS.
+    pub fn reboot(&mut self) -> Result<()> {
+        self.command("reboot").map(|_| ())
+    }
+}
+
+/// Parse a numeric variable, like `max-download-size`. The value may be in
+/// decimal or in hex with a `0x` prefix.
+pub fn parse_number(value: &str) -> Option<u64> {
+    let value = value.trim();
+
+    match value
+        .strip_prefix("0x")
+        .or_else(|| value.strip_prefix("0X"))
+    {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, collections::VecDeque, io::Cursor, rc::Rc};
+
+    use super::*;
+
+    #[derive(Default)]
+    struct MockTransport {
+        sent: Vec<Vec<u8>>,
+        responses: VecDeque<&'static [u8]>,
+    }
+
+    impl Transport for MockTransport {
+        fn send(&mut self, data: &[u8]) -> io::Result<()> {
+            self.sent.push(data.to_vec());
+            Ok(())
+        }
+
+        fn receive(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let response = self.responses.pop_front().unwrap();
+            buf[..response.len()].copy_from_slice(response);
+            Ok(response.len())
+        }
+    }
+
+    #[test]
+    fn commands() {
+        let transport = MockTransport {
+            responses: [
+                &b"INFOhello"[..],
+                b"OKAY0x10000000",
+                b"FAILunknown variable",
+                b"DATA00000004",
+                b"OKAY",
+                b"FAILpartition does not exist",
+            ]
+            .into(),
+            ..Default::default()
+        };
+        let infos = Rc::new(RefCell::new(vec![]));
+        let cancel_signal = AtomicBool::new(false);
+
+        let mut fastboot = Fastboot::new(transport);
+        let infos_clone = infos.clone();
+        fastboot.set_info_callback(move |m| infos_clone.borrow_mut().push(m.to_owned()));
+
+        let value = fastboot.getvar("max-download-size").unwrap();
+        assert_eq!(parse_number(&value), Some(0x10000000));
+        assert_eq!(fastboot.getvar_opt("foo").unwrap(), None);
+        fastboot.download(&b"data"[..], 4, &cancel_signal).unwrap();
+        assert!(matches!(
+            fastboot.flash("foo"),
+            Err(Error::CommandFailed { .. }),
+        ));
+
+        assert_eq!(*infos.borrow(), ["hello"]);
+        assert_eq!(
+            fastboot.transport.sent,
+            [
+                &b"getvar:max-download-size"[..],
+                b"getvar:foo",
+                b"download:00000004",
+                b"data",
+                b"flash:foo",
+            ],
+        );
+    }
+
+    #[test]
+    fn non_utf8_status() {
+        let transport = MockTransport {
+            // A multi-byte character straddles the status and the message.
+            responses: ["OKA\u{e9}".as_bytes(), b"\xffOKAY"].into(),
+            ..Default::default()
+        };
+        let mut fastboot = Fastboot::new(transport);
+
+        assert!(matches!(
+            fastboot.command("foo"),
+            Err(Error::UnexpectedResponse { .. }),
+        ));
+        assert!(matches!(
+            fastboot.command("foo"),
+            Err(Error::UnexpectedResponse { .. }),
+        ));
+    }
+
+    #[test]
+    fn tcp_framing() {
+        // Cursor shares the position for reads and writes, so use separate
+        // buffers for each direction.
+        struct Duplex(Cursor<Vec<u8>>, Vec<u8>);
+
+        impl Read for Duplex {
+            fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+                self.0.read(buf)
+            }
+        }
+
+        impl Write for Duplex {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.1.write(buf)
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut input = b"FB01".to_vec();
+        input.extend(4u64.to_be_bytes());
+        input.extend(b"OKAY");
+
+        let transport = TcpTransport::new(Duplex(Cursor::new(input), vec![])).unwrap();
+        let mut fastboot = Fastboot::new(transport);
+        fastboot.reboot().unwrap();
+
+        let mut expected = b"FB01".to_vec();
+        expected.extend(6u64.to_be_bytes());
+        expected.extend(b"reboot");
+        assert_eq!(fastboot.transport.stream.1, expected);
+
+        assert!(matches!(
+            TcpTransport::new(Duplex(Cursor::new(b"XX01".to_vec()), vec![])),
+            Err(Error::InvalidTcpHandshake(_)),
+        ));
+    }
+}
@@ -12,16 +12,17 @@ use std::{
 };
 
 use bstr::ByteSlice;
-use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_bigint_dig::{ModInverse, ToBigInt};
 use num_traits::{Pow, ToPrimitive};
-use ring::digest::{Algorithm, Context};
-use rsa::{traits::PublicKeyParts, BigUint, Pkcs1v15Sign, RsaPrivateKey, RsaPublicKey};
+use rsa::{traits::PublicKeyParts, BigUint, Pkcs1v15Sign, RsaPublicKey};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256, Sha512};
 use thiserror::Error;
 
 use crate::{
+    crypto::{self, BlobDigest, RsaSigningKey},
+    digest::{Algorithm, Context},
     escape,
     format::{
         fec::{self, Fec},
@@ -91,7 +92,7 @@ pub enum Error {
         key_size: usize,
         algo: AlgorithmType,
     },
-    #[error("RSA key size (0) is not compatible with any AVB signing algorithm")]
+    #[error("RSA key size ({0} bytes) is not compatible with any AVB signing algorithm")]
     UnsupportedKey(usize),
     #[error("Hash tree does not immediately follow image data")]
     HashTreeGap,
@@ -104,9 +105,11 @@ pub enum Error {
     #[error("Must have exactly one hash or hash tree descriptor")]
     NoAppendedDescriptor,
     #[error("Failed to RSA sign digest")]
-    RsaSign(#[source] rsa::Error),
+    RsaSign(#[source] crypto::Error),
     #[error("Failed to RSA verify signature")]
     RsaVerify(#[source] rsa::Error),
+    #[error("Header cannot be written back byte-for-byte: data differs at offset {0}")]
+    NotRoundTrippable(u64),
     #[error("{0} byte image size is too small to fit header or footer")]
     ImageSizeTooSmall(u64),
     #[error("Hash tree error")]
@@ -121,9 +124,9 @@ type Result<T> = std::result::Result<T, Error>;
 
 pub(crate) fn ring_algorithm(name: &str, for_verify: bool) -> Result<&'static Algorithm> {
     match name {
-        "sha1" if for_verify => Ok(&ring::digest::SHA1_FOR_LEGACY_USE_ONLY),
-        "sha256" => Ok(&ring::digest::SHA256),
-        "sha512" => Ok(&ring::digest::SHA512),
+        "sha1" if for_verify => Ok(&crate::digest::SHA1_FOR_LEGACY_USE_ONLY),
+        "sha256" => Ok(&crate::digest::SHA256),
+        "sha512" => Ok(&crate::digest::SHA512),
         a => Err(Error::UnsupportedHashAlgorithm(a.to_owned())),
     }
 }
@@ -209,17 +212,15 @@ impl AlgorithmType {
         }
     }
 
-    pub fn sign(self, key: &RsaPrivateKey, digest: &[u8]) -> Result<Vec<u8>> {
+    pub fn sign(self, key: &RsaSigningKey, digest: &[u8]) -> Result<Vec<u8>> {
         let signature = match self {
             Self::None | Self::Unknown(_) => vec![],
-            Self::Sha256Rsa2048 | Self::Sha256Rsa4096 | Self::Sha256Rsa8192 => {
-                let scheme = Pkcs1v15Sign::new::<Sha256>();
-                key.sign(scheme, digest).map_err(Error::RsaSign)?
-            }
-            Self::Sha512Rsa2048 | Self::Sha512Rsa4096 | Self::Sha512Rsa8192 => {
-                let scheme = Pkcs1v15Sign::new::<Sha512>();
-                key.sign(scheme, digest).map_err(Error::RsaSign)?
-            }
+            Self::Sha256Rsa2048 | Self::Sha256Rsa4096 | Self::Sha256Rsa8192 => key
+                .sign("avb_header", BlobDigest::Sha256, digest)
+                .map_err(Error::RsaSign)?,
+            Self::Sha512Rsa2048 | Self::Sha512Rsa4096 | Self::Sha512Rsa8192 => key
+                .sign("avb_header", BlobDigest::Sha512, digest)
+                .map_err(Error::RsaSign)?,
         };
 
         Ok(signature)
@@ -791,7 +792,7 @@ impl HashDescriptor {
         reader: impl Read,
         for_verify: bool,
         cancel_signal: &AtomicBool,
-    ) -> Result<ring::digest::Digest> {
+    ) -> Result<crate::digest::Digest> {
         let algorithm = ring_algorithm(&self.hash_algorithm, for_verify)?;
         let mut context = Context::new(algorithm);
         context.update(&self.salt);
@@ -1070,6 +1071,145 @@ impl<W: Write> ToWriter<W> for ChainPartitionDescriptor {
     }
 }
 
+/// Size of the AVB-encoded RSA 4096 public keys used in ATX structures.
+pub const ATX_PUBLIC_KEY_SIZE: usize = 1032;
+
+/// Size of the RSA 4096 signatures used in ATX certificates.
+pub const ATX_SIGNATURE_SIZE: usize = 512;
+
+/// ATX (Android Things) certificate. These fields are little endian, matching
+/// libavb_atx.
+#[derive(Clone, Eq, PartialEq, Deserialize, Serialize)]
+pub struct AtxCertificate {
+    pub version: u32,
+    #[serde(with = "hex")]
+    pub public_key: Vec<u8>,
+    #[serde(with = "hex")]
+    pub subject: [u8; 32],
+    #[serde(with = "hex")]
+    pub usage: [u8; 32],
+    pub key_version: u64,
+    #[serde(with = "hex")]
+    pub signature: Vec<u8>,
+}
+
+impl AtxCertificate {
+    pub const SIZE: usize = 4 + ATX_PUBLIC_KEY_SIZE + 32 + 32 + 8 + ATX_SIGNATURE_SIZE;
+}
+
+impl fmt::Debug for AtxCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AtxCertificate")
+            .field("version", &self.version)
+            .field("public_key", &hex::encode(&self.public_key))
+            .field("subject", &hex::encode(self.subject))
+            .field("usage", &hex::encode(self.usage))
+            .field("key_version", &self.key_version)
+            .field("signature", &hex::encode(&self.signature))
+            .finish()
+    }
+}
+
+impl<R: Read> FromReader<R> for AtxCertificate {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let version = reader.read_u32::<LittleEndian>()?;
+
+        let mut public_key = vec![0u8; ATX_PUBLIC_KEY_SIZE];
+        reader.read_exact(&mut public_key)?;
+
+        let mut subject = [0u8; 32];
+        reader.read_exact(&mut subject)?;
+
+        let mut usage = [0u8; 32];
+        reader.read_exact(&mut usage)?;
+
+        let key_version = reader.read_u64::<LittleEndian>()?;
+
+        let mut signature = vec![0u8; ATX_SIGNATURE_SIZE];
+        reader.read_exact(&mut signature)?;
+
+        let certificate = Self {
+            version,
+            public_key,
+            subject,
+            usage,
+            key_version,
+            signature,
+        };
+
+        Ok(certificate)
+    }
+}
+
+impl<W: Write> ToWriter<W> for AtxCertificate {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        if self.public_key.len() != ATX_PUBLIC_KEY_SIZE {
+            return Err(Error::FieldOutOfBounds("public_key"));
+        } else if self.signature.len() != ATX_SIGNATURE_SIZE {
+            return Err(Error::FieldOutOfBounds("signature"));
+        }
+
+        writer.write_u32::<LittleEndian>(self.version)?;
+        writer.write_all(&self.public_key)?;
+        writer.write_all(&self.subject)?;
+        writer.write_all(&self.usage)?;
+        writer.write_u64::<LittleEndian>(self.key_version)?;
+        writer.write_all(&self.signature)?;
+
+        Ok(())
+    }
+}
+
+/// ATX (Android Things) public key metadata. libavb_atx stores this in the
+/// vbmeta header's public key metadata field to chain the vbmeta signing key
+/// back to the product root key in the device's permanent attributes.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+pub struct AtxPublicKeyMetadata {
+    pub version: u32,
+    pub product_intermediate_key_certificate: AtxCertificate,
+    pub product_signing_key_certificate: AtxCertificate,
+}
+
+impl AtxPublicKeyMetadata {
+    pub const SIZE: usize = 4 + 2 * AtxCertificate::SIZE;
+}
+
+impl<R: Read> FromReader<R> for AtxPublicKeyMetadata {
+    type Error = Error;
+
+    fn from_reader(mut reader: R) -> Result<Self> {
+        let version = reader.read_u32::<LittleEndian>()?;
+        let product_intermediate_key_certificate = AtxCertificate::from_reader(&mut reader)?;
+        let product_signing_key_certificate = AtxCertificate::from_reader(&mut reader)?;
+
+        let metadata = Self {
+            version,
+            product_intermediate_key_certificate,
+            product_signing_key_certificate,
+        };
+
+        Ok(metadata)
+    }
+}
+
+impl<W: Write> ToWriter<W> for AtxPublicKeyMetadata {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        writer.write_u32::<LittleEndian>(self.version)?;
+        self.product_intermediate_key_certificate
+            .to_writer(&mut writer)?;
+        self.product_signing_key_certificate
+            .to_writer(&mut writer)?;
+
+        Ok(())
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
 #[serde(tag = "type")]
 pub enum Descriptor {
@@ -1105,6 +1245,15 @@ impl Descriptor {
             _ => None,
         }
     }
+
+    pub fn partition_name_mut(&mut self) -> Option<&mut String> {
+        match self {
+            Self::HashTree(d) => Some(&mut d.partition_name),
+            Self::Hash(d) => Some(&mut d.partition_name),
+            Self::ChainPartition(d) => Some(&mut d.partition_name),
+            _ => None,
+        }
+    }
 }
 
 impl<R: Read> FromReader<R> for Descriptor {
@@ -1270,24 +1419,29 @@ pub struct Header {
 
 impl fmt::Debug for Header {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Header")
-            .field(
-                "required_libavb_version_major",
-                &self.required_libavb_version_major,
-            )
-            .field(
-                "required_libavb_version_minor",
-                &self.required_libavb_version_minor,
-            )
-            .field("algorithm_type", &self.algorithm_type)
-            .field("hash", &hex::encode(&self.hash))
-            .field("signature", &hex::encode(&self.signature))
-            .field("public_key", &hex::encode(&self.public_key))
-            .field(
-                "public_key_metadata",
-                &hex::encode(&self.public_key_metadata),
-            )
-            .field("descriptors", &self.descriptors)
+        let mut s = f.debug_struct("Header");
+        s.field(
+            "required_libavb_version_major",
+            &self.required_libavb_version_major,
+        )
+        .field(
+            "required_libavb_version_minor",
+            &self.required_libavb_version_minor,
+        )
+        .field("algorithm_type", &self.algorithm_type)
+        .field("hash", &hex::encode(&self.hash))
+        .field("signature", &hex::encode(&self.signature))
+        .field("public_key", &hex::encode(&self.public_key))
+        .field(
+            "public_key_metadata",
+            &hex::encode(&self.public_key_metadata),
+        );
+
+        if let Some(metadata) = self.atx_public_key_metadata() {
+            s.field("atx_public_key_metadata", &metadata);
+        }
+
+        s.field("descriptors", &self.descriptors)
             .field("rollback_index", &self.rollback_index)
             .field("flags", &self.flags)
             .field("rollback_index_location", &self.rollback_index_location)
@@ -1300,6 +1454,16 @@ impl fmt::Debug for Header {
 impl Header {
     pub const SIZE: usize = 256;
 
+    /// Parse the public key metadata as ATX public key metadata. Returns
+    /// [`None`] if the metadata is not the size of the ATX structure.
+    pub fn atx_public_key_metadata(&self) -> Option<AtxPublicKeyMetadata> {
+        if self.public_key_metadata.len() != AtxPublicKeyMetadata::SIZE {
+            return None;
+        }
+
+        AtxPublicKeyMetadata::from_reader(Cursor::new(&self.public_key_metadata)).ok()
+    }
+
     fn to_writer_internal(&self, mut writer: impl Write, skip_auth_block: bool) -> Result<()> {
         let mut descriptors_writer = Cursor::new(Vec::new());
         for d in &self.descriptors {
@@ -1390,6 +1554,24 @@ impl Header {
         Ok(())
     }
 
+    /// Ensure that serializing this header produces exactly `raw`. Unknown
+    /// descriptors, descriptor ordering, and the release string are always
+    /// preserved, but other details, like non-zero descriptor padding or
+    /// non-canonical block offsets, are normalized when writing.
+    pub fn check_round_trip(&self, raw: &[u8]) -> Result<()> {
+        let mut writer = Cursor::new(Vec::new());
+        self.to_writer(&mut writer)?;
+        let data = writer.into_inner();
+
+        if let Some(offset) = data.iter().zip(raw).position(|(a, b)| a != b) {
+            return Err(Error::NotRoundTrippable(offset as u64));
+        } else if data.len() != raw.len() {
+            return Err(Error::NotRoundTrippable(data.len().min(raw.len()) as u64));
+        }
+
+        Ok(())
+    }
+
     /// Get the first hash or hash tree descriptor if there is only one. This is
     /// the case for appended AVB images.
     pub fn appended_descriptor(&self) -> Result<AppendedDescriptorRef> {
@@ -1442,11 +1624,52 @@ impl Header {
         result.ok_or(Error::NoAppendedDescriptor)
     }
 
-    pub fn set_algo_for_key(&mut self, key: &RsaPrivateKey) -> Result<()> {
+    /// Set the signing algorithm for `key`. If `algorithm` is specified, it is
+    /// used as long as it matches the key size. Otherwise, the digest algorithm
+    /// of the header's current signing algorithm is kept if it is SHA512 and
+    /// SHA256 is used in all other cases.
+    pub fn set_algo_for_key(
+        &mut self,
+        key: &RsaSigningKey,
+        algorithm: Option<AlgorithmType>,
+    ) -> Result<()> {
         let key_raw = encode_public_key(&key.to_public_key())?;
 
-        for algo in [AlgorithmType::Sha256Rsa2048, AlgorithmType::Sha256Rsa4096] {
+        if let Some(algo) = algorithm {
+            if matches!(algo, AlgorithmType::None | AlgorithmType::Unknown(_)) {
+                return Err(Error::UnsupportedAlgorithm(algo));
+            } else if key_raw.len() != algo.public_key_len() {
+                return Err(Error::IncorrectKeySize {
+                    key_size: key_raw.len(),
+                    algo,
+                });
+            }
+
+            check_local_key_support(key, algo)?;
+
+            self.algorithm_type = algo;
+            return Ok(());
+        }
+
+        let candidates = match self.algorithm_type {
+            AlgorithmType::Sha512Rsa2048
+            | AlgorithmType::Sha512Rsa4096
+            | AlgorithmType::Sha512Rsa8192 => [
+                AlgorithmType::Sha512Rsa2048,
+                AlgorithmType::Sha512Rsa4096,
+                AlgorithmType::Sha512Rsa8192,
+            ],
+            _ => [
+                AlgorithmType::Sha256Rsa2048,
+                AlgorithmType::Sha256Rsa4096,
+                AlgorithmType::Sha256Rsa8192,
+            ],
+        };
+
+        for algo in candidates {
             if key_raw.len() == algo.public_key_len() {
+                check_local_key_support(key, algo)?;
+
                 self.algorithm_type = algo;
                 return Ok(());
             }
@@ -1462,19 +1685,15 @@ impl Header {
         self.public_key_metadata.clear();
     }
 
-    pub fn sign(&mut self, key: &RsaPrivateKey) -> Result<()> {
+    pub fn sign(&mut self, key: &RsaSigningKey) -> Result<()> {
         let key_raw = encode_public_key(&key.to_public_key())?;
 
-        // RustCrypto does not support 8192-bit keys.
-        match self.algorithm_type {
-            AlgorithmType::Sha256Rsa8192
-            | AlgorithmType::Sha512Rsa8192
-            | AlgorithmType::Unknown(_) => {
-                return Err(Error::UnsupportedAlgorithm(self.algorithm_type));
-            }
-            _ => {}
+        if let AlgorithmType::Unknown(_) = self.algorithm_type {
+            return Err(Error::UnsupportedAlgorithm(self.algorithm_type));
         }
 
+        check_local_key_support(key, self.algorithm_type)?;
+
         if key_raw.len() != self.algorithm_type.public_key_len() {
             return Err(Error::IncorrectKeySize {
                 key_size: key_raw.len(),
@@ -1506,12 +1725,9 @@ impl Header {
     /// and return the public key. If the header is not signed, then `None` is
     /// returned.
     pub fn verify(&self) -> Result<Option<RsaPublicKey>> {
-        // RustCrypto does not support 8192-bit keys.
         match self.algorithm_type {
             AlgorithmType::None => return Ok(None),
-            a @ AlgorithmType::Sha256Rsa8192
-            | a @ AlgorithmType::Sha512Rsa8192
-            | a @ AlgorithmType::Unknown(_) => return Err(Error::UnsupportedAlgorithm(a)),
+            a @ AlgorithmType::Unknown(_) => return Err(Error::UnsupportedAlgorithm(a)),
             _ => {}
         }
 
@@ -1791,6 +2007,18 @@ pub fn encode_public_key(key: &RsaPublicKey) -> Result<Vec<u8>> {
     Ok(data)
 }
 
+/// RustCrypto does not support signing with 8192-bit private keys. Keys held by
+/// the other signing backends, like PKCS#11 tokens, are not affected.
+fn check_local_key_support(key: &RsaSigningKey, algo: AlgorithmType) -> Result<()> {
+    match (key, algo) {
+        (
+            RsaSigningKey::Internal(_),
+            AlgorithmType::Sha256Rsa8192 | AlgorithmType::Sha512Rsa8192,
+        ) => Err(Error::UnsupportedAlgorithm(algo)),
+        _ => Ok(()),
+    }
+}
+
 /// Decode a public key from the AVB binary format.
 pub fn decode_public_key(data: &[u8]) -> Result<RsaPublicKey> {
     let mut reader = Cursor::new(data);
@@ -1806,8 +2034,10 @@ pub fn decode_public_key(data: &[u8]) -> Result<RsaPublicKey> {
     reader.read_exact(&mut modulus_raw)?;
 
     let modulus = BigUint::from_bytes_be(&modulus_raw);
-    let public_key =
-        RsaPublicKey::new(modulus, BigUint::from(65537u32)).map_err(Error::RsaVerify)?;
+    // RustCrypto limits public keys to 4096 bits by default, but AVB supports
+    // 8192-bit keys.
+    let public_key = RsaPublicKey::new_with_max_size(modulus, BigUint::from(65537u32), 8192)
+        .map_err(Error::RsaVerify)?;
 
     Ok(public_key)
 }
@@ -1815,7 +2045,22 @@ pub fn decode_public_key(data: &[u8]) -> Result<RsaPublicKey> {
 /// Load the vbmeta header and footer from the specified reader. A footer is
 /// present only if the file is not a vbmeta partition image (ie. the header
 /// follows actual data).
-pub fn load_image(mut reader: impl Read + Seek) -> Result<(Header, Option<Footer>, u64)> {
+pub fn load_image(reader: impl Read + Seek) -> Result<(Header, Option<Footer>, u64)> {
+    load_image_internal(reader, false)
+}
+
+/// Like [`load_image`], but fail if the vbmeta header cannot be written back
+/// out byte-for-byte. This guarantees that an unmodified header will be
+/// identical after patching, which matters for devices with bootloaders that
+/// are picky about the exact layout.
+pub fn load_image_strict(reader: impl Read + Seek) -> Result<(Header, Option<Footer>, u64)> {
+    load_image_internal(reader, true)
+}
+
+fn load_image_internal(
+    mut reader: impl Read + Seek,
+    strict: bool,
+) -> Result<(Header, Option<Footer>, u64)> {
     let image_size = reader.seek(SeekFrom::End(0))?;
 
     reader.seek(SeekFrom::End(-(Footer::SIZE as i64)))?;
@@ -1831,6 +2076,16 @@ pub fn load_image(mut reader: impl Read + Seek) -> Result<(Header, Option<Footer
     reader.seek(SeekFrom::Start(vbmeta_offset))?;
     let header = Header::from_reader(&mut reader)?;
 
+    if strict {
+        let vbmeta_size = reader.stream_position()? - vbmeta_offset;
+
+        let mut raw = vec![0u8; vbmeta_size as usize];
+        reader.seek(SeekFrom::Start(vbmeta_offset))?;
+        reader.read_exact(&mut raw)?;
+
+        header.check_round_trip(&raw)?;
+    }
+
     Ok((header, footer, image_size))
 }
 
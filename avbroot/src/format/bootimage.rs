@@ -11,12 +11,12 @@ use std::{
 
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
 use num_traits::ToPrimitive;
-use ring::digest::Context;
-use rsa::RsaPrivateKey;
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
 use crate::{
+    crypto::RsaSigningKey,
+    digest::Context,
     format::{
         avb::{self, Descriptor, Header},
         padding,
@@ -765,8 +765,8 @@ impl BootImageV3Through4 {
     /// Sign the boot image with a legacy VTS signature. Returns true if the
     /// image was successfully signed. Returns false if there's no vbmeta
     /// structure to sign in [`V4Extra::signature`].
-    pub fn sign(&mut self, key: &RsaPrivateKey) -> Result<bool> {
-        let mut context = Context::new(&ring::digest::SHA256);
+    pub fn sign(&mut self, key: &RsaSigningKey) -> Result<bool> {
+        let mut context = Context::new(&crate::digest::SHA256);
         let image_size;
 
         if let Some(v4) = &self.v4_extra {
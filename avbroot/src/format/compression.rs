@@ -24,6 +24,11 @@ static XZ_MAGIC: &[u8; 6] = b"\xfd\x37\x7a\x58\x5a\x00";
 pub enum Error {
     #[error("Unknown compression format")]
     UnknownFormat,
+    #[error("Compression level {level} is not supported for {format:?}")]
+    UnsupportedLevel {
+        format: CompressedFormat,
+        level: u32,
+    },
     #[error("XZ stream error")]
     XzStream(#[from] liblzma::stream::Error),
     #[error("I/O error")]
@@ -179,15 +184,29 @@ pub enum CompressedWriter<W: Write> {
 
 impl<W: Write> CompressedWriter<W> {
     pub fn new(writer: W, format: CompressedFormat) -> Result<Self> {
+        Self::with_level(writer, format, None)
+    }
+
+    /// Like [`Self::new()`], but with a specific compression level. Gzip and
+    /// XZ support levels 0 through 9. LZ4 legacy does not support levels. If
+    /// `level` is [`None`], the format's default level is used.
+    pub fn with_level(writer: W, format: CompressedFormat, level: Option<u32>) -> Result<Self> {
+        if let Some(level) = level {
+            if !matches!(format, CompressedFormat::Gzip | CompressedFormat::Xz) || level > 9 {
+                return Err(Error::UnsupportedLevel { format, level });
+            }
+        }
+
         match format {
             CompressedFormat::None => Ok(Self::None(writer)),
             CompressedFormat::Gzip => {
-                Ok(Self::Gzip(GzEncoder::new(writer, Compression::default())))
+                let compression = level.map_or_else(Compression::default, Compression::new);
+                Ok(Self::Gzip(GzEncoder::new(writer, compression)))
             }
             CompressedFormat::Lz4Legacy => Ok(Self::Lz4Legacy(Lz4LegacyEncoder::new(writer)?)),
             CompressedFormat::Xz => {
                 // Some kernels are compiled without support for the default CRC64.
-                let stream = Stream::new_easy_encoder(6, Check::Crc32)?;
+                let stream = Stream::new_easy_encoder(level.unwrap_or(6), Check::Crc32)?;
                 Ok(Self::Xz(XzEncoder::new_stream(writer, stream)))
             }
         }
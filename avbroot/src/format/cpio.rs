@@ -4,7 +4,7 @@
  */
 
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     fmt,
     io::{self, Cursor, Read, Write},
     ops::Range,
@@ -50,8 +50,12 @@ const VEC_CAP_THRESHOLD: usize = 16384;
 pub enum Error {
     #[error("Unknown magic: {0:?}")]
     UnknownMagic([u8; 6]),
-    #[error("Hard links are not supported: {:?}", .0.as_bstr())]
-    HardLinksNotSupported(Vec<u8>),
+    #[error("Hard links to the same inode have different data: {:?}", .0.as_bstr())]
+    LinkGroupDataMismatch(Vec<u8>),
+    #[error("Hard link data must be loaded into memory: {:?}", .0.as_bstr())]
+    LinkGroupLazyData(Vec<u8>),
+    #[error("Entry is not a regular file: {:?}", .0.as_bstr())]
+    NotRegularFile(Vec<u8>),
     #[error("Entry of type {0} should not have data: {:?}", .1.as_bstr())]
     EntryHasData(CpioEntryType, Vec<u8>),
     #[error("No inodes available for device {0:x},{1:x}")]
@@ -227,6 +231,14 @@ impl CpioEntryData {
     }
 }
 
+/// Identifies the inode shared by a group of hard links.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct LinkGroupId {
+    pub dev_maj: u32,
+    pub dev_min: u32,
+    pub inode: u32,
+}
+
 #[derive(Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct CpioEntry {
     /// File path.
@@ -380,6 +392,22 @@ impl CpioEntry {
         }
     }
 
+    /// Whether this entry is one of several paths referencing the same inode.
+    /// Directories are never considered to be hard links because their link
+    /// count includes their subdirectories.
+    pub fn is_hard_link(&self) -> bool {
+        self.file_type != CpioEntryType::Directory && self.nlink > 1
+    }
+
+    /// Get the identifier of the inode that this entry refers to.
+    pub fn link_group_id(&self) -> LinkGroupId {
+        LinkGroupId {
+            dev_maj: self.dev_maj,
+            dev_min: self.dev_min,
+            inode: self.inode,
+        }
+    }
+
     pub fn is_trailer(&self) -> bool {
         self.path == CPIO_TRAILER
     }
@@ -684,10 +712,6 @@ pub fn load(
     while let Some(mut entry) = cpio_reader.next_entry()? {
         stream::check_cancel(cancel_signal)?;
 
-        if entry.file_type != CpioEntryType::Directory && entry.nlink > 1 {
-            return Err(Error::HardLinksNotSupported(entry.path.clone()));
-        }
-
         if let CpioEntryData::Size(s) = entry.data {
             let data = read_data(&mut cpio_reader, s.to_usize().unwrap(), cancel_signal)?;
             entry.data = CpioEntryData::Data(data);
@@ -699,6 +723,52 @@ pub fn load(
     Ok(entries)
 }
 
+/// Allocator for unused inode numbers on a per-device basis.
+#[derive(Default)]
+struct InodeAllocator {
+    /// (dev maj, dev min) -> (inode set, last assigned inode)
+    inodes: HashMap<(u32, u32), (HashSet<u32>, u32)>,
+}
+
+impl InodeAllocator {
+    fn next_non_zero(i: u32) -> u32 {
+        if i == u32::MAX {
+            1
+        } else {
+            i.wrapping_add(1)
+        }
+    }
+
+    fn reserve(&mut self, dev_maj: u32, dev_min: u32, inode: u32) {
+        let (set, last) = self.inodes.entry((dev_maj, dev_min)).or_default();
+
+        set.insert(inode);
+        *last = (*last).max(inode);
+    }
+
+    fn allocate(&mut self, dev_maj: u32, dev_min: u32) -> Result<u32> {
+        let (set, last) = self
+            .inodes
+            .entry((dev_maj, dev_min))
+            .or_insert_with(|| (HashSet::new(), 299999));
+
+        let mut unused = Self::next_non_zero(*last);
+
+        while set.contains(&unused) {
+            if unused == *last {
+                return Err(Error::DeviceFull(dev_maj, dev_min));
+            }
+
+            unused = Self::next_non_zero(unused);
+        }
+
+        set.insert(unused);
+        *last = unused;
+
+        Ok(unused)
+    }
+}
+
 pub fn sort(entries: &mut [CpioEntry]) {
     entries.sort_by(|a, b| a.path.cmp(&b.path));
 }
@@ -711,55 +781,194 @@ pub fn sort(entries: &mut [CpioEntry]) {
 /// are no existing inodes assigned for a device, then the numbers begin at
 /// 300000.
 pub fn assign_inodes(entries: &mut [CpioEntry], missing_only: bool) -> Result<()> {
-    fn next_non_zero(i: u32) -> u32 {
-        if i == u32::MAX {
-            1
-        } else {
-            i.wrapping_add(1)
-        }
-    }
-
-    // (dev maj, dev min) -> (inode set, last assigned inode)
-    let mut inodes: HashMap<(u32, u32), (HashSet<u32>, u32)> = HashMap::new();
+    let mut allocator = InodeAllocator::default();
 
     if missing_only {
-        for entry in &mut *entries {
+        for entry in &*entries {
             if entry.inode != 0 {
-                let key = (entry.dev_maj, entry.dev_min);
-                let (set, last) = inodes.entry(key).or_default();
-
-                set.insert(entry.inode);
-                *last = (*last).max(entry.inode);
+                allocator.reserve(entry.dev_maj, entry.dev_min, entry.inode);
             }
         }
     }
 
     for entry in entries {
         if entry.inode == 0 {
-            let key = (entry.dev_maj, entry.dev_min);
-            let (set, last) = inodes
-                .entry(key)
-                .or_insert_with(|| (HashSet::new(), 299999));
+            entry.inode = allocator.allocate(entry.dev_maj, entry.dev_min)?;
+        }
+    }
 
-            let mut unused = next_non_zero(*last);
+    Ok(())
+}
+
+/// Reassign every inode, ignoring the existing inode numbers. The new inodes are
+/// assigned in the order that the entries are listed, so the result only
+/// depends on the entries' order and devices. Hard links to the same inode are
+/// assigned the same new inode.
+pub fn renumber_inodes(entries: &mut [CpioEntry]) -> Result<()> {
+    let mut allocator = InodeAllocator::default();
+    let mut groups = HashMap::<LinkGroupId, u32>::new();
 
-            while set.contains(&unused) {
-                if unused == *last {
-                    return Err(Error::DeviceFull(entry.dev_maj, entry.dev_min));
+    for entry in entries {
+        let inode = if entry.is_hard_link() {
+            match groups.get(&entry.link_group_id()) {
+                Some(inode) => *inode,
+                None => {
+                    let inode = allocator.allocate(entry.dev_maj, entry.dev_min)?;
+                    groups.insert(entry.link_group_id(), inode);
+                    inode
                 }
+            }
+        } else {
+            allocator.allocate(entry.dev_maj, entry.dev_min)?
+        };
+
+        entry.inode = inode;
+    }
+
+    Ok(())
+}
+
+/// Find all groups of hard links. The keys are the shared inodes and the values
+/// are the indices of the entries referencing them, in the order that they are
+/// listed. Only entries where [`CpioEntry::is_hard_link`] is true are included.
+pub fn link_groups(entries: &[CpioEntry]) -> BTreeMap<LinkGroupId, Vec<usize>> {
+    let mut groups = BTreeMap::<LinkGroupId, Vec<usize>>::new();
 
-                unused = next_non_zero(unused);
+    for (i, entry) in entries.iter().enumerate() {
+        if entry.is_hard_link() {
+            groups.entry(entry.link_group_id()).or_default().push(i);
+        }
+    }
+
+    groups
+}
+
+/// Make every group of hard links consistent with how the newc format stores
+/// them. The link count of each entry is set to the number of entries in the
+/// group and the file data is moved to the last entry in the group. All other
+/// entries in the group are left empty.
+///
+/// The data must already be loaded into memory (ie. [`CpioEntryData::Data`]).
+/// If more than one entry in a group has data, then the data must be identical.
+pub fn normalize_link_groups(entries: &mut [CpioEntry]) -> Result<()> {
+    for indices in link_groups(entries).into_values() {
+        let mut data: Option<Vec<u8>> = None;
+
+        for &i in &indices {
+            let entry = &mut entries[i];
+
+            match &mut entry.data {
+                CpioEntryData::Size(0) => {}
+                CpioEntryData::Size(_) => {
+                    return Err(Error::LinkGroupLazyData(entry.path.clone()));
+                }
+                CpioEntryData::Data(d) if d.is_empty() => {}
+                CpioEntryData::Data(d) => match &data {
+                    Some(existing) if existing != d => {
+                        return Err(Error::LinkGroupDataMismatch(entry.path.clone()));
+                    }
+                    Some(_) => {}
+                    None => data = Some(std::mem::take(d)),
+                },
             }
+        }
 
-            entry.inode = unused;
-            set.insert(unused);
-            *last = unused;
+        let nlink = indices
+            .len()
+            .to_u32()
+            .ok_or(Error::IntegerTooLarge("nlink"))?;
+        let last = *indices.last().unwrap();
+
+        for &i in &indices {
+            let entry = &mut entries[i];
+
+            entry.nlink = nlink;
+            entry.data = if i == last {
+                CpioEntryData::Data(data.take().unwrap_or_default())
+            } else {
+                CpioEntryData::Data(vec![])
+            };
         }
     }
 
     Ok(())
 }
 
+/// Add a new hard link at `path` that refers to the same inode as the regular
+/// file at `index`. The new entry is appended to the end of the list and the
+/// link group is normalized with [`normalize_link_groups`].
+pub fn add_hard_link(entries: &mut Vec<CpioEntry>, index: usize, path: &[u8]) -> Result<()> {
+    let target = &mut entries[index];
+    if target.file_type != CpioEntryType::Regular {
+        return Err(Error::NotRegularFile(target.path.clone()));
+    }
+
+    // Make sure the target is counted as a hard link even if it was not one.
+    target.nlink = target.nlink.max(2);
+
+    let link = CpioEntry {
+        path: path.to_vec(),
+        data: CpioEntryData::Data(vec![]),
+        ..target.clone()
+    };
+    entries.push(link);
+
+    normalize_link_groups(entries)
+}
+
+/// Detach the entry at `index` from its link group. The entry gets its own copy
+/// of the group's data and a newly assigned inode. The remaining entries in the
+/// group are normalized with [`normalize_link_groups`].
+pub fn break_hard_link(entries: &mut [CpioEntry], index: usize) -> Result<()> {
+    if !entries[index].is_hard_link() {
+        return Ok(());
+    }
+
+    let id = entries[index].link_group_id();
+    let mut data = vec![];
+
+    for entry in entries.iter().filter(|e| e.is_hard_link()) {
+        if entry.link_group_id() == id {
+            match &entry.data {
+                CpioEntryData::Size(0) => {}
+                CpioEntryData::Size(_) => {
+                    return Err(Error::LinkGroupLazyData(entry.path.clone()));
+                }
+                CpioEntryData::Data(d) if !d.is_empty() => data.clone_from(d),
+                CpioEntryData::Data(_) => {}
+            }
+        }
+    }
+
+    let mut allocator = InodeAllocator::default();
+    for entry in &*entries {
+        allocator.reserve(entry.dev_maj, entry.dev_min, entry.inode);
+    }
+
+    let entry = &mut entries[index];
+    entry.inode = allocator.allocate(entry.dev_maj, entry.dev_min)?;
+    entry.nlink = 1;
+    entry.data = CpioEntryData::Data(data.clone());
+
+    // The detached entry may have been the one holding the group's data.
+    if let Some(last) = entries
+        .iter_mut()
+        .rev()
+        .find(|e| e.is_hard_link() && e.link_group_id() == id)
+    {
+        last.data = CpioEntryData::Data(data);
+    }
+
+    normalize_link_groups(entries)
+}
+
+/// Set the modification time of every entry.
+pub fn set_mtimes(entries: &mut [CpioEntry], mtime: u32) {
+    for entry in entries {
+        entry.mtime = mtime;
+    }
+}
+
 pub fn save(
     writer: impl Write,
     entries: &[CpioEntry],
@@ -0,0 +1,1120 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Minimal read-only EROFS reader. Only what is needed for pulling individual
+//! files out of Android partition images is implemented: uncompressed, inline,
+//! and chunk-based files, and LZ4 or DEFLATE compressed files with either index
+//! format. Tail packing, fragments, deduplication, interlaced pclusters, and
+//! multiple devices are not supported.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::atomic::AtomicBool,
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use flate2::read::DeflateDecoder;
+use thiserror::Error;
+
+use crate::{
+    format::filesystem::{self, DirEntry, FileSystemReader, FileType},
+    stream::{self, WriteZerosExt},
+};
+
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 128;
+pub const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+
+const MIN_BLOCK_BITS: u8 = 9;
+const MAX_BLOCK_BITS: u8 = 16;
+
+const FEATURE_INCOMPAT_ZERO_PADDING: u32 = 0x1;
+
+const INODE_SLOT_SIZE: u64 = 32;
+const INODE_COMPACT_SIZE: u64 = 32;
+const INODE_EXTENDED_SIZE: u64 = 64;
+const XATTR_IBODY_HEADER_SIZE: u64 = 12;
+const XATTR_ENTRY_SIZE: u64 = 4;
+
+const LAYOUT_FLAT_PLAIN: u8 = 0;
+const LAYOUT_COMPRESSED_FULL: u8 = 1;
+const LAYOUT_FLAT_INLINE: u8 = 2;
+const LAYOUT_COMPRESSED_COMPACT: u8 = 3;
+const LAYOUT_CHUNK_BASED: u8 = 4;
+
+const NULL_ADDR: u32 = u32::MAX;
+
+const CHUNK_FORMAT_BLKBITS_MASK: u16 = 0x1f;
+const CHUNK_FORMAT_INDEXES: u16 = 0x20;
+const CHUNK_INDEX_SIZE: u64 = 8;
+const BLOCK_MAP_ENTRY_SIZE: u64 = 4;
+
+const DIRENT_SIZE: usize = 12;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xa000;
+
+const MAP_HEADER_SIZE: u64 = 8;
+const FULL_INDEX_SIZE: u64 = 8;
+
+const ADVISE_COMPACTED_2B: u16 = 0x1;
+const ADVISE_BIG_PCLUSTER_1: u16 = 0x2;
+const ADVISE_BIG_PCLUSTER_2: u16 = 0x4;
+const ADVISE_INLINE_PCLUSTER: u16 = 0x8;
+const ADVISE_INTERLACED_PCLUSTER: u16 = 0x10;
+const ADVISE_FRAGMENT_PCLUSTER: u16 = 0x20;
+const ADVISE_SUPPORTED: u16 = ADVISE_COMPACTED_2B | ADVISE_BIG_PCLUSTER_1 | ADVISE_BIG_PCLUSTER_2;
+
+const CLUSTERBITS_FRAGMENT_INODE: u8 = 0x80;
+
+const LCLUSTER_TYPE_PLAIN: u8 = 0;
+const LCLUSTER_TYPE_HEAD1: u8 = 1;
+const LCLUSTER_TYPE_NONHEAD: u8 = 2;
+const LCLUSTER_TYPE_HEAD2: u8 = 3;
+
+const LI_PARTIAL_REF: u16 = 1 << 15;
+const LI_D0_CBLKCNT: u32 = 1 << 11;
+
+/// Maximum size of a physical cluster. This matches the kernel's
+/// `Z_EROFS_PCLUSTER_MAX_SIZE`.
+const MAX_PCLUSTER_SIZE: u64 = 1024 * 1024;
+/// Maximum decompressed size of a single extent. Extents produced by
+/// mkfs.erofs are far smaller than this. The limit only exists so that
+/// malformed indexes can't trigger huge allocations.
+const MAX_EXTENT_SIZE: u64 = 64 * 1024 * 1024;
+
+const COMPRESSION_LZ4: u8 = 0;
+const COMPRESSION_DEFLATE: u8 = 2;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid EROFS superblock magic: {0:#010x}")]
+    InvalidMagic(u32),
+    #[error("Invalid EROFS superblock field: {0}")]
+    InvalidSuperblock(&'static str),
+    #[error("Inode {0} has invalid data layout: {1}")]
+    InvalidLayout(u64, u8),
+    #[error("Inode {0} uses unsupported feature: {1}")]
+    UnsupportedInode(u64, &'static str),
+    #[error("Inode {0} uses unsupported compression algorithm: {1}")]
+    UnsupportedCompression(u64, u8),
+    #[error("Inode {0} has invalid compression indexes")]
+    InvalidIndexes(u64),
+    #[error("Inode {0} has an extent at offset {1} that is too large")]
+    ExtentTooLarge(u64, u64),
+    #[error("Inode {0} has invalid compressed data at block {1}")]
+    InvalidCompressedData(u64, u64),
+    #[error("Invalid directory entry in inode {0} at offset {1}")]
+    InvalidDirEntry(u64, u64),
+    #[error("Inode {0} is too large to read into memory: {1} bytes")]
+    TooLarge(u64, u64),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn align(value: u64, alignment: u64) -> u64 {
+    value.div_ceil(alignment) * alignment
+}
+
+#[derive(Clone, Debug)]
+struct Superblock {
+    block_bits: u8,
+    root_nid: u64,
+    meta_blkaddr: u32,
+    feature_incompat: u32,
+}
+
+impl Superblock {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let magic = LittleEndian::read_u32(&data[0..]);
+        if magic != EROFS_MAGIC {
+            return Err(Error::InvalidMagic(magic));
+        }
+
+        let block_bits = data[12];
+        if !(MIN_BLOCK_BITS..=MAX_BLOCK_BITS).contains(&block_bits) {
+            return Err(Error::InvalidSuperblock("blkszbits"));
+        }
+
+        // Same restriction as the kernel.
+        if data[90] != 0 {
+            return Err(Error::InvalidSuperblock("dirblkbits"));
+        }
+
+        Ok(Self {
+            block_bits,
+            root_nid: LittleEndian::read_u16(&data[14..]).into(),
+            meta_blkaddr: LittleEndian::read_u32(&data[40..]),
+            feature_incompat: LittleEndian::read_u32(&data[80..]),
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Inode {
+    nid: u64,
+    /// Offset of the inode on disk.
+    location: u64,
+    layout: u8,
+    /// Size of the on-disk inode plus its inline xattrs.
+    header_size: u64,
+    mode: u16,
+    size: u64,
+    /// Interpretation depends on the data layout.
+    raw_u: u32,
+}
+
+impl Inode {
+    fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::Regular,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+
+    /// Offset of the data that immediately follows the inode and its xattrs.
+    fn trailer_offset(&self) -> u64 {
+        self.location + self.header_size
+    }
+}
+
+/// Compression info from the `z_erofs_map_header`.
+#[derive(Clone, Debug)]
+struct MapHeader {
+    advise: u16,
+    algorithm_types: u8,
+    lcluster_bits: u8,
+    /// Offset where the lcluster indexes begin.
+    index_offset: u64,
+    lcluster_count: u64,
+}
+
+/// A decoded logical cluster index.
+#[derive(Clone, Copy, Debug, Default)]
+struct Lcluster {
+    cluster_type: u8,
+    cluster_offset: u32,
+    delta0: u32,
+    pblk: u64,
+    compressed_blocks: u32,
+    partial_ref: bool,
+}
+
+/// The start of a physical cluster.
+#[derive(Clone, Copy, Debug)]
+struct Head {
+    lcn: u64,
+    /// Logical offset of the decompressed data.
+    offset: u64,
+    cluster_type: u8,
+    pblk: u64,
+    compressed_blocks: u32,
+}
+
+pub struct ErofsReader<R: Read + Seek> {
+    reader: R,
+    sb: Superblock,
+}
+
+impl<R: Read + Seek> ErofsReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut data = [0u8; SUPERBLOCK_SIZE];
+        reader.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        reader.read_exact(&mut data)?;
+
+        let sb = Superblock::parse(&data)?;
+
+        Ok(Self { reader, sb })
+    }
+
+    fn block_size(&self) -> u64 {
+        1 << self.sb.block_bits
+    }
+
+    fn block_offset(&self, inode: &Inode, block: u64) -> Result<u64> {
+        block
+            .checked_mul(self.block_size())
+            .ok_or(Error::InvalidLayout(inode.nid, inode.layout))
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn read_inode(&mut self, nid: u64) -> Result<Inode> {
+        let location = nid
+            .checked_mul(INODE_SLOT_SIZE)
+            .and_then(|o| o.checked_add(u64::from(self.sb.meta_blkaddr) << self.sb.block_bits))
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+
+        let mut data = [0u8; INODE_EXTENDED_SIZE as usize];
+        self.read_at(location, &mut data[..INODE_COMPACT_SIZE as usize])?;
+
+        let format = LittleEndian::read_u16(&data[0..]);
+        let extended = format & 1 != 0;
+        let layout = ((format >> 1) & 7) as u8;
+        let xattr_icount = u64::from(LittleEndian::read_u16(&data[2..]));
+
+        let (inode_size, size) = if extended {
+            self.read_at(
+                location + INODE_COMPACT_SIZE,
+                &mut data[INODE_COMPACT_SIZE as usize..],
+            )?;
+            (INODE_EXTENDED_SIZE, LittleEndian::read_u64(&data[8..]))
+        } else {
+            (
+                INODE_COMPACT_SIZE,
+                LittleEndian::read_u32(&data[8..]).into(),
+            )
+        };
+
+        let xattr_size = if xattr_icount == 0 {
+            0
+        } else {
+            XATTR_IBODY_HEADER_SIZE + (xattr_icount - 1) * XATTR_ENTRY_SIZE
+        };
+
+        if layout > LAYOUT_CHUNK_BASED {
+            return Err(Error::InvalidLayout(nid, layout));
+        }
+
+        Ok(Inode {
+            nid,
+            location,
+            layout,
+            header_size: inode_size + xattr_size,
+            mode: LittleEndian::read_u16(&data[4..]),
+            size,
+            raw_u: LittleEndian::read_u32(&data[16..]),
+        })
+    }
+
+    /// Copy the contents of an inode to `writer`.
+    fn copy_data(
+        &mut self,
+        inode: &Inode,
+        writer: &mut dyn Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        match inode.layout {
+            LAYOUT_FLAT_PLAIN | LAYOUT_FLAT_INLINE => self.copy_flat(inode, writer, cancel_signal),
+            LAYOUT_CHUNK_BASED => self.copy_chunked(inode, writer, cancel_signal),
+            LAYOUT_COMPRESSED_FULL | LAYOUT_COMPRESSED_COMPACT => {
+                self.copy_compressed(inode, writer, cancel_signal)
+            }
+            l => Err(Error::InvalidLayout(inode.nid, l)),
+        }
+    }
+
+    /// Read the contents of an inode into memory. The inode is rejected if it
+    /// is larger than `limit`.
+    fn read_data(&mut self, inode: &Inode, limit: u64) -> Result<Vec<u8>> {
+        if inode.size > limit {
+            return Err(Error::TooLarge(inode.nid, inode.size));
+        }
+
+        let mut data = vec![];
+        self.copy_data(inode, &mut data, &AtomicBool::new(false))?;
+        Ok(data)
+    }
+
+    /// Copy an uncompressed file. With the inline layout, the last block is
+    /// stored immediately after the inode instead of at the end of the
+    /// contiguous data.
+    fn copy_flat(
+        &mut self,
+        inode: &Inode,
+        writer: &mut dyn Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        let block_count = inode.size.div_ceil(self.block_size());
+        let tail_packing = inode.layout == LAYOUT_FLAT_INLINE;
+        let contiguous_blocks = block_count.saturating_sub(tail_packing.into());
+        let contiguous_size = (contiguous_blocks * self.block_size()).min(inode.size);
+
+        if contiguous_size > 0 {
+            let offset = self.block_offset(inode, inode.raw_u.into())?;
+            self.reader.seek(SeekFrom::Start(offset))?;
+            stream::copy_n(
+                &mut self.reader,
+                &mut *writer,
+                contiguous_size,
+                cancel_signal,
+            )?;
+        }
+
+        let tail_size = inode.size - contiguous_size;
+        if tail_size > 0 {
+            self.reader.seek(SeekFrom::Start(inode.trailer_offset()))?;
+            stream::copy_n(&mut self.reader, writer, tail_size, cancel_signal)?;
+        }
+
+        Ok(())
+    }
+
+    fn copy_chunked(
+        &mut self,
+        inode: &Inode,
+        mut writer: &mut dyn Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        let chunk_format = inode.raw_u as u16;
+        let chunk_bits =
+            u32::from(self.sb.block_bits) + u32::from(chunk_format & CHUNK_FORMAT_BLKBITS_MASK);
+        if chunk_bits >= 48 {
+            return Err(Error::InvalidLayout(inode.nid, inode.layout));
+        }
+
+        let chunk_size = 1u64 << chunk_bits;
+        let chunk_count = inode.size.div_ceil(chunk_size);
+        let entry_size = if chunk_format & CHUNK_FORMAT_INDEXES != 0 {
+            CHUNK_INDEX_SIZE
+        } else {
+            BLOCK_MAP_ENTRY_SIZE
+        };
+        let index_offset = align(inode.trailer_offset(), entry_size);
+
+        for chunk in 0..chunk_count {
+            let mut entry = [0u8; CHUNK_INDEX_SIZE as usize];
+            self.read_at(
+                index_offset + chunk * entry_size,
+                &mut entry[..entry_size as usize],
+            )?;
+
+            let blkaddr = if entry_size == CHUNK_INDEX_SIZE {
+                if LittleEndian::read_u16(&entry[2..]) != 0 {
+                    return Err(Error::UnsupportedInode(inode.nid, "multiple devices"));
+                }
+                LittleEndian::read_u32(&entry[4..])
+            } else {
+                LittleEndian::read_u32(&entry[0..])
+            };
+
+            let size = chunk_size.min(inode.size - chunk * chunk_size);
+
+            if blkaddr == NULL_ADDR {
+                writer.write_zeros_exact(size)?;
+            } else {
+                let offset = self.block_offset(inode, blkaddr.into())?;
+                self.reader.seek(SeekFrom::Start(offset))?;
+                stream::copy_n(&mut self.reader, &mut *writer, size, cancel_signal)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn read_map_header(&mut self, inode: &Inode) -> Result<MapHeader> {
+        let offset = align(inode.trailer_offset(), 8);
+        let mut data = [0u8; MAP_HEADER_SIZE as usize];
+        self.read_at(offset, &mut data)?;
+
+        let advise = LittleEndian::read_u16(&data[4..]);
+        let algorithm_types = data[6];
+        let cluster_bits = data[7];
+
+        if cluster_bits & CLUSTERBITS_FRAGMENT_INODE != 0 || advise & ADVISE_FRAGMENT_PCLUSTER != 0
+        {
+            return Err(Error::UnsupportedInode(inode.nid, "fragments"));
+        } else if advise & ADVISE_INLINE_PCLUSTER != 0 {
+            return Err(Error::UnsupportedInode(inode.nid, "tail packing"));
+        } else if advise & ADVISE_INTERLACED_PCLUSTER != 0 {
+            return Err(Error::UnsupportedInode(inode.nid, "interlaced pclusters"));
+        } else if advise & !ADVISE_SUPPORTED != 0 {
+            return Err(Error::UnsupportedInode(
+                inode.nid,
+                "unknown compression advise",
+            ));
+        }
+
+        let lcluster_bits = self.sb.block_bits + (cluster_bits & 7);
+        let index_offset = if inode.layout == LAYOUT_COMPRESSED_FULL {
+            // There is an 8-byte reserved gap after the map header.
+            offset + MAP_HEADER_SIZE + 8
+        } else {
+            if lcluster_bits != self.sb.block_bits {
+                return Err(Error::UnsupportedInode(
+                    inode.nid,
+                    "compact indexes with lclusters larger than a block",
+                ));
+            }
+
+            offset + MAP_HEADER_SIZE
+        };
+
+        Ok(MapHeader {
+            advise,
+            algorithm_types,
+            lcluster_bits,
+            index_offset,
+            lcluster_count: inode.size.div_ceil(1 << lcluster_bits),
+        })
+    }
+
+    fn load_full_lcluster(&mut self, inode: &Inode, map: &MapHeader, lcn: u64) -> Result<Lcluster> {
+        let mut data = [0u8; FULL_INDEX_SIZE as usize];
+        self.read_at(map.index_offset + lcn * FULL_INDEX_SIZE, &mut data)?;
+
+        let advise = LittleEndian::read_u16(&data[0..]);
+        let mut lcluster = Lcluster {
+            cluster_type: (advise & 3) as u8,
+            ..Default::default()
+        };
+
+        if lcluster.cluster_type == LCLUSTER_TYPE_NONHEAD {
+            lcluster.delta0 = LittleEndian::read_u16(&data[4..]).into();
+
+            if lcluster.delta0 & LI_D0_CBLKCNT != 0 {
+                if map.advise & (ADVISE_BIG_PCLUSTER_1 | ADVISE_BIG_PCLUSTER_2) == 0 {
+                    return Err(Error::InvalidIndexes(inode.nid));
+                }
+
+                lcluster.compressed_blocks = lcluster.delta0 & !LI_D0_CBLKCNT;
+                lcluster.delta0 = 1;
+            }
+        } else {
+            lcluster.partial_ref = advise & LI_PARTIAL_REF != 0;
+            lcluster.cluster_offset = LittleEndian::read_u16(&data[2..]).into();
+            lcluster.pblk = LittleEndian::read_u32(&data[4..]).into();
+
+            if lcluster.cluster_offset >= 1 << map.lcluster_bits {
+                return Err(Error::InvalidIndexes(inode.nid));
+            }
+        }
+
+        Ok(lcluster)
+    }
+
+    /// Load an lcluster from the compact index format. This is a port of the
+    /// kernel's `z_erofs_load_compact_lcluster()` and
+    /// `unpack_compacted_index()`.
+    fn load_compact_lcluster(
+        &mut self,
+        inode: &Inode,
+        map: &MapHeader,
+        mut lcn: u64,
+    ) -> Result<Lcluster> {
+        let invalid = || Error::InvalidIndexes(inode.nid);
+
+        let total = map.lcluster_count;
+        let compacted_4b_initial = ((32 - map.index_offset % 32) / 4) & 7;
+        let compacted_2b = if map.advise & ADVISE_COMPACTED_2B != 0 && compacted_4b_initial < total
+        {
+            (total - compacted_4b_initial) / 16 * 16
+        } else {
+            0
+        };
+
+        let mut offset = map.index_offset;
+        let mut amortized_shift = 2;
+        if lcn >= compacted_4b_initial {
+            offset += compacted_4b_initial * 4;
+            lcn -= compacted_4b_initial;
+
+            if lcn < compacted_2b {
+                amortized_shift = 1;
+            } else {
+                offset += compacted_2b * 2;
+                lcn -= compacted_2b;
+            }
+        }
+        offset += lcn << amortized_shift;
+
+        let lcluster_bits = u32::from(map.lcluster_bits);
+        let count: i64 = match amortized_shift {
+            2 if lcluster_bits <= 14 => 2,
+            1 if lcluster_bits <= 12 => 16,
+            _ => return Err(invalid()),
+        };
+        let pack_size = (count as u64) << amortized_shift;
+        let pack_offset = offset / pack_size * pack_size;
+
+        let mut pack = [0u8; 32];
+        let pack = &mut pack[..pack_size as usize];
+        self.read_at(pack_offset, pack)?;
+
+        let lo_bits = lcluster_bits.max(LI_D0_CBLKCNT.ilog2() + 1);
+        let encode_bits = ((pack_size - 4) * 8 / count as u64) as u32;
+        let decode = |i: i64| {
+            let bit = encode_bits * i as u32;
+            let v = LittleEndian::read_u32(&pack[(bit / 8) as usize..]) >> (bit & 7);
+            (v & ((1 << lo_bits) - 1), ((v >> lo_bits) & 3) as u8)
+        };
+
+        let mut i = ((offset - pack_offset) >> amortized_shift) as i64;
+        let (lo, cluster_type) = decode(i);
+        let mut lcluster = Lcluster {
+            cluster_type,
+            ..Default::default()
+        };
+
+        if cluster_type == LCLUSTER_TYPE_NONHEAD {
+            if lo & LI_D0_CBLKCNT != 0 {
+                if map.advise & ADVISE_BIG_PCLUSTER_1 == 0 {
+                    return Err(invalid());
+                }
+
+                lcluster.compressed_blocks = lo & !LI_D0_CBLKCNT;
+                lcluster.delta0 = 1;
+            } else if i + 1 != count {
+                lcluster.delta0 = lo;
+            } else {
+                // The last lcluster in a pack stores delta[1] instead of
+                // delta[0], so it has to be computed from the previous one.
+                let (prev_lo, prev_type) = decode(i - 1);
+                let prev_lo = if prev_type != LCLUSTER_TYPE_NONHEAD {
+                    0
+                } else if prev_lo & LI_D0_CBLKCNT != 0 {
+                    1
+                } else {
+                    prev_lo
+                };
+
+                lcluster.delta0 = prev_lo + 1;
+            }
+
+            return Ok(lcluster);
+        }
+
+        lcluster.cluster_offset = lo;
+
+        // The pack only stores the block address of its first pcluster, so
+        // count the blocks of the pclusters before this one.
+        let mut blocks = 0u64;
+        if map.advise & ADVISE_BIG_PCLUSTER_1 == 0 {
+            blocks = 1;
+
+            while i > 0 {
+                i -= 1;
+                let (lo, cluster_type) = decode(i);
+                if cluster_type == LCLUSTER_TYPE_NONHEAD {
+                    i -= i64::from(lo);
+                }
+                if i >= 0 {
+                    blocks += 1;
+                }
+            }
+        } else {
+            while i > 0 {
+                i -= 1;
+                let (lo, cluster_type) = decode(i);
+                if cluster_type == LCLUSTER_TYPE_NONHEAD {
+                    if lo & LI_D0_CBLKCNT != 0 {
+                        i -= 1;
+                        blocks += u64::from(lo & !LI_D0_CBLKCNT);
+                        continue;
+                    } else if lo <= 1 {
+                        return Err(invalid());
+                    }
+
+                    i -= i64::from(lo) - 2;
+                    continue;
+                }
+                blocks += 1;
+            }
+        }
+
+        let base = LittleEndian::read_u32(&pack[pack.len() - 4..]);
+        lcluster.pblk = u64::from(base) + blocks;
+
+        Ok(lcluster)
+    }
+
+    /// Find the start of every pcluster in a compressed file.
+    fn compressed_heads(
+        &mut self,
+        inode: &Inode,
+        map: &MapHeader,
+        cancel_signal: &AtomicBool,
+    ) -> Result<Vec<Head>> {
+        let mut heads: Vec<Head> = vec![];
+
+        for lcn in 0..map.lcluster_count {
+            stream::check_cancel(cancel_signal)?;
+
+            let lcluster = if inode.layout == LAYOUT_COMPRESSED_FULL {
+                self.load_full_lcluster(inode, map, lcn)?
+            } else {
+                self.load_compact_lcluster(inode, map, lcn)?
+            };
+
+            if lcluster.cluster_type == LCLUSTER_TYPE_NONHEAD {
+                // The first non-head lcluster of a big pcluster stores the
+                // number of compressed blocks.
+                if lcluster.compressed_blocks != 0 {
+                    match heads.last_mut() {
+                        Some(h) if h.lcn + 1 == lcn => {
+                            h.compressed_blocks = lcluster.compressed_blocks;
+                        }
+                        _ => return Err(Error::InvalidIndexes(inode.nid)),
+                    }
+                }
+            } else {
+                if lcluster.partial_ref {
+                    return Err(Error::UnsupportedInode(inode.nid, "deduplication"));
+                }
+
+                heads.push(Head {
+                    lcn,
+                    offset: (lcn << map.lcluster_bits) + u64::from(lcluster.cluster_offset),
+                    cluster_type: lcluster.cluster_type,
+                    pblk: lcluster.pblk,
+                    compressed_blocks: 1,
+                });
+            }
+        }
+
+        if heads.first().map(|h| h.offset) != Some(0) && inode.size != 0 {
+            return Err(Error::InvalidIndexes(inode.nid));
+        }
+
+        Ok(heads)
+    }
+
+    fn copy_compressed(
+        &mut self,
+        inode: &Inode,
+        writer: &mut dyn Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        let map = self.read_map_header(inode)?;
+        let heads = self.compressed_heads(inode, &map, cancel_signal)?;
+        let mut written = 0;
+
+        for (i, head) in heads.iter().enumerate() {
+            stream::check_cancel(cancel_signal)?;
+
+            let end = heads
+                .get(i + 1)
+                .map_or(inode.size, |h| h.offset)
+                .min(inode.size);
+            if head.offset >= end {
+                if i + 1 == heads.len() {
+                    break;
+                }
+                return Err(Error::InvalidIndexes(inode.nid));
+            }
+
+            let decompressed_size = end - head.offset;
+            let compressed_size = u64::from(head.compressed_blocks) * self.block_size();
+
+            if compressed_size > MAX_PCLUSTER_SIZE || decompressed_size > MAX_EXTENT_SIZE {
+                return Err(Error::ExtentTooLarge(inode.nid, head.offset));
+            }
+
+            let mut compressed = vec![0u8; compressed_size as usize];
+            let offset = self.block_offset(inode, head.pblk)?;
+            self.read_at(offset, &mut compressed)?;
+
+            let invalid = || Error::InvalidCompressedData(inode.nid, head.pblk);
+
+            if head.cluster_type == LCLUSTER_TYPE_PLAIN {
+                if decompressed_size > compressed_size {
+                    return Err(invalid());
+                }
+
+                writer.write_all(&compressed[..decompressed_size as usize])?;
+            } else {
+                let algorithm = if head.cluster_type == LCLUSTER_TYPE_HEAD1 {
+                    map.algorithm_types & 0xf
+                } else {
+                    debug_assert_eq!(head.cluster_type, LCLUSTER_TYPE_HEAD2);
+                    map.algorithm_types >> 4
+                };
+
+                // The compressed data is padded with leading zeros so that it
+                // ends at the end of the pcluster.
+                let start = compressed
+                    .iter()
+                    .position(|b| *b != 0)
+                    .unwrap_or(compressed.len());
+                let input = &compressed[start..];
+                let mut output = vec![0u8; decompressed_size as usize];
+
+                match algorithm {
+                    COMPRESSION_LZ4 => {
+                        if self.sb.feature_incompat & FEATURE_INCOMPAT_ZERO_PADDING == 0 {
+                            return Err(Error::UnsupportedInode(
+                                inode.nid,
+                                "LZ4 without zero padding",
+                            ));
+                        }
+
+                        let n = lz4_flex::block::decompress_into(input, &mut output)
+                            .map_err(|_| invalid())?;
+                        if n != output.len() {
+                            return Err(invalid());
+                        }
+                    }
+                    COMPRESSION_DEFLATE => {
+                        DeflateDecoder::new(input)
+                            .read_exact(&mut output)
+                            .map_err(|_| invalid())?;
+                    }
+                    a => return Err(Error::UnsupportedCompression(inode.nid, a)),
+                }
+
+                writer.write_all(&output)?;
+            }
+
+            written = end;
+        }
+
+        if written != inode.size {
+            return Err(Error::InvalidIndexes(inode.nid));
+        }
+
+        Ok(())
+    }
+
+    fn parse_dir_entries(&self, inode: &Inode, data: &[u8]) -> Result<Vec<DirEntry>> {
+        let mut entries = vec![];
+
+        for (i, block) in data.chunks(self.block_size() as usize).enumerate() {
+            let block_offset = i as u64 * self.block_size();
+            let invalid =
+                |offset: usize| Error::InvalidDirEntry(inode.nid, block_offset + offset as u64);
+
+            if block.len() < DIRENT_SIZE {
+                return Err(invalid(0));
+            }
+
+            let first_name_offset = usize::from(LittleEndian::read_u16(&block[8..]));
+            if first_name_offset < DIRENT_SIZE
+                || first_name_offset % DIRENT_SIZE != 0
+                || first_name_offset > block.len()
+            {
+                return Err(invalid(0));
+            }
+
+            let count = first_name_offset / DIRENT_SIZE;
+
+            for j in 0..count {
+                let offset = j * DIRENT_SIZE;
+                let nid = LittleEndian::read_u64(&block[offset..]);
+                let name_start = usize::from(LittleEndian::read_u16(&block[offset + 8..]));
+                let name_end = if j + 1 < count {
+                    usize::from(LittleEndian::read_u16(&block[offset + DIRENT_SIZE + 8..]))
+                } else {
+                    // The last name is NULL-terminated, unless it fills the
+                    // rest of the block.
+                    block
+                        .get(name_start..)
+                        .and_then(|n| n.iter().position(|b| *b == 0))
+                        .map_or(block.len(), |n| name_start + n)
+                };
+
+                if name_start > name_end || name_end > block.len() || name_start == name_end {
+                    return Err(invalid(offset));
+                }
+
+                let name = &block[name_start..name_end];
+                if name != b"." && name != b".." {
+                    entries.push(DirEntry {
+                        name: name.to_vec(),
+                        inode: nid,
+                    });
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+}
+
+impl<R: Read + Seek> FileSystemReader for ErofsReader<R> {
+    fn root(&self) -> u64 {
+        self.sb.root_nid
+    }
+
+    fn file_type(&mut self, inode: u64) -> filesystem::Result<FileType> {
+        let inode = self.read_inode(inode)?;
+        Ok(inode.file_type())
+    }
+
+    fn read_dir(&mut self, inode: u64) -> filesystem::Result<Vec<DirEntry>> {
+        let inode = self.read_inode(inode)?;
+        let data = self.read_data(&inode, filesystem::MAX_DIR_SIZE)?;
+
+        Ok(self.parse_dir_entries(&inode, &data)?)
+    }
+
+    fn read_link(&mut self, inode: u64) -> filesystem::Result<Vec<u8>> {
+        let inode = self.read_inode(inode)?;
+        Ok(self.read_data(&inode, filesystem::MAX_SYMLINK_SIZE)?)
+    }
+
+    fn read_file(
+        &mut self,
+        inode: u64,
+        writer: &mut dyn Write,
+        cancel_signal: &AtomicBool,
+    ) -> filesystem::Result<()> {
+        let inode = self.read_inode(inode)?;
+        self.copy_data(&inode, writer, cancel_signal)?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use assert_matches::assert_matches;
+
+    use super::*;
+
+    const BLOCK_SIZE: usize = 4096;
+
+    fn write_inode(
+        image: &mut [u8],
+        nid: u64,
+        extended: bool,
+        layout: u8,
+        mode: u16,
+        size: u64,
+        raw_u: u32,
+    ) -> usize {
+        let offset = BLOCK_SIZE + nid as usize * INODE_SLOT_SIZE as usize;
+        let inode = &mut image[offset..];
+
+        LittleEndian::write_u16(
+            &mut inode[0..],
+            u16::from(layout) << 1 | u16::from(extended),
+        );
+        LittleEndian::write_u16(&mut inode[4..], mode);
+        if extended {
+            LittleEndian::write_u64(&mut inode[8..], size);
+        } else {
+            LittleEndian::write_u32(&mut inode[8..], size as u32);
+        }
+        LittleEndian::write_u32(&mut inode[16..], raw_u);
+
+        offset
+            + if extended {
+                INODE_EXTENDED_SIZE
+            } else {
+                INODE_COMPACT_SIZE
+            } as usize
+    }
+
+    /// Build an uncompressed image with a compact root directory and an inline
+    /// plain file, symlink, tail-packed file, and chunk-based file with a hole.
+    fn build_image() -> Vec<u8> {
+        let mut image = vec![0u8; 7 * BLOCK_SIZE];
+
+        let sb = &mut image[SUPERBLOCK_OFFSET as usize..];
+        LittleEndian::write_u32(&mut sb[0..], EROFS_MAGIC);
+        sb[12] = 12;
+        LittleEndian::write_u16(&mut sb[14..], 0);
+        LittleEndian::write_u32(&mut sb[40..], 1);
+
+        let entries: [(&[u8], u64); 6] = [
+            (b".", 0),
+            (b"..", 0),
+            (b"chunk", 10),
+            (b"file", 4),
+            (b"link", 5),
+            (b"tail", 7),
+        ];
+        let mut dir = vec![0u8; entries.len() * DIRENT_SIZE];
+        for (i, (name, nid)) in entries.iter().enumerate() {
+            let name_offset = dir.len() as u16;
+            LittleEndian::write_u64(&mut dir[i * DIRENT_SIZE..], *nid);
+            LittleEndian::write_u16(&mut dir[i * DIRENT_SIZE + 8..], name_offset);
+            dir.extend_from_slice(name);
+        }
+
+        let offset = write_inode(
+            &mut image,
+            0,
+            false,
+            LAYOUT_FLAT_INLINE,
+            S_IFDIR | 0o755,
+            dir.len() as u64,
+            0,
+        );
+        image[offset..offset + dir.len()].copy_from_slice(&dir);
+
+        write_inode(
+            &mut image,
+            4,
+            false,
+            LAYOUT_FLAT_PLAIN,
+            S_IFREG | 0o644,
+            5000,
+            2,
+        );
+        for (i, b) in image[2 * BLOCK_SIZE..2 * BLOCK_SIZE + 5000]
+            .iter_mut()
+            .enumerate()
+        {
+            *b = i as u8;
+        }
+
+        let offset = write_inode(
+            &mut image,
+            5,
+            false,
+            LAYOUT_FLAT_INLINE,
+            S_IFLNK | 0o777,
+            5,
+            0,
+        );
+        image[offset..offset + 5].copy_from_slice(b"/tail");
+
+        let offset = write_inode(
+            &mut image,
+            7,
+            true,
+            LAYOUT_FLAT_INLINE,
+            S_IFREG | 0o644,
+            BLOCK_SIZE as u64 + 4,
+            4,
+        );
+        image[offset..offset + 4].copy_from_slice(b"tail");
+        image[4 * BLOCK_SIZE..5 * BLOCK_SIZE].fill(b'T');
+
+        let offset = write_inode(
+            &mut image,
+            10,
+            false,
+            LAYOUT_CHUNK_BASED,
+            S_IFREG | 0o644,
+            3 * BLOCK_SIZE as u64,
+            0,
+        );
+        for (i, blkaddr) in [5, NULL_ADDR, 6].iter().enumerate() {
+            LittleEndian::write_u32(&mut image[offset + i * 4..], *blkaddr);
+        }
+        image[5 * BLOCK_SIZE..6 * BLOCK_SIZE].fill(b'A');
+        image[6 * BLOCK_SIZE..7 * BLOCK_SIZE].fill(b'B');
+
+        image
+    }
+
+    fn read_file(fs: &mut dyn FileSystemReader, path: &str) -> Vec<u8> {
+        let (_, inode) = filesystem::find(fs, path).unwrap().remove(0);
+        let mut data = vec![];
+        fs.read_file(inode, &mut data, &AtomicBool::new(false))
+            .unwrap();
+        data
+    }
+
+    #[test]
+    fn read_uncompressed() {
+        let mut fs = filesystem::open(Cursor::new(build_image())).unwrap();
+
+        let names = fs
+            .read_dir(fs.root())
+            .unwrap()
+            .into_iter()
+            .map(|e| e.name)
+            .collect::<Vec<_>>();
+        assert_eq!(names, [&b"chunk"[..], b"file", b"link", b"tail"]);
+
+        let data = read_file(fs.as_mut(), "/file");
+        assert_eq!(data.len(), 5000);
+        assert!(data.iter().enumerate().all(|(i, b)| *b == i as u8));
+
+        let mut expected = vec![b'T'; BLOCK_SIZE];
+        expected.extend_from_slice(b"tail");
+        assert_eq!(read_file(fs.as_mut(), "/tail"), expected);
+
+        let link = filesystem::resolve(fs.as_mut(), &[b"link".to_vec()], false)
+            .unwrap()
+            .unwrap();
+        assert_eq!(fs.file_type(link).unwrap(), FileType::Symlink);
+        assert_eq!(fs.read_link(link).unwrap(), b"/tail");
+        assert_eq!(
+            filesystem::resolve(fs.as_mut(), &[b"link".to_vec()], true).unwrap(),
+            Some(7),
+        );
+
+        let mut expected = vec![b'A'; BLOCK_SIZE];
+        expected.resize(2 * BLOCK_SIZE, 0);
+        expected.resize(3 * BLOCK_SIZE, b'B');
+        assert_eq!(read_file(fs.as_mut(), "/chunk"), expected);
+    }
+
+    #[test]
+    fn reject_oversized_extent() {
+        // A single head lcluster followed only by non-head lclusters makes the
+        // whole file one extent.
+        let lcluster_count = 2 * MAX_EXTENT_SIZE / BLOCK_SIZE as u64;
+        let mut image = vec![0u8; 2 * BLOCK_SIZE + lcluster_count as usize * 8];
+
+        let sb = &mut image[SUPERBLOCK_OFFSET as usize..];
+        LittleEndian::write_u32(&mut sb[0..], EROFS_MAGIC);
+        sb[12] = 12;
+        LittleEndian::write_u32(&mut sb[40..], 1);
+        LittleEndian::write_u32(&mut sb[80..], FEATURE_INCOMPAT_ZERO_PADDING);
+
+        let offset = write_inode(
+            &mut image,
+            0,
+            true,
+            LAYOUT_COMPRESSED_FULL,
+            S_IFREG | 0o644,
+            lcluster_count * BLOCK_SIZE as u64,
+            0,
+        );
+
+        // Map header followed by an 8-byte reserved gap.
+        let index_offset = align(offset as u64, 8) as usize + 16;
+        LittleEndian::write_u16(&mut image[index_offset..], LCLUSTER_TYPE_HEAD1.into());
+        for lcn in 1..lcluster_count as usize {
+            LittleEndian::write_u16(
+                &mut image[index_offset + lcn * 8..],
+                LCLUSTER_TYPE_NONHEAD.into(),
+            );
+        }
+
+        let mut reader = ErofsReader::new(Cursor::new(image)).unwrap();
+        let inode = reader.read_inode(0).unwrap();
+
+        assert_matches!(
+            reader.copy_data(&inode, &mut io::sink(), &AtomicBool::new(false)),
+            Err(Error::ExtentTooLarge(0, 0))
+        );
+    }
+
+    #[test]
+    fn reject_oversized_symlink() {
+        let mut image = build_image();
+        let size = filesystem::MAX_SYMLINK_SIZE as u32 + 1;
+        LittleEndian::write_u32(
+            &mut image[BLOCK_SIZE + 5 * INODE_SLOT_SIZE as usize + 8..],
+            size,
+        );
+
+        let mut fs = ErofsReader::new(Cursor::new(image)).unwrap();
+
+        assert_matches!(
+            fs.read_link(5),
+            Err(filesystem::Error::Erofs(Error::TooLarge(5, s))) if s == u64::from(size)
+        );
+    }
+
+    #[test]
+    fn reject_invalid_magic() {
+        let mut image = build_image();
+        image[SUPERBLOCK_OFFSET as usize] ^= 0xff;
+
+        assert_matches!(
+            ErofsReader::new(Cursor::new(image)).err(),
+            Some(Error::InvalidMagic(_))
+        );
+    }
+}
@@ -0,0 +1,601 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Minimal read-only ext4 reader. Only what is needed for pulling individual
+//! files out of Android partition images is implemented: extent-mapped and
+//! block-mapped files, linear and hashed directories, and symlinks. Inline data
+//! and encrypted files are not supported. The journal is ignored.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::atomic::AtomicBool,
+};
+
+use byteorder::{ByteOrder, LittleEndian};
+use thiserror::Error;
+
+use crate::{
+    format::filesystem::{self, DirEntry, FileSystemReader, FileType},
+    stream::{self, WriteZerosExt},
+};
+
+pub const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+pub const EXT4_MAGIC_OFFSET: u64 = SUPERBLOCK_OFFSET + 0x38;
+pub const EXT4_MAGIC: u16 = 0xef53;
+
+const ROOT_INODE: u32 = 2;
+const MAX_LOG_BLOCK_SIZE: u32 = 6;
+
+const INCOMPAT_FILETYPE: u32 = 0x2;
+const INCOMPAT_RECOVER: u32 = 0x4;
+const INCOMPAT_EXTENTS: u32 = 0x40;
+const INCOMPAT_64BIT: u32 = 0x80;
+const INCOMPAT_MMP: u32 = 0x100;
+const INCOMPAT_FLEX_BG: u32 = 0x200;
+const INCOMPAT_EA_INODE: u32 = 0x400;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+const INCOMPAT_INLINE_DATA: u32 = 0x8000;
+const INCOMPAT_ENCRYPT: u32 = 0x10000;
+const INCOMPAT_CASEFOLD: u32 = 0x20000;
+
+/// Features that don't affect how files are located on disk. Inline data and
+/// encryption are checked for each inode instead.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_EA_INODE
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR
+    | INCOMPAT_INLINE_DATA
+    | INCOMPAT_ENCRYPT
+    | INCOMPAT_CASEFOLD;
+
+const INODE_FLAG_ENCRYPT: u32 = 0x800;
+const INODE_FLAG_EXTENTS: u32 = 0x80000;
+const INODE_FLAG_EA_INODE: u32 = 0x200000;
+const INODE_FLAG_INLINE_DATA: u32 = 0x10000000;
+
+const S_IFMT: u16 = 0xf000;
+const S_IFREG: u16 = 0x8000;
+const S_IFDIR: u16 = 0x4000;
+const S_IFLNK: u16 = 0xa000;
+
+const EXTENT_MAGIC: u16 = 0xf30a;
+const EXTENT_MAX_DEPTH: u16 = 5;
+const EXTENT_INIT_MAX_LEN: u16 = 32768;
+
+/// Number of direct block pointers in a block-mapped inode.
+const DIRECT_BLOCKS: usize = 12;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid ext4 superblock magic: {0:#06x}")]
+    InvalidMagic(u16),
+    #[error("Invalid ext4 superblock field: {0}")]
+    InvalidSuperblock(&'static str),
+    #[error("Unsupported ext4 incompatible features: {0:#x}")]
+    UnsupportedFeatures(u32),
+    #[error("Inode {0} is out of range")]
+    InodeOutOfRange(u32),
+    #[error("Inode {0} uses unsupported feature: {1}")]
+    UnsupportedInode(u32, &'static str),
+    #[error("Invalid extent tree in inode {0}")]
+    InvalidExtentTree(u32),
+    #[error("Invalid directory entry in inode {0} at offset {1}")]
+    InvalidDirEntry(u32, u64),
+    #[error("Inode {0} is too large to read into memory: {1} bytes")]
+    TooLarge(u32, u64),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Debug)]
+struct Superblock {
+    inodes_count: u32,
+    first_data_block: u32,
+    block_size: u32,
+    inodes_per_group: u32,
+    inode_size: u16,
+    feature_incompat: u32,
+    desc_size: u16,
+}
+
+impl Superblock {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let magic = LittleEndian::read_u16(&data[0x38..]);
+        if magic != EXT4_MAGIC {
+            return Err(Error::InvalidMagic(magic));
+        }
+
+        let feature_incompat = LittleEndian::read_u32(&data[0x60..]);
+        if feature_incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(Error::UnsupportedFeatures(
+                feature_incompat & !INCOMPAT_SUPPORTED,
+            ));
+        }
+
+        let log_block_size = LittleEndian::read_u32(&data[0x18..]);
+        if log_block_size > MAX_LOG_BLOCK_SIZE {
+            return Err(Error::InvalidSuperblock("s_log_block_size"));
+        }
+        let block_size = 1024 << log_block_size;
+
+        let inodes_per_group = LittleEndian::read_u32(&data[0x28..]);
+        if inodes_per_group == 0 {
+            return Err(Error::InvalidSuperblock("s_inodes_per_group"));
+        }
+
+        let rev_level = LittleEndian::read_u32(&data[0x4c..]);
+        let inode_size = if rev_level == 0 {
+            128
+        } else {
+            LittleEndian::read_u16(&data[0x58..])
+        };
+        if inode_size < 128 || !inode_size.is_power_of_two() || u32::from(inode_size) > block_size {
+            return Err(Error::InvalidSuperblock("s_inode_size"));
+        }
+
+        let desc_size = if feature_incompat & INCOMPAT_64BIT != 0 {
+            LittleEndian::read_u16(&data[0xfe..])
+        } else {
+            32
+        };
+        if desc_size < 32 || !desc_size.is_power_of_two() || u32::from(desc_size) > block_size {
+            return Err(Error::InvalidSuperblock("s_desc_size"));
+        }
+
+        Ok(Self {
+            inodes_count: LittleEndian::read_u32(&data[0x0..]),
+            first_data_block: LittleEndian::read_u32(&data[0x14..]),
+            block_size,
+            inodes_per_group,
+            inode_size,
+            feature_incompat,
+            desc_size,
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+struct Inode {
+    number: u32,
+    mode: u16,
+    size: u64,
+    blocks: u32,
+    flags: u32,
+    block: [u8; 60],
+    file_acl: u32,
+}
+
+impl Inode {
+    fn file_type(&self) -> FileType {
+        match self.mode & S_IFMT {
+            S_IFREG => FileType::Regular,
+            S_IFDIR => FileType::Directory,
+            S_IFLNK => FileType::Symlink,
+            _ => FileType::Other,
+        }
+    }
+}
+
+/// A contiguous run of blocks in a file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Extent {
+    logical: u64,
+    physical: u64,
+    len: u64,
+    /// Uninitialized extents are allocated, but read back as zeros.
+    uninit: bool,
+}
+
+/// Append a single block to the extent list, merging it into the previous
+/// extent if they are contiguous.
+fn push_block(extents: &mut Vec<Extent>, logical: u64, physical: u64) {
+    if let Some(last) = extents.last_mut() {
+        if !last.uninit
+            && last.logical + last.len == logical
+            && last.physical + last.len == physical
+        {
+            last.len += 1;
+            return;
+        }
+    }
+
+    extents.push(Extent {
+        logical,
+        physical,
+        len: 1,
+        uninit: false,
+    });
+}
+
+pub struct Ext4Reader<R: Read + Seek> {
+    reader: R,
+    sb: Superblock,
+}
+
+impl<R: Read + Seek> Ext4Reader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut data = [0u8; SUPERBLOCK_SIZE];
+        reader.seek(SeekFrom::Start(SUPERBLOCK_OFFSET))?;
+        reader.read_exact(&mut data)?;
+
+        let sb = Superblock::parse(&data)?;
+
+        Ok(Self { reader, sb })
+    }
+
+    fn block_size(&self) -> u64 {
+        self.sb.block_size.into()
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<()> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.reader.read_exact(buf)?;
+        Ok(())
+    }
+
+    fn read_block(&mut self, block: u64) -> Result<Vec<u8>> {
+        let mut buf = vec![0u8; self.sb.block_size as usize];
+        let offset = block
+            .checked_mul(self.block_size())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::InvalidData))?;
+        self.read_at(offset, &mut buf)?;
+        Ok(buf)
+    }
+
+    fn read_inode(&mut self, number: u32) -> Result<Inode> {
+        if number == 0 || number > self.sb.inodes_count {
+            return Err(Error::InodeOutOfRange(number));
+        }
+
+        let group = u64::from((number - 1) / self.sb.inodes_per_group);
+        let index = u64::from((number - 1) % self.sb.inodes_per_group);
+
+        let desc_offset = (u64::from(self.sb.first_data_block) + 1) * self.block_size()
+            + group * u64::from(self.sb.desc_size);
+        let mut desc = vec![0u8; self.sb.desc_size.into()];
+        self.read_at(desc_offset, &mut desc)?;
+
+        let mut inode_table = u64::from(LittleEndian::read_u32(&desc[0x8..]));
+        if self.sb.desc_size >= 64 {
+            inode_table |= u64::from(LittleEndian::read_u32(&desc[0x28..])) << 32;
+        }
+
+        let mut data = [0u8; 128];
+        let offset = inode_table
+            .checked_mul(self.block_size())
+            .and_then(|o| o.checked_add(index * u64::from(self.sb.inode_size)))
+            .ok_or(Error::InodeOutOfRange(number))?;
+        self.read_at(offset, &mut data)?;
+
+        let mut block = [0u8; 60];
+        block.copy_from_slice(&data[0x28..0x64]);
+
+        Ok(Inode {
+            number,
+            mode: LittleEndian::read_u16(&data[0x0..]),
+            size: u64::from(LittleEndian::read_u32(&data[0x4..]))
+                | u64::from(LittleEndian::read_u32(&data[0x6c..])) << 32,
+            blocks: LittleEndian::read_u32(&data[0x1c..]),
+            flags: LittleEndian::read_u32(&data[0x20..]),
+            block,
+            file_acl: LittleEndian::read_u32(&data[0x68..]),
+        })
+    }
+
+    fn check_inode_supported(&self, inode: &Inode) -> Result<()> {
+        if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            return Err(Error::UnsupportedInode(inode.number, "inline data"));
+        } else if inode.flags & INODE_FLAG_ENCRYPT != 0 {
+            return Err(Error::UnsupportedInode(inode.number, "encryption"));
+        }
+
+        Ok(())
+    }
+
+    /// Get the list of extents for an inode, sorted by logical block.
+    fn extents(&mut self, inode: &Inode) -> Result<Vec<Extent>> {
+        self.check_inode_supported(inode)?;
+
+        let mut extents = vec![];
+
+        if inode.flags & INODE_FLAG_EXTENTS != 0 {
+            self.walk_extent_node(inode, &inode.block, EXTENT_MAX_DEPTH, &mut extents)?;
+            extents.sort_by_key(|e| e.logical);
+        } else {
+            let total = inode.size.div_ceil(self.block_size());
+            let mut logical = 0;
+
+            for chunk in inode.block.chunks_exact(4).take(DIRECT_BLOCKS) {
+                if logical >= total {
+                    break;
+                }
+
+                let physical = LittleEndian::read_u32(chunk);
+                if physical != 0 {
+                    push_block(&mut extents, logical, physical.into());
+                }
+                logical += 1;
+            }
+
+            for level in 1..=3 {
+                if logical >= total {
+                    break;
+                }
+
+                let offset = (DIRECT_BLOCKS + level - 1) * 4;
+                let pointer = LittleEndian::read_u32(&inode.block[offset..]);
+
+                self.walk_indirect(pointer, level as u32, &mut logical, total, &mut extents)?;
+            }
+        }
+
+        Ok(extents)
+    }
+
+    fn walk_extent_node(
+        &mut self,
+        inode: &Inode,
+        node: &[u8],
+        max_depth: u16,
+        extents: &mut Vec<Extent>,
+    ) -> Result<()> {
+        let invalid = || Error::InvalidExtentTree(inode.number);
+
+        if node.len() < 12 || LittleEndian::read_u16(&node[0..]) != EXTENT_MAGIC {
+            return Err(invalid());
+        }
+
+        let entries = usize::from(LittleEndian::read_u16(&node[2..]));
+        let depth = LittleEndian::read_u16(&node[6..]);
+        if depth > max_depth || 12 + entries * 12 > node.len() {
+            return Err(invalid());
+        }
+
+        for entry in node[12..].chunks_exact(12).take(entries) {
+            let logical = LittleEndian::read_u32(&entry[0..]);
+
+            if depth == 0 {
+                let mut len = LittleEndian::read_u16(&entry[4..]);
+                let uninit = len > EXTENT_INIT_MAX_LEN;
+                if uninit {
+                    len -= EXTENT_INIT_MAX_LEN;
+                }
+
+                let physical = u64::from(LittleEndian::read_u16(&entry[6..])) << 32
+                    | u64::from(LittleEndian::read_u32(&entry[8..]));
+
+                extents.push(Extent {
+                    logical: logical.into(),
+                    physical,
+                    len: len.into(),
+                    uninit,
+                });
+            } else {
+                let child = u64::from(LittleEndian::read_u32(&entry[4..]))
+                    | u64::from(LittleEndian::read_u16(&entry[8..])) << 32;
+                let data = self.read_block(child)?;
+
+                self.walk_extent_node(inode, &data, depth - 1, extents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn walk_indirect(
+        &mut self,
+        pointer: u32,
+        level: u32,
+        logical: &mut u64,
+        total: u64,
+        extents: &mut Vec<Extent>,
+    ) -> Result<()> {
+        let per_block = self.block_size() / 4;
+
+        if pointer == 0 {
+            *logical = logical.saturating_add(per_block.pow(level));
+            return Ok(());
+        }
+
+        let data = self.read_block(pointer.into())?;
+
+        for chunk in data.chunks_exact(4) {
+            if *logical >= total {
+                break;
+            }
+
+            let child = LittleEndian::read_u32(chunk);
+
+            if level == 1 {
+                if child != 0 {
+                    push_block(extents, *logical, child.into());
+                }
+                *logical += 1;
+            } else {
+                self.walk_indirect(child, level - 1, logical, total, extents)?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Copy the contents of an inode to `writer`. Holes and uninitialized
+    /// extents are written as zeros.
+    fn copy_data(
+        &mut self,
+        inode: &Inode,
+        mut writer: impl Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        let block_size = self.block_size();
+        let extents = self.extents(inode)?;
+        let mut offset = 0;
+
+        for extent in extents {
+            let start = extent.logical.saturating_mul(block_size).min(inode.size);
+            let end = extent
+                .logical
+                .saturating_add(extent.len)
+                .saturating_mul(block_size)
+                .min(inode.size);
+            if start < offset {
+                return Err(Error::InvalidExtentTree(inode.number));
+            } else if start == end {
+                continue;
+            }
+
+            writer.write_zeros_exact(start - offset)?;
+
+            if extent.uninit {
+                writer.write_zeros_exact(end - start)?;
+            } else {
+                let physical = extent
+                    .physical
+                    .checked_mul(block_size)
+                    .ok_or(Error::InvalidExtentTree(inode.number))?;
+                self.reader.seek(SeekFrom::Start(physical))?;
+                stream::copy_n(&mut self.reader, &mut writer, end - start, cancel_signal)?;
+            }
+
+            offset = end;
+        }
+
+        writer.write_zeros_exact(inode.size - offset)?;
+
+        Ok(())
+    }
+
+    /// Read the contents of an inode into memory. The inode is rejected if it
+    /// is larger than `limit`.
+    fn read_data(&mut self, inode: &Inode, limit: u64) -> Result<Vec<u8>> {
+        if inode.size > limit {
+            return Err(Error::TooLarge(inode.number, inode.size));
+        }
+
+        let mut data = vec![];
+        self.copy_data(inode, &mut data, &AtomicBool::new(false))?;
+        Ok(data)
+    }
+
+    /// Whether a symlink's target is stored directly in the inode's block
+    /// pointers. This matches the kernel's `ext4_inode_is_fast_symlink()`.
+    fn is_fast_symlink(&self, inode: &Inode) -> bool {
+        if inode.flags & INODE_FLAG_EA_INODE != 0 {
+            inode.size > 0 && inode.size < inode.block.len() as u64
+        } else if inode.flags & INODE_FLAG_INLINE_DATA != 0 {
+            false
+        } else {
+            let acl_blocks = if inode.file_acl != 0 {
+                self.sb.block_size / 512
+            } else {
+                0
+            };
+
+            inode.blocks == acl_blocks
+        }
+    }
+
+    fn parse_dir_entries(&self, inode: &Inode, data: &[u8]) -> Result<Vec<DirEntry>> {
+        let has_file_type = self.sb.feature_incompat & INCOMPAT_FILETYPE != 0;
+        let mut entries = vec![];
+
+        for (i, block) in data.chunks(self.sb.block_size as usize).enumerate() {
+            let mut offset = 0;
+
+            while offset + 8 <= block.len() {
+                let invalid = || {
+                    Error::InvalidDirEntry(
+                        inode.number,
+                        i as u64 * self.block_size() + offset as u64,
+                    )
+                };
+
+                let number = LittleEndian::read_u32(&block[offset..]);
+                let rec_len = usize::from(LittleEndian::read_u16(&block[offset + 4..]));
+                let name_len = if has_file_type {
+                    usize::from(block[offset + 6])
+                } else {
+                    usize::from(LittleEndian::read_u16(&block[offset + 6..]))
+                };
+
+                if rec_len < 8 || rec_len % 4 != 0 || offset + rec_len > block.len() {
+                    return Err(invalid());
+                }
+
+                if number != 0 && name_len > 0 {
+                    if 8 + name_len > rec_len {
+                        return Err(invalid());
+                    }
+
+                    let name = &block[offset + 8..offset + 8 + name_len];
+                    if name != b"." && name != b".." {
+                        entries.push(DirEntry {
+                            name: name.to_vec(),
+                            inode: number.into(),
+                        });
+                    }
+                }
+
+                offset += rec_len;
+            }
+        }
+
+        Ok(entries)
+    }
+
+    fn inode_number(&self, inode: u64) -> Result<u32> {
+        u32::try_from(inode).map_err(|_| Error::InodeOutOfRange(u32::MAX))
+    }
+}
+
+impl<R: Read + Seek> FileSystemReader for Ext4Reader<R> {
+    fn root(&self) -> u64 {
+        ROOT_INODE.into()
+    }
+
+    fn file_type(&mut self, inode: u64) -> filesystem::Result<FileType> {
+        let inode = self.read_inode(self.inode_number(inode)?)?;
+        Ok(inode.file_type())
+    }
+
+    fn read_dir(&mut self, inode: u64) -> filesystem::Result<Vec<DirEntry>> {
+        let inode = self.read_inode(self.inode_number(inode)?)?;
+        let data = self.read_data(&inode, filesystem::MAX_DIR_SIZE)?;
+
+        Ok(self.parse_dir_entries(&inode, &data)?)
+    }
+
+    fn read_link(&mut self, inode: u64) -> filesystem::Result<Vec<u8>> {
+        let inode = self.read_inode(self.inode_number(inode)?)?;
+
+        if self.is_fast_symlink(&inode) {
+            let size = (inode.size as usize).min(inode.block.len());
+            Ok(inode.block[..size].to_vec())
+        } else {
+            Ok(self.read_data(&inode, filesystem::MAX_SYMLINK_SIZE)?)
+        }
+    }
+
+    fn read_file(
+        &mut self,
+        inode: u64,
+        writer: &mut dyn Write,
+        cancel_signal: &AtomicBool,
+    ) -> filesystem::Result<()> {
+        let inode = self.read_inode(self.inode_number(inode)?)?;
+        self.copy_data(&inode, writer, cancel_signal)?;
+
+        Ok(())
+    }
+}
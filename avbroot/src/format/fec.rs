@@ -678,7 +678,7 @@ impl FecImage {
 
         let mut writer = Cursor::new([0u8; FEC_HEADER_SIZE]);
 
-        let digest = ring::digest::digest(&ring::digest::SHA256, &self.fec);
+        let digest = crate::digest::digest(&crate::digest::SHA256, &self.fec);
 
         writer.write_u32::<LittleEndian>(FEC_MAGIC)?;
         writer.write_u32::<LittleEndian>(FEC_VERSION)?;
@@ -757,7 +757,7 @@ impl<R: Read> FromReader<R> for FecImage {
         // Chop off headers.
         fec.resize(fec_size, 0);
 
-        let actual_digest = ring::digest::digest(&ring::digest::SHA256, &fec);
+        let actual_digest = crate::digest::digest(&crate::digest::SHA256, &fec);
         if digest != actual_digest.as_ref() {
             return Err(Error::InvalidFecDigest {
                 expected: hex::encode(digest),
@@ -851,7 +851,7 @@ mod tests {
             let mut buf = vec![0u8; size];
             rand::thread_rng().fill_bytes(&mut buf);
             file.write_all(&buf).unwrap();
-            ring::digest::digest(&ring::digest::SHA256, &buf)
+            crate::digest::digest(&crate::digest::SHA256, &buf)
         };
 
         let fec = Fec::new(size as u64, block_size, parity).unwrap();
@@ -886,7 +886,7 @@ mod tests {
             let mut buf = Vec::new();
             file.rewind().unwrap();
             file.read_to_end(&mut buf).unwrap();
-            ring::digest::digest(&ring::digest::SHA256, &buf)
+            crate::digest::digest(&crate::digest::SHA256, &buf)
         };
         assert_eq!(repaired_digest.as_ref(), orig_digest.as_ref());
 
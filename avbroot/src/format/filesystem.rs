@@ -0,0 +1,296 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Common interface for the read-only filesystem readers. This is only meant
+//! for looking up and reading individual files from partition images, so
+//! metadata like permissions, ownership, and xattrs are not exposed.
+
+use std::{
+    io::{self, Read, Seek, SeekFrom, Write},
+    sync::atomic::AtomicBool,
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use thiserror::Error;
+
+use crate::format::{
+    erofs::{self, ErofsReader},
+    ext4::{self, Ext4Reader},
+};
+
+/// Same limit as Linux's `MAXSYMLINKS`.
+const MAX_SYMLINKS: usize = 40;
+
+/// Maximum size of a directory that will be read into memory. Directories in
+/// Android partition images are nowhere near this large.
+pub const MAX_DIR_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Maximum length of a symlink target. Same limit as Linux's `PATH_MAX`.
+pub const MAX_SYMLINK_SIZE: u64 = 4096;
+
+/// Maximum directory nesting depth when walking a directory tree recursively.
+pub const MAX_DEPTH: usize = 256;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Unrecognized filesystem")]
+    UnknownFilesystem,
+    #[error("Path is not absolute: {0:?}")]
+    RelativePath(String),
+    #[error("Path not found: {0:?}")]
+    NotFound(String),
+    #[error("Too many levels of symbolic links: {0:?}")]
+    SymlinkLoop(String),
+    #[error("ext4 error")]
+    Ext4(#[from] ext4::Error),
+    #[error("EROFS error")]
+    Erofs(#[from] erofs::Error),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    Other,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: Vec<u8>,
+    pub inode: u64,
+}
+
+pub trait FileSystemReader {
+    /// Inode number of the root directory.
+    fn root(&self) -> u64;
+
+    fn file_type(&mut self, inode: u64) -> Result<FileType>;
+
+    /// List the entries of a directory, excluding `.` and `..`.
+    fn read_dir(&mut self, inode: u64) -> Result<Vec<DirEntry>>;
+
+    /// Get the target of a symlink.
+    fn read_link(&mut self, inode: u64) -> Result<Vec<u8>>;
+
+    /// Copy the contents of a regular file to `writer`.
+    fn read_file(
+        &mut self,
+        inode: u64,
+        writer: &mut dyn Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()>;
+}
+
+/// Open a filesystem image, detecting the filesystem type from the superblock.
+pub fn open(mut reader: impl Read + Seek + 'static) -> Result<Box<dyn FileSystemReader>> {
+    reader.seek(SeekFrom::Start(erofs::SUPERBLOCK_OFFSET))?;
+    if reader.read_u32::<LittleEndian>()? == erofs::EROFS_MAGIC {
+        return Ok(Box::new(ErofsReader::new(reader)?));
+    }
+
+    reader.seek(SeekFrom::Start(ext4::EXT4_MAGIC_OFFSET))?;
+    if reader.read_u16::<LittleEndian>()? == ext4::EXT4_MAGIC {
+        return Ok(Box::new(Ext4Reader::new(reader)?));
+    }
+
+    Err(Error::UnknownFilesystem)
+}
+
+/// Check if `name` matches a shell-style glob `pattern`. Only `*` and `?` are
+/// supported. Unlike shells, `*` also matches leading dots.
+pub fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Position to backtrack to after the last `*`.
+    let mut star = None;
+
+    while n < name.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((sp, sn)) => {
+                    p = sp + 1;
+                    n = sn + 1;
+                    star = Some((sp, sn + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|c| *c == b'*')
+}
+
+fn is_glob(component: &[u8]) -> bool {
+    component.iter().any(|c| *c == b'*' || *c == b'?')
+}
+
+fn join(components: &[Vec<u8>]) -> String {
+    let mut result = String::new();
+
+    for c in components {
+        result.push('/');
+        result.push_str(&String::from_utf8_lossy(c));
+    }
+
+    if result.is_empty() {
+        result.push('/');
+    }
+
+    result
+}
+
+/// Resolve an absolute path to an inode. Symlinks in the path are followed,
+/// except for the last component if `follow_last` is false. Absolute symlink
+/// targets are resolved relative to the root of the filesystem. Returns
+/// [`None`] if the path does not exist.
+pub fn resolve(
+    fs: &mut dyn FileSystemReader,
+    components: &[Vec<u8>],
+    follow_last: bool,
+) -> Result<Option<u64>> {
+    // Pending components in reverse order.
+    let mut pending = components.iter().rev().cloned().collect::<Vec<_>>();
+    // Inodes of the directories leading up to the current position.
+    let mut stack = vec![fs.root()];
+    let mut symlinks = 0;
+
+    while let Some(component) = pending.pop() {
+        if component.is_empty() || component == b"." {
+            continue;
+        } else if component == b".." {
+            if stack.len() > 1 {
+                stack.pop();
+            }
+            continue;
+        }
+
+        let dir = *stack.last().unwrap();
+        if fs.file_type(dir)? != FileType::Directory {
+            return Ok(None);
+        }
+
+        let Some(entry) = fs.read_dir(dir)?.into_iter().find(|e| e.name == component) else {
+            return Ok(None);
+        };
+
+        let is_last = pending.iter().all(|c| c.is_empty() || c == b".");
+
+        if (!is_last || follow_last) && fs.file_type(entry.inode)? == FileType::Symlink {
+            symlinks += 1;
+            if symlinks > MAX_SYMLINKS {
+                return Err(Error::SymlinkLoop(join(components)));
+            }
+
+            let target = fs.read_link(entry.inode)?;
+            if target.starts_with(b"/") {
+                stack.truncate(1);
+            }
+
+            pending.extend(target.split(|c| *c == b'/').rev().map(|c| c.to_vec()));
+            continue;
+        }
+
+        stack.push(entry.inode);
+    }
+
+    Ok(stack.last().copied())
+}
+
+/// Find all paths matching `pattern`, which must be absolute. Each path
+/// component may contain glob characters (see [`glob_match`]). The last
+/// component is not resolved if it is a symlink. Returns a sorted list of
+/// matching paths and their inodes.
+pub fn find(fs: &mut dyn FileSystemReader, pattern: &str) -> Result<Vec<(String, u64)>> {
+    if !pattern.starts_with('/') {
+        return Err(Error::RelativePath(pattern.to_owned()));
+    }
+
+    let mut candidates: Vec<Vec<Vec<u8>>> = vec![vec![]];
+
+    for component in pattern.as_bytes().split(|c| *c == b'/') {
+        if component.is_empty() || component == b"." {
+            continue;
+        }
+
+        let mut next = vec![];
+
+        for candidate in candidates {
+            if is_glob(component) {
+                let Some(dir) = resolve(fs, &candidate, true)? else {
+                    continue;
+                };
+                if fs.file_type(dir)? != FileType::Directory {
+                    continue;
+                }
+
+                let mut names = fs
+                    .read_dir(dir)?
+                    .into_iter()
+                    .map(|e| e.name)
+                    .filter(|n| glob_match(component, n))
+                    .collect::<Vec<_>>();
+                names.sort();
+
+                for name in names {
+                    let mut path = candidate.clone();
+                    path.push(name);
+                    next.push(path);
+                }
+            } else {
+                let mut path = candidate;
+                path.push(component.to_vec());
+                next.push(path);
+            }
+        }
+
+        candidates = next;
+    }
+
+    let mut result = vec![];
+
+    for candidate in candidates {
+        if let Some(inode) = resolve(fs, &candidate, false)? {
+            result.push((join(&candidate), inode));
+        }
+    }
+
+    if result.is_empty() {
+        return Err(Error::NotFound(pattern.to_owned()));
+    }
+
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob() {
+        assert!(glob_match(b"fstab.*", b"fstab.husky"));
+        assert!(glob_match(b"fstab.*", b"fstab."));
+        assert!(!glob_match(b"fstab.*", b"fstab"));
+        assert!(glob_match(b"*.prop", b"build.prop"));
+        assert!(glob_match(b"*", b".hidden"));
+        assert!(glob_match(b"a*b*c", b"abxbyc"));
+        assert!(!glob_match(b"a*b*c", b"abxbyd"));
+        assert!(glob_match(b"?at", b"cat"));
+        assert!(!glob_match(b"?at", b"at"));
+        assert!(glob_match(b"build.prop", b"build.prop"));
+        assert!(!glob_match(b"build.prop", b"build.props"));
+    }
+}
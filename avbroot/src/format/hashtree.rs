@@ -5,7 +5,8 @@
 
 use std::{
     fmt,
-    io::{self, Cursor, Read, SeekFrom, Write},
+    fs::File,
+    io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
     ops::Range,
     sync::atomic::AtomicBool,
 };
@@ -17,10 +18,10 @@ use rayon::{
     iter::{IndexedParallelIterator, ParallelIterator},
     slice::ParallelSliceMut,
 };
-use ring::digest::{Algorithm, Context};
 use thiserror::Error;
 
 use crate::{
+    digest::{Algorithm, Context},
     format::{avb, padding},
     stream::{self, FromReader, ReadSeekReopen, ReadStringExt, ToWriter, WriteStringExt},
     util::{self, NumBytes},
@@ -52,6 +53,36 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Maximum size of the digests that [`HashTree::generate_streaming()`] keeps in
+/// memory at a time.
+const STREAMING_WINDOW_SIZE: usize = 16 * 1024 * 1024;
+
+/// Hash tree data where each level is stored in a temporary file instead of in
+/// memory.
+pub struct SpilledHashTree {
+    pub root_digest: Vec<u8>,
+    /// Each level's file and size, with the bottom level first.
+    levels: Vec<(File, usize)>,
+}
+
+impl SpilledHashTree {
+    /// Total size of the hash tree data.
+    pub fn size(&self) -> usize {
+        self.levels.iter().map(|(_, size)| size).sum()
+    }
+
+    /// Write the hash tree data in the standard layout, which has the top level
+    /// first.
+    pub fn write_to(self, mut writer: impl Write, cancel_signal: &AtomicBool) -> Result<()> {
+        for (mut file, size) in self.levels.into_iter().rev() {
+            file.rewind()?;
+            stream::copy_n(&mut file, &mut writer, size as u64, cancel_signal)?;
+        }
+
+        Ok(())
+    }
+}
+
 pub struct HashTree<'a> {
     block_size: u32,
     algorithm: &'static Algorithm,
@@ -177,11 +208,14 @@ impl<'a> HashTree<'a> {
         Ok(())
     }
 
-    /// Hash one full level in parallel.
+    /// Hash one full level in parallel. If `in_offset` is block-aligned, then
+    /// this function can also be used to calculate the digests for the portion
+    /// of the level starting at that input offset.
     fn hash_one_level_parallel(
         &self,
         input: &(dyn ReadSeekReopen + Sync),
         size: u64,
+        in_offset: u64,
         level_data: &mut [u8],
         cancel_signal: &AtomicBool,
     ) -> io::Result<()> {
@@ -199,7 +233,7 @@ impl<'a> HashTree<'a> {
             .enumerate()
             .map(|(chunk, out_data)| -> io::Result<()> {
                 let digests = out_data.len() / digest_size;
-                let in_start = (chunk as u64) * multiplier * u64::from(self.block_size);
+                let in_start = in_offset + (chunk as u64) * multiplier * u64::from(self.block_size);
                 let in_size = ((digests as u64) * u64::from(self.block_size)).min(size - in_start);
 
                 let mut reader = input.reopen_boxed()?;
@@ -301,7 +335,7 @@ impl<'a> HashTree<'a> {
                 )?;
             } else {
                 // Read entire file.
-                self.hash_one_level_parallel(input, image_size, level_data, cancel_signal)?;
+                self.hash_one_level_parallel(input, image_size, 0, level_data, cancel_signal)?;
             }
 
             // No need to explicitly ensure the level is padded to the block
@@ -341,6 +375,114 @@ impl<'a> HashTree<'a> {
         Ok((root_digest, hash_tree_data))
     }
 
+    /// Generate hash tree data for the file with bounded memory usage. Instead
+    /// of building the whole tree in memory, each level is computed in a
+    /// separate pass and written to a temporary file. Only a fixed-size window
+    /// of digests is held in memory at a time.
+    pub fn generate_streaming(
+        &self,
+        input: &(dyn ReadSeekReopen + Sync),
+        image_size: u64,
+        cancel_signal: &AtomicBool,
+    ) -> Result<SpilledHashTree> {
+        self.generate_spilled(input, image_size, STREAMING_WINDOW_SIZE, cancel_signal)
+    }
+
+    fn generate_spilled(
+        &self,
+        input: &(dyn ReadSeekReopen + Sync),
+        image_size: u64,
+        window_size: usize,
+        cancel_signal: &AtomicBool,
+    ) -> Result<SpilledHashTree> {
+        let offsets = self.compute_level_offsets(image_size)?;
+
+        if offsets.is_empty() {
+            let root_digest =
+                self.calculate(input, image_size, None, &offsets, &mut [], cancel_signal)?;
+
+            return Ok(SpilledHashTree {
+                root_digest,
+                levels: vec![],
+            });
+        }
+
+        let block_size = u64::from(self.block_size);
+        let digest_size = self.algorithm.output_len().next_power_of_two();
+        let mut window = vec![0u8; window_size.max(digest_size)];
+        let mut levels = Vec::<(File, usize)>::with_capacity(offsets.len());
+
+        for level_range in &offsets {
+            let level_size = level_range.end - level_range.start;
+            let mut writer = BufWriter::new(tempfile::tempfile()?);
+
+            // The bottom level hashes the input file and every other level
+            // hashes the (padded) level below it.
+            let mut prev_reader = match levels.last_mut() {
+                Some((file, size)) => {
+                    file.rewind()?;
+                    Some((BufReader::new(&*file), *size as u64))
+                }
+                None => None,
+            };
+            let in_size = prev_reader.as_ref().map_or(image_size, |(_, s)| *s);
+            let mut in_offset = 0;
+            let mut written = 0;
+
+            while in_offset < in_size {
+                let digests = util::div_ceil(in_size - in_offset, block_size)
+                    .min((window.len() / digest_size) as u64);
+                let out_data = &mut window[..digests as usize * digest_size];
+                let n = (digests * block_size).min(in_size - in_offset);
+
+                match &mut prev_reader {
+                    Some((reader, _)) => {
+                        self.hash_partial_level(reader, n, out_data, cancel_signal)?;
+                    }
+                    None => {
+                        self.hash_one_level_parallel(
+                            input,
+                            image_size,
+                            in_offset,
+                            out_data,
+                            cancel_signal,
+                        )?;
+                    }
+                }
+
+                writer.write_all(out_data)?;
+
+                in_offset += n;
+                written += out_data.len();
+            }
+
+            // Pad the level to the block size.
+            let padding = (level_size - written) as u64;
+            io::copy(&mut io::repeat(0).take(padding), &mut writer)?;
+
+            drop(prev_reader);
+
+            let file = writer.into_inner().map_err(|e| e.into_error())?;
+            levels.push((file, level_size));
+        }
+
+        // The top level is always a single block.
+        let (top, top_size) = levels.last_mut().unwrap();
+        let mut top_data = vec![0u8; *top_size];
+        top.rewind()?;
+        top.read_exact(&mut top_data)?;
+
+        let mut context = Context::new(self.algorithm);
+        context.update(self.salt);
+        context.update(&top_data);
+        let root_digest = context.finish().as_ref().to_vec();
+
+        Ok(SpilledHashTree {
+            root_digest,
+            levels,
+        })
+    }
+
     /// Update hash tree data corresponding to the specified file ranges.
     /// Returns the new root digest.
     pub fn update(
@@ -402,8 +544,8 @@ impl<'a> HashTree<'a> {
 
         if hash_tree_data != actual_hash_tree_data {
             // These are multiple megabytes, so only report the hashes.
-            let expected = ring::digest::digest(self.algorithm, hash_tree_data);
-            let actual = ring::digest::digest(self.algorithm, &actual_hash_tree_data);
+            let expected = crate::digest::digest(self.algorithm, hash_tree_data);
+            let actual = crate::digest::digest(self.algorithm, &actual_hash_tree_data);
 
             return Err(Error::InvalidHashTree {
                 expected: hex::encode(expected),
@@ -486,6 +628,38 @@ impl HashTreeImage {
         })
     }
 
+    /// Generate hash tree data for a file and write it in the custom hash tree
+    /// image format without ever holding the full hash tree in memory. This is
+    /// suitable for images that are larger than the available RAM.
+    pub fn generate_streaming(
+        input: &(dyn ReadSeekReopen + Sync),
+        block_size: u32,
+        algorithm: &str,
+        salt: &[u8],
+        mut writer: impl Write,
+        cancel_signal: &AtomicBool,
+    ) -> Result<()> {
+        let image_size = {
+            let mut file = input.reopen_boxed()?;
+            file.seek(SeekFrom::End(0))?
+        };
+        let ring_algorithm = Self::ring_algorithm(algorithm)?;
+        let hash_tree = HashTree::new(block_size, ring_algorithm, salt);
+        let spilled = hash_tree.generate_streaming(input, image_size, cancel_signal)?;
+
+        let header = Self {
+            image_size,
+            block_size,
+            algorithm: algorithm.to_owned(),
+            salt: salt.to_vec(),
+            root_digest: spilled.root_digest.clone(),
+            hash_tree: vec![],
+        };
+        header.write_header(&mut writer, spilled.size())?;
+
+        spilled.write_to(writer, cancel_signal)
+    }
+
     /// Update hash tree data coreesponding to the specified file ranges.
     pub fn update(
         &mut self,
@@ -571,10 +745,9 @@ impl<R: Read> FromReader<R> for HashTreeImage {
     }
 }
 
-impl<W: Write> ToWriter<W> for HashTreeImage {
-    type Error = Error;
-
-    fn to_writer(&self, mut writer: W) -> Result<()> {
+impl HashTreeImage {
+    /// Write everything except for the hash tree data itself.
+    fn write_header(&self, mut writer: impl Write, hash_tree_size: usize) -> Result<()> {
         let salt_size = self
             .salt
             .len()
@@ -585,11 +758,9 @@ impl<W: Write> ToWriter<W> for HashTreeImage {
             .len()
             .to_u16()
             .ok_or_else(|| Error::FieldOutOfBounds("root_digest_size"))?;
-        let hash_tree_size = self
-            .hash_tree
-            .len()
+        let hash_tree_size = hash_tree_size
             .to_u32()
-            .ok_or_else(|| Error::FieldOutOfBounds("hash_tree_size"))?;
+            .ok_or(Error::FieldOutOfBounds("hash_tree_size"))?;
 
         writer.write_all(Self::MAGIC)?;
         writer.write_u16::<LittleEndian>(Self::VERSION)?;
@@ -601,6 +772,16 @@ impl<W: Write> ToWriter<W> for HashTreeImage {
         writer.write_u32::<LittleEndian>(hash_tree_size)?;
         writer.write_all(&self.salt)?;
         writer.write_all(&self.root_digest)?;
+
+        Ok(())
+    }
+}
+
+impl<W: Write> ToWriter<W> for HashTreeImage {
+    type Error = Error;
+
+    fn to_writer(&self, mut writer: W) -> Result<()> {
+        self.write_header(&mut writer, self.hash_tree.len())?;
         writer.write_all(&self.hash_tree)?;
 
         Ok(())
@@ -619,7 +800,7 @@ mod tests {
 
     #[test]
     fn calculate_level_ranges() {
-        let hash_tree = HashTree::new(4096, &ring::digest::SHA256, &[]);
+        let hash_tree = HashTree::new(4096, &crate::digest::SHA256, &[]);
         assert_eq!(
             hash_tree.compute_level_offsets(0).unwrap(),
             &[] as &[Range<usize>],
@@ -632,7 +813,7 @@ mod tests {
 
     #[test]
     fn blocks_for_ranges() {
-        let hash_tree = HashTree::new(4096, &ring::digest::SHA256, b"Salt");
+        let hash_tree = HashTree::new(4096, &crate::digest::SHA256, b"Salt");
         assert_eq!(
             hash_tree.blocks_for_ranges(16384, &[0..16384]).unwrap(),
             &[0..4],
@@ -653,7 +834,7 @@ mod tests {
     #[test]
     fn generate_update_verify() {
         let cancel_signal = AtomicBool::new(false);
-        let hash_tree = HashTree::new(64, &ring::digest::SHA256, b"Salt");
+        let hash_tree = HashTree::new(64, &crate::digest::SHA256, b"Salt");
         let mut input = SharedCursor::new();
 
         // Try input smaller than one block.
@@ -667,7 +848,7 @@ mod tests {
                 0xae, 0x1e, 0x00, 0xa3
             ],
         );
-        assert_eq!(hash_tree_data, &[]);
+        assert_eq!(hash_tree_data, &[] as &[u8]);
 
         // Try larger input that spans multiple blocks are results in an actual
         // hash tree being created.
@@ -739,4 +920,33 @@ mod tests {
             .verify(&input, 100, &root_digest, &hash_tree_data, &cancel_signal)
             .unwrap_err();
     }
+
+    #[test]
+    fn generate_streaming() {
+        let cancel_signal = AtomicBool::new(false);
+        let hash_tree = HashTree::new(64, &crate::digest::SHA256, b"Salt");
+        let mut input = SharedCursor::new();
+
+        for size in [0, 52, 100, 4096, 100_000] {
+            input.rewind().unwrap();
+            input.write_all(&b"Data".repeat(size / 4)).unwrap();
+
+            let (root_digest, hash_tree_data) = hash_tree
+                .generate(&input, size as u64, &cancel_signal)
+                .unwrap();
+
+            // Use a tiny window so that every level takes multiple passes.
+            let spilled = hash_tree
+                .generate_spilled(&input, size as u64, 128, &cancel_signal)
+                .unwrap();
+            assert_eq!(spilled.root_digest, root_digest);
+            assert_eq!(spilled.size(), hash_tree_data.len());
+
+            let mut streamed_data = vec![];
+            spilled
+                .write_to(&mut streamed_data, &cancel_signal)
+                .unwrap();
+            assert_eq!(streamed_data, hash_tree_data);
+        }
+    }
 }
@@ -7,9 +7,13 @@ pub mod avb;
 pub mod bootimage;
 pub mod compression;
 pub mod cpio;
+pub mod erofs;
+pub mod ext4;
 pub mod fec;
+pub mod filesystem;
 pub mod hashtree;
 pub mod ota;
 pub mod padding;
 pub mod payload;
+pub mod sparse;
 pub mod verityrs;
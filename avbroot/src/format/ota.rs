@@ -4,18 +4,19 @@
  */
 
 use std::{
-    collections::BTreeMap,
-    io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    io::{self, BufReader, Cursor, Read, Seek, SeekFrom, Write},
     iter,
     sync::atomic::AtomicBool,
 };
 
-use cms::signed_data::SignedData;
+use byteorder::{LittleEndian, ReadBytesExt};
+use cms::signed_data::{SignedData, SignerIdentifier};
 use const_oid::{db::rfc5912, ObjectIdentifier};
 use memchr::memmem;
 use prost::Message;
-use ring::digest::Context;
-use rsa::{Pkcs1v15Sign, RsaPrivateKey};
+use rsa::Pkcs1v15Sign;
+use serde::{Deserialize, Serialize};
 use sha1::Sha1;
 use sha2::Sha256;
 use thiserror::Error;
@@ -23,10 +24,14 @@ use x509_cert::{der::Encode, Certificate};
 use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 use crate::{
-    crypto,
+    crypto::{self, RsaSigningKey},
+    digest::{Context, Digest},
     format::payload::{self, PayloadHeader},
     protobuf::build::tools::releasetools::{ota_metadata::OtaType, OtaMetadata},
-    stream::{self, FromReader, HashingReader, HashingWriter},
+    stream::{
+        self, CountingWriter, FromReader, HashingReader, HashingWriter, SectionReader,
+        ThreadedHashingWriter,
+    },
 };
 
 pub const PATH_METADATA: &str = "META-INF/com/android/metadata";
@@ -34,6 +39,8 @@ pub const PATH_METADATA_PB: &str = "META-INF/com/android/metadata.pb";
 pub const PATH_OTACERT: &str = "META-INF/com/android/otacert";
 pub const PATH_PAYLOAD: &str = "payload.bin";
 pub const PATH_PROPERTIES: &str = "payload_properties.txt";
+pub const PATH_PROVENANCE: &str = "avbroot.json";
+pub const PATH_ORIGINAL_MANIFEST: &str = "avbroot.original.json";
 
 const NAME_PAYLOAD_METADATA: &str = "payload_metadata.bin";
 
@@ -41,9 +48,31 @@ pub const PF_NAME: &str = "ota-property-files";
 pub const PF_STREAMING_NAME: &str = "ota-streaming-property-files";
 
 pub const ZIP_EOCD_MAGIC: &[u8; 4] = b"PK\x05\x06";
+const ZIP64_EOCD_MAGIC: &[u8; 4] = b"PK\x06\x06";
+const ZIP64_EOCD_LOCATOR_MAGIC: &[u8; 4] = b"PK\x06\x07";
+const ZIP_CDFH_MAGIC: &[u8; 4] = b"PK\x01\x02";
+const ZIP_LFH_MAGIC: &[u8; 4] = b"PK\x03\x04";
 
 const COMMENT_MESSAGE: &[u8] = b"signed by avbroot\0";
 
+const APK_SIG_BLOCK_MAGIC: &[u8; 16] = b"APK Sig Block 42";
+
+/// Signing block pair IDs that only make sense for the zip that they were
+/// created for. These are APK signatures and source stamps, which become
+/// invalid once any entry changes, and alignment padding.
+pub const APK_SIG_BLOCK_TRANSIENT_IDS: &[(u32, &str)] = &[
+    (0x7109871a, "APK signature scheme v2"),
+    (0xf05368c0, "APK signature scheme v3"),
+    (0x1b93ad61, "APK signature scheme v3.1"),
+    (0x2b09189e, "Source stamp v1"),
+    (0x6dff800d, "Source stamp v2"),
+    (0x42726577, "Verity padding"),
+];
+
+/// Largest central directory that [`SigningWriter`] can insert a signing block
+/// in front of.
+const MAX_SIGNING_BLOCK_CD_SIZE: usize = 1024 * 1024;
+
 const LEGACY_SEP: &str = "|";
 
 #[derive(Debug, Error)]
@@ -58,10 +87,18 @@ pub enum Error {
     ZipTooSmall,
     #[error("Signature offset exceeds archive comment size")]
     SignatureOffsetTooLarge,
+    #[error("Archive comment is too large: {0} bytes")]
+    CommentTooLarge(usize),
+    #[error("Invalid APK signing block: {0}")]
+    InvalidSigningBlock(&'static str),
     #[error("Expected exactly one CMS embedded certificate, but found {0}")]
     NotOneCmsCertificate(usize),
     #[error("Expected exactly one CMS SignerInfo, but found {0}")]
     NotOneCmsSignerInfo(usize),
+    #[error("No CMS SignerInfo found")]
+    NoCmsSignerInfo,
+    #[error("CMS embedded certificate not found for SignerInfo #{0}")]
+    MissingCmsCertificate(usize),
     #[error("Unsupported digest algorithm: {0}")]
     UnsupportedDigestAlgorithm(ObjectIdentifier),
     #[error("Unsupported signature algorithm: {0}")]
@@ -78,10 +115,26 @@ pub enum Error {
     InvalidPropertyFileEntry(String),
     #[error("Missing entry in OTA zip: {0}")]
     MissingZipEntry(&'static str),
+    #[error("Invalid zip central directory: {0}")]
+    InvalidCentralDirectory(&'static str),
+    #[error("Invalid local header for zip entry: {0}")]
+    InvalidLocalHeader(&'static str),
+    #[error("Zip entry is not stored uncompressed: {0}")]
+    EntryNotStored(&'static str),
+    #[error("Patched OTA data at offset {0} does not match stock OTA")]
+    StripDataMismatch(u64),
+    #[error("Expected stock OTA size {expected}, but have {actual}")]
+    StripSourceSizeMismatch { expected: u64, actual: u64 },
+    #[error("Strip manifest hole #{0} is out of bounds or overlaps another hole")]
+    InvalidStripHole(usize),
+    #[error("Expected sha256 {expected}, but have {actual}")]
+    StripHashMismatch { expected: String, actual: String },
     #[error("CMS signing error")]
     CmsSign(#[from] crypto::Error),
     #[error("Payload error")]
     Payload(#[from] payload::Error),
+    #[error("Failed to (de)serialize JSON data")]
+    Json(#[from] serde_json::Error),
     #[error("Failed to decode protobuf message")]
     ProtobufDecode(#[from] prost::DecodeError),
     #[error("SPKI error")]
@@ -262,6 +315,107 @@ fn serialize_metadata(metadata: &OtaMetadata) -> Result<(String, Vec<u8>)> {
     Ok((legacy_metadata, modern_metadata))
 }
 
+/// Record of how avbroot produced a patched OTA. This is stored in the output
+/// zip, where it is covered by the whole-file signature, so that a patched OTA
+/// is self-describing.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Provenance {
+    /// Version of avbroot that patched the OTA.
+    pub avbroot_version: String,
+    /// sha256 digest of the input OTA zip.
+    pub input_sha256: String,
+    /// Patch options that were used.
+    pub options: BTreeMap<String, String>,
+    /// Boot image patchers that were applied.
+    pub patchers: Vec<String>,
+    /// sha256 digests of the files referenced by the patch options.
+    #[serde(default)]
+    pub input_digests: BTreeMap<String, String>,
+    /// sha256 digests of the signing keys' public keys.
+    #[serde(default)]
+    pub key_fingerprints: BTreeMap<String, String>,
+}
+
+pub fn parse_provenance(data: &[u8]) -> Result<Provenance> {
+    Ok(serde_json::from_slice(data)?)
+}
+
+pub fn serialize_provenance(provenance: &Provenance) -> Result<String> {
+    let mut data = serde_json::to_string_pretty(provenance)?;
+    data.push('\n');
+
+    Ok(data)
+}
+
+/// Size and digest of a partition image in the original OTA.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OriginalPartition {
+    pub size: u64,
+    pub sha256: String,
+}
+
+/// Description of the unpatched OTA that a patched OTA was produced from. This
+/// identifies the stock build that needs to be flashed to roll back.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct OriginalManifest {
+    /// Device codenames from the OTA metadata postcondition.
+    pub device: Vec<String>,
+    /// Build fingerprints from the OTA metadata postcondition.
+    pub build: Vec<String>,
+    pub build_incremental: String,
+    pub security_patch_level: String,
+    pub sdk_level: String,
+    pub timestamp: i64,
+    /// Partition images in the original payload.
+    pub partitions: BTreeMap<String, OriginalPartition>,
+}
+
+impl OriginalManifest {
+    pub fn new(metadata: &OtaMetadata, header: &PayloadHeader) -> Self {
+        let mut result = Self::default();
+
+        if let Some(state) = &metadata.postcondition {
+            result.device.clone_from(&state.device);
+            result.build.clone_from(&state.build);
+            result
+                .build_incremental
+                .clone_from(&state.build_incremental);
+            result
+                .security_patch_level
+                .clone_from(&state.security_patch_level);
+            result.sdk_level.clone_from(&state.sdk_level);
+            result.timestamp = state.timestamp;
+        }
+
+        for p in &header.manifest.partitions {
+            let Some(info) = &p.new_partition_info else {
+                continue;
+            };
+
+            result.partitions.insert(
+                p.partition_name.clone(),
+                OriginalPartition {
+                    size: info.size.unwrap_or_default(),
+                    sha256: info.hash.as_deref().map(hex::encode).unwrap_or_default(),
+                },
+            );
+        }
+
+        result
+    }
+}
+
+pub fn parse_original_manifest(data: &[u8]) -> Result<OriginalManifest> {
+    Ok(serde_json::from_slice(data)?)
+}
+
+pub fn serialize_original_manifest(manifest: &OriginalManifest) -> Result<String> {
+    let mut data = serde_json::to_string_pretty(manifest)?;
+    data.push('\n');
+
+    Ok(data)
+}
+
 #[derive(Clone, Debug)]
 pub struct ZipEntry {
     pub name: String,
@@ -338,8 +492,15 @@ fn compute_property_files(
     }
 
     if max_length.is_none() {
-        tokens.push(format!("metadata:{}", " ".repeat(15)));
-        tokens.push(format!("metadata.pb:{}", " ".repeat(15)));
+        // AOSP reserves 15 bytes for each offset and size pair, which is not
+        // enough once the metadata entries are beyond ~10 GB. The metadata
+        // entries follow every other entry, so reserve enough space for an
+        // offset slightly past the end of the last one.
+        let end = entries.iter().map(|e| e.offset + e.size).max().unwrap_or(0);
+        let reserved = 15.max(end.saturating_add(65536).to_string().len() + 1 + 4);
+
+        tokens.push(format!("metadata:{}", " ".repeat(reserved)));
+        tokens.push(format!("metadata.pb:{}", " ".repeat(reserved)));
     } else {
         tokens.push(compute(PATH_METADATA)?);
         tokens.push(compute(PATH_METADATA_PB)?);
@@ -462,17 +623,31 @@ pub fn add_metadata(
     Ok(metadata)
 }
 
-/// Verify that the zip entry offsets and sizes match the OTA metadata.
+/// Verify that the zip entry offsets and sizes match the OTA metadata. The
+/// zip64 EOCD records are validated if the EOCD refers to them and every entry's
+/// data must end before the central directory, which catches sizes and offsets
+/// that were truncated to 32 bits.
 pub fn verify_metadata(
-    reader: impl Read + Seek,
+    mut reader: impl Read + Seek,
     metadata: &OtaMetadata,
     payload_metadata_size: u64,
 ) -> Result<()> {
+    let (cd_start, _, _) = find_central_directory(&mut reader)?;
     let mut zip_reader = ZipArchive::new(reader)?;
     let mut zip_entries = vec![];
 
     for i in 0..zip_reader.len() {
         let entry = zip_reader.by_index(i)?;
+        if entry
+            .data_start()
+            .checked_add(entry.compressed_size())
+            .is_none_or(|end| end > cd_start)
+        {
+            return Err(Error::InvalidCentralDirectory(
+                "Entry data overlaps central directory",
+            ));
+        }
+
         zip_entries.push(ZipEntry {
             name: entry.name().to_owned(),
             offset: entry.data_start(),
@@ -495,6 +670,412 @@ pub fn verify_metadata(
     Ok(())
 }
 
+/// Find the non-zip64 EOCD. Returns its offset and its data, including the
+/// archive comment.
+fn find_eocd(mut reader: impl Read + Seek) -> Result<(u64, Vec<u8>)> {
+    let file_size = reader.seek(SeekFrom::End(0))?;
+
+    // The EOCD is 22 bytes, followed by a comment of up to 65535 bytes.
+    let tail_size = file_size.min(22 + 65535);
+    if tail_size < 22 {
+        return Err(Error::ZipTooSmall);
+    }
+
+    let tail_offset = file_size - tail_size;
+    reader.seek(SeekFrom::Start(tail_offset))?;
+    let mut tail = vec![0u8; tail_size as usize];
+    reader.read_exact(&mut tail)?;
+
+    // Search backwards since the magic may also appear in earlier data. The
+    // comment length must exactly account for the rest of the file.
+    let eocd_index = memmem::rfind_iter(&tail, ZIP_EOCD_MAGIC)
+        .find(|&i| {
+            tail.len() - i >= 22 && {
+                let comment_size = u16::from_le_bytes([tail[i + 20], tail[i + 21]]);
+                i + 22 + usize::from(comment_size) == tail.len()
+            }
+        })
+        .ok_or(Error::EocdMagicNotFound)?;
+    tail.drain(..eocd_index);
+
+    Ok((tail_offset + eocd_index as u64, tail))
+}
+
+/// Find the central directory's offset and size from the (zip64) EOCD. The
+/// returned offset is where the central directory actually begins in the file,
+/// which may differ from the recorded offset if data was prepended to the zip.
+/// The difference between the two is returned as the third value.
+fn find_central_directory(mut reader: impl Read + Seek) -> Result<(u64, u64, u64)> {
+    let (eocd_offset, eocd) = find_eocd(&mut reader)?;
+
+    let num_entries = u16::from_le_bytes(eocd[10..12].try_into().unwrap());
+    let mut cd_size = u64::from(u32::from_le_bytes(eocd[12..16].try_into().unwrap()));
+    let mut cd_offset = u64::from(u32::from_le_bytes(eocd[16..20].try_into().unwrap()));
+    let mut cd_end = eocd_offset;
+
+    if num_entries == 0xffff || cd_size == 0xffffffff || cd_offset == 0xffffffff {
+        let locator_offset = eocd_offset
+            .checked_sub(20)
+            .ok_or(Error::InvalidCentralDirectory("Missing zip64 EOCD locator"))?;
+        reader.seek(SeekFrom::Start(locator_offset))?;
+
+        let mut magic = [0u8; 4];
+        reader.read_exact(&mut magic)?;
+        if magic != *ZIP64_EOCD_LOCATOR_MAGIC {
+            return Err(Error::InvalidCentralDirectory("Missing zip64 EOCD locator"));
+        }
+
+        // Assume there's no extensible data sector, which is what every zip
+        // writer does in practice. This avoids relying on the recorded offset,
+        // which is wrong if data was prepended.
+        let zip64_eocd_offset = locator_offset
+            .checked_sub(56)
+            .ok_or(Error::InvalidCentralDirectory("Missing zip64 EOCD"))?;
+        reader.seek(SeekFrom::Start(zip64_eocd_offset))?;
+
+        reader.read_exact(&mut magic)?;
+        if magic != *ZIP64_EOCD_MAGIC {
+            return Err(Error::InvalidCentralDirectory("Missing zip64 EOCD"));
+        }
+
+        reader.seek(SeekFrom::Current(36))?;
+        cd_size = reader.read_u64::<LittleEndian>()?;
+        cd_offset = reader.read_u64::<LittleEndian>()?;
+        cd_end = zip64_eocd_offset;
+    }
+
+    let cd_start = cd_end
+        .checked_sub(cd_size)
+        .ok_or(Error::InvalidCentralDirectory("Size exceeds file size"))?;
+    let shift = cd_start
+        .checked_sub(cd_offset)
+        .ok_or(Error::InvalidCentralDirectory(
+            "Offset exceeds actual position",
+        ))?;
+
+    Ok((cd_start, cd_size, shift))
+}
+
+/// Find the data of a stored (uncompressed) zip entry by parsing the central
+/// directory directly instead of going through the zip library. This is meant
+/// as a fallback for OTAs produced by tools that emit streaming entries or other
+/// unusual layouts. The sizes are always taken from the central directory, so
+/// data descriptors are irrelevant, and the local header is only used to find
+/// where the data begins. Data prepended to the zip is accounted for.
+pub fn find_stored_zip_entry(mut reader: impl Read + Seek, name: &'static str) -> Result<ZipEntry> {
+    let (cd_start, cd_size, shift) = find_central_directory(&mut reader)?;
+
+    reader.seek(SeekFrom::Start(cd_start))?;
+    let cd_size_usize =
+        usize::try_from(cd_size).map_err(|_| Error::InvalidCentralDirectory("Size too large"))?;
+    let mut cd = vec![0u8; cd_size_usize];
+    reader.read_exact(&mut cd)?;
+
+    let mut cursor = Cursor::new(cd.as_slice());
+
+    while cursor.position() < cd_size {
+        let mut magic = [0u8; 4];
+        cursor.read_exact(&mut magic)?;
+        if magic != *ZIP_CDFH_MAGIC {
+            return Err(Error::InvalidCentralDirectory("Invalid file header magic"));
+        }
+
+        // Version made by, version needed, flags.
+        cursor.seek(SeekFrom::Current(6))?;
+        let method = cursor.read_u16::<LittleEndian>()?;
+        // Modification time, modification date, CRC32.
+        cursor.seek(SeekFrom::Current(8))?;
+        let mut compressed_size = u64::from(cursor.read_u32::<LittleEndian>()?);
+        let mut size = u64::from(cursor.read_u32::<LittleEndian>()?);
+        let name_size = cursor.read_u16::<LittleEndian>()?;
+        let extra_size = cursor.read_u16::<LittleEndian>()?;
+        let comment_size = cursor.read_u16::<LittleEndian>()?;
+        // Disk number, internal attributes, external attributes.
+        cursor.seek(SeekFrom::Current(8))?;
+        let mut header_offset = u64::from(cursor.read_u32::<LittleEndian>()?);
+
+        let mut entry_name = vec![0u8; name_size.into()];
+        cursor.read_exact(&mut entry_name)?;
+        let mut extra = vec![0u8; extra_size.into()];
+        cursor.read_exact(&mut extra)?;
+        cursor.seek(SeekFrom::Current(comment_size.into()))?;
+
+        if entry_name != name.as_bytes() {
+            continue;
+        }
+
+        // The zip64 extended information field only contains the values that
+        // did not fit in the regular fields, in this order.
+        let mut extra_cursor = Cursor::new(extra.as_slice());
+        while extra_cursor.position() + 4 <= u64::from(extra_size) {
+            let id = extra_cursor.read_u16::<LittleEndian>()?;
+            let len = extra_cursor.read_u16::<LittleEndian>()?;
+
+            if id == 0x0001 {
+                for value in [&mut size, &mut compressed_size, &mut header_offset] {
+                    if *value == 0xffffffff {
+                        *value = extra_cursor.read_u64::<LittleEndian>()?;
+                    }
+                }
+                break;
+            }
+
+            extra_cursor.seek(SeekFrom::Current(len.into()))?;
+        }
+
+        if method != 0 || compressed_size != size {
+            return Err(Error::EntryNotStored(name));
+        }
+
+        let header_offset = header_offset
+            .checked_add(shift)
+            .ok_or(Error::InvalidLocalHeader(name))?;
+        reader.seek(SeekFrom::Start(header_offset))?;
+
+        let mut header = [0u8; 30];
+        reader.read_exact(&mut header)?;
+        if header[..4] != *ZIP_LFH_MAGIC {
+            return Err(Error::InvalidLocalHeader(name));
+        }
+
+        // The local name and extra field may differ from the central directory.
+        let local_name_size = u16::from_le_bytes([header[26], header[27]]);
+        let local_extra_size = u16::from_le_bytes([header[28], header[29]]);
+        let offset = header_offset + 30 + u64::from(local_name_size) + u64::from(local_extra_size);
+
+        if offset
+            .checked_add(size)
+            .filter(|&end| end <= cd_start)
+            .is_none()
+        {
+            return Err(Error::InvalidLocalHeader(name));
+        }
+
+        return Ok(ZipEntry {
+            name: name.to_owned(),
+            offset,
+            size,
+        });
+    }
+
+    Err(Error::MissingZipEntry(name))
+}
+
+/// Regions of a zip file that are outside of the entries and the central
+/// directory.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ZipExtras {
+    /// The archive comment, excluding the whole-file signature if there is one.
+    pub comment: Vec<u8>,
+    /// Whether the archive comment ends in a whole-file signature.
+    pub signed: bool,
+    /// ID-value pairs from the APK signing block in front of the central
+    /// directory, if there is one.
+    pub signing_block: Option<Vec<(u32, Vec<u8>)>>,
+}
+
+/// Read the archive comment and APK signing block from a zip.
+pub fn read_zip_extras(mut reader: impl Read + Seek) -> Result<ZipExtras> {
+    let (_, eocd) = find_eocd(&mut reader)?;
+    let mut comment = eocd[22..].to_vec();
+    let mut signed = false;
+
+    // Same footer as parsed by parse_ota_sig(), but the signature itself is
+    // not checked here.
+    if let Some(footer) = comment.len().checked_sub(6).map(|i| &comment[i..]) {
+        let abs_eoc_offset = usize::from(u16::from_le_bytes([footer[0], footer[1]]));
+
+        if footer[2..4] == *b"\xff\xff" && abs_eoc_offset <= comment.len() {
+            comment.truncate(comment.len() - abs_eoc_offset);
+            signed = true;
+        }
+    }
+
+    let (cd_start, _, _) = find_central_directory(&mut reader)?;
+    let signing_block = match cd_start.checked_sub(24) {
+        Some(footer_offset) => {
+            reader.seek(SeekFrom::Start(footer_offset))?;
+            let mut footer = [0u8; 24];
+            reader.read_exact(&mut footer)?;
+
+            if footer[8..] == *APK_SIG_BLOCK_MAGIC {
+                let size = u64::from_le_bytes(footer[..8].try_into().unwrap());
+                let block_offset = (cd_start - 8)
+                    .checked_sub(size)
+                    .ok_or(Error::InvalidSigningBlock("Size exceeds file size"))?;
+                let block_size = usize::try_from(size + 8)
+                    .map_err(|_| Error::InvalidSigningBlock("Size too large"))?;
+
+                reader.seek(SeekFrom::Start(block_offset))?;
+                let mut block = vec![0u8; block_size];
+                reader.read_exact(&mut block)?;
+
+                Some(parse_signing_block(&block)?)
+            } else {
+                None
+            }
+        }
+        None => None,
+    };
+
+    Ok(ZipExtras {
+        comment,
+        signed,
+        signing_block,
+    })
+}
+
+/// Parse the ID-value pairs of a raw APK signing block.
+pub fn parse_signing_block(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>> {
+    if data.len() < 32 || data[data.len() - 16..] != *APK_SIG_BLOCK_MAGIC {
+        return Err(Error::InvalidSigningBlock("Missing magic"));
+    }
+
+    let leading_size = u64::from_le_bytes(data[..8].try_into().unwrap());
+    let trailing_size = u64::from_le_bytes(data[data.len() - 24..][..8].try_into().unwrap());
+    if leading_size != trailing_size || leading_size != data.len() as u64 - 8 {
+        return Err(Error::InvalidSigningBlock("Mismatched sizes"));
+    }
+
+    let mut cursor = Cursor::new(&data[8..data.len() - 24]);
+    let mut pairs = vec![];
+
+    while (cursor.position() as usize) < cursor.get_ref().len() {
+        let pair_size = cursor.read_u64::<LittleEndian>()?;
+        let remain = cursor.get_ref().len() as u64 - cursor.position();
+        if pair_size < 4 || pair_size > remain {
+            return Err(Error::InvalidSigningBlock("Pair size out of bounds"));
+        }
+
+        let id = cursor.read_u32::<LittleEndian>()?;
+        let mut value = vec![0u8; pair_size as usize - 4];
+        cursor.read_exact(&mut value)?;
+
+        pairs.push((id, value));
+    }
+
+    Ok(pairs)
+}
+
+/// Build a raw APK signing block from ID-value pairs.
+pub fn build_signing_block(pairs: &[(u32, Vec<u8>)]) -> Vec<u8> {
+    let pairs_size = pairs.iter().map(|(_, v)| 12 + v.len()).sum::<usize>();
+    // Everything except the leading size field.
+    let size = (pairs_size + 24) as u64;
+
+    let mut data = Vec::with_capacity(pairs_size + 32);
+    data.extend(size.to_le_bytes());
+    for (id, value) in pairs {
+        data.extend((value.len() as u64 + 4).to_le_bytes());
+        data.extend(id.to_le_bytes());
+        data.extend(value);
+    }
+    data.extend(size.to_le_bytes());
+    data.extend(APK_SIG_BLOCK_MAGIC);
+
+    data
+}
+
+/// Insert `block` in front of the central directory in `tail`, which must
+/// contain the entire central directory and end with an EOCD with no archive
+/// comment. The central directory offsets are updated accordingly.
+fn insert_signing_block(tail: &mut Vec<u8>, block: &[u8]) -> Result<()> {
+    let eocd = tail.len() - 22;
+    let entries = u16::from_le_bytes(tail[eocd + 10..eocd + 12].try_into().unwrap());
+    let cd_size = u32::from_le_bytes(tail[eocd + 12..eocd + 16].try_into().unwrap());
+    let cd_offset = u32::from_le_bytes(tail[eocd + 16..eocd + 20].try_into().unwrap());
+    let too_large = || Error::InvalidCentralDirectory("Too large to insert signing block");
+
+    if cd_offset != 0xffffffff {
+        let new_offset = u32::try_from(block.len())
+            .ok()
+            .and_then(|n| cd_offset.checked_add(n))
+            .filter(|n| *n != 0xffffffff)
+            .ok_or(Error::InvalidCentralDirectory("Offset would require zip64"))?;
+        tail[eocd + 16..eocd + 20].copy_from_slice(&new_offset.to_le_bytes());
+    }
+
+    let (cd_end, cd_size) = if entries == 0xffff || cd_size == 0xffffffff || cd_offset == 0xffffffff
+    {
+        let locator = eocd.checked_sub(20).ok_or_else(too_large)?;
+        if tail[locator..locator + 4] != *ZIP64_EOCD_LOCATOR_MAGIC {
+            return Err(Error::InvalidCentralDirectory("Missing zip64 EOCD locator"));
+        }
+
+        let zip64_eocd = locator
+            .checked_sub(56)
+            .ok_or(Error::InvalidCentralDirectory("Missing zip64 EOCD"))?;
+        if tail[zip64_eocd..zip64_eocd + 4] != *ZIP64_EOCD_MAGIC {
+            return Err(Error::InvalidCentralDirectory("Missing zip64 EOCD"));
+        }
+
+        let mut add = |offset: usize| {
+            let field = &mut tail[offset..offset + 8];
+            let value = u64::from_le_bytes((*field).try_into().unwrap()) + block.len() as u64;
+            field.copy_from_slice(&value.to_le_bytes());
+        };
+        add(zip64_eocd + 48);
+        add(locator + 8);
+
+        let cd_size =
+            u64::from_le_bytes(tail[zip64_eocd + 40..zip64_eocd + 48].try_into().unwrap());
+
+        (zip64_eocd, cd_size)
+    } else {
+        (eocd, u64::from(cd_size))
+    };
+
+    let cd_start = usize::try_from(cd_size)
+        .ok()
+        .and_then(|s| cd_end.checked_sub(s))
+        .ok_or_else(too_large)?;
+    tail.splice(cd_start..cd_start, block.iter().copied());
+
+    Ok(())
+}
+
+/// Check that the zip64 EOCD locator and zip64 EOCD are present if the EOCD at
+/// the end of `tail` refers to them and that their offsets match where the
+/// records actually are. `tail_offset` is the offset of `tail` in the file.
+fn check_zip64_eocd(tail: &[u8], tail_offset: u64) -> Result<()> {
+    let eocd = tail.len() - 22;
+    let entries = u16::from_le_bytes(tail[eocd + 10..eocd + 12].try_into().unwrap());
+    let cd_size = u32::from_le_bytes(tail[eocd + 12..eocd + 16].try_into().unwrap());
+    let cd_offset = u32::from_le_bytes(tail[eocd + 16..eocd + 20].try_into().unwrap());
+
+    if entries != 0xffff && cd_size != 0xffffffff && cd_offset != 0xffffffff {
+        return Ok(());
+    }
+
+    let locator = eocd
+        .checked_sub(20)
+        .filter(|&l| tail[l..l + 4] == *ZIP64_EOCD_LOCATOR_MAGIC)
+        .ok_or(Error::InvalidCentralDirectory("Missing zip64 EOCD locator"))?;
+    let zip64_eocd = locator
+        .checked_sub(56)
+        .filter(|&e| tail[e..e + 4] == *ZIP64_EOCD_MAGIC)
+        .ok_or(Error::InvalidCentralDirectory("Missing zip64 EOCD"))?;
+
+    let recorded_offset = u64::from_le_bytes(tail[locator + 8..locator + 16].try_into().unwrap());
+    if recorded_offset != tail_offset + zip64_eocd as u64 {
+        return Err(Error::InvalidCentralDirectory(
+            "Zip64 EOCD locator does not point to zip64 EOCD",
+        ));
+    }
+
+    let zip64_cd_size =
+        u64::from_le_bytes(tail[zip64_eocd + 40..zip64_eocd + 48].try_into().unwrap());
+    let zip64_cd_offset =
+        u64::from_le_bytes(tail[zip64_eocd + 48..zip64_eocd + 56].try_into().unwrap());
+    if zip64_cd_offset.checked_add(zip64_cd_size) != Some(recorded_offset) {
+        return Err(Error::InvalidCentralDirectory(
+            "Zip64 central directory does not end at zip64 EOCD",
+        ));
+    }
+
+    Ok(())
+}
+
 /// Parse the CMS signature from the OTA zip comment. Returns the decoded CMS
 /// [`SignedData`] structure and the length of the file (from the beginning)
 /// that's covered by the signature. This does not perform any parsing of zip
@@ -545,67 +1126,119 @@ fn parse_ota_sig(mut reader: impl Read + Seek) -> Result<(SignedData, u64)> {
 }
 
 /// Verify an OTA zip against its embedded certificates. This function makes no
-/// assertion about whether the certificate is actually trusted. Returns the
-/// embedded certificate.
+/// assertion about whether the certificates are actually trusted. Unless
+/// `allow_multiple` is true, the OTA must have exactly one SignerInfo and one
+/// embedded certificate. Every SignerInfo must have a valid signature. Returns
+/// the embedded certificate for each SignerInfo, sorted by the SignerInfos' DER
+/// encodings. The first certificate is the only one that AOSP checks.
 ///
 /// CMS signed attributes are intentionally not supported because AOSP recovery
 /// does not support them either. It expects the CMS [`SignedData`] structure to
 /// be used for nothing more than a raw signature transport mechanism.
-pub fn verify_ota(mut reader: impl Read + Seek, cancel_signal: &AtomicBool) -> Result<Certificate> {
+pub fn verify_ota(
+    mut reader: impl Read + Seek,
+    allow_multiple: bool,
+    cancel_signal: &AtomicBool,
+) -> Result<Vec<Certificate>> {
     let (sd, hashed_size) = parse_ota_sig(&mut reader)?;
-
-    // Make sure the certificate in the CMS structure matches the otacert zip
-    // entry.
     let certs = crypto::get_cms_certs(&sd);
-    if certs.len() != 1 {
-        return Err(Error::NotOneCmsCertificate(certs.len()));
+
+    if sd.signer_infos.0.is_empty() {
+        return Err(Error::NoCmsSignerInfo);
+    } else if !allow_multiple {
+        if sd.signer_infos.0.len() != 1 {
+            return Err(Error::NotOneCmsSignerInfo(sd.signer_infos.0.len()));
+        } else if certs.len() != 1 {
+            return Err(Error::NotOneCmsCertificate(certs.len()));
+        }
     }
 
-    let cert = &certs[0];
-    let public_key = crypto::get_public_key(cert)?;
+    // The SignerInfos are a SET OF, so DER requires them to be sorted by their
+    // encodings. Don't rely on the input being valid DER.
+    let mut signers = sd
+        .signer_infos
+        .0
+        .iter()
+        .map(|s| Ok((s.to_der()?, s)))
+        .collect::<Result<Vec<_>>>()?;
+    signers.sort_by(|a, b| a.0.cmp(&b.0));
+    let signers = signers.into_iter().map(|(_, s)| s).collect::<Vec<_>>();
 
     // Make sure this is a signature scheme we can handle. There's currently no
     // Rust library to verify arbitrary CMS signatures for large files without
     // fully reading them into memory.
-    if sd.signer_infos.0.len() != 1 {
-        return Err(Error::NotOneCmsSignerInfo(sd.signer_infos.0.len()));
+    for signer in &signers {
+        if signer.digest_alg.oid != rfc5912::ID_SHA_256
+            && signer.digest_alg.oid != rfc5912::ID_SHA_1
+        {
+            return Err(Error::UnsupportedDigestAlgorithm(signer.digest_alg.oid));
+        } else if signer.signature_algorithm.oid != rfc5912::RSA_ENCRYPTION
+            && signer.signature_algorithm.oid != rfc5912::SHA_256_WITH_RSA_ENCRYPTION
+        {
+            return Err(Error::UnsupportedSignatureAlgorithm(
+                signer.signature_algorithm.oid,
+            ));
+        }
     }
 
-    let signer = sd.signer_infos.0.get(0).unwrap();
-    if signer.digest_alg.oid != rfc5912::ID_SHA_256 && signer.digest_alg.oid != rfc5912::ID_SHA_1 {
-        return Err(Error::UnsupportedDigestAlgorithm(signer.digest_alg.oid));
-    } else if signer.signature_algorithm.oid != rfc5912::RSA_ENCRYPTION
-        && signer.signature_algorithm.oid != rfc5912::SHA_256_WITH_RSA_ENCRYPTION
-    {
-        return Err(Error::UnsupportedSignatureAlgorithm(
-            signer.signature_algorithm.oid,
-        ));
-    }
+    // Manually hash the parts of the file covered by the signature. Each digest
+    // algorithm is only computed once, even if there are multiple signers.
+    let mut digests = Vec::<(ObjectIdentifier, Digest)>::new();
 
-    // Manually hash the parts of the file covered by the signature.
-    reader.seek(SeekFrom::Start(0))?;
+    for signer in &signers {
+        if digests.iter().any(|(oid, _)| *oid == signer.digest_alg.oid) {
+            continue;
+        }
 
-    // We support SHA1 for verification only.
-    let (algorithm, scheme) = if signer.digest_alg.oid == rfc5912::ID_SHA_256 {
-        (&ring::digest::SHA256, Pkcs1v15Sign::new::<Sha256>())
-    } else {
-        (
-            &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
-            Pkcs1v15Sign::new::<Sha1>(),
-        )
-    };
+        // We support SHA1 for verification only.
+        let algorithm = if signer.digest_alg.oid == rfc5912::ID_SHA_256 {
+            &crate::digest::SHA256
+        } else {
+            &crate::digest::SHA1_FOR_LEGACY_USE_ONLY
+        };
+
+        reader.seek(SeekFrom::Start(0))?;
+
+        let mut hashing_reader = HashingReader::new(&mut reader, Context::new(algorithm));
 
-    let mut hashing_reader = HashingReader::new(reader, Context::new(algorithm));
+        stream::copy_n(&mut hashing_reader, io::sink(), hashed_size, cancel_signal)?;
 
-    stream::copy_n(&mut hashing_reader, io::sink(), hashed_size, cancel_signal)?;
+        let (_, context) = hashing_reader.finish();
+        digests.push((signer.digest_alg.oid, context.finish()));
+    }
+
+    let mut signer_certs = vec![];
+
+    for (i, signer) in signers.iter().enumerate() {
+        let cert = certs
+            .iter()
+            .find(|c| match &signer.sid {
+                SignerIdentifier::IssuerAndSerialNumber(isn) => {
+                    c.tbs_certificate.issuer == isn.issuer
+                        && c.tbs_certificate.serial_number == isn.serial_number
+                }
+                SignerIdentifier::SubjectKeyIdentifier(_) => false,
+            })
+            .ok_or(Error::MissingCmsCertificate(i))?;
+        let public_key = crypto::get_public_key(cert)?;
+
+        let (_, digest) = digests
+            .iter()
+            .find(|(oid, _)| *oid == signer.digest_alg.oid)
+            .unwrap();
+        let scheme = if signer.digest_alg.oid == rfc5912::ID_SHA_256 {
+            Pkcs1v15Sign::new::<Sha256>()
+        } else {
+            Pkcs1v15Sign::new::<Sha1>()
+        };
 
-    let (_, context) = hashing_reader.finish();
-    let digest = context.finish();
+        // Verify the signature against the public key.
+        public_key.verify(scheme, digest.as_ref(), signer.signature.as_bytes())?;
 
-    // Verify the signature against the public key.
-    public_key.verify(scheme, digest.as_ref(), signer.signature.as_bytes())?;
+        signer_certs.push(cert.clone());
+    }
 
-    Ok(cert.clone())
+    Ok(signer_certs)
 }
 
 /// Get and parse the protobuf-encoded OTA metadata, the PEM-encoded otacert,
@@ -642,33 +1275,427 @@ pub fn parse_zip_ota_info(
     Ok((metadata, certificate, header, properties))
 }
 
+/// Replace the payload signatures and the whole-file signature of an OTA zip
+/// with ones from a new key. The payload header and partition data are copied
+/// as-is. `payload_properties.txt`, the otacert entry, and the OTA metadata are
+/// regenerated because they depend on the new signatures. Returns the writer,
+/// the new OTA metadata, and the new payload metadata size, which can be passed
+/// to [`verify_metadata()`].
+pub fn resign<W: Write>(
+    mut reader: impl Read + Seek,
+    writer: W,
+    key: &RsaSigningKey,
+    cert: &Certificate,
+    cancel_signal: &AtomicBool,
+) -> Result<(W, OtaMetadata, u64)> {
+    let payload_entry = find_stored_zip_entry(&mut reader, PATH_PAYLOAD)?;
+
+    // Everything other than the payload is small enough to keep in memory.
+    // This frees up the reader for the payload, which needs to be seekable.
+    let mut entries = BTreeMap::new();
+    {
+        let mut zip = ZipArchive::new(&mut reader)?;
+
+        for i in 0..zip.len() {
+            let mut entry = zip.by_index(i)?;
+            if entry.name() == PATH_PAYLOAD {
+                continue;
+            }
+
+            let mut buf = vec![];
+            entry.read_to_end(&mut buf)?;
+            entries.insert(entry.name().to_owned(), buf);
+        }
+    }
+
+    for path in [PATH_OTACERT, PATH_PROPERTIES] {
+        if !entries.contains_key(path) {
+            return Err(Error::MissingZipEntry(path));
+        }
+    }
+
+    // Legacy-only OTAs from Android 11 are converted like `ota patch` does.
+    let metadata = if let Some(data) = entries.remove(PATH_METADATA_PB) {
+        entries.remove(PATH_METADATA);
+        parse_protobuf_metadata(&data)?
+    } else if let Some(data) = entries.remove(PATH_METADATA) {
+        parse_legacy_metadata(&String::from_utf8_lossy(&data))?
+    } else {
+        return Err(Error::MissingZipEntry(PATH_METADATA_PB));
+    };
+
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(writer));
+    let mut zip_entries = vec![];
+    let mut properties = None;
+    let mut payload_metadata_size = 0;
+    let mut last_entry_used_zip64 = false;
+
+    // payload.bin sorts before payload_properties.txt, so the new properties
+    // are always known by the time they are written.
+    let paths = entries
+        .keys()
+        .map(|p| p.as_str())
+        .chain(iter::once(PATH_PAYLOAD))
+        .collect::<BTreeSet<_>>();
+
+    for path in paths {
+        // Android's libarchive parser only reads 64-bit data descriptor sizes
+        // if the central directory size is at least 2^32 - 1.
+        let use_zip64 = path == PATH_PAYLOAD && payload_entry.size >= 0xffffffff;
+        let options = FileOptions::default()
+            .compression_method(CompressionMethod::Stored)
+            .large_file(use_zip64);
+
+        zip_writer.start_file_with_extra_data(path, options)?;
+        let offset = zip_writer.end_extra_data()?;
+        let mut writer = CountingWriter::new(&mut zip_writer);
+
+        match path {
+            PATH_PAYLOAD => {
+                let mut section = SectionReader::new(
+                    BufReader::new(&mut reader),
+                    payload_entry.offset,
+                    payload_entry.size,
+                )?;
+                let header = PayloadHeader::from_reader(&mut section)?;
+                let (p, m) = payload::rewrite_header(
+                    &mut section,
+                    &mut writer,
+                    &header,
+                    key,
+                    cancel_signal,
+                )?;
+
+                properties = Some(p);
+                payload_metadata_size = m;
+            }
+            PATH_PROPERTIES => writer.write_all(properties.as_ref().unwrap().as_bytes())?,
+            PATH_OTACERT => crypto::write_pem_cert(&mut writer, cert)?,
+            _ => writer.write_all(&entries[path])?,
+        }
+
+        // Cannot fail.
+        let size = writer.stream_position()?;
+
+        zip_entries.push(ZipEntry {
+            name: path.to_owned(),
+            offset,
+            size,
+        });
+
+        last_entry_used_zip64 = use_zip64;
+    }
+
+    let data_descriptor_size = if last_entry_used_zip64 { 24 } else { 16 };
+    let metadata = add_metadata(
+        &zip_entries,
+        &mut zip_writer,
+        // Offset where next entry would begin.
+        zip_entries.last().map(|e| e.offset + e.size).unwrap() + data_descriptor_size,
+        &metadata,
+        payload_metadata_size,
+    )?;
+
+    let writer = zip_writer.finish()?.finish(key, cert)?;
+
+    Ok((writer, metadata, payload_metadata_size))
+}
+
+/// A range of a patched OTA whose data is identical to a range of the stock OTA
+/// that it was patched from.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StripHole {
+    /// Offset of the range in the patched OTA.
+    pub offset: u64,
+    /// Size of the range.
+    pub size: u64,
+    /// Offset of the identical data in the stock OTA.
+    pub source_offset: u64,
+}
+
+/// Information needed to reconstruct a patched OTA from its stripped form and
+/// the stock OTA.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct StripManifest {
+    /// Size of the full patched OTA.
+    pub size: u64,
+    /// sha256 digest of the full patched OTA.
+    pub sha256: String,
+    /// Size of the stock OTA.
+    pub source_size: u64,
+    /// Ranges that were removed from the patched OTA, sorted by offset.
+    pub holes: Vec<StripHole>,
+}
+
+pub fn parse_strip_manifest(data: &[u8]) -> Result<StripManifest> {
+    Ok(serde_json::from_slice(data)?)
+}
+
+pub fn serialize_strip_manifest(manifest: &StripManifest) -> Result<String> {
+    let mut data = serde_json::to_string_pretty(manifest)?;
+    data.push('\n');
+
+    Ok(data)
+}
+
+/// Get the absolute offset, size, and sha256 digest of every install operation
+/// blob in an OTA zip's payload.
+fn payload_blobs(mut reader: impl Read + Seek) -> Result<Vec<(u64, u64, Vec<u8>)>> {
+    let entry = find_stored_zip_entry(&mut reader, PATH_PAYLOAD)?;
+    let section = SectionReader::new(&mut reader, entry.offset, entry.size)?;
+    let header = PayloadHeader::from_reader(BufReader::new(section))?;
+    let blob_start = entry.offset + header.blob_offset;
+    let mut blobs = vec![];
+
+    for op in header
+        .manifest
+        .partitions
+        .iter()
+        .flat_map(|p| &p.operations)
+    {
+        let (Some(offset), Some(size), Some(digest)) =
+            (op.data_offset, op.data_length, &op.data_sha256_hash)
+        else {
+            continue;
+        };
+
+        if size != 0 {
+            blobs.push((blob_start + offset, size, digest.clone()));
+        }
+    }
+
+    Ok(blobs)
+}
+
+/// Find the ranges of the patched OTA's payload that have an identical
+/// counterpart in the stock OTA's payload. Contiguous ranges are merged.
+fn find_strip_holes(patched: impl Read + Seek, source: impl Read + Seek) -> Result<Vec<StripHole>> {
+    let source_blobs = payload_blobs(source)?
+        .into_iter()
+        .map(|(offset, size, digest)| ((digest, size), offset))
+        .collect::<HashMap<_, _>>();
+
+    let mut patched_blobs = payload_blobs(patched)?;
+    patched_blobs.sort_by_key(|(offset, _, _)| *offset);
+
+    let mut holes = Vec::<StripHole>::new();
+
+    for (offset, size, digest) in patched_blobs {
+        let Some(&source_offset) = source_blobs.get(&(digest, size)) else {
+            continue;
+        };
+
+        if let Some(last) = holes.last_mut() {
+            let last_end = last.offset + last.size;
+
+            if offset < last_end {
+                // Blobs should never overlap, but don't produce holes that do.
+                continue;
+            } else if offset == last_end && source_offset == last.source_offset + last.size {
+                last.size += size;
+                continue;
+            }
+        }
+
+        holes.push(StripHole {
+            offset,
+            size,
+            source_offset,
+        });
+    }
+
+    Ok(holes)
+}
+
+/// Remove the payload data from a patched OTA that is identical to the stock
+/// OTA's payload data. The remaining data is written to `writer` and the
+/// returned manifest describes how to reconstruct the patched OTA with
+/// [`unstrip()`]. The data of each hole is compared against the stock OTA to
+/// guarantee that reconstruction will succeed.
+pub fn strip(
+    mut patched: impl Read + Seek,
+    mut source: impl Read + Seek,
+    mut writer: impl Write,
+    cancel_signal: &AtomicBool,
+) -> Result<StripManifest> {
+    let holes = find_strip_holes(&mut patched, &mut source)?;
+    let size = patched.seek(SeekFrom::End(0))?;
+    let source_size = source.seek(SeekFrom::End(0))?;
+
+    patched.rewind()?;
+
+    let mut reader = HashingReader::new(patched, Context::new(&crate::digest::SHA256));
+    let mut patched_buf = [0u8; 16384];
+    let mut source_buf = [0u8; 16384];
+    let mut pos = 0;
+
+    for hole in &holes {
+        stream::copy_n(&mut reader, &mut writer, hole.offset - pos, cancel_signal)?;
+
+        source.seek(SeekFrom::Start(hole.source_offset))?;
+
+        let mut remain = hole.size;
+
+        while remain > 0 {
+            stream::check_cancel(cancel_signal)?;
+
+            let n = remain.min(patched_buf.len() as u64) as usize;
+            reader.read_exact(&mut patched_buf[..n])?;
+            source.read_exact(&mut source_buf[..n])?;
+
+            if patched_buf[..n] != source_buf[..n] {
+                return Err(Error::StripDataMismatch(hole.offset));
+            }
+
+            remain -= n as u64;
+        }
+
+        pos = hole.offset + hole.size;
+    }
+
+    stream::copy_n(&mut reader, &mut writer, size - pos, cancel_signal)?;
+
+    let (_, context) = reader.finish();
+
+    Ok(StripManifest {
+        size,
+        sha256: hex::encode(context.finish()),
+        source_size,
+        holes,
+    })
+}
+
+/// Reconstruct a patched OTA from the data produced by [`strip()`] and the
+/// stock OTA. The sha256 digest of the reconstructed data is checked against
+/// the manifest.
+pub fn unstrip(
+    mut stripped: impl Read,
+    mut source: impl Read + Seek,
+    manifest: &StripManifest,
+    writer: impl Write,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    let source_size = source.seek(SeekFrom::End(0))?;
+    if source_size != manifest.source_size {
+        return Err(Error::StripSourceSizeMismatch {
+            expected: manifest.source_size,
+            actual: source_size,
+        });
+    }
+
+    let mut writer = HashingWriter::new(writer, Context::new(&crate::digest::SHA256));
+    let mut pos = 0;
+
+    for (i, hole) in manifest.holes.iter().enumerate() {
+        let in_bounds = |offset: u64, limit: u64| {
+            offset
+                .checked_add(hole.size)
+                .is_some_and(|end| end <= limit)
+        };
+
+        if hole.offset < pos
+            || !in_bounds(hole.offset, manifest.size)
+            || !in_bounds(hole.source_offset, source_size)
+        {
+            return Err(Error::InvalidStripHole(i));
+        }
+
+        stream::copy_n(&mut stripped, &mut writer, hole.offset - pos, cancel_signal)?;
+
+        source.seek(SeekFrom::Start(hole.source_offset))?;
+        stream::copy_n(&mut source, &mut writer, hole.size, cancel_signal)?;
+
+        pos = hole.offset + hole.size;
+    }
+
+    stream::copy_n(
+        &mut stripped,
+        &mut writer,
+        manifest.size - pos,
+        cancel_signal,
+    )?;
+
+    let (mut writer, context) = writer.finish();
+    writer.flush()?;
+
+    let digest = hex::encode(context.finish());
+    if digest != manifest.sha256 {
+        return Err(Error::StripHashMismatch {
+            expected: manifest.sha256.clone(),
+            actual: digest,
+        });
+    }
+
+    Ok(())
+}
+
 /// A writer that produces a signapk-style signed zip file with a whole-file
 /// signature stored in the zip archive comment. The data will be left in an
-/// unusable state if [`Self::finish()`] is not called.
+/// unusable state if [`Self::finish()`] is not called. The whole-file digest is
+/// computed on a separate thread so that it overlaps with compression. Only the
+/// sha256 digest is offloaded. The CRC32 of each zip entry is still computed by
+/// [`zip::ZipWriter`] on the writing thread.
 pub struct SigningWriter<W: Write> {
-    inner: HashingWriter<W>,
-    // Android only supports non-zip64 EOCD.
-    queue: [u8; 22],
-    used: usize,
+    inner: ThreadedHashingWriter<W>,
+    // Data that is held back until [`Self::finish()`]. This is at least the
+    // zip64 EOCD, the zip64 EOCD locator, and the EOCD (Android only supports
+    // non-zip64 EOCD, but zip64 records may precede it) and, if a signing block
+    // is being inserted, the central directory.
+    queue: VecDeque<u8>,
+    queue_size: usize,
+    // Number of bytes that have been written to `inner`.
+    written: u64,
+    comment: Vec<u8>,
+    signing_block: Option<Vec<u8>>,
 }
 
 impl<W: Write> SigningWriter<W> {
     pub fn new(inner: W) -> Self {
         Self {
-            inner: HashingWriter::new(inner, Context::new(&ring::digest::SHA256)),
-            queue: Default::default(),
-            used: 0,
+            inner: ThreadedHashingWriter::new(inner, Context::new(&crate::digest::SHA256)),
+            queue: VecDeque::new(),
+            queue_size: 56 + 20 + 22,
+            written: 0,
+            comment: COMMENT_MESSAGE.to_vec(),
+            signing_block: None,
         }
     }
 
-    pub fn finish(mut self, key: &RsaPrivateKey, cert: &Certificate) -> Result<W> {
-        if self.used < self.queue.len() {
+    /// Use `comment` as the part of the archive comment that precedes the
+    /// whole-file signature instead of the default message.
+    pub fn with_comment(mut self, comment: Vec<u8>) -> Self {
+        self.comment = comment;
+        self
+    }
+
+    /// Insert a raw APK signing block in front of the central directory. The
+    /// signing block is covered by the whole-file signature.
+    pub fn with_signing_block(mut self, block: Vec<u8>) -> Self {
+        // Central directory, zip64 EOCD, zip64 EOCD locator, and EOCD.
+        self.queue_size = MAX_SIGNING_BLOCK_CD_SIZE + 56 + 20 + 22;
+        self.signing_block = Some(block);
+        self
+    }
+
+    pub fn finish(self, key: &RsaSigningKey, cert: &Certificate) -> Result<W> {
+        self.finish_with_signers(&[(key, cert)])
+    }
+
+    /// Like [`Self::finish()`], but signs the zip with multiple keys. Note that
+    /// AOSP only verifies the first SignerInfo in the CMS structure, which is
+    /// not necessarily the first signer since the DER encoding requires the
+    /// SignerInfos to be sorted.
+    pub fn finish_with_signers(mut self, signers: &[(&RsaSigningKey, &Certificate)]) -> Result<W> {
+        let mut tail = Vec::from(self.queue);
+        let Some(eocd) = tail.len().checked_sub(22) else {
             return Err(
                 io::Error::new(io::ErrorKind::InvalidData, "Too small to contain EOCD").into(),
             );
-        } else if &self.queue[..4] != b"PK\x05\x06" {
+        };
+
+        if &tail[eocd..eocd + 4] != b"PK\x05\x06" {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "EOCD magic not found").into());
-        } else if &self.queue[20..22] != b"\0\0" {
+        } else if &tail[eocd + 20..] != b"\0\0" {
             return Err(io::Error::new(
                 io::ErrorKind::InvalidData,
                 "Archive comment is not 0 bytes",
@@ -676,19 +1703,28 @@ impl<W: Write> SigningWriter<W> {
             .into());
         }
 
+        check_zip64_eocd(&tail, self.written)?;
+
+        if let Some(block) = &self.signing_block {
+            insert_signing_block(&mut tail, block)?;
+        }
+
         // Chop off the archive comment size field and write the remaining data.
-        self.inner.write_all(&self.queue[..20])?;
+        self.inner.write_all(&tail[..tail.len() - 2])?;
 
         let (mut raw_writer, context) = self.inner.finish();
         let digest = context.finish();
 
-        let cms_signature = crypto::cms_sign_external(key, cert, digest.as_ref())?;
+        let cms_signature = crypto::cms_sign_external(signers, digest.as_ref())?;
         let cms_signature_der = cms_signature.to_der()?;
 
-        let mut comment = COMMENT_MESSAGE.to_vec();
+        let mut comment = self.comment;
         comment.extend(&cms_signature_der);
 
         let comment_size = comment.len() + 6;
+        if comment_size > usize::from(u16::MAX) {
+            return Err(Error::CommentTooLarge(comment_size));
+        }
 
         // Absolute value of the offset of the signature from the end of the
         // archive comment.
@@ -720,28 +1756,22 @@ impl<W: Write> SigningWriter<W> {
 
 impl<W: Write> Write for SigningWriter<W> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let (front, back) = buf.split_at(buf.len().saturating_sub(self.queue.len()));
-        if front.is_empty() {
-            // Write data from the front of the queue while keeping it as full
-            // as possible.
-            let n_from_queue = (self.used + back.len()).saturating_sub(self.queue.len());
-            self.inner.write_all(&self.queue[..n_from_queue])?;
-
-            // Move unused queued bytes to the front.
-            self.queue.rotate_left(n_from_queue);
-            self.used -= n_from_queue;
-
-            // Add the remaining data to the queue.
-            self.queue[self.used..self.used + back.len()].copy_from_slice(back);
-            self.used += back.len();
-        } else {
-            // We have enough data in the back to fill the entire queue.
-            self.inner.write_all(&self.queue[..self.used])?;
-            self.inner.write_all(front)?;
-
-            self.queue.copy_from_slice(back);
-            self.used = self.queue.len();
-        }
+        // Write out everything except for the last `queue_size` bytes, first
+        // from the front of the queue and then from the new data.
+        let excess = (self.queue.len() + buf.len()).saturating_sub(self.queue_size);
+        let n_from_queue = excess.min(self.queue.len());
+
+        let (a, b) = self.queue.as_slices();
+        let n_from_a = n_from_queue.min(a.len());
+        self.inner.write_all(&a[..n_from_a])?;
+        self.inner.write_all(&b[..n_from_queue - n_from_a])?;
+        self.queue.drain(..n_from_queue);
+
+        let (front, back) = buf.split_at(excess - n_from_queue);
+        self.inner.write_all(front)?;
+        self.queue.extend(back);
+
+        self.written += excess as u64;
 
         Ok(buf.len())
     }
@@ -6,6 +6,7 @@
 use std::{
     collections::{HashMap, HashSet},
     io::{self, Cursor, Read, Seek, SeekFrom, Write},
+    mem,
     ops::Range,
     sync::atomic::AtomicBool,
 };
@@ -15,7 +16,7 @@ use base64::Engine;
 use byteorder::{BigEndian, ReadBytesExt};
 use bzip2::write::BzDecoder;
 use liblzma::{
-    stream::{Check, Stream},
+    stream::{Check, Filters, LzmaOptions, Stream},
     write::XzDecoder,
     write::XzEncoder,
 };
@@ -25,14 +26,15 @@ use rayon::{
     iter::{IndexedParallelIterator, IntoParallelRefMutIterator},
     prelude::{IntoParallelIterator, IntoParallelRefIterator, ParallelIterator},
 };
-use ring::digest::{Context, Digest};
-use rsa::{traits::PublicKeyParts, Pkcs1v15Sign, RsaPrivateKey};
+use rsa::Pkcs1v15Sign;
 use sha2::Sha256;
 use thiserror::Error;
 use x509_cert::Certificate;
 
 use crate::{
-    crypto,
+    crypto::{self, BlobDigest, RsaSigningKey},
+    digest::{Context, Digest},
+    format::padding,
     protobuf::chromeos_update_engine::{
         install_operation::Type, signatures::Signature, DeltaArchiveManifest, Extent,
         InstallOperation, PartitionInfo, PartitionUpdate, Signatures,
@@ -44,7 +46,7 @@ use crate::{
     util,
 };
 
-const OTA_MAGIC: &[u8; 4] = b"CrAU";
+pub const OTA_MAGIC: &[u8; 4] = b"CrAU";
 const OTA_HEADER_SIZE: usize = OTA_MAGIC.len() + 8 + 8 + 4;
 
 const MANIFEST_MAX_SIZE: usize = 4 * 1024 * 1024;
@@ -73,6 +75,8 @@ pub enum Error {
     },
     #[error("Unsupported partition operation: {0:?}")]
     UnsupportedOperation(Type),
+    #[error("Partition operation requires the source partition: {0:?}")]
+    RequiresSource(Type),
     #[error("Expected sha256 {expected:?}, but have {actual:?}")]
     MismatchedDigest {
         expected: Option<String>,
@@ -84,6 +88,8 @@ pub enum Error {
         size: u64,
         block_size: u32,
     },
+    #[error("Invalid block size: {0}")]
+    InvalidBlockSize(u32),
     #[error("Destination extents are not in order")]
     ExtentsNotInOrder,
     #[error("Partition not found in payload: {0}")]
@@ -94,12 +100,22 @@ pub enum Error {
     MissingField(&'static str),
     #[error("{0:?} field is out of bounds")]
     FieldOutOfBounds(&'static str),
+    #[error("Invalid manifest encoding: {0}")]
+    InvalidManifestEncoding(&'static str),
+    #[error("Number of {field} ({count}) exceeds limit ({limit})")]
+    LimitExceeded {
+        field: &'static str,
+        count: usize,
+        limit: usize,
+    },
     #[error("Crypto error")]
     Crypto(#[from] crypto::Error),
     #[error("Failed to decode protobuf message")]
     ProtobufDecode(#[from] prost::DecodeError),
     #[error("XZ stream error")]
     XzStream(#[from] liblzma::stream::Error),
+    #[error("Invalid XZ header: {0}")]
+    InvalidXzHeader(&'static str),
     #[error("RSA error")]
     Rsa(#[from] rsa::Error),
     #[error("I/O error")]
@@ -108,6 +124,176 @@ pub enum Error {
 
 type Result<T> = std::result::Result<T, Error>;
 
+/// Limits on the number of repeated elements in a payload manifest. These are
+/// checked against the raw protobuf encoding before it is decoded so that a
+/// small malicious manifest cannot trigger huge allocations.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ManifestLimits {
+    /// Maximum number of partitions.
+    pub max_partitions: usize,
+    /// Maximum number of install operations per partition.
+    pub max_operations: usize,
+    /// Maximum number of source or destination extents per install operation.
+    pub max_extents: usize,
+}
+
+impl Default for ManifestLimits {
+    fn default() -> Self {
+        Self {
+            max_partitions: 256,
+            max_operations: 1 << 18,
+            max_extents: 1 << 16,
+        }
+    }
+}
+
+/// Field number and, if the field is length-delimited, its raw contents.
+type WireField<'a> = (u64, Option<&'a [u8]>);
+
+/// Minimal reader for the protobuf wire format. This only understands enough
+/// to walk over fields without decoding them.
+struct WireReader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> WireReader<'a> {
+    fn read_varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+
+        for i in 0..10 {
+            let (&byte, rest) = self
+                .data
+                .split_first()
+                .ok_or(Error::InvalidManifestEncoding("Truncated varint"))?;
+            self.data = rest;
+
+            value |= u64::from(byte & 0x7f) << (i * 7);
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+
+        Err(Error::InvalidManifestEncoding("Varint is too long"))
+    }
+
+    fn read_bytes(&mut self, size: u64) -> Result<&'a [u8]> {
+        let size = size
+            .to_usize()
+            .filter(|s| *s <= self.data.len())
+            .ok_or(Error::InvalidManifestEncoding("Truncated field"))?;
+        let (bytes, rest) = self.data.split_at(size);
+        self.data = rest;
+
+        Ok(bytes)
+    }
+
+    fn next_field(&mut self) -> Result<Option<WireField<'a>>> {
+        if self.data.is_empty() {
+            return Ok(None);
+        }
+
+        let key = self.read_varint()?;
+        let value = match key & 7 {
+            0 => {
+                self.read_varint()?;
+                None
+            }
+            1 => {
+                self.read_bytes(8)?;
+                None
+            }
+            2 => {
+                let size = self.read_varint()?;
+                Some(self.read_bytes(size)?)
+            }
+            5 => {
+                self.read_bytes(4)?;
+                None
+            }
+            _ => return Err(Error::InvalidManifestEncoding("Unsupported wire type")),
+        };
+
+        Ok(Some((key >> 3, value)))
+    }
+}
+
+/// Count the occurrences of the repeated message field `number` in the encoded
+/// message `data`, failing if there are more than `limit`. `f` is called with
+/// each occurrence's encoded message.
+fn check_repeated(
+    data: &[u8],
+    number: u64,
+    name: &'static str,
+    limit: usize,
+    mut f: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut reader = WireReader { data };
+    let mut count = 0;
+
+    while let Some((n, value)) = reader.next_field()? {
+        if n != number {
+            continue;
+        }
+
+        let value = value.ok_or(Error::InvalidManifestEncoding(
+            "Message field is not length-delimited",
+        ))?;
+
+        count += 1;
+        if count > limit {
+            return Err(Error::LimitExceeded {
+                field: name,
+                count,
+                limit,
+            });
+        }
+
+        f(value)?;
+    }
+
+    Ok(())
+}
+
+/// Ensure that the encoded [`DeltaArchiveManifest`] does not exceed `limits`.
+fn check_manifest_limits(data: &[u8], limits: &ManifestLimits) -> Result<()> {
+    // Field numbers from update_metadata.proto.
+    const MANIFEST_PARTITIONS: u64 = 13;
+    const PARTITION_OPERATIONS: u64 = 8;
+    const OPERATION_SRC_EXTENTS: u64 = 4;
+    const OPERATION_DST_EXTENTS: u64 = 6;
+
+    check_repeated(
+        data,
+        MANIFEST_PARTITIONS,
+        "partitions",
+        limits.max_partitions,
+        |partition| {
+            check_repeated(
+                partition,
+                PARTITION_OPERATIONS,
+                "operations",
+                limits.max_operations,
+                |op| {
+                    check_repeated(
+                        op,
+                        OPERATION_SRC_EXTENTS,
+                        "src_extents",
+                        limits.max_extents,
+                        |_| Ok(()),
+                    )?;
+                    check_repeated(
+                        op,
+                        OPERATION_DST_EXTENTS,
+                        "dst_extents",
+                        limits.max_extents,
+                        |_| Ok(()),
+                    )
+                },
+            )
+        },
+    )
+}
+
 #[derive(Clone, Debug)]
 pub struct PayloadHeader {
     pub version: u64,
@@ -123,14 +309,11 @@ impl PayloadHeader {
             .iter()
             .all(|p| p.old_partition_info.is_none())
     }
-}
 
-impl<R: Read> FromReader<R> for PayloadHeader {
-    type Error = Error;
-
-    /// Parse the header from an OTA payload file. After this function returns,
-    /// the file position is set to the beginning of the blob section.
-    fn from_reader(reader: R) -> Result<Self> {
+    /// Parse the header from an OTA payload file, rejecting manifests that
+    /// exceed `limits`. After this function returns, the file position is set
+    /// to the beginning of the blob section.
+    pub fn from_reader_with_limits(reader: impl Read, limits: &ManifestLimits) -> Result<Self> {
         let mut reader = CountingReader::new(reader);
 
         let mut magic = [0u8; 4];
@@ -157,8 +340,18 @@ impl<R: Read> FromReader<R> for PayloadHeader {
             .ok_or_else(|| Error::FieldOutOfBounds("manifest_size"))?;
         let metadata_signature_size = reader.read_u32::<BigEndian>()?;
 
-        let mut manifest_raw = vec![0u8; manifest_size];
-        reader.read_exact(&mut manifest_raw)?;
+        // Read incrementally instead of allocating the claimed size upfront in
+        // case the file is truncated.
+        let mut manifest_raw = vec![];
+        reader
+            .by_ref()
+            .take(manifest_size as u64)
+            .read_to_end(&mut manifest_raw)?;
+        if manifest_raw.len() != manifest_size {
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+
+        check_manifest_limits(&manifest_raw, limits)?;
         let manifest = DeltaArchiveManifest::decode(manifest_raw.as_slice())?;
 
         // Skip manifest signatures.
@@ -173,29 +366,44 @@ impl<R: Read> FromReader<R> for PayloadHeader {
     }
 }
 
-/// Sign `digest` with `key` and return a [`Signatures`] protobuf struct with
-/// the signature padded to the maximum size.
-fn sign_digest(digest: &[u8], key: &RsaPrivateKey) -> Result<Signatures> {
-    let scheme = Pkcs1v15Sign::new::<Sha256>();
-    let mut digest_signed = key.sign(scheme, digest)?;
-    assert!(
-        digest_signed.len() <= key.size(),
-        "Signature exceeds maximum size",
-    );
+impl<R: Read> FromReader<R> for PayloadHeader {
+    type Error = Error;
 
-    let unpadded_size = digest_signed.len();
-    digest_signed.resize(key.size(), 0);
+    /// Parse the header from an OTA payload file. After this function returns,
+    /// the file position is set to the beginning of the blob section.
+    fn from_reader(reader: R) -> Result<Self> {
+        Self::from_reader_with_limits(reader, &ManifestLimits::default())
+    }
+}
 
-    let signature = Signature {
-        data: Some(digest_signed),
-        // Always fits in even a u16.
-        unpadded_signature_size: Some(unpadded_size as u32),
-        ..Default::default()
-    };
+/// Sign `digest` with each key in `keys` and return a [`Signatures`] protobuf
+/// struct with the signatures padded to the maximum size. `purpose` describes
+/// what is being signed. If it is [`None`], zero-filled dummy signatures are
+/// returned because they are only used for computing sizes.
+fn sign_digest(digest: &[u8], keys: &[RsaSigningKey], purpose: Option<&str>) -> Result<Signatures> {
+    let mut signatures = Signatures::default();
+
+    for key in keys {
+        let mut digest_signed = match purpose {
+            Some(p) => key.sign(p, BlobDigest::Sha256, digest)?,
+            // PKCS#1 v1.5 signatures are always the size of the modulus.
+            None => vec![0u8; key.size()],
+        };
+        assert!(
+            digest_signed.len() <= key.size(),
+            "Signature exceeds maximum size",
+        );
 
-    let signatures = Signatures {
-        signatures: vec![signature],
-    };
+        let unpadded_size = digest_signed.len();
+        digest_signed.resize(key.size(), 0);
+
+        signatures.signatures.push(Signature {
+            data: Some(digest_signed),
+            // Always fits in even a u16.
+            unpadded_signature_size: Some(unpadded_size as u32),
+            ..Default::default()
+        });
+    }
 
     Ok(signatures)
 }
@@ -273,6 +481,13 @@ fn generate_properties(
     properties
 }
 
+/// Generate `payload_properties.txt` contents for a payload that is wrapped in
+/// an opaque OEM container. The container has no separate metadata region, so
+/// the whole file is treated as the metadata.
+pub fn generate_opaque_properties(file_hash: &[u8], file_size: u64) -> String {
+    generate_properties(file_hash, file_size, file_hash, file_size)
+}
+
 /// A writer for producing signed `payload.bin` files.
 pub struct PayloadWriter<W: Write> {
     inner: W,
@@ -292,7 +507,7 @@ pub struct PayloadWriter<W: Write> {
     h_partial: Context,
     /// Includes signatures (hashes are for properties file).
     h_full: Context,
-    key: RsaPrivateKey,
+    keys: Vec<RsaSigningKey>,
 }
 
 /// Write data to a writer and one or more hashers.
@@ -315,7 +530,20 @@ impl<W: Write> PayloadWriter<W> {
     /// fields are ignored and internally recomputed to guarantee that there are
     /// no gaps. All partitions' install operation data is written to the blob
     /// section in order.
-    pub fn new(mut inner: W, mut header: PayloadHeader, key: RsaPrivateKey) -> Result<Self> {
+    pub fn new(inner: W, header: PayloadHeader, key: RsaSigningKey) -> Result<Self> {
+        Self::new_with_keys(inner, header, vec![key])
+    }
+
+    /// Like [`Self::new()`], but signs the payload with multiple keys. The
+    /// metadata and payload signatures will contain one signature per key, in
+    /// order. update_engine accepts the payload if any of them are trusted.
+    pub fn new_with_keys(
+        mut inner: W,
+        mut header: PayloadHeader,
+        keys: Vec<RsaSigningKey>,
+    ) -> Result<Self> {
+        assert!(!keys.is_empty(), "No signing keys specified");
+
         let mut blob_size = 0;
 
         // The blob must contain all data in sequential order with no gaps.
@@ -333,8 +561,9 @@ impl<W: Write> PayloadWriter<W> {
         // Get the length of an dummy signature struct since the length fields
         // are part of the data to be signed.
         let dummy_sig = sign_digest(
-            ring::digest::digest(&ring::digest::SHA256, b"").as_ref(),
-            &key,
+            crate::digest::digest(&crate::digest::SHA256, b"").as_ref(),
+            &keys,
+            None,
         )?;
         let dummy_sig_size = dummy_sig.encoded_len();
 
@@ -346,9 +575,9 @@ impl<W: Write> PayloadWriter<W> {
         let manifest_raw_new = header.manifest.encode_to_vec();
 
         // Excludes signatures (hashes are for signing).
-        let mut h_partial = Context::new(&ring::digest::SHA256);
+        let mut h_partial = Context::new(&crate::digest::SHA256);
         // Includes signatures (hashes are for properties file).
-        let mut h_full = Context::new(&ring::digest::SHA256);
+        let mut h_full = Context::new(&crate::digest::SHA256);
 
         // Write header to output file.
         write_hash!(inner, [h_partial, h_full], OTA_MAGIC)?;
@@ -370,7 +599,7 @@ impl<W: Write> PayloadWriter<W> {
         // Sign metadata (header + manifest) hash. The signature is not included
         // in the payload hash.
         let metadata_hash = h_partial.clone().finish();
-        let metadata_sig = sign_digest(metadata_hash.as_ref(), &key)?;
+        let metadata_sig = sign_digest(metadata_hash.as_ref(), &keys, Some("payload_metadata"))?;
         let metadata_sig_raw = metadata_sig.encode_to_vec();
         write_hash!(inner, [h_full], &metadata_sig_raw)?;
 
@@ -385,7 +614,7 @@ impl<W: Write> PayloadWriter<W> {
             written: 0,
             h_partial,
             h_full,
-            key,
+            keys,
         })
     }
 
@@ -398,7 +627,7 @@ impl<W: Write> PayloadWriter<W> {
     pub fn finish(mut self) -> Result<(W, String, u64)> {
         // Append payload signature.
         let payload_partial_hash = self.h_partial.clone().finish();
-        let payload_sig = sign_digest(payload_partial_hash.as_ref(), &self.key)?;
+        let payload_sig = sign_digest(payload_partial_hash.as_ref(), &self.keys, Some("payload"))?;
         let payload_sig_raw = payload_sig.encode_to_vec();
         write_hash!(self.inner, [self.h_full], &payload_sig_raw)?;
 
@@ -542,6 +771,46 @@ impl<W: Write> Write for PayloadWriter<W> {
     }
 }
 
+/// Write a new signed payload with the manifest from `header`, copying the
+/// install operation data from the original payload in `reader` as-is. This is
+/// only valid if the manifest changes do not affect the data, like changing the
+/// timestamp or postinstall settings. The data offsets in `header` must still
+/// refer to the original payload's blob. Returns the `payload_properties.txt`
+/// contents and metadata size, like [`PayloadWriter::finish()`].
+pub fn rewrite_header(
+    mut reader: impl Read + Seek,
+    writer: impl Write,
+    header: &PayloadHeader,
+    key: &RsaSigningKey,
+    cancel_signal: &AtomicBool,
+) -> Result<(String, u64)> {
+    let mut payload_writer = PayloadWriter::new(writer, header.clone(), key.clone())?;
+
+    while payload_writer.begin_next_operation()? {
+        let pi = payload_writer.partition_index().unwrap();
+        let oi = payload_writer.operation_index().unwrap();
+        let operation = &header.manifest.partitions[pi].operations[oi];
+
+        let Some(data_length) = operation.data_length else {
+            continue;
+        };
+        let data_offset = operation
+            .data_offset
+            .ok_or(Error::MissingField("data_offset"))?;
+        let in_offset = header
+            .blob_offset
+            .checked_add(data_offset)
+            .ok_or_else(|| Error::FieldOutOfBounds("in_offset"))?;
+
+        reader.seek(SeekFrom::Start(in_offset))?;
+        stream::copy_n(&mut reader, &mut payload_writer, data_length, cancel_signal)?;
+    }
+
+    let (_, properties, metadata_size) = payload_writer.finish()?;
+
+    Ok((properties, metadata_size))
+}
+
 /// Verify the payload signatures using the specified certificate and check that
 /// the digests in `payload_properties.txt` are correct.
 pub fn verify_payload(
@@ -563,9 +832,9 @@ pub fn verify_payload(
         .ok_or_else(|| Error::MissingField("signatures_size"))?;
 
     // Excludes signatures (hashes are for signing).
-    let mut h_partial = Context::new(&ring::digest::SHA256);
+    let mut h_partial = Context::new(&crate::digest::SHA256);
     // Includes signatures (hashes are for properties file).
-    let mut h_full = Context::new(&ring::digest::SHA256);
+    let mut h_full = Context::new(&crate::digest::SHA256);
 
     // Read from the beginning to the metadata signature.
     let metadata_size = header.blob_offset - u64::from(header.metadata_signature_size);
@@ -678,15 +947,151 @@ pub fn verify_payload(
     Ok(())
 }
 
-/// Apply a partition operation from `reader` to `writer`.
+/// Check if the operation reads from the partition's previous contents.
+pub fn requires_source(op: &InstallOperation) -> bool {
+    matches!(
+        op.r#type(),
+        Type::Move
+            | Type::Bsdiff
+            | Type::SourceCopy
+            | Type::SourceBsdiff
+            | Type::BrotliBsdiff
+            | Type::Puffdiff
+            | Type::Zucchini
+            | Type::Lz4diffBsdiff
+            | Type::Lz4diffPuffdiff
+    )
+}
+
+/// Convert a list of extents to byte ranges.
+fn extent_ranges(extents: &[Extent], block_size: u32) -> Result<Vec<Range<u64>>> {
+    extents
+        .iter()
+        .map(|extent| {
+            let start = extent
+                .start_block
+                .ok_or_else(|| Error::MissingField("start_block"))?
+                .checked_mul(block_size.into())
+                .ok_or_else(|| Error::FieldOutOfBounds("start_block"))?;
+            let size = extent
+                .num_blocks
+                .ok_or_else(|| Error::MissingField("num_blocks"))?
+                .checked_mul(block_size.into())
+                .ok_or_else(|| Error::FieldOutOfBounds("num_blocks"))?;
+            let end = start
+                .checked_add(size)
+                .ok_or_else(|| Error::FieldOutOfBounds("num_blocks"))?;
+
+            Ok(start..end)
+        })
+        .collect()
+}
+
+/// Apply a [`Type::SourceCopy`] operation by copying its source extents from
+/// the previous version of the partition in `source` to `writer`. If the
+/// operation has a source digest, it is verified.
+pub fn apply_source_copy(
+    mut source: impl Read + Seek,
+    mut writer: impl Write + Seek,
+    block_size: u32,
+    op: &InstallOperation,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    if op.r#type() != Type::SourceCopy {
+        return Err(Error::UnsupportedOperation(op.r#type()));
+    }
+
+    let src_ranges = extent_ranges(&op.src_extents, block_size)?;
+    let dst_ranges = extent_ranges(&op.dst_extents, block_size)?;
+
+    let src_size = src_ranges.iter().map(|r| r.end - r.start).sum::<u64>();
+    let dst_size = dst_ranges.iter().map(|r| r.end - r.start).sum::<u64>();
+    if src_size != dst_size {
+        return Err(Error::FieldOutOfBounds("src_extents"));
+    }
+
+    let mut hasher = Context::new(&crate::digest::SHA256);
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut src_iter = src_ranges.into_iter();
+    let mut dst_iter = dst_ranges.into_iter();
+    let mut src = 0..0;
+    let mut dst = 0..0;
+
+    loop {
+        if src.is_empty() {
+            match src_iter.next() {
+                Some(r) => {
+                    source.seek(SeekFrom::Start(r.start))?;
+                    src = r;
+                }
+                None => break,
+            }
+            continue;
+        }
+        if dst.is_empty() {
+            let r = dst_iter
+                .next()
+                .ok_or_else(|| Error::FieldOutOfBounds("dst_extents"))?;
+            writer.seek(SeekFrom::Start(r.start))?;
+            dst = r;
+            continue;
+        }
+
+        stream::check_cancel(cancel_signal)?;
+
+        let n = (src.end - src.start)
+            .min(dst.end - dst.start)
+            .min(buf.len() as u64);
+        let data = &mut buf[..n as usize];
+
+        source.read_exact(data)?;
+        hasher.update(data);
+        writer.write_all(data)?;
+
+        src.start += n;
+        dst.start += n;
+    }
+
+    if let Some(expected) = &op.src_sha256_hash {
+        let digest = hasher.finish();
+
+        if expected != digest.as_ref() {
+            return Err(Error::MismatchedDigest {
+                expected: Some(hex::encode(expected)),
+                actual: hex::encode(digest.as_ref()),
+            });
+        }
+    }
+
+    Ok(())
+}
+
+/// Options for applying partition operations.
+#[derive(Clone, Debug, Default)]
+pub struct ApplyOptions {
+    /// Maximum amount of memory that each XZ decoder may allocate when applying
+    /// [`Type::ReplaceXz`] operations. Most of this is the LZMA2 dictionary,
+    /// whose size is chosen by whoever created the payload. Operations that
+    /// need more memory fail instead. If [`None`], there is no limit.
+    pub xz_memlimit: Option<u64>,
+}
+
+/// Apply a partition operation from `reader` to `writer`. Operations that
+/// require the partition's previous contents are rejected with
+/// [`Error::RequiresSource`]. See [`apply_source_copy()`].
 pub fn apply_operation(
     mut reader: impl Read + Seek,
     mut writer: impl Write + Seek,
     block_size: u32,
     blob_offset: u64,
     op: &InstallOperation,
+    options: &ApplyOptions,
     cancel_signal: &AtomicBool,
 ) -> Result<()> {
+    if requires_source(op) {
+        return Err(Error::RequiresSource(op.r#type()));
+    }
+
     for extent in &op.dst_extents {
         let start_block = extent
             .start_block
@@ -704,7 +1109,7 @@ pub fn apply_operation(
 
         writer.seek(SeekFrom::Start(out_offset))?;
 
-        let mut hasher = Context::new(&ring::digest::SHA256);
+        let mut hasher = Context::new(&crate::digest::SHA256);
 
         match op.r#type() {
             // Handle ZERO/DISCARD specially since they don't require access to
@@ -753,7 +1158,9 @@ pub fn apply_operation(
                         decoder.finish()?;
                     }
                     Type::ReplaceXz => {
-                        let mut decoder = XzDecoder::new(&mut writer);
+                        let memlimit = options.xz_memlimit.unwrap_or(u64::MAX);
+                        let stream = Stream::new_stream_decoder(memlimit, 0)?;
+                        let mut decoder = XzDecoder::new_stream(&mut writer, stream);
                         stream::copy_n_inspect(
                             &mut reader,
                             &mut decoder,
@@ -790,6 +1197,29 @@ pub fn extract_image(
     output: &(dyn WriteSeekReopen + Sync),
     header: &PayloadHeader,
     partition_name: &str,
+    options: &ApplyOptions,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    extract_image_with_source(
+        payload,
+        None,
+        output,
+        header,
+        partition_name,
+        options,
+        cancel_signal,
+    )
+}
+
+/// Like [`extract_image()`], but [`Type::SourceCopy`] operations are applied
+/// by reading from the previous version of the partition in `source`.
+pub fn extract_image_with_source(
+    payload: &(dyn ReadSeekReopen + Sync),
+    source: Option<&(dyn ReadSeekReopen + Sync)>,
+    output: &(dyn WriteSeekReopen + Sync),
+    header: &PayloadHeader,
+    partition_name: &str,
+    options: &ApplyOptions,
     cancel_signal: &AtomicBool,
 ) -> Result<()> {
     let partition = header
@@ -803,17 +1233,27 @@ pub fn extract_image(
         .operations
         .par_iter()
         .map(|op| -> Result<()> {
-            let reader = payload.reopen_boxed()?;
             let writer = output.reopen_boxed()?;
 
-            apply_operation(
-                reader,
-                writer,
-                header.manifest.block_size(),
-                header.blob_offset,
-                op,
-                cancel_signal,
-            )?;
+            if let (Type::SourceCopy, Some(source)) = (op.r#type(), source) {
+                apply_source_copy(
+                    source.reopen_boxed()?,
+                    writer,
+                    header.manifest.block_size(),
+                    op,
+                    cancel_signal,
+                )?;
+            } else {
+                apply_operation(
+                    payload.reopen_boxed()?,
+                    writer,
+                    header.manifest.block_size(),
+                    header.blob_offset,
+                    op,
+                    options,
+                    cancel_signal,
+                )?;
+            }
 
             Ok(())
         })
@@ -828,6 +1268,7 @@ pub fn extract_images<'a>(
     open_output: impl Fn(&str) -> io::Result<Box<dyn WriteSeek>> + Sync,
     header: &PayloadHeader,
     partition_names: impl IntoIterator<Item = &'a str>,
+    options: &ApplyOptions,
     cancel_signal: &AtomicBool,
 ) -> Result<()> {
     let mut remaining = partition_names.into_iter().collect::<HashSet<_>>();
@@ -860,6 +1301,7 @@ pub fn extract_images<'a>(
                 header.manifest.block_size(),
                 header.blob_offset,
                 op,
+                options,
                 cancel_signal,
             )?;
 
@@ -868,16 +1310,248 @@ pub fn extract_images<'a>(
         .collect()
 }
 
-fn compress_chunk(raw_data: &[u8], cancel_signal: &AtomicBool) -> Result<(Vec<u8>, Digest)> {
+/// liblzma's `LZMA_PRESET_EXTREME` flag.
+const XZ_PRESET_EXTREME: u32 = 1 << 31;
+
+/// Size of the uncompressed chunks produced by [`compress_image()`]. This is
+/// rounded up to a multiple of the block size if needed.
+const CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+
+/// Get the chunk size for payloads with the specified block size. Most payloads
+/// use 4 KiB blocks, but larger block sizes that do not evenly divide
+/// [`CHUNK_SIZE`] are valid too.
+fn chunk_size_for(block_size: u32) -> Result<u64> {
+    if block_size == 0 {
+        return Err(Error::InvalidBlockSize(block_size));
+    }
+
+    padding::round(CHUNK_SIZE, u64::from(block_size)).ok_or(Error::InvalidBlockSize(block_size))
+}
+
+/// XZ stream header magic.
+const XZ_MAGIC: &[u8; 6] = b"\xfd7zXZ\0";
+
+/// Size of the XZ stream header.
+const XZ_STREAM_HEADER_SIZE: usize = 12;
+
+/// Maximum size of an XZ block header.
+const XZ_MAX_BLOCK_HEADER_SIZE: usize = 1024;
+
+/// XZ filter ID for LZMA2.
+const XZ_FILTER_LZMA2: u64 = 0x21;
+
+/// Integrity check type stored in XZ streams.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum XzCheck {
+    /// No check. This is what AOSP's payload generator uses.
+    #[default]
+    None,
+    Crc32,
+    Crc64,
+    Sha256,
+}
+
+impl XzCheck {
+    fn from_id(id: u8) -> Option<Self> {
+        match id {
+            0x00 => Some(Self::None),
+            0x01 => Some(Self::Crc32),
+            0x04 => Some(Self::Crc64),
+            0x0a => Some(Self::Sha256),
+            _ => None,
+        }
+    }
+
+    fn to_lzma(self) -> Check {
+        match self {
+            Self::None => Check::None,
+            Self::Crc32 => Check::Crc32,
+            Self::Crc64 => Check::Crc64,
+            Self::Sha256 => Check::Sha256,
+        }
+    }
+}
+
+/// Parameters of an XZ stream that may matter for compatibility with a
+/// device's XZ decoder.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct XzParams {
+    pub check: XzCheck,
+    /// LZMA2 dictionary size.
+    pub dict_size: u32,
+}
+
+/// Dictionary size used by liblzma's compression presets.
+fn xz_preset_dict_size(level: u32) -> u32 {
+    const MIB: u32 = 1024 * 1024;
+
+    match level {
+        0 => 256 * 1024,
+        1 => MIB,
+        2 => 2 * MIB,
+        3 | 4 => 4 * MIB,
+        5 | 6 => 8 * MIB,
+        7 => 16 * MIB,
+        8 => 32 * MIB,
+        _ => 64 * MIB,
+    }
+}
+
+/// Approximate memory usage of liblzma's encoder with each compression preset,
+/// as documented in xz(1).
+fn xz_preset_encoder_memory(level: u32) -> u64 {
+    const MIB: u64 = 1024 * 1024;
+
+    match level {
+        0 => 3 * MIB,
+        1 => 9 * MIB,
+        2 => 17 * MIB,
+        3 => 32 * MIB,
+        4 => 48 * MIB,
+        5 | 6 => 94 * MIB,
+        7 => 186 * MIB,
+        8 => 370 * MIB,
+        _ => 674 * MIB,
+    }
+}
+
+/// Read an XZ variable-length integer.
+fn read_xz_varint(data: &[u8], pos: &mut usize) -> Result<u64> {
+    let mut value = 0u64;
+
+    for i in 0..9 {
+        let byte = *data
+            .get(*pos)
+            .ok_or(Error::InvalidXzHeader("Truncated block header"))?;
+        *pos += 1;
+
+        value |= u64::from(byte & 0x7f) << (i * 7);
+
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+
+    Err(Error::InvalidXzHeader(
+        "Variable-length integer is too long",
+    ))
+}
+
+/// Parse the check type and LZMA2 dictionary size from the stream header and
+/// first block header of XZ data.
+pub fn parse_xz_params(data: &[u8]) -> Result<XzParams> {
+    if data.len() < XZ_STREAM_HEADER_SIZE || !data.starts_with(XZ_MAGIC) {
+        return Err(Error::InvalidXzHeader("Invalid stream header magic"));
+    }
+
+    let check =
+        XzCheck::from_id(data[7] & 0x0f).ok_or(Error::InvalidXzHeader("Unknown check type"))?;
+
+    let block = &data[XZ_STREAM_HEADER_SIZE..];
+    let header_size = match block.first() {
+        Some(0) | None => return Err(Error::InvalidXzHeader("Stream has no blocks")),
+        Some(b) => (usize::from(*b) + 1) * 4,
+    };
+    let header = block
+        .get(..header_size)
+        .ok_or(Error::InvalidXzHeader("Truncated block header"))?;
+    let flags = header[1];
+    let mut pos = 2;
+
+    // Compressed size and uncompressed size.
+    if flags & 0x40 != 0 {
+        read_xz_varint(header, &mut pos)?;
+    }
+    if flags & 0x80 != 0 {
+        read_xz_varint(header, &mut pos)?;
+    }
+
+    for _ in 0..(flags & 0x03) + 1 {
+        let id = read_xz_varint(header, &mut pos)?;
+        let props_size = read_xz_varint(header, &mut pos)?;
+        let props = usize::try_from(props_size)
+            .ok()
+            .and_then(|s| header.get(pos..pos.checked_add(s)?))
+            .ok_or(Error::InvalidXzHeader("Truncated filter properties"))?;
+        pos += props.len();
+
+        if id == XZ_FILTER_LZMA2 {
+            let dict_size = match props.first() {
+                Some(40) => u32::MAX,
+                Some(&b) if b < 40 => (2 | (u32::from(b) & 1)) << (b / 2 + 11),
+                _ => return Err(Error::InvalidXzHeader("Invalid LZMA2 properties")),
+            };
+
+            return Ok(XzParams { check, dict_size });
+        }
+    }
+
+    Err(Error::InvalidXzHeader("No LZMA2 filter"))
+}
+
+/// Find the XZ parameters used by the payload's first [`Type::ReplaceXz`]
+/// operation. Returns [`None`] if the payload has no such operation.
+pub fn detect_xz_params(
+    mut reader: impl Read + Seek,
+    header: &PayloadHeader,
+) -> Result<Option<XzParams>> {
+    let Some(op) = header
+        .manifest
+        .partitions
+        .iter()
+        .flat_map(|p| &p.operations)
+        .find(|op| op.r#type() == Type::ReplaceXz)
+    else {
+        return Ok(None);
+    };
+
+    let data_offset = op
+        .data_offset
+        .ok_or_else(|| Error::MissingField("data_offset"))?;
+    let data_length = op
+        .data_length
+        .ok_or_else(|| Error::MissingField("data_length"))?;
+    let offset = header
+        .blob_offset
+        .checked_add(data_offset)
+        .ok_or_else(|| Error::FieldOutOfBounds("data_offset"))?;
+    let size = data_length.min((XZ_STREAM_HEADER_SIZE + XZ_MAX_BLOCK_HEADER_SIZE) as u64);
+
+    let mut data = vec![0u8; size as usize];
+    reader.seek(SeekFrom::Start(offset))?;
+    reader.read_exact(&mut data)?;
+
+    parse_xz_params(&data).map(Some)
+}
+
+fn compress_chunk(
+    raw_data: &[u8],
+    options: &CompressOptions,
+    cancel_signal: &AtomicBool,
+) -> Result<(Vec<u8>, Digest)> {
     let reader = Cursor::new(raw_data);
-    let writer = Cursor::new(Vec::new());
-    let hashing_writer = HashingWriter::new(writer, Context::new(&ring::digest::SHA256));
+    // The compressed data is almost never larger than the input.
+    let writer = Cursor::new(Vec::with_capacity(raw_data.len()));
+    let hashing_writer = HashingWriter::new(writer, Context::new(&crate::digest::SHA256));
+
+    let mut preset = options.xz_level;
+    if options.xz_extreme {
+        preset |= XZ_PRESET_EXTREME;
+    }
+
+    let mut lzma_options = LzmaOptions::new_preset(preset)?;
+    lzma_options.dict_size(options.effective_xz_dict_size());
+
+    let mut filters = Filters::new();
+    filters.lzma2(&lzma_options);
 
     // AOSP's payload_consumer does not support checking CRC during
-    // decompression. Also, we intentionally pick the lowest compression level
-    // since we primarily care about squishing zeros. The non-zero portions of
-    // boot images are usually already-compressed kernels and ramdisks.
-    let stream = Stream::new_easy_encoder(0, Check::None)?;
+    // decompression, but some OEM builds require a CRC32 check.
+    let check = options
+        .xz_check
+        .or(options.xz_original.map(|p| p.check))
+        .unwrap_or_default();
+    let stream = Stream::new_stream_encoder(&filters, check.to_lzma())?;
     let mut xz_writer = XzEncoder::new_stream(hashing_writer, stream);
 
     stream::copy_n(reader, &mut xz_writer, raw_data.len() as u64, cancel_signal)?;
@@ -890,27 +1564,180 @@ fn compress_chunk(raw_data: &[u8], cancel_signal: &AtomicBool) -> Result<(Vec<u8
     Ok((data, digest_compressed))
 }
 
+/// The operation type, data, and data sha256 digest for a chunk.
+#[derive(Clone)]
+struct ChunkData {
+    r#type: Type,
+    data: Vec<u8>,
+    digest: Vec<u8>,
+}
+
+/// Options for how [`compress_image()`] and [`compress_modified_image()`]
+/// produce the data for each chunk.
+#[derive(Default)]
+pub struct CompressOptions {
+    /// Store all-zero chunks as [`Type::Zero`] operations, which have no blob
+    /// data at all.
+    pub zero_chunks: bool,
+    /// Store chunks with a Shannon entropy (in bits per byte) at or above this
+    /// threshold as [`Type::Replace`] operations without compressing them.
+    /// High-entropy data, like APKs and other zip files, is usually already
+    /// compressed and would only waste CPU time.
+    pub skip_entropy: Option<f64>,
+    /// XZ compression level (0-9). The default of 0 is intentionally the
+    /// lowest because the goal is primarily to squish zeros. The non-zero
+    /// portions of boot images are usually already-compressed kernels and
+    /// ramdisks.
+    pub xz_level: u32,
+    /// Use XZ's slower "extreme" variant of [`Self::xz_level`].
+    pub xz_extreme: bool,
+    /// XZ integrity check type. If [`None`], the check type of
+    /// [`Self::xz_original`] is used, if known, and otherwise no check.
+    pub xz_check: Option<XzCheck>,
+    /// LZMA2 dictionary size. If [`None`], the preset's dictionary size is
+    /// used, but capped to the chunk size and to the dictionary size of
+    /// [`Self::xz_original`].
+    pub xz_dict_size: Option<u32>,
+    /// XZ parameters of the original payload. See [`detect_xz_params()`].
+    pub xz_original: Option<XzParams>,
+    /// Read and compress one chunk at a time instead of groups of chunks in
+    /// parallel. This keeps only a single chunk and XZ encoder in memory at the
+    /// cost of being much slower.
+    pub low_memory: bool,
+}
+
+impl CompressOptions {
+    /// Number of chunks or operations to process in parallel at a time.
+    fn group_size(&self, default: usize) -> usize {
+        if self.low_memory {
+            1
+        } else {
+            default
+        }
+    }
+
+    /// Get the XZ dictionary size to use for compressing chunks.
+    fn effective_xz_dict_size(&self) -> u32 {
+        self.xz_dict_size.unwrap_or_else(|| {
+            // Presets 3 and up use dictionaries of 4 MiB to 64 MiB. A
+            // dictionary larger than a chunk is never filled, but liblzma would
+            // still allocate and initialize all of it for every single
+            // operation.
+            let mut size = xz_preset_dict_size(self.xz_level).min(CHUNK_SIZE as u32);
+
+            // Some devices' decoders only support dictionaries up to the size
+            // that the original payload used.
+            if let Some(original) = &self.xz_original {
+                size = size.min(original.dict_size);
+            }
+
+            size
+        })
+    }
+
+    /// Roughly estimate the peak amount of memory used when compressing an
+    /// image with these options. This covers the uncompressed and compressed
+    /// data of a group of chunks and one XZ encoder per worker thread, which
+    /// are the only allocations that scale with the options. The encoder memory usage is scaled down from the preset's
+    /// documented usage based on the dictionary size.
+    pub fn estimate_peak_memory(&self, block_size: u32) -> Result<u64> {
+        let chunk_size = chunk_size_for(block_size)?;
+        let group = self.group_size(32);
+        let workers = rayon::current_num_threads().clamp(1, group) as u64;
+
+        let encoder_memory = xz_preset_encoder_memory(self.xz_level)
+            * u64::from(self.effective_xz_dict_size())
+            / u64::from(xz_preset_dict_size(self.xz_level));
+
+        let data_memory = 2 * group as u64 * chunk_size;
+
+        Ok(workers * encoder_memory + data_memory)
+    }
+
+    /// Produce the data for a chunk. Returns [`None`] if the chunk should become a [`Type::Zero`] operation.
+    fn compress_chunk(
+        &self,
+        raw_data: &[u8],
+        cancel_signal: &AtomicBool,
+    ) -> Result<Option<ChunkData>> {
+        if self.zero_chunks && util::is_zero(raw_data) {
+            return Ok(None);
+        }
+
+        if let Some(threshold) = self.skip_entropy {
+            if util::entropy(raw_data) >= threshold {
+                let digest = crate::digest::digest(&crate::digest::SHA256, raw_data);
+
+                return Ok(Some(ChunkData {
+                    r#type: Type::Replace,
+                    data: raw_data.to_vec(),
+                    digest: digest.as_ref().to_vec(),
+                }));
+            }
+        }
+
+        let (data, digest) = compress_chunk(raw_data, self, cancel_signal)?;
+
+        Ok(Some(ChunkData {
+            r#type: Type::ReplaceXz,
+            data,
+            digest: digest.as_ref().to_vec(),
+        }))
+    }
+}
+
+/// Update an operation to store the result of
+/// [`CompressOptions::compress_chunk()`] and return the data to be written.
+fn set_operation_data(operation: &mut InstallOperation, chunk: Option<ChunkData>) -> Vec<u8> {
+    operation.data_offset = None;
+    // The operation may have previously been a SOURCE_COPY operation.
+    operation.src_extents.clear();
+    operation.src_length = None;
+    operation.src_sha256_hash = None;
+
+    match chunk {
+        Some(chunk) => {
+            operation.set_type(chunk.r#type);
+            operation.data_length = Some(chunk.data.len() as u64);
+            operation.data_sha256_hash = Some(chunk.digest);
+
+            chunk.data
+        }
+        None => {
+            operation.set_type(Type::Zero);
+            operation.data_length = None;
+            operation.data_sha256_hash = None;
+
+            vec![]
+        }
+    }
+}
+
 /// Compress the image and return the corresponding information to insert into
 /// the payload manifest's [`PartitionUpdate`] instance. The uncompressed data
-/// is split into 2 MiB chunks, which are read and compressed in parallel, and
-/// then written in parallel (but in order) to the output. Each chunk will have
-/// a corresponding [`InstallOperation`] in the return value. The caller must
-/// update [`InstallOperation::data_offset`] in each operation manually because
-/// the initial values are relative to 0.
+/// is split into 2 MiB chunks (rounded up to a multiple of `block_size`), which
+/// are read and compressed in parallel, and then written in parallel (but in
+/// order) to the output. Each chunk will have a corresponding
+/// [`InstallOperation`] in the return value. The caller must update
+/// [`InstallOperation::data_offset`] in each operation manually because the
+/// initial values are relative to 0.
+///
+/// Depending on `options`, some chunks may be emitted as [`Type::Zero`] or
+/// [`Type::Replace`] operations instead.
 pub fn compress_image(
     input: &(dyn ReadSeekReopen + Sync),
     output: &(dyn WriteSeekReopen + Sync),
     partition_name: &str,
     block_size: u32,
+    options: &CompressOptions,
     cancel_signal: &AtomicBool,
 ) -> Result<(PartitionInfo, Vec<InstallOperation>)> {
-    const CHUNK_SIZE: u64 = 2 * 1024 * 1024;
-    const CHUNK_GROUP: u64 = 32;
-
+    let chunk_group = options.group_size(32) as u64;
+    let chunk_size = chunk_size_for(block_size)?;
     let file_size = input.reopen_boxed()?.seek(SeekFrom::End(0))?;
-    let final_chunk_different = file_size % CHUNK_SIZE != 0;
+    let final_chunk_different = file_size % chunk_size != 0;
 
-    if file_size % u64::from(block_size) != 0 || CHUNK_SIZE % u64::from(block_size) != 0 {
+    if file_size % u64::from(block_size) != 0 {
         return Err(Error::InvalidPartitionSize {
             name: partition_name.to_owned(),
             size: file_size,
@@ -918,9 +1745,9 @@ pub fn compress_image(
         });
     }
 
-    let chunks_total = util::div_ceil(file_size, CHUNK_SIZE);
+    let chunks_total = util::div_ceil(file_size, chunk_size);
     let mut bytes_compressed = 0;
-    let mut context_uncompressed = Context::new(&ring::digest::SHA256);
+    let mut context_uncompressed = Context::new(&crate::digest::SHA256);
     let mut operations = vec![];
 
     // Read the file one group at a time. This allows for some parallelization
@@ -928,20 +1755,20 @@ pub fn compress_image(
     // need to compute the checksum of the entire file.
     while (operations.len() as u64) < chunks_total {
         let chunks_done = operations.len() as u64;
-        let chunks_group = (chunks_total - chunks_done).min(CHUNK_GROUP);
+        let chunks_group = (chunks_total - chunks_done).min(chunk_group);
 
         let uncompressed_data_group = (chunks_done..chunks_done + chunks_group)
             .into_par_iter()
             .map(|chunk| -> Result<(u64, Vec<u8>)> {
                 let mut reader = input.reopen_boxed()?;
-                let offset = reader.seek(SeekFrom::Start(chunk * CHUNK_SIZE))?;
+                let offset = reader.seek(SeekFrom::Start(chunk * chunk_size))?;
 
-                let chunk_size = if final_chunk_different && chunk == chunks_total - 1 {
-                    file_size % CHUNK_SIZE
+                let size = if final_chunk_different && chunk == chunks_total - 1 {
+                    file_size % chunk_size
                 } else {
-                    CHUNK_SIZE
+                    chunk_size
                 };
-                let mut data = vec![0u8; chunk_size as usize];
+                let mut data = vec![0u8; size as usize];
 
                 stream::check_cancel(cancel_signal)?;
                 reader.read_exact(&mut data)?;
@@ -958,18 +1785,16 @@ pub fn compress_image(
             .into_par_iter()
             .map(
                 |(raw_offset, raw_data)| -> Result<(Vec<u8>, InstallOperation)> {
-                    let (data, digest_compressed) = compress_chunk(&raw_data, cancel_signal)?;
-
                     let extent = Extent {
                         start_block: Some(raw_offset / u64::from(block_size)),
                         num_blocks: Some(raw_data.len() as u64 / u64::from(block_size)),
                     };
 
                     let mut operation = InstallOperation::default();
-                    operation.set_type(Type::ReplaceXz);
-                    operation.data_length = Some(data.len() as u64);
                     operation.dst_extents.push(extent);
-                    operation.data_sha256_hash = Some(digest_compressed.as_ref().to_vec());
+
+                    let chunk = options.compress_chunk(&raw_data, cancel_signal)?;
+                    let data = set_operation_data(&mut operation, chunk);
 
                     Ok((data, operation))
                 },
@@ -977,16 +1802,20 @@ pub fn compress_image(
             .collect::<Result<Vec<_>>>()?;
 
         for (data, operation) in &mut compressed_data_group {
-            operation.data_offset = Some(bytes_compressed);
-            bytes_compressed += data.len() as u64;
+            if operation.data_length.is_some() {
+                operation.data_offset = Some(bytes_compressed);
+                bytes_compressed += data.len() as u64;
+            }
         }
 
         let group_operations = compressed_data_group
             .into_par_iter()
             .map(|(data, operation)| -> Result<InstallOperation> {
-                let mut writer = output.reopen_boxed()?;
-                writer.seek(SeekFrom::Start(operation.data_offset.unwrap()))?;
-                writer.write_all(&data)?;
+                if let Some(offset) = operation.data_offset {
+                    let mut writer = output.reopen_boxed()?;
+                    writer.seek(SeekFrom::Start(offset))?;
+                    writer.write_all(&data)?;
+                }
 
                 Ok(operation)
             })
@@ -1040,7 +1869,10 @@ fn extents_sorted(operations: &[InstallOperation]) -> bool {
 /// [`InstallOperation::data_offset`] in each operation manually because the
 /// initial values are relative to 0.
 ///
+/// `options` is used in the same way as in [`compress_image()`].
+///
 /// Returns the ranges of indices of `operations` that were updated.
+#[allow(clippy::too_many_arguments)]
 pub fn compress_modified_image(
     input: &(dyn ReadSeekReopen + Sync),
     output: &(dyn WriteSeekReopen + Sync),
@@ -1048,9 +1880,14 @@ pub fn compress_modified_image(
     partition_info: &mut PartitionInfo,
     operations: &mut [InstallOperation],
     ranges: &[Range<u64>],
+    options: &CompressOptions,
     cancel_signal: &AtomicBool,
 ) -> Result<Vec<Range<usize>>> {
-    const OPERATION_GROUP: usize = 32;
+    let operation_group = options.group_size(32);
+
+    if block_size == 0 {
+        return Err(Error::InvalidBlockSize(block_size));
+    }
 
     // Full OTAs created by payload_generator have one extent per operation and
     // they're all in order with no gaps. Verify this so we can take advantage
@@ -1059,17 +1896,17 @@ pub fn compress_modified_image(
         return Err(Error::ExtentsNotInOrder);
     }
 
-    let groups_total = util::div_ceil(operations.len(), OPERATION_GROUP);
+    let groups_total = util::div_ceil(operations.len(), operation_group);
     let mut bytes_compressed = 0;
-    let mut context_uncompressed = Context::new(&ring::digest::SHA256);
+    let mut context_uncompressed = Context::new(&crate::digest::SHA256);
     let mut modified_operations = vec![];
 
     // Read the file one group at a time. This allows for some parallelization
     // without reading the entire file into memory. This is necessary because we
     // need to compute the checksum of the entire file.
     for group in 0..groups_total {
-        let operation_start = group * OPERATION_GROUP;
-        let operation_size = (operations.len() - operation_start).min(OPERATION_GROUP);
+        let operation_start = group * operation_group;
+        let operation_size = (operations.len() - operation_start).min(operation_group);
         let operation_end = operation_start + operation_size;
 
         let uncompressed_data_group = operations[operation_start..operation_end]
@@ -1119,11 +1956,8 @@ pub fn compress_modified_image(
             .filter(|(_, (_, was_modified))| *was_modified)
             .map(
                 |((i_rel, operation), (raw_data, _))| -> Result<(Vec<u8>, usize, &mut InstallOperation)> {
-                    let (data, digest_compressed) = compress_chunk(&raw_data, cancel_signal)?;
-
-                    operation.set_type(Type::ReplaceXz);
-                    operation.data_length = Some(data.len() as u64);
-                    operation.data_sha256_hash = Some(digest_compressed.as_ref().to_vec());
+                    let chunk = options.compress_chunk(&raw_data, cancel_signal)?;
+                    let data = set_operation_data(operation, chunk);
 
                     Ok((data, i_rel + operation_start, operation))
                 },
@@ -1131,16 +1965,20 @@ pub fn compress_modified_image(
             .collect::<Result<Vec<_>>>()?;
 
         for (data, _, operation) in &mut compressed_data_group {
-            operation.data_offset = Some(bytes_compressed);
-            bytes_compressed += data.len() as u64;
+            if operation.data_length.is_some() {
+                operation.data_offset = Some(bytes_compressed);
+                bytes_compressed += data.len() as u64;
+            }
         }
 
         let modified_group_operations = compressed_data_group
             .into_par_iter()
             .map(|(data, i, operation)| {
-                let mut writer = output.reopen_boxed()?;
-                writer.seek(SeekFrom::Start(operation.data_offset.unwrap()))?;
-                writer.write_all(&data)?;
+                if let Some(offset) = operation.data_offset {
+                    let mut writer = output.reopen_boxed()?;
+                    writer.seek(SeekFrom::Start(offset))?;
+                    writer.write_all(&data)?;
+                }
 
                 Ok(i..i + 1)
             })
@@ -1154,3 +1992,294 @@ pub fn compress_modified_image(
 
     Ok(util::merge_overlapping(&modified_operations))
 }
+
+/// Read the chunks in `chunks` from the image in parallel. The final chunk may
+/// be shorter than `chunk_size`.
+fn read_chunks(
+    input: &(dyn ReadSeekReopen + Sync),
+    file_size: u64,
+    chunk_size: u64,
+    chunks: Range<u64>,
+    cancel_signal: &AtomicBool,
+) -> Result<Vec<(u64, Vec<u8>)>> {
+    chunks
+        .into_par_iter()
+        .map(|chunk| -> Result<(u64, Vec<u8>)> {
+            let offset = chunk * chunk_size;
+            let size = chunk_size.min(file_size - offset);
+            let mut data = vec![0u8; size as usize];
+
+            let mut reader = input.reopen_boxed()?;
+            reader.seek(SeekFrom::Start(offset))?;
+
+            stream::check_cancel(cancel_signal)?;
+            reader.read_exact(&mut data)?;
+
+            Ok((offset, data))
+        })
+        .collect()
+}
+
+/// Where [`diff_image()`] gets the data for a block of the target image from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum BlockSource {
+    /// The block is all zeros.
+    Zero,
+    /// The block is identical to the block at this index in the source image.
+    Copy(u64),
+    /// The block must be stored in the payload.
+    Data,
+}
+
+/// An operation and the data to be written to the payload blob for it.
+type OperationData = (Vec<u8>, InstallOperation);
+
+/// Append a block to a list of extents, extending the last extent if possible.
+fn push_extent_block(extents: &mut Vec<Extent>, block: u64) {
+    if let Some(last) = extents.last_mut() {
+        if last.start_block() + last.num_blocks() == block {
+            last.num_blocks = Some(last.num_blocks() + 1);
+            return;
+        }
+    }
+
+    extents.push(Extent {
+        start_block: Some(block),
+        num_blocks: Some(1),
+    });
+}
+
+/// Compute the operations for a single chunk of the target image. Each run of
+/// blocks with the same kind of [`BlockSource`] becomes one operation.
+fn diff_chunk(
+    source_blocks: &HashMap<Vec<u8>, u64>,
+    offset: u64,
+    data: &[u8],
+    block_size: u32,
+    options: &CompressOptions,
+    cancel_signal: &AtomicBool,
+) -> Result<Vec<OperationData>> {
+    let first_block = offset / u64::from(block_size);
+    let sources = data
+        .chunks(block_size as usize)
+        .map(|block| {
+            if util::is_zero(block) {
+                return BlockSource::Zero;
+            }
+
+            let digest = crate::digest::digest(&crate::digest::SHA256, block);
+
+            match source_blocks.get(digest.as_ref()) {
+                Some(&index) => BlockSource::Copy(index),
+                None => BlockSource::Data,
+            }
+        })
+        .collect::<Vec<_>>();
+
+    let mut result = vec![];
+    let mut start = 0;
+
+    while start < sources.len() {
+        let kind = mem::discriminant(&sources[start]);
+        let end = start
+            + sources[start..]
+                .iter()
+                .take_while(|s| mem::discriminant(*s) == kind)
+                .count();
+        let raw_data = &data[start * block_size as usize..end * block_size as usize];
+
+        let mut operation = InstallOperation::default();
+        operation.dst_extents.push(Extent {
+            start_block: Some(first_block + start as u64),
+            num_blocks: Some((end - start) as u64),
+        });
+
+        let data = match sources[start] {
+            BlockSource::Zero => set_operation_data(&mut operation, None),
+            BlockSource::Copy(_) => {
+                operation.set_type(Type::SourceCopy);
+
+                for source in &sources[start..end] {
+                    if let BlockSource::Copy(index) = source {
+                        push_extent_block(&mut operation.src_extents, *index);
+                    }
+                }
+
+                let digest = crate::digest::digest(&crate::digest::SHA256, raw_data);
+                operation.src_sha256_hash = Some(digest.as_ref().to_vec());
+
+                vec![]
+            }
+            BlockSource::Data => {
+                let chunk = options.compress_chunk(raw_data, cancel_signal)?;
+                set_operation_data(&mut operation, chunk)
+            }
+        };
+
+        result.push((data, operation));
+        start = end;
+    }
+
+    Ok(result)
+}
+
+/// Compute the operations for updating the `source` image to the `target` image
+/// and return the corresponding information to insert into the payload
+/// manifest's [`PartitionUpdate`] instance. The return value contains the
+/// [`PartitionInfo`] for the source and target images, in that order.
+///
+/// Every block of the target image that also exists anywhere in the source
+/// image becomes part of a [`Type::SourceCopy`] operation. Runs of all-zero
+/// blocks become [`Type::Zero`] operations. All other runs of blocks are
+/// compressed in the same way as [`compress_image()`]. No operation spans more
+/// than one 2 MiB chunk of the target image. Like with [`compress_image()`],
+/// the caller must update [`InstallOperation::data_offset`] in each operation
+/// manually because the initial values are relative to 0.
+///
+/// The entire source image is indexed in memory by the sha256 digest of each
+/// block, so the memory usage is proportional to the size of the source image.
+pub fn diff_image(
+    source: &(dyn ReadSeekReopen + Sync),
+    target: &(dyn ReadSeekReopen + Sync),
+    output: &(dyn WriteSeekReopen + Sync),
+    partition_name: &str,
+    block_size: u32,
+    options: &CompressOptions,
+    cancel_signal: &AtomicBool,
+) -> Result<(PartitionInfo, PartitionInfo, Vec<InstallOperation>)> {
+    let chunk_group = options.group_size(32) as u64;
+
+    let chunk_size = chunk_size_for(block_size)?;
+
+    let image_size = |input: &(dyn ReadSeekReopen + Sync)| -> Result<u64> {
+        let size = input.reopen_boxed()?.seek(SeekFrom::End(0))?;
+
+        if size % u64::from(block_size) != 0 {
+            return Err(Error::InvalidPartitionSize {
+                name: partition_name.to_owned(),
+                size,
+                block_size,
+            });
+        }
+
+        Ok(size)
+    };
+    let source_size = image_size(source)?;
+    let target_size = image_size(target)?;
+
+    // Index the non-zero blocks of the source image. If a block appears more
+    // than once, the first occurrence is used.
+    let mut source_blocks = HashMap::<Vec<u8>, u64>::new();
+    let mut context_source = Context::new(&crate::digest::SHA256);
+    let source_chunks = util::div_ceil(source_size, chunk_size);
+
+    for group_start in (0..source_chunks).step_by(chunk_group as usize) {
+        let group_end = (group_start + chunk_group).min(source_chunks);
+        let group = read_chunks(
+            source,
+            source_size,
+            chunk_size,
+            group_start..group_end,
+            cancel_signal,
+        )?;
+
+        let digests_group = group
+            .par_iter()
+            .map(|(_, data)| {
+                data.chunks(block_size as usize)
+                    .map(|block| {
+                        (!util::is_zero(block)).then(|| {
+                            crate::digest::digest(&crate::digest::SHA256, block)
+                                .as_ref()
+                                .to_vec()
+                        })
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+
+        for ((offset, data), digests) in group.iter().zip(digests_group) {
+            context_source.update(data);
+
+            let first_block = offset / u64::from(block_size);
+
+            for (i, digest) in digests.into_iter().enumerate() {
+                if let Some(digest) = digest {
+                    source_blocks
+                        .entry(digest)
+                        .or_insert(first_block + i as u64);
+                }
+            }
+        }
+    }
+
+    let mut context_target = Context::new(&crate::digest::SHA256);
+    let mut bytes_written = 0;
+    let mut operations = vec![];
+    let target_chunks = util::div_ceil(target_size, chunk_size);
+
+    for group_start in (0..target_chunks).step_by(chunk_group as usize) {
+        let group_end = (group_start + chunk_group).min(target_chunks);
+        let group = read_chunks(
+            target,
+            target_size,
+            chunk_size,
+            group_start..group_end,
+            cancel_signal,
+        )?;
+
+        for (_, data) in &group {
+            context_target.update(data);
+        }
+
+        let mut operation_data_group = group
+            .into_par_iter()
+            .map(|(offset, data)| {
+                diff_chunk(
+                    &source_blocks,
+                    offset,
+                    &data,
+                    block_size,
+                    options,
+                    cancel_signal,
+                )
+            })
+            .collect::<Result<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect::<Vec<_>>();
+
+        for (data, operation) in &mut operation_data_group {
+            if operation.data_length.is_some() {
+                operation.data_offset = Some(bytes_written);
+                bytes_written += data.len() as u64;
+            }
+        }
+
+        let group_operations = operation_data_group
+            .into_par_iter()
+            .map(|(data, operation)| -> Result<InstallOperation> {
+                if let Some(offset) = operation.data_offset {
+                    let mut writer = output.reopen_boxed()?;
+                    writer.seek(SeekFrom::Start(offset))?;
+                    writer.write_all(&data)?;
+                }
+
+                Ok(operation)
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        operations.extend(group_operations);
+    }
+
+    let source_info = PartitionInfo {
+        size: Some(source_size),
+        hash: Some(context_source.finish().as_ref().to_vec()),
+    };
+    let target_info = PartitionInfo {
+        size: Some(target_size),
+        hash: Some(context_target.finish().as_ref().to_vec()),
+    };
+
+    Ok((source_info, target_info, operations))
+}
@@ -0,0 +1,230 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Writer for Android sparse images. Only what is needed for splitting large
+//! images into multiple fastboot downloads is implemented: each output image
+//! contains a single raw range and everything else is marked as "don't care".
+
+use std::{
+    io::{self, Read, Write},
+    ops::Range,
+    sync::atomic::AtomicBool,
+};
+
+use byteorder::{LittleEndian, WriteBytesExt};
+use thiserror::Error;
+
+use crate::{
+    format::padding,
+    stream::{self, WriteZerosExt},
+};
+
+const SPARSE_HEADER_MAGIC: u32 = 0xed26ff3a;
+const SPARSE_MAJOR_VERSION: u16 = 1;
+const SPARSE_MINOR_VERSION: u16 = 0;
+const FILE_HEADER_SIZE: u16 = 28;
+const CHUNK_HEADER_SIZE: u16 = 12;
+
+const CHUNK_TYPE_RAW: u16 = 0xcac1;
+const CHUNK_TYPE_DONT_CARE: u16 = 0xcac3;
+
+/// Size of the file header plus the headers of the (at most) three chunks.
+const MAX_OVERHEAD: u64 = FILE_HEADER_SIZE as u64 + 3 * CHUNK_HEADER_SIZE as u64;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Block size must be a non-zero multiple of 4: {0}")]
+    InvalidBlockSize(u32),
+    #[error("Range {range:?} is not block aligned or exceeds image size {image_size}")]
+    InvalidRange { range: Range<u64>, image_size: u64 },
+    #[error("Maximum sparse image size is too small for block size {block_size}: {max_size}")]
+    MaxSizeTooSmall { max_size: u64, block_size: u32 },
+    #[error("{0:?} field is out of bounds")]
+    FieldOutOfBounds(&'static str),
+    #[error("I/O error")]
+    Io(#[from] io::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+fn check_block_size(block_size: u32) -> Result<()> {
+    if block_size == 0 || padding::calc(block_size, 4) != 0 {
+        return Err(Error::InvalidBlockSize(block_size));
+    }
+
+    Ok(())
+}
+
+/// Split an image of `image_size` bytes into ranges that each fit in a sparse
+/// image no larger than `max_size` bytes. All ranges, except possibly the last,
+/// are block aligned.
+pub fn split(image_size: u64, max_size: u64, block_size: u32) -> Result<Vec<Range<u64>>> {
+    check_block_size(block_size)?;
+
+    let piece_size =
+        max_size.saturating_sub(MAX_OVERHEAD) / u64::from(block_size) * u64::from(block_size);
+    if piece_size == 0 {
+        return Err(Error::MaxSizeTooSmall {
+            max_size,
+            block_size,
+        });
+    }
+
+    let mut ranges = vec![];
+    let mut offset = 0;
+
+    while offset < image_size {
+        let end = offset.saturating_add(piece_size).min(image_size);
+        ranges.push(offset..end);
+        offset = end;
+    }
+
+    Ok(ranges)
+}
+
+/// Write a sparse image for an image of `image_size` bytes, where only `range`
+/// is included. The data for the range is read from `reader`. If the range is
+/// not a multiple of the block size, the last block is padded with zeros.
+pub fn write_range(
+    mut reader: impl Read,
+    mut writer: impl Write,
+    image_size: u64,
+    range: Range<u64>,
+    block_size: u32,
+    cancel_signal: &AtomicBool,
+) -> Result<()> {
+    check_block_size(block_size)?;
+
+    let block_size_64 = u64::from(block_size);
+
+    if range.start > range.end
+        || range.end > image_size
+        || padding::calc(range.start, block_size_64) != 0
+        || (padding::calc(range.end, block_size_64) != 0 && range.end != image_size)
+    {
+        return Err(Error::InvalidRange { range, image_size });
+    }
+
+    let to_blocks = |size: u64, field| -> Result<u32> {
+        padding::round(size, block_size_64)
+            .and_then(|s| u32::try_from(s / block_size_64).ok())
+            .ok_or(Error::FieldOutOfBounds(field))
+    };
+
+    let total_blocks = to_blocks(image_size, "total_blks")?;
+    let before_blocks = to_blocks(range.start, "chunk_sz")?;
+    let raw_blocks = to_blocks(range.end - range.start, "chunk_sz")?;
+    let after_blocks = total_blocks - before_blocks - raw_blocks;
+
+    let chunks = [
+        (CHUNK_TYPE_DONT_CARE, before_blocks),
+        (CHUNK_TYPE_RAW, raw_blocks),
+        (CHUNK_TYPE_DONT_CARE, after_blocks),
+    ];
+    let total_chunks = chunks.iter().filter(|(_, n)| *n > 0).count() as u32;
+
+    writer.write_u32::<LittleEndian>(SPARSE_HEADER_MAGIC)?;
+    writer.write_u16::<LittleEndian>(SPARSE_MAJOR_VERSION)?;
+    writer.write_u16::<LittleEndian>(SPARSE_MINOR_VERSION)?;
+    writer.write_u16::<LittleEndian>(FILE_HEADER_SIZE)?;
+    writer.write_u16::<LittleEndian>(CHUNK_HEADER_SIZE)?;
+    writer.write_u32::<LittleEndian>(block_size)?;
+    writer.write_u32::<LittleEndian>(total_blocks)?;
+    writer.write_u32::<LittleEndian>(total_chunks)?;
+    // The checksum is optional and is not verified by fastboot.
+    writer.write_u32::<LittleEndian>(0)?;
+
+    for (chunk_type, blocks) in chunks {
+        if blocks == 0 {
+            continue;
+        }
+
+        let data_size = if chunk_type == CHUNK_TYPE_RAW {
+            u64::from(blocks) * block_size_64
+        } else {
+            0
+        };
+        let total_size = u32::try_from(u64::from(CHUNK_HEADER_SIZE) + data_size)
+            .map_err(|_| Error::FieldOutOfBounds("total_sz"))?;
+
+        writer.write_u16::<LittleEndian>(chunk_type)?;
+        writer.write_u16::<LittleEndian>(0)?;
+        writer.write_u32::<LittleEndian>(blocks)?;
+        writer.write_u32::<LittleEndian>(total_size)?;
+
+        if chunk_type == CHUNK_TYPE_RAW {
+            let size = range.end - range.start;
+
+            stream::copy_n(&mut reader, &mut writer, size, cancel_signal)?;
+            writer.write_zeros_exact(data_size - size)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the size of the sparse image that [`write_range()`] would write.
+pub fn range_size(image_size: u64, range: Range<u64>, block_size: u32) -> Result<u64> {
+    check_block_size(block_size)?;
+
+    let raw_size = padding::round(range.end - range.start, u64::from(block_size))
+        .ok_or(Error::FieldOutOfBounds("chunk_sz"))?;
+    let mut size = u64::from(FILE_HEADER_SIZE) + u64::from(CHUNK_HEADER_SIZE) + raw_size;
+
+    if range.start > 0 {
+        size += u64::from(CHUNK_HEADER_SIZE);
+    }
+    if padding::round(range.end, u64::from(block_size))
+        < padding::round(image_size, u64::from(block_size))
+    {
+        size += u64::from(CHUNK_HEADER_SIZE);
+    }
+
+    Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    #[test]
+    fn split_ranges() {
+        assert_eq!(
+            split(10000, 4096 + MAX_OVERHEAD, 4096).unwrap(),
+            vec![0..4096, 4096..8192, 8192..10000],
+        );
+        assert_eq!(split(0, 8192, 4096).unwrap(), vec![]);
+        assert!(matches!(
+            split(10000, 4096, 4096),
+            Err(Error::MaxSizeTooSmall { .. }),
+        ));
+    }
+
+    #[test]
+    fn write_middle_range() {
+        let cancel_signal = AtomicBool::new(false);
+        let data = [0xaau8; 6];
+        let mut output = Cursor::new(vec![]);
+
+        write_range(&data[..], &mut output, 16, 4..10, 4, &cancel_signal).unwrap_err();
+        write_range(&data[..], &mut output, 14, 8..14, 4, &cancel_signal).unwrap();
+
+        let output = output.into_inner();
+        assert_eq!(output.len() as u64, range_size(14, 8..14, 4).unwrap());
+        // total_blks and total_chunks.
+        assert_eq!(&output[16..20], &4u32.to_le_bytes());
+        assert_eq!(&output[20..24], &2u32.to_le_bytes());
+        // Don't care chunk covering the first two blocks.
+        assert_eq!(&output[28..30], &CHUNK_TYPE_DONT_CARE.to_le_bytes());
+        assert_eq!(&output[32..36], &2u32.to_le_bytes());
+        // Raw chunk with the last block padded.
+        assert_eq!(&output[40..42], &CHUNK_TYPE_RAW.to_le_bytes());
+        assert_eq!(&output[44..48], &2u32.to_le_bytes());
+        assert_eq!(&output[48..52], &20u32.to_le_bytes());
+        assert_eq!(&output[52..], &[0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0xaa, 0, 0]);
+    }
+}
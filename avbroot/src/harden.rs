@@ -0,0 +1,182 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Kernel-enforced process hardening on Linux.
+//!
+//! Unlike [`crate::sandbox`], which only covers filesystem access performed by
+//! avbroot itself, the restrictions here are enforced by the kernel and apply
+//! to everything running in the process, including bugs in the parsers for
+//! untrusted input. Filesystem access is restricted with Landlock and
+//! dangerous syscalls are blocked with a seccomp filter. Neither can be undone
+//! once applied.
+
+use std::{
+    path::PathBuf,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use thiserror::Error;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Process hardening is only supported on Linux")]
+    UnsupportedOs,
+    #[error("Landlock is not supported by the running kernel")]
+    LandlockUnsupported,
+    #[cfg(target_os = "linux")]
+    #[error("Failed to apply Landlock ruleset")]
+    Landlock(#[from] landlock::RulesetError),
+    #[cfg(target_os = "linux")]
+    #[error("Failed to apply seccomp filter")]
+    Seccomp(#[from] seccompiler::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+static APPLIED: AtomicBool = AtomicBool::new(false);
+
+/// How completely the Landlock ruleset is enforced.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Enforcement {
+    /// Every requested access right is restricted.
+    Full,
+    /// The running kernel only supports an older Landlock ABI, so some access
+    /// rights (eg. truncation) are not restricted.
+    Partial,
+}
+
+/// Syscalls that avbroot never needs. These are denied with `EPERM`.
+#[cfg(target_os = "linux")]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    // Networking.
+    libc::SYS_socket,
+    libc::SYS_socketpair,
+    libc::SYS_connect,
+    libc::SYS_bind,
+    libc::SYS_listen,
+    libc::SYS_accept,
+    libc::SYS_accept4,
+    // Running other programs.
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    // Inspecting or modifying other processes.
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    // Changing the filesystem or namespace layout.
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_setns,
+    libc::SYS_unshare,
+    // Kernel and system administration.
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_kexec_load,
+    libc::SYS_reboot,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+];
+
+/// Paths that are needed regardless of the command being run, in addition to
+/// the user-specified directories.
+#[cfg(target_os = "linux")]
+fn implicit_paths() -> Vec<PathBuf> {
+    let mut paths = vec![std::env::temp_dir()];
+
+    // Needed for interactive passphrase prompts.
+    let tty = std::path::Path::new("/dev/tty");
+    if tty.exists() {
+        paths.push(tty.to_owned());
+    }
+
+    paths
+}
+
+#[cfg(target_os = "linux")]
+fn apply_landlock(dirs: &[PathBuf]) -> Result<Enforcement> {
+    use landlock::{
+        path_beneath_rules, Access, AccessFs, Ruleset, RulesetAttr, RulesetCreatedAttr,
+        RulesetStatus, ABI,
+    };
+
+    let access = AccessFs::from_all(ABI::V2);
+    let paths = dirs.iter().cloned().chain(implicit_paths());
+
+    let status = Ruleset::default()
+        .handle_access(access)?
+        .create()?
+        .add_rules(path_beneath_rules(paths, access))?
+        .restrict_self()?;
+
+    match status.ruleset {
+        RulesetStatus::FullyEnforced => Ok(Enforcement::Full),
+        RulesetStatus::PartiallyEnforced => Ok(Enforcement::Partial),
+        RulesetStatus::NotEnforced => Err(Error::LandlockUnsupported),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn apply_seccomp() -> Result<()> {
+    use std::collections::BTreeMap;
+
+    use seccompiler::{BpfProgram, SeccompAction, SeccompFilter};
+
+    // c_long is only i32 on 32-bit targets.
+    #[allow(clippy::useless_conversion)]
+    let rules = DENIED_SYSCALLS
+        .iter()
+        .map(|s| (i64::from(*s), vec![]))
+        .collect::<BTreeMap<_, _>>();
+
+    let arch = std::env::consts::ARCH
+        .try_into()
+        .map_err(seccompiler::Error::Backend)?;
+    let filter = SeccompFilter::new(
+        rules,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )
+    .map_err(seccompiler::Error::Backend)?;
+    let program: BpfProgram = filter.try_into().map_err(seccompiler::Error::Backend)?;
+
+    // Threads, like the signal handler thread, may already exist.
+    seccompiler::apply_filter_all_threads(&program)?;
+
+    Ok(())
+}
+
+/// Restrict filesystem access to `dirs` (plus the temporary directory) and
+/// block syscalls that are never needed. This affects the calling thread and
+/// all threads spawned afterwards. The seccomp filter also applies to existing
+/// threads.
+#[cfg(target_os = "linux")]
+pub fn apply(dirs: &[PathBuf]) -> Result<Enforcement> {
+    let enforcement = apply_landlock(dirs)?;
+    apply_seccomp()?;
+
+    APPLIED.store(true, Ordering::Relaxed);
+
+    Ok(enforcement)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn apply(_dirs: &[PathBuf]) -> Result<Enforcement> {
+    Err(Error::UnsupportedOs)
+}
+
+/// Whether the restrictions from [`apply()`] are in effect. Features that run
+/// other programs or access the network cannot work if this is true.
+pub fn is_applied() -> bool {
+    APPLIED.load(Ordering::Relaxed)
+}
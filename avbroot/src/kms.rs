@@ -0,0 +1,630 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Signing with RSA keys that are stored in a cloud key management service.
+//!
+//! Keys are specified with URIs:
+//!
+//! ```text
+//! awskms:arn:aws:kms:us-east-1:111122223333:key/1234abcd-12ab-34cd-56ef-1234567890ab
+//! gcpkms:projects/<project>/locations/<location>/keyRings/<ring>/cryptoKeys/<key>/cryptoKeyVersions/<version>
+//! azurekv:<vault>.vault.azure.net/keys/<key>/<version>
+//! ```
+//!
+//! Credentials are only ever read from environment variables so that they do
+//! not end up in command lines or logs. The private key never leaves the KMS.
+
+use std::{
+    env, fmt,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use base64::{
+    alphabet,
+    engine::{
+        general_purpose::{GeneralPurpose, GeneralPurposeConfig, STANDARD},
+        DecodePaddingMode,
+    },
+    Engine,
+};
+use pkcs8::DecodePublicKey;
+use ring::{digest, hmac};
+use rsa::{BigUint, RsaPublicKey};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::json;
+use thiserror::Error;
+
+use crate::{crypto::BlobDigest, util};
+
+pub const AWS_PREFIX: &str = "awskms:";
+pub const GCP_PREFIX: &str = "gcpkms:";
+pub const AZURE_PREFIX: &str = "azurekv:";
+
+const AWS_ACCESS_KEY_ID_VAR: &str = "AWS_ACCESS_KEY_ID";
+const AWS_SECRET_ACCESS_KEY_VAR: &str = "AWS_SECRET_ACCESS_KEY";
+const AWS_SESSION_TOKEN_VAR: &str = "AWS_SESSION_TOKEN";
+const AWS_REGION_VARS: [&str; 2] = ["AWS_REGION", "AWS_DEFAULT_REGION"];
+const GCP_TOKEN_VAR: &str = "GOOGLE_OAUTH_ACCESS_TOKEN";
+const AZURE_TOKEN_VAR: &str = "AZURE_ACCESS_TOKEN";
+
+const AZURE_API_VERSION: &str = "7.4";
+
+/// Base64url with optional padding, as used by Azure Key Vault.
+const URL_SAFE_LENIENT: GeneralPurpose = GeneralPurpose::new(
+    &alphabet::URL_SAFE,
+    GeneralPurposeConfig::new()
+        .with_encode_padding(false)
+        .with_decode_padding_mode(DecodePaddingMode::Indifferent),
+);
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid KMS key URI: {0:?}")]
+    InvalidUri(String),
+    #[error("Environment variable is not set: {0}")]
+    MissingEnvVar(&'static str),
+    #[error("Key ARN has no region and neither AWS_REGION nor AWS_DEFAULT_REGION is set")]
+    NoRegion,
+    #[error("{0} does not support {1} signatures")]
+    UnsupportedDigest(&'static str, &'static str),
+    #[error("Request to {0} failed")]
+    Request(String, #[source] Box<ureq::Error>),
+    #[error("Request to {0} failed with status {1}: {2}")]
+    Status(String, u16, String),
+    #[error("Invalid response from {0}")]
+    InvalidResponse(String, #[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("KMS returned an invalid signature")]
+    InvalidSignature(#[source] rsa::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Check if `uri` refers to a key in a cloud KMS.
+pub fn is_kms_uri(uri: &str) -> bool {
+    [AWS_PREFIX, GCP_PREFIX, AZURE_PREFIX]
+        .iter()
+        .any(|p| uri.starts_with(p))
+}
+
+/// A credential that must never be printed.
+struct Secret(String);
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("<redacted>")
+    }
+}
+
+fn env_secret(name: &'static str) -> Result<Secret> {
+    env::var(name)
+        .ok()
+        .filter(|v| !v.is_empty())
+        .map(Secret)
+        .ok_or(Error::MissingEnvVar(name))
+}
+
+#[derive(Debug)]
+struct AwsCredentials {
+    access_key_id: String,
+    secret_access_key: Secret,
+    session_token: Option<Secret>,
+}
+
+impl AwsCredentials {
+    fn from_env() -> Result<Self> {
+        Ok(Self {
+            access_key_id: env_secret(AWS_ACCESS_KEY_ID_VAR)?.0,
+            secret_access_key: env_secret(AWS_SECRET_ACCESS_KEY_VAR)?,
+            session_token: env_secret(AWS_SESSION_TOKEN_VAR).ok(),
+        })
+    }
+}
+
+/// Compute the AWS Signature Version 4 `Authorization` header value for a
+/// request with no query string. `headers` must have lowercase names, be sorted
+/// by name, and include `x-amz-date`.
+fn aws_authorization(
+    credentials: &AwsCredentials,
+    region: &str,
+    service: &str,
+    method: &str,
+    path: &str,
+    headers: &[(&str, &str)],
+    body: &[u8],
+) -> String {
+    let amz_date = headers
+        .iter()
+        .find(|(n, _)| *n == "x-amz-date")
+        .map(|(_, v)| *v)
+        .expect("x-amz-date header is required");
+    let date = &amz_date[..8];
+
+    let mut canonical_headers = String::new();
+    for (name, value) in headers {
+        canonical_headers.push_str(name);
+        canonical_headers.push(':');
+        canonical_headers.push_str(value.trim());
+        canonical_headers.push('\n');
+    }
+
+    let signed_headers = headers
+        .iter()
+        .map(|(n, _)| *n)
+        .collect::<Vec<_>>()
+        .join(";");
+    let body_digest = hex::encode(digest::digest(&digest::SHA256, body));
+    let canonical_request =
+        format!("{method}\n{path}\n\n{canonical_headers}\n{signed_headers}\n{body_digest}");
+
+    let scope = format!("{date}/{region}/{service}/aws4_request");
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{amz_date}\n{scope}\n{}",
+        hex::encode(digest::digest(
+            &digest::SHA256,
+            canonical_request.as_bytes()
+        )),
+    );
+
+    let mut key = format!("AWS4{}", credentials.secret_access_key.0).into_bytes();
+    for part in [date, region, service, "aws4_request"] {
+        let tag = hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, &key), part.as_bytes());
+        key = tag.as_ref().to_vec();
+    }
+    let signature = hmac::sign(
+        &hmac::Key::new(hmac::HMAC_SHA256, &key),
+        string_to_sign.as_bytes(),
+    );
+
+    format!(
+        "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders={signed_headers}, Signature={}",
+        credentials.access_key_id,
+        hex::encode(signature.as_ref()),
+    )
+}
+
+#[derive(Debug)]
+enum Provider {
+    Aws {
+        region: String,
+        key_id: String,
+        credentials: AwsCredentials,
+    },
+    Gcp {
+        name: String,
+        token: Secret,
+    },
+    Azure {
+        url: String,
+        token: Secret,
+    },
+}
+
+impl Provider {
+    fn parse(uri: &str) -> Result<Self> {
+        let invalid = || Error::InvalidUri(uri.to_owned());
+
+        if let Some(key_id) = uri.strip_prefix(AWS_PREFIX) {
+            if key_id.is_empty() || key_id.contains(char::is_whitespace) {
+                return Err(invalid());
+            }
+
+            // arn:aws:kms:<region>:<account>:key/<id>
+            let region = match key_id.strip_prefix("arn:") {
+                Some(arn) => Some(
+                    arn.split(':')
+                        .nth(2)
+                        .filter(|r| !r.is_empty())
+                        .ok_or_else(invalid)?
+                        .to_owned(),
+                ),
+                None => AWS_REGION_VARS
+                    .iter()
+                    .find_map(|v| env::var(v).ok().filter(|r| !r.is_empty())),
+            }
+            .ok_or(Error::NoRegion)?;
+
+            Ok(Self::Aws {
+                region,
+                key_id: key_id.to_owned(),
+                credentials: AwsCredentials::from_env()?,
+            })
+        } else if let Some(name) = uri.strip_prefix(GCP_PREFIX) {
+            let components = name.split('/').collect::<Vec<_>>();
+            let labels = [
+                "projects",
+                "locations",
+                "keyRings",
+                "cryptoKeys",
+                "cryptoKeyVersions",
+            ];
+
+            if components.len() != labels.len() * 2
+                || components.iter().any(|c| c.is_empty())
+                || components.iter().step_by(2).ne(labels.iter())
+            {
+                return Err(invalid());
+            }
+
+            Ok(Self::Gcp {
+                name: name.to_owned(),
+                token: env_secret(GCP_TOKEN_VAR)?,
+            })
+        } else if let Some(rest) = uri.strip_prefix(AZURE_PREFIX) {
+            let rest = rest.strip_prefix("https://").unwrap_or(rest);
+            let components = rest.split('/').collect::<Vec<_>>();
+
+            // <vault host>/keys/<name>/<version>
+            if components.len() != 4
+                || components.iter().any(|c| c.is_empty())
+                || components[1] != "keys"
+            {
+                return Err(invalid());
+            }
+
+            Ok(Self::Azure {
+                url: format!("https://{rest}"),
+                token: env_secret(AZURE_TOKEN_VAR)?,
+            })
+        } else {
+            Err(invalid())
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Aws { .. } => "AWS KMS",
+            Self::Gcp { .. } => "Google Cloud KMS",
+            Self::Azure { .. } => "Azure Key Vault",
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AwsPublicKeyResponse {
+    public_key: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct AwsSignResponse {
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct GcpPublicKeyResponse {
+    pem: String,
+}
+
+#[derive(Deserialize)]
+struct GcpSignResponse {
+    signature: String,
+}
+
+#[derive(Deserialize)]
+struct AzureJsonWebKey {
+    n: String,
+    e: String,
+}
+
+#[derive(Deserialize)]
+struct AzureKeyResponse {
+    key: AzureJsonWebKey,
+}
+
+#[derive(Deserialize)]
+struct AzureSignResponse {
+    value: String,
+}
+
+fn invalid_response(url: &str, e: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> Error {
+    Error::InvalidResponse(url.to_owned(), e.into())
+}
+
+/// An RSA key stored in a cloud KMS. The public key is fetched when the key is
+/// opened and every signature is verified against it.
+#[derive(Debug)]
+pub struct KmsKey {
+    agent: ureq::Agent,
+    provider: Provider,
+    public_key: RsaPublicKey,
+}
+
+impl KmsKey {
+    /// Open the key referred to by `uri` and fetch its public key.
+    pub fn open(uri: &str) -> Result<Self> {
+        let provider = Provider::parse(uri)?;
+        let agent = ureq::AgentBuilder::new()
+            .timeout(Duration::from_secs(60))
+            .build();
+
+        let public_key = match &provider {
+            Provider::Aws { key_id, .. } => {
+                let (url, response): (_, AwsPublicKeyResponse) = Self::aws_call(
+                    &agent,
+                    &provider,
+                    "GetPublicKey",
+                    &json!({ "KeyId": key_id }),
+                )?;
+                let der = STANDARD
+                    .decode(response.public_key)
+                    .map_err(|e| invalid_response(&url, e))?;
+
+                RsaPublicKey::from_public_key_der(&der).map_err(|e| invalid_response(&url, e))?
+            }
+            Provider::Gcp { name, token } => {
+                let url = format!("https://cloudkms.googleapis.com/v1/{name}/publicKey");
+                let response: GcpPublicKeyResponse =
+                    Self::call(agent.get(&url).set("Authorization", &bearer(token)), None)?;
+
+                RsaPublicKey::from_public_key_pem(&response.pem)
+                    .map_err(|e| invalid_response(&url, e))?
+            }
+            Provider::Azure { url, token } => {
+                let url = format!("{url}?api-version={AZURE_API_VERSION}");
+                let response: AzureKeyResponse =
+                    Self::call(agent.get(&url).set("Authorization", &bearer(token)), None)?;
+
+                let decode = |v: &str| {
+                    URL_SAFE_LENIENT
+                        .decode(v)
+                        .map(|b| BigUint::from_bytes_be(&b))
+                        .map_err(|e| invalid_response(&url, e))
+                };
+
+                RsaPublicKey::new(decode(&response.key.n)?, decode(&response.key.e)?)
+                    .map_err(|e| invalid_response(&url, e))?
+            }
+        };
+
+        Ok(Self {
+            agent,
+            provider,
+            public_key,
+        })
+    }
+
+    pub fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+
+    /// Create a PKCS#1 v1.5 signature of `digest` in the KMS. The signature is
+    /// verified before it is returned.
+    pub fn sign(&self, algorithm: BlobDigest, digest: &[u8]) -> Result<Vec<u8>> {
+        let unsupported = || Error::UnsupportedDigest(self.provider.name(), algorithm.name());
+
+        let signature = match &self.provider {
+            Provider::Aws { key_id, .. } => {
+                let signing_algorithm = match algorithm {
+                    BlobDigest::Sha1 => return Err(unsupported()),
+                    BlobDigest::Sha256 => "RSASSA_PKCS1_V1_5_SHA_256",
+                    BlobDigest::Sha512 => "RSASSA_PKCS1_V1_5_SHA_512",
+                };
+
+                let (url, response): (_, AwsSignResponse) = Self::aws_call(
+                    &self.agent,
+                    &self.provider,
+                    "Sign",
+                    &json!({
+                        "KeyId": key_id,
+                        "Message": STANDARD.encode(digest),
+                        "MessageType": "DIGEST",
+                        "SigningAlgorithm": signing_algorithm,
+                    }),
+                )?;
+
+                STANDARD
+                    .decode(response.signature)
+                    .map_err(|e| invalid_response(&url, e))?
+            }
+            Provider::Gcp { name, token } => {
+                if algorithm == BlobDigest::Sha1 {
+                    return Err(unsupported());
+                }
+
+                let url = format!("https://cloudkms.googleapis.com/v1/{name}:asymmetricSign");
+                let body = json!({
+                    "digest": { algorithm.name(): STANDARD.encode(digest) },
+                });
+                let response: GcpSignResponse = Self::call(
+                    self.agent
+                        .post(&url)
+                        .set("Authorization", &bearer(token))
+                        .set("Content-Type", "application/json"),
+                    Some(body.to_string().as_bytes()),
+                )?;
+
+                STANDARD
+                    .decode(response.signature)
+                    .map_err(|e| invalid_response(&url, e))?
+            }
+            Provider::Azure { url, token } => {
+                let alg = match algorithm {
+                    BlobDigest::Sha1 => return Err(unsupported()),
+                    BlobDigest::Sha256 => "RS256",
+                    BlobDigest::Sha512 => "RS512",
+                };
+
+                let url = format!("{url}/sign?api-version={AZURE_API_VERSION}");
+                let body = json!({
+                    "alg": alg,
+                    "value": URL_SAFE_LENIENT.encode(digest),
+                });
+                let response: AzureSignResponse = Self::call(
+                    self.agent
+                        .post(&url)
+                        .set("Authorization", &bearer(token))
+                        .set("Content-Type", "application/json"),
+                    Some(body.to_string().as_bytes()),
+                )?;
+
+                URL_SAFE_LENIENT
+                    .decode(response.value)
+                    .map_err(|e| invalid_response(&url, e))?
+            }
+        };
+
+        self.public_key
+            .verify(algorithm.pkcs1v15(), digest, &signature)
+            .map_err(Error::InvalidSignature)?;
+
+        Ok(signature)
+    }
+
+    /// Send a request and parse the JSON response. If there is a body, the
+    /// request must already have its `Content-Type` set.
+    fn call<T: DeserializeOwned>(request: ureq::Request, body: Option<&[u8]>) -> Result<T> {
+        let url = request.url().to_owned();
+
+        let result = match body {
+            Some(b) => request.send_bytes(b),
+            None => request.call(),
+        };
+
+        let response = match result {
+            Ok(r) => r,
+            Err(ureq::Error::Status(code, r)) => {
+                // Error responses describe the problem (eg. missing
+                // permissions) and never contain key material.
+                let message = r.into_string().unwrap_or_default();
+                return Err(Error::Status(url, code, message));
+            }
+            Err(e) => return Err(Error::Request(url, Box::new(e))),
+        };
+
+        let data = response
+            .into_string()
+            .map_err(|e| invalid_response(&url, e))?;
+
+        serde_json::from_str(&data).map_err(|e| invalid_response(&url, e))
+    }
+
+    /// Call an AWS KMS API action with a request signed by the credentials from
+    /// the environment.
+    fn aws_call<T: DeserializeOwned>(
+        agent: &ureq::Agent,
+        provider: &Provider,
+        action: &str,
+        body: &serde_json::Value,
+    ) -> Result<(String, T)> {
+        let Provider::Aws {
+            region,
+            credentials,
+            ..
+        } = provider
+        else {
+            unreachable!("Not an AWS provider");
+        };
+
+        let host = format!("kms.{region}.amazonaws.com");
+        let url = format!("https://{host}/");
+        let body = body.to_string();
+        let amz_date = util::format_utc_timestamp(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs()),
+        );
+        let target = format!("TrentService.{action}");
+        let content_type = "application/x-amz-json-1.1";
+
+        let mut headers = vec![
+            ("content-type", content_type),
+            ("host", host.as_str()),
+            ("x-amz-date", amz_date.as_str()),
+        ];
+        if let Some(token) = &credentials.session_token {
+            headers.push(("x-amz-security-token", token.0.as_str()));
+        }
+        headers.push(("x-amz-target", target.as_str()));
+
+        let authorization = aws_authorization(
+            credentials,
+            region,
+            "kms",
+            "POST",
+            "/",
+            &headers,
+            body.as_bytes(),
+        );
+
+        let mut request = agent.post(&url).set("Authorization", &authorization);
+        for (name, value) in &headers {
+            if *name != "host" {
+                request = request.set(name, value);
+            }
+        }
+
+        let response = Self::call(request, Some(body.as_bytes()))?;
+
+        Ok((url, response))
+    }
+}
+
+fn bearer(token: &Secret) -> String {
+    format!("Bearer {}", token.0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sigv4_vanilla() {
+        // get-vanilla from the AWS Signature Version 4 test suite.
+        let credentials = AwsCredentials {
+            access_key_id: "AKIDEXAMPLE".to_owned(),
+            secret_access_key: Secret("wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY".to_owned()),
+            session_token: None,
+        };
+
+        let authorization = aws_authorization(
+            &credentials,
+            "us-east-1",
+            "service",
+            "GET",
+            "/",
+            &[
+                ("host", "example.amazonaws.com"),
+                ("x-amz-date", "20150830T123600Z"),
+            ],
+            b"",
+        );
+
+        assert_eq!(
+            authorization,
+            "AWS4-HMAC-SHA256 Credential=AKIDEXAMPLE/20150830/us-east-1/service/aws4_request, \
+            SignedHeaders=host;x-amz-date, \
+            Signature=5fa00fa31553b73ebf1942676e86291e8372ff2a2260956d9b8aae1d763fbf31",
+        );
+    }
+
+    #[test]
+    fn parse_uri() {
+        assert!(is_kms_uri("awskms:alias/avb"));
+        assert!(is_kms_uri("gcpkms:projects/p"));
+        assert!(is_kms_uri("azurekv:v.vault.azure.net/keys/k/1"));
+        assert!(!is_kms_uri("pkcs11:token=avbroot"));
+        assert!(!is_kms_uri("/path/to/awskms:key"));
+
+        assert!(matches!(
+            Provider::parse("gcpkms:projects/p/locations/l/keyRings/r/cryptoKeys/k"),
+            Err(Error::InvalidUri(_)),
+        ));
+        assert!(matches!(
+            Provider::parse(
+                "gcpkms:projects/p/regions/l/keyRings/r/cryptoKeys/k/cryptoKeyVersions/1"
+            ),
+            Err(Error::InvalidUri(_)),
+        ));
+        assert!(matches!(
+            Provider::parse("azurekv:v.vault.azure.net/secrets/k/1"),
+            Err(Error::InvalidUri(_)),
+        ));
+        assert!(matches!(
+            Provider::parse("awskms:arn:aws:kms::111122223333:key/1234"),
+            Err(Error::InvalidUri(_)),
+        ));
+    }
+}
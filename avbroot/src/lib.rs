@@ -13,12 +13,22 @@
 // We use pb-rs' nostd mode. See build.rs.
 extern crate alloc;
 
+pub mod audit;
+pub mod blobcache;
+pub mod blockdev;
 pub mod cli;
 pub mod crypto;
+pub mod detached;
+pub mod digest;
 pub mod escape;
+pub mod fastboot;
 pub mod format;
+pub mod harden;
+pub mod kms;
 pub mod octal;
 pub mod patch;
+pub mod pkcs11;
 pub mod protobuf;
+pub mod sandbox;
 pub mod stream;
 pub mod util;
@@ -6,11 +6,10 @@
 use std::{
     cmp::Ordering,
     collections::{HashMap, HashSet},
-    fs::File,
     io::{self, BufRead, BufReader, Cursor, Read, Seek},
     num::ParseIntError,
-    ops::Range,
     path::{Path, PathBuf},
+    process::{Command, ExitStatus},
     slice,
     sync::atomic::AtomicBool,
 };
@@ -22,21 +21,24 @@ use liblzma::{
 };
 use rayon::iter::{IntoParallelRefIterator, IntoParallelRefMutIterator, ParallelIterator};
 use regex::bytes::Regex;
-use ring::digest::Context;
-use rsa::RsaPrivateKey;
 use thiserror::Error;
 use x509_cert::Certificate;
 use zip::{result::ZipError, ZipArchive};
 
 use crate::{
-    crypto,
+    crypto::{self, RsaSigningKey},
+    digest::Context,
     format::{
-        avb::{self, AppendedDescriptorMut, Footer, Header},
+        avb::{self, AlgorithmType, AppendedDescriptorMut, Footer, Header},
         bootimage::{self, BootImage, BootImageExt, RamdiskMeta},
         compression::{self, CompressedFormat, CompressedReader, CompressedWriter},
         cpio::{self, CpioEntry, CpioEntryData},
     },
-    patch::otacert::{self, OtaCertBuildFlags},
+    patch::{
+        magisk_compat::{MagiskCompatDb, MagiskCompatEntry},
+        otacert::{self, OtaCertBuildFlags},
+    },
+    sandbox,
     stream::{self, FromReader, HashingWriter, ReadSeek, SectionReader, ToWriter, WriteSeek},
 };
 
@@ -74,6 +76,14 @@ pub enum Error {
     Io(#[from] io::Error),
     #[error("File I/O error")]
     File(PathBuf, #[source] io::Error),
+    #[error("Failed to run command: {0:?}")]
+    CommandSpawn(PathBuf, #[source] io::Error),
+    #[error("Command {0:?} failed: {1}")]
+    CommandFailed(PathBuf, ExitStatus),
+    #[error("Failed to serialize boot image header")]
+    HeaderSerialize(#[from] toml_edit::ser::Error),
+    #[error("Failed to parse boot image header")]
+    HeaderDeserialize(#[from] toml_edit::de::Error),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -102,6 +112,74 @@ fn save_ramdisk(
     Ok(raw_writer.into_inner())
 }
 
+/// Load the first ramdisk of a boot image. If it doesn't exist, an empty entry
+/// list is returned so that a new ramdisk can be generated from scratch.
+fn load_first_ramdisk(
+    boot_image: &BootImage,
+    cancel_signal: &AtomicBool,
+) -> Result<(Vec<CpioEntry>, CompressedFormat)> {
+    let ramdisk = match boot_image {
+        BootImage::V0Through2(b) => Some(&b.ramdisk),
+        BootImage::V3Through4(b) => Some(&b.ramdisk),
+        BootImage::VendorV3Through4(b) => b.ramdisks.first(),
+    };
+
+    match ramdisk {
+        Some(r) if !r.is_empty() => load_ramdisk(r, cancel_signal),
+        _ => Ok((vec![], CompressedFormat::Lz4Legacy)),
+    }
+}
+
+/// Replace the first ramdisk of a boot image, adding one if needed.
+fn set_first_ramdisk(boot_image: &mut BootImage, new_ramdisk: Vec<u8>) {
+    match boot_image {
+        BootImage::V0Through2(b) => b.ramdisk = new_ramdisk,
+        BootImage::V3Through4(b) => b.ramdisk = new_ramdisk,
+        BootImage::VendorV3Through4(b) => {
+            if b.ramdisks.is_empty() {
+                b.ramdisks.push(new_ramdisk);
+
+                if let Some(v4) = &mut b.v4_extra {
+                    v4.ramdisk_metas.push(RamdiskMeta {
+                        ramdisk_type: bootimage::VENDOR_RAMDISK_TYPE_NONE,
+                        ramdisk_name: String::new(),
+                        board_id: Default::default(),
+                    });
+                }
+            } else {
+                b.ramdisks[0] = new_ramdisk;
+            }
+        }
+    }
+}
+
+/// Find the image containing the ramdisk that should be rooted. This is
+/// `init_boot` if it exists and `boot` otherwise. The exception is the
+/// recovery-as-root layout used by some system-as-root and A-only devices,
+/// where `boot` has no ramdisk and the device boots to `recovery` for root.
+fn find_root_target<'a, 'b>(
+    boot_images: impl IntoIterator<Item = (&'a str, &'b BootImage)>,
+) -> Option<&'a str> {
+    let boot_images = boot_images.into_iter().collect::<HashMap<_, _>>();
+
+    let has_ramdisk = |image: &BootImage| match image {
+        BootImage::V0Through2(b) => !b.ramdisk.is_empty(),
+        BootImage::V3Through4(b) => !b.ramdisk.is_empty(),
+        BootImage::VendorV3Through4(b) => b.ramdisks.iter().any(|r| !r.is_empty()),
+    };
+
+    if let Some((name, _)) = boot_images.get_key_value("init_boot") {
+        Some(name)
+    } else if let Some((name, image)) = boot_images.get_key_value("boot") {
+        match boot_images.get_key_value("recovery") {
+            Some((recovery, _)) if !has_ramdisk(image) => Some(recovery),
+            _ => Some(name),
+        }
+    } else {
+        None
+    }
+}
+
 pub struct BootImageInfo {
     pub header: Header,
     pub footer: Footer,
@@ -127,54 +205,29 @@ pub trait BootImagePatch {
 /// Root a boot image with Magisk.
 pub struct MagiskRootPatcher {
     apk_path: PathBuf,
-    version: u32,
+    compat: MagiskCompatEntry,
     preinit_device: Option<String>,
     random_seed: u64,
+    target: Option<String>,
 }
 
 impl MagiskRootPatcher {
-    // - Versions <25102 are not supported because they're missing commit
-    //   1f8c063dc64806c4f7320ed66c785ff7bc116383, which would leave devices
-    //   that use Android 13 GKIs unable to boot into recovery
-    // - Versions 25207 through 25210 are not supported because they used the
-    //   RULESDEVICE config option, which stored the writable block device as an
-    //   rdev major/minor pair, which was not consistent across reboots and was
-    //   replaced by PREINITDEVICE
-    const VERS_SUPPORTED: &'static [Range<u32>] = &[25102..25207, 25211..26500];
-    const VER_PREINIT_DEVICE: Range<u32> =
-        25211..Self::VERS_SUPPORTED[Self::VERS_SUPPORTED.len() - 1].end;
-    const VER_RANDOM_SEED: Range<u32> = 25211..26103;
-
+    /// Create a new Magisk patcher. The Magisk version is checked against
+    /// `compat_db` to make sure that it is supported, that the required
+    /// options are specified, and that it supports the OTA's Android SDK level
+    /// (`sdk`), if known.
     pub fn new(
         path: &Path,
         preinit_device: Option<&str>,
         random_seed: Option<u64>,
         ignore_compatibility: bool,
+        compat_db: &MagiskCompatDb,
+        sdk: Option<u32>,
         warning_fn: impl Fn(&str) + Send + 'static,
     ) -> Result<Self> {
         let version = Self::get_version(path)?;
 
-        if !Self::VERS_SUPPORTED.iter().any(|v| v.contains(&version)) {
-            let msg = format!(
-                "Unsupported Magisk version {} (supported: {:?})",
-                version,
-                Self::VERS_SUPPORTED,
-            );
-
-            if ignore_compatibility {
-                warning_fn(&msg);
-            } else {
-                return Err(Error::Validation(msg));
-            }
-        }
-
-        if preinit_device.is_none() && Self::VER_PREINIT_DEVICE.contains(&version) {
-            let msg = format!(
-                "Magisk version {} ({:?}) requires a preinit device to be specified",
-                version,
-                Self::VER_PREINIT_DEVICE,
-            );
-
+        for msg in compat_db.check(version, preinit_device.is_some(), sdk) {
             if ignore_compatibility {
                 warning_fn(&msg);
             } else {
@@ -184,16 +237,25 @@ impl MagiskRootPatcher {
 
         Ok(Self {
             apk_path: path.to_owned(),
-            version,
+            compat: compat_db.find(version).cloned().unwrap_or_default(),
             preinit_device: preinit_device.map(|d| d.to_owned()),
             // Use a hardcoded random seed by default to ensure byte-for-byte
             // reproducibility.
             random_seed: random_seed.unwrap_or(0xfedcba9876543210),
+            target: None,
         })
     }
 
-    fn get_version(path: &Path) -> Result<u32> {
-        let reader = File::open(path).map_err(|e| Error::File(path.to_owned(), e))?;
+    /// Patch the specified image instead of automatically selecting
+    /// `init_boot` or `boot`. The image must exist.
+    pub fn with_target(mut self, target: impl Into<String>) -> Self {
+        self.target = Some(target.into());
+        self
+    }
+
+    /// Get the Magisk version code (`MAGISK_VER_CODE`) of a Magisk APK.
+    pub fn get_version(path: &Path) -> Result<u32> {
+        let reader = sandbox::open(path).map_err(|e| Error::File(path.to_owned(), e))?;
         let reader = BufReader::new(reader);
         let mut zip = ZipArchive::new(reader)?;
         let entry = zip.by_name("assets/util_functions.sh")?;
@@ -272,6 +334,24 @@ impl MagiskRootPatcher {
             let mut new_entry = old_entry.clone();
             new_entry.path = b".backup/".to_vec();
             new_entry.path.extend(&old_entry.path);
+
+            // The backup is a standalone file and not another link to the
+            // original inode. Only the last link in a group holds the data.
+            if old_entry.is_hard_link() {
+                let id = old_entry.link_group_id();
+
+                if let Some(last) = old_entries
+                    .iter()
+                    .rev()
+                    .find(|e| e.is_hard_link() && e.link_group_id() == id)
+                {
+                    new_entry.data = last.data.clone();
+                }
+
+                new_entry.inode = 0;
+                new_entry.nlink = 1;
+            }
+
             new_entries.push(new_entry);
         }
 
@@ -295,31 +375,29 @@ impl BootImagePatch for MagiskRootPatcher {
     ) -> Result<Vec<&'a str>> {
         let mut targets = vec![];
 
-        if boot_images.contains_key("init_boot") {
-            targets.push("init_boot");
-        } else if boot_images.contains_key("boot") {
-            targets.push("boot");
-        };
+        if let Some(target) = &self.target {
+            let Some((name, _)) = boot_images.get_key_value(target.as_str()) else {
+                return Err(Error::Validation(format!(
+                    "Boot image not found for Magisk: {target}",
+                )));
+            };
+
+            targets.push(*name);
+        } else {
+            targets.extend(find_root_target(
+                boot_images.iter().map(|(n, i)| (*n, &i.boot_image)),
+            ));
+        }
 
         Ok(targets)
     }
 
     fn patch(&self, boot_image: &mut BootImage, cancel_signal: &AtomicBool) -> Result<()> {
         let zip_reader =
-            File::open(&self.apk_path).map_err(|e| Error::File(self.apk_path.clone(), e))?;
+            sandbox::open(&self.apk_path).map_err(|e| Error::File(self.apk_path.clone(), e))?;
         let mut zip = ZipArchive::new(BufReader::new(zip_reader))?;
 
-        // Load the first ramdisk. If it doesn't exist, we have to generate one
-        // from scratch.
-        let ramdisk = match boot_image {
-            BootImage::V0Through2(b) => Some(&b.ramdisk),
-            BootImage::V3Through4(b) => Some(&b.ramdisk),
-            BootImage::VendorV3Through4(b) => b.ramdisks.first(),
-        };
-        let (mut entries, ramdisk_format) = match ramdisk {
-            Some(r) if !r.is_empty() => load_ramdisk(r, cancel_signal)?,
-            _ => (vec![], CompressedFormat::Lz4Legacy),
-        };
+        let (mut entries, ramdisk_format) = load_first_ramdisk(boot_image, cancel_signal)?;
 
         let mut old_entries = entries.clone();
 
@@ -391,11 +469,10 @@ impl BootImagePatch for MagiskRootPatcher {
         magisk_config.push_str("PATCHVBMETAFLAG=false\n");
         magisk_config.push_str("RECOVERYMODE=false\n");
 
-        if Self::VER_PREINIT_DEVICE.contains(&self.version) {
-            magisk_config.push_str(&format!(
-                "PREINITDEVICE={}\n",
-                self.preinit_device.as_ref().unwrap(),
-            ));
+        if self.compat.preinit_device {
+            if let Some(device) = &self.preinit_device {
+                magisk_config.push_str(&format!("PREINITDEVICE={device}\n"));
+            }
         }
 
         // Magisk normally saves the original SHA1 digest in its config file. It
@@ -404,7 +481,7 @@ impl BootImagePatch for MagiskRootPatcher {
         // feature we cannot ever use, so just use a dummy value.
         magisk_config.push_str("SHA1=0000000000000000000000000000000000000000\n");
 
-        if Self::VER_RANDOM_SEED.contains(&self.version) {
+        if self.compat.random_seed {
             magisk_config.push_str(&format!("RANDOMSEED={:#x}\n", self.random_seed));
         }
 
@@ -416,29 +493,331 @@ impl BootImagePatch for MagiskRootPatcher {
 
         // Repack ramdisk.
         cpio::sort(&mut entries);
+        cpio::normalize_link_groups(&mut entries)?;
         cpio::assign_inodes(&mut entries, false)?;
         let new_ramdisk = save_ramdisk(&entries, ramdisk_format, cancel_signal)?;
 
-        match boot_image {
-            BootImage::V0Through2(b) => b.ramdisk = new_ramdisk,
-            BootImage::V3Through4(b) => b.ramdisk = new_ramdisk,
-            BootImage::VendorV3Through4(b) => {
-                if b.ramdisks.is_empty() {
-                    b.ramdisks.push(new_ramdisk);
-
-                    if let Some(v4) = &mut b.v4_extra {
-                        v4.ramdisk_metas.push(RamdiskMeta {
-                            ramdisk_type: bootimage::VENDOR_RAMDISK_TYPE_NONE,
-                            ramdisk_name: String::new(),
-                            board_id: Default::default(),
-                        });
-                    }
-                } else {
-                    b.ramdisks[0] = new_ramdisk;
+        set_first_ramdisk(boot_image, new_ramdisk);
+
+        Ok(())
+    }
+}
+
+/// Root a boot image with KernelSU in LKM mode. This works the same way as
+/// `ksud boot-patch`. The original `init` is renamed to `init.real` and is
+/// replaced by `ksuinit`, which loads the KernelSU kernel module before
+/// executing the real init. This only works with GKI kernels and the kernel
+/// module must be built for the kernel's KMI.
+pub struct KernelSuRootPatcher {
+    lkm_path: PathBuf,
+    init_path: PathBuf,
+    lkm_kmi: Option<String>,
+    ignore_kmi: bool,
+    warning_fn: Box<dyn Fn(&str) + Send + Sync>,
+}
+
+impl KernelSuRootPatcher {
+    // We compile without Unicode support so we have to use [0-9] instead of \d.
+    const KERNEL_REGEX: &'static str = r"Linux version ([0-9]+\.[0-9]+)\.[0-9]+-(android[0-9]+)-";
+    const LKM_REGEX: &'static str = r"vermagic=([0-9]+\.[0-9]+)\.[0-9]+-(android[0-9]+)-";
+
+    /// Create a new KernelSU patcher from the kernel module (`kernelsu.ko`)
+    /// and the `ksuinit` binary. If the KMI of the kernel module does not
+    /// match the KMI of the kernel in the `boot` image, an error is returned
+    /// during patching, unless `ignore_kmi` is set.
+    pub fn new(
+        lkm_path: &Path,
+        init_path: &Path,
+        ignore_kmi: bool,
+        warning_fn: impl Fn(&str) + Send + Sync + 'static,
+    ) -> Result<Self> {
+        let lkm = sandbox::read(lkm_path).map_err(|e| Error::File(lkm_path.to_owned(), e))?;
+        if !lkm.starts_with(b"\x7fELF") {
+            return Err(Error::Validation(format!(
+                "KernelSU kernel module is not an ELF file: {lkm_path:?}",
+            )));
+        }
+
+        Ok(Self {
+            lkm_path: lkm_path.to_owned(),
+            init_path: init_path.to_owned(),
+            lkm_kmi: Self::find_kmi(&lkm, Self::LKM_REGEX),
+            ignore_kmi,
+            warning_fn: Box::new(warning_fn),
+        })
+    }
+
+    /// Find the KMI (eg. `android14-6.1`) in the kernel release string matched
+    /// by `regex`. The first capture group is the kernel version and the
+    /// second capture group is the Android release.
+    fn find_kmi(data: &[u8], regex: &str) -> Option<String> {
+        let regex = Regex::new(regex).unwrap();
+        let captures = regex.captures(data)?;
+
+        // Our regex only matches ASCII bytes.
+        let version = std::str::from_utf8(&captures[1]).unwrap();
+        let android = std::str::from_utf8(&captures[2]).unwrap();
+
+        Some(format!("{android}-{version}"))
+    }
+
+    fn get_kernel_kmi(kernel: &[u8]) -> Result<Option<String>> {
+        let mut decompressed = vec![];
+        {
+            let raw_reader = Cursor::new(kernel);
+            let mut reader = CompressedReader::new(raw_reader, true)?;
+            reader.read_to_end(&mut decompressed)?;
+        }
+
+        Ok(Self::find_kmi(&decompressed, Self::KERNEL_REGEX))
+    }
+
+    /// Make sure that the kernel module's KMI matches the kernel's KMI.
+    fn check_kmi(&self, boot_images: &HashMap<&str, BootImageInfo>) -> Result<()> {
+        let kernel = boot_images
+            .get("boot")
+            .and_then(|info| match &info.boot_image {
+                BootImage::V0Through2(b) => Some(&b.kernel),
+                BootImage::V3Through4(b) => Some(&b.kernel),
+                BootImage::VendorV3Through4(_) => None,
+            })
+            .filter(|k| !k.is_empty());
+        let kernel_kmi = match kernel {
+            Some(k) => Self::get_kernel_kmi(k)?,
+            None => None,
+        };
+
+        let msg = match (&self.lkm_kmi, &kernel_kmi) {
+            (Some(lkm), Some(kernel)) if lkm == kernel => return Ok(()),
+            (Some(lkm), Some(kernel)) => {
+                format!("KernelSU kernel module is for {lkm}, but the kernel is {kernel}")
+            }
+            (None, _) => format!(
+                "Failed to determine KMI of KernelSU kernel module: {:?}",
+                self.lkm_path,
+            ),
+            (_, None) => "Failed to determine KMI of the boot image kernel".to_owned(),
+        };
+
+        if self.ignore_kmi || kernel_kmi.is_none() || self.lkm_kmi.is_none() {
+            (self.warning_fn)(&msg);
+            Ok(())
+        } else {
+            Err(Error::Validation(msg))
+        }
+    }
+
+    /// Add `ksuinit` and the kernel module to the ramdisk entries. The original
+    /// `init` is preserved as `init.real`, unless the ramdisk was already
+    /// patched by KernelSU.
+    fn patch_entries(entries: &mut Vec<CpioEntry>, init: Vec<u8>, lkm: Vec<u8>) -> Result<()> {
+        if entries.iter().any(|e| e.path == b".backup/.magisk") {
+            return Err(Error::Validation(
+                "Cannot install KernelSU into a Magisk-patched image".to_owned(),
+            ));
+        }
+
+        if !entries.iter().any(|e| e.path == b"kernelsu.ko")
+            && entries.iter().any(|e| e.path == b"init")
+        {
+            entries.retain(|e| e.path != b"init.real");
+
+            for entry in entries.iter_mut() {
+                if entry.path == b"init" {
+                    entry.path = b"init.real".to_vec();
                 }
             }
         }
 
+        entries.retain(|e| e.path != b"init" && e.path != b"kernelsu.ko");
+
+        entries.push(CpioEntry::new_file(
+            b"init",
+            0o755,
+            CpioEntryData::Data(init),
+        ));
+        entries.push(CpioEntry::new_file(
+            b"kernelsu.ko",
+            0o755,
+            CpioEntryData::Data(lkm),
+        ));
+
+        Ok(())
+    }
+}
+
+impl BootImagePatch for KernelSuRootPatcher {
+    fn patcher_name(&self) -> &'static str {
+        "KernelSuRootPatcher"
+    }
+
+    fn find_targets<'a>(
+        &self,
+        boot_images: &HashMap<&'a str, BootImageInfo>,
+        _cancel_signal: &AtomicBool,
+    ) -> Result<Vec<&'a str>> {
+        let targets = find_root_target(boot_images.iter().map(|(n, i)| (*n, &i.boot_image)))
+            .into_iter()
+            .collect::<Vec<_>>();
+
+        if !targets.is_empty() {
+            self.check_kmi(boot_images)?;
+        }
+
+        Ok(targets)
+    }
+
+    fn patch(&self, boot_image: &mut BootImage, cancel_signal: &AtomicBool) -> Result<()> {
+        let init =
+            sandbox::read(&self.init_path).map_err(|e| Error::File(self.init_path.clone(), e))?;
+        let lkm =
+            sandbox::read(&self.lkm_path).map_err(|e| Error::File(self.lkm_path.clone(), e))?;
+
+        let (mut entries, ramdisk_format) = load_first_ramdisk(boot_image, cancel_signal)?;
+
+        Self::patch_entries(&mut entries, init, lkm)?;
+
+        // Repack ramdisk.
+        cpio::sort(&mut entries);
+        cpio::normalize_link_groups(&mut entries)?;
+        cpio::assign_inodes(&mut entries, false)?;
+        let new_ramdisk = save_ramdisk(&entries, ramdisk_format, cancel_signal)?;
+
+        set_first_ramdisk(boot_image, new_ramdisk);
+
+        Ok(())
+    }
+}
+
+/// Remove root from boot images that were patched by [`MagiskRootPatcher`] or
+/// [`KernelSuRootPatcher`] by restoring the original ramdisk contents. Images
+/// that were replaced by a prepatched image cannot be reverted because the
+/// original image is not available.
+pub struct RootRemovalPatcher;
+
+impl RootRemovalPatcher {
+    fn is_rooted(entries: &[CpioEntry]) -> bool {
+        entries
+            .iter()
+            .any(|e| e.path == b".backup/.magisk" || e.path == b"kernelsu.ko")
+    }
+
+    /// Undo the changes made by Magisk using the `.backup/` directory. The
+    /// files listed in `.backup/.rmlist` are removed and the backed up files
+    /// are moved back to their original locations. This is equivalent to
+    /// `magiskboot cpio restore`.
+    fn revert_magisk(entries: &mut Vec<CpioEntry>) -> Result<()> {
+        let rm_list = match entries.iter().find(|e| e.path == b".backup/.rmlist") {
+            Some(e) => match &e.data {
+                CpioEntryData::Data(d) => d.clone(),
+                CpioEntryData::Size(_) => {
+                    return Err(Error::Validation(
+                        "Magisk .backup/.rmlist has no data".to_owned(),
+                    ))
+                }
+            },
+            None => vec![],
+        };
+        let removed = rm_list
+            .split(|b| *b == b'\0')
+            .filter(|p| !p.is_empty())
+            .collect::<HashSet<_>>();
+
+        let mut restored = vec![];
+        let mut remaining = vec![];
+
+        for mut entry in entries.drain(..) {
+            if let Some(path) = entry.path.strip_prefix(b".backup/".as_slice()) {
+                if path != b".magisk" && path != b".rmlist" {
+                    entry.path = path.to_vec();
+                    restored.push(entry);
+                }
+            } else if entry.path != b".backup" && !removed.contains(entry.path.as_slice()) {
+                remaining.push(entry);
+            }
+        }
+
+        let restored_paths = restored
+            .iter()
+            .map(|e| e.path.clone())
+            .collect::<HashSet<_>>();
+        remaining.retain(|e| !restored_paths.contains(&e.path));
+
+        entries.extend(remaining);
+        entries.extend(restored);
+
+        Ok(())
+    }
+
+    /// Undo the changes made by [`KernelSuRootPatcher::patch_entries()`].
+    fn revert_kernelsu(entries: &mut Vec<CpioEntry>) {
+        let has_real_init = entries.iter().any(|e| e.path == b"init.real");
+
+        entries.retain(|e| e.path != b"kernelsu.ko" && (!has_real_init || e.path != b"init"));
+
+        for entry in entries.iter_mut() {
+            if entry.path == b"init.real" {
+                entry.path = b"init".to_vec();
+            }
+        }
+    }
+
+    fn revert_entries(entries: &mut Vec<CpioEntry>) -> Result<()> {
+        if entries.iter().any(|e| e.path == b".backup/.magisk") {
+            Self::revert_magisk(entries)
+        } else {
+            Self::revert_kernelsu(entries);
+            Ok(())
+        }
+    }
+}
+
+impl BootImagePatch for RootRemovalPatcher {
+    fn patcher_name(&self) -> &'static str {
+        "RootRemovalPatcher"
+    }
+
+    fn find_targets<'a>(
+        &self,
+        boot_images: &HashMap<&'a str, BootImageInfo>,
+        cancel_signal: &AtomicBool,
+    ) -> Result<Vec<&'a str>> {
+        let mut targets = vec![];
+
+        for (name, info) in boot_images {
+            let (entries, _) = load_first_ramdisk(&info.boot_image, cancel_signal)?;
+
+            if Self::is_rooted(&entries) {
+                targets.push(*name);
+            }
+        }
+
+        if targets.is_empty() {
+            return Err(Error::Validation(
+                "No boot image rooted with Magisk or KernelSU found".to_owned(),
+            ));
+        }
+
+        Ok(targets)
+    }
+
+    fn patch(&self, boot_image: &mut BootImage, cancel_signal: &AtomicBool) -> Result<()> {
+        let (mut entries, ramdisk_format) = load_first_ramdisk(boot_image, cancel_signal)?;
+
+        Self::revert_entries(&mut entries)?;
+
+        // Magisk creates a new ramdisk if the image didn't have one.
+        let new_ramdisk = if entries.is_empty() {
+            vec![]
+        } else {
+            cpio::sort(&mut entries);
+            cpio::normalize_link_groups(&mut entries)?;
+            cpio::assign_inodes(&mut entries, false)?;
+            save_ramdisk(&entries, ramdisk_format, cancel_signal)?
+        };
+
+        set_first_ramdisk(boot_image, new_ramdisk);
+
         Ok(())
     }
 }
@@ -447,13 +826,34 @@ impl BootImagePatch for MagiskRootPatcher {
 /// custom OTA signing certificate.
 pub struct OtaCertPatcher {
     cert: Certificate,
+    targets: Option<HashSet<String>>,
 }
 
 impl OtaCertPatcher {
-    const OTACERTS_PATH: &'static [u8] = b"system/etc/security/otacerts.zip";
+    /// Known locations of otacerts.zip within a ramdisk. The first is used by
+    /// modern recovery and first-stage init ramdisks. The second is used by
+    /// older, non-system-as-root recovery ramdisks.
+    const OTACERTS_PATHS: &'static [&'static [u8]] = &[
+        b"system/etc/security/otacerts.zip",
+        b"etc/security/otacerts.zip",
+    ];
 
     pub fn new(cert: Certificate) -> Self {
-        Self { cert }
+        Self {
+            cert,
+            targets: None,
+        }
+    }
+
+    /// Only patch the specified images instead of every image that contains
+    /// otacerts.zip. Each specified image must contain otacerts.zip.
+    pub fn with_targets(mut self, targets: impl IntoIterator<Item = String>) -> Self {
+        self.targets = Some(targets.into_iter().collect());
+        self
+    }
+
+    fn is_otacerts_entry(entry: &CpioEntry) -> bool {
+        Self::OTACERTS_PATHS.contains(&entry.path.as_slice())
     }
 
     pub fn get_certificates(
@@ -476,24 +876,14 @@ impl OtaCertPatcher {
             }
 
             let (entries, _) = load_ramdisk(ramdisk, cancel_signal)?;
-            let Some(entry) = entries.iter().find(|e| e.path == Self::OTACERTS_PATH) else {
+            let Some(entry) = entries.iter().find(|e| Self::is_otacerts_entry(e)) else {
                 continue;
             };
             let CpioEntryData::Data(data) = &entry.data else {
                 continue;
             };
 
-            let mut zip = ZipArchive::new(Cursor::new(&data))?;
-
-            for index in 0..zip.len() {
-                let zip_entry = zip.by_index(index)?;
-                if !zip_entry.name().ends_with(".x509.pem") {
-                    continue;
-                }
-
-                let certificate = crypto::read_pem_cert(zip_entry)?;
-                certificates.push(certificate);
-            }
+            certificates.extend(otacert::read_zip(data)?);
         }
 
         Ok(certificates)
@@ -506,7 +896,7 @@ impl OtaCertPatcher {
         cancel_signal: &AtomicBool,
     ) -> Result<bool> {
         let (mut entries, ramdisk_format) = load_ramdisk(ramdisk, cancel_signal)?;
-        let Some(entry) = entries.iter_mut().find(|e| e.path == Self::OTACERTS_PATH) else {
+        let Some(entry) = entries.iter_mut().find(|e| Self::is_otacerts_entry(e)) else {
             return Ok(false);
         };
 
@@ -534,6 +924,12 @@ impl BootImagePatch for OtaCertPatcher {
         let mut targets = vec![];
 
         'outer: for (name, info) in boot_images {
+            if let Some(t) = &self.targets {
+                if !t.contains(*name) {
+                    continue;
+                }
+            }
+
             let ramdisks = match &info.boot_image {
                 BootImage::V0Through2(b) => slice::from_ref(&b.ramdisk),
                 BootImage::V3Through4(b) => slice::from_ref(&b.ramdisk),
@@ -546,11 +942,25 @@ impl BootImagePatch for OtaCertPatcher {
                 }
 
                 let (entries, _) = load_ramdisk(ramdisk, cancel_signal)?;
-                if entries.iter().any(|e| e.path == Self::OTACERTS_PATH) {
+                if entries.iter().any(Self::is_otacerts_entry) {
                     targets.push(*name);
                     continue 'outer;
                 }
             }
+
+            if self.targets.is_some() {
+                return Err(Error::Validation(format!(
+                    "No ramdisk in {name} contains otacerts.zip",
+                )));
+            }
+        }
+
+        if let Some(t) = &self.targets {
+            if let Some(name) = t.iter().find(|n| !boot_images.contains_key(n.as_str())) {
+                return Err(Error::Validation(format!(
+                    "Boot image not found for otacerts.zip: {name}",
+                )));
+            }
         }
 
         Ok(targets)
@@ -578,8 +988,11 @@ impl BootImagePatch for OtaCertPatcher {
         // Fail hard if otacerts does not exist. We don't want to lock the user
         // out of future updates if the OTA certificate mechanism has changed.
         Err(Error::Validation(format!(
-            "No ramdisk contains {:?}",
-            Self::OTACERTS_PATH.as_bstr(),
+            "No ramdisk contains any of {:?}",
+            Self::OTACERTS_PATHS
+                .iter()
+                .map(|p| p.as_bstr())
+                .collect::<Vec<_>>(),
         )))
     }
 }
@@ -618,7 +1031,7 @@ impl PrepatchedImagePatcher {
 
     fn load_prepatched_image(&self) -> Result<BootImage> {
         let raw_reader =
-            File::open(&self.prepatched).map_err(|e| Error::File(self.prepatched.clone(), e))?;
+            sandbox::open(&self.prepatched).map_err(|e| Error::File(self.prepatched.clone(), e))?;
         let boot_image = BootImage::from_reader(BufReader::new(raw_reader))?;
 
         Ok(boot_image)
@@ -849,6 +1262,240 @@ impl BootImagePatch for PrepatchedImagePatcher {
     }
 }
 
+/// Patch boot images with an external program. The image is unpacked to a
+/// temporary directory with the same file names that `avbroot boot unpack`
+/// uses by default, the program is run with the directory as its argument, and
+/// the directory is packed back into a boot image like `avbroot boot pack`.
+/// Missing files are treated as empty sections. If multiple images are
+/// targeted, the program may run for several images concurrently.
+pub struct CustomBootPatcher {
+    program: PathBuf,
+    targets: Option<HashSet<String>>,
+}
+
+impl CustomBootPatcher {
+    const HEADER: &'static str = "boot.toml";
+    const KERNEL: &'static str = "kernel.img";
+    const RAMDISK_PREFIX: &'static str = "ramdisk.img.";
+    const SECOND: &'static str = "second.img";
+    const RECOVERY_DTBO: &'static str = "recovery_dtbo.img";
+    const DTB: &'static str = "dtb.img";
+    const VTS_SIGNATURE: &'static str = "vts_signature.img";
+    const BOOTCONFIG: &'static str = "bootconfig.txt";
+
+    pub fn new(program: &Path) -> Self {
+        Self {
+            program: program.to_owned(),
+            targets: None,
+        }
+    }
+
+    /// Patch the specified images instead of the image that a root patcher
+    /// would patch. Each specified image must be a boot image.
+    pub fn with_targets(mut self, targets: impl IntoIterator<Item = String>) -> Self {
+        self.targets = Some(targets.into_iter().collect());
+        self
+    }
+
+    fn write_if_not_empty(dir: &Path, name: &str, data: &[u8]) -> Result<()> {
+        if !data.is_empty() {
+            let path = dir.join(name);
+            sandbox::write(&path, data).map_err(|e| Error::File(path, e))?;
+        }
+
+        Ok(())
+    }
+
+    fn read_if_exists(dir: &Path, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = dir.join(name);
+
+        match sandbox::read(&path) {
+            Ok(data) => Ok(Some(data)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(Error::File(path, e)),
+        }
+    }
+
+    fn unpack(boot_image: &BootImage, dir: &Path) -> Result<()> {
+        let header = toml_edit::ser::to_string_pretty(boot_image)?;
+        Self::write_if_not_empty(dir, Self::HEADER, header.as_bytes())?;
+
+        let mut ramdisks = vec![];
+
+        match boot_image {
+            BootImage::V0Through2(b) => {
+                Self::write_if_not_empty(dir, Self::KERNEL, &b.kernel)?;
+                Self::write_if_not_empty(dir, Self::SECOND, &b.second)?;
+                if let Some(v1) = &b.v1_extra {
+                    Self::write_if_not_empty(dir, Self::RECOVERY_DTBO, &v1.recovery_dtbo)?;
+                }
+                if let Some(v2) = &b.v2_extra {
+                    Self::write_if_not_empty(dir, Self::DTB, &v2.dtb)?;
+                }
+                ramdisks.push(&b.ramdisk);
+            }
+            BootImage::V3Through4(b) => {
+                Self::write_if_not_empty(dir, Self::KERNEL, &b.kernel)?;
+                if let Some(signature) = b.v4_extra.as_ref().and_then(|v4| v4.signature.as_ref()) {
+                    let mut data = vec![];
+                    signature.to_writer(&mut data)?;
+                    Self::write_if_not_empty(dir, Self::VTS_SIGNATURE, &data)?;
+                }
+                ramdisks.push(&b.ramdisk);
+            }
+            BootImage::VendorV3Through4(b) => {
+                Self::write_if_not_empty(dir, Self::DTB, &b.dtb)?;
+                if let Some(v4) = &b.v4_extra {
+                    Self::write_if_not_empty(dir, Self::BOOTCONFIG, v4.bootconfig.as_bytes())?;
+                }
+                ramdisks.extend(b.ramdisks.iter());
+            }
+        }
+
+        for (i, ramdisk) in ramdisks.into_iter().enumerate() {
+            Self::write_if_not_empty(dir, &format!("{}{i}", Self::RAMDISK_PREFIX), ramdisk)?;
+        }
+
+        Ok(())
+    }
+
+    fn pack(dir: &Path) -> Result<BootImage> {
+        let Some(header) = Self::read_if_exists(dir, Self::HEADER)? else {
+            return Err(Error::Validation(format!(
+                "Custom boot patcher deleted {}",
+                Self::HEADER,
+            )));
+        };
+        let header = String::from_utf8(header)
+            .map_err(|_| Error::Validation(format!("{} is not valid UTF-8", Self::HEADER)))?;
+        let mut boot_image: BootImage = toml_edit::de::from_str(&header)?;
+
+        let kernel = Self::read_if_exists(dir, Self::KERNEL)?;
+        let second = Self::read_if_exists(dir, Self::SECOND)?;
+        let recovery_dtbo = Self::read_if_exists(dir, Self::RECOVERY_DTBO)?;
+        let dtb = Self::read_if_exists(dir, Self::DTB)?;
+        let vts_signature = Self::read_if_exists(dir, Self::VTS_SIGNATURE)?
+            .map(|data| Header::from_reader(Cursor::new(data)))
+            .transpose()?;
+        let bootconfig = Self::read_if_exists(dir, Self::BOOTCONFIG)?
+            .map(|data| {
+                String::from_utf8(data).map_err(|_| {
+                    Error::Validation(format!("{} is not valid UTF-8", Self::BOOTCONFIG))
+                })
+            })
+            .transpose()?;
+        let mut ramdisks = vec![];
+
+        for i in 0.. {
+            let name = format!("{}{i}", Self::RAMDISK_PREFIX);
+            let Some(ramdisk) = Self::read_if_exists(dir, &name)? else {
+                break;
+            };
+
+            ramdisks.push(ramdisk);
+        }
+
+        let single_ramdisk = |ramdisks: Vec<Vec<u8>>| {
+            if ramdisks.len() > 1 {
+                return Err(Error::Validation(
+                    "Image type only supports a single ramdisk".to_owned(),
+                ));
+            }
+
+            Ok(ramdisks.into_iter().next().unwrap_or_default())
+        };
+
+        match &mut boot_image {
+            BootImage::V0Through2(b) => {
+                b.kernel = kernel.unwrap_or_default();
+                b.second = second.unwrap_or_default();
+                if let Some(v1) = &mut b.v1_extra {
+                    v1.recovery_dtbo = recovery_dtbo.unwrap_or_default();
+                }
+                if let Some(v2) = &mut b.v2_extra {
+                    v2.dtb = dtb.unwrap_or_default();
+                }
+                b.ramdisk = single_ramdisk(ramdisks)?;
+            }
+            BootImage::V3Through4(b) => {
+                b.kernel = kernel.unwrap_or_default();
+                if let Some(v4) = &mut b.v4_extra {
+                    v4.signature = vts_signature;
+                }
+                b.ramdisk = single_ramdisk(ramdisks)?;
+            }
+            BootImage::VendorV3Through4(b) => {
+                b.dtb = dtb.unwrap_or_default();
+                if let Some(v4) = &mut b.v4_extra {
+                    v4.bootconfig = bootconfig.unwrap_or_default();
+                }
+                b.ramdisks = ramdisks;
+            }
+        }
+
+        Ok(boot_image)
+    }
+}
+
+impl BootImagePatch for CustomBootPatcher {
+    fn patcher_name(&self) -> &'static str {
+        "CustomBootPatcher"
+    }
+
+    fn find_targets<'a>(
+        &self,
+        boot_images: &HashMap<&'a str, BootImageInfo>,
+        _cancel_signal: &AtomicBool,
+    ) -> Result<Vec<&'a str>> {
+        let Some(targets) = &self.targets else {
+            return Ok(
+                find_root_target(boot_images.iter().map(|(n, i)| (*n, &i.boot_image)))
+                    .into_iter()
+                    .collect(),
+            );
+        };
+
+        let mut result = vec![];
+
+        for target in targets {
+            let Some((name, _)) = boot_images.get_key_value(target.as_str()) else {
+                return Err(Error::Validation(format!(
+                    "Custom boot patcher target is not a boot image: {target}"
+                )));
+            };
+
+            result.push(*name);
+        }
+
+        result.sort();
+
+        Ok(result)
+    }
+
+    fn patch(&self, boot_image: &mut BootImage, cancel_signal: &AtomicBool) -> Result<()> {
+        let temp_dir = sandbox::tempdir()?;
+
+        Self::unpack(boot_image, temp_dir.path())?;
+
+        stream::check_cancel(cancel_signal)?;
+
+        let status = Command::new(&self.program)
+            .arg(temp_dir.path())
+            .current_dir(temp_dir.path())
+            .status()
+            .map_err(|e| Error::CommandSpawn(self.program.clone(), e))?;
+        if !status.success() {
+            return Err(Error::CommandFailed(self.program.clone(), status));
+        }
+
+        stream::check_cancel(cancel_signal)?;
+
+        *boot_image = Self::pack(temp_dir.path())?;
+
+        Ok(())
+    }
+}
+
 pub fn load_boot_images<'a>(
     names: &[&'a str],
     open_input: impl Fn(&str) -> io::Result<Box<dyn ReadSeek>> + Sync,
@@ -878,35 +1525,46 @@ pub fn load_boot_images<'a>(
         .collect()
 }
 
+/// Find the targets that each patcher wants to patch. The returned list is in
+/// the same order as `patchers`. Every patcher must have at least one target.
+pub fn find_patch_targets<'a>(
+    boot_images: &HashMap<&'a str, BootImageInfo>,
+    patchers: &[Box<dyn BootImagePatch + Sync>],
+    cancel_signal: &AtomicBool,
+) -> Result<Vec<Vec<&'a str>>> {
+    patchers
+        .par_iter()
+        .map(|p| {
+            p.find_targets(boot_images, cancel_signal)
+                .and_then(|targets| {
+                    if targets.is_empty() {
+                        Err(Error::NoTargets(p.patcher_name()))
+                    } else {
+                        Ok(targets)
+                    }
+                })
+        })
+        .collect()
+}
+
 /// Apply applicable patches to the list of specified boot images. For each
 /// image, the applicable patchers run in the same order as in `patchers`. All
 /// operations run in parallel where possible. Only the patcher execution for a
 /// given image is guaranteed to be sequential. The input and output files will
-/// be opened from multiple threads, but at most once each.
+/// be opened from multiple threads, but at most once each. Returns the names of
+/// the patchers that were applied to each modified image.
 pub fn patch_boot_images<'a>(
     names: &[&'a str],
     open_input: impl Fn(&str) -> io::Result<Box<dyn ReadSeek>> + Sync,
     open_output: impl Fn(&str) -> io::Result<Box<dyn WriteSeek>> + Sync,
-    key: &RsaPrivateKey,
+    key: &RsaSigningKey,
+    algorithm: Option<AlgorithmType>,
     patchers: &[Box<dyn BootImagePatch + Sync>],
     cancel_signal: &AtomicBool,
-) -> Result<HashSet<&'a str>> {
+) -> Result<HashMap<&'a str, Vec<&'static str>>> {
     // Preparse all images. Some patchers need to inspect every candidate.
     let mut images = load_boot_images(names, open_input)?;
-
-    // Find the targets that each patcher wants to patch.
-    let all_targets = patchers
-        .par_iter()
-        .map(|p| {
-            p.find_targets(&images, cancel_signal).and_then(|targets| {
-                if targets.is_empty() {
-                    Err(Error::NoTargets(p.patcher_name()))
-                } else {
-                    Ok(targets)
-                }
-            })
-        })
-        .collect::<Result<Vec<_>>>()?;
+    let all_targets = find_patch_targets(&images, patchers, cancel_signal)?;
 
     // Regroup data so we can parallelize by target.
     let mut groups = HashMap::<&str, (BootImageInfo, Vec<&Box<dyn BootImagePatch + Sync>>)>::new();
@@ -944,7 +1602,7 @@ pub fn patch_boot_images<'a>(
             let writer = open_output(name)?;
 
             // Write new boot image. We reuse the existing salt for the digest.
-            let mut context = Context::new(&ring::digest::SHA256);
+            let mut context = Context::new(&crate::digest::SHA256);
             context.update(&descriptor.salt);
             let mut hashing_writer = HashingWriter::new(writer, context);
             info.boot_image.to_writer(&mut hashing_writer)?;
@@ -955,7 +1613,7 @@ pub fn patch_boot_images<'a>(
             descriptor.root_digest = context.finish().as_ref().to_vec();
 
             if !info.header.public_key.is_empty() {
-                info.header.set_algo_for_key(key)?;
+                info.header.set_algo_for_key(key, algorithm)?;
                 info.header.sign(key)?;
             }
 
@@ -965,5 +1623,334 @@ pub fn patch_boot_images<'a>(
         })
         .collect::<Result<()>>()?;
 
-    Ok(groups.keys().cloned().collect())
+    Ok(groups
+        .into_iter()
+        .map(|(name, (_, patchers))| (name, patchers.iter().map(|p| p.patcher_name()).collect()))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, time::Duration};
+
+    use assert_matches::assert_matches;
+
+    use crate::format::bootimage::{BootImageV0Through2, BootImageV3Through4};
+
+    use super::*;
+
+    fn v2_image(ramdisk: &[u8]) -> BootImage {
+        BootImage::V0Through2(BootImageV0Through2 {
+            kernel_addr: 0,
+            ramdisk_addr: 0,
+            second_addr: 0,
+            tags_addr: 0,
+            page_size: 4096,
+            os_version: 0,
+            name: String::new(),
+            cmdline: String::new(),
+            id: [0; 8],
+            extra_cmdline: String::new(),
+            kernel: b"kernel".to_vec(),
+            ramdisk: ramdisk.to_vec(),
+            second: vec![],
+            v1_extra: None,
+            v2_extra: None,
+        })
+    }
+
+    fn v4_image(ramdisk: &[u8]) -> BootImage {
+        BootImage::V3Through4(BootImageV3Through4 {
+            os_version: 0,
+            reserved: [0; 4],
+            cmdline: String::new(),
+            v4_extra: None,
+            kernel: vec![],
+            ramdisk: ramdisk.to_vec(),
+        })
+    }
+
+    fn image_info(boot_image: BootImage) -> BootImageInfo {
+        BootImageInfo {
+            header: Header {
+                required_libavb_version_major: avb::VERSION_MAJOR,
+                required_libavb_version_minor: avb::VERSION_MINOR,
+                algorithm_type: AlgorithmType::None,
+                hash: vec![],
+                signature: vec![],
+                public_key: vec![],
+                public_key_metadata: vec![],
+                descriptors: vec![],
+                rollback_index: 0,
+                flags: 0,
+                rollback_index_location: 0,
+                release_string: String::new(),
+                reserved: [0u8; 80],
+            },
+            footer: Footer {
+                version_major: avb::FOOTER_VERSION_MAJOR,
+                version_minor: avb::FOOTER_VERSION_MINOR,
+                original_image_size: 0,
+                vbmeta_offset: 0,
+                vbmeta_size: 0,
+                reserved: [0u8; 28],
+            },
+            image_size: 0,
+            boot_image,
+        }
+    }
+
+    #[test]
+    fn root_target() {
+        let with_ramdisk = v2_image(b"ramdisk");
+        let without_ramdisk = v2_image(b"");
+        let init_boot = v4_image(b"ramdisk");
+
+        // Pixel-style layout with init_boot.
+        assert_eq!(
+            find_root_target([
+                ("boot", &without_ramdisk),
+                ("init_boot", &init_boot),
+                ("vendor_boot", &with_ramdisk),
+            ]),
+            Some("init_boot"),
+        );
+
+        // Pre-init_boot layout where the ramdisk is in boot.
+        assert_eq!(
+            find_root_target([("boot", &with_ramdisk), ("recovery", &with_ramdisk)]),
+            Some("boot"),
+        );
+
+        // Recovery-as-root layout.
+        assert_eq!(
+            find_root_target([("boot", &without_ramdisk), ("recovery", &with_ramdisk)]),
+            Some("recovery"),
+        );
+
+        // Magisk creates a new ramdisk if there's nowhere else to go.
+        assert_eq!(find_root_target([("boot", &without_ramdisk)]), Some("boot"),);
+
+        assert_eq!(find_root_target([("recovery", &with_ramdisk)]), None);
+    }
+
+    #[test]
+    fn kernelsu_kmi() {
+        assert_eq!(
+            KernelSuRootPatcher::find_kmi(
+                b"\0vermagic=6.1.75-android14-11-g1234567 SMP preempt mod_unload aarch64\0",
+                KernelSuRootPatcher::LKM_REGEX,
+            ),
+            Some("android14-6.1".to_owned()),
+        );
+        assert_eq!(
+            KernelSuRootPatcher::find_kmi(
+                b"Linux version 5.15.137-android13-8-00001-g1234567 (build-user@build-host)",
+                KernelSuRootPatcher::KERNEL_REGEX,
+            ),
+            Some("android13-5.15".to_owned()),
+        );
+        assert_eq!(
+            KernelSuRootPatcher::find_kmi(
+                b"vermagic=4.19.157-perf SMP preempt",
+                KernelSuRootPatcher::LKM_REGEX,
+            ),
+            None,
+        );
+    }
+
+    #[test]
+    fn kernelsu_patch_entries() {
+        let data = |entries: &[CpioEntry], path: &[u8]| {
+            entries
+                .iter()
+                .find(|e| e.path == path)
+                .map(|e| e.data.clone())
+        };
+
+        let mut entries = vec![
+            CpioEntry::new_file(b"init", 0o750, CpioEntryData::Data(b"stock".to_vec())),
+            CpioEntry::new_directory(b"system", 0o755),
+        ];
+
+        KernelSuRootPatcher::patch_entries(&mut entries, b"ksuinit".to_vec(), b"lkm".to_vec())
+            .unwrap();
+        assert_eq!(
+            data(&entries, b"init"),
+            Some(CpioEntryData::Data(b"ksuinit".to_vec())),
+        );
+        assert_eq!(
+            data(&entries, b"init.real"),
+            Some(CpioEntryData::Data(b"stock".to_vec())),
+        );
+        assert_eq!(
+            data(&entries, b"kernelsu.ko"),
+            Some(CpioEntryData::Data(b"lkm".to_vec())),
+        );
+
+        // Patching again must not overwrite the original init.
+        KernelSuRootPatcher::patch_entries(&mut entries, b"ksuinit2".to_vec(), b"lkm".to_vec())
+            .unwrap();
+        assert_eq!(
+            data(&entries, b"init"),
+            Some(CpioEntryData::Data(b"ksuinit2".to_vec())),
+        );
+        assert_eq!(
+            data(&entries, b"init.real"),
+            Some(CpioEntryData::Data(b"stock".to_vec())),
+        );
+        assert_eq!(entries.len(), 4);
+
+        entries.push(CpioEntry::new_file(
+            b".backup/.magisk",
+            0,
+            CpioEntryData::Data(vec![]),
+        ));
+        assert_matches!(
+            KernelSuRootPatcher::patch_entries(&mut entries, vec![], vec![]),
+            Err(Error::Validation(_))
+        );
+    }
+
+    #[test]
+    fn root_removal_entries() {
+        let stock = vec![
+            CpioEntry::new_directory(b"system", 0o755),
+            CpioEntry::new_file(b"init", 0o750, CpioEntryData::Data(b"stock".to_vec())),
+            CpioEntry::new_file(b"removed", 0o644, CpioEntryData::Data(b"old".to_vec())),
+        ];
+
+        // Magisk.
+        let mut old_entries = stock.clone();
+        let mut entries = vec![
+            CpioEntry::new_directory(b"system", 0o755),
+            CpioEntry::new_file(b"init", 0o750, CpioEntryData::Data(b"magisk".to_vec())),
+            CpioEntry::new_directory(b"overlay.d", 0o750),
+        ];
+        MagiskRootPatcher::apply_magisk_backup(&mut old_entries, &mut entries);
+        entries.push(CpioEntry::new_file(
+            b".backup/.magisk",
+            0,
+            CpioEntryData::Data(vec![]),
+        ));
+        assert!(RootRemovalPatcher::is_rooted(&entries));
+
+        RootRemovalPatcher::revert_entries(&mut entries).unwrap();
+        cpio::sort(&mut entries);
+        assert_eq!(entries, old_entries);
+        assert!(!RootRemovalPatcher::is_rooted(&entries));
+
+        // KernelSU.
+        let mut entries = stock.clone();
+        KernelSuRootPatcher::patch_entries(&mut entries, b"ksuinit".to_vec(), b"lkm".to_vec())
+            .unwrap();
+        assert!(RootRemovalPatcher::is_rooted(&entries));
+
+        RootRemovalPatcher::revert_entries(&mut entries).unwrap();
+        let mut expected = stock.clone();
+        cpio::sort(&mut entries);
+        cpio::sort(&mut expected);
+        assert_eq!(entries, expected);
+    }
+
+    #[test]
+    fn otacerts_targets() {
+        let cancel_signal = AtomicBool::new(false);
+        let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+        let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=test").unwrap();
+
+        let ramdisk = |path: &[u8]| {
+            let entries = [CpioEntry::new_file(path, 0o644, CpioEntryData::Data(vec![]))];
+            save_ramdisk(&entries, CompressedFormat::Lz4Legacy, &cancel_signal).unwrap()
+        };
+        let with_otacerts = HashMap::from([(
+            "image",
+            image_info(v2_image(&ramdisk(b"system/etc/security/otacerts.zip"))),
+        )]);
+        let without_otacerts = HashMap::from([("image", image_info(v2_image(&ramdisk(b"init"))))]);
+
+        let patcher = OtaCertPatcher::new(cert.clone());
+        assert_eq!(
+            patcher.find_targets(&with_otacerts, &cancel_signal).unwrap(),
+            ["image"],
+        );
+        assert!(patcher
+            .find_targets(&without_otacerts, &cancel_signal)
+            .unwrap()
+            .is_empty());
+
+        // Explicit targets must contain otacerts.zip.
+        let patcher = OtaCertPatcher::new(cert).with_targets(["image".to_owned()]);
+        assert_eq!(
+            patcher.find_targets(&with_otacerts, &cancel_signal).unwrap(),
+            ["image"],
+        );
+        assert_matches!(
+            patcher.find_targets(&without_otacerts, &cancel_signal),
+            Err(Error::Validation(_))
+        );
+    }
+
+    #[test]
+    fn custom_unpack_pack() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+
+        let image = v2_image(b"ramdisk");
+        CustomBootPatcher::unpack(&image, dir).unwrap();
+
+        assert_eq!(fs::read(dir.join("kernel.img")).unwrap(), b"kernel");
+        assert_eq!(fs::read(dir.join("ramdisk.img.0")).unwrap(), b"ramdisk");
+        assert!(!dir.join("second.img").exists());
+        assert_eq!(CustomBootPatcher::pack(dir).unwrap(), image);
+
+        fs::write(dir.join("kernel.img"), b"patched").unwrap();
+        fs::remove_file(dir.join("ramdisk.img.0")).unwrap();
+
+        let BootImage::V0Through2(b) = CustomBootPatcher::pack(dir).unwrap() else {
+            panic!("Unexpected boot image type");
+        };
+        assert_eq!(b.kernel, b"patched");
+        assert!(b.ramdisk.is_empty());
+
+        fs::write(dir.join("ramdisk.img.0"), b"a").unwrap();
+        fs::write(dir.join("ramdisk.img.1"), b"b").unwrap();
+        assert_matches!(CustomBootPatcher::pack(dir), Err(Error::Validation(_)));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn custom_program() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let temp_dir = tempfile::tempdir().unwrap();
+        let script = temp_dir.path().join("patch.sh");
+        let cancel_signal = AtomicBool::new(false);
+
+        fs::write(
+            &script,
+            "#!/bin/sh\n\
+            [ \"$(cat \"$1/kernel.img\")\" = kernel ] || exit 1\n\
+            printf patched > \"$1/kernel.img\"\n\
+            rm ramdisk.img.0\n",
+        )
+        .unwrap();
+        fs::set_permissions(&script, fs::Permissions::from_mode(0o755)).unwrap();
+
+        let patcher = CustomBootPatcher::new(&script);
+        let mut image = v2_image(b"ramdisk");
+        patcher.patch(&mut image, &cancel_signal).unwrap();
+
+        let BootImage::V0Through2(b) = &image else {
+            panic!("Unexpected boot image type");
+        };
+        assert_eq!(b.kernel, b"patched");
+        assert!(b.ramdisk.is_empty());
+
+        // The image is already patched, so the program's check fails.
+        assert_matches!(
+            patcher.patch(&mut image, &cancel_signal),
+            Err(Error::CommandFailed(_, _))
+        );
+    }
 }
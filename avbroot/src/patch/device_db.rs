@@ -0,0 +1,188 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+};
+
+use byteorder::{LittleEndian, ReadBytesExt};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const BUILTIN_DB: &str = include_str!("device_db.toml");
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to parse device database")]
+    Parse(#[from] toml_edit::de::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Filesystem used by a system image.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Filesystem {
+    Erofs,
+    Ext4,
+}
+
+impl Filesystem {
+    const EROFS_MAGIC_OFFSET: u64 = 1024;
+    const EROFS_MAGIC: u32 = 0xe0f5e1e2;
+    const EXT4_MAGIC_OFFSET: u64 = 1024 + 0x38;
+    const EXT4_MAGIC: u16 = 0xef53;
+
+    /// Detect the filesystem from the superblock magic. Returns [`None`] if the
+    /// image is too small to contain both magic values.
+    pub fn detect(mut reader: impl Read + Seek) -> io::Result<Option<Self>> {
+        let mut buf = [0u8; (Self::EXT4_MAGIC_OFFSET + 2 - Self::EROFS_MAGIC_OFFSET) as usize];
+
+        reader.seek(SeekFrom::Start(Self::EROFS_MAGIC_OFFSET))?;
+        match reader.read_exact(&mut buf) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            Err(e) => return Err(e),
+        }
+
+        let mut cursor = Cursor::new(&buf);
+        if cursor.read_u32::<LittleEndian>()? == Self::EROFS_MAGIC {
+            return Ok(Some(Self::Erofs));
+        }
+
+        cursor.seek(SeekFrom::Start(
+            Self::EXT4_MAGIC_OFFSET - Self::EROFS_MAGIC_OFFSET,
+        ))?;
+        if cursor.read_u16::<LittleEndian>()? == Self::EXT4_MAGIC {
+            return Ok(Some(Self::Ext4));
+        }
+
+        Ok(None)
+    }
+}
+
+impl fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Erofs => f.write_str("erofs"),
+            Self::Ext4 => f.write_str("ext4"),
+        }
+    }
+}
+
+/// Known quirks and required patch options for a device.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct DevicePreset {
+    /// Human-readable device name.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// Magisk preinit block device.
+    #[serde(default)]
+    pub magisk_preinit_device: Option<String>,
+    /// Partitions containing otacerts.zip to patch.
+    #[serde(default)]
+    pub otacerts_target: Vec<String>,
+    /// Whether the vbmeta flags must be cleared for the device to boot.
+    #[serde(default)]
+    pub clear_vbmeta_flags: bool,
+    /// Expected filesystem of the system image.
+    #[serde(default)]
+    pub system_fs: Option<Filesystem>,
+    /// Maximum size of each vbmeta image.
+    #[serde(default)]
+    pub max_vbmeta_size: Option<u64>,
+    /// Guidance to show to the user when the preset is applied.
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// Table of device codenames and their presets.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct DeviceDb {
+    #[serde(rename = "device", default)]
+    pub devices: BTreeMap<String, DevicePreset>,
+}
+
+impl DeviceDb {
+    /// Load the database that is built into avbroot.
+    pub fn builtin() -> Self {
+        Self::from_toml(BUILTIN_DB).expect("Invalid built-in device database")
+    }
+
+    /// Load a database from its TOML representation.
+    pub fn from_toml(data: &str) -> Result<Self> {
+        Ok(toml_edit::de::from_str(data)?)
+    }
+
+    /// Add the entries from `other`. Entries in `other` replace existing
+    /// entries with the same codename.
+    pub fn extend(&mut self, other: Self) {
+        self.devices.extend(other.devices);
+    }
+
+    /// Find the preset for a device codename.
+    pub fn find(&self, codename: &str) -> Option<&DevicePreset> {
+        self.devices.get(codename)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_db() {
+        let mut db = DeviceDb::builtin();
+        assert_eq!(db.find("cheetah").unwrap().max_vbmeta_size, Some(65536));
+        assert!(db.find("nonexistent").is_none());
+
+        let custom = DeviceDb::from_toml(
+            r#"
+            [device.cheetah]
+            magisk_preinit_device = "persist"
+            system_fs = "erofs"
+
+            [device.custom]
+            clear_vbmeta_flags = true
+            "#,
+        )
+        .unwrap();
+        db.extend(custom);
+
+        let cheetah = db.find("cheetah").unwrap();
+        assert_eq!(cheetah.magisk_preinit_device.as_deref(), Some("persist"));
+        assert_eq!(cheetah.system_fs, Some(Filesystem::Erofs));
+        assert_eq!(cheetah.max_vbmeta_size, None);
+        assert!(db.find("custom").unwrap().clear_vbmeta_flags);
+        assert!(db.find("panther").is_some());
+    }
+
+    #[test]
+    fn detect_filesystem() {
+        let mut data = vec![0u8; 4096];
+        assert_eq!(Filesystem::detect(Cursor::new(&data)).unwrap(), None);
+
+        data[1024..1028].copy_from_slice(&0xe0f5e1e2u32.to_le_bytes());
+        assert_eq!(
+            Filesystem::detect(Cursor::new(&data)).unwrap(),
+            Some(Filesystem::Erofs),
+        );
+
+        data[1024..1028].fill(0);
+        data[1080..1082].copy_from_slice(&0xef53u16.to_le_bytes());
+        assert_eq!(
+            Filesystem::detect(Cursor::new(&data)).unwrap(),
+            Some(Filesystem::Ext4),
+        );
+
+        // Images that are too small for a superblock have no filesystem.
+        data.truncate(1081);
+        assert_eq!(Filesystem::detect(Cursor::new(&data)).unwrap(), None);
+        assert_eq!(Filesystem::detect(Cursor::new(b"")).unwrap(), None);
+    }
+}
@@ -0,0 +1,144 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::ops::Range;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+const BUILTIN_DB: &str = include_str!("magisk_compat.toml");
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Failed to parse Magisk compatibility database")]
+    Parse(#[from] toml_edit::de::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// Compatibility information for a range of Magisk versions.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MagiskCompatEntry {
+    /// Range of Magisk version codes.
+    pub versions: Range<u32>,
+    /// Whether avbroot can patch boot images with these Magisk versions.
+    pub supported: bool,
+    /// Whether the Magisk config has a PREINITDEVICE option, which requires
+    /// the user to specify a preinit device.
+    #[serde(default)]
+    pub preinit_device: bool,
+    /// Whether the Magisk config has a RANDOMSEED option.
+    #[serde(default)]
+    pub random_seed: bool,
+    /// Minimum supported Android SDK level (inclusive).
+    #[serde(default)]
+    pub min_sdk: Option<u32>,
+    /// Maximum supported Android SDK level (inclusive).
+    #[serde(default)]
+    pub max_sdk: Option<u32>,
+    /// Guidance to show to the user if a preflight check fails.
+    #[serde(default)]
+    pub note: Option<String>,
+}
+
+/// Table of Magisk version ranges and the options and Android versions that
+/// they require.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct MagiskCompatDb {
+    #[serde(rename = "magisk")]
+    pub entries: Vec<MagiskCompatEntry>,
+}
+
+impl MagiskCompatDb {
+    /// Load the database that is built into avbroot.
+    pub fn builtin() -> Self {
+        Self::from_toml(BUILTIN_DB).expect("Invalid built-in Magisk compatibility database")
+    }
+
+    /// Load a database from its TOML representation.
+    pub fn from_toml(data: &str) -> Result<Self> {
+        Ok(toml_edit::de::from_str(data)?)
+    }
+
+    /// Find the entry for a Magisk version. If multiple entries match, the
+    /// first one takes precedence.
+    pub fn find(&self, version: u32) -> Option<&MagiskCompatEntry> {
+        self.entries.iter().find(|e| e.versions.contains(&version))
+    }
+
+    /// Check if the specified Magisk version can be used with the given
+    /// options for an OTA with the given Android SDK level. Returns a list of
+    /// problems, each with guidance on how to resolve it.
+    pub fn check(&self, version: u32, has_preinit_device: bool, sdk: Option<u32>) -> Vec<String> {
+        let Some(entry) = self.find(version) else {
+            let supported = self
+                .entries
+                .iter()
+                .filter(|e| e.supported)
+                .map(|e| &e.versions)
+                .collect::<Vec<_>>();
+
+            return vec![format!(
+                "Unsupported Magisk version {version} (supported: {supported:?})",
+            )];
+        };
+
+        let with_note = |msg: String| match &entry.note {
+            Some(note) => format!("{msg}: {note}"),
+            None => msg,
+        };
+        let mut problems = vec![];
+
+        if !entry.supported {
+            problems.push(with_note(format!(
+                "Unsupported Magisk version {version} ({:?})",
+                entry.versions,
+            )));
+        }
+
+        if entry.preinit_device && !has_preinit_device {
+            problems.push(format!(
+                "Magisk version {version} ({:?}) requires a preinit device to be specified",
+                entry.versions,
+            ));
+        }
+
+        if let Some(sdk) = sdk {
+            if entry.min_sdk.is_some_and(|min| sdk < min)
+                || entry.max_sdk.is_some_and(|max| sdk > max)
+            {
+                problems.push(with_note(format!(
+                    "Magisk version {version} ({:?}) does not support Android SDK level {sdk} \
+                    (supported: {} to {})",
+                    entry.versions,
+                    entry.min_sdk.map(|s| s.to_string()).unwrap_or_default(),
+                    entry.max_sdk.map(|s| s.to_string()).unwrap_or_default(),
+                )));
+            }
+        }
+
+        problems
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builtin_db() {
+        let db = MagiskCompatDb::builtin();
+
+        assert!(db.check(25102, false, Some(33)).is_empty());
+        assert_eq!(db.check(25207, true, Some(33)).len(), 1);
+        assert_eq!(db.check(25211, false, Some(33)).len(), 1);
+        assert!(db.check(26100, true, Some(34)).is_empty());
+        assert_eq!(db.check(26100, true, Some(22)).len(), 1);
+        assert_eq!(db.check(99999, true, None).len(), 1);
+
+        assert!(db.find(25211).unwrap().random_seed);
+        assert!(!db.find(26103).unwrap().random_seed);
+    }
+}
@@ -4,5 +4,7 @@
  */
 
 pub mod boot;
+pub mod device_db;
+pub mod magisk_compat;
 pub mod otacert;
 pub mod system;
@@ -8,7 +8,7 @@ use std::{borrow::Cow, cmp::Ordering, io::Cursor};
 use bitflags::bitflags;
 use thiserror::Error;
 use x509_cert::{der::asn1::BitString, Certificate};
-use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipWriter};
+use zip::{result::ZipError, write::FileOptions, CompressionMethod, ZipArchive, ZipWriter};
 
 use crate::{crypto, format::ota};
 
@@ -143,3 +143,21 @@ pub fn create_zip_with_size(cert: &Certificate, size: usize) -> Result<Vec<u8>>
 
     Err(Error::ZipTooLarge(size))
 }
+
+/// Read all certificates from an `otacerts.zip` file. Like the framework's
+/// RecoverySystem, only entries ending in `.x509.pem` are considered.
+pub fn read_zip(data: &[u8]) -> Result<Vec<Certificate>> {
+    let mut zip = ZipArchive::new(Cursor::new(data))?;
+    let mut certificates = vec![];
+
+    for index in 0..zip.len() {
+        let entry = zip.by_index(index)?;
+        if !entry.name().ends_with(".x509.pem") {
+            continue;
+        }
+
+        certificates.push(crypto::read_pem_cert(entry)?);
+    }
+
+    Ok(certificates)
+}
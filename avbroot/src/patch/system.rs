@@ -11,14 +11,14 @@ use std::{
 
 use memchr::memmem;
 use rayon::iter::{IntoParallelIterator, ParallelIterator};
-use rsa::RsaPrivateKey;
 use thiserror::Error;
 use x509_cert::Certificate;
 use zip::ZipArchive;
 
 use crate::{
+    crypto::RsaSigningKey,
     format::{
-        avb::{self, AppendedDescriptorMut, Footer},
+        avb::{self, AlgorithmType, AppendedDescriptorMut, Footer},
         ota,
     },
     patch::otacert,
@@ -84,6 +84,76 @@ fn find_zip_bounds(data: &[u8], eocd_offset: usize) -> Option<Range<usize>> {
     Some(start..end)
 }
 
+/// Find every `otacerts.zip` in the first `data_size` bytes of the input and
+/// call `f` with the zip's byte range and its data. The data is searched in
+/// chunks in parallel and the results are returned in order of their offsets.
+fn find_zips<T: Send>(
+    input: &(dyn ReadSeekReopen + Sync),
+    data_size: u64,
+    cancel_signal: &AtomicBool,
+    f: impl Fn(Range<u64>, &[u8]) -> Result<T> + Sync,
+) -> Result<Vec<T>> {
+    // This must be a multiple of normal filesystem block sizes (eg. 4 KiB).
+    // This ensures that the block containing otacerts.zip's data won't cross
+    // chunk boundaries.
+    const CHUNK_SIZE: u64 = 2 * 1024 * 1024;
+
+    let num_chunks = util::div_ceil(data_size, CHUNK_SIZE);
+
+    (0..num_chunks)
+        .into_par_iter()
+        .map(|chunk| -> Result<Vec<T>> {
+            stream::check_cancel(cancel_signal)?;
+
+            let offset = chunk * CHUNK_SIZE;
+            let size = CHUNK_SIZE.min(data_size - offset);
+            let mut buf = vec![0u8; size as usize];
+
+            let mut reader = input.reopen_boxed()?;
+            reader.seek(SeekFrom::Start(offset))?;
+            reader.read_exact(&mut buf)?;
+
+            let mut result = vec![];
+
+            for eocd_offset_rel in memmem::find_iter(&buf, ota::ZIP_EOCD_MAGIC) {
+                let Some(bounds_rel) = find_zip_bounds(&buf, eocd_offset_rel) else {
+                    continue;
+                };
+
+                let bounds = offset + bounds_rel.start as u64..offset + bounds_rel.end as u64;
+
+                result.push(f(bounds, &buf[bounds_rel])?);
+            }
+
+            Ok(result)
+        })
+        .try_reduce(Vec::new, |mut result, item| {
+            result.extend(item);
+            Ok(result)
+        })
+}
+
+/// Find every `otacerts.zip` in a system image and return the certificates they
+/// contain. This uses the same search as [`patch_system_image()`], so a zip is
+/// found if and only if the patcher would be able to replace it.
+pub fn get_certificates(
+    input: &(dyn ReadSeekReopen + Sync),
+    cancel_signal: &AtomicBool,
+) -> Result<Vec<Certificate>> {
+    let (_, footer, image_size) = avb::load_image(input.reopen_boxed()?)?;
+    let data_size = footer.map_or(image_size, |f| f.original_image_size);
+
+    let certificates = find_zips(input, data_size, cancel_signal, |_, data| {
+        otacert::read_zip(data).map_err(Error::from)
+    })?;
+
+    if certificates.is_empty() {
+        return Err(Error::OldZipNotFound);
+    }
+
+    Ok(certificates.into_iter().flatten().collect())
+}
+
 /// Replace `otacerts.zip` with a new one containing the new certificate, but
 /// padded to the same size. If the new zip is too large, the certificate will
 /// be modified to remove unnecessary components until it fits. All operations
@@ -102,14 +172,10 @@ pub fn patch_system_image(
     input: &(dyn ReadSeekReopen + Sync),
     output: &(dyn WriteSeekReopen + Sync),
     certificate: &Certificate,
-    key: &RsaPrivateKey,
+    key: &RsaSigningKey,
+    algorithm: Option<AlgorithmType>,
     cancel_signal: &AtomicBool,
 ) -> Result<(Vec<Range<u64>>, Vec<Range<u64>>)> {
-    // This must be a multiple of normal filesystem block sizes (eg. 4 KiB).
-    // This ensures that the block containing otacerts.zip's data won't cross
-    // chunk boundaries.
-    const CHUNK_SIZE: u64 = 2 * 1024 * 1024;
-
     let (mut header, footer, image_size) = avb::load_image(input.reopen_boxed()?)?;
     let Some(mut footer) = footer else {
         return Err(Error::NoFooter);
@@ -118,48 +184,22 @@ pub fn patch_system_image(
         return Err(Error::NoHashTreeDescriptor);
     };
 
-    let num_chunks = util::div_ceil(footer.original_image_size, CHUNK_SIZE);
+    let modified_ranges = find_zips(
+        input,
+        footer.original_image_size,
+        cancel_signal,
+        |bounds, data| {
+            let new_zip = otacert::create_zip_with_size(certificate, data.len())?;
 
-    let modified_ranges = (0..num_chunks)
-        .into_par_iter()
-        .map(|chunk| -> Result<Vec<Range<u64>>> {
             stream::check_cancel(cancel_signal)?;
 
-            let offset = chunk * CHUNK_SIZE;
-            let size = CHUNK_SIZE.min(footer.original_image_size - offset);
-            let mut buf = vec![0u8; size as usize];
-
-            let mut reader = input.reopen_boxed()?;
-            reader.seek(SeekFrom::Start(offset))?;
-            reader.read_exact(&mut buf)?;
-
             let mut writer = output.reopen_boxed()?;
-            let mut ranges = Vec::<Range<u64>>::new();
-
-            for eocd_offset_rel in memmem::find_iter(&buf, ota::ZIP_EOCD_MAGIC) {
-                let Some(bounds_rel) = find_zip_bounds(&buf, eocd_offset_rel) else {
-                    continue;
-                };
-
-                let zip_size = bounds_rel.end - bounds_rel.start;
-                let new_zip = otacert::create_zip_with_size(certificate, zip_size)?;
-
-                let bounds = offset + bounds_rel.start as u64..offset + bounds_rel.end as u64;
-
-                stream::check_cancel(cancel_signal)?;
-
-                writer.seek(SeekFrom::Start(bounds.start))?;
-                writer.write_all(&new_zip)?;
-
-                ranges.push(bounds);
-            }
+            writer.seek(SeekFrom::Start(bounds.start))?;
+            writer.write_all(&new_zip)?;
 
-            Ok(ranges)
-        })
-        .try_reduce(Vec::new, |mut result, item| {
-            result.extend(item);
-            Ok(result)
-        })?;
+            Ok(bounds)
+        },
+    )?;
 
     if modified_ranges.is_empty() {
         return Err(Error::OldZipNotFound);
@@ -179,7 +219,7 @@ pub fn patch_system_image(
     descriptor.update(input, output, update_ranges, cancel_signal)?;
 
     if !header.public_key.is_empty() {
-        header.set_algo_for_key(key)?;
+        header.set_algo_for_key(key, algorithm)?;
         header.sign(key)?;
     }
 
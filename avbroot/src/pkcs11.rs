@@ -0,0 +1,406 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Signing with RSA keys that are stored on PKCS#11 tokens, like YubiKeys,
+//! Nitrokeys, or SoftHSM.
+//!
+//! Keys are specified with RFC 7512 URIs, for example:
+//!
+//! ```text
+//! pkcs11:token=avbroot;object=avb?module-path=/usr/lib/softhsm/libsofthsm2.so
+//! ```
+//!
+//! The module is loaded with the `cryptoki` crate. Only what is needed for
+//! finding a key and creating PKCS#1 v1.5 signatures is used. The private key
+//! never leaves the token.
+
+use std::{
+    collections::HashMap,
+    env, fmt, io,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use cryptoki::{
+    context::{CInitializeArgs, Pkcs11},
+    error::RvError,
+    mechanism::Mechanism,
+    object::{Attribute, AttributeType, ObjectClass, ObjectHandle},
+    session::{Session, UserType},
+    types::AuthPin,
+};
+use rsa::{BigUint, Pkcs1v15Sign, RsaPublicKey};
+use thiserror::Error;
+
+use crate::{crypto, sandbox};
+
+pub const URI_PREFIX: &str = "pkcs11:";
+
+/// Environment variable containing the path to the PKCS#11 module if the URI
+/// has no `module-path` query attribute.
+pub const MODULE_ENV_VAR: &str = "AVBROOT_PKCS11_MODULE";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("Invalid or unsupported PKCS#11 URI component: {0:?}")]
+    InvalidUri(String),
+    #[error("PKCS#11 URI has no module-path and {MODULE_ENV_VAR} is not set")]
+    NoModule,
+    #[error("Failed to load PKCS#11 module: {0:?}")]
+    LoadModule(PathBuf, #[source] cryptoki::error::Error),
+    #[error("PKCS#11 error")]
+    Pkcs11(#[from] cryptoki::error::Error),
+    #[error("No token matches: {0}")]
+    TokenNotFound(String),
+    #[error("No private key matches: {0}")]
+    KeyNotFound(String),
+    #[error("Multiple private keys match: {0}")]
+    MultipleKeys(String),
+    #[error("Token does not expose the RSA public key: {0}")]
+    PublicKeyUnavailable(String),
+    #[error("Failed to read PIN file: {0:?}")]
+    PinFile(PathBuf, #[source] io::Error),
+    #[error("Failed to acquire PIN")]
+    Pin(#[source] Box<crypto::Error>),
+    #[error("Token has an invalid RSA public key")]
+    InvalidPublicKey(#[source] rsa::Error),
+    #[error("Token produced an invalid signature")]
+    InvalidSignature(#[source] rsa::Error),
+}
+
+type Result<T> = std::result::Result<T, Error>;
+
+/// A parsed RFC 7512 PKCS#11 URI. Only the attributes that are relevant for
+/// selecting a private key are supported.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Pkcs11Uri {
+    /// Token label.
+    pub token: Option<String>,
+    /// Key label.
+    pub object: Option<String>,
+    /// Key ID.
+    pub id: Option<Vec<u8>>,
+    pub module_path: Option<PathBuf>,
+    pub pin_value: Option<String>,
+    pub pin_source: Option<PathBuf>,
+}
+
+impl Pkcs11Uri {
+    pub fn parse(uri: &str) -> Result<Self> {
+        // Values are never included in errors because they may contain the
+        // PIN.
+        let invalid = |key: &str| Error::InvalidUri(key.to_owned());
+        let decode_str = |key, value| {
+            percent_decode(value)
+                .and_then(|v| String::from_utf8(v).ok())
+                .ok_or_else(|| invalid(key))
+        };
+
+        let rest = uri
+            .strip_prefix(URI_PREFIX)
+            .ok_or_else(|| invalid("scheme"))?;
+        let (path, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut result = Self::default();
+
+        for attr in path.split(';').filter(|a| !a.is_empty()) {
+            let (key, value) = attr.split_once('=').ok_or_else(|| invalid(attr))?;
+
+            match key {
+                "token" => result.token = Some(decode_str(key, value)?),
+                "object" => result.object = Some(decode_str(key, value)?),
+                "id" => result.id = Some(percent_decode(value).ok_or_else(|| invalid(key))?),
+                "type" if value == "private" => {}
+                _ => return Err(invalid(key)),
+            }
+        }
+
+        for attr in query.split('&').filter(|a| !a.is_empty()) {
+            let (key, value) = attr.split_once('=').ok_or_else(|| invalid(attr))?;
+
+            match key {
+                "module-path" => result.module_path = Some(decode_str(key, value)?.into()),
+                "pin-value" => result.pin_value = Some(decode_str(key, value)?),
+                "pin-source" => {
+                    let value = decode_str(key, value)?;
+                    let path = value.strip_prefix("file:").unwrap_or(&value);
+                    result.pin_source = Some(path.into());
+                }
+                _ => return Err(invalid(key)),
+            }
+        }
+
+        Ok(result)
+    }
+
+    /// Human-readable description of the key that does not include the PIN.
+    pub fn description(&self) -> String {
+        let mut parts = vec![];
+
+        if let Some(token) = &self.token {
+            parts.push(format!("token={token}"));
+        }
+        if let Some(object) = &self.object {
+            parts.push(format!("object={object}"));
+        }
+        if let Some(id) = &self.id {
+            parts.push(format!("id={}", hex::encode(id)));
+        }
+
+        format!("{URI_PREFIX}{}", parts.join(";"))
+    }
+}
+
+fn percent_decode(value: &str) -> Option<Vec<u8>> {
+    let mut result = vec![];
+    let mut bytes = value.bytes();
+
+    while let Some(b) = bytes.next() {
+        if b == b'%' {
+            let hex = [bytes.next()?, bytes.next()?];
+            result.push(u8::from_str_radix(std::str::from_utf8(&hex).ok()?, 16).ok()?);
+        } else {
+            result.push(b);
+        }
+    }
+
+    Some(result)
+}
+
+/// Load and initialize the module at `path`, reusing the existing instance if
+/// it was already loaded. A module can only be initialized once per process, so
+/// modules stay loaded until the process exits.
+fn load_module(path: &Path) -> Result<Pkcs11> {
+    static MODULES: OnceLock<Mutex<HashMap<PathBuf, Pkcs11>>> = OnceLock::new();
+
+    let mut modules = MODULES.get_or_init(Mutex::default).lock().unwrap();
+    if let Some(module) = modules.get(path) {
+        return Ok(module.clone());
+    }
+
+    let module = Pkcs11::new(path).map_err(|e| Error::LoadModule(path.to_owned(), e))?;
+
+    match module.initialize(CInitializeArgs::OsThreads) {
+        Ok(()) | Err(cryptoki::error::Error::Pkcs11(RvError::CryptokiAlreadyInitialized)) => {}
+        Err(e) => return Err(Error::LoadModule(path.to_owned(), e)),
+    }
+
+    modules.insert(path.to_owned(), module.clone());
+
+    Ok(module)
+}
+
+/// Get the RSA public key components of `object`. Returns [`None`] if the token
+/// does not expose them.
+fn get_rsa_public_key(session: &Session, object: ObjectHandle) -> Result<Option<RsaPublicKey>> {
+    let attrs = session.get_attributes(
+        object,
+        &[AttributeType::Modulus, AttributeType::PublicExponent],
+    )?;
+    let mut n = None;
+    let mut e = None;
+
+    for attr in attrs {
+        match attr {
+            Attribute::Modulus(v) => n = Some(v),
+            Attribute::PublicExponent(v) => e = Some(v),
+            _ => {}
+        }
+    }
+
+    let (Some(n), Some(e)) = (n, e) else {
+        return Ok(None);
+    };
+
+    RsaPublicKey::new(BigUint::from_bytes_be(&n), BigUint::from_bytes_be(&e))
+        .map(Some)
+        .map_err(Error::InvalidPublicKey)
+}
+
+/// An RSA private key on a PKCS#11 token.
+pub struct Pkcs11Key {
+    /// Sessions cannot be used from multiple threads at the same time.
+    session: Mutex<Session>,
+    key: ObjectHandle,
+    public_key: RsaPublicKey,
+    description: String,
+}
+
+impl fmt::Debug for Pkcs11Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pkcs11Key")
+            .field("description", &self.description)
+            .field("public_key", &self.public_key)
+            .finish()
+    }
+}
+
+impl Pkcs11Key {
+    /// Open the key specified by `uri`. If the token requires logging in and
+    /// the URI does not specify the PIN, then `pin` is called with a
+    /// description of the key to acquire it.
+    pub fn open(
+        uri: &str,
+        pin: impl FnOnce(&str) -> std::result::Result<String, crypto::Error>,
+    ) -> Result<Self> {
+        let uri = Pkcs11Uri::parse(uri)?;
+        let description = uri.description();
+
+        let module_path = match &uri.module_path {
+            Some(p) => p.clone(),
+            None => env::var_os(MODULE_ENV_VAR)
+                .map(PathBuf::from)
+                .ok_or(Error::NoModule)?,
+        };
+        let module = load_module(&module_path)?;
+
+        let (slot, login_required) = find_token(&module, uri.token.as_deref())?
+            .ok_or_else(|| Error::TokenNotFound(description.clone()))?;
+
+        let session = module.open_ro_session(slot)?;
+
+        if login_required {
+            let pin = if let Some(p) = &uri.pin_value {
+                p.clone()
+            } else if let Some(p) = &uri.pin_source {
+                sandbox::read_to_string(p)
+                    .map_err(|e| Error::PinFile(p.clone(), e))?
+                    .trim_end_matches(['\r', '\n'])
+                    .to_owned()
+            } else {
+                pin(&description).map_err(|e| Error::Pin(Box::new(e)))?
+            };
+
+            match session.login(UserType::User, Some(&AuthPin::new(pin))) {
+                Ok(()) | Err(cryptoki::error::Error::Pkcs11(RvError::UserAlreadyLoggedIn)) => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let find = |class| {
+            let mut template = vec![Attribute::Class(class)];
+            if let Some(object) = &uri.object {
+                template.push(Attribute::Label(object.as_bytes().to_vec()));
+            }
+            if let Some(id) = &uri.id {
+                template.push(Attribute::Id(id.clone()));
+            }
+
+            session.find_objects(&template)
+        };
+
+        let key = match find(ObjectClass::PRIVATE_KEY)?.as_slice() {
+            [] => return Err(Error::KeyNotFound(description)),
+            [k] => *k,
+            _ => return Err(Error::MultipleKeys(description)),
+        };
+
+        // Not all tokens expose the public components on the private key
+        // object, so fall back to the matching public key object.
+        let public_key = match get_rsa_public_key(&session, key)? {
+            Some(k) => k,
+            None => {
+                let object = find(ObjectClass::PUBLIC_KEY)?
+                    .first()
+                    .copied()
+                    .ok_or_else(|| Error::PublicKeyUnavailable(description.clone()))?;
+
+                get_rsa_public_key(&session, object)?
+                    .ok_or_else(|| Error::PublicKeyUnavailable(description.clone()))?
+            }
+        };
+
+        Ok(Self {
+            session: Mutex::new(session),
+            key,
+            public_key,
+            description,
+        })
+    }
+
+    pub fn public_key(&self) -> &RsaPublicKey {
+        &self.public_key
+    }
+
+    /// Create a PKCS#1 v1.5 signature of `digest` on the token. The signature is
+    /// verified before it is returned to guard against misbehaving tokens.
+    pub fn sign(&self, scheme: Pkcs1v15Sign, digest: &[u8]) -> Result<Vec<u8>> {
+        let mut data = scheme.prefix.to_vec();
+        data.extend_from_slice(digest);
+
+        let signature = self
+            .session
+            .lock()
+            .unwrap()
+            .sign(&Mechanism::RsaPkcs, self.key, &data)?;
+
+        self.public_key
+            .verify(scheme, digest, &signature)
+            .map_err(Error::InvalidSignature)?;
+
+        Ok(signature)
+    }
+}
+
+/// Find the first slot with a token whose label matches `label`. Returns the
+/// slot and whether the token requires logging in.
+fn find_token(
+    module: &Pkcs11,
+    label: Option<&str>,
+) -> Result<Option<(cryptoki::slot::Slot, bool)>> {
+    for slot in module.get_slots_with_token()? {
+        let info = module.get_token_info(slot)?;
+
+        if label.is_none_or(|l| l == info.label().trim_end_matches(' ')) {
+            return Ok(Some((slot, info.login_required())));
+        }
+    }
+
+    Ok(None)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::Pkcs11Uri;
+
+    #[test]
+    fn parse_uri() {
+        let uri = Pkcs11Uri::parse(
+            "pkcs11:token=My%20Token;object=avb;id=%01%02;type=private\
+            ?module-path=/usr/lib/libsofthsm2.so&pin-source=file:/tmp/pin",
+        )
+        .unwrap();
+
+        assert_eq!(
+            uri,
+            Pkcs11Uri {
+                token: Some("My Token".to_owned()),
+                object: Some("avb".to_owned()),
+                id: Some(vec![1, 2]),
+                module_path: Some(PathBuf::from("/usr/lib/libsofthsm2.so")),
+                pin_value: None,
+                pin_source: Some(PathBuf::from("/tmp/pin")),
+            },
+        );
+        assert_eq!(
+            uri.description(),
+            "pkcs11:token=My Token;object=avb;id=0102"
+        );
+
+        assert_eq!(
+            Pkcs11Uri::parse("pkcs11:?pin-value=1234")
+                .unwrap()
+                .pin_value
+                .as_deref(),
+            Some("1234"),
+        );
+
+        assert!(Pkcs11Uri::parse("pkcs11:serial=1234").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:id=%0").is_err());
+        assert!(Pkcs11Uri::parse("pkcs11:type=public").is_err());
+        assert!(Pkcs11Uri::parse("file:avb.key").is_err());
+    }
+}
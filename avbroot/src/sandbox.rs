@@ -0,0 +1,334 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+//! Optional restriction of filesystem access to explicitly granted directories.
+//!
+//! By default, every function in this module behaves like its [`std::fs`]
+//! counterpart. Once [`restrict()`] is called, paths are only accessible if they
+//! are inside one of the granted directories. Files are then opened relative to
+//! the granted directory with [`cap_std`], so symlinks and `..` components
+//! cannot be used to escape it.
+//!
+//! Anonymous temporary files are not affected by the restriction. Temporary
+//! directories must be created with [`tempdir()`] or [`cap_tempdir()`] so that
+//! they are placed inside a granted directory.
+
+use std::{
+    fs::{self, File},
+    io,
+    path::{self, Component, Path, PathBuf},
+    sync::OnceLock,
+};
+
+use cap_std::{ambient_authority, fs::Dir};
+use tempfile::TempDir;
+
+struct Grant {
+    path: PathBuf,
+    dir: Dir,
+}
+
+static GRANTS: OnceLock<Vec<Grant>> = OnceLock::new();
+
+/// Restrict all filesystem access performed through this module to the
+/// specified directories. This can only be called once.
+pub fn restrict(dirs: &[PathBuf]) -> io::Result<()> {
+    let mut grants = vec![];
+
+    for dir in dirs {
+        let path = path::absolute(dir)?;
+        let dir = Dir::open_ambient_dir(&path, ambient_authority())?;
+
+        grants.push(Grant { path, dir });
+    }
+
+    GRANTS
+        .set(grants)
+        .map_err(|_| io::Error::other("Sandbox is already initialized"))
+}
+
+/// Whether filesystem access is restricted to granted directories.
+pub fn is_restricted() -> bool {
+    GRANTS.get().is_some()
+}
+
+/// Find the granted directory containing `path` and the path relative to it.
+/// Returns [`None`] if filesystem access is not restricted.
+fn resolve(path: &Path) -> io::Result<Option<(&'static Dir, PathBuf)>> {
+    let Some(grants) = GRANTS.get() else {
+        return Ok(None);
+    };
+
+    // Collapse `..` components lexically so that paths which leave and then
+    // reenter a granted directory still match. cap-std rejects any escapes
+    // that remain, including via symlinks.
+    let mut absolute = PathBuf::new();
+    for component in path::absolute(path)?.components() {
+        match component {
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            Component::CurDir => {}
+            c => absolute.push(c),
+        }
+    }
+
+    for grant in grants {
+        if let Ok(relative) = absolute.strip_prefix(&grant.path) {
+            let relative = if relative.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                relative
+            };
+
+            return Ok(Some((&grant.dir, relative.to_owned())));
+        }
+    }
+
+    Err(io::Error::new(
+        io::ErrorKind::PermissionDenied,
+        format!("Path is outside of the granted directories: {path:?}"),
+    ))
+}
+
+/// Ensure that `path` is accessible without opening it. This is for operations
+/// that cannot be performed through a directory handle, like creating named
+/// temporary files.
+pub fn check(path: &Path) -> io::Result<()> {
+    resolve(path).map(|_| ())
+}
+
+/// Like [`Path::is_dir()`]. Paths outside of the granted directories are never
+/// directories.
+pub fn is_dir(path: impl AsRef<Path>) -> bool {
+    let path = path.as_ref();
+
+    match resolve(path) {
+        Ok(Some((dir, relative))) => dir.is_dir(relative),
+        Ok(None) => path.is_dir(),
+        Err(_) => false,
+    }
+}
+
+/// Options for opening files. This mirrors the subset of
+/// [`std::fs::OpenOptions`] that avbroot uses.
+#[derive(Clone, Debug, Default)]
+pub struct OpenOptions {
+    read: bool,
+    write: bool,
+    append: bool,
+    create: bool,
+    truncate: bool,
+    #[cfg(unix)]
+    mode: Option<u32>,
+}
+
+impl OpenOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&mut self, read: bool) -> &mut Self {
+        self.read = read;
+        self
+    }
+
+    pub fn write(&mut self, write: bool) -> &mut Self {
+        self.write = write;
+        self
+    }
+
+    pub fn append(&mut self, append: bool) -> &mut Self {
+        self.append = append;
+        self
+    }
+
+    pub fn create(&mut self, create: bool) -> &mut Self {
+        self.create = create;
+        self
+    }
+
+    pub fn truncate(&mut self, truncate: bool) -> &mut Self {
+        self.truncate = truncate;
+        self
+    }
+
+    #[cfg(unix)]
+    pub fn mode(&mut self, mode: u32) -> &mut Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    pub fn open(&self, path: impl AsRef<Path>) -> io::Result<File> {
+        let path = path.as_ref();
+
+        match resolve(path)? {
+            Some((dir, relative)) => {
+                let mut options = cap_std::fs::OpenOptions::new();
+                options
+                    .read(self.read)
+                    .write(self.write)
+                    .append(self.append)
+                    .create(self.create)
+                    .truncate(self.truncate);
+
+                #[cfg(unix)]
+                if let Some(mode) = self.mode {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(mode);
+                }
+
+                dir.open_with(relative, &options).map(|f| f.into_std())
+            }
+            None => {
+                let mut options = fs::OpenOptions::new();
+                options
+                    .read(self.read)
+                    .write(self.write)
+                    .append(self.append)
+                    .create(self.create)
+                    .truncate(self.truncate);
+
+                #[cfg(unix)]
+                if let Some(mode) = self.mode {
+                    use std::os::unix::fs::OpenOptionsExt;
+                    options.mode(mode);
+                }
+
+                options.open(path)
+            }
+        }
+    }
+}
+
+/// Like [`File::open()`].
+pub fn open(path: impl AsRef<Path>) -> io::Result<File> {
+    OpenOptions::new().read(true).open(path)
+}
+
+/// Like [`File::create()`].
+pub fn create(path: impl AsRef<Path>) -> io::Result<File> {
+    OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+}
+
+/// Like [`fs::read()`].
+pub fn read(path: impl AsRef<Path>) -> io::Result<Vec<u8>> {
+    let path = path.as_ref();
+
+    match resolve(path)? {
+        Some((dir, relative)) => dir.read(relative),
+        None => fs::read(path),
+    }
+}
+
+/// Like [`fs::read_to_string()`].
+pub fn read_to_string(path: impl AsRef<Path>) -> io::Result<String> {
+    let path = path.as_ref();
+
+    match resolve(path)? {
+        Some((dir, relative)) => dir.read_to_string(relative),
+        None => fs::read_to_string(path),
+    }
+}
+
+/// Like [`fs::write()`].
+pub fn write(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+    let path = path.as_ref();
+
+    match resolve(path)? {
+        Some((dir, relative)) => dir.write(relative, contents),
+        None => fs::write(path, contents),
+    }
+}
+
+/// Like [`fs::create_dir_all()`].
+pub fn create_dir_all(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+
+    match resolve(path)? {
+        Some((dir, relative)) => dir.create_dir_all(relative),
+        None => fs::create_dir_all(path),
+    }
+}
+
+/// Like [`fs::remove_file()`].
+pub fn remove_file(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+
+    match resolve(path)? {
+        Some((dir, relative)) => dir.remove_file(relative),
+        None => fs::remove_file(path),
+    }
+}
+
+/// Like [`fs::rename()`].
+pub fn rename(from: impl AsRef<Path>, to: impl AsRef<Path>) -> io::Result<()> {
+    let from = from.as_ref();
+    let to = to.as_ref();
+
+    match (resolve(from)?, resolve(to)?) {
+        (Some((from_dir, from_relative)), Some((to_dir, to_relative))) => {
+            from_dir.rename(from_relative, to_dir, to_relative)
+        }
+        _ => fs::rename(from, to),
+    }
+}
+
+/// List the paths of the entries in a directory.
+pub fn read_dir(path: impl AsRef<Path>) -> io::Result<Vec<PathBuf>> {
+    let path = path.as_ref();
+    let mut paths = vec![];
+
+    match resolve(path)? {
+        Some((dir, relative)) => {
+            for entry in dir.read_dir(relative)? {
+                paths.push(path.join(entry?.file_name()));
+            }
+        }
+        None => {
+            for entry in fs::read_dir(path)? {
+                paths.push(entry?.path());
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+/// Open a directory handle. Unlike [`Dir::open_ambient_dir()`], this respects
+/// the restriction to granted directories.
+pub fn open_dir(path: impl AsRef<Path>) -> io::Result<Dir> {
+    let path = path.as_ref();
+
+    match resolve(path)? {
+        Some((dir, relative)) => dir.open_dir(relative),
+        None => Dir::open_ambient_dir(path, ambient_authority()),
+    }
+}
+
+/// Create a temporary directory that can be accessed by path. If filesystem
+/// access is restricted, it is created inside the first granted directory
+/// instead of the system temporary directory.
+pub fn tempdir() -> io::Result<TempDir> {
+    match GRANTS.get().and_then(|g| g.first()) {
+        Some(grant) => tempfile::Builder::new()
+            .prefix(".avbroot-")
+            .tempdir_in(&grant.path),
+        None => tempfile::tempdir(),
+    }
+}
+
+/// Like [`tempdir()`], but the temporary directory is only accessible via its
+/// directory handle.
+pub fn cap_tempdir() -> io::Result<cap_tempfile::TempDir> {
+    match GRANTS.get().and_then(|g| g.first()) {
+        Some(grant) => cap_tempfile::TempDir::new_in(&grant.dir),
+        None => cap_tempfile::TempDir::new(ambient_authority()),
+    }
+}
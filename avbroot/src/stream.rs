@@ -4,19 +4,24 @@
  */
 
 use std::{
+    cell::RefCell,
     fs::File,
     io::{self, BufReader, BufWriter, Cursor, Read, Seek, SeekFrom, Write},
+    mem, panic,
+    rc::Rc,
     sync::{
         atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, SyncSender},
         Arc, Mutex, RwLock,
     },
+    thread::{self, JoinHandle},
+    time::Duration,
 };
 
 use bstr::ByteSlice;
 use num_traits::ToPrimitive;
-use ring::digest::Context;
 
-use crate::util;
+use crate::{digest::Context, util};
 
 /// A trait for seekable readers. This is only needed because `dyn Read + Seek`
 /// is not a valid construct in Rust yet.
@@ -341,6 +346,128 @@ impl<W: Write> Write for HashingWriter<W> {
     }
 }
 
+/// Size of the buffers passed to the hashing thread of
+/// [`ThreadedHashingWriter`].
+const THREADED_HASHING_BUF_SIZE: usize = 1024 * 1024;
+
+/// A writer wrapper like [`HashingWriter`], except that the hash is computed on
+/// a dedicated thread. Written data is collected into buffers that are handed
+/// off to the hashing thread once full. One buffer can be hashed while the next
+/// one is being filled, so hashing only slows down writing if it cannot keep up.
+///
+/// With only one CPU, the extra copy makes this slower than hashing inline, so
+/// the hash is computed on the writing thread instead, like [`HashingWriter`].
+pub struct ThreadedHashingWriter<W: Write> {
+    inner: W,
+    buf: Vec<u8>,
+    data_tx: Option<SyncSender<Vec<u8>>>,
+    free_rx: Receiver<Vec<u8>>,
+    thread: Option<JoinHandle<Context>>,
+    inline_context: Option<Context>,
+}
+
+impl<W: Write> ThreadedHashingWriter<W> {
+    pub fn new(inner: W, context: Context) -> Self {
+        let threaded = thread::available_parallelism().map_or(1, |n| n.get()) > 1;
+
+        Self::with_threading(inner, context, threaded)
+    }
+
+    fn with_threading(inner: W, mut context: Context, threaded: bool) -> Self {
+        // At most one buffer is queued while another is being hashed.
+        let (data_tx, data_rx) = mpsc::sync_channel::<Vec<u8>>(1);
+        let (free_tx, free_rx) = mpsc::channel();
+
+        if !threaded {
+            return Self {
+                inner,
+                buf: vec![],
+                data_tx: None,
+                free_rx,
+                thread: None,
+                inline_context: Some(context),
+            };
+        }
+
+        let thread = thread::spawn(move || {
+            for mut buf in data_rx {
+                context.update(&buf);
+
+                // Return the buffer for reuse. The writer may already be gone.
+                buf.clear();
+                let _ = free_tx.send(buf);
+            }
+
+            context
+        });
+
+        Self {
+            inner,
+            buf: Vec::with_capacity(THREADED_HASHING_BUF_SIZE),
+            data_tx: Some(data_tx),
+            free_rx,
+            thread: Some(thread),
+            inline_context: None,
+        }
+    }
+
+    fn send_buf(&mut self) -> io::Result<()> {
+        let next = self
+            .free_rx
+            .try_recv()
+            .unwrap_or_else(|_| Vec::with_capacity(THREADED_HASHING_BUF_SIZE));
+        let data = mem::replace(&mut self.buf, next);
+
+        self.data_tx
+            .as_ref()
+            .unwrap()
+            .send(data)
+            .map_err(|_| io::Error::other("Hashing thread exited unexpectedly"))
+    }
+
+    pub fn finish(mut self) -> (W, Context) {
+        if let Some(context) = self.inline_context.take() {
+            return (self.inner, context);
+        }
+
+        if !self.buf.is_empty() {
+            // If this fails, the thread panicked and joining will report it.
+            let _ = self.send_buf();
+        }
+
+        drop(self.data_tx.take());
+
+        let context = match self.thread.take().unwrap().join() {
+            Ok(c) => c,
+            Err(e) => panic::resume_unwind(e),
+        };
+
+        (self.inner, context)
+    }
+}
+
+impl<W: Write> Write for ThreadedHashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+
+        if let Some(context) = &mut self.inline_context {
+            context.update(&buf[..n]);
+            return Ok(n);
+        }
+
+        self.buf.extend_from_slice(&buf[..n]);
+        if self.buf.len() >= THREADED_HASHING_BUF_SIZE {
+            self.send_buf()?;
+        }
+
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 /// A reader wrapper that only allows reading a specific section of a file.
 pub struct SectionReader<R: Read + Seek> {
     inner: R,
@@ -416,6 +543,212 @@ impl<R: Read + Seek> Seek for SectionReader<R> {
     }
 }
 
+/// A reader that splices a section of another file between two in-memory
+/// buffers. This allows wrapping a large file in a container format without
+/// copying it. See [`SpliceWriter`] for creating the buffers.
+pub struct SplicedReader<R: Read + Seek> {
+    head: Arc<[u8]>,
+    inner: R,
+    size: u64,
+    tail: Arc<[u8]>,
+    pos: u64,
+}
+
+impl<R: Read + Seek> SplicedReader<R> {
+    /// The reader will contain `head`, then the first `size` bytes of `inner`,
+    /// then `tail`.
+    pub fn new(
+        head: impl Into<Arc<[u8]>>,
+        inner: R,
+        size: u64,
+        tail: impl Into<Arc<[u8]>>,
+    ) -> Self {
+        Self {
+            head: head.into(),
+            inner,
+            size,
+            tail: tail.into(),
+            pos: 0,
+        }
+    }
+
+    fn len(&self) -> u64 {
+        self.head.len() as u64 + self.size + self.tail.len() as u64
+    }
+}
+
+impl<R: Read + Seek + Reopen> Reopen for SplicedReader<R> {
+    fn reopen(&self) -> io::Result<Self> {
+        Ok(Self {
+            head: self.head.clone(),
+            inner: self.inner.reopen()?,
+            size: self.size,
+            tail: self.tail.clone(),
+            pos: 0,
+        })
+    }
+}
+
+impl<R: Read + Seek> Read for SplicedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let head_size = self.head.len() as u64;
+        let inner_end = head_size + self.size;
+
+        let n = if self.pos < head_size {
+            (&self.head[self.pos as usize..]).read(buf)?
+        } else if self.pos < inner_end {
+            let to_read = (inner_end - self.pos).min(buf.len() as u64) as usize;
+            self.inner.seek(SeekFrom::Start(self.pos - head_size))?;
+            self.inner.read(&mut buf[..to_read])?
+        } else {
+            let offset = (self.pos - inner_end).min(self.tail.len() as u64) as usize;
+            (&self.tail[offset..]).read(buf)?
+        };
+
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Read + Seek> Seek for SplicedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(o) => {
+                self.pos = o;
+                return Ok(o);
+            }
+            SeekFrom::End(o) => (self.len(), o),
+            SeekFrom::Current(o) => (self.pos, o),
+        };
+
+        self.pos = base
+            .to_i64()
+            .and_then(|s| s.checked_add(offset))
+            .and_then(|s| s.to_u64())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Offset would be before the start of the file",
+                )
+            })?;
+
+        Ok(self.pos)
+    }
+}
+
+#[derive(Default)]
+struct SpliceState {
+    head: Vec<u8>,
+    tail: Vec<u8>,
+    /// Start and end offsets of the discarded section.
+    skip: Option<(u64, u64)>,
+    pos: u64,
+}
+
+/// A writer that keeps data in memory, except for a single section whose data
+/// is discarded. Clones share the same state so that the section can be marked
+/// while another writer, like a [`zip::ZipWriter`], owns an instance. The
+/// buffers are meant to be passed to [`SplicedReader::new()`] along with the
+/// file that the discarded data came from.
+#[derive(Clone, Default)]
+pub struct SpliceWriter(Rc<RefCell<SpliceState>>);
+
+impl SpliceWriter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Discard the next `size` bytes written at the current position.
+    pub fn skip(&self, size: u64) -> io::Result<()> {
+        let mut state = self.0.borrow_mut();
+        if state.skip.is_some() {
+            return Err(io::Error::other("Section to skip was already set"));
+        } else if state.pos != state.head.len() as u64 {
+            return Err(io::Error::other("Section to skip must be at the end"));
+        }
+
+        let end = state
+            .pos
+            .checked_add(size)
+            .ok_or_else(|| io::Error::other("Section to skip is too large"))?;
+        state.skip = Some((state.pos, end));
+
+        Ok(())
+    }
+
+    /// Get the data before and after the discarded section.
+    pub fn buffers(&self) -> (Vec<u8>, Vec<u8>) {
+        let state = self.0.borrow();
+        (state.head.clone(), state.tail.clone())
+    }
+}
+
+impl Write for SpliceWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.0.borrow_mut();
+        let state = &mut *state;
+        let (start, end) = state.skip.unwrap_or((u64::MAX, u64::MAX));
+
+        // Only write up to the next boundary so that each write is entirely
+        // within one region.
+        let (data, offset, limit) = if state.pos < start {
+            (Some(&mut state.head), state.pos, start - state.pos)
+        } else if state.pos < end {
+            (None, 0, end - state.pos)
+        } else {
+            (Some(&mut state.tail), state.pos - end, u64::MAX)
+        };
+        let n = limit.min(buf.len() as u64) as usize;
+
+        if let Some(data) = data {
+            let offset = usize::try_from(offset)
+                .map_err(|_| io::Error::other("Offset is too large for memory"))?;
+            if data.len() < offset + n {
+                data.resize(offset + n, 0);
+            }
+            data[offset..offset + n].copy_from_slice(&buf[..n]);
+        }
+
+        state.pos += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Seek for SpliceWriter {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let mut state = self.0.borrow_mut();
+        let len = match state.skip {
+            Some((_, end)) => end + state.tail.len() as u64,
+            None => state.head.len() as u64,
+        };
+        let (base, offset) = match pos {
+            SeekFrom::Start(o) => {
+                state.pos = o;
+                return Ok(o);
+            }
+            SeekFrom::End(o) => (len, o),
+            SeekFrom::Current(o) => (state.pos, o),
+        };
+
+        state.pos = base
+            .to_i64()
+            .and_then(|s| s.checked_add(offset))
+            .and_then(|s| s.to_u64())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Offset would be before the start of the file",
+                )
+            })?;
+
+        Ok(state.pos)
+    }
+}
+
 /// A writer wrapper that seeks instead of writing when a write buffer consists
 /// solely of zeros.
 #[derive(Debug)]
@@ -448,6 +781,12 @@ impl<W: Write + Seek> Write for HolePunchingWriter<W> {
     }
 }
 
+impl<W: Write + Seek> Seek for HolePunchingWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.inner.seek(pos)
+    }
+}
+
 /// A file wrapper that uses a userspace file offset. A reopened instance uses
 /// the same underlying kernel file descriptor, but a new userspace file offset,
 /// initially set to 0.
@@ -471,6 +810,11 @@ impl PSeekFile {
         file_locked.set_len(size)
     }
 
+    pub fn sync_all(&self) -> io::Result<()> {
+        let file_locked = self.file.read().unwrap();
+        file_locked.sync_all()
+    }
+
     /// Read data from offset. The kernel's file position *will* be changed.
     #[cfg(windows)]
     fn read_at(&self, buf: &mut [u8]) -> io::Result<usize> {
@@ -629,6 +973,261 @@ impl Seek for SharedCursor {
     }
 }
 
+/// Smallest amount of data that [`HttpFile`] requests at a time.
+const HTTP_MIN_READ_AHEAD: usize = 64 * 1024;
+/// Largest amount of data that [`HttpFile`] requests at a time.
+const HTTP_MAX_READ_AHEAD: usize = 8 * 1024 * 1024;
+/// Number of times a failed range request is retried.
+const HTTP_RETRIES: u32 = 3;
+
+#[derive(Debug)]
+struct HttpShared {
+    agent: ureq::Agent,
+    url: String,
+    size: u64,
+    /// Strong ETag or Last-Modified value from when the file was opened. This
+    /// is sent as If-Range so that the file cannot change between requests.
+    validator: Option<String>,
+}
+
+impl HttpShared {
+    fn changed_error(&self) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("File changed on server since it was opened: {}", self.url),
+        )
+    }
+
+    /// Request `size` bytes starting at `offset` once.
+    fn fetch_once(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let end = offset + size as u64 - 1;
+
+        let mut request = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={offset}-{end}"));
+        if let Some(validator) = &self.validator {
+            request = request.set("If-Range", validator);
+        }
+
+        let response = match request.call() {
+            Ok(r) => r,
+            Err(ureq::Error::Status(412, _)) => return Err(self.changed_error()),
+            Err(e) => return Err(io::Error::other(e)),
+        };
+
+        // If the validator no longer matches, the server sends the whole file
+        // instead of the range.
+        if response.status() == 200 && self.validator.is_some() {
+            return Err(self.changed_error());
+        } else if response.status() != 206 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Server does not support range requests: {}", self.url),
+            ));
+        }
+
+        let expected = format!("bytes {offset}-{end}/");
+        if !response
+            .header("Content-Range")
+            .is_some_and(|r| r.starts_with(&expected))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Server returned the wrong range: {}", self.url),
+            ));
+        }
+
+        let mut data = Vec::with_capacity(size);
+        response
+            .into_reader()
+            .take(size as u64)
+            .read_to_end(&mut data)?;
+
+        if data.len() != size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!(
+                    "Expected {size} bytes, but got {}: {}",
+                    data.len(),
+                    self.url
+                ),
+            ));
+        }
+
+        Ok(data)
+    }
+
+    /// Request `size` bytes starting at `offset`, retrying transient failures.
+    fn fetch(&self, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        let mut attempt = 0;
+
+        loop {
+            match self.fetch_once(offset, size) {
+                Ok(data) => return Ok(data),
+                Err(e)
+                    if attempt < HTTP_RETRIES
+                        && !matches!(
+                            e.kind(),
+                            io::ErrorKind::Unsupported | io::ErrorKind::InvalidData
+                        ) =>
+                {
+                    attempt += 1;
+                    thread::sleep(Duration::from_secs(attempt.into()));
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+}
+
+/// A read-only file on an HTTP(S) server that is accessed via range requests.
+/// Like [`PSeekFile`], a reopened instance shares the connection pool, but has
+/// its own file offset, initially set to 0.
+///
+/// Each instance keeps a read-ahead buffer. The amount of data requested at a
+/// time starts small and doubles for every read that continues where the
+/// previous request left off, so that sequential reads need few requests and
+/// random reads do not download much more than needed.
+#[derive(Debug)]
+pub struct HttpFile {
+    shared: Arc<HttpShared>,
+    offset: u64,
+    buf: Vec<u8>,
+    buf_offset: u64,
+    read_ahead: usize,
+}
+
+impl HttpFile {
+    /// Open the file at `url`. The server must support range requests.
+    pub fn open(url: &str) -> io::Result<Self> {
+        let agent = ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(30))
+            .timeout_read(Duration::from_secs(60))
+            .build();
+
+        // Fetch the first byte to find the file size and make sure that range
+        // requests work.
+        let response = agent
+            .get(url)
+            .set("Range", "bytes=0-0")
+            .call()
+            .map_err(io::Error::other)?;
+
+        let size = if response.status() == 206 {
+            response
+                .header("Content-Range")
+                .and_then(|r| r.rsplit_once('/'))
+                .and_then(|(_, s)| s.parse::<u64>().ok())
+        } else {
+            None
+        }
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("Server does not support range requests: {url}"),
+            )
+        })?;
+
+        // Weak ETags cannot be used with If-Range.
+        let validator = response
+            .header("ETag")
+            .filter(|e| !e.starts_with("W/"))
+            .or_else(|| response.header("Last-Modified"))
+            .map(|v| v.to_owned());
+
+        Ok(Self {
+            shared: Arc::new(HttpShared {
+                agent,
+                url: url.to_owned(),
+                size,
+                validator,
+            }),
+            offset: 0,
+            buf: vec![],
+            buf_offset: 0,
+            read_ahead: HTTP_MIN_READ_AHEAD,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.shared.size
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shared.size == 0
+    }
+}
+
+impl Reopen for HttpFile {
+    fn reopen(&self) -> io::Result<Self> {
+        Ok(Self {
+            shared: self.shared.clone(),
+            offset: 0,
+            buf: vec![],
+            buf_offset: 0,
+            read_ahead: HTTP_MIN_READ_AHEAD,
+        })
+    }
+}
+
+impl Read for HttpFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() || self.offset >= self.shared.size {
+            return Ok(0);
+        }
+
+        let buf_end = self.buf_offset + self.buf.len() as u64;
+
+        if self.offset < self.buf_offset || self.offset >= buf_end {
+            if self.offset == buf_end && !self.buf.is_empty() {
+                self.read_ahead = (self.read_ahead * 2).min(HTTP_MAX_READ_AHEAD);
+            } else {
+                self.read_ahead = HTTP_MIN_READ_AHEAD;
+            }
+
+            let size = (self.shared.size - self.offset).min(self.read_ahead.max(buf.len()) as u64)
+                as usize;
+
+            self.buf = self.shared.fetch(self.offset, size)?;
+            self.buf_offset = self.offset;
+        }
+
+        let start = (self.offset - self.buf_offset) as usize;
+        let n = buf.len().min(self.buf.len() - start);
+        buf[..n].copy_from_slice(&self.buf[start..start + n]);
+        self.offset += n as u64;
+
+        Ok(n)
+    }
+}
+
+impl Seek for HttpFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, delta) = match pos {
+            SeekFrom::Start(o) => {
+                self.offset = o;
+                return Ok(o);
+            }
+            SeekFrom::End(o) => (self.shared.size, o),
+            SeekFrom::Current(o) => (self.offset, o),
+        };
+
+        self.offset = base
+            .to_i64()
+            .and_then(|s| s.checked_add(delta))
+            .and_then(|s| s.to_u64())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "Offset would be before the start of the file",
+                )
+            })?;
+
+        Ok(self.offset)
+    }
+}
+
 /// Returns an I/O error with the [`io::ErrorKind::Interrupted`] type if
 /// `cancel_signal` is true. This should be called frequently in I/O loops for
 /// cancellation to be responsive.
@@ -713,16 +1312,19 @@ pub fn copy(
 #[cfg(test)]
 mod tests {
     use std::{
-        io::{self, Cursor, Read, Seek, SeekFrom, Write},
+        io::{self, BufRead, BufReader, Cursor, Read, Seek, SeekFrom, Write},
+        net::TcpListener,
         sync::atomic::{AtomicBool, Ordering},
+        thread,
     };
 
-    use ring::digest::Context;
+    use crate::digest::Context;
 
     use super::{
-        CountingReader, CountingWriter, HashingReader, HashingWriter, HolePunchingWriter,
+        CountingReader, CountingWriter, HashingReader, HashingWriter, HolePunchingWriter, HttpFile,
         PSeekFile, ReadDiscardExt, ReadStringExt, Reopen, SectionReader, SharedCursor,
-        WriteStringExt, WriteZerosExt,
+        SpliceWriter, SplicedReader, ThreadedHashingWriter, WriteStringExt, WriteZerosExt,
+        THREADED_HASHING_BUF_SIZE,
     };
 
     const FOOBAR_SHA256: [u8; 32] = [
@@ -820,7 +1422,7 @@ mod tests {
     #[test]
     fn hashing_reader() {
         let raw_reader = Cursor::new(b"foobar");
-        let mut reader = HashingReader::new(raw_reader, Context::new(&ring::digest::SHA256));
+        let mut reader = HashingReader::new(raw_reader, Context::new(&crate::digest::SHA256));
 
         let mut buf = [0u8; 6];
         reader.read_exact(&mut buf[..0]).unwrap();
@@ -837,7 +1439,7 @@ mod tests {
     #[test]
     fn hashing_writer() {
         let raw_writer = Cursor::new([0u8; 6]);
-        let mut writer = HashingWriter::new(raw_writer, Context::new(&ring::digest::SHA256));
+        let mut writer = HashingWriter::new(raw_writer, Context::new(&crate::digest::SHA256));
 
         writer.write_all(b"").unwrap();
         writer.write_all(b"foo").unwrap();
@@ -849,6 +1451,33 @@ mod tests {
         assert_eq!(context.finish().as_ref(), FOOBAR_SHA256);
     }
 
+    #[test]
+    fn threaded_hashing_writer() {
+        let data = (0..3 * THREADED_HASHING_BUF_SIZE + 1)
+            .map(|i| (i % 251) as u8)
+            .collect::<Vec<_>>();
+
+        for threaded in [false, true] {
+            let mut writer = ThreadedHashingWriter::with_threading(
+                Vec::new(),
+                Context::new(&crate::digest::SHA256),
+                threaded,
+            );
+
+            writer.write_all(b"").unwrap();
+            for chunk in data.chunks(100_000) {
+                writer.write_all(chunk).unwrap();
+            }
+
+            let (raw_writer, context) = writer.finish();
+            assert_eq!(raw_writer, data);
+            assert_eq!(
+                context.finish().as_ref(),
+                crate::digest::digest(&crate::digest::SHA256, &data).as_ref(),
+            );
+        }
+    }
+
     #[test]
     fn section_reader() {
         let raw_reader = Cursor::new(b"fooinnerbar");
@@ -882,6 +1511,35 @@ mod tests {
         assert_eq!(raw_reader.stream_position().unwrap(), 6);
     }
 
+    #[test]
+    fn splice_round_trip() {
+        let mut writer = SpliceWriter::new();
+        writer.write_all(b"head").unwrap();
+        writer.skip(5).unwrap();
+        writer.write_all(b"xxxxxtail").unwrap();
+
+        // Overwrite data on both sides of the skipped section.
+        writer.seek(SeekFrom::Start(0)).unwrap();
+        writer.write_all(b"H").unwrap();
+        writer.seek(SeekFrom::End(-4)).unwrap();
+        writer.write_all(b"T").unwrap();
+        assert_eq!(writer.stream_position().unwrap(), 10);
+
+        let (head, tail) = writer.buffers();
+        assert_eq!(head, b"Head");
+        assert_eq!(tail, b"Tail");
+
+        let mut reader = SplicedReader::new(head, Cursor::new(b"inner-ignored"), 5, tail);
+        let mut buf = vec![];
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"HeadinnerTail");
+
+        buf.clear();
+        reader.seek(SeekFrom::End(-6)).unwrap();
+        reader.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, b"erTail");
+    }
+
     #[test]
     fn hole_punching_writer() {
         let raw_writer = Cursor::new(b"foobar foobar".to_owned());
@@ -938,6 +1596,100 @@ mod tests {
         assert_eq!(n, 0);
     }
 
+    /// Serve `data` over HTTP with range request support. Returns the URL. If
+    /// `change_after` is set, the ETag changes after that many requests, as if
+    /// the file was replaced.
+    fn serve_ranges(data: Vec<u8>, change_after: Option<usize>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/ota.zip", listener.local_addr().unwrap());
+
+        thread::spawn(move || {
+            for (i, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut range = None;
+                let mut if_range = None;
+
+                for line in BufReader::new(&mut stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    } else if let Some(r) = line.strip_prefix("Range: bytes=") {
+                        let (start, end) = r.split_once('-').unwrap();
+                        range = Some((start.parse::<usize>().unwrap(), end.parse().unwrap()));
+                    } else if let Some(v) = line.strip_prefix("If-Range: ") {
+                        if_range = Some(v.to_owned());
+                    }
+                }
+
+                let etag = if change_after.is_some_and(|n| i >= n) {
+                    "\"v2\""
+                } else {
+                    "\"v1\""
+                };
+
+                let (start, end): (usize, usize) = range.unwrap();
+                let (status, start, end) = if if_range.is_some_and(|v| v != etag) {
+                    ("200 OK", 0, data.len() - 1)
+                } else {
+                    ("206 Partial Content", start, end.min(data.len() - 1))
+                };
+                let header = format!(
+                    "HTTP/1.1 {status}\r\n\
+                    Content-Range: bytes {start}-{end}/{}\r\n\
+                    Content-Length: {}\r\n\
+                    ETag: {etag}\r\n\
+                    Connection: close\r\n\r\n",
+                    data.len(),
+                    end - start + 1,
+                );
+
+                stream.write_all(header.as_bytes()).unwrap();
+                stream.write_all(&data[start..=end]).unwrap();
+            }
+        });
+
+        url
+    }
+
+    #[test]
+    fn http_file() {
+        let data = (0..300_000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let url = serve_ranges(data.clone(), None);
+
+        let mut a = HttpFile::open(&url).unwrap();
+        assert_eq!(a.len(), data.len() as u64);
+
+        let mut buf = vec![];
+        a.read_to_end(&mut buf).unwrap();
+        assert_eq!(buf, data);
+
+        let mut b = a.reopen().unwrap();
+        b.seek(SeekFrom::End(-10)).unwrap();
+        let mut buf = [0u8; 20];
+        let n = b.read(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &data[data.len() - 10..]);
+
+        b.seek(SeekFrom::Start(100_000)).unwrap();
+        b.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, &data[100_000..100_020]);
+
+        let n = b.read_discard(1_000_000).unwrap();
+        assert_eq!(n, data.len() as u64 - 100_020);
+
+        assert!(b.seek(SeekFrom::Current(-1_000_000)).is_err());
+    }
+
+    #[test]
+    fn http_file_changed() {
+        let data = (0..1000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let url = serve_ranges(data, Some(1));
+
+        let mut a = HttpFile::open(&url).unwrap();
+        let mut buf = [0u8; 20];
+        let e = a.read_exact(&mut buf).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+    }
+
     #[test]
     fn copy() {
         let cancel_signal = AtomicBool::new(false);
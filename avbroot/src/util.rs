@@ -6,6 +6,7 @@
 use std::{cmp::Ordering, fmt, ops::Range, path::Path};
 
 use num_traits::PrimInt;
+use thiserror::Error;
 
 pub const ZEROS: [u8; 16384] = [0u8; 16384];
 
@@ -37,6 +38,31 @@ pub fn is_zero(mut buf: &[u8]) -> bool {
     true
 }
 
+/// Compute the Shannon entropy of a byte slice in bits per byte. The result is
+/// in the range `[0, 8]`.
+pub fn entropy(buf: &[u8]) -> f64 {
+    if buf.is_empty() {
+        return 0.0;
+    }
+
+    let mut counts = [0u64; 256];
+
+    for b in buf {
+        counts[usize::from(*b)] += 1;
+    }
+
+    let len = buf.len() as f64;
+
+    counts
+        .iter()
+        .filter(|c| **c != 0)
+        .map(|c| {
+            let p = *c as f64 / len;
+            -p * p.log2()
+        })
+        .sum()
+}
+
 /// Get the non-empty parent of a path. If the path has no parent in the string,
 /// then `.` is returned. This does not perform any filesystem operations.
 pub fn parent_path(path: &Path) -> &Path {
@@ -125,10 +151,90 @@ where
         .is_ok()
 }
 
+/// Format a Unix timestamp as an ISO 8601 basic format UTC timestamp, like
+/// `20150830T123600Z`.
+pub fn format_utc_timestamp(secs: u64) -> String {
+    let days = (secs / 86400) as i64;
+    let rem = secs % 86400;
+
+    // Convert days since the epoch to a proleptic Gregorian date.
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}{month:02}{day:02}T{:02}{:02}{:02}Z",
+        rem / 3600,
+        rem % 3600 / 60,
+        rem % 60,
+    )
+}
+
+#[derive(Debug, Error)]
+pub enum TemplateError {
+    #[error("Unknown template variable: {0:?}")]
+    UnknownVariable(String),
+    #[error("Unmatched brace at offset {0}")]
+    UnmatchedBrace(usize),
+}
+
+/// Expand `{name}` placeholders in `template` with the values returned by
+/// `lookup`. `{{` and `}}` produce literal braces.
+pub fn expand_template(
+    template: &str,
+    lookup: impl Fn(&str) -> Option<String>,
+) -> Result<String, TemplateError> {
+    let mut result = String::new();
+    let mut rest = template;
+
+    while let Some(i) = rest.find(['{', '}']) {
+        let offset = template.len() - rest.len() + i;
+        result.push_str(&rest[..i]);
+
+        if rest[i..].starts_with("{{") || rest[i..].starts_with("}}") {
+            result.push_str(&rest[i..i + 1]);
+            rest = &rest[i + 2..];
+        } else if rest[i..].starts_with('}') {
+            return Err(TemplateError::UnmatchedBrace(offset));
+        } else {
+            let end = rest[i + 1..]
+                .find(['{', '}'])
+                .filter(|&j| rest.as_bytes()[i + 1 + j] == b'}')
+                .ok_or(TemplateError::UnmatchedBrace(offset))?;
+            let name = &rest[i + 1..i + 1 + end];
+            let value =
+                lookup(name).ok_or_else(|| TemplateError::UnknownVariable(name.to_owned()))?;
+
+            result.push_str(&value);
+            rest = &rest[i + 2 + end..];
+        }
+    }
+
+    result.push_str(rest);
+
+    Ok(result)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_entropy() {
+        assert_eq!(entropy(&[]), 0.0);
+        assert_eq!(entropy(&[0u8; 1024]), 0.0);
+        assert_eq!(entropy(&[0, 1, 0, 1]), 1.0);
+
+        let all_bytes = (0..=255).collect::<Vec<u8>>();
+        assert_eq!(entropy(&all_bytes), 8.0);
+    }
+
     #[test]
     fn test_ranges_overlaps() {
         assert_eq!(ranges_overlaps(&[0..4], &(0..0)), false);
@@ -143,6 +249,48 @@ mod tests {
         assert_eq!(ranges_overlaps(&[0..4, 5..8], &(0..9)), true);
     }
 
+    #[test]
+    fn test_format_utc_timestamp() {
+        assert_eq!(format_utc_timestamp(0), "19700101T000000Z");
+        assert_eq!(format_utc_timestamp(1440938160), "20150830T123600Z");
+        assert_eq!(format_utc_timestamp(951782400), "20000229T000000Z");
+    }
+
+    #[test]
+    fn test_expand_template() {
+        let lookup = |name: &str| match name {
+            "stem" => Some("ota".to_owned()),
+            "date" => Some("20240101".to_owned()),
+            "empty" => Some(String::new()),
+            _ => None,
+        };
+
+        assert_eq!(
+            expand_template("{stem}-{date}{empty}.zip", lookup).unwrap(),
+            "ota-20240101.zip",
+        );
+        assert_eq!(
+            expand_template("{{stem}}-}}{{", lookup).unwrap(),
+            "{stem}-}{",
+        );
+        assert!(matches!(
+            expand_template("{unknown}", lookup),
+            Err(TemplateError::UnknownVariable(n)) if n == "unknown",
+        ));
+        assert!(matches!(
+            expand_template("a{stem", lookup),
+            Err(TemplateError::UnmatchedBrace(1)),
+        ));
+        assert!(matches!(
+            expand_template("a{st{em}", lookup),
+            Err(TemplateError::UnmatchedBrace(1)),
+        ));
+        assert!(matches!(
+            expand_template("a}", lookup),
+            Err(TemplateError::UnmatchedBrace(1)),
+        ));
+    }
+
     #[test]
     fn test_ranges_contains() {
         assert_eq!(ranges_contains(&[0..4], &0), true);
@@ -14,11 +14,12 @@ use rsa::RsaPrivateKey;
 
 use avbroot::{
     self,
+    crypto::RsaSigningKey,
     format::avb::{self, AppendedDescriptorMut, AppendedDescriptorRef},
-    stream::{self, SharedCursor},
+    stream::{self, SharedCursor, ToWriter},
 };
 
-fn get_test_key() -> RsaPrivateKey {
+fn get_test_key() -> RsaSigningKey {
     let data = include_str!(concat!(
         env!("CARGO_WORKSPACE_DIR"),
         "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.key",
@@ -28,7 +29,9 @@ fn get_test_key() -> RsaPrivateKey {
         "/e2e/keys/TEST_KEY_DO_NOT_USE_avb.passphrase",
     ));
 
-    RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap()
+    let key = RsaPrivateKey::from_pkcs8_encrypted_pem(data, passphrase.trim_end()).unwrap();
+
+    RsaSigningKey::Internal(key)
 }
 
 #[test]
@@ -182,3 +185,208 @@ fn round_trip_appended_hash_tree_image() {
 
     assert_eq!(data, new_data.as_slice());
 }
+
+#[test]
+fn parse_atx_public_key_metadata() {
+    let certificate = |fill: u8| avb::AtxCertificate {
+        version: 1,
+        public_key: vec![fill; avb::ATX_PUBLIC_KEY_SIZE],
+        subject: [fill; 32],
+        usage: [fill; 32],
+        key_version: 42,
+        signature: vec![fill; avb::ATX_SIGNATURE_SIZE],
+    };
+    let metadata = avb::AtxPublicKeyMetadata {
+        version: 1,
+        product_intermediate_key_certificate: certificate(0xaa),
+        product_signing_key_certificate: certificate(0xbb),
+    };
+
+    let mut writer = Cursor::new(Vec::new());
+    metadata.to_writer(&mut writer).unwrap();
+    let data = writer.into_inner();
+    assert_eq!(data.len(), avb::AtxPublicKeyMetadata::SIZE);
+
+    // Little endian version fields, matching libavb_atx.
+    assert_eq!(&data[..8], &[1, 0, 0, 0, 1, 0, 0, 0]);
+
+    let mut header = avb::Header {
+        required_libavb_version_major: avb::VERSION_MAJOR,
+        required_libavb_version_minor: avb::VERSION_MINOR,
+        algorithm_type: avb::AlgorithmType::None,
+        hash: vec![],
+        signature: vec![],
+        public_key: vec![],
+        public_key_metadata: data,
+        descriptors: vec![],
+        rollback_index: 0,
+        flags: 0,
+        rollback_index_location: 0,
+        release_string: String::new(),
+        reserved: [0u8; 80],
+    };
+    assert_eq!(header.atx_public_key_metadata(), Some(metadata));
+
+    // The raw metadata is what gets written back out.
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 0).unwrap();
+
+    let (new_header, _, _) = avb::load_image_strict(Cursor::new(writer.into_inner())).unwrap();
+    assert_eq!(header, new_header);
+
+    // Metadata that isn't the size of the ATX structure is left alone.
+    header.public_key_metadata = vec![0xdd; 16];
+    assert_eq!(header.atx_public_key_metadata(), None);
+}
+
+#[test]
+fn strict_round_trip_corpus() {
+    let images: [&[u8]; 3] = [
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/vbmeta_root.img",
+        )),
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/vbmeta_appended_hash.img",
+        )),
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/vbmeta_appended_hash_tree.img",
+        )),
+    ];
+
+    for data in images {
+        avb::load_image_strict(Cursor::new(data)).unwrap();
+    }
+}
+
+#[test]
+fn strict_round_trip_unknown_descriptors() {
+    let header = avb::Header {
+        required_libavb_version_major: avb::VERSION_MAJOR,
+        required_libavb_version_minor: avb::VERSION_MINOR,
+        algorithm_type: avb::AlgorithmType::None,
+        hash: vec![],
+        signature: vec![],
+        public_key: vec![],
+        public_key_metadata: vec![],
+        descriptors: vec![
+            avb::Descriptor::Unknown {
+                tag: 0x1234,
+                data: b"\xde\xad\xbe\xef\xde\xad\xbe\xef".to_vec(),
+            },
+            avb::Descriptor::KernelCmdline(avb::KernelCmdlineDescriptor {
+                flags: 0,
+                cmdline: "foo=bar".to_owned(),
+            }),
+            avb::Descriptor::Unknown {
+                tag: 0x5678,
+                data: vec![],
+            },
+        ],
+        rollback_index: 1,
+        flags: 0,
+        rollback_index_location: 0,
+        release_string: "oem\0release".to_owned(),
+        reserved: [0x5a; 80],
+    };
+
+    let mut writer = Cursor::new(Vec::new());
+    avb::write_root_image(&mut writer, &header, 0).unwrap();
+    let mut data = writer.into_inner();
+
+    let (new_header, _, _) = avb::load_image_strict(Cursor::new(&data)).unwrap();
+    assert_eq!(header, new_header);
+
+    // Non-zero descriptor padding is normalized, so it must be rejected.
+    let pos = data.windows(7).position(|w| w == b"foo=bar").unwrap();
+    data[pos + 7] = 0xff;
+
+    avb::load_image(Cursor::new(&data)).unwrap();
+    assert_matches!(
+        avb::load_image_strict(Cursor::new(&data)),
+        Err(avb::Error::NotRoundTrippable(o)) if o == pos as u64 + 7
+    );
+}
+
+#[test]
+fn json_round_trip_headers() {
+    let images: [&[u8]; 3] = [
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/vbmeta_root.img",
+        )),
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/vbmeta_appended_hash.img",
+        )),
+        include_bytes!(concat!(
+            env!("CARGO_MANIFEST_DIR"),
+            "/tests/data/vbmeta_appended_hash_tree.img",
+        )),
+    ];
+
+    for data in images {
+        let (header, _, _) = avb::load_image(Cursor::new(data)).unwrap();
+
+        let json = serde_json::to_string(&header).unwrap();
+        let new_header: avb::Header = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(new_header, header);
+    }
+}
+
+#[test]
+fn sign_preserves_sha512() {
+    let key = get_test_key();
+    let key_len = avb::encode_public_key(&key.to_public_key()).unwrap().len();
+
+    let mut header = avb::Header {
+        required_libavb_version_major: avb::VERSION_MAJOR,
+        required_libavb_version_minor: avb::VERSION_MINOR,
+        algorithm_type: avb::AlgorithmType::Sha512Rsa2048,
+        hash: vec![],
+        signature: vec![],
+        public_key: vec![],
+        public_key_metadata: vec![],
+        descriptors: vec![],
+        rollback_index: 0,
+        flags: 0,
+        rollback_index_location: 0,
+        release_string: String::new(),
+        reserved: [0; 80],
+    };
+
+    // The SHA512 digest is kept, but the RSA key size follows the key.
+    header.set_algo_for_key(&key, None).unwrap();
+    assert_eq!(header.algorithm_type.hash_len(), 64);
+    assert_eq!(header.algorithm_type.public_key_len(), key_len);
+
+    header.sign(&key).unwrap();
+    assert_eq!(header.verify().unwrap(), Some(key.to_public_key()));
+
+    // Other headers default to SHA256.
+    header.algorithm_type = avb::AlgorithmType::None;
+    header.set_algo_for_key(&key, None).unwrap();
+    assert_eq!(header.algorithm_type.hash_len(), 32);
+
+    // Explicitly requested algorithms must match the key size.
+    assert_matches!(
+        header.set_algo_for_key(&key, Some(avb::AlgorithmType::Sha512Rsa8192)),
+        Err(avb::Error::IncorrectKeySize { .. })
+    );
+    assert_matches!(
+        header.set_algo_for_key(&key, Some(avb::AlgorithmType::None)),
+        Err(avb::Error::UnsupportedAlgorithm(_))
+    );
+
+    // Locally held keys can't be used for 8192-bit signatures.
+    for algo in [
+        avb::AlgorithmType::Sha256Rsa8192,
+        avb::AlgorithmType::Sha512Rsa8192,
+    ] {
+        header.algorithm_type = algo;
+        assert_matches!(header.sign(&key), Err(avb::Error::UnsupportedAlgorithm(a)) if a == algo);
+    }
+}
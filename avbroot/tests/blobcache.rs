@@ -0,0 +1,95 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{fs, io::Cursor, sync::atomic::AtomicBool};
+
+use assert_matches::assert_matches;
+use avbroot::blobcache::{BlobCache, Error};
+use tempfile::TempDir;
+
+#[test]
+fn copy_through_cache() {
+    let cancel_signal = AtomicBool::new(false);
+    let temp_dir = TempDir::new().unwrap();
+    let cache = BlobCache::new(temp_dir.path()).unwrap();
+
+    let data = b"prefix|blob data|suffix";
+    let blob = &data[7..16];
+    let digest = avbroot::digest::digest(&avbroot::digest::SHA256, blob);
+
+    // The first copy reads from the source and populates the cache.
+    let mut output = vec![];
+    cache
+        .copy(
+            digest.as_ref(),
+            Cursor::new(data),
+            7,
+            blob.len() as u64,
+            &mut output,
+            &cancel_signal,
+        )
+        .unwrap();
+    assert_eq!(output, blob);
+    assert_eq!(cache.stats(), (0, 1));
+
+    // The second copy must not touch the source at all.
+    let mut output = vec![];
+    cache
+        .copy(
+            digest.as_ref(),
+            Cursor::new(b""),
+            7,
+            blob.len() as u64,
+            &mut output,
+            &cancel_signal,
+        )
+        .unwrap();
+    assert_eq!(output, blob);
+    assert_eq!(cache.stats(), (1, 1));
+
+    // Corrupted entries are detected.
+    let name = hex::encode(digest);
+    fs::write(temp_dir.path().join(&name[..2]).join(&name), b"corrupted").unwrap();
+
+    let result = cache.copy(
+        digest.as_ref(),
+        Cursor::new(b""),
+        7,
+        blob.len() as u64,
+        &mut vec![],
+        &cancel_signal,
+    );
+    assert_matches!(result, Err(Error::CorruptEntry(_)));
+}
+
+#[test]
+fn mismatched_source_not_cached() {
+    let cancel_signal = AtomicBool::new(false);
+    let temp_dir = TempDir::new().unwrap();
+    let cache = BlobCache::new(temp_dir.path()).unwrap();
+
+    let digest = avbroot::digest::digest(&avbroot::digest::SHA256, b"expected");
+
+    let mut output = vec![];
+    cache
+        .copy(
+            digest.as_ref(),
+            Cursor::new(b"actual!!"),
+            0,
+            8,
+            &mut output,
+            &cancel_signal,
+        )
+        .unwrap();
+    assert_eq!(output, b"actual!!");
+
+    let name = hex::encode(digest);
+    assert_eq!(
+        fs::read_dir(temp_dir.path().join(&name[..2]))
+            .unwrap()
+            .count(),
+        0
+    );
+}
@@ -7,12 +7,12 @@ use std::io::{Cursor, Read, Seek, Write};
 
 use avbroot::{
     self,
-    format::compression::{CompressedFormat, CompressedReader, CompressedWriter},
+    format::compression::{self, CompressedFormat, CompressedReader, CompressedWriter},
 };
 
-fn round_trip(data: &[u8], format: CompressedFormat) {
+fn round_trip_with_level(data: &[u8], format: CompressedFormat, level: Option<u32>) {
     let raw_writer = Cursor::new(Vec::new());
-    let mut writer = CompressedWriter::new(raw_writer, format).unwrap();
+    let mut writer = CompressedWriter::with_level(raw_writer, format, level).unwrap();
     writer.write_all(data).unwrap();
     let mut raw_reader = writer.finish().unwrap();
 
@@ -26,6 +26,10 @@ fn round_trip(data: &[u8], format: CompressedFormat) {
     assert_eq!(data, new_data);
 }
 
+fn round_trip(data: &[u8], format: CompressedFormat) {
+    round_trip_with_level(data, format, None);
+}
+
 #[test]
 fn round_trip_gzip() {
     round_trip(b"gzip-compressed data", CompressedFormat::Gzip);
@@ -37,3 +41,22 @@ fn round_trip_lz4_legacy() {
     let data = b"Lz4Legacy".repeat(1024 * 1024);
     round_trip(&data, CompressedFormat::Lz4Legacy);
 }
+
+#[test]
+fn round_trip_levels() {
+    for level in [0, 9] {
+        round_trip_with_level(b"gzip-compressed data", CompressedFormat::Gzip, Some(level));
+        round_trip_with_level(b"xz-compressed data", CompressedFormat::Xz, Some(level));
+    }
+
+    for (format, level) in [
+        (CompressedFormat::Gzip, 10),
+        (CompressedFormat::Lz4Legacy, 1),
+        (CompressedFormat::None, 1),
+    ] {
+        assert!(matches!(
+            CompressedWriter::with_level(Cursor::new(Vec::new()), format, Some(level)),
+            Err(compression::Error::UnsupportedLevel { .. }),
+        ));
+    }
+}
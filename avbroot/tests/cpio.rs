@@ -3,11 +3,14 @@
  * SPDX-License-Identifier: GPL-3.0-only
  */
 
-use std::io::{self, Cursor};
+use std::{
+    io::{self, Cursor},
+    sync::atomic::AtomicBool,
+};
 
 use avbroot::{
     self,
-    format::cpio::{CpioEntryType, CpioReader, CpioWriter},
+    format::cpio::{self, CpioEntry, CpioEntryData, CpioEntryType, CpioReader, CpioWriter},
     util,
 };
 
@@ -48,3 +51,121 @@ fn round_trip_archive() {
         }
     }
 }
+
+#[test]
+fn renumber_inodes() {
+    let mut entries = vec![
+        CpioEntry::new_directory(b"b", 0o755),
+        CpioEntry::new_file(b"a", 0o644, CpioEntryData::Data(vec![])),
+        CpioEntry::new_file(b"c", 0o644, CpioEntryData::Data(vec![])),
+    ];
+    entries[0].inode = 5;
+    entries[1].inode = 300001;
+    entries[2].dev_min = 1;
+    entries[2].inode = 7;
+
+    cpio::renumber_inodes(&mut entries).unwrap();
+
+    assert_eq!(
+        entries.iter().map(|e| e.inode).collect::<Vec<_>>(),
+        [300000, 300001, 300000],
+    );
+
+    cpio::set_mtimes(&mut entries, 1234);
+    assert!(entries.iter().all(|e| e.mtime == 1234));
+}
+
+fn hard_link(path: &[u8], inode: u32, data: &[u8]) -> CpioEntry {
+    CpioEntry {
+        inode,
+        nlink: 2,
+        ..CpioEntry::new_file(path, 0o755, CpioEntryData::Data(data.to_vec()))
+    }
+}
+
+#[test]
+fn round_trip_links_and_special_files() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let mut entries = vec![
+        hard_link(b"bin/a", 10, b""),
+        CpioEntry {
+            inode: 11,
+            file_type: CpioEntryType::Pipe,
+            file_mode: 0o600,
+            nlink: 1,
+            path: b"dev/fifo".to_vec(),
+            data: CpioEntryData::Data(vec![]),
+            ..Default::default()
+        },
+        CpioEntry {
+            inode: 12,
+            file_type: CpioEntryType::Socket,
+            file_mode: 0o600,
+            nlink: 1,
+            path: b"dev/socket".to_vec(),
+            data: CpioEntryData::Data(vec![]),
+            ..Default::default()
+        },
+        hard_link(b"bin/b", 10, b"binary"),
+    ];
+
+    let mut writer = Cursor::new(Vec::new());
+    cpio::save(&mut writer, &entries, false, &cancel_signal).unwrap();
+
+    writer.set_position(0);
+    let loaded = cpio::load(&mut writer, false, &cancel_signal).unwrap();
+    assert_eq!(loaded, entries);
+
+    let groups = cpio::link_groups(&loaded);
+    assert_eq!(groups.len(), 1);
+    assert_eq!(groups.values().next().unwrap(), &[0, 3]);
+
+    // Sorting moves the entry holding the data to the front of the group.
+    cpio::sort(&mut entries);
+    cpio::normalize_link_groups(&mut entries).unwrap();
+    assert_eq!(entries[0].data, CpioEntryData::Data(vec![]));
+    assert_eq!(entries[1].data, CpioEntryData::Data(b"binary".to_vec()));
+
+    cpio::renumber_inodes(&mut entries).unwrap();
+    assert_eq!(
+        entries.iter().map(|e| e.inode).collect::<Vec<_>>(),
+        [300000, 300000, 300001, 300002],
+    );
+}
+
+#[test]
+fn modify_link_groups() {
+    let mut entries = vec![
+        CpioEntry::new_file(b"a", 0o644, CpioEntryData::Data(b"data".to_vec())),
+        CpioEntry::new_file(b"other", 0o644, CpioEntryData::Data(vec![])),
+    ];
+    entries[0].inode = 1;
+    entries[1].inode = 2;
+
+    cpio::add_hard_link(&mut entries, 0, b"b").unwrap();
+    cpio::add_hard_link(&mut entries, 0, b"c").unwrap();
+
+    assert_eq!(
+        entries.iter().map(|e| e.nlink).collect::<Vec<_>>(),
+        [3, 1, 3, 3]
+    );
+    assert_eq!(entries[0].data, CpioEntryData::Data(vec![]));
+    assert_eq!(entries[3].data, CpioEntryData::Data(b"data".to_vec()));
+
+    cpio::break_hard_link(&mut entries, 3).unwrap();
+
+    assert_eq!(
+        entries.iter().map(|e| e.nlink).collect::<Vec<_>>(),
+        [2, 1, 2, 1]
+    );
+    assert_eq!(entries[2].data, CpioEntryData::Data(b"data".to_vec()));
+    assert_eq!(entries[3].data, CpioEntryData::Data(b"data".to_vec()));
+    assert_ne!(entries[3].inode, 1);
+
+    entries[0].data = CpioEntryData::Data(b"different".to_vec());
+    assert!(matches!(
+        cpio::normalize_link_groups(&mut entries),
+        Err(cpio::Error::LinkGroupDataMismatch(_)),
+    ));
+}
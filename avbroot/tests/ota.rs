@@ -0,0 +1,724 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Cursor, Write},
+    slice,
+    sync::atomic::AtomicBool,
+    time::Duration,
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    crypto::{self, RsaSigningKey},
+    format::{
+        ota::{self, Error, OriginalManifest, OriginalPartition, SigningWriter, ZipEntry},
+        payload::{self, PayloadHeader, PayloadWriter},
+    },
+    protobuf::{
+        build::tools::releasetools::{DeviceState, OtaMetadata},
+        chromeos_update_engine::{
+            install_operation::Type, DeltaArchiveManifest, InstallOperation, PartitionInfo,
+            PartitionUpdate,
+        },
+    },
+    stream::SectionReader,
+};
+use rsa::RsaPrivateKey;
+use x509_cert::{
+    der::{pem::LineEnding, EncodePem},
+    Certificate,
+};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+struct RawEntry<'a> {
+    name: &'a [u8],
+    method: u16,
+    data: &'a [u8],
+    local_extra: &'a [u8],
+    data_descriptor: bool,
+}
+
+/// Build a zip by hand with `prefix` prepended to the archive. Entries with data
+/// descriptors have zeroed sizes in their local headers, like streaming zip
+/// writers produce.
+fn build_zip(prefix: &[u8], entries: &[RawEntry]) -> Vec<u8> {
+    let mut zip = vec![];
+    let mut cd = vec![];
+
+    for entry in entries {
+        let header_offset = zip.len() as u32;
+        let flags: u16 = if entry.data_descriptor { 1 << 3 } else { 0 };
+        let size = entry.data.len() as u32;
+        let local_size = if entry.data_descriptor { 0 } else { size };
+
+        zip.extend_from_slice(b"PK\x03\x04");
+        zip.extend_from_slice(&20u16.to_le_bytes());
+        zip.extend_from_slice(&flags.to_le_bytes());
+        zip.extend_from_slice(&entry.method.to_le_bytes());
+        zip.extend_from_slice(&[0; 8]);
+        zip.extend_from_slice(&local_size.to_le_bytes());
+        zip.extend_from_slice(&local_size.to_le_bytes());
+        zip.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        zip.extend_from_slice(&(entry.local_extra.len() as u16).to_le_bytes());
+        zip.extend_from_slice(entry.name);
+        zip.extend_from_slice(entry.local_extra);
+        zip.extend_from_slice(entry.data);
+
+        if entry.data_descriptor {
+            zip.extend_from_slice(b"PK\x07\x08");
+            zip.extend_from_slice(&[0; 4]);
+            zip.extend_from_slice(&size.to_le_bytes());
+            zip.extend_from_slice(&size.to_le_bytes());
+        }
+
+        cd.extend_from_slice(b"PK\x01\x02");
+        cd.extend_from_slice(&20u16.to_le_bytes());
+        cd.extend_from_slice(&20u16.to_le_bytes());
+        cd.extend_from_slice(&flags.to_le_bytes());
+        cd.extend_from_slice(&entry.method.to_le_bytes());
+        cd.extend_from_slice(&[0; 8]);
+        cd.extend_from_slice(&size.to_le_bytes());
+        cd.extend_from_slice(&size.to_le_bytes());
+        cd.extend_from_slice(&(entry.name.len() as u16).to_le_bytes());
+        cd.extend_from_slice(&[0; 12]);
+        cd.extend_from_slice(&header_offset.to_le_bytes());
+        cd.extend_from_slice(entry.name);
+    }
+
+    let cd_offset = zip.len() as u32;
+    zip.extend_from_slice(&cd);
+
+    zip.extend_from_slice(b"PK\x05\x06");
+    zip.extend_from_slice(&[0; 4]);
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(entries.len() as u16).to_le_bytes());
+    zip.extend_from_slice(&(cd.len() as u32).to_le_bytes());
+    zip.extend_from_slice(&cd_offset.to_le_bytes());
+    zip.extend_from_slice(&0u16.to_le_bytes());
+
+    let mut result = prefix.to_vec();
+    result.extend_from_slice(&zip);
+    result
+}
+
+#[test]
+fn find_stored_zip_entry_fallback() {
+    let entries = [
+        RawEntry {
+            name: b"other",
+            method: 8,
+            data: b"compressed",
+            local_extra: b"",
+            data_descriptor: true,
+        },
+        RawEntry {
+            name: ota::PATH_PAYLOAD.as_bytes(),
+            method: 0,
+            data: b"payload data",
+            local_extra: b"\xfe\xca\x04\x00\x00\x00\x00\x00",
+            data_descriptor: true,
+        },
+    ];
+
+    for prefix in [&b""[..], b"garbage"] {
+        let zip = build_zip(prefix, &entries);
+
+        let entry = ota::find_stored_zip_entry(Cursor::new(&zip), ota::PATH_PAYLOAD).unwrap();
+        let range = entry.offset as usize..(entry.offset + entry.size) as usize;
+        assert_eq!(&zip[range], b"payload data");
+
+        assert!(matches!(
+            ota::find_stored_zip_entry(Cursor::new(&zip), ota::PATH_PROPERTIES),
+            Err(Error::MissingZipEntry(_)),
+        ));
+    }
+
+    let zip = build_zip(b"", &entries[..1]);
+    assert!(matches!(
+        ota::find_stored_zip_entry(Cursor::new(&zip), "other"),
+        Err(Error::EntryNotStored(_)),
+    ));
+}
+
+#[test]
+fn sign_with_multiple_signers() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let signers = (1..=2)
+        .map(|serial| {
+            let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+            let cert = crypto::generate_cert(
+                &key,
+                serial,
+                Duration::from_secs(3600),
+                &format!("CN=signer{serial}"),
+            )
+            .unwrap();
+
+            (RsaSigningKey::Internal(key), cert)
+        })
+        .collect::<Vec<_>>();
+
+    let zip = build_zip(
+        b"",
+        &[RawEntry {
+            name: b"payload.bin",
+            method: 0,
+            data: b"payload",
+            local_extra: b"",
+            data_descriptor: false,
+        }],
+    );
+
+    let mut writer = SigningWriter::new(Cursor::new(vec![]));
+    writer.write_all(&zip).unwrap();
+    let signed = writer
+        .finish_with_signers(&signers.iter().map(|(k, c)| (k, c)).collect::<Vec<_>>())
+        .unwrap()
+        .into_inner();
+
+    assert_matches!(
+        ota::verify_ota(Cursor::new(&signed), false, &cancel_signal),
+        Err(ota::Error::NotOneCmsSignerInfo(2))
+    );
+
+    let mut certs = ota::verify_ota(Cursor::new(&signed), true, &cancel_signal).unwrap();
+    certs.sort_by_key(|c| c.tbs_certificate.serial_number.as_bytes().to_vec());
+    assert_eq!(
+        certs,
+        signers.into_iter().map(|(_, c)| c).collect::<Vec<_>>(),
+    );
+
+    // Corrupting the signed data invalidates all signatures.
+    let mut corrupted = signed.clone();
+    corrupted[0] ^= 0xff;
+    assert!(ota::verify_ota(Cursor::new(&corrupted), true, &cancel_signal).is_err());
+}
+
+#[test]
+fn sign_with_comment_and_signing_block() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=signer").unwrap();
+    let key = RsaSigningKey::Internal(key);
+
+    let zip = build_zip(
+        b"",
+        &[RawEntry {
+            name: ota::PATH_PAYLOAD.as_bytes(),
+            method: 0,
+            data: b"payload data",
+            local_extra: b"",
+            data_descriptor: false,
+        }],
+    );
+    let pairs = vec![(0x12345678, b"vendor".to_vec()), (0x9abcdef0, vec![])];
+
+    let mut writer = SigningWriter::new(Cursor::new(vec![]))
+        .with_comment(b"vendor comment".to_vec())
+        .with_signing_block(ota::build_signing_block(&pairs));
+    // Write in small pieces to exercise the queueing.
+    for chunk in zip.chunks(7) {
+        writer.write_all(chunk).unwrap();
+    }
+    let signed = writer.finish(&key, &cert).unwrap().into_inner();
+
+    assert_eq!(
+        ota::verify_ota(Cursor::new(&signed), false, &cancel_signal).unwrap(),
+        [cert],
+    );
+
+    let extras = ota::read_zip_extras(Cursor::new(&signed)).unwrap();
+    assert_eq!(extras.comment, b"vendor comment");
+    assert!(extras.signed);
+    assert_eq!(extras.signing_block, Some(pairs));
+
+    // The entries are still where the central directory says they are.
+    let entry = ota::find_stored_zip_entry(Cursor::new(&signed), ota::PATH_PAYLOAD).unwrap();
+    let range = entry.offset as usize..(entry.offset + entry.size) as usize;
+    assert_eq!(&signed[range], b"payload data");
+
+    // An unsigned zip has no signature to strip from the comment.
+    let extras = ota::read_zip_extras(Cursor::new(&zip)).unwrap();
+    assert_eq!(extras, ota::ZipExtras::default());
+}
+
+/// Replace the EOCD of a zip built by [`build_zip()`] with a zip64 EOCD, a
+/// zip64 EOCD locator, and an EOCD whose fields all refer to the zip64 EOCD.
+fn convert_to_zip64(mut zip: Vec<u8>) -> Vec<u8> {
+    let eocd = zip.split_off(zip.len() - 22);
+    let num_entries = u16::from_le_bytes(eocd[10..12].try_into().unwrap());
+    let cd_size = u32::from_le_bytes(eocd[12..16].try_into().unwrap());
+    let cd_offset = u32::from_le_bytes(eocd[16..20].try_into().unwrap());
+    let zip64_eocd_offset = zip.len() as u64;
+
+    zip.extend_from_slice(b"PK\x06\x06");
+    zip.extend_from_slice(&44u64.to_le_bytes());
+    zip.extend_from_slice(&45u16.to_le_bytes());
+    zip.extend_from_slice(&45u16.to_le_bytes());
+    zip.extend_from_slice(&[0; 8]);
+    zip.extend_from_slice(&u64::from(num_entries).to_le_bytes());
+    zip.extend_from_slice(&u64::from(num_entries).to_le_bytes());
+    zip.extend_from_slice(&u64::from(cd_size).to_le_bytes());
+    zip.extend_from_slice(&u64::from(cd_offset).to_le_bytes());
+
+    zip.extend_from_slice(b"PK\x06\x07");
+    zip.extend_from_slice(&[0; 4]);
+    zip.extend_from_slice(&zip64_eocd_offset.to_le_bytes());
+    zip.extend_from_slice(&1u32.to_le_bytes());
+
+    zip.extend_from_slice(b"PK\x05\x06");
+    zip.extend_from_slice(&[0; 4]);
+    zip.extend_from_slice(&[0xff; 12]);
+    zip.extend_from_slice(&0u16.to_le_bytes());
+
+    zip
+}
+
+#[test]
+fn sign_zip64_eocd() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=signer").unwrap();
+    let key = RsaSigningKey::Internal(key);
+
+    let zip = convert_to_zip64(build_zip(
+        b"",
+        &[RawEntry {
+            name: ota::PATH_PAYLOAD.as_bytes(),
+            method: 0,
+            data: b"payload data",
+            local_extra: b"",
+            data_descriptor: false,
+        }],
+    ));
+    let pairs = vec![(0x12345678, b"vendor".to_vec())];
+
+    for block in [None, Some(ota::build_signing_block(&pairs))] {
+        let mut writer = SigningWriter::new(Cursor::new(vec![]));
+        if let Some(b) = &block {
+            writer = writer.with_signing_block(b.clone());
+        }
+        for chunk in zip.chunks(7) {
+            writer.write_all(chunk).unwrap();
+        }
+        let signed = writer.finish(&key, &cert).unwrap().into_inner();
+
+        assert_eq!(
+            ota::verify_ota(Cursor::new(&signed), false, &cancel_signal).unwrap(),
+            slice::from_ref(&cert),
+        );
+
+        let entry = ota::find_stored_zip_entry(Cursor::new(&signed), ota::PATH_PAYLOAD).unwrap();
+        let range = entry.offset as usize..(entry.offset + entry.size) as usize;
+        assert_eq!(&signed[range], b"payload data");
+
+        let extras = ota::read_zip_extras(Cursor::new(&signed)).unwrap();
+        assert_eq!(extras.signing_block, block.as_ref().map(|_| pairs.clone()));
+    }
+
+    // The zip64 EOCD locator must point to the zip64 EOCD.
+    let mut bad_locator = zip.clone();
+    let locator = bad_locator.len() - 22 - 20;
+    bad_locator[locator + 8] ^= 0xff;
+
+    // The EOCD refers to zip64 records that don't exist.
+    let mut missing_zip64 = zip[..zip.len() - 22 - 20 - 56].to_vec();
+    missing_zip64.extend_from_slice(&zip[zip.len() - 22..]);
+
+    for data in [bad_locator, missing_zip64] {
+        let mut writer = SigningWriter::new(Cursor::new(vec![]));
+        writer.write_all(&data).unwrap();
+
+        assert!(matches!(
+            writer.finish(&key, &cert),
+            Err(Error::InvalidCentralDirectory(_)),
+        ));
+    }
+}
+
+/// Write an OTA containing a zero-filled payload.bin of `payload_size` bytes
+/// and the OTA metadata files. Returns the writer and the OTA metadata.
+#[cfg(feature = "large-tests")]
+fn write_large_ota<W: Write>(
+    writer: W,
+    payload_size: u64,
+    key: &RsaSigningKey,
+    cert: &x509_cert::Certificate,
+) -> (W, OtaMetadata) {
+    use std::io::{self, Read};
+
+    use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(writer));
+    let options = FileOptions::default()
+        .compression_method(CompressionMethod::Stored)
+        .large_file(true);
+    let mut entries = vec![];
+
+    for (path, size) in [(ota::PATH_PAYLOAD, payload_size), (ota::PATH_PROPERTIES, 0)] {
+        zip_writer
+            .start_file_with_extra_data(path, options)
+            .unwrap();
+        let offset = zip_writer.end_extra_data().unwrap();
+        io::copy(&mut io::repeat(0).take(size), &mut zip_writer).unwrap();
+
+        entries.push(ota::ZipEntry {
+            name: path.to_owned(),
+            offset,
+            size,
+        });
+    }
+
+    let metadata = ota::add_metadata(
+        &entries,
+        &mut zip_writer,
+        // Offset where next entry would begin, after the zip64 data descriptor.
+        entries.last().map(|e| e.offset + e.size).unwrap() + 24,
+        &OtaMetadata::default(),
+        LARGE_OTA_PAYLOAD_METADATA_SIZE,
+    )
+    .unwrap();
+
+    let writer = zip_writer.finish().unwrap().finish(key, cert).unwrap();
+
+    (writer, metadata)
+}
+
+#[cfg(feature = "large-tests")]
+const LARGE_OTA_PAYLOAD_METADATA_SIZE: u64 = 4096;
+
+#[cfg(feature = "large-tests")]
+#[test]
+fn sign_and_verify_large_entry() {
+    use std::io::BufWriter;
+
+    let cancel_signal = AtomicBool::new(false);
+
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=signer").unwrap();
+    let key = RsaSigningKey::Internal(key);
+
+    let payload_size = (4 << 30) + 4096;
+    let (writer, metadata) = write_large_ota(
+        BufWriter::new(tempfile::tempfile().unwrap()),
+        payload_size,
+        &key,
+        &cert,
+    );
+    let file = writer.into_inner().unwrap();
+
+    assert_eq!(
+        ota::verify_ota(&file, false, &cancel_signal).unwrap(),
+        [cert]
+    );
+    ota::verify_metadata(&file, &metadata, LARGE_OTA_PAYLOAD_METADATA_SIZE).unwrap();
+
+    let entry = ota::find_stored_zip_entry(&file, ota::PATH_PAYLOAD).unwrap();
+    assert_eq!(entry.size, payload_size);
+
+    // Every entry following the payload is beyond 4 GiB.
+    for value in metadata.property_files.values() {
+        let entries = ota::parse_property_files(value).unwrap();
+        let metadata_entry = entries.iter().find(|e| e.name == "metadata").unwrap();
+        assert!(metadata_entry.offset > u64::from(u32::MAX));
+    }
+
+    // The property files also cover the payload metadata size.
+    assert!(matches!(
+        ota::verify_metadata(&file, &metadata, LARGE_OTA_PAYLOAD_METADATA_SIZE + 1),
+        Err(Error::MismatchedPropertyFiles { .. }),
+    ));
+}
+
+#[test]
+fn parse_invalid_signing_block() {
+    let mut block = ota::build_signing_block(&[(1, b"data".to_vec())]);
+    assert!(ota::parse_signing_block(&block).is_ok());
+
+    // Pair size exceeds the block.
+    block[8] = 0xff;
+    assert!(matches!(
+        ota::parse_signing_block(&block),
+        Err(Error::InvalidSigningBlock(_)),
+    ));
+
+    assert!(matches!(
+        ota::parse_signing_block(b"garbage"),
+        Err(Error::InvalidSigningBlock(_)),
+    ));
+}
+
+/// Build a payload with one operation per data blob. Returns the payload, its
+/// properties, and its metadata size.
+fn build_payload(key: &RsaSigningKey, blobs: &[&[u8]]) -> (Vec<u8>, String, u64) {
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![PartitionUpdate {
+                partition_name: "test".to_owned(),
+                operations: blobs
+                    .iter()
+                    .map(|b| InstallOperation {
+                        r#type: Type::Replace.into(),
+                        data_length: Some(b.len() as u64),
+                        data_sha256_hash: Some(
+                            avbroot::digest::digest(&avbroot::digest::SHA256, b)
+                                .as_ref()
+                                .to_vec(),
+                        ),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+    };
+
+    let mut writer = PayloadWriter::new(vec![], header, key.clone()).unwrap();
+    for blob in blobs {
+        assert!(writer.begin_next_operation().unwrap());
+        writer.write_all(blob).unwrap();
+    }
+    assert!(!writer.begin_next_operation().unwrap());
+
+    writer.finish().unwrap()
+}
+
+/// Build an OTA zip containing only a stored payload.bin with one operation per
+/// data blob.
+fn build_payload_zip(key: &RsaPrivateKey, blobs: &[&[u8]]) -> Vec<u8> {
+    let (payload, _, _) = build_payload(&key.clone().into(), blobs);
+
+    build_zip(
+        b"",
+        &[RawEntry {
+            name: ota::PATH_PAYLOAD.as_bytes(),
+            method: 0,
+            data: &payload,
+            local_extra: b"",
+            data_descriptor: false,
+        }],
+    )
+}
+
+#[test]
+fn strip_round_trip() {
+    let cancel_signal = AtomicBool::new(false);
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+
+    let unchanged_a = vec![0xaau8; 65536];
+    let unchanged_b = vec![0xbbu8; 32768];
+    let stock = build_payload_zip(&key, &[b"stock boot", &unchanged_a, &unchanged_b]);
+    let patched = build_payload_zip(&key, &[b"patched boot", &unchanged_a, &unchanged_b]);
+
+    let mut stripped = vec![];
+    let manifest = ota::strip(
+        Cursor::new(&patched),
+        Cursor::new(&stock),
+        &mut stripped,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    // Both unchanged blobs are adjacent, so they are merged into one hole.
+    assert_eq!(manifest.holes.len(), 1);
+    assert_eq!(manifest.holes[0].size, 65536 + 32768);
+    assert_eq!(manifest.size, patched.len() as u64);
+    assert_eq!(manifest.source_size, stock.len() as u64);
+    assert_eq!(
+        stripped.len() as u64,
+        manifest.size - manifest.holes[0].size
+    );
+
+    let data = ota::serialize_strip_manifest(&manifest).unwrap();
+    let manifest = ota::parse_strip_manifest(data.as_bytes()).unwrap();
+
+    let mut unstripped = vec![];
+    ota::unstrip(
+        Cursor::new(&stripped),
+        Cursor::new(&stock),
+        &manifest,
+        &mut unstripped,
+        &cancel_signal,
+    )
+    .unwrap();
+    assert_eq!(unstripped, patched);
+
+    // Reconstructing with the wrong stock OTA must fail.
+    let mut other_stock = stock.clone();
+    let hole = &manifest.holes[0];
+    other_stock[hole.source_offset as usize] ^= 0xff;
+
+    let result = ota::unstrip(
+        Cursor::new(&stripped),
+        Cursor::new(&other_stock),
+        &manifest,
+        &mut vec![],
+        &cancel_signal,
+    );
+    assert!(matches!(result, Err(Error::StripHashMismatch { .. })));
+}
+
+#[test]
+fn original_manifest_round_trip() {
+    let metadata = OtaMetadata {
+        postcondition: Some(DeviceState {
+            device: vec!["device".to_owned()],
+            build: vec![
+                "google/device/device:15/AP00.000000.000/1234:user/release-keys".to_owned(),
+            ],
+            build_incremental: "1234".to_owned(),
+            security_patch_level: "2024-01-01".to_owned(),
+            sdk_level: "35".to_owned(),
+            timestamp: 1_700_000_000,
+            ..Default::default()
+        }),
+        ..Default::default()
+    };
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            partitions: vec![
+                PartitionUpdate {
+                    partition_name: "boot".to_owned(),
+                    new_partition_info: Some(PartitionInfo {
+                        size: Some(4096),
+                        hash: Some(vec![0xab; 32]),
+                    }),
+                    ..Default::default()
+                },
+                PartitionUpdate {
+                    partition_name: "no_info".to_owned(),
+                    ..Default::default()
+                },
+            ],
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+    };
+
+    let manifest = OriginalManifest::new(&metadata, &header);
+    assert_eq!(manifest.build_incremental, "1234");
+    assert_eq!(manifest.timestamp, 1_700_000_000);
+    assert_eq!(manifest.partitions.len(), 1);
+    assert_eq!(
+        manifest.partitions["boot"],
+        OriginalPartition {
+            size: 4096,
+            sha256: "ab".repeat(32),
+        },
+    );
+
+    let data = ota::serialize_original_manifest(&manifest).unwrap();
+    let parsed = ota::parse_original_manifest(data.as_bytes()).unwrap();
+    assert_eq!(parsed, manifest);
+}
+
+/// Build a signed OTA zip with a payload, its properties, the otacert, and the
+/// OTA metadata, like `ota patch` produces.
+fn build_signed_ota(key: &RsaSigningKey, cert: &Certificate, blobs: &[&[u8]]) -> Vec<u8> {
+    let (payload, properties, metadata_size) = build_payload(key, blobs);
+    let cert_pem = cert.to_pem(LineEnding::LF).unwrap();
+
+    let mut zip_writer = ZipWriter::new_streaming(SigningWriter::new(Cursor::new(vec![])));
+    let options = FileOptions::default().compression_method(CompressionMethod::Stored);
+    let mut entries = vec![];
+
+    for (name, data) in [
+        (ota::PATH_OTACERT, cert_pem.as_bytes()),
+        (ota::PATH_PAYLOAD, &payload),
+        (ota::PATH_PROPERTIES, properties.as_bytes()),
+    ] {
+        zip_writer
+            .start_file_with_extra_data(name, options)
+            .unwrap();
+        let offset = zip_writer.end_extra_data().unwrap();
+        zip_writer.write_all(data).unwrap();
+
+        entries.push(ZipEntry {
+            name: name.to_owned(),
+            offset,
+            size: data.len() as u64,
+        });
+    }
+
+    let last = entries.last().unwrap();
+    ota::add_metadata(
+        &entries,
+        &mut zip_writer,
+        last.offset + last.size + 16,
+        &OtaMetadata::default(),
+        metadata_size,
+    )
+    .unwrap();
+
+    zip_writer
+        .finish()
+        .unwrap()
+        .finish(key, cert)
+        .unwrap()
+        .into_inner()
+}
+
+#[test]
+fn resign_replaces_signatures() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let signers = (1..=2)
+        .map(|serial| {
+            let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+            let cert = crypto::generate_cert(
+                &key,
+                serial,
+                Duration::from_secs(3600),
+                &format!("CN=signer{serial}"),
+            )
+            .unwrap();
+
+            (RsaSigningKey::Internal(key), cert)
+        })
+        .collect::<Vec<_>>();
+    let (old_key, old_cert) = &signers[0];
+    let (new_key, new_cert) = &signers[1];
+
+    let original = build_signed_ota(old_key, old_cert, &[b"boot", b"system"]);
+
+    let (writer, metadata, payload_metadata_size) = ota::resign(
+        Cursor::new(&original),
+        Cursor::new(vec![]),
+        new_key,
+        new_cert,
+        &cancel_signal,
+    )
+    .unwrap();
+    let resigned = writer.into_inner();
+
+    ota::verify_metadata(Cursor::new(&resigned), &metadata, payload_metadata_size).unwrap();
+    assert_eq!(
+        ota::verify_ota(Cursor::new(&resigned), false, &cancel_signal).unwrap(),
+        slice::from_ref(new_cert),
+    );
+
+    let (_, old_otacert, old_header, _) = ota::parse_zip_ota_info(Cursor::new(&original)).unwrap();
+    let (_, otacert, header, properties) = ota::parse_zip_ota_info(Cursor::new(&resigned)).unwrap();
+    assert_eq!(&old_otacert, old_cert);
+    assert_eq!(&otacert, new_cert);
+    assert_eq!(header.manifest.partitions, old_header.manifest.partitions);
+
+    // The payload is only valid with the new key.
+    let entry = ota::find_stored_zip_entry(Cursor::new(&resigned), ota::PATH_PAYLOAD).unwrap();
+    let payload = || SectionReader::new(Cursor::new(&resigned), entry.offset, entry.size).unwrap();
+    payload::verify_payload(payload(), new_cert, &properties, &cancel_signal).unwrap();
+    assert!(payload::verify_payload(payload(), old_cert, &properties, &cancel_signal).is_err());
+}
@@ -0,0 +1,757 @@
+/*
+ * SPDX-FileCopyrightText: 2024 Andrew Gunnerson
+ * SPDX-License-Identifier: GPL-3.0-only
+ */
+
+use std::{
+    io::{Read, Seek, SeekFrom, Write},
+    slice,
+    sync::atomic::AtomicBool,
+    time::Duration,
+};
+
+use assert_matches::assert_matches;
+use avbroot::{
+    crypto::{self, RsaSigningKey},
+    format::payload::{
+        self, ApplyOptions, CompressOptions, ManifestLimits, PayloadHeader, PayloadWriter, XzCheck,
+        XzParams,
+    },
+    protobuf::chromeos_update_engine::{
+        install_operation::Type, DeltaArchiveManifest, Extent, InstallOperation, PartitionUpdate,
+    },
+    stream::{FromReader, Reopen, SharedCursor},
+};
+use prost::Message;
+use rsa::RsaPrivateKey;
+
+const CHUNK_SIZE: usize = 2 * 1024 * 1024;
+
+#[test]
+fn compress_image_zero_chunks() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let mut data = vec![0u8; CHUNK_SIZE];
+    data.extend((0..CHUNK_SIZE).map(|i| (i % 251) as u8));
+
+    let mut input = SharedCursor::new();
+    input.write_all(&data).unwrap();
+
+    let blob = SharedCursor::new();
+    let options = CompressOptions {
+        zero_chunks: true,
+        ..Default::default()
+    };
+
+    let (_, operations) =
+        payload::compress_image(&input, &blob, "test", 4096, &options, &cancel_signal).unwrap();
+
+    assert_eq!(
+        operations.iter().map(|op| op.r#type()).collect::<Vec<_>>(),
+        [Type::Zero, Type::ReplaceXz],
+    );
+    assert_eq!(operations[0].data_length, None);
+}
+
+#[test]
+fn compress_image_low_memory() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let mut data = (0..3 * CHUNK_SIZE)
+        .map(|i| (i % 251) as u8)
+        .collect::<Vec<_>>();
+    data.resize(5 * CHUNK_SIZE, 0);
+
+    let mut input = SharedCursor::new();
+    input.write_all(&data).unwrap();
+
+    let compress = |low_memory| {
+        let mut blob = SharedCursor::new();
+        let options = CompressOptions {
+            zero_chunks: true,
+            low_memory,
+            ..Default::default()
+        };
+
+        let (partition_info, operations) =
+            payload::compress_image(&input, &blob, "test", 4096, &options, &cancel_signal).unwrap();
+
+        let mut blob_data = vec![];
+        blob.seek(SeekFrom::Start(0)).unwrap();
+        blob.read_to_end(&mut blob_data).unwrap();
+
+        (partition_info, operations, blob_data)
+    };
+
+    // Compressing one chunk at a time must produce the same output.
+    assert_eq!(compress(true), compress(false));
+}
+
+#[test]
+fn apply_operation_xz_memlimit() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let data = (0..CHUNK_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    let mut input = SharedCursor::new();
+    input.write_all(&data).unwrap();
+
+    let blob = SharedCursor::new();
+    let options = CompressOptions {
+        xz_dict_size: Some(1024 * 1024),
+        ..Default::default()
+    };
+
+    let (_, operations) =
+        payload::compress_image(&input, &blob, "test", 4096, &options, &cancel_signal).unwrap();
+    assert_eq!(operations.len(), 1);
+    assert_eq!(operations[0].r#type(), Type::ReplaceXz);
+
+    let apply = |xz_memlimit| {
+        payload::apply_operation(
+            blob.reopen().unwrap(),
+            SharedCursor::new(),
+            4096,
+            0,
+            &operations[0],
+            &ApplyOptions { xz_memlimit },
+            &cancel_signal,
+        )
+    };
+
+    // The decoder must be able to allocate the 1 MiB dictionary.
+    assert!(apply(Some(512 * 1024)).is_err());
+    apply(Some(2 * 1024 * 1024)).unwrap();
+    apply(None).unwrap();
+}
+
+#[test]
+fn compress_image_skip_entropy() {
+    let cancel_signal = AtomicBool::new(false);
+
+    // Simple LCG to generate high-entropy data followed by low-entropy data.
+    let mut state = 1u32;
+    let mut data = (0..CHUNK_SIZE)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect::<Vec<_>>();
+    data.extend((0..CHUNK_SIZE).map(|i| (i % 4) as u8));
+
+    let mut input = SharedCursor::new();
+    input.write_all(&data).unwrap();
+
+    let blob = SharedCursor::new();
+    let options = CompressOptions {
+        skip_entropy: Some(7.5),
+        ..Default::default()
+    };
+
+    let (_, operations) =
+        payload::compress_image(&input, &blob, "test", 4096, &options, &cancel_signal).unwrap();
+
+    assert_eq!(
+        operations.iter().map(|op| op.r#type()).collect::<Vec<_>>(),
+        [Type::Replace, Type::ReplaceXz],
+    );
+    assert_eq!(operations[0].data_length, Some(CHUNK_SIZE as u64));
+}
+
+#[test]
+fn compress_image_xz_level() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let data = (0..CHUNK_SIZE)
+        .flat_map(|i| format!("{:08}\n", i / 3 % 1000).into_bytes())
+        .take(CHUNK_SIZE)
+        .collect::<Vec<_>>();
+
+    let mut input = SharedCursor::new();
+    input.write_all(&data).unwrap();
+
+    let mut sizes = vec![];
+
+    for (xz_level, xz_extreme) in [(0, false), (9, true)] {
+        let mut blob = SharedCursor::new();
+        let options = CompressOptions {
+            xz_level,
+            xz_extreme,
+            ..Default::default()
+        };
+
+        let (_, operations) =
+            payload::compress_image(&input, &blob, "test", 4096, &options, &cancel_signal).unwrap();
+        assert_eq!(operations.len(), 1);
+        assert_eq!(operations[0].r#type(), Type::ReplaceXz);
+
+        let mut compressed = vec![];
+        blob.rewind().unwrap();
+        blob.read_to_end(&mut compressed).unwrap();
+
+        let mut decompressed = vec![];
+        liblzma::read::XzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+
+        sizes.push(compressed.len());
+    }
+
+    assert!(sizes[1] <= sizes[0], "{sizes:?}");
+}
+
+#[test]
+fn compress_image_xz_params() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let data = (0..CHUNK_SIZE).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+    let mut input = SharedCursor::new();
+    input.write_all(&data).unwrap();
+
+    let original = XzParams {
+        check: XzCheck::Crc32,
+        dict_size: 64 * 1024,
+    };
+
+    for (options, expected) in [
+        (
+            CompressOptions::default(),
+            XzParams {
+                check: XzCheck::None,
+                dict_size: 256 * 1024,
+            },
+        ),
+        (
+            CompressOptions {
+                xz_original: Some(original),
+                ..Default::default()
+            },
+            original,
+        ),
+        (
+            CompressOptions {
+                xz_check: Some(XzCheck::Crc64),
+                xz_dict_size: Some(1024 * 1024),
+                xz_original: Some(original),
+                ..Default::default()
+            },
+            XzParams {
+                check: XzCheck::Crc64,
+                dict_size: 1024 * 1024,
+            },
+        ),
+    ] {
+        let mut blob = SharedCursor::new();
+
+        payload::compress_image(&input, &blob, "test", 4096, &options, &cancel_signal).unwrap();
+
+        let mut compressed = vec![];
+        blob.rewind().unwrap();
+        blob.read_to_end(&mut compressed).unwrap();
+
+        assert_eq!(payload::parse_xz_params(&compressed).unwrap(), expected);
+
+        let mut decompressed = vec![];
+        liblzma::read::XzDecoder::new(compressed.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap();
+        assert_eq!(decompressed, data);
+    }
+
+    assert_matches!(
+        payload::parse_xz_params(b"not xz data"),
+        Err(payload::Error::InvalidXzHeader(_))
+    );
+}
+
+fn apply_operations(
+    blob: &SharedCursor,
+    output: &SharedCursor,
+    block_size: u32,
+    operations: &[InstallOperation],
+) {
+    let cancel_signal = AtomicBool::new(false);
+
+    for op in operations {
+        payload::apply_operation(
+            blob.reopen().unwrap(),
+            output.reopen().unwrap(),
+            block_size,
+            0,
+            op,
+            &ApplyOptions::default(),
+            &cancel_signal,
+        )
+        .unwrap();
+    }
+}
+
+fn read_all(mut cursor: SharedCursor) -> Vec<u8> {
+    let mut data = vec![];
+    cursor.rewind().unwrap();
+    cursor.read_to_end(&mut data).unwrap();
+    data
+}
+
+#[test]
+fn compress_image_block_sizes() {
+    let cancel_signal = AtomicBool::new(false);
+
+    // 12 KiB blocks do not evenly divide the 2 MiB chunk size.
+    for block_size in [4096u32, 12 * 1024, 64 * 1024] {
+        let size = 3 * CHUNK_SIZE + 5 * block_size as usize;
+        let data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+        let mut input = SharedCursor::new();
+        input.write_all(&data).unwrap();
+
+        let blob = SharedCursor::new();
+        let options = CompressOptions {
+            skip_entropy: Some(0.0),
+            ..Default::default()
+        };
+
+        let (partition_info, operations) =
+            payload::compress_image(&input, &blob, "test", block_size, &options, &cancel_signal)
+                .unwrap();
+
+        assert_eq!(partition_info.size, Some(size as u64));
+
+        let mut next_block = 0;
+        for op in &operations {
+            assert_eq!(op.dst_extents.len(), 1);
+            assert_eq!(op.dst_extents[0].start_block, Some(next_block));
+            next_block += op.dst_extents[0].num_blocks();
+        }
+        assert_eq!(next_block * u64::from(block_size), size as u64);
+
+        let output = SharedCursor::new();
+        apply_operations(&blob, &output, block_size, &operations);
+
+        assert_eq!(read_all(output), data);
+    }
+}
+
+#[test]
+fn compress_image_invalid_block_size() {
+    let cancel_signal = AtomicBool::new(false);
+
+    let mut input = SharedCursor::new();
+    input.write_all(&[0u8; 6144]).unwrap();
+
+    let blob = SharedCursor::new();
+    let options = CompressOptions::default();
+
+    assert_matches!(
+        payload::compress_image(&input, &blob, "test", 0, &options, &cancel_signal),
+        Err(payload::Error::InvalidBlockSize(0))
+    );
+    assert_matches!(
+        payload::compress_image(&input, &blob, "test", 4096, &options, &cancel_signal),
+        Err(payload::Error::InvalidPartitionSize {
+            size: 6144,
+            block_size: 4096,
+            ..
+        })
+    );
+}
+
+#[test]
+fn compress_modified_image_block_size() {
+    let cancel_signal = AtomicBool::new(false);
+    let block_size = 64 * 1024;
+
+    let size = 2 * CHUNK_SIZE + 3 * block_size as usize;
+    let mut data = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+
+    let mut input = SharedCursor::new();
+    input.write_all(&data).unwrap();
+
+    let blob = SharedCursor::new();
+    let options = CompressOptions {
+        skip_entropy: Some(0.0),
+        ..Default::default()
+    };
+
+    let (mut partition_info, mut operations) =
+        payload::compress_image(&input, &blob, "test", block_size, &options, &cancel_signal)
+            .unwrap();
+
+    // Modify the final, partial chunk only.
+    let offset = 2 * CHUNK_SIZE + block_size as usize;
+    data[offset] ^= 0xff;
+    input.seek(SeekFrom::Start(offset as u64)).unwrap();
+    input.write_all(&data[offset..offset + 1]).unwrap();
+
+    let modified_range = offset as u64..offset as u64 + 1;
+    let new_blob = SharedCursor::new();
+    let modified = payload::compress_modified_image(
+        &input,
+        &new_blob,
+        block_size,
+        &mut partition_info,
+        &mut operations,
+        slice::from_ref(&modified_range),
+        &options,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    assert_eq!(modified.len(), 1);
+    assert_eq!(modified[0], 2..3);
+
+    let output = SharedCursor::new();
+    apply_operations(&blob, &output, block_size, &operations[..2]);
+    apply_operations(&new_blob, &output, block_size, &operations[2..]);
+
+    assert_eq!(read_all(output), data);
+}
+
+#[test]
+fn diff_image_round_trip() {
+    const BLOCK_SIZE: usize = 4096;
+
+    let cancel_signal = AtomicBool::new(false);
+    let old_block = |n: usize| (0..BLOCK_SIZE).map(move |i| ((i * 7 + n * 13) % 251) as u8);
+    let new_block = |n: usize| (0..BLOCK_SIZE).map(move |i| ((i * 11 + n * 17) % 241) as u8);
+
+    let mut source_data = vec![];
+    for n in 0..600 {
+        if n % 50 == 49 {
+            source_data.resize(source_data.len() + BLOCK_SIZE, 0);
+        } else {
+            source_data.extend(old_block(n));
+        }
+    }
+
+    // Moved blocks, zeros, new data, and then unmoved blocks that extend past
+    // the end of the source image and cross a chunk boundary.
+    let mut target_data = vec![];
+    for n in (100..200).rev() {
+        target_data.extend_from_slice(&source_data[n * BLOCK_SIZE..(n + 1) * BLOCK_SIZE]);
+    }
+    target_data.resize(target_data.len() + 10 * BLOCK_SIZE, 0);
+    for n in 0..10 {
+        target_data.extend(new_block(n));
+    }
+    target_data.extend_from_slice(&source_data[..580 * BLOCK_SIZE]);
+
+    let mut source = SharedCursor::new();
+    source.write_all(&source_data).unwrap();
+    let mut target = SharedCursor::new();
+    target.write_all(&target_data).unwrap();
+
+    let blob = SharedCursor::new();
+    let (source_info, target_info, operations) = payload::diff_image(
+        &source,
+        &target,
+        &blob,
+        "test",
+        BLOCK_SIZE as u32,
+        &CompressOptions::default(),
+        &cancel_signal,
+    )
+    .unwrap();
+
+    assert_eq!(source_info.size, Some(source_data.len() as u64));
+    assert_eq!(target_info.size, Some(target_data.len() as u64));
+    assert_eq!(
+        target_info.hash.as_deref(),
+        Some(avbroot::digest::digest(&avbroot::digest::SHA256, &target_data).as_ref()),
+    );
+
+    // Only the new blocks are stored in the payload.
+    let stored_blocks = operations
+        .iter()
+        .filter(|op| op.data_length.is_some())
+        .flat_map(|op| &op.dst_extents)
+        .map(|e| e.num_blocks())
+        .sum::<u64>();
+    assert_eq!(stored_blocks, 10);
+    assert!(operations.iter().any(|op| op.r#type() == Type::Zero));
+
+    let output = SharedCursor::new();
+
+    for op in &operations {
+        if op.r#type() != Type::SourceCopy {
+            apply_operations(&blob, &output, BLOCK_SIZE as u32, slice::from_ref(op));
+            continue;
+        }
+
+        let mut data = vec![];
+        for extent in &op.src_extents {
+            let start = extent.start_block() as usize * BLOCK_SIZE;
+            let end = start + extent.num_blocks() as usize * BLOCK_SIZE;
+            data.extend_from_slice(&source_data[start..end]);
+        }
+
+        assert_eq!(
+            op.src_sha256_hash.as_deref(),
+            Some(avbroot::digest::digest(&avbroot::digest::SHA256, &data).as_ref()),
+        );
+
+        let mut writer = output.reopen().unwrap();
+        writer
+            .seek(SeekFrom::Start(
+                op.dst_extents[0].start_block() * BLOCK_SIZE as u64,
+            ))
+            .unwrap();
+        writer.write_all(&data).unwrap();
+    }
+
+    assert_eq!(read_all(output), target_data);
+}
+
+#[test]
+fn apply_source_copy() {
+    const BLOCK_SIZE: usize = 4096;
+
+    let cancel_signal = AtomicBool::new(false);
+    let source_data = (0..4 * BLOCK_SIZE)
+        .map(|i| (i / BLOCK_SIZE) as u8 + 1)
+        .collect::<Vec<_>>();
+
+    let mut source = SharedCursor::new();
+    source.write_all(&source_data).unwrap();
+
+    let extent = |start_block, num_blocks| Extent {
+        start_block: Some(start_block),
+        num_blocks: Some(num_blocks),
+    };
+
+    // Source extents and destination extents with different boundaries.
+    let mut expected = source_data[2 * BLOCK_SIZE..4 * BLOCK_SIZE].to_vec();
+    expected.extend_from_slice(&source_data[..BLOCK_SIZE]);
+
+    let mut op = InstallOperation {
+        src_extents: vec![extent(2, 2), extent(0, 1)],
+        dst_extents: vec![extent(1, 1), extent(3, 2)],
+        src_sha256_hash: Some(
+            avbroot::digest::digest(&avbroot::digest::SHA256, &expected)
+                .as_ref()
+                .to_vec(),
+        ),
+        ..Default::default()
+    };
+    op.set_type(Type::SourceCopy);
+
+    let output = SharedCursor::new();
+    payload::apply_source_copy(
+        source.reopen().unwrap(),
+        output.reopen().unwrap(),
+        BLOCK_SIZE as u32,
+        &op,
+        &cancel_signal,
+    )
+    .unwrap();
+
+    let data = read_all(output);
+    assert_eq!(data[BLOCK_SIZE..2 * BLOCK_SIZE], expected[..BLOCK_SIZE]);
+    assert_eq!(data[3 * BLOCK_SIZE..], expected[BLOCK_SIZE..]);
+
+    assert_matches!(
+        payload::apply_operation(
+            source.reopen().unwrap(),
+            SharedCursor::new(),
+            BLOCK_SIZE as u32,
+            0,
+            &op,
+            &ApplyOptions::default(),
+            &cancel_signal,
+        ),
+        Err(payload::Error::RequiresSource(Type::SourceCopy))
+    );
+
+    op.src_sha256_hash = Some(vec![0u8; 32]);
+    assert_matches!(
+        payload::apply_source_copy(
+            source.reopen().unwrap(),
+            SharedCursor::new(),
+            BLOCK_SIZE as u32,
+            &op,
+            &cancel_signal,
+        ),
+        Err(payload::Error::MismatchedDigest { .. })
+    );
+}
+
+fn encode_header(manifest: &DeltaArchiveManifest) -> Vec<u8> {
+    let manifest_raw = manifest.encode_to_vec();
+
+    let mut data = vec![];
+    data.extend_from_slice(payload::OTA_MAGIC);
+    data.extend_from_slice(&2u64.to_be_bytes());
+    data.extend_from_slice(&(manifest_raw.len() as u64).to_be_bytes());
+    data.extend_from_slice(&0u32.to_be_bytes());
+    data.extend_from_slice(&manifest_raw);
+
+    data
+}
+
+#[test]
+fn parse_header_limits() {
+    let operation = InstallOperation {
+        dst_extents: vec![Extent::default(); 4],
+        ..Default::default()
+    };
+    let partition = PartitionUpdate {
+        partition_name: "test".to_owned(),
+        operations: vec![operation; 3],
+        ..Default::default()
+    };
+    let manifest = DeltaArchiveManifest {
+        partitions: vec![partition; 2],
+        ..Default::default()
+    };
+    let data = encode_header(&manifest);
+
+    let header = PayloadHeader::from_reader(data.as_slice()).unwrap();
+    assert_eq!(header.manifest, manifest);
+    assert_eq!(header.blob_offset, data.len() as u64);
+
+    let limits = ManifestLimits {
+        max_partitions: 2,
+        max_operations: 3,
+        max_extents: 4,
+    };
+    PayloadHeader::from_reader_with_limits(data.as_slice(), &limits).unwrap();
+
+    for (limits, expected) in [
+        (
+            ManifestLimits {
+                max_partitions: 1,
+                ..limits
+            },
+            "partitions",
+        ),
+        (
+            ManifestLimits {
+                max_operations: 2,
+                ..limits
+            },
+            "operations",
+        ),
+        (
+            ManifestLimits {
+                max_extents: 3,
+                ..limits
+            },
+            "dst_extents",
+        ),
+    ] {
+        assert_matches!(
+            PayloadHeader::from_reader_with_limits(data.as_slice(), &limits),
+            Err(payload::Error::LimitExceeded { field, .. }) if field == expected
+        );
+    }
+
+    // Truncated manifest.
+    assert_matches!(
+        PayloadHeader::from_reader(&data[..data.len() - 1]),
+        Err(payload::Error::Io(_))
+    );
+}
+
+#[test]
+fn rewrite_header_metadata() {
+    let cancel_signal = AtomicBool::new(false);
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    let cert = crypto::generate_cert(&key, 1, Duration::from_secs(3600), "CN=test").unwrap();
+
+    let data = [b"foo".as_slice(), b"barbaz".as_slice()];
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: Some(4096),
+            max_timestamp: Some(1),
+            partitions: vec![PartitionUpdate {
+                partition_name: "test".to_owned(),
+                operations: data
+                    .iter()
+                    .map(|d| InstallOperation {
+                        r#type: Type::Replace.into(),
+                        data_length: Some(d.len() as u64),
+                        ..Default::default()
+                    })
+                    .collect(),
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 0,
+    };
+
+    let key = RsaSigningKey::Internal(key);
+    let mut writer = PayloadWriter::new(SharedCursor::new(), header, key.clone()).unwrap();
+    for d in data {
+        assert!(writer.begin_next_operation().unwrap());
+        writer.write_all(d).unwrap();
+    }
+    assert!(!writer.begin_next_operation().unwrap());
+    let (mut original, _, _) = writer.finish().unwrap();
+
+    original.rewind().unwrap();
+    let mut header = PayloadHeader::from_reader(&mut original).unwrap();
+    header.manifest.max_timestamp = Some(2);
+    header.manifest.security_patch_level = Some("2024-01-01".to_owned());
+
+    let mut rewritten = SharedCursor::new();
+    let (properties, _) =
+        payload::rewrite_header(&mut original, &mut rewritten, &header, &key, &cancel_signal)
+            .unwrap();
+
+    rewritten.rewind().unwrap();
+    payload::verify_payload(&mut rewritten, &cert, &properties, &cancel_signal).unwrap();
+
+    rewritten.rewind().unwrap();
+    let new_header = PayloadHeader::from_reader(&mut rewritten).unwrap();
+    assert_eq!(new_header.manifest.max_timestamp, Some(2));
+    assert_eq!(
+        new_header.manifest.security_patch_level.as_deref(),
+        Some("2024-01-01"),
+    );
+    assert_eq!(new_header.manifest.partitions, header.manifest.partitions,);
+
+    let mut blob = vec![];
+    rewritten.read_to_end(&mut blob).unwrap();
+    assert!(blob.starts_with(b"foobarbaz"));
+}
+
+#[test]
+fn rewrite_header_data_offset_overflow() {
+    let cancel_signal = AtomicBool::new(false);
+    let key = RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+    let key = RsaSigningKey::Internal(key);
+
+    let header = PayloadHeader {
+        version: 2,
+        manifest: DeltaArchiveManifest {
+            block_size: Some(4096),
+            partitions: vec![PartitionUpdate {
+                partition_name: "test".to_owned(),
+                operations: vec![InstallOperation {
+                    r#type: Type::Replace.into(),
+                    data_offset: Some(u64::MAX),
+                    data_length: Some(1),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }],
+            ..Default::default()
+        },
+        metadata_signature_size: 0,
+        blob_offset: 1,
+    };
+
+    let err = payload::rewrite_header(
+        SharedCursor::new(),
+        SharedCursor::new(),
+        &header,
+        &key,
+        &cancel_signal,
+    )
+    .unwrap_err();
+    assert_matches!(err, payload::Error::FieldOutOfBounds("in_offset"));
+}
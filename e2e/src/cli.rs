@@ -49,6 +49,27 @@ pub struct TestCli {
     pub config: ConfigGroup,
 }
 
+/// Generate fixture OTAs for `avbroot selftest`.
+#[derive(Debug, Parser)]
+pub struct FixturesCli {
+    #[command(flatten)]
+    pub profile: ProfileGroup,
+
+    /// Path to config file.
+    #[arg(
+        short,
+        long,
+        value_name = "FILE",
+        value_parser,
+        default_value = "compat.toml"
+    )]
+    pub config: PathBuf,
+
+    /// Output directory for the fixture OTAs.
+    #[arg(short, long, value_name = "DIRECTORY", value_parser)]
+    pub output_dir: PathBuf,
+}
+
 /// List profiles in config file.
 #[derive(Debug, Parser)]
 pub struct ListCli {
@@ -59,6 +80,7 @@ pub struct ListCli {
 #[derive(Debug, Subcommand)]
 pub enum Command {
     Test(TestCli),
+    Fixtures(FixturesCli),
     List(ListCli),
 }
 
@@ -6,6 +6,7 @@
 use std::{collections::BTreeMap, fs, path::Path};
 
 use anyhow::{Context, Result};
+use avbroot::cli::fake::{self, OtaInfo, Partition};
 use serde::{Deserialize, Serialize};
 use toml_edit::Document;
 
@@ -18,79 +19,6 @@ pub struct Sha256Hash(
     pub [u8; 32],
 );
 
-#[derive(Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct OtaInfo {
-    pub device: String,
-    pub fingerprint: String,
-    pub build_number: String,
-    pub incremental_version: String,
-    pub android_version: String,
-    pub sdk_version: String,
-    pub security_patch_level: String,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct Avb {
-    pub signed: bool,
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum RamdiskContent {
-    Init,
-    Otacerts,
-    InitAndOtacerts,
-    Dlkm,
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum BootVersion {
-    V2,
-    V3,
-    V4,
-    VendorV3,
-    VendorV4,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct BootData {
-    pub version: BootVersion,
-    #[serde(default)]
-    pub kernel: bool,
-    #[serde(default)]
-    pub ramdisks: Vec<RamdiskContent>,
-}
-
-#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
-#[serde(rename_all = "snake_case")]
-pub enum DmVerityContent {
-    SystemOtacerts,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct DmVerityData {
-    pub content: DmVerityContent,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct VbmetaData {
-    pub deps: Vec<String>,
-}
-
-#[derive(Serialize, Deserialize)]
-#[serde(tag = "type", rename_all = "snake_case")]
-pub enum Data {
-    Boot(BootData),
-    DmVerity(DmVerityData),
-    Vbmeta(VbmetaData),
-}
-
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Hashes {
@@ -98,18 +26,27 @@ pub struct Hashes {
     pub patched: Sha256Hash,
 }
 
-#[derive(Serialize, Deserialize)]
-#[serde(deny_unknown_fields)]
-pub struct Partition {
-    pub avb: Avb,
-    pub data: Data,
-}
-
 #[derive(Serialize, Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Profile {
     pub partitions: BTreeMap<String, Partition>,
-    pub hashes: Hashes,
+    /// Whether to write the OTA zip with zip64 extensions.
+    #[serde(default)]
+    pub zip64: bool,
+    /// Expected checksums. The `test` subcommand refuses to run profiles
+    /// without them. Profiles that are only used for generating fixtures have
+    /// none.
+    #[serde(default)]
+    pub hashes: Option<Hashes>,
+}
+
+impl Profile {
+    pub fn to_fake(&self) -> fake::Profile {
+        fake::Profile {
+            partitions: self.partitions.clone(),
+            zip64: self.zip64,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]